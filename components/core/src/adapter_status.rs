@@ -0,0 +1,91 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Whether each adapter started, or is waiting for another attempt after a failure, e.g.
+//! because its hardware isn't plugged yet or its hub is unreachable.
+
+use serde_json::value::Value as JSON;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdapterStatus {
+    /// The adapter started.
+    Running,
+    /// The adapter failed to start and will be retried.
+    Failed {
+        /// The error returned by the latest attempt.
+        error: String,
+        /// How many attempts failed so far.
+        attempts: u32,
+        /// Seconds until the next attempt.
+        retry_in: u64,
+    },
+}
+
+impl AdapterStatus {
+    pub fn to_json(&self) -> JSON {
+        let mut map = BTreeMap::new();
+        match *self {
+            AdapterStatus::Running => {
+                map.insert("status".to_owned(), JSON::String("running".to_owned()));
+            }
+            AdapterStatus::Failed { ref error, attempts, retry_in } => {
+                map.insert("status".to_owned(), JSON::String("failed".to_owned()));
+                map.insert("error".to_owned(), JSON::String(error.clone()));
+                map.insert("attempts".to_owned(), JSON::U64(attempts as u64));
+                map.insert("retry_in".to_owned(), JSON::U64(retry_in));
+            }
+        }
+        JSON::Object(map)
+    }
+}
+
+/// The status of each adapter, by name.
+#[derive(Clone, Default)]
+pub struct AdapterStatuses {
+    statuses: Arc<Mutex<BTreeMap<String, AdapterStatus>>>,
+}
+
+impl AdapterStatuses {
+    pub fn new() -> Self {
+        AdapterStatuses::default()
+    }
+
+    pub fn set(&self, adapter: &str, status: AdapterStatus) {
+        self.statuses.lock().unwrap().insert(adapter.to_owned(), status);
+    }
+
+    pub fn get(&self, adapter: &str) -> Option<AdapterStatus> {
+        self.statuses.lock().unwrap().get(adapter).cloned()
+    }
+
+    /// An object with a field per adapter.
+    pub fn to_json(&self) -> JSON {
+        let statuses = self.statuses.lock().unwrap();
+        JSON::Object(statuses.iter()
+            .map(|(adapter, status)| (adapter.clone(), status.to_json()))
+            .collect())
+    }
+}
+
+#[test]
+fn test_adapter_statuses() {
+    let statuses = AdapterStatuses::new();
+    statuses.set("clock", AdapterStatus::Running);
+    statuses.clone().set("philips_hue",
+                         AdapterStatus::Failed {
+                             error: "unreachable".to_owned(),
+                             attempts: 2,
+                             retry_in: 20,
+                         });
+    assert_eq!(statuses.get("clock"), Some(AdapterStatus::Running));
+    assert_eq!(statuses.get("zwave"), None);
+
+    let json = statuses.to_json();
+    let hue = json.find("philips_hue").unwrap();
+    assert_eq!(hue.find("status").and_then(JSON::as_string), Some("failed"));
+    assert_eq!(hue.find("attempts").and_then(JSON::as_u64), Some(2));
+}
@@ -0,0 +1,220 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The companion apps registered on the box, and how to open a device in each of them.
+//!
+//! An app registers URL templates per feature, e.g. `camera/x-latest-image` =>
+//! `camapp://view?channel={channel}`. Services and value events then carry "open in app"
+//! links for the channels implementing these features. The `{channel}`, `{service}` and
+//! `{feature}` placeholders are replaced by the percent-encoded ids.
+
+use serde_json;
+use serde_json::value::Value as JSON;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct App {
+    /// Chosen by the app, e.g. its package name.
+    pub id: String,
+    pub name: String,
+    /// The url of an icon.
+    pub icon: Option<String>,
+    /// URL templates, by feature.
+    pub links: BTreeMap<String, String>,
+}
+
+impl App {
+    pub fn from_json(source: &JSON) -> Result<Self, String> {
+        let id = try!(string_field(source, "id").ok_or("Missing field `id`"));
+        let name = try!(string_field(source, "name").ok_or("Missing field `name`"));
+        if id.is_empty() {
+            return Err("Field `id` is empty".to_owned());
+        }
+        let mut links = BTreeMap::new();
+        if let Some(source) = source.find("links") {
+            let source = try!(source.as_object().ok_or("Field `links` is not an object"));
+            for (feature, template) in source {
+                let template = try!(template.as_string()
+                    .ok_or(format!("The link of {} is not a string", feature)));
+                links.insert(feature.clone(), template.to_owned());
+            }
+        }
+        Ok(App {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            icon: string_field(source, "icon").map(|icon| icon.to_owned()),
+            links: links,
+        })
+    }
+
+    pub fn to_json(&self) -> JSON {
+        let mut map = BTreeMap::new();
+        map.insert("id".to_owned(), JSON::String(self.id.clone()));
+        map.insert("name".to_owned(), JSON::String(self.name.clone()));
+        map.insert("icon".to_owned(), self.icon.clone().map_or(JSON::Null, JSON::String));
+        map.insert("links".to_owned(),
+                   JSON::Object(self.links
+                       .iter()
+                       .map(|(feature, template)| (feature.clone(), JSON::String(template.clone())))
+                       .collect()));
+        JSON::Object(map)
+    }
+}
+
+fn string_field<'a>(source: &'a JSON, field: &str) -> Option<&'a str> {
+    source.find(field).and_then(JSON::as_string)
+}
+
+/// Percent-encode everything but the unreserved characters of RFC 3986.
+fn encode(source: &str) -> String {
+    let mut encoded = String::new();
+    for byte in source.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[derive(Clone)]
+pub struct AppRegistry {
+    /// Where the registry is persisted, if anywhere.
+    path: Option<PathBuf>,
+    apps: Arc<Mutex<BTreeMap<String, App>>>,
+}
+
+impl AppRegistry {
+    /// A registry persisted in a file, loaded if it exists.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let mut apps = BTreeMap::new();
+        if let Some(ref path) = path {
+            let mut source = String::new();
+            if File::open(path).and_then(|mut file| file.read_to_string(&mut source)).is_ok() {
+                match serde_json::from_str::<JSON>(&source) {
+                    Ok(JSON::Array(items)) => {
+                        for item in &items {
+                            match App::from_json(item) {
+                                Ok(app) => {
+                                    apps.insert(app.id.clone(), app);
+                                }
+                                Err(err) => warn!("Ignoring app in {}: {}", path.display(), err),
+                            }
+                        }
+                    }
+                    _ => error!("Ignoring invalid app registry {}", path.display()),
+                }
+            }
+        }
+        AppRegistry {
+            path: path,
+            apps: Arc::new(Mutex::new(apps)),
+        }
+    }
+
+    fn save(&self, apps: &BTreeMap<String, App>) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let json = JSON::Array(apps.values().map(App::to_json).collect());
+        let result = File::create(path)
+            .and_then(|mut file| file.write_all(serde_json::to_string(&json).unwrap().as_bytes()));
+        if let Err(err) = result {
+            error!("Could not save the app registry {}: {}", path.display(), err);
+        }
+    }
+
+    /// Register an app, replacing any app with the same id.
+    pub fn register(&self, app: App) {
+        let mut apps = self.apps.lock().unwrap();
+        apps.insert(app.id.clone(), app);
+        self.save(&apps);
+    }
+
+    /// Returns `false` if there was no such app.
+    pub fn unregister(&self, id: &str) -> bool {
+        let mut apps = self.apps.lock().unwrap();
+        let removed = apps.remove(id).is_some();
+        if removed {
+            self.save(&apps);
+        }
+        removed
+    }
+
+    pub fn apps(&self) -> Vec<App> {
+        self.apps.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.apps.lock().unwrap().is_empty()
+    }
+
+    /// The "open in app" links for a channel, as objects with fields `app`, `name`, `icon`
+    /// and `url`.
+    pub fn links(&self, feature: &str, service: &str, channel: &str) -> Vec<JSON> {
+        let apps = self.apps.lock().unwrap();
+        apps.values()
+            .filter_map(|app| {
+                app.links.get(feature).map(|template| {
+                    let url = template.replace("{channel}", &encode(channel))
+                        .replace("{service}", &encode(service))
+                        .replace("{feature}", &encode(feature));
+                    let mut map = BTreeMap::new();
+                    map.insert("app".to_owned(), JSON::String(app.id.clone()));
+                    map.insert("name".to_owned(), JSON::String(app.name.clone()));
+                    let icon = app.icon.clone().map_or(JSON::Null, JSON::String);
+                    map.insert("icon".to_owned(), icon);
+                    map.insert("url".to_owned(), JSON::String(url));
+                    JSON::Object(map)
+                })
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_app_registry() {
+    use tempdir::TempDir;
+
+    let dir = TempDir::new("apps").unwrap();
+    let path = dir.path().join("apps.json");
+    let registry = AppRegistry::new(Some(path.clone()));
+    assert!(registry.is_empty());
+
+    let app = App::from_json(&serde_json::from_str(r#"{"id": "cam", "name": "Cam",
+        "links": {"camera/x-latest-image": "cam://view?c={channel}"}}"#)
+            .unwrap())
+        .unwrap();
+    registry.register(app.clone());
+
+    let links = registry.links("camera/x-latest-image", "service:door", "getter:image@door");
+    assert_eq!(links.len(), 1);
+    assert_eq!(links[0].find("url").and_then(JSON::as_string),
+               Some("cam://view?c=getter%3Aimage%40door"));
+    assert!(registry.links("light/is-on", "service:door", "getter:image@door").is_empty());
+
+    // The registry survives restarts.
+    assert_eq!(AppRegistry::new(Some(path.clone())).apps(), vec![app]);
+    assert!(registry.unregister("cam"));
+    assert!(!registry.unregister("cam"));
+    assert!(AppRegistry::new(Some(path)).is_empty());
+}
+
+#[test]
+fn test_app_from_json_requires_id_and_name() {
+    assert!(App::from_json(&serde_json::from_str(r#"{"name": "Cam"}"#).unwrap()).is_err());
+    assert!(App::from_json(&serde_json::from_str(r#"{"id": "cam"}"#).unwrap()).is_err());
+    assert!(App::from_json(&serde_json::from_str(r#"{"id": "cam", "name": "Cam",
+        "links": {"camera/x-latest-image": 1}}"#)
+            .unwrap())
+        .is_err());
+}
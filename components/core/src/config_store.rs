@@ -9,6 +9,7 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 use std::sync::{Mutex, RwLock};
+use std::sync::mpsc::Sender;
 
 type ConfigNameSpace = BTreeMap<String, String>;
 
@@ -74,6 +75,21 @@ impl ConfigStore {
         self.overrides.get_mut(namespace).unwrap().insert(property.to_owned(), value.to_owned());
     }
 
+    /// Forget an override, so that the value set in the configuration applies again.
+    pub fn remove_override(&mut self, namespace: &str, property: &str) {
+        debug!("Removing config override for {}::{}", namespace, property);
+        let is_empty = match self.overrides.get_mut(namespace) {
+            Some(overrides) => {
+                overrides.remove(property);
+                overrides.is_empty()
+            }
+            None => return,
+        };
+        if is_empty {
+            self.overrides.remove(namespace);
+        }
+    }
+
     fn get_override(&self, namespace: &str, property: &str) -> Option<&String> {
         if self.overrides.contains_key(namespace) {
             let res = self.overrides[namespace].get(property);
@@ -135,13 +151,136 @@ impl ConfigStore {
     }
 }
 
+/// The type of a property, checked when it is changed at runtime, e.g. through the REST API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigType {
+    /// "true" or "false".
+    Bool,
+    /// A non-negative integer.
+    Integer,
+    String,
+    /// A string that is never shown back, e.g. an API key.
+    Secret,
+}
+
+impl ConfigType {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ConfigType::Bool => "bool",
+            ConfigType::Integer => "integer",
+            ConfigType::String => "string",
+            ConfigType::Secret => "secret",
+        }
+    }
+
+    pub fn validate(&self, value: &str) -> Result<(), String> {
+        match *self {
+            ConfigType::Bool if value != "true" && value != "false" => {
+                Err(format!("Expected true or false, got {:?}", value))
+            }
+            ConfigType::Integer if value.parse::<u64>().is_err() => {
+                Err(format!("Expected a non-negative integer, got {:?}", value))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A property whose value changed, see `ConfigService::watch`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigChange {
+    pub namespace: String,
+    pub property: String,
+    pub value: Option<String>,
+}
+
 pub struct ConfigService {
     store: RwLock<ConfigStore>,
+    /// The properties that may be changed at runtime, and their type.
+    schema: RwLock<BTreeMap<(String, String), ConfigType>>,
+    /// The namespaces watched, and where to send their changes.
+    watchers: Mutex<Vec<(String, Sender<ConfigChange>)>>,
 }
 
 impl ConfigService {
     pub fn new(file_name: &str) -> Self {
-        ConfigService { store: RwLock::new(ConfigStore::new(file_name)) }
+        ConfigService {
+            store: RwLock::new(ConfigStore::new(file_name)),
+            schema: RwLock::new(BTreeMap::new()),
+            watchers: Mutex::new(vec![]),
+        }
+    }
+
+    /// Let a property be changed at runtime, e.g. through the REST API, with values of `kind`.
+    pub fn register(&self, namespace: &str, property: &str, kind: ConfigType) {
+        self.schema
+            .write()
+            .unwrap()
+            .insert((namespace.to_owned(), property.to_owned()), kind);
+    }
+
+    /// The type of a property, if it was registered.
+    pub fn get_type(&self, namespace: &str, property: &str) -> Option<ConfigType> {
+        self.schema
+            .read()
+            .unwrap()
+            .get(&(namespace.to_owned(), property.to_owned()))
+            .cloned()
+    }
+
+    /// The registered properties, sorted by namespace and property.
+    pub fn schema(&self) -> Vec<(String, String, ConfigType)> {
+        self.schema
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(&(ref namespace, ref property), kind)| {
+                (namespace.clone(), property.clone(), *kind)
+            })
+            .collect()
+    }
+
+    /// Change a registered property, checking the value against its type.
+    pub fn set_checked(&self, namespace: &str, property: &str, value: &str) -> Result<(), String> {
+        match self.get_type(namespace, property) {
+            Some(kind) => try!(kind.validate(value)),
+            None => return Err(format!("Unknown property {}.{}", namespace, property)),
+        }
+        self.set(namespace, property, value);
+        Ok(())
+    }
+
+    /// Be told when the value of a property of `namespace` changes. The watch ends when the
+    /// receiver is dropped.
+    pub fn watch(&self, namespace: &str, tx: Sender<ConfigChange>) {
+        self.watchers.lock().unwrap().push((namespace.to_owned(), tx));
+    }
+
+    fn notify(&self, namespace: &str, property: &str, value: Option<String>) {
+        let change = ConfigChange {
+            namespace: namespace.to_owned(),
+            property: property.to_owned(),
+            value: value,
+        };
+        self.watchers
+            .lock()
+            .unwrap()
+            .retain(|&(ref watched, ref tx)| {
+                watched != namespace || tx.send(change.clone()).is_ok()
+            });
+    }
+
+    /// Apply `update` to the store, and tell the watchers if the value of the property changed.
+    fn update<F: FnOnce(&mut ConfigStore)>(&self, namespace: &str, property: &str, update: F) {
+        let (before, after) = {
+            let mut store = self.store.write().unwrap();
+            let before = store.get(namespace, property).cloned();
+            update(&mut store);
+            (before, store.get(namespace, property).cloned())
+        };
+        if before != after {
+            self.notify(namespace, property, after);
+        }
     }
 
     pub fn get(&self, namespace: &str, property: &str) -> Option<String> {
@@ -160,11 +299,21 @@ impl ConfigService {
     }
 
     pub fn set(&self, namespace: &str, property: &str, value: &str) {
-        self.store.write().unwrap().set(namespace, property, value);
+        self.update(namespace,
+                    property,
+                    |store| store.set(namespace, property, value));
     }
 
     pub fn set_override(&self, namespace: &str, property: &str, value: &str) {
-        self.store.write().unwrap().set_override(namespace, property, value);
+        self.update(namespace,
+                    property,
+                    |store| store.set_override(namespace, property, value));
+    }
+
+    pub fn remove_override(&self, namespace: &str, property: &str) {
+        self.update(namespace,
+                    property,
+                    |store| store.remove_override(namespace, property));
     }
 }
 
@@ -238,6 +387,46 @@ describe! config {
             config.set_override("foo", "bar", "bazbaz");
             let foo_baz = config.get("foo", "bar").unwrap();
             assert_eq!(foo_baz, "bazbaz");
+            config.remove_override("foo", "bar");
+            assert_eq!(config.get("foo", "bar"), Some("baz".to_owned()));
+        }
+
+        it "should only accept valid values for registered properties" {
+            config.register("foo", "enabled", ConfigType::Bool);
+            config.register("foo", "delay", ConfigType::Integer);
+            assert_eq!(config.get_type("foo", "enabled"), Some(ConfigType::Bool));
+            assert_eq!(config.get_type("foo", "bar"), None);
+
+            assert!(config.set_checked("foo", "enabled", "yes").is_err());
+            assert!(config.set_checked("foo", "delay", "-1").is_err());
+            assert!(config.set_checked("foo", "bar", "baz").is_err());
+            assert_eq!(config.get("foo", "bar"), None);
+            assert!(config.set_checked("foo", "enabled", "true").is_ok());
+            assert_eq!(config.get("foo", "enabled"), Some("true".to_owned()));
+            assert_eq!(config.schema(),
+                       vec![("foo".to_owned(), "delay".to_owned(), ConfigType::Integer),
+                            ("foo".to_owned(), "enabled".to_owned(), ConfigType::Bool)]);
+        }
+
+        it "should tell the watchers of a namespace about changes" {
+            use std::sync::mpsc::channel;
+
+            let (tx, rx) = channel();
+            config.watch("foo", tx);
+            config.set("foo", "bar", "baz");
+            // Unchanged values and other namespaces aren't reported.
+            config.set("foo", "bar", "baz");
+            config.set("other", "bar", "baz");
+            config.set_override("foo", "bar", "bazbaz");
+            assert_eq!(rx.try_recv(),
+                       Ok(ConfigChange {
+                           namespace: "foo".to_owned(),
+                           property: "bar".to_owned(),
+                           value: Some("baz".to_owned()),
+                       }));
+            assert_eq!(rx.try_recv().map(|change| change.value),
+                       Ok(Some("bazbaz".to_owned())));
+            assert!(rx.try_recv().is_err());
         }
     }
 
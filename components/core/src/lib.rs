@@ -11,15 +11,18 @@
 #![cfg_attr(test, plugin(stainless))] // Test runner
 
 extern crate core;
+extern crate foxbox_taxonomy;
 extern crate foxbox_users;
 extern crate hyper;
 extern crate libc;
 
 #[macro_use]
 extern crate log;
+extern crate rand;
 extern crate serde_json;
 
 extern crate tls;
+extern crate url;
 
 #[cfg(test)]
 extern crate uuid;
@@ -33,8 +36,19 @@ extern crate xml;
 #[macro_use]
 pub mod utils;
 
+pub mod adapter_status;
+pub mod app_registry;
 pub mod config_store;
+pub mod log_buffer;
 pub mod managed_process;
+pub mod modes;
+pub mod oauth2;
 pub mod profile_service;
+pub mod timeline;
 pub mod traits;
 pub mod upnp;
+pub mod watch_sets;
+pub mod ws_trace;
+
+// The pool also runs the calls of the AdapterManager to adapters.
+pub use foxbox_taxonomy::thread_pool;
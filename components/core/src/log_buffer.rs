@@ -0,0 +1,48 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A bounded buffer keeping the latest lines of a log, e.g. the output of a helper process,
+//! so that it can be displayed without having to dig through the system logs.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+pub struct LogBuffer {
+    capacity: usize,
+    lines: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        LogBuffer {
+            capacity: capacity,
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Append a line, dropping the oldest one if the buffer is full.
+    pub fn push(&self, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() >= self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// The lines currently in the buffer, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[test]
+fn test_log_buffer_drops_oldest_lines() {
+    let buffer = LogBuffer::new(2);
+    assert!(buffer.lines().is_empty());
+    buffer.push("one".to_owned());
+    buffer.push("two".to_owned());
+    buffer.clone().push("three".to_owned());
+    assert_eq!(buffer.lines(), vec!["two".to_owned(), "three".to_owned()]);
+}
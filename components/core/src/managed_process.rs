@@ -127,6 +127,7 @@ impl ManagedProcess {
                 }
 
                 child_process.wait().unwrap();
+                *shared_pid.lock().unwrap() = None;
 
                 let backoff_duration = checklock!(backoff.write()).next_backoff();
                 thread::sleep(backoff_duration);
@@ -141,7 +142,6 @@ impl ManagedProcess {
         })
     }
 
-    #[allow(dead_code)]
     pub fn get_restart_count(&self) -> u64 {
         checklock!(self.backoff.read()).get_restart_count()
     }
@@ -151,6 +151,12 @@ impl ManagedProcess {
         *self.pid.lock().unwrap()
     }
 
+    /// Whether the process is currently running. This is false while waiting to restart
+    /// a process that has exited.
+    pub fn is_running(&self) -> bool {
+        self.get_pid().is_some()
+    }
+
     /// Shut the ManagedProcess down safely. Equivalent to sending SIGKILL to the
     /// running process if it is currently alive
     ///
@@ -343,3 +349,27 @@ fn test_managed_process_shutdown() {
 
     process.shutdown().unwrap();
 }
+
+#[test]
+fn test_managed_process_is_running() {
+    use std::process::Command;
+
+    let process = ManagedProcess::start(|| {
+            Command::new("sleep")
+                .arg("1000")
+                .spawn()
+        })
+        .unwrap();
+
+    let mut spin_count = 0;
+    while !process.is_running() {
+        if spin_count > 10 {
+            panic!("Process has not started within the expected amount of time");
+        } else {
+            spin_count += 1;
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    process.shutdown().unwrap();
+}
@@ -0,0 +1,329 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The global modes of the box, e.g. "home", "away" or "vacation", and the current one.
+//!
+//! Each mode overrides some properties of the configuration, and enables or disables some
+//! rules, e.g. "vacation" disables the routine lighting rules and enables presence
+//! simulation. A mode may also be entered at given times of day. Entering the modes is up to
+//! the `modes` adapter, this only keeps their definitions.
+
+use serde_json;
+use serde_json::value::Value as JSON;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// The namespaces of the configuration that a mode may override. The others, e.g. the
+/// commands of `supervisor` and `sandbox`, are only changed by admins through `api/v1/config`.
+pub const OVERRIDABLE_NAMESPACES: &'static [&'static str] =
+    &["alerts", "irrigation", "occupancy", "presence", "security", "thinkerbell", "tts"];
+
+/// A property of the configuration, and its value while the mode is current.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigOverride {
+    pub namespace: String,
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mode {
+    pub name: String,
+    pub config: Vec<ConfigOverride>,
+    /// The names of the rules to enable when entering the mode.
+    pub enable_rules: Vec<String>,
+    /// The names of the rules to disable when entering the mode.
+    pub disable_rules: Vec<String>,
+    /// The times of day at which to enter the mode, as (hours, minutes).
+    pub starts_at: Vec<(u32, u32)>,
+}
+
+/// Parse a time of day "HH:MM".
+fn parse_time_of_day(source: &str) -> Option<(u32, u32)> {
+    let mut parts = source.splitn(2, ':');
+    let hours = parts.next().and_then(|hours| hours.parse::<u32>().ok());
+    let minutes = parts.next().and_then(|minutes| minutes.parse::<u32>().ok());
+    match (hours, minutes) {
+        (Some(hours), Some(minutes)) if hours < 24 && minutes < 60 => Some((hours, minutes)),
+        _ => None,
+    }
+}
+
+fn string_field<'a>(source: &'a JSON, field: &str) -> Option<&'a str> {
+    source.find(field).and_then(JSON::as_string)
+}
+
+/// An optional array of strings.
+fn strings_field(source: &JSON, field: &str) -> Result<Vec<String>, String> {
+    match source.find(field) {
+        None => Ok(vec![]),
+        Some(&JSON::Array(ref items)) => {
+            items.iter()
+                .map(|item| {
+                    item.as_string()
+                        .map(|item| item.to_owned())
+                        .ok_or(format!("Field `{}` holds a non-string", field))
+                })
+                .collect()
+        }
+        Some(_) => Err(format!("Field `{}` is not an array", field)),
+    }
+}
+
+fn strings_json(source: &[String]) -> JSON {
+    JSON::Array(source.iter().cloned().map(JSON::String).collect())
+}
+
+impl Mode {
+    /// Read a mode from an object `{"name": string, "config"?: [{"namespace": string,
+    /// "key": string, "value": string}], "enable_rules"?: [string], "disable_rules"?: [string],
+    /// "starts_at"?: ["HH:MM"]}`.
+    pub fn from_json(source: &JSON) -> Result<Self, String> {
+        let name = try!(string_field(source, "name").ok_or("Missing field `name`"));
+        if name.is_empty() {
+            return Err("Field `name` is empty".to_owned());
+        }
+        let mut config = vec![];
+        match source.find("config") {
+            None => {}
+            Some(&JSON::Array(ref items)) => {
+                for item in items {
+                    let field = |field| {
+                        string_field(item, field)
+                            .map(|value| value.to_owned())
+                            .ok_or(format!("Missing field `{}` in `config`", field))
+                    };
+                    let namespace = try!(field("namespace"));
+                    if !OVERRIDABLE_NAMESPACES.contains(&namespace.as_str()) {
+                        return Err(format!("Namespace `{}` may not be overridden by a mode",
+                                           namespace));
+                    }
+                    config.push(ConfigOverride {
+                        namespace: namespace,
+                        key: try!(field("key")),
+                        value: try!(field("value")),
+                    });
+                }
+            }
+            Some(_) => return Err("Field `config` is not an array".to_owned()),
+        }
+        let mut starts_at = vec![];
+        for time in try!(strings_field(source, "starts_at")) {
+            starts_at.push(try!(parse_time_of_day(&time)
+                .ok_or(format!("Invalid time of day {}, expected HH:MM", time))));
+        }
+        Ok(Mode {
+            name: name.to_owned(),
+            config: config,
+            enable_rules: try!(strings_field(source, "enable_rules")),
+            disable_rules: try!(strings_field(source, "disable_rules")),
+            starts_at: starts_at,
+        })
+    }
+
+    pub fn to_json(&self) -> JSON {
+        let config = self.config
+            .iter()
+            .map(|item| {
+                let mut map = BTreeMap::new();
+                map.insert("namespace".to_owned(), JSON::String(item.namespace.clone()));
+                map.insert("key".to_owned(), JSON::String(item.key.clone()));
+                map.insert("value".to_owned(), JSON::String(item.value.clone()));
+                JSON::Object(map)
+            })
+            .collect();
+        let starts_at = self.starts_at
+            .iter()
+            .map(|&(hours, minutes)| JSON::String(format!("{:02}:{:02}", hours, minutes)))
+            .collect();
+        let mut map = BTreeMap::new();
+        map.insert("name".to_owned(), JSON::String(self.name.clone()));
+        map.insert("config".to_owned(), JSON::Array(config));
+        map.insert("enable_rules".to_owned(), strings_json(&self.enable_rules));
+        map.insert("disable_rules".to_owned(), strings_json(&self.disable_rules));
+        map.insert("starts_at".to_owned(), JSON::Array(starts_at));
+        JSON::Object(map)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    modes: BTreeMap<String, Mode>,
+    current: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ModeRegistry {
+    /// Where the registry is persisted, if anywhere.
+    path: Option<PathBuf>,
+    state: Arc<Mutex<State>>,
+}
+
+impl ModeRegistry {
+    /// A registry persisted in a file, loaded if it exists.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let mut state = State::default();
+        if let Some(ref path) = path {
+            let mut source = String::new();
+            if File::open(path).and_then(|mut file| file.read_to_string(&mut source)).is_ok() {
+                match serde_json::from_str::<JSON>(&source) {
+                    Ok(ref json) if json.is_object() => {
+                        if let Some(&JSON::Array(ref items)) = json.find("modes") {
+                            for item in items {
+                                match Mode::from_json(item) {
+                                    Ok(mode) => {
+                                        state.modes.insert(mode.name.clone(), mode);
+                                    }
+                                    Err(err) => {
+                                        warn!("Ignoring mode in {}: {}", path.display(), err)
+                                    }
+                                }
+                            }
+                        }
+                        state.current = string_field(json, "current")
+                            .map(|current| current.to_owned())
+                            .and_then(|current| {
+                                if state.modes.contains_key(&current) {
+                                    Some(current)
+                                } else {
+                                    None
+                                }
+                            });
+                    }
+                    _ => error!("Ignoring invalid mode registry {}", path.display()),
+                }
+            }
+        }
+        ModeRegistry {
+            path: path,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn save(&self, state: &State) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let mut map = BTreeMap::new();
+        map.insert("modes".to_owned(),
+                   JSON::Array(state.modes.values().map(Mode::to_json).collect()));
+        map.insert("current".to_owned(),
+                   state.current.clone().map_or(JSON::Null, JSON::String));
+        let json = JSON::Object(map);
+        let result = File::create(path)
+            .and_then(|mut file| file.write_all(serde_json::to_string(&json).unwrap().as_bytes()));
+        if let Err(err) = result {
+            error!("Could not save the mode registry {}: {}", path.display(), err);
+        }
+    }
+
+    /// Define a mode, replacing any mode with the same name. If it is the current mode, the
+    /// new definition applies the next time the mode is entered.
+    pub fn put(&self, mode: Mode) {
+        let mut state = self.state.lock().unwrap();
+        state.modes.insert(mode.name.clone(), mode);
+        self.save(&state);
+    }
+
+    /// Returns `false` if there was no such mode, or if it is the current mode.
+    pub fn remove(&self, name: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.current.as_ref().map_or(false, |current| current == name) {
+            return false;
+        }
+        let removed = state.modes.remove(name).is_some();
+        if removed {
+            self.save(&state);
+        }
+        removed
+    }
+
+    pub fn get(&self, name: &str) -> Option<Mode> {
+        self.state.lock().unwrap().modes.get(name).cloned()
+    }
+
+    pub fn modes(&self) -> Vec<Mode> {
+        self.state.lock().unwrap().modes.values().cloned().collect()
+    }
+
+    /// The name of the current mode, if any.
+    pub fn current(&self) -> Option<String> {
+        self.state.lock().unwrap().current.clone()
+    }
+
+    /// Record the current mode. Returns `false` if there is no such mode.
+    pub fn set_current(&self, name: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.modes.contains_key(name) {
+            return false;
+        }
+        state.current = Some(name.to_owned());
+        self.save(&state);
+        true
+    }
+
+    /// The modes to enter at a time of day.
+    pub fn starting_at(&self, hours: u32, minutes: u32) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .modes
+            .values()
+            .filter(|mode| mode.starts_at.contains(&(hours, minutes)))
+            .map(|mode| mode.name.clone())
+            .collect()
+    }
+}
+
+#[test]
+fn test_mode_registry() {
+    use tempdir::TempDir;
+
+    let dir = TempDir::new("modes").unwrap();
+    let path = dir.path().join("modes.json");
+    let registry = ModeRegistry::new(Some(path.clone()));
+    assert!(registry.modes().is_empty());
+    assert!(!registry.set_current("vacation"));
+
+    let mode = Mode::from_json(&serde_json::from_str(r#"{"name": "vacation",
+        "config": [{"namespace": "occupancy", "key": "simulate", "value": "true"}],
+        "disable_rules": ["morning-lights"], "starts_at": ["08:30"]}"#)
+            .unwrap())
+        .unwrap();
+    assert_eq!(mode.starts_at, vec![(8, 30)]);
+    assert_eq!(Mode::from_json(&mode.to_json()), Ok(mode.clone()));
+    registry.put(mode.clone());
+    assert_eq!(registry.starting_at(8, 30), vec!["vacation".to_owned()]);
+    assert!(registry.starting_at(8, 31).is_empty());
+
+    assert!(registry.set_current("vacation"));
+    // The current mode can't be removed.
+    assert!(!registry.remove("vacation"));
+
+    // The registry survives restarts.
+    let restarted = ModeRegistry::new(Some(path.clone()));
+    assert_eq!(restarted.modes(), vec![mode]);
+    assert_eq!(restarted.current(), Some("vacation".to_owned()));
+}
+
+#[test]
+fn test_mode_from_json_checks_fields() {
+    let parse = |source| Mode::from_json(&serde_json::from_str(source).unwrap());
+    assert!(parse(r#"{"name": "home"}"#).is_ok());
+    assert!(parse(r#"{"enable_rules": ["a"]}"#).is_err());
+    assert!(parse(r#"{"name": "home", "starts_at": ["25:00"]}"#).is_err());
+    assert!(parse(r#"{"name": "home", "enable_rules": [1]}"#).is_err());
+    assert!(parse(r#"{"name": "home", "config": [{"namespace": "a", "key": "b"}]}"#).is_err());
+    assert!(parse(r#"{"name": "home", "config": [{"namespace": "presence", "key": "enabled",
+                                                  "value": "true"}]}"#)
+        .is_ok());
+    assert!(parse(r#"{"name": "home", "config": [{"namespace": "supervisor",
+                                                  "key": "zwave.command", "value": "sh"}]}"#)
+        .is_err());
+}
@@ -0,0 +1,488 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! OAuth2 credentials for the adapters of cloud services, e.g. Nest, Netatmo or Spotify.
+//!
+//! An adapter registers its `Provider` with the broker, then a user links the box to their
+//! account with the authorization-code flow:
+//!
+//! 1. the UI calls `begin` (through `POST /oauth2/providers/<name>/authorize`) and sends the
+//!    user to the consent page of the provider;
+//! 2. the provider redirects the user to the box, which calls `complete` to exchange the code
+//!    for tokens.
+//!
+//! Tokens are persisted in the profile and refreshed when they expire, so adapters only ever
+//! call `access_token`.
+
+use hyper;
+use hyper::header::{Connection, ContentType};
+use rand::Rng;
+use rand::os::OsRng;
+use serde_json;
+use serde_json::value::Value as JSON;
+use url::Url;
+use url::form_urlencoded;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Tokens are refreshed a bit before they expire, so that they don't expire in flight.
+const EXPIRY_MARGIN_S: u64 = 60;
+
+/// How long a user has to go through the consent page of a provider.
+const PENDING_TIMEOUT_S: u64 = 600;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Provider {
+    /// Unique on the box, e.g. "netatmo".
+    pub name: String,
+    /// The consent page of the provider.
+    pub authorize_url: String,
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// Seconds since the epoch, if the token expires.
+    pub expires_at: Option<u64>,
+}
+
+impl Token {
+    /// Parse the successful response of a token endpoint (RFC 6749, section 5.1).
+    fn from_response(json: &JSON, now: u64) -> Result<Self, OAuth2Error> {
+        let access_token = try!(json.find("access_token")
+            .and_then(JSON::as_string)
+            .ok_or_else(|| OAuth2Error::Provider("Missing field `access_token`".to_owned())));
+        Ok(Token {
+            access_token: access_token.to_owned(),
+            refresh_token: json.find("refresh_token")
+                .and_then(JSON::as_string)
+                .map(str::to_owned),
+            expires_at: json.find("expires_in").and_then(JSON::as_u64).map(|secs| now + secs),
+        })
+    }
+
+    fn from_json(json: &JSON) -> Option<Self> {
+        json.find("access_token").and_then(JSON::as_string).map(|access_token| {
+            Token {
+                access_token: access_token.to_owned(),
+                refresh_token: json.find("refresh_token")
+                    .and_then(JSON::as_string)
+                    .map(str::to_owned),
+                expires_at: json.find("expires_at").and_then(JSON::as_u64),
+            }
+        })
+    }
+
+    fn to_json(&self) -> JSON {
+        let mut map = BTreeMap::new();
+        map.insert("access_token".to_owned(),
+                   JSON::String(self.access_token.clone()));
+        map.insert("refresh_token".to_owned(),
+                   self.refresh_token.clone().map_or(JSON::Null, JSON::String));
+        map.insert("expires_at".to_owned(),
+                   self.expires_at.map_or(JSON::Null, JSON::U64));
+        JSON::Object(map)
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= now + EXPIRY_MARGIN_S)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OAuth2Error {
+    UnknownProvider(String),
+    /// The user hasn't linked the box to their account, or the link was revoked.
+    NotAuthorized(String),
+    /// The redirection doesn't match an authorization in progress.
+    InvalidState,
+    /// The provider refused, e.g. `invalid_grant`.
+    Provider(String),
+    Network(String),
+}
+
+impl fmt::Display for OAuth2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OAuth2Error::UnknownProvider(ref name) => write!(f, "Unknown provider {}", name),
+            OAuth2Error::NotAuthorized(ref name) => {
+                write!(f, "The box is not linked to an account of {}", name)
+            }
+            OAuth2Error::InvalidState => write!(f, "No such authorization in progress"),
+            OAuth2Error::Provider(ref err) => write!(f, "The provider refused: {}", err),
+            OAuth2Error::Network(ref err) => write!(f, "Cannot reach the provider: {}", err),
+        }
+    }
+}
+
+/// An authorization waiting for the user to come back from the consent page.
+struct Pending {
+    provider: String,
+    redirect_uri: String,
+    started_at: u64,
+}
+
+#[derive(Default)]
+struct State {
+    providers: BTreeMap<String, Provider>,
+    tokens: BTreeMap<String, Token>,
+    /// By `state` parameter.
+    pending: HashMap<String, Pending>,
+}
+
+#[derive(Clone)]
+pub struct OAuth2Broker {
+    /// Where the tokens are persisted, if anywhere.
+    path: Option<PathBuf>,
+    state: Arc<Mutex<State>>,
+    /// Held while refreshing a token, so that concurrent callers don't each spend the refresh
+    /// token.
+    refreshing: Arc<Mutex<()>>,
+}
+
+impl OAuth2Broker {
+    /// A broker persisting its tokens in a file, loaded if it exists.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let mut state = State::default();
+        if let Some(ref path) = path {
+            let mut source = String::new();
+            if fs::File::open(path).and_then(|mut file| file.read_to_string(&mut source)).is_ok() {
+                match serde_json::from_str::<JSON>(&source) {
+                    Ok(JSON::Object(tokens)) => {
+                        for (provider, token) in tokens {
+                            if let Some(token) = Token::from_json(&token) {
+                                state.tokens.insert(provider, token);
+                            }
+                        }
+                    }
+                    _ => error!("Ignoring invalid OAuth2 tokens {}", path.display()),
+                }
+            }
+        }
+        OAuth2Broker {
+            path: path,
+            state: Arc::new(Mutex::new(state)),
+            refreshing: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn save(&self, tokens: &BTreeMap<String, Token>) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let json = JSON::Object(tokens.iter()
+            .map(|(provider, token)| (provider.clone(), token.to_json()))
+            .collect());
+        let serialized = serde_json::to_string(&json).unwrap();
+
+        // The tokens grant access to the accounts of the user, only the daemon may read them.
+        let tmp = path.with_extension("tmp");
+        let result = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut file| file.write_all(serialized.as_bytes()))
+            .and_then(|_| fs::rename(&tmp, path));
+        if let Err(err) = result {
+            error!("Could not save the OAuth2 tokens {}: {}", path.display(), err);
+        }
+    }
+
+    /// Register a provider, replacing any provider with the same name. Its tokens, if any, are
+    /// kept.
+    pub fn register_provider(&self, provider: Provider) {
+        let mut state = self.state.lock().unwrap();
+        state.providers.insert(provider.name.clone(), provider);
+    }
+
+    /// The providers, and whether the box is linked to an account of each.
+    pub fn to_json(&self) -> JSON {
+        let state = self.state.lock().unwrap();
+        JSON::Array(state.providers
+            .keys()
+            .map(|name| {
+                let token = state.tokens.get(name);
+                let mut map = BTreeMap::new();
+                map.insert("name".to_owned(), JSON::String(name.clone()));
+                map.insert("authorized".to_owned(), JSON::Bool(token.is_some()));
+                map.insert("expires_at".to_owned(),
+                           token.and_then(|token| token.expires_at).map_or(JSON::Null, JSON::U64));
+                JSON::Object(map)
+            })
+            .collect())
+    }
+
+    /// Start linking the box to an account. Returns the url of the consent page, which will
+    /// redirect the user to `redirect_uri`.
+    pub fn begin(&self, provider: &str, redirect_uri: &str) -> Result<String, OAuth2Error> {
+        let mut bytes = [0u8; 16];
+        OsRng::new().expect("No source of randomness").fill_bytes(&mut bytes);
+        let nonce: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        let mut state = self.state.lock().unwrap();
+        let url = {
+            let provider = try!(state.providers
+                .get(provider)
+                .ok_or_else(|| OAuth2Error::UnknownProvider(provider.to_owned())));
+            try!(Url::parse_with_params(&provider.authorize_url,
+                                        &[("response_type", "code"),
+                                          ("client_id", &provider.client_id[..]),
+                                          ("redirect_uri", redirect_uri),
+                                          ("scope", &provider.scopes.join(" ")[..]),
+                                          ("state", &nonce[..])])
+                .map_err(|err| OAuth2Error::Provider(format!("{}", err))))
+        };
+
+        let now = now();
+        let expired: Vec<String> = state.pending
+            .iter()
+            .filter(|&(_, pending)| pending.started_at + PENDING_TIMEOUT_S <= now)
+            .map(|(nonce, _)| nonce.clone())
+            .collect();
+        for nonce in expired {
+            state.pending.remove(&nonce);
+        }
+        state.pending.insert(nonce,
+                             Pending {
+                                 provider: provider.to_owned(),
+                                 redirect_uri: redirect_uri.to_owned(),
+                                 started_at: now,
+                             });
+        Ok(url.into_string())
+    }
+
+    /// Forget an authorization in progress, e.g. because the user declined. Returns the
+    /// provider, if any.
+    pub fn cancel(&self, nonce: &str) -> Option<String> {
+        self.state.lock().unwrap().pending.remove(nonce).map(|pending| pending.provider)
+    }
+
+    /// Exchange the code the provider sent with the user for tokens. Returns the provider.
+    pub fn complete(&self, nonce: &str, code: &str) -> Result<String, OAuth2Error> {
+        let (provider, redirect_uri) = {
+            let mut state = self.state.lock().unwrap();
+            let pending = try!(state.pending.remove(nonce).ok_or(OAuth2Error::InvalidState));
+            if pending.started_at + PENDING_TIMEOUT_S <= now() {
+                return Err(OAuth2Error::InvalidState);
+            }
+            let provider = try!(state.providers
+                .get(&pending.provider)
+                .cloned()
+                .ok_or(OAuth2Error::UnknownProvider(pending.provider)));
+            (provider, pending.redirect_uri)
+        };
+        let token = try!(request_token(&provider,
+                                       &[("grant_type", "authorization_code"),
+                                         ("code", code),
+                                         ("redirect_uri", &redirect_uri[..])]));
+        info!("Linked the box to an account of {}", provider.name);
+        self.set_token(&provider.name, token);
+        Ok(provider.name)
+    }
+
+    fn set_token(&self, provider: &str, token: Token) {
+        let mut state = self.state.lock().unwrap();
+        state.tokens.insert(provider.to_owned(), token);
+        self.save(&state.tokens);
+    }
+
+    /// A valid access token for the account of the user, refreshed if needed.
+    pub fn access_token(&self, provider: &str) -> Result<String, OAuth2Error> {
+        let _refreshing = self.refreshing.lock().unwrap();
+        let (descriptor, token) = {
+            let state = self.state.lock().unwrap();
+            let descriptor = try!(state.providers
+                .get(provider)
+                .cloned()
+                .ok_or_else(|| OAuth2Error::UnknownProvider(provider.to_owned())));
+            let token = try!(state.tokens
+                .get(provider)
+                .cloned()
+                .ok_or_else(|| OAuth2Error::NotAuthorized(provider.to_owned())));
+            (descriptor, token)
+        };
+        if !token.is_expired(now()) {
+            return Ok(token.access_token);
+        }
+        let refresh_token = try!(token.refresh_token
+            .ok_or_else(|| OAuth2Error::NotAuthorized(provider.to_owned())));
+
+        debug!("Refreshing the OAuth2 token of {}", provider);
+        let mut refreshed = match request_token(&descriptor,
+                                                &[("grant_type", "refresh_token"),
+                                                  ("refresh_token", &refresh_token[..])]) {
+            Ok(token) => token,
+            Err(OAuth2Error::Provider(ref err)) if err == "invalid_grant" => {
+                // The refresh token was revoked, the user has to link the account again.
+                warn!("Could not refresh the OAuth2 token of {}: {}", provider, err);
+                self.revoke(provider);
+                return Err(OAuth2Error::NotAuthorized(provider.to_owned()));
+            }
+            Err(err) => return Err(err),
+        };
+        // Providers may keep the same refresh token without sending it again.
+        if refreshed.refresh_token.is_none() {
+            refreshed.refresh_token = Some(refresh_token);
+        }
+        let access_token = refreshed.access_token.clone();
+        self.set_token(provider, refreshed);
+        Ok(access_token)
+    }
+
+    /// Forget the tokens of a provider. Returns `false` if there were none.
+    pub fn revoke(&self, provider: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let removed = state.tokens.remove(provider).is_some();
+        if removed {
+            self.save(&state.tokens);
+        }
+        removed
+    }
+}
+
+/// Call the token endpoint of a provider (RFC 6749, section 4.1.3 and 6).
+fn request_token(provider: &Provider, params: &[(&str, &str)]) -> Result<Token, OAuth2Error> {
+    let body = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("client_secret", &provider.client_secret)
+        .finish();
+    let mut response = try!(hyper::Client::new()
+        .post(&provider.token_url)
+        .header(ContentType::form_url_encoded())
+        .header(Connection::close())
+        .body(&body[..])
+        .send()
+        .map_err(|err| OAuth2Error::Network(format!("{}", err))));
+    let mut source = String::new();
+    try!(response.read_to_string(&mut source)
+        .map_err(|err| OAuth2Error::Network(format!("{}", err))));
+
+    let json: JSON = try!(serde_json::from_str(&source).map_err(|_| {
+        OAuth2Error::Provider(format!("{} returned {}", provider.token_url, response.status))
+    }));
+    if let Some(error) = json.find("error").and_then(JSON::as_string) {
+        return Err(OAuth2Error::Provider(error.to_owned()));
+    }
+    if !response.status.is_success() {
+        return Err(OAuth2Error::Provider(format!("{} returned {}",
+                                                 provider.token_url,
+                                                 response.status)));
+    }
+    Token::from_response(&json, now())
+}
+
+#[cfg(test)]
+fn test_provider() -> Provider {
+    Provider {
+        name: "cloud".to_owned(),
+        authorize_url: "https://cloud.example.com/oauth2/authorize".to_owned(),
+        token_url: "https://cloud.example.com/oauth2/token".to_owned(),
+        client_id: "box".to_owned(),
+        client_secret: "secret".to_owned(),
+        scopes: vec!["read".to_owned(), "write".to_owned()],
+    }
+}
+
+#[test]
+fn test_token_from_response() {
+    let json = serde_json::from_str(r#"{"access_token": "a", "token_type": "bearer",
+                                        "expires_in": 3600, "refresh_token": "r"}"#)
+        .unwrap();
+    let token = Token::from_response(&json, 1000).unwrap();
+    assert_eq!(token,
+               Token {
+                   access_token: "a".to_owned(),
+                   refresh_token: Some("r".to_owned()),
+                   expires_at: Some(4600),
+               });
+    assert!(!token.is_expired(1000));
+    assert!(token.is_expired(4600 - EXPIRY_MARGIN_S));
+    assert_eq!(Token::from_json(&token.to_json()), Some(token));
+
+    let json = serde_json::from_str(r#"{"token_type": "bearer"}"#).unwrap();
+    assert!(Token::from_response(&json, 1000).is_err());
+}
+
+#[test]
+fn test_oauth2_broker_begin() {
+    let broker = OAuth2Broker::new(None);
+    assert_eq!(broker.begin("cloud", "https://box.local/oauth2/callback"),
+               Err(OAuth2Error::UnknownProvider("cloud".to_owned())));
+
+    broker.register_provider(test_provider());
+    let url = Url::parse(&broker.begin("cloud", "https://box.local/oauth2/callback").unwrap())
+        .unwrap();
+    let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+    assert_eq!(params["client_id"], "box");
+    assert_eq!(params["redirect_uri"], "https://box.local/oauth2/callback");
+    assert_eq!(params["scope"], "read write");
+
+    // The state identifies the authorization, once.
+    assert_eq!(broker.complete("not the state", "code"),
+               Err(OAuth2Error::InvalidState));
+    assert_eq!(broker.cancel(&params["state"]), Some("cloud".to_owned()));
+    assert_eq!(broker.complete(&params["state"], "code"),
+               Err(OAuth2Error::InvalidState));
+}
+
+#[test]
+fn test_oauth2_broker_tokens() {
+    use tempdir::TempDir;
+
+    let dir = TempDir::new("oauth2").unwrap();
+    let path = dir.path().join("oauth2_tokens.json");
+    let broker = OAuth2Broker::new(Some(path.clone()));
+    broker.register_provider(test_provider());
+    assert_eq!(broker.access_token("cloud"),
+               Err(OAuth2Error::NotAuthorized("cloud".to_owned())));
+
+    broker.set_token("cloud",
+                     Token {
+                         access_token: "a".to_owned(),
+                         refresh_token: None,
+                         expires_at: Some(now() + 3600),
+                     });
+    assert_eq!(broker.access_token("cloud"), Ok("a".to_owned()));
+
+    // The tokens survive restarts, providers are registered again by their adapters.
+    let restarted = OAuth2Broker::new(Some(path.clone()));
+    assert_eq!(restarted.access_token("cloud"),
+               Err(OAuth2Error::UnknownProvider("cloud".to_owned())));
+    restarted.register_provider(test_provider());
+    assert_eq!(restarted.access_token("cloud"), Ok("a".to_owned()));
+
+    // An expired token without a refresh token can't be used.
+    restarted.set_token("cloud",
+                        Token {
+                            access_token: "a".to_owned(),
+                            refresh_token: None,
+                            expires_at: Some(now()),
+                        });
+    assert_eq!(restarted.access_token("cloud"),
+               Err(OAuth2Error::NotAuthorized("cloud".to_owned())));
+
+    assert!(restarted.revoke("cloud"));
+    assert!(!restarted.revoke("cloud"));
+    assert!(OAuth2Broker::new(Some(path)).to_json().as_array().unwrap().is_empty());
+}
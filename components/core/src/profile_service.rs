@@ -67,6 +67,11 @@ impl ProfileService {
         ProfileService { profile_path: dir }
     }
 
+    // Returns the profile directory.
+    pub fn directory(&self) -> &str {
+        &self.profile_path
+    }
+
     // Returns an absolute path for a file.
     // This doesn't try to create the file.
     pub fn path_for(&self, relative_path: &str) -> String {
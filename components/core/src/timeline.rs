@@ -0,0 +1,190 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A bounded, in-memory history of what happened on the box: values reported by devices, rules
+//! executed, actions of users and notifications. This powers "what happened today" screens,
+//! without clients having to stay connected to the websocket all day.
+
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A channel reported a value.
+    Event,
+    /// A rule sent values to channels.
+    Rule,
+    /// A user sent values to channels.
+    Action,
+    /// An adapter notified the user.
+    Notification,
+}
+
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            EntryKind::Event => "event",
+            EntryKind::Rule => "rule",
+            EntryKind::Action => "action",
+            EntryKind::Notification => "notification",
+        }
+    }
+
+    pub fn parse(source: &str) -> Option<Self> {
+        match source {
+            "event" => Some(EntryKind::Event),
+            "rule" => Some(EntryKind::Rule),
+            "action" => Some(EntryKind::Action),
+            "notification" => Some(EntryKind::Notification),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Milliseconds since the epoch.
+    pub timestamp: u64,
+    pub kind: EntryKind,
+    /// The channels involved, if any.
+    pub channels: Vec<String>,
+    /// The user who caused this entry, if any.
+    pub user: Option<String>,
+    pub details: JSON,
+}
+
+impl Entry {
+    pub fn new(kind: EntryKind,
+               channels: Vec<String>,
+               user: Option<String>,
+               details: JSON)
+               -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0));
+        Entry {
+            timestamp: now.as_secs() * 1000 + (now.subsec_nanos() / 1_000_000) as u64,
+            kind: kind,
+            channels: channels,
+            user: user,
+            details: details,
+        }
+    }
+
+    pub fn to_json(&self) -> JSON {
+        let mut map = BTreeMap::new();
+        map.insert("timestamp".to_owned(), JSON::U64(self.timestamp));
+        map.insert("kind".to_owned(), JSON::String(self.kind.as_str().to_owned()));
+        map.insert("channels".to_owned(),
+                   JSON::Array(self.channels.iter().cloned().map(JSON::String).collect()));
+        map.insert("user".to_owned(), self.user.clone().map_or(JSON::Null, JSON::String));
+        map.insert("details".to_owned(), self.details.clone());
+        JSON::Object(map)
+    }
+}
+
+/// Which entries to return.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    /// Only entries of these kinds. Empty for all kinds.
+    pub kinds: Vec<EntryKind>,
+    /// Only entries involving one of these channels.
+    pub channels: Option<HashSet<String>>,
+    /// Only entries caused by this user.
+    pub user: Option<String>,
+    /// Only entries more recent than this timestamp (in milliseconds since the epoch).
+    pub since: Option<u64>,
+    /// At most this many entries. `0` for no limit.
+    pub limit: usize,
+}
+
+impl Filter {
+    fn matches(&self, entry: &Entry) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&entry.kind)) &&
+        self.channels.as_ref().map_or(true, |channels| {
+            entry.channels.iter().any(|channel| channels.contains(channel))
+        }) && self.user.as_ref().map_or(true, |user| entry.user.as_ref() == Some(user)) &&
+        self.since.map_or(true, |since| entry.timestamp > since)
+    }
+}
+
+#[derive(Clone)]
+pub struct Timeline {
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<Entry>>>,
+}
+
+impl Timeline {
+    pub fn new(capacity: usize) -> Self {
+        Timeline {
+            capacity: capacity,
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Record an entry, dropping the oldest one if the timeline is full.
+    pub fn push(&self, entry: Entry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The entries matching a filter, most recent first.
+    pub fn query(&self, filter: &Filter) -> Vec<Entry> {
+        let entries = self.entries.lock().unwrap();
+        let matching = entries.iter().rev().filter(|entry| filter.matches(entry)).cloned();
+        if filter.limit == 0 {
+            matching.collect()
+        } else {
+            matching.take(filter.limit).collect()
+        }
+    }
+}
+
+#[test]
+fn test_timeline_is_bounded_and_reverse_chronological() {
+    let timeline = Timeline::new(2);
+    for kind in &[EntryKind::Event, EntryKind::Rule, EntryKind::Action] {
+        timeline.push(Entry::new(*kind, vec![], None, JSON::Null));
+    }
+    let kinds: Vec<_> = timeline.query(&Filter::default())
+        .iter()
+        .map(|entry| entry.kind)
+        .collect();
+    assert_eq!(kinds, vec![EntryKind::Action, EntryKind::Rule]);
+}
+
+#[test]
+fn test_timeline_filters() {
+    let timeline = Timeline::new(10);
+    timeline.push(Entry::new(EntryKind::Event, vec!["door".to_owned()], None, JSON::Null));
+    timeline.push(Entry::new(EntryKind::Action,
+                             vec!["light".to_owned()],
+                             Some("alice".to_owned()),
+                             JSON::Null));
+    timeline.push(Entry::new(EntryKind::Notification, vec![], None, JSON::Null));
+
+    let mut channels = HashSet::new();
+    channels.insert("light".to_owned());
+    let filter = Filter { channels: Some(channels), ..Filter::default() };
+    assert_eq!(timeline.query(&filter).len(), 1);
+
+    let filter = Filter { user: Some("bob".to_owned()), ..Filter::default() };
+    assert!(timeline.query(&filter).is_empty());
+
+    let filter = Filter {
+        kinds: vec![EntryKind::Event, EntryKind::Notification],
+        limit: 1,
+        ..Filter::default()
+    };
+    let entries = timeline.query(&filter);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].kind, EntryKind::Notification);
+    assert_eq!(EntryKind::parse("rule"), Some(EntryKind::Rule));
+}
@@ -2,8 +2,13 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use adapter_status::AdapterStatuses;
+use app_registry::AppRegistry;
 use config_store::ConfigService;
 use foxbox_users::UsersManager;
+use log_buffer::LogBuffer;
+use modes::ModeRegistry;
+use oauth2::OAuth2Broker;
 use profile_service::ProfileService;
 use serde_json;
 use std::io;
@@ -11,9 +16,13 @@ use std::net::SocketAddr;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::vec::IntoIter;
+use thread_pool::ThreadPool;
+use timeline::Timeline;
 use tls::{CertificateRecord, CertificateManager};
 use upnp::UpnpManager;
+use watch_sets::WatchSetRegistry;
 use ws;
+use ws_trace::WsTraces;
 
 pub trait Controller: Send + Sync + Clone + 'static {
     fn run(&mut self, shutdown_flag: &AtomicBool);
@@ -28,12 +37,31 @@ pub trait Controller: Send + Sync + Clone + 'static {
     fn get_hostname(&self) -> String;
     fn get_domain(&self) -> String;
 
-    fn add_websocket(&mut self, socket: ws::Sender);
+    /// Register a websocket. If `binary` is `true`, the client accepts the binary components
+    /// of values as binary frames.
+    fn add_websocket(&mut self, socket: ws::Sender, binary: bool);
     fn remove_websocket(&mut self, socket: ws::Sender);
     fn broadcast_to_websockets(&self, data: serde_json::value::Value);
+    /// The recordings of the websockets opened with `trace=true`.
+    fn get_ws_traces(&self) -> WsTraces;
 
     fn get_config(&self) -> Arc<ConfigService>;
     fn get_upnp_manager(&self) -> Arc<UpnpManager>;
     fn get_users_manager(&self) -> Arc<UsersManager>;
     fn get_profile(&self) -> &ProfileService;
+    fn get_timeline(&self) -> Timeline;
+    /// The latest lines logged by the daemon.
+    fn get_log(&self) -> LogBuffer;
+    /// The threads shared by adapters for short-lived blocking work.
+    fn get_thread_pool(&self) -> ThreadPool;
+    /// Whether each adapter started.
+    fn get_adapter_statuses(&self) -> AdapterStatuses;
+    /// The companion apps, and how to open devices in them.
+    fn get_app_registry(&self) -> AppRegistry;
+    /// The global modes, e.g. "home" or "away".
+    fn get_modes(&self) -> ModeRegistry;
+    /// The OAuth2 credentials of the cloud services.
+    fn get_oauth2(&self) -> OAuth2Broker;
+    /// The sets of watches that websocket clients may follow as a unit.
+    fn get_watch_sets(&self) -> WatchSetRegistry;
 }
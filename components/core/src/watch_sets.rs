@@ -0,0 +1,270 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Named sets of watches, registered in a single REST call and followed as a unit over the
+//! websocket, e.g. all the doors and windows of a dashboard.
+//!
+//! This only keeps the definitions. Selectors and ranges are kept as JSON, checked by the
+//! router when the set is registered, and registered with the taxonomy when a websocket
+//! client resumes the set.
+
+use rand::Rng;
+use rand::os::OsRng;
+use serde_json;
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// One watch of a set: some channels, and the range of values to watch, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchSpec {
+    pub selector: JSON,
+    pub range: Option<JSON>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchSet {
+    pub id: String,
+    pub name: String,
+    pub watches: Vec<WatchSpec>,
+}
+
+impl WatchSpec {
+    /// Read a watch from an object `{"selector": selectors, "range"?: value}`.
+    pub fn from_json(source: &JSON) -> Result<Self, String> {
+        let selector = try!(source.find("selector").ok_or("Missing field `selector`"));
+        Ok(WatchSpec {
+            selector: selector.clone(),
+            range: match source.find("range") {
+                None | Some(&JSON::Null) => None,
+                Some(range) => Some(range.clone()),
+            },
+        })
+    }
+
+    pub fn to_json(&self) -> JSON {
+        let mut map = BTreeMap::new();
+        map.insert("selector".to_owned(), self.selector.clone());
+        map.insert("range".to_owned(), self.range.clone().unwrap_or(JSON::Null));
+        JSON::Object(map)
+    }
+}
+
+impl WatchSet {
+    /// Read a set from an object `{"id": string, "name": string, "watches": [watch]}`.
+    pub fn from_json(source: &JSON) -> Result<Self, String> {
+        let field = |field| {
+            source.find(field)
+                .and_then(JSON::as_string)
+                .map(|value| value.to_owned())
+                .ok_or(format!("Missing field `{}`", field))
+        };
+        Ok(WatchSet {
+            id: try!(field("id")),
+            name: try!(field("name")),
+            watches: try!(WatchSet::watches_from_json(source)),
+        })
+    }
+
+    /// Read the field `watches` of an object, which must hold at least one watch.
+    pub fn watches_from_json(source: &JSON) -> Result<Vec<WatchSpec>, String> {
+        match source.find("watches") {
+            Some(&JSON::Array(ref items)) if !items.is_empty() => {
+                items.iter().map(WatchSpec::from_json).collect()
+            }
+            Some(&JSON::Array(_)) => Err("Field `watches` is empty".to_owned()),
+            Some(_) => Err("Field `watches` is not an array".to_owned()),
+            None => Err("Missing field `watches`".to_owned()),
+        }
+    }
+
+    pub fn to_json(&self) -> JSON {
+        let mut map = BTreeMap::new();
+        map.insert("id".to_owned(), JSON::String(self.id.clone()));
+        map.insert("name".to_owned(), JSON::String(self.name.clone()));
+        map.insert("watches".to_owned(),
+                   JSON::Array(self.watches.iter().map(WatchSpec::to_json).collect()));
+        JSON::Object(map)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    sets: BTreeMap<String, WatchSet>,
+    /// Who to tell when a set is removed, by token: the id of the set, and the callback.
+    followers: HashMap<usize, (String, Box<Fn() + Send>)>,
+    next_token: usize,
+}
+
+#[derive(Clone)]
+pub struct WatchSetRegistry {
+    /// Where the registry is persisted, if anywhere.
+    path: Option<PathBuf>,
+    state: Arc<Mutex<State>>,
+}
+
+impl WatchSetRegistry {
+    /// A registry persisted in a file, loaded if it exists.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let mut state = State::default();
+        if let Some(ref path) = path {
+            let mut source = String::new();
+            if File::open(path).and_then(|mut file| file.read_to_string(&mut source)).is_ok() {
+                match serde_json::from_str::<JSON>(&source) {
+                    Ok(JSON::Array(items)) => {
+                        for item in items {
+                            match WatchSet::from_json(&item) {
+                                Ok(set) => {
+                                    state.sets.insert(set.id.clone(), set);
+                                }
+                                Err(err) => {
+                                    warn!("Ignoring watch set in {}: {}", path.display(), err)
+                                }
+                            }
+                        }
+                    }
+                    _ => error!("Ignoring invalid watch set registry {}", path.display()),
+                }
+            }
+        }
+        WatchSetRegistry {
+            path: path,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn save(&self, state: &State) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let json = JSON::Array(state.sets.values().map(WatchSet::to_json).collect());
+        let result = File::create(path)
+            .and_then(|mut file| file.write_all(serde_json::to_string(&json).unwrap().as_bytes()));
+        if let Err(err) = result {
+            error!("Could not save the watch set registry {}: {}", path.display(), err);
+        }
+    }
+
+    /// Register a set of watches, under a new id.
+    pub fn put(&self, name: &str, watches: Vec<WatchSpec>) -> WatchSet {
+        let mut bytes = [0u8; 8];
+        OsRng::new().expect("No source of randomness").fill_bytes(&mut bytes);
+        let id: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let set = WatchSet {
+            id: id.clone(),
+            name: name.to_owned(),
+            watches: watches,
+        };
+        let mut state = self.state.lock().unwrap();
+        state.sets.insert(id, set.clone());
+        self.save(&state);
+        set
+    }
+
+    pub fn get(&self, id: &str) -> Option<WatchSet> {
+        self.state.lock().unwrap().sets.get(id).cloned()
+    }
+
+    pub fn sets(&self) -> Vec<WatchSet> {
+        self.state.lock().unwrap().sets.values().cloned().collect()
+    }
+
+    /// Remove a set, and tell its followers. Returns `false` if there was no such set.
+    pub fn remove(&self, id: &str) -> bool {
+        let followers = {
+            let mut state = self.state.lock().unwrap();
+            if state.sets.remove(id).is_none() {
+                return false;
+            }
+            self.save(&state);
+            let tokens: Vec<_> = state.followers
+                .iter()
+                .filter(|&(_, &(ref set, _))| set == id)
+                .map(|(token, _)| *token)
+                .collect();
+            tokens.iter()
+                .filter_map(|token| state.followers.remove(token))
+                .collect::<Vec<_>>()
+        };
+        // Called without the lock, so that followers may use the registry.
+        for (_, on_removed) in followers {
+            on_removed();
+        }
+        true
+    }
+
+    /// Call `on_removed` once if the set is removed. Returns a token for `unfollow`, or `None`
+    /// if there is no such set.
+    pub fn follow(&self, id: &str, on_removed: Box<Fn() + Send>) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+        if !state.sets.contains_key(id) {
+            return None;
+        }
+        let token = state.next_token;
+        state.next_token += 1;
+        state.followers.insert(token, (id.to_owned(), on_removed));
+        Some(token)
+    }
+
+    pub fn unfollow(&self, token: usize) {
+        self.state.lock().unwrap().followers.remove(&token);
+    }
+}
+
+#[test]
+fn test_watch_set_registry() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempdir::TempDir;
+
+    let dir = TempDir::new("watch_sets").unwrap();
+    let path = dir.path().join("watch_sets.json");
+    let registry = WatchSetRegistry::new(Some(path.clone()));
+    assert!(registry.sets().is_empty());
+
+    let watches = WatchSet::watches_from_json(&serde_json::from_str(r#"{"watches": [
+        {"selector": {"feature": "door/is-open"}, "range": "Open"},
+        {"selector": [{"feature": "light/is-on"}]}]}"#)
+            .unwrap())
+        .unwrap();
+    assert_eq!(watches[1].range, None);
+    let set = registry.put("dashboard", watches);
+    assert_eq!(WatchSet::from_json(&set.to_json()), Ok(set.clone()));
+    assert_eq!(registry.get(&set.id), Some(set.clone()));
+
+    // The registry survives restarts.
+    let restarted = WatchSetRegistry::new(Some(path.clone()));
+    assert_eq!(restarted.sets(), vec![set.clone()]);
+
+    let removed = Arc::new(AtomicUsize::new(0));
+    let follow = |registry: &WatchSetRegistry| {
+        let removed = removed.clone();
+        registry.follow(&set.id,
+                    Box::new(move || {
+                        removed.fetch_add(1, Ordering::SeqCst);
+                    }))
+    };
+    assert_eq!(registry.follow("unknown", Box::new(|| {})), None);
+    follow(&registry).unwrap();
+    let token = follow(&registry).unwrap();
+    registry.unfollow(token);
+    assert!(registry.remove(&set.id));
+    assert!(!registry.remove(&set.id));
+    assert_eq!(removed.load(Ordering::SeqCst), 1);
+    assert!(registry.get(&set.id).is_none());
+}
+
+#[test]
+fn test_watch_set_from_json_checks_fields() {
+    let parse = |source| WatchSet::watches_from_json(&serde_json::from_str(source).unwrap());
+    assert!(parse(r#"{"watches": [{"selector": {}}]}"#).is_ok());
+    assert!(parse(r#"{"watches": []}"#).is_err());
+    assert!(parse(r#"{"watches": {}}"#).is_err());
+    assert!(parse(r#"{"watches": [{"range": "On"}]}"#).is_err());
+    assert!(parse(r#"{}"#).is_err());
+}
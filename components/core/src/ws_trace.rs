@@ -0,0 +1,340 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Opt-in recordings of the frames exchanged with a websocket client, to diagnose a
+//! misbehaving client app against the actual protocol traffic.
+//!
+//! A client connecting with `trace=true` in its query has its frames recorded in the profile,
+//! one JSON object per line: `{"time": milliseconds, "direction": "open" | "in" | "out",
+//! "frame": ...}`. Secrets (the `auth` token, fields such as `password` or `session_token`)
+//! are replaced with `"[redacted]"` before anything is written, and binary frames are only
+//! recorded by their size.
+
+use serde_json;
+use serde_json::value::Value as JSON;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use ws::util::Token;
+
+const REDACTED: &'static str = "[redacted]";
+
+/// A trace stops growing once it reaches this size, in bytes.
+const MAX_TRACE_SIZE: u64 = 4 * 1024 * 1024;
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() * 1000 + (now.subsec_nanos() / 1_000_000) as u64)
+        .unwrap_or(0)
+}
+
+fn is_secret(key: &str) -> bool {
+    let key = key.to_lowercase();
+    key == "auth" || key == "authorization" ||
+    ["token", "password", "secret"].iter().any(|secret| key.contains(secret))
+}
+
+/// Replace the values of the secret fields of `json`, at any depth.
+pub fn redact(json: JSON) -> JSON {
+    match json {
+        JSON::Object(map) => {
+            JSON::Object(map.into_iter()
+                .map(|(key, value)| if is_secret(&key) {
+                    (key, JSON::String(REDACTED.to_owned()))
+                } else {
+                    (key, redact(value))
+                })
+                .collect())
+        }
+        JSON::Array(items) => JSON::Array(items.into_iter().map(redact).collect()),
+        json => json,
+    }
+}
+
+/// Replace the values of the secret parameters of the query of `resource`, e.g. `auth`.
+pub fn redact_query(resource: &str) -> String {
+    let index = match resource.find('?') {
+        Some(index) => index,
+        None => return resource.to_owned(),
+    };
+    let params: Vec<String> = resource[index + 1..]
+        .split('&')
+        .map(|param| match param.find('=') {
+            Some(equal) if is_secret(&param[..equal]) => {
+                format!("{}={}", &param[..equal], REDACTED)
+            }
+            _ => param.to_owned(),
+        })
+        .collect();
+    format!("{}?{}", &resource[..index], params.join("&"))
+}
+
+/// Replace the secrets found in a line of text, e.g. a line of the log: the secret parameters of
+/// urls, the values of secret `key=value` or `key: value` pairs, and `Bearer` tokens.
+pub fn redact_line(line: &str) -> String {
+    let mut redact_next = false;
+    line.split(' ')
+        .map(|word| {
+            if redact_next && !word.is_empty() && word != "Bearer" && word != "Basic" {
+                redact_next = false;
+                return REDACTED.to_owned();
+            }
+            if word == "Bearer" || word == "Basic" {
+                redact_next = true;
+                return word.to_owned();
+            }
+            if word.contains('?') {
+                return redact_query(word);
+            }
+            match word.find(|c: char| c == '=' || c == ':') {
+                Some(index) if is_secret(word[..index]
+                    .trim_matches(|c: char| !c.is_alphanumeric() && c != '_')) => {
+                    if index + 1 == word.len() {
+                        redact_next = true;
+                        word.to_owned()
+                    } else {
+                        format!("{}{}", &word[..index + 1], REDACTED)
+                    }
+                }
+                _ => word.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// The client connected, the frame is the resource it asked for.
+    Open,
+    /// A frame sent by the client.
+    In,
+    /// A frame sent to the client.
+    Out,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Direction::Open => "open",
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+struct Trace {
+    file: File,
+    size: u64,
+}
+
+/// The traces of the websockets, shared by the websocket server and the controller, which both
+/// send frames to the clients.
+#[derive(Clone)]
+pub struct WsTraces {
+    dir: PathBuf,
+    traces: Arc<Mutex<HashMap<Token, Trace>>>,
+}
+
+impl WsTraces {
+    /// Traces are written to `dir`, which is created on the first trace.
+    pub fn new(dir: PathBuf) -> Self {
+        WsTraces {
+            dir: dir,
+            traces: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Start recording the frames of the websocket `token`, opened on `resource`. Returns the
+    /// name of the trace.
+    pub fn start(&self, token: Token, resource: &str) -> io::Result<String> {
+        try!(fs::create_dir_all(&self.dir));
+        let name = format!("{}-{}", now_ms(), token.as_usize());
+        let file = try!(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(format!("{}.jsonl", name))));
+        self.traces.lock().unwrap().insert(token,
+                                           Trace {
+                                               file: file,
+                                               size: 0,
+                                           });
+        self.write(token,
+                   Direction::Open,
+                   JSON::String(redact_query(resource)));
+        Ok(name)
+    }
+
+    /// Stop recording the frames of the websocket `token`, e.g. once it is closed.
+    pub fn stop(&self, token: Token) {
+        self.traces.lock().unwrap().remove(&token);
+    }
+
+    pub fn is_tracing(&self, token: Token) -> bool {
+        self.traces.lock().unwrap().contains_key(&token)
+    }
+
+    /// Record a text frame. Does nothing if the websocket isn't traced.
+    pub fn record(&self, token: Token, direction: Direction, frame: &str) {
+        if !self.is_tracing(token) {
+            return;
+        }
+        let frame = match serde_json::from_str::<JSON>(frame) {
+            Ok(json) => redact(json),
+            Err(_) => JSON::String(frame.to_owned()),
+        };
+        self.write(token, direction, frame);
+    }
+
+    /// Record a binary frame of `len` bytes. Does nothing if the websocket isn't traced.
+    pub fn record_binary(&self, token: Token, direction: Direction, len: usize) {
+        self.write(token,
+                   direction,
+                   JSON::String(format!("<{} bytes of binary data>", len)));
+    }
+
+    fn write(&self, token: Token, direction: Direction, frame: JSON) {
+        let mut traces = self.traces.lock().unwrap();
+        let trace = match traces.get_mut(&token) {
+            Some(trace) => trace,
+            None => return,
+        };
+        if trace.size >= MAX_TRACE_SIZE {
+            return;
+        }
+        let mut entry = BTreeMap::new();
+        entry.insert("time".to_owned(), JSON::U64(now_ms()));
+        entry.insert("direction".to_owned(),
+                     JSON::String(direction.as_str().to_owned()));
+        entry.insert("frame".to_owned(), frame);
+        let line = format!("{}\n",
+                           serde_json::to_string(&JSON::Object(entry)).unwrap_or("{}".to_owned()));
+        match trace.file.write_all(line.as_bytes()) {
+            Ok(()) => trace.size += line.len() as u64,
+            Err(err) => error!("Could not record websocket frame: {}", err),
+        }
+    }
+
+    fn path(&self, name: &str) -> Option<PathBuf> {
+        // Names are generated by `start`, anything else could escape `dir`.
+        if name.is_empty() || !name.chars().all(|c| c.is_digit(10) || c == '-') {
+            return None;
+        }
+        Some(self.dir.join(format!("{}.jsonl", name)))
+    }
+
+    /// The names of the traces, most recent first.
+    pub fn list(&self) -> Vec<String> {
+        let mut names: Vec<String> = match fs::read_dir(&self.dir) {
+            Ok(entries) => {
+                entries.filter_map(|entry| entry.ok())
+                    .filter_map(|entry| {
+                        entry.file_name()
+                            .to_str()
+                            .and_then(|name| if name.ends_with(".jsonl") {
+                                Some(name[..name.len() - ".jsonl".len()].to_owned())
+                            } else {
+                                None
+                            })
+                    })
+                    .collect()
+            }
+            Err(_) => vec![],
+        };
+        // Names start with the time at which they were started.
+        names.sort_by(|a, b| (b.len(), b).cmp(&(a.len(), a)));
+        names
+    }
+
+    /// The entries of trace `name`, or `None` if there is no such trace.
+    pub fn read(&self, name: &str) -> Option<Vec<JSON>> {
+        let mut source = String::new();
+        match self.path(name).map(File::open) {
+            Some(Ok(mut file)) => {
+                if file.read_to_string(&mut source).is_err() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+        Some(source.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+    }
+
+    /// Delete trace `name`. Returns `false` if there is no such trace.
+    pub fn remove(&self, name: &str) -> bool {
+        match self.path(name) {
+            Some(path) => fs::remove_file(path).is_ok(),
+            None => false,
+        }
+    }
+}
+
+#[test]
+fn test_redact() {
+    let json: JSON = serde_json::from_str(r#"{"type": "login", "password": "hunter2",
+                                              "user": {"session_token": "abc", "name": "Ann"},
+                                              "items": [{"secret": 1}]}"#)
+        .unwrap();
+    let redacted = redact(json);
+    assert_eq!(redacted.find("password"), Some(&JSON::String(REDACTED.to_owned())));
+    assert_eq!(redacted.lookup("user.session_token"),
+               Some(&JSON::String(REDACTED.to_owned())));
+    assert_eq!(redacted.lookup("user.name"), Some(&JSON::String("Ann".to_owned())));
+    assert_eq!(redacted.lookup("items.0.secret"),
+               Some(&JSON::String(REDACTED.to_owned())));
+    assert_eq!(redacted.find("type"), Some(&JSON::String("login".to_owned())));
+
+    assert_eq!(redact_query("/?auth=abc&binary=true&trace=true"),
+               "/?auth=[redacted]&binary=true&trace=true");
+    assert_eq!(redact_query("/"), "/");
+
+    assert_eq!(redact_line("GET /?auth=abc&trace=true"), "GET /?auth=[redacted]&trace=true");
+    assert_eq!(redact_line("Authorization: Bearer abc.def"),
+               "Authorization: Bearer [redacted]");
+    assert_eq!(redact_line(r#"{"password": "hunter2", "name": "Ann"}"#),
+               r#"{"password": [redacted] "name": "Ann"}"#);
+    assert_eq!(redact_line("client_secret=abc url=http://example.org"),
+               "client_secret=[redacted] url=http://example.org");
+    assert_eq!(redact_line("Adapter started"), "Adapter started");
+}
+
+#[test]
+fn test_ws_traces() {
+    use tempdir::TempDir;
+
+    let dir = TempDir::new("ws_traces").unwrap();
+    let traces = WsTraces::new(dir.path().join("ws_traces"));
+    let traced = Token(1);
+    let other = Token(2);
+    assert!(traces.list().is_empty());
+
+    let name = traces.start(traced, "/?auth=abc&trace=true").unwrap();
+    assert!(traces.is_tracing(traced));
+    assert!(!traces.is_tracing(other));
+    traces.record(traced, Direction::In, r#"{"type": "keepalive"}"#);
+    traces.record(other, Direction::In, r#"{"type": "keepalive"}"#);
+    traces.record_binary(traced, Direction::Out, 42);
+    traces.stop(traced);
+    traces.record(traced, Direction::Out, r#"{"type": "keepalive"}"#);
+
+    assert_eq!(traces.list(), vec![name.clone()]);
+    let entries = traces.read(&name).unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].find("direction"), Some(&JSON::String("open".to_owned())));
+    assert_eq!(entries[0].find("frame"),
+               Some(&JSON::String("/?auth=[redacted]&trace=true".to_owned())));
+    assert_eq!(entries[1].lookup("frame.type"),
+               Some(&JSON::String("keepalive".to_owned())));
+    assert_eq!(entries[2].find("frame"),
+               Some(&JSON::String("<42 bytes of binary data>".to_owned())));
+
+    assert_eq!(traces.read("../users_db"), None);
+    assert!(traces.remove(&name));
+    assert!(!traces.remove(&name));
+    assert!(traces.list().is_empty());
+}
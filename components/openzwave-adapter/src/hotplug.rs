@@ -0,0 +1,68 @@
+//! Detect the Z-Wave controllers that are plugged or unplugged while the box runs.
+//!
+//! udev isn't available on every platform we run on, so the device nodes are simply polled.
+
+use std::fs;
+use std::path::Path;
+
+/// The prefixes of the device nodes of USB Z-Wave controllers, on Linux and OS X.
+const DEVICE_PREFIXES: [&'static str; 5] = ["ttyACM",
+                                            "ttyUSB",
+                                            "cu.usbmodem",
+                                            "cu.usbserial",
+                                            "cu.SLAB_USBtoUART"];
+
+/// The Z-Wave controllers currently plugged, sorted. If devices are `configured`, only those
+/// are looked for, otherwise all the device nodes of `dev_dir` that look like a USB
+/// controller.
+pub fn present_devices(dev_dir: &Path, configured: Option<&[String]>) -> Vec<String> {
+    let mut devices: Vec<String> = match configured {
+        Some(configured) => {
+            configured.iter().filter(|device| Path::new(device).exists()).cloned().collect()
+        }
+        None => {
+            match fs::read_dir(dev_dir) {
+                Ok(entries) => {
+                    entries.filter_map(|entry| entry.ok())
+                        .filter(|entry| {
+                            entry.file_name()
+                                .to_str()
+                                .map_or(false, |name| {
+                                    DEVICE_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+                                })
+                        })
+                        .filter_map(|entry| entry.path().to_str().map(str::to_owned))
+                        .collect()
+                }
+                Err(_) => vec![],
+            }
+        }
+    };
+    devices.sort();
+    devices
+}
+
+#[test]
+fn test_present_devices() {
+    use std::env;
+    use std::fs::File;
+
+    let dev_dir = env::temp_dir().join("ozw-hotplug-test");
+    let _ = fs::remove_dir_all(&dev_dir);
+    fs::create_dir_all(&dev_dir).unwrap();
+    assert!(present_devices(&dev_dir, None).is_empty());
+
+    for name in &["ttyUSB0", "ttyACM0", "tty0", "null"] {
+        File::create(dev_dir.join(name)).unwrap();
+    }
+    let stick = dev_dir.join("ttyUSB0").to_str().unwrap().to_owned();
+    let modem = dev_dir.join("ttyACM0").to_str().unwrap().to_owned();
+    assert_eq!(present_devices(&dev_dir, None), vec![modem.clone(), stick.clone()]);
+
+    // Configured devices are looked for, wherever they are.
+    let configured = vec![stick.clone(), dev_dir.join("ttyUSB1").to_str().unwrap().to_owned()];
+    assert_eq!(present_devices(&dev_dir, Some(&configured)), vec![stick]);
+
+    fs::remove_dir_all(&dev_dir).unwrap();
+    assert!(present_devices(&dev_dir, None).is_empty());
+}
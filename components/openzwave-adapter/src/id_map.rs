@@ -1,10 +1,54 @@
 use taxonomy::util::Id as TaxoId;
 
+use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
+/// A map between taxonomy ids and OpenZWave objects. Several OpenZWave objects may share the
+/// same taxonomy id (e.g. the values of a scene).
+///
+/// Taxonomy ids are interned atoms, so cloning them is cheap. Entries are stored in a single
+/// vector, indexed by taxonomy id, so lookups by taxonomy id don't scan the whole map. OpenZWave
+/// objects can't be hashed, so lookups by OpenZWave object still scan the entries, without
+/// cloning them.
 #[derive(Debug, Clone)]
 pub struct IdMap<Kind, Type> {
-    map: Arc<RwLock<Vec<(TaxoId<Kind>, Type)>>>,
+    map: Arc<RwLock<Entries<Kind, Type>>>,
+}
+
+#[derive(Debug)]
+struct Entries<Kind, Type> {
+    entries: Vec<(TaxoId<Kind>, Type)>,
+    /// The positions in `entries` of the objects of each taxonomy id, in the order the objects
+    /// were added. `swap_remove` moves entries around, so the positions themselves don't
+    /// follow that order.
+    by_taxo_id: HashMap<TaxoId<Kind>, Vec<usize>>,
+}
+
+impl<Kind, Type> Entries<Kind, Type>
+    where Kind: Clone
+{
+    /// Remove the entry at `index`, moving the last entry in its place.
+    fn swap_remove(&mut self, index: usize) -> (TaxoId<Kind>, Type) {
+        let last = self.entries.len() - 1;
+        let removed = self.entries.swap_remove(index);
+        let now_empty = {
+            let positions = self.by_taxo_id.get_mut(&removed.0).unwrap();
+            positions.retain(|&position| position != index);
+            positions.is_empty()
+        };
+        if now_empty {
+            self.by_taxo_id.remove(&removed.0);
+        }
+        if index != last {
+            let moved = self.entries[index].0.clone();
+            for position in self.by_taxo_id.get_mut(&moved).unwrap().iter_mut() {
+                if *position == last {
+                    *position = index;
+                }
+            }
+        }
+        removed
+    }
 }
 
 impl<Kind, Type> IdMap<Kind, Type>
@@ -12,28 +56,135 @@ impl<Kind, Type> IdMap<Kind, Type>
           Kind: Clone
 {
     pub fn new() -> Self {
-        IdMap { map: Arc::new(RwLock::new(Vec::new())) }
+        IdMap {
+            map: Arc::new(RwLock::new(Entries {
+                entries: Vec::new(),
+                by_taxo_id: HashMap::new(),
+            })),
+        }
     }
 
     pub fn push(&mut self, id: TaxoId<Kind>, ozw_object: Type) {
         let mut guard = self.map.write().unwrap(); // we have bigger problems if we're poisoned
-        guard.push((id, ozw_object));
+        let index = guard.entries.len();
+        guard.by_taxo_id.entry(id.clone()).or_insert_with(Vec::new).push(index);
+        guard.entries.push((id, ozw_object));
     }
 
     pub fn find_taxo_id_from_ozw(&self, needle: &Type) -> Option<TaxoId<Kind>> {
         let guard = self.map.read().unwrap(); // we have bigger problems if we're poisoned
-        let find_result = guard.iter().find(|&&(_, ref item)| item == needle);
+        let find_result = guard.entries.iter().find(|&&(_, ref item)| item == needle);
         find_result.map(|&(ref id, _)| id.clone())
     }
 
     pub fn find_ozw_from_taxo_id(&self, needle: &TaxoId<Kind>) -> Option<Type> {
         let guard = self.map.read().unwrap(); // we have bigger problems if we're poisoned
-        let find_result = guard.iter().find(|&&(ref id, _)| id == needle);
-        find_result.map(|&(_, ref ozw_object)| ozw_object.clone())
+        let positions = match guard.by_taxo_id.get(needle) {
+            Some(positions) => positions,
+            None => return None,
+        };
+        positions.first().map(|&index| guard.entries[index].1.clone())
+    }
+
+    pub fn ozw_objects(&self) -> Vec<Type> {
+        let guard = self.map.read().unwrap(); // we have bigger problems if we're poisoned
+        guard.entries.iter().map(|&(_, ref ozw_object)| ozw_object.clone()).collect()
     }
 
     pub fn remove_by_ozw(&mut self, needle: &Type) -> Option<TaxoId<Kind>> {
         let mut guard = self.map.write().unwrap(); // we have bigger problems if we're poisoned
-        guard.iter().position(|&(_, ref item)| item == needle).map(|index| guard.remove(index).0)
+        let position = guard.entries.iter().position(|&(_, ref item)| item == needle);
+        position.map(|index| guard.swap_remove(index).0)
+    }
+
+    /// Remove the objects matching `predicate`, e.g. all the objects of a Z-Wave network.
+    /// Returns their taxonomy ids, once each.
+    pub fn remove_where<F>(&mut self, predicate: F) -> Vec<TaxoId<Kind>>
+        where F: Fn(&Type) -> bool
+    {
+        let mut guard = self.map.write().unwrap(); // we have bigger problems if we're poisoned
+        let mut removed: Vec<TaxoId<Kind>> = vec![];
+        let mut index = 0;
+        while index < guard.entries.len() {
+            if !predicate(&guard.entries[index].1) {
+                index += 1;
+                continue;
+            }
+            // The last entry is moved at `index`, so look at `index` again.
+            let (id, _) = guard.swap_remove(index);
+            if !removed.contains(&id) {
+                removed.push(id);
+            }
+        }
+        removed
     }
 }
+
+#[test]
+fn test_id_map() {
+    let mut map = IdMap::<(), u32>::new();
+    let scene = TaxoId::new("scene");
+    map.push(TaxoId::new("a"), 1);
+    map.push(scene.clone(), 2);
+    map.push(scene.clone(), 3);
+    map.push(TaxoId::new("b"), 4);
+
+    assert_eq!(map.find_ozw_from_taxo_id(&scene), Some(2));
+    assert_eq!(map.find_taxo_id_from_ozw(&3), Some(scene.clone()));
+
+    // Removing an entry moves the last one, the index must follow.
+    assert_eq!(map.remove_by_ozw(&1), Some(TaxoId::new("a")));
+    assert_eq!(map.find_ozw_from_taxo_id(&TaxoId::new("b")), Some(4));
+    assert_eq!(map.find_ozw_from_taxo_id(&TaxoId::new("a")), None);
+
+    // A taxonomy id remains as long as one of its objects does.
+    assert_eq!(map.remove_by_ozw(&2), Some(scene.clone()));
+    assert_eq!(map.find_ozw_from_taxo_id(&scene), Some(3));
+    assert_eq!(map.remove_by_ozw(&3), Some(scene.clone()));
+    assert_eq!(map.find_ozw_from_taxo_id(&scene), None);
+    assert_eq!(map.remove_by_ozw(&3), None);
+
+    let mut objects = map.ozw_objects();
+    objects.sort();
+    assert_eq!(objects, vec![4]);
+}
+
+#[test]
+fn test_id_map_keeps_insertion_order() {
+    let mut map = IdMap::<(), u32>::new();
+    let scene = TaxoId::new("scene");
+    map.push(TaxoId::new("a"), 1);
+    map.push(scene.clone(), 2);
+    map.push(scene.clone(), 3);
+    map.push(TaxoId::new("b"), 4);
+    map.push(scene.clone(), 5);
+
+    // The last object of the scene moves in front of the others.
+    assert_eq!(map.remove_by_ozw(&1), Some(TaxoId::new("a")));
+    assert_eq!(map.find_ozw_from_taxo_id(&scene), Some(2));
+
+    assert_eq!(map.remove_by_ozw(&2), Some(scene.clone()));
+    assert_eq!(map.find_ozw_from_taxo_id(&scene), Some(3));
+    assert_eq!(map.remove_by_ozw(&3), Some(scene.clone()));
+    assert_eq!(map.find_ozw_from_taxo_id(&scene), Some(5));
+}
+
+#[test]
+fn test_id_map_remove_where() {
+    let mut map = IdMap::<(), u32>::new();
+    let scene = TaxoId::new("scene");
+    map.push(TaxoId::new("a"), 10);
+    map.push(scene.clone(), 11);
+    map.push(TaxoId::new("b"), 20);
+    map.push(scene.clone(), 12);
+    map.push(TaxoId::new("c"), 13);
+
+    // e.g. all the objects of the network 1.
+    let mut removed = map.remove_where(|object| object / 10 == 1);
+    removed.sort_by_key(|id| id.to_string());
+    assert_eq!(removed, vec![TaxoId::new("a"), TaxoId::new("c"), scene.clone()]);
+    assert_eq!(map.find_ozw_from_taxo_id(&scene), None);
+    assert_eq!(map.find_ozw_from_taxo_id(&TaxoId::new("b")), Some(20));
+    assert_eq!(map.ozw_objects(), vec![20]);
+    assert!(map.remove_where(|object| object / 10 == 1).is_empty());
+}
@@ -1,35 +1,46 @@
 extern crate openzwave_stateful as openzwave;
 extern crate foxbox_taxonomy as taxonomy;
 extern crate transformable_channels;
+extern crate chrono;
 #[macro_use]
 extern crate log;
 
+mod hotplug;
 mod id_map;
+mod unsupported;
+mod wake_up;
 mod watchers;
 
 
 use taxonomy::channel::*;
+use taxonomy::parse::{JSON, ToJSON};
 use taxonomy::util::{Id as TaxoId, Maybe, ref_eq};
 use taxonomy::services::{AdapterId, ServiceId, Service};
 use taxonomy::values::*;
-use taxonomy::api::{Operation, ResultMap, Error as TaxoError, InternalError, User};
-use taxonomy::adapter::{AdapterManagerHandle, AdapterWatchGuard, WatchEvent};
+use taxonomy::api::{Operation, Observation, ResultMap, Error as TaxoError, InternalError, Source,
+                    User};
+use taxonomy::adapter::{AdapterManagerHandle, AdapterStatus, AdapterWatchGuard, WatchEvent};
 use transformable_channels::mpsc::ExtSender;
 
+use chrono::Duration as ChronoDuration;
+
 use openzwave::{ConfigPath, InitOptions, ZWaveManager, ZWaveNotification};
 use openzwave::{CommandClass, ValueGenre, ValueType, ValueID};
-use openzwave::{Controller, Node};
+use openzwave::{Controller, ControllerState, Node};
 
 use std::error;
 use std::fmt;
 use std::{fs, io};
 use std::path::Path;
 use std::thread;
+use std::time::Duration;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use id_map::IdMap;
+use unsupported::{UnsupportedLog, UnsupportedValue};
+use wake_up::WakeUpQueue;
 use watchers::Watchers;
 
 pub use self::OpenzwaveAdapter as Adapter;
@@ -115,21 +126,111 @@ impl RangeChecker for Option<Value> {
     fn should_send(&self, value: &Value, event_type: EventType) -> bool {
         match *self {
             None => event_type == EventType::Enter, // no range means we send only Enter events
-            Some(ref range) => range == value, // FIXME: This won't scale up to interesting ranges.
+            Some(ref range) => range.should_send(value, event_type),
         }
     }
 }
 
+/// A condition is either a `Range` of the type of the values of the channel, e.g.
+/// `{"Geq": 50}` to be told when a covering is at least half open, or `{"Geq": {"C": 25}}`
+/// when a room gets warm, or a single value.
 impl RangeChecker for Value {
     fn should_send(&self, value: &Value, _: EventType) -> bool {
-        self == value // FIXME: This won't scale up to interesting ranges.
+        range_contains::<Percent>(self, value)
+            .or_else(|| range_contains::<Temperature>(self, value))
+            .or_else(|| range_contains::<Humidity>(self, value))
+            .or_else(|| range_contains::<Luminance>(self, value))
+            .or_else(|| range_contains::<OnOff>(self, value))
+            .or_else(|| range_contains::<OpenClosed>(self, value))
+            .or_else(|| range_contains::<IsLocked>(self, value))
+            .or_else(|| range_contains::<IsDetected>(self, value))
+            .unwrap_or_else(|| self == value)
     }
 }
 
+/// Whether `value` is in `condition`, or `None` if `condition` isn't a `Range<T>`.
+fn range_contains<T>(condition: &Value, value: &Value) -> Option<bool>
+    where T: Data + PartialOrd + PartialEq
+{
+    condition.downcast::<Range<T>>().map(|range| range.contains(value))
+}
+
+/// The properties of the service of a node.
+fn node_properties(node: &Node) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    properties.insert(String::from("name"), node.get_name());
+    properties.insert(String::from("product_name"), node.get_product_name());
+    properties.insert(String::from("manufacturer_name"), node.get_manufacturer_name());
+    properties.insert(String::from("location"), node.get_location());
+    properties
+}
+
+/// Window coverings (blinds, shutters, etc.) are exposed by Z-Wave as multilevel switches on
+/// nodes of the "Motor Control" device classes. We need to look at the node type to distinguish
+/// them from dimmers, which use the same command class.
+fn is_cover_node(node: &Node) -> bool {
+    let node_type = node.get_type().to_lowercase();
+    node_type.contains("motor") || node_type.contains("cover") || node_type.contains("blind")
+}
+
+// OpenZWave exposes the notification types of the Alarm (a.k.a. Notification) command class,
+// version 2 and above, as byte values at index `notification type + 3`. Indices 0 to 2 are the
+// legacy (manufacturer-specific) alarm type, alarm level and source node.
+const OZW_ALARM_INDEX_SMOKE: u8 = 4;
+const OZW_ALARM_INDEX_CO: u8 = 5;
+const OZW_ALARM_INDEX_FLOOD: u8 = 8;
+const OZW_ALARM_INDEX_BURGLAR: u8 = 10;
+
+// Events of the "Home Security" (burglar) notification type that denote tampering, and the
+// event that clears them.
+const OZW_ALARM_EVENT_IDLE: u8 = 0;
+const OZW_ALARM_EVENT_TAMPER_COVER_REMOVED: u8 = 3;
+const OZW_ALARM_EVENT_TAMPER_INVALID_CODE: u8 = 4;
+
+// OpenZWave exposes the readings of the Sensor Multilevel command class at index `sensor type`.
+const OZW_SENSOR_INDEX_TEMPERATURE: u8 = 1;
+const OZW_SENSOR_INDEX_LUMINANCE: u8 = 3;
+const OZW_SENSOR_INDEX_HUMIDITY: u8 = 5;
+
+// The Battery command class reports this level instead of a charge when the battery is low.
+const OZW_BATTERY_LOW_WARNING: u8 = 0xFF;
+
 fn taxo_kind_from_ozw_vid(vid: &ValueID) -> Option<&Channel> {
     match (vid.get_type(), vid.get_command_class(), vid.get_index()) {
         (ValueType::ValueType_Bool, Some(CommandClass::DoorLock), 0) => Some(&DOOR_IS_LOCKED),
         (ValueType::ValueType_Bool, Some(CommandClass::SensorBinary), _) => Some(&DOOR_IS_OPEN),
+        (ValueType::ValueType_Bool, Some(CommandClass::SwitchBinary), 0) => Some(&SWITCH_IS_ON),
+        // Multilevel switches: index 0 is the level, index 1 ("Bright") and index 2 ("Dim") are
+        // buttons that start moving up/down until released.
+        (ValueType::ValueType_Byte, Some(CommandClass::SwitchMultilevel), 0)
+            if is_cover_node(&vid.get_node()) => Some(&COVER_POSITION),
+        (ValueType::ValueType_Button, Some(CommandClass::SwitchMultilevel), 1)
+            if is_cover_node(&vid.get_node()) => Some(&COVER_OPEN),
+        (ValueType::ValueType_Button, Some(CommandClass::SwitchMultilevel), 2)
+            if is_cover_node(&vid.get_node()) => Some(&COVER_CLOSE),
+        (ValueType::ValueType_Byte, Some(CommandClass::SwitchMultilevel), 0) => Some(&SWITCH_LEVEL),
+        (ValueType::ValueType_Byte, Some(CommandClass::Battery), 0) => Some(&BATTERY_LEVEL),
+        (ValueType::ValueType_Decimal,
+         Some(CommandClass::SensorMultilevel),
+         OZW_SENSOR_INDEX_TEMPERATURE) => Some(&SENSOR_TEMPERATURE),
+        (ValueType::ValueType_Decimal,
+         Some(CommandClass::SensorMultilevel),
+         OZW_SENSOR_INDEX_LUMINANCE) => Some(&SENSOR_LUMINANCE),
+        (ValueType::ValueType_Decimal,
+         Some(CommandClass::SensorMultilevel),
+         OZW_SENSOR_INDEX_HUMIDITY) => Some(&SENSOR_HUMIDITY),
+        (ValueType::ValueType_Byte, Some(CommandClass::Alarm), OZW_ALARM_INDEX_SMOKE) => {
+            Some(&ALARM_SMOKE)
+        }
+        (ValueType::ValueType_Byte, Some(CommandClass::Alarm), OZW_ALARM_INDEX_CO) => {
+            Some(&ALARM_CO)
+        }
+        (ValueType::ValueType_Byte, Some(CommandClass::Alarm), OZW_ALARM_INDEX_FLOOD) => {
+            Some(&ALARM_LEAK)
+        }
+        (ValueType::ValueType_Byte, Some(CommandClass::Alarm), OZW_ALARM_INDEX_BURGLAR) => {
+            Some(&ALARM_TAMPER)
+        }
         // (ValueType::ValueType_Bool, Some(_)) => Some(ChannelKind::OnOff), TODO Find a proper type
         // Unrecognized command class or type - we don't know what to do with it.
         _ => None,
@@ -161,6 +262,8 @@ fn ozw_vid_as_taxo_value(vid: &ValueID) -> Option<Value> {
                     } else {
                         IsLocked::Unlocked
                     }))
+                } else if ref_eq(kind, &SWITCH_IS_ON) {
+                    Some(Value::new(if value { OnOff::On } else { OnOff::Off }))
                 } else {
                     None
                 }
@@ -169,10 +272,83 @@ fn ozw_vid_as_taxo_value(vid: &ValueID) -> Option<Value> {
                 None
             }
         }
+        ValueType::ValueType_Byte => {
+            match (taxo_kind_from_ozw_vid(vid), vid.as_byte()) {
+                (Some(kind), Ok(value)) if ref_eq(kind, &COVER_POSITION) ||
+                                           ref_eq(kind, &SWITCH_LEVEL) => {
+                    // Z-Wave levels go from 0 to 99, 99 meaning "fully open" or "fully on".
+                    Some(Value::new(Percent::new(if value >= 99 { 100 } else { value })))
+                }
+                (Some(kind), Ok(value)) if ref_eq(kind, &BATTERY_LEVEL) => {
+                    Some(Value::new(Percent::new(if value == OZW_BATTERY_LOW_WARNING {
+                        0
+                    } else {
+                        value
+                    })))
+                }
+                (Some(kind), Ok(value)) if ref_eq(kind, &ALARM_TAMPER) => {
+                    ozw_burglar_event_as_tamper(value).map(Value::new)
+                }
+                (Some(kind), Ok(value)) if ref_eq(kind, &ALARM_SMOKE) ||
+                                           ref_eq(kind, &ALARM_CO) ||
+                                           ref_eq(kind, &ALARM_LEAK) => {
+                    // 0 means "no event", anything else is an event of this notification type.
+                    Some(Value::new(if value != 0 {
+                        IsDetected::Detected
+                    } else {
+                        IsDetected::NotDetected
+                    }))
+                }
+                _ => None,
+            }
+        }
+        ValueType::ValueType_Decimal => {
+            match (taxo_kind_from_ozw_vid(vid), vid.as_float()) {
+                (Some(kind), Ok(value)) => ozw_reading_as_taxo_value(kind, value, &vid.get_units()),
+                _ => None,
+            }
+        }
         _ => None,   // TODO: Support more ValueType's
     }
 }
 
+/// Convert an event of the burglar notification to the state of the tamper channel. The
+/// notification also reports e.g. intrusion or motion, which neither mean that the device was
+/// tampered with nor that tampering stopped, so they are ignored.
+fn ozw_burglar_event_as_tamper(event: u8) -> Option<IsDetected> {
+    match event {
+        OZW_ALARM_EVENT_TAMPER_COVER_REMOVED |
+        OZW_ALARM_EVENT_TAMPER_INVALID_CODE => Some(IsDetected::Detected),
+        OZW_ALARM_EVENT_IDLE => Some(IsDetected::NotDetected),
+        _ => None,
+    }
+}
+
+/// Convert a reading of a multilevel sensor, in `units` (e.g. "C", "F", "lux" or "%").
+/// Luminances in "%" of the range of the sensor can't be converted to lux, and are ignored.
+fn ozw_reading_as_taxo_value(kind: &Channel, value: f32, units: &str) -> Option<Value> {
+    // Readings are decimals with a few digits, which `f32` doesn't represent exactly.
+    let value = (value as f64 * 100.).round() / 100.;
+    if ref_eq(kind, &SENSOR_TEMPERATURE) {
+        let celsius = if units == "F" {
+            (Temperature::F(value).as_c() * 100.).round() / 100.
+        } else {
+            value
+        };
+        Some(Value::new(Temperature::C(celsius)))
+    } else if ref_eq(kind, &SENSOR_LUMINANCE) {
+        if units.is_empty() || units == "lux" {
+            Some(Value::new(Luminance::from_lux(value.max(0.))))
+        } else {
+            None
+        }
+    } else if ref_eq(kind, &SENSOR_HUMIDITY) {
+        Some(Value::new(Humidity::new(value)))
+    } else {
+        None
+    }
+}
+
 fn set_ozw_vid_from_taxo_value(vid: &ValueID, value: Value) -> Result<(), TaxoError> {
     if vid.get_command_class().is_none() {
         return Err(TaxoError::Internal(InternalError::GenericError(format!("Unknown command class: {}", vid.get_command_class_id()))));
@@ -185,10 +361,26 @@ fn set_ozw_vid_from_taxo_value(vid: &ValueID, value: Value) -> Result<(), TaxoEr
                     vid.set_bool(*open_closed == OpenClosed::Open)
                 } else if let Some(locked_unlocked) = value.downcast::<IsLocked>() {
                     vid.set_bool(*locked_unlocked == IsLocked::Locked)
+                } else if let Some(on_off) = value.downcast::<OnOff>() {
+                    vid.set_bool(*on_off == OnOff::On)
                 } else {
                     return Err(TaxoError::InvalidValue); // TODO InvalidType would be better but we'll need to fix specific types for specific TaxoIds
                 }
             }
+            ValueType::ValueType_Byte => {
+                if let Some(percent) = value.downcast::<Percent>() {
+                    // Z-Wave levels go from 0 to 99.
+                    let level = percent.as_u8();
+                    vid.set_byte(if level >= 99 { 99 } else { level })
+                } else {
+                    return Err(TaxoError::InvalidValue);
+                }
+            }
+            ValueType::ValueType_Button => {
+                // Buttons (e.g. cover/open, cover/close) don't take any argument. The movement
+                // continues until the button is released, see `stop_moving`.
+                vid.press_button()
+            }
             _ => { return Err(TaxoError::Internal(InternalError::GenericError(format!("Unsupported OZW type: {:?}", vid.get_type())))) }
         };
 
@@ -198,6 +390,434 @@ fn set_ozw_vid_from_taxo_value(vid: &ValueID, value: Value) -> Result<(), TaxoEr
     })
 }
 
+/// Whether a value is the indicator of a node, i.e. the light or the buzzer that devices use
+/// to make themselves noticed.
+fn is_indicator_vid(vid: &ValueID) -> bool {
+    vid.get_command_class() == Some(CommandClass::Indicator) && vid.get_index() == 0 &&
+    vid.get_type() == ValueType::ValueType_Byte
+}
+
+/// Turn on the indicator of a node, see `device/identify`. Devices blink or beep for a while,
+/// then turn it off by themselves.
+fn identify(vid: &ValueID) -> Result<(), TaxoError> {
+    vid.set_byte(0xff).map_err(|e| {
+        TaxoError::Internal(InternalError::GenericError(format!("Error while identifying a \
+                                                                 node: {}",
+                                                                e)))
+    })
+}
+
+/// Stop a window covering that has been started with a button (cover/open or cover/close).
+fn stop_moving(vid: &ValueID) -> Result<(), TaxoError> {
+    vid.release_button().map_err(|e| {
+        TaxoError::Internal(InternalError::GenericError(format!("Error while stopping a \
+                                                                 covering: {}",
+                                                                e)))
+    })
+}
+
+/// Represent the raw value of a ValueID as JSON, for the types that map to JSON.
+fn ozw_raw_value_as_json(vid: &ValueID) -> Option<JSON> {
+    match vid.get_type() {
+        ValueType::ValueType_Bool => vid.as_bool().ok().map(JSON::Bool),
+        ValueType::ValueType_Byte => vid.as_byte().ok().map(|v| JSON::U64(v as u64)),
+        ValueType::ValueType_Short => vid.as_short().ok().map(|v| JSON::I64(v as i64)),
+        ValueType::ValueType_Int => vid.as_int().ok().map(|v| JSON::I64(v as i64)),
+        ValueType::ValueType_List | ValueType::ValueType_String => {
+            vid.as_string().ok().map(JSON::String)
+        }
+        _ => None,
+    }
+}
+
+/// Represent a configuration parameter (`ValueGenre_Config`) as JSON, i.e.
+/// `{"index": number, "label": string, "help": string, "value": number | bool | string}`.
+fn ozw_config_as_json(vid: &ValueID) -> Option<JSON> {
+    ozw_raw_value_as_json(vid).map(|value| {
+        vec![("index", JSON::U64(vid.get_index() as u64)),
+             ("label", vid.get_label().to_json()),
+             ("help", vid.get_help().to_json()),
+             ("value", value)]
+            .to_json()
+    })
+}
+
+/// Set a configuration parameter (`ValueGenre_Config`) from JSON `{"value": ...}`. The JSON may
+/// also hold the `"index"` of the parameter, as returned by `ozw_config_as_json`, in which case
+/// it must be the index of `vid`.
+fn set_ozw_config_from_taxo_value(vid: &ValueID, value: Value) -> Result<(), TaxoError> {
+    match try!(config_param_index(&value)) {
+        Some(index) if index != vid.get_index() => return Err(TaxoError::InvalidValue),
+        _ => {}
+    }
+    let json = try!(value.cast::<Json>());
+    let value = match json.0.find("value") {
+        Some(value) => value,
+        None => return Err(TaxoError::InvalidValue),
+    };
+    let result = match (vid.get_type(), value) {
+        (ValueType::ValueType_Bool, &JSON::Bool(value)) => vid.set_bool(value),
+        (ValueType::ValueType_Byte, &JSON::U64(value)) if value <= u8::max_value() as u64 => {
+            vid.set_byte(value as u8)
+        }
+        (ValueType::ValueType_Short, _) => {
+            match value.as_i64() {
+                Some(value) if value >= i16::min_value() as i64 &&
+                               value <= i16::max_value() as i64 => vid.set_short(value as i16),
+                _ => return Err(TaxoError::InvalidValue),
+            }
+        }
+        (ValueType::ValueType_Int, _) => {
+            match value.as_i64() {
+                Some(value) if value >= i32::min_value() as i64 &&
+                               value <= i32::max_value() as i64 => vid.set_int(value as i32),
+                _ => return Err(TaxoError::InvalidValue),
+            }
+        }
+        // OpenZWave selects list items by label.
+        (ValueType::ValueType_List, &JSON::String(ref value)) |
+        (ValueType::ValueType_String, &JSON::String(ref value)) => vid.set_string(value),
+        _ => return Err(TaxoError::InvalidValue),
+    };
+    result.map_err(|e| {
+        TaxoError::Internal(InternalError::GenericError(format!("Error while setting a \
+                                                                 configuration parameter: {}",
+                                                                e)))
+    })
+}
+
+/// The index of the configuration parameter targeted by JSON `{"index": number, "value": ...}`,
+/// if the JSON has one.
+fn config_param_index(value: &Value) -> Result<Option<u8>, TaxoError> {
+    let json = try!(value.cast::<Json>());
+    match json.0.find("index") {
+        None => Ok(None),
+        Some(index) => {
+            match index.as_u64() {
+                Some(index) if index <= u8::max_value() as u64 => Ok(Some(index as u8)),
+                _ => Err(TaxoError::InvalidValue),
+            }
+        }
+    }
+}
+
+/// Scene values are reported by wall controllers and multi-tap buttons, either through the
+/// Scene Activation command class (a single "Scene" value) or through the Central Scene command
+/// class (one value per scene, index 0 being the number of scenes).
+fn is_scene_vid(vid: &ValueID) -> bool {
+    match vid.get_command_class() {
+        Some(CommandClass::SceneActivation) => vid.get_index() == 0,
+        Some(CommandClass::CentralScene) => vid.get_index() > 0,
+        _ => false,
+    }
+}
+
+/// Represent a scene activation as JSON, i.e. `{"scene": number}` for Scene Activation or
+/// `{"scene": number, "action": string}` for Central Scene.
+fn ozw_scene_as_taxo_value(vid: &ValueID) -> Option<Value> {
+    let json = match vid.get_command_class() {
+        Some(CommandClass::SceneActivation) => {
+            match vid.as_byte() {
+                // Scene 0 doesn't exist, this is just the initial value.
+                Ok(0) | Err(_) => return None,
+                Ok(scene) => vec![("scene", JSON::U64(scene as u64))].to_json(),
+            }
+        }
+        Some(CommandClass::CentralScene) => {
+            let action = match vid.as_int() {
+                Ok(0) => "pressed",
+                Ok(1) => "released",
+                Ok(2) => "held",
+                Ok(3) => "pressed-2x",
+                Ok(4) => "pressed-3x",
+                Ok(5) => "pressed-4x",
+                Ok(6) => "pressed-5x",
+                _ => return None,
+            };
+            vec![("scene", JSON::U64(vid.get_index() as u64)), ("action", action.to_json())]
+                .to_json()
+        }
+        _ => return None,
+    };
+    Some(Value::new(Json(json)))
+}
+
+/// Scene activations are momentary: notify the watchers that the value has entered its range
+/// then immediately exited it, so that pressing the same button twice triggers twice.
+fn send_scene_event(watchers: &Arc<Mutex<Watchers>>, id: &TaxoId<Channel>, value: Value) {
+    let observed = Observation::now(Source::Device);
+    let watchers = watchers.lock().unwrap();
+    let watchers = match watchers.get_from_taxo_id(id) {
+        Some(watchers) => watchers,
+        None => return,
+    };
+    for &(ref when, ref sender) in &watchers {
+        if !when.should_send(&value, EventType::Enter) {
+            continue;
+        }
+        let sender = sender.lock().unwrap();
+        debug!("[OpenzwaveAdapter] Sending scene event {:?} {:?}", id, value);
+        sender.send(WatchEvent::Enter {
+                id: id.clone(),
+                value: value.clone(),
+                observed: observed,
+            })
+            .unwrap_or_else(|_| {
+                error!("Couldn't send the enter event {{ id: {:?}, value: {:?} }}", id, value);
+            });
+        if when.should_send(&value, EventType::Exit) {
+            sender.send(WatchEvent::Exit {
+                    id: id.clone(),
+                    value: value.clone(),
+                    observed: observed,
+                })
+                .unwrap_or_else(|_| {
+                    error!("Couldn't send the exit event {{ id: {:?}, value: {:?} }}", id, value);
+                });
+        }
+    }
+}
+
+/// Record the new value of a channel, then notify the watchers whose range it exits or enters.
+fn send_value_events(watchers: &Arc<Mutex<Watchers>>,
+                     value_cache: &Arc<Mutex<ValueCache>>,
+                     taxo_id: &TaxoId<Channel>,
+                     taxo_value: Value) {
+    let observed = Observation::now(Source::Device);
+    let previous_value = {
+        let mut cache = value_cache.lock().unwrap();
+        let previous = cache.get(taxo_id).cloned();
+        cache.insert(taxo_id.clone(), taxo_value.clone());
+        previous
+    };
+
+    let watchers = watchers.lock().unwrap();
+    let watchers = match watchers.get_from_taxo_id(taxo_id) {
+        Some(watchers) => watchers,
+        None => return,
+    };
+
+    for &(ref when, ref sender) in &watchers {
+        debug!("[OpenzwaveAdapter::ValueChanged] Iterating over watcher {:?} {:?}",
+               taxo_id,
+               when);
+
+        let should_send_value = when.should_send(&taxo_value, EventType::Enter);
+
+        if let Some(ref previous_value) = previous_value {
+            let should_send_previous = when.should_send(previous_value, EventType::Exit);
+            // If the new and the old values are both in the same range, we need to send nothing.
+            if should_send_value && should_send_previous {
+                continue;
+            }
+
+            if should_send_previous {
+                debug!("Openzwave::Adapter::ValueChanged Sending event Exit {:?} {:?}",
+                       taxo_id,
+                       taxo_value);
+                let sender = sender.lock().unwrap();
+                sender.send(WatchEvent::Exit {
+                        id: taxo_id.clone(),
+                        value: taxo_value.clone(),
+                        observed: observed,
+                    })
+                    .unwrap_or_else(|_| {
+                        error!("Couldn't send the exit event {{ id: {:?}, value: {:?} }}",
+                               taxo_id,
+                               taxo_value);
+                    });
+            }
+        }
+
+        if should_send_value {
+            debug!("[OpenzwaveAdapter::ValueChanged] Sending event Enter {:?} {:?}",
+                   taxo_id,
+                   taxo_value);
+            let sender = sender.lock().unwrap();
+            sender.send(WatchEvent::Enter {
+                    id: taxo_id.clone(),
+                    value: taxo_value.clone(),
+                    observed: observed,
+                })
+                .unwrap_or_else(|_| {
+                    error!("Couldn't send the enter event {{ id: {:?}, value: {:?} }}",
+                           taxo_id,
+                           taxo_value);
+                });
+        }
+    }
+}
+
+/// Describe the mesh network of a controller as JSON, i.e.
+/// `{"home_id": string, "library_version": string, "library_type": string, "nodes": [...]}`,
+/// where each node is
+/// `{"id": number, "name": string, "type": string, "routing": bool, "listening": bool,
+///   "neighbors": [number]}`.
+fn ozw_topology_as_json(controller: &Controller, nodes: &[Node]) -> JSON {
+    let home_id = controller.get_home_id();
+    let nodes: Vec<JSON> = nodes.iter()
+        .filter(|node| node.get_home_id() == home_id)
+        .map(|node| {
+            let neighbors: Vec<JSON> = node.get_neighbors()
+                .unwrap_or_else(Vec::new)
+                .iter()
+                .map(|neighbor| JSON::U64(neighbor.get_id() as u64))
+                .collect();
+            vec![("id", JSON::U64(node.get_id() as u64)),
+                 ("name", node.get_name().to_json()),
+                 ("type", node.get_type().to_json()),
+                 ("routing", node.is_routing_device().to_json()),
+                 ("listening", node.is_listening_device().to_json()),
+                 ("neighbors", JSON::Array(neighbors))]
+                .to_json()
+        })
+        .collect();
+    vec![("home_id", format!("{:08x}", home_id).to_json()),
+         ("library_version", controller.get_library_version().to_json()),
+         ("library_type", controller.get_library_type_name().to_json()),
+         ("nodes", JSON::Array(nodes))]
+        .to_json()
+}
+
+/// Describe a value that we don't expose as a channel, for the `zwave/unsupported-data`
+/// channels. Values whose type doesn't map to JSON are read as strings.
+fn ozw_unsupported_value(vid: &ValueID) -> UnsupportedValue {
+    UnsupportedValue {
+        node: vid.get_node().get_id(),
+        command_class: vid.get_command_class()
+            .map_or_else(|| "Unknown".to_owned(), |class| format!("{:?}", class)),
+        value_type: format!("{:?}", vid.get_type()),
+        index: vid.get_index(),
+        label: vid.get_label(),
+        value: ozw_unsupported_value_as_json(vid),
+    }
+}
+
+fn ozw_unsupported_value_as_json(vid: &ValueID) -> Option<JSON> {
+    if !vid.is_set() {
+        return None;
+    }
+    ozw_raw_value_as_json(vid).or_else(|| vid.as_string().ok().map(JSON::String))
+}
+
+// The Wake-Up command class exposes the wake-up interval, in seconds, at index 0, followed by
+// its minimum, maximum, default and step.
+const OZW_WAKE_UP_INDEX_INTERVAL: u8 = 0;
+
+fn is_wake_up_interval_vid(vid: &ValueID) -> bool {
+    vid.get_command_class() == Some(CommandClass::WakeUp) &&
+    vid.get_index() == OZW_WAKE_UP_INDEX_INTERVAL
+}
+
+/// Nodes that are neither always listening nor woken up by a beam (FLiRS, e.g. door locks) are
+/// battery-powered devices that only listen when they wake up.
+fn is_sleeping_node(node: &Node) -> bool {
+    !node.is_listening_device() && !node.is_frequent_listening_device()
+}
+
+fn ozw_wake_up_interval_as_taxo_value(vid: &ValueID) -> Option<Value> {
+    vid.as_int()
+        .ok()
+        .map(|seconds| Value::new(Duration::from(ChronoDuration::seconds(seconds as i64))))
+}
+
+fn set_ozw_wake_up_interval_from_taxo_value(vid: &ValueID, value: Value) -> Result<(), TaxoError> {
+    let duration = try!(value.cast::<Duration>());
+    let seconds = duration.as_duration().num_seconds();
+    if seconds < 0 || seconds > i32::max_value() as i64 {
+        return Err(TaxoError::InvalidValue);
+    }
+    vid.set_int(seconds as i32).map_err(|e| {
+        TaxoError::Internal(InternalError::GenericError(format!("Error while setting the \
+                                                                 wake-up interval: {}",
+                                                                e)))
+    })
+}
+
+/// A write to a node, performed right away if the node is awake, or kept until it wakes up.
+enum PendingWrite {
+    Value(ValueID, Value),
+    Config(ValueID, Value),
+    WakeUpInterval(ValueID, Value),
+}
+
+impl PendingWrite {
+    fn node(&self) -> Node {
+        match *self {
+            PendingWrite::Value(ref vid, _) |
+            PendingWrite::Config(ref vid, _) |
+            PendingWrite::WakeUpInterval(ref vid, _) => vid.get_node(),
+        }
+    }
+
+    fn perform(self) -> Result<(), TaxoError> {
+        match self {
+            PendingWrite::Value(vid, value) => set_ozw_vid_from_taxo_value(&vid, value),
+            PendingWrite::Config(vid, value) => set_ozw_config_from_taxo_value(&vid, value),
+            PendingWrite::WakeUpInterval(vid, value) => {
+                set_ozw_wake_up_interval_from_taxo_value(&vid, value)
+            }
+        }
+    }
+}
+
+/// The progress of an inclusion, as exposed by the `zwave/inclusion-state` channels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InclusionState {
+    /// No inclusion has been started since the controller was opened.
+    Idle,
+    /// The controller is waiting for the user to activate the device to include.
+    Waiting,
+    /// A device has been found and is being included.
+    NodeFound,
+    /// The inclusion failed or timed out.
+    Failed,
+    /// The device has been included.
+    Done,
+}
+
+impl InclusionState {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            InclusionState::Idle => "Idle",
+            InclusionState::Waiting => "Waiting",
+            InclusionState::NodeFound => "NodeFound",
+            InclusionState::Failed => "Failed",
+            InclusionState::Done => "Done",
+        }
+    }
+
+    fn as_taxo_value(&self) -> Value {
+        Value::new(self.as_str().to_owned())
+    }
+
+    /// Interpret the progress of the command of a controller that is including a device.
+    /// OpenZWave reports an inclusion that timed out as `Failed`.
+    fn from_controller_state(state: ControllerState) -> Self {
+        match state {
+            ControllerState::Starting |
+            ControllerState::Waiting |
+            ControllerState::Sleeping => InclusionState::Waiting,
+            ControllerState::InProgress => InclusionState::NodeFound,
+            ControllerState::Completed |
+            ControllerState::NodeOK => InclusionState::Done,
+            ControllerState::Error |
+            ControllerState::Failed |
+            ControllerState::NodeFailed => InclusionState::Failed,
+            ControllerState::Normal |
+            ControllerState::Cancel => InclusionState::Idle,
+        }
+    }
+
+    /// Whether the inclusion is over.
+    fn is_final(&self) -> bool {
+        match *self {
+            InclusionState::Waiting | InclusionState::NodeFound => false,
+            _ => true,
+        }
+    }
+}
+
 fn start_including(ozw: &ZWaveManager, home_id: u32, value: &Value) -> Result<(), TaxoError> {
     let is_secure = try!(value.cast::<IsSecure>());
     let is_secure_bool = *is_secure == IsSecure::Secure;
@@ -245,6 +865,24 @@ pub struct OpenzwaveAdapter {
     controller_map: IdMap<ServiceId, Controller>,
     include_map: IdMap<Channel, Controller>,
     exclude_map: IdMap<Channel, Controller>,
+    stop_map: IdMap<Channel, ValueID>,
+    /// The indicator of each node that has one, behind its `device/identify` channel.
+    identify_map: IdMap<Channel, ValueID>,
+    config_map: IdMap<Channel, ValueID>,
+    scene_map: IdMap<Channel, ValueID>,
+    topology_map: IdMap<Channel, Controller>,
+    inclusion_state_map: IdMap<Channel, Controller>,
+    unsupported_map: IdMap<Channel, Controller>,
+    /// The values sent by devices that we don't expose as channels, see `zwave/unsupported-data`.
+    unsupported: UnsupportedLog,
+    /// The networks whose controller is including a device, by home id. The commands of the
+    /// other controllers (e.g. exclusions) don't change their `zwave/inclusion-state`.
+    including: Arc<Mutex<HashSet<u32>>>,
+    wake_up_map: IdMap<Channel, ValueID>,
+    wake_up_queue: WakeUpQueue<PendingWrite>,
+
+    /// If `true`, expose the configuration parameters of devices as channels.
+    advanced: bool,
 }
 
 fn ensure_directory<T: AsRef<Path> + ?Sized>(directory: &T) -> Result<(), Error> {
@@ -263,41 +901,139 @@ fn ensure_directory<T: AsRef<Path> + ?Sized>(directory: &T) -> Result<(), Error>
     Ok(())
 }
 
+static ADAPTER_NAME: &'static str = "OpenZwave Adapter";
+
+/// Where the device nodes of the Z-Wave controllers appear, see `hotplug`.
+static DEV_DIR: &'static str = "/dev";
+
+/// How often we look for Z-Wave controllers being plugged or unplugged.
+const HOTPLUG_POLL_INTERVAL_S: u64 = 5;
+
 impl OpenzwaveAdapter {
+    /// Start the adapter with the Z-Wave controllers that are plugged, if any, then keep
+    /// following the controllers being plugged or unplugged, see `spawn_device_monitor`.
     pub fn init<T: AdapterManagerHandle + Send + Sync + 'static>(box_manager: &Arc<T>,
                                                                  user_path: &str,
-                                                                 devices: Option<String>)
+                                                                 devices: Option<String>,
+                                                                 advanced: bool)
                                                                  -> Result<(), Error> {
 
         try!(ensure_directory(user_path));
 
+        // We treat devices as a comma (with optional whitespace) delimited list of device names.
+        let devices: Option<Vec<String>> =
+            devices.map(|s| s.split(',').map(|s| s.trim().to_owned()).collect());
+        let present = hotplug::present_devices(Path::new(DEV_DIR),
+                                               devices.as_ref().map(|devices| &devices[..]));
+        let started = try!(Self::start(box_manager,
+                                       user_path,
+                                       devices.as_ref().map(|_| present.clone()),
+                                       advanced));
+        Self::spawn_device_monitor(box_manager.clone(),
+                                   user_path.to_owned(),
+                                   devices,
+                                   advanced,
+                                   present,
+                                   started);
+        Ok(())
+    }
+
+    /// Poll for Z-Wave controllers being plugged or unplugged. Whenever they change, the
+    /// adapter is removed, along with its services and channels, then started again with the
+    /// controllers that are still plugged, if any.
+    fn spawn_device_monitor<T>(box_manager: Arc<T>,
+                               user_path: String,
+                               devices: Option<Vec<String>>,
+                               advanced: bool,
+                               mut present: Vec<String>,
+                               mut started: bool)
+        where T: AdapterManagerHandle + Send + Sync + 'static
+    {
+        thread::Builder::new()
+            .name("OpenZWave-hotplug".to_owned())
+            .spawn(move || {
+                let id = TaxoId::<AdapterId>::new(ADAPTER_NAME);
+                loop {
+                    thread::sleep(Duration::from_secs(HOTPLUG_POLL_INTERVAL_S));
+                    let now_present =
+                        hotplug::present_devices(Path::new(DEV_DIR),
+                                                 devices.as_ref().map(|devices| &devices[..]));
+                    if now_present == present {
+                        continue;
+                    }
+                    info!("[OpenzwaveAdapter] Z-Wave controllers changed from {:?} to {:?}.",
+                          present,
+                          now_present);
+                    present = now_present;
+
+                    if started {
+                        // This drops the OpenZWave manager, which closes the controllers.
+                        box_manager.remove_adapter(&id).unwrap_or_else(|e| {
+                            error!("[OpenzwaveAdapter] Couldn't remove the adapter: {}", e);
+                        });
+                        started = false;
+                    }
+                    if present.is_empty() {
+                        let reason = "No Z-Wave controller was found.".to_owned();
+                        box_manager.report_adapter_status(&id, AdapterStatus::Degraded(reason));
+                        continue;
+                    }
+                    match Self::start(&box_manager,
+                                      &user_path,
+                                      devices.as_ref().map(|_| present.clone()),
+                                      advanced) {
+                        Ok(now_started) => started = now_started,
+                        Err(err) => {
+                            error!("[OpenzwaveAdapter] Couldn't start the adapter: {}", err);
+                            box_manager.report_adapter_status(&id,
+                                                              AdapterStatus::Failed(format!("{}",
+                                                                                            err)));
+                        }
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    /// Start the adapter with `devices`, or with all the controllers that OpenZWave finds if
+    /// `None`. Returns `false` if there is no controller to start with.
+    fn start<T: AdapterManagerHandle + Send + Sync + 'static>(box_manager: &Arc<T>,
+                                                              user_path: &str,
+                                                              devices: Option<Vec<String>>,
+                                                              advanced: bool)
+                                                              -> Result<bool, Error> {
         let options = InitOptions {
-            // We treat devices as a comma (with optional whitespace) delimited list of device names.
-            devices: devices.map(|s| s.split(',').map(|s| s.trim().to_owned()).collect()),
+            devices: devices,
             config_path: ConfigPath::Default, /* This is where the default system configuraton is, usually contains the device information. */
             user_path: user_path, /* This is where we can override the system configuration, and where the network layout and logs are stored. */
         };
 
+        let name = String::from(ADAPTER_NAME);
+        let id = TaxoId::new(&name);
         let (ozw, rx) = try!(match openzwave::init(&options) {
             Err(openzwave::Error::NoDeviceFound) => {
-                // early return: we should not impair foxbox startup for this error.
-                // TODO manage errors at adapter start: https://github.com/fxbox/RFC/issues/14
+                // early return: we should not impair foxbox startup for this error, but let
+                // the user know why there are no Z-Wave devices. We start once a controller is
+                // plugged, see `spawn_device_monitor`.
                 info!("[OpenzwaveAdapter] No ZWave device has been found.");
-                return Ok(());
+                let reason = "No Z-Wave controller was found.".to_owned();
+                box_manager.report_adapter_status(&id, AdapterStatus::Degraded(reason));
+                return Ok(false);
             }
             Err(openzwave::Error::CannotReadDevice(device, cause)) => {
                 // early return for the same reason as above.
                 error!("[OpenzwaveAdapter] Could not read the device {}: {}.",
                        device,
                        cause);
-                return Ok(());
+                let reason = format!("Could not read the device {}: {}.", device, cause);
+                box_manager.report_adapter_status(&id, AdapterStatus::Failed(reason));
+                return Ok(false);
             }
             result => result,
         });
 
-        let name = String::from("OpenZwave Adapter");
         let adapter = Arc::new(OpenzwaveAdapter {
-            id: TaxoId::new(&name),
+            id: id.clone(),
             name: name,
             vendor: String::from("Mozilla"),
             version: [1, 0, 0, 0],
@@ -310,14 +1046,27 @@ impl OpenzwaveAdapter {
             controller_map: IdMap::new(),
             include_map: IdMap::new(),
             exclude_map: IdMap::new(),
+            stop_map: IdMap::new(),
+            identify_map: IdMap::new(),
+            config_map: IdMap::new(),
+            scene_map: IdMap::new(),
+            topology_map: IdMap::new(),
+            inclusion_state_map: IdMap::new(),
+            unsupported_map: IdMap::new(),
+            unsupported: UnsupportedLog::new(),
+            including: Arc::new(Mutex::new(HashSet::new())),
+            wake_up_map: IdMap::new(),
+            wake_up_queue: WakeUpQueue::new(),
+            advanced: advanced,
         });
 
         try!(box_manager.add_adapter(adapter.clone()));
         adapter.spawn_notification_thread(rx, box_manager);
+        box_manager.report_adapter_status(&id, AdapterStatus::Running);
 
         info!("[OpenzwaveAdapter] Started.");
 
-        Ok(())
+        Ok(true)
     }
 
     fn spawn_notification_thread<T: AdapterManagerHandle + Send + Sync + 'static>(&self, rx: mpsc::Receiver<ZWaveNotification>, box_manager: &Arc<T>) {
@@ -329,6 +1078,18 @@ impl OpenzwaveAdapter {
         let mut controller_map = self.controller_map.clone();
         let mut include_map = self.include_map.clone();
         let mut exclude_map = self.exclude_map.clone();
+        let mut stop_map = self.stop_map.clone();
+        let mut identify_map = self.identify_map.clone();
+        let mut config_map = self.config_map.clone();
+        let mut scene_map = self.scene_map.clone();
+        let mut topology_map = self.topology_map.clone();
+        let mut inclusion_state_map = self.inclusion_state_map.clone();
+        let mut unsupported_map = self.unsupported_map.clone();
+        let unsupported = self.unsupported.clone();
+        let including = self.including.clone();
+        let mut wake_up_map = self.wake_up_map.clone();
+        let wake_up_queue = self.wake_up_queue.clone();
+        let advanced = self.advanced;
 
         let watchers = self.watchers.clone();
         let value_cache = self.value_cache.clone();
@@ -388,6 +1149,132 @@ impl OpenzwaveAdapter {
                             .unwrap_or_else(|e| {
                                 error!("Couldn't add the setter {}: {}", exclude_setter_id, e);
                             });
+
+                        let topology_getter_name = format!("OpenZWave-controller-{:08x}-topology",
+                                                           home_id);
+                        let topology_getter_id = TaxoId::new(&topology_getter_name);
+                        topology_map.push(topology_getter_id.clone(), controller);
+
+                        box_manager.add_channel(Channel {
+                                feature: TaxoId::new("zwave/topology"),
+                                supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                                id: topology_getter_id.clone(),
+                                service: service_id.clone(),
+                                adapter: adapter_id.clone(),
+                                ..Channel::default()
+                            })
+                            .unwrap_or_else(|e| {
+                                error!("Couldn't add the getter {}: {}", topology_getter_id, e);
+                            });
+
+                        let inclusion_getter_name =
+                            format!("OpenZWave-controller-{:08x}-inclusion-state", home_id);
+                        let inclusion_getter_id = TaxoId::new(&inclusion_getter_name);
+                        inclusion_state_map.push(inclusion_getter_id.clone(), controller);
+                        value_cache.lock()
+                            .unwrap()
+                            .insert(inclusion_getter_id.clone(),
+                                    InclusionState::Idle.as_taxo_value());
+
+                        box_manager.add_channel(Channel {
+                                feature: TaxoId::new("zwave/inclusion-state"),
+                                supports_fetch: Some(Signature::returns(Maybe::Required(format::STRING.clone()))),
+                                supports_watch: Some(Signature::returns(Maybe::Required(format::STRING.clone()))),
+                                id: inclusion_getter_id.clone(),
+                                service: service_id.clone(),
+                                adapter: adapter_id.clone(),
+                                ..Channel::default()
+                            })
+                            .unwrap_or_else(|e| {
+                                error!("Couldn't add the getter {}: {}", inclusion_getter_id, e);
+                            });
+
+                        let unsupported_getter_name =
+                            format!("OpenZWave-controller-{:08x}-unsupported-data", home_id);
+                        let unsupported_getter_id = TaxoId::new(&unsupported_getter_name);
+                        unsupported_map.push(unsupported_getter_id.clone(), controller);
+
+                        box_manager.add_channel(Channel {
+                                feature: TaxoId::new("zwave/unsupported-data"),
+                                supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                                id: unsupported_getter_id.clone(),
+                                service: service_id.clone(),
+                                adapter: adapter_id.clone(),
+                                ..Channel::default()
+                            })
+                            .unwrap_or_else(|e| {
+                                error!("Couldn't add the getter {}: {}", unsupported_getter_id, e);
+                            });
+                    }
+                    ZWaveNotification::ControllerCommand(controller, state) => {
+                        let home_id = controller.get_home_id();
+                        if !including.lock().unwrap().contains(&home_id) {
+                            continue;
+                        }
+                        let inclusion_state = InclusionState::from_controller_state(state);
+                        info!("[OpenzwaveAdapter] Inclusion on network {:08x}: {:?}",
+                              home_id,
+                              inclusion_state);
+                        if inclusion_state.is_final() {
+                            including.lock().unwrap().remove(&home_id);
+                        }
+                        if let Some(id) = inclusion_state_map.find_taxo_id_from_ozw(&controller) {
+                            send_value_events(&watchers,
+                                              &value_cache,
+                                              &id,
+                                              inclusion_state.as_taxo_value());
+                        }
+                    }
+                    ZWaveNotification::ControllerRemoved(controller) |
+                    ZWaveNotification::ControllerFailed(controller) => {
+                        // Each controller runs its own network: forget the nodes and values of
+                        // this one, the other networks keep running.
+                        let home_id = controller.get_home_id();
+                        info!("Closed ZWave Controller {} HomeId: {:08x}",
+                              controller.get_controller_path(),
+                              home_id);
+
+                        let mut channels = vec![];
+                        for map in &mut [&mut getter_map,
+                                         &mut setter_map,
+                                         &mut stop_map,
+                                         &mut identify_map,
+                                         &mut config_map,
+                                         &mut scene_map,
+                                         &mut wake_up_map] {
+                            channels.extend(map.remove_where(|vid| vid.get_home_id() == home_id));
+                        }
+                        including.lock().unwrap().remove(&home_id);
+                        unsupported.remove_home(home_id);
+                        for map in &mut [&mut include_map,
+                                         &mut exclude_map,
+                                         &mut topology_map,
+                                         &mut inclusion_state_map,
+                                         &mut unsupported_map] {
+                            channels.extend(map.remove_where(|controller| {
+                                controller.get_home_id() == home_id
+                            }));
+                        }
+                        {
+                            let mut cache = value_cache.lock().unwrap();
+                            for id in &channels {
+                                cache.remove(id);
+                            }
+                        }
+
+                        // Removing the services also removes their channels.
+                        let mut services = node_map.remove_where(|node| {
+                            node.get_home_id() == home_id
+                        });
+                        services.extend(controller_map.remove_where(|controller| {
+                            controller.get_home_id() == home_id
+                        }));
+                        for service_id in services {
+                            wake_up_queue.remove(&service_id);
+                            box_manager.remove_service(&service_id).unwrap_or_else(|e| {
+                                error!("Couldn't remove the service {}: {}", service_id, e);
+                            });
+                        }
                     }
                     ZWaveNotification::NodeNew(_node) => {}
                     ZWaveNotification::NodeAdded(node) => {
@@ -397,47 +1284,217 @@ impl OpenzwaveAdapter {
                         node_map.push(service_id.clone(), node);
 
                         let mut service = Service::empty(&service_id, &adapter_id);
-                        service.properties.insert(String::from("name"), node.get_name());
-                        service.properties
-                            .insert(String::from("product_name"), node.get_product_name());
-                        service.properties.insert(String::from("manufacturer_name"),
-                                                  node.get_manufacturer_name());
-                        service.properties.insert(String::from("location"), node.get_location());
+                        service.properties = node_properties(&node);
+
+                        if is_sleeping_node(&node) {
+                            // Until we hear from it, assume that the node is asleep.
+                            wake_up_queue.fall_asleep(&service_id);
+                        }
 
                         box_manager.add_service(service).unwrap_or_else(|e| {
                             error!("Couldn't add the service {}: {}", service_name, e);
                         });
                     }
-                    ZWaveNotification::NodeNaming(_node) => {
-                        // unfortunately we can't change a service' properties :(
-                        // https://github.com/fxbox/taxonomy/issues/97
-                        // When it's done we can move the properties change from above to here.
+                    ZWaveNotification::NodeNaming(node) => {
+                        // The name and location are known once the node has been queried, or
+                        // when the user changed them, e.g. through another controller.
+                        let service_id = match node_map.find_taxo_id_from_ozw(&node) {
+                            Some(service_id) => service_id,
+                            None => continue,
+                        };
+                        box_manager.update_service_properties(&service_id, node_properties(&node))
+                            .unwrap_or_else(|e| {
+                                error!("Couldn't update the properties of {}: {}", service_id, e);
+                            });
                     }
                     ZWaveNotification::NodeRemoved(node) => {
                         if let Some(service_id) = node_map.remove_by_ozw(&node) {
+                            wake_up_queue.remove(&service_id);
                             box_manager.remove_service(&service_id).unwrap_or_else(|e| {
                                 error!("Couldn't remove the service {}: {}", service_id, e);
                             });
                         }
                     }
+                    ZWaveNotification::NodeAwake(node) => {
+                        let service_id = match node_map.find_taxo_id_from_ozw(&node) {
+                            Some(service_id) => service_id,
+                            None => continue,
+                        };
+                        for (id, write) in wake_up_queue.wake_up(&service_id) {
+                            debug!("[OpenzwaveAdapter] Node {} woke up, writing {}",
+                                   service_id,
+                                   id);
+                            write.perform().unwrap_or_else(|e| {
+                                error!("Couldn't write {} after the node woke up: {}", id, e);
+                            });
+                        }
+                        // Also deliver the values queued by the box for this node.
+                        box_manager.service_reachable(&service_id);
+                    }
+                    ZWaveNotification::NodeAsleep(node) => {
+                        if !is_sleeping_node(&node) {
+                            continue;
+                        }
+                        if let Some(service_id) = node_map.find_taxo_id_from_ozw(&node) {
+                            wake_up_queue.fall_asleep(&service_id);
+                        }
+                    }
                     ZWaveNotification::ValueAdded(vid) => {
+                        if is_wake_up_interval_vid(&vid) {
+                            let value_id = format!("OpenZWave-{:08x}-{:016x}",
+                                                   vid.get_home_id(),
+                                                   vid.get_id());
+                            let node_id = match node_map.find_taxo_id_from_ozw(&vid.get_node()) {
+                                Some(node_id) => node_id,
+                                None => continue,
+                            };
+                            let id = TaxoId::new(&value_id);
+                            wake_up_map.push(id.clone(), vid);
+                            box_manager.add_channel(Channel {
+                                    feature: TaxoId::new("zwave/wake-up-interval"),
+                                    supports_fetch: Some(Signature::returns(Maybe::Required(format::DURATION.clone()))),
+                                    supports_send: if vid.is_read_only() {
+                                        None
+                                    } else {
+                                        Some(Signature::accepts(Maybe::Required(format::DURATION.clone())))
+                                    },
+                                    id: id,
+                                    service: node_id,
+                                    adapter: adapter_id.clone(),
+                                    ..Channel::default()
+                                })
+                                .unwrap_or_else(|e| {
+                                    error!("Couldn't add the wake-up interval {}: {}", value_id, e);
+                                });
+                            continue;
+                        }
+
+                        if advanced && vid.get_genre() == ValueGenre::ValueGenre_Config {
+                            let value_id = format!("OpenZWave-{:08x}-{:016x}",
+                                                   vid.get_home_id(),
+                                                   vid.get_id());
+                            let node_id = match node_map.find_taxo_id_from_ozw(&vid.get_node()) {
+                                Some(node_id) => node_id,
+                                None => continue,
+                            };
+                            let id = TaxoId::new(&value_id);
+                            config_map.push(id.clone(), vid);
+                            box_manager.add_channel(Channel {
+                                    feature: TaxoId::new("zwave/config-parameter"),
+                                    supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                                    supports_send: if vid.is_read_only() {
+                                        None
+                                    } else {
+                                        Some(Signature::accepts(Maybe::Required(format::JSON.clone())))
+                                    },
+                                    id: id,
+                                    service: node_id,
+                                    adapter: adapter_id.clone(),
+                                    ..Channel::default()
+                                })
+                                .unwrap_or_else(|e| {
+                                    error!("Couldn't add the config parameter {}: {}", value_id, e);
+                                });
+                            continue;
+                        }
+
+                        if is_indicator_vid(&vid) {
+                            let node = vid.get_node();
+                            let node_id = match node_map.find_taxo_id_from_ozw(&node) {
+                                Some(node_id) => node_id,
+                                None => continue,
+                            };
+                            let identify_id =
+                                TaxoId::new(&format!("OpenZWave-{:08x}-{:02x}-identify",
+                                                     node.get_home_id(),
+                                                     node.get_id()));
+                            identify_map.push(identify_id.clone(), vid);
+                            box_manager.add_channel(Channel {
+                                    id: identify_id.clone(),
+                                    service: node_id,
+                                    adapter: adapter_id.clone(),
+                                    ..IDENTIFY.clone()
+                                })
+                                .unwrap_or_else(|e| {
+                                    error!("Couldn't add the setter {}: {}", identify_id, e);
+                                });
+                            continue;
+                        }
+
                         if vid.get_genre() != ValueGenre::ValueGenre_User {
                             continue;
                         }
 
+                        if is_scene_vid(&vid) {
+                            // All the scenes of a node are exposed through a single channel.
+                            let node = vid.get_node();
+                            let node_id = match node_map.find_taxo_id_from_ozw(&node) {
+                                Some(node_id) => node_id,
+                                None => continue,
+                            };
+                            let scene_id = TaxoId::new(&format!("OpenZWave-{:08x}-{:02x}-scene",
+                                                                node.get_home_id(),
+                                                                node.get_id()));
+                            let is_new = scene_map.find_ozw_from_taxo_id(&scene_id).is_none();
+                            scene_map.push(scene_id.clone(), vid);
+                            if is_new {
+                                box_manager.add_channel(Channel {
+                                        feature: TaxoId::new("zwave/scene-activated"),
+                                        supports_watch: Some(Signature {
+                                            accepts: Maybe::Optional(format::JSON.clone()),
+                                            returns: Maybe::Required(format::JSON.clone()),
+                                        }),
+                                        id: scene_id.clone(),
+                                        service: node_id,
+                                        adapter: adapter_id.clone(),
+                                        ..Channel::default()
+                                    })
+                                    .unwrap_or_else(|e| {
+                                        error!("Couldn't add the scene channel {}: {}", scene_id, e);
+                                    });
+                            }
+                            continue;
+                        }
+
                         let value_id =
                             format!("OpenZWave-{:08x}-{:016x}", vid.get_home_id(), vid.get_id());
 
-                        let node_id = node_map.find_taxo_id_from_ozw(&vid.get_node()).unwrap();
-
-                        let kind = taxo_kind_from_ozw_vid(&vid);
-                        let chan = match kind {
+                        let node_id = match node_map.find_taxo_id_from_ozw(&vid.get_node()) {
+                            Some(node_id) => node_id,
                             None => continue,
-                            Some(kind) => kind.clone(),
                         };
 
+                        let kind = match taxo_kind_from_ozw_vid(&vid) {
+                            None => {
+                                // Keep track of it, for users to tell us what their devices
+                                // send.
+                                unsupported.record(vid.get_home_id(),
+                                                   vid.get_id(),
+                                                   ozw_unsupported_value(&vid));
+                                continue;
+                            }
+                            Some(kind) => kind,
+                        };
+                        let chan = kind.clone();
+
                         let id = TaxoId::new(&value_id);
 
+                        if ref_eq(kind, &COVER_OPEN) {
+                            // Releasing the "open" button stops the covering, whichever
+                            // direction it was moving in.
+                            let stop_id = TaxoId::new(&format!("{}-stop", value_id));
+                            stop_map.push(stop_id.clone(), vid);
+                            box_manager.add_channel(Channel {
+                                    id: stop_id.clone(),
+                                    service: node_id.clone(),
+                                    adapter: adapter_id.clone(),
+                                    ..COVER_STOP.clone()
+                                })
+                                .unwrap_or_else(|e| {
+                                    error!("Couldn't add the setter {}: {}", stop_id, e);
+                                });
+                        }
+
                         let mut chan = Channel {
                             id: id.clone(),
                             service: node_id,
@@ -445,6 +1502,22 @@ impl OpenzwaveAdapter {
                             ..chan
                         };
 
+                        // OpenZWave names values after what they measure, e.g. "Temperature".
+                        let label = vid.get_label();
+                        if !label.is_empty() {
+                            chan.label = Some(label);
+                        }
+
+                        if ref_eq(kind, &COVER_POSITION) || ref_eq(kind, &SWITCH_LEVEL) ||
+                           ref_eq(kind, &BATTERY_LEVEL) {
+                            // Let watchers be told when a threshold is crossed, e.g. with
+                            // `{"Leq": 20}`, rather than only when a given level is reached.
+                            chan.supports_watch = Some(Signature {
+                                accepts: Maybe::Optional(format::PERCENT_RANGE.clone()),
+                                returns: Maybe::Required(format::PERCENT.clone()),
+                            });
+                        }
+
                         if vid.is_write_only() {
                             // For some reason, the value is configured as not being readable.
                             // Make sure that the channel doesn't pretend the opposite.
@@ -468,9 +1541,22 @@ impl OpenzwaveAdapter {
                             });
                     }
                     ZWaveNotification::ValueChanged(vid) => {
+                        if let Some(scene_id) = scene_map.find_taxo_id_from_ozw(&vid) {
+                            if let Some(value) = ozw_scene_as_taxo_value(&vid) {
+                                send_scene_event(&watchers, &scene_id, value);
+                            }
+                            continue;
+                        }
+
+                        if unsupported.update(vid.get_home_id(),
+                                              vid.get_id(),
+                                              ozw_unsupported_value_as_json(&vid)) {
+                            continue;
+                        }
+
                         match vid.get_type() {
-                            ValueType::ValueType_Bool => {}
-                            _ => continue, // ignore non-bool vals for now
+                            ValueType::ValueType_Bool | ValueType::ValueType_Byte => {}
+                            _ => continue, // ignore other vals for now
                         };
 
                         let taxo_id = match getter_map.find_taxo_id_from_ozw(&vid) {
@@ -483,76 +1569,10 @@ impl OpenzwaveAdapter {
                             _ => continue,
                         };
 
-                        let watchers = watchers.lock().unwrap();
-
-                        let watchers = match watchers.get_from_taxo_id(&taxo_id) {
-                            Some(watchers) => watchers,
-                            _ => continue,
-                        };
-
-                        let previous_value = {
-                            let mut cache = value_cache.lock().unwrap();
-                            let previous = cache.get(&taxo_id).cloned();
-                            cache.insert(taxo_id.clone(), taxo_value.clone());
-                            previous
-                        };
-
-                        for &(ref when, ref sender) in &watchers {
-                            debug!("[OpenzwaveAdapter::ValueChanged] Iterating over watcher {:?} \
-                                    {:?}",
-                                   taxo_id,
-                                   when);
-
-                            let should_send_value = when.should_send(&taxo_value, EventType::Enter);
-
-                            if let Some(ref previous_value) = previous_value {
-                                let should_send_previous =
-                                    when.should_send(previous_value, EventType::Exit);
-                                // If the new and the old values are both in the same range, we
-                                // need to send nothing.
-                                if should_send_value && should_send_previous {
-                                    continue;
-                                }
-
-                                if should_send_previous {
-                                    debug!("Openzwave::Adapter::ValueChanged Sending event Exit \
-                                            {:?} {:?}",
-                                           taxo_id,
-                                           taxo_value);
-                                    let sender = sender.lock().unwrap();
-                                    sender.send(WatchEvent::Exit {
-                                            id: taxo_id.clone(),
-                                            value: taxo_value.clone(),
-                                        })
-                                        .unwrap_or_else(|_| {
-                                            error!("Couldn't send the exit event {{ id: {:?}, \
-                                                    value: {:?} }}",
-                                                   taxo_id,
-                                                   taxo_value);
-                                        });
-                                }
-                            }
-
-                            if should_send_value {
-                                debug!("[OpenzwaveAdapter::ValueChanged] Sending event Enter \
-                                        {:?} {:?}",
-                                       taxo_id,
-                                       taxo_value);
-                                let sender = sender.lock().unwrap();
-                                sender.send(WatchEvent::Enter {
-                                        id: taxo_id.clone(),
-                                        value: taxo_value.clone(),
-                                    })
-                                    .unwrap_or_else(|_| {
-                                        error!("Couldn't send the enter event {{ id: {:?}, \
-                                                value: {:?} }}",
-                                               taxo_id,
-                                               taxo_value);
-                                    });
-                            }
-                        }
+                        send_value_events(&watchers, &value_cache, &taxo_id, taxo_value);
                     }
                     ZWaveNotification::ValueRemoved(vid) => {
+                        unsupported.remove(vid.get_home_id(), vid.get_id());
                         if let Some(getter_id) = getter_map.remove_by_ozw(&vid) {
                             box_manager.remove_channel(&getter_id).unwrap_or_else(|e| {
                                 error!("Unable to remove getter_id {}: {}", getter_id, e);
@@ -563,10 +1583,41 @@ impl OpenzwaveAdapter {
                                 error!("Unable to remove setter_id {}: {}", setter_id, e);
                             });
                         }
+                        if let Some(scene_id) = scene_map.remove_by_ozw(&vid) {
+                            // Only remove the channel once all the scenes of the node are gone.
+                            if scene_map.find_ozw_from_taxo_id(&scene_id).is_none() {
+                                box_manager.remove_channel(&scene_id).unwrap_or_else(|e| {
+                                    error!("Unable to remove scene channel {}: {}", scene_id, e);
+                                });
+                            }
+                        }
+                        if let Some(config_id) = config_map.remove_by_ozw(&vid) {
+                            box_manager.remove_channel(&config_id).unwrap_or_else(|e| {
+                                error!("Unable to remove config parameter {}: {}", config_id, e);
+                            });
+                        }
+                        if let Some(interval_id) = wake_up_map.remove_by_ozw(&vid) {
+                            box_manager.remove_channel(&interval_id).unwrap_or_else(|e| {
+                                error!("Unable to remove wake-up interval {}: {}", interval_id, e);
+                            });
+                        }
+                        if let Some(stop_id) = stop_map.remove_by_ozw(&vid) {
+                            box_manager.remove_channel(&stop_id).unwrap_or_else(|e| {
+                                error!("Unable to remove setter_id {}: {}", stop_id, e);
+                            });
+                        }
+                        if let Some(identify_id) = identify_map.remove_by_ozw(&vid) {
+                            box_manager.remove_channel(&identify_id).unwrap_or_else(|e| {
+                                error!("Unable to remove setter_id {}: {}", identify_id, e);
+                            });
+                        }
                     }
                     ZWaveNotification::AwakeNodesQueried(ref controller) |
                     ZWaveNotification::AllNodesQueried(ref controller) => {
-                        debug!("[OpenzwaveAdapter] Writing the network config.");
+                        // Only write the config of this network, the others may still be
+                        // querying their nodes.
+                        debug!("[OpenzwaveAdapter] Writing the config of network {:08x}.",
+                               controller.get_home_id());
                         controller.write_config();
                     }
                     ZWaveNotification::Generic(_string) => {}
@@ -575,6 +1626,31 @@ impl OpenzwaveAdapter {
             }
         });
     }
+
+    /// Start following the inclusion on the network of `controller`, from `state`.
+    fn set_inclusion_state(&self, controller: &Controller, state: InclusionState) {
+        self.including.lock().unwrap().insert(controller.get_home_id());
+        if let Some(id) = self.inclusion_state_map.find_taxo_id_from_ozw(controller) {
+            send_value_events(&self.watchers, &self.value_cache, &id, state.as_taxo_value());
+        }
+    }
+
+    /// Perform `write` if its node is awake, otherwise keep it until the node wakes up.
+    fn write_or_queue(&self, id: &TaxoId<Channel>, write: PendingWrite) -> Result<(), TaxoError> {
+        let service_id = match self.node_map.find_taxo_id_from_ozw(&write.node()) {
+            Some(service_id) => service_id,
+            None => return write.perform(),
+        };
+        match self.wake_up_queue.push(&service_id, id.clone(), write) {
+            Some(write) => write.perform(),
+            None => {
+                info!("[OpenzwaveAdapter] Node {} is asleep, {} will be written when it wakes up.",
+                      service_id,
+                      id);
+                Ok(())
+            }
+        }
+    }
 }
 
 impl taxonomy::adapter::Adapter for OpenzwaveAdapter {
@@ -599,12 +1675,49 @@ impl taxonomy::adapter::Adapter for OpenzwaveAdapter {
                     _: User)
                     -> ResultMap<TaxoId<Channel>, Option<Value>, TaxoError> {
         set.drain(..).map(|id| {
+            if let Some(controller) = self.topology_map.find_ozw_from_taxo_id(&id) {
+                let nodes = self.node_map.ozw_objects();
+                let json = ozw_topology_as_json(&controller, &nodes);
+                return (id, Ok(Some(Value::new(Json(json)))));
+            }
+
+            if let Some(controller) = self.unsupported_map.find_ozw_from_taxo_id(&id) {
+                let json = self.unsupported.as_json(controller.get_home_id());
+                return (id, Ok(Some(Value::new(Json(json)))));
+            }
+
+            if self.inclusion_state_map.find_ozw_from_taxo_id(&id).is_some() {
+                let value = self.value_cache.lock().unwrap().get(&id).cloned();
+                return (id, Ok(value));
+            }
+
+            if let Some(ozw_vid) = self.config_map.find_ozw_from_taxo_id(&id) {
+                let value = if ozw_vid.is_set() {
+                    ozw_config_as_json(&ozw_vid).map(|json| Value::new(Json(json)))
+                } else {
+                    None
+                };
+                return (id, Ok(value));
+            }
+
+            if let Some(ozw_vid) = self.wake_up_map.find_ozw_from_taxo_id(&id) {
+                let value = if ozw_vid.is_set() {
+                    ozw_wake_up_interval_as_taxo_value(&ozw_vid)
+                } else {
+                    None
+                };
+                return (id, Ok(value));
+            }
+
             let ozw_vid = self.getter_map.find_ozw_from_taxo_id(&id);
 
             let taxo_value: Option<Option<Value>> = ozw_vid.map(|ozw_vid: ValueID| {
                 if !ozw_vid.is_set() { return None }
 
+                // Events that don't change the state of the channel (e.g. motion, for the
+                // tamper channel) leave the latest state in the cache.
                 ozw_vid_as_taxo_value(&ozw_vid)
+                    .or_else(|| self.value_cache.lock().unwrap().get(&id).cloned())
             });
             let value_result: Result<Option<Value>, TaxoError> = taxo_value.ok_or(TaxoError::OperationNotSupported(Operation::Fetch, id.clone()));
             (id, value_result)
@@ -617,15 +1730,30 @@ impl taxonomy::adapter::Adapter for OpenzwaveAdapter {
                    -> ResultMap<TaxoId<Channel>, (), TaxoError> {
         values.drain()
             .map(|(id, value)| {
-                if let Some(ozw_vid) = self.setter_map.find_ozw_from_taxo_id(&id) {
-                    (id, set_ozw_vid_from_taxo_value(&ozw_vid, value))
+                let write = if let Some(ozw_vid) = self.setter_map.find_ozw_from_taxo_id(&id) {
+                    PendingWrite::Value(ozw_vid, value)
+                } else if let Some(ozw_vid) = self.config_map.find_ozw_from_taxo_id(&id) {
+                    PendingWrite::Config(ozw_vid, value)
+                } else if let Some(ozw_vid) = self.wake_up_map.find_ozw_from_taxo_id(&id) {
+                    PendingWrite::WakeUpInterval(ozw_vid, value)
+                } else if let Some(ozw_vid) = self.stop_map.find_ozw_from_taxo_id(&id) {
+                    return (id, stop_moving(&ozw_vid));
+                } else if let Some(ozw_vid) = self.identify_map.find_ozw_from_taxo_id(&id) {
+                    return (id, identify(&ozw_vid));
                 } else if let Some(ozw_controller) = self.include_map.find_ozw_from_taxo_id(&id) {
-                    (id, start_including(&self.ozw, ozw_controller.get_home_id(), &value))
+                    let home_id = ozw_controller.get_home_id();
+                    let result = start_including(&self.ozw, home_id, &value);
+                    if result.is_ok() {
+                        self.set_inclusion_state(&ozw_controller, InclusionState::Waiting);
+                    }
+                    return (id, result);
                 } else if let Some(ozw_controller) = self.exclude_map.find_ozw_from_taxo_id(&id) {
-                    (id, start_excluding(&self.ozw, ozw_controller.get_home_id()))
+                    return (id, start_excluding(&self.ozw, ozw_controller.get_home_id()));
                 } else {
-                    (id.clone(), Err(TaxoError::Internal(InternalError::NoSuchChannel(id))))
-                }
+                    return (id.clone(), Err(TaxoError::Internal(InternalError::NoSuchChannel(id))));
+                };
+                let result = self.write_or_queue(&id, write);
+                (id, result)
             })
             .collect()
     }
@@ -633,11 +1761,14 @@ impl taxonomy::adapter::Adapter for OpenzwaveAdapter {
     fn register_watch(&self,
                       mut values: Vec<(TaxoId<Channel>,
                                        Option<Value>,
-                                       Box<ExtSender<WatchEvent<Value>>>)>)
+                                       Box<ExtSender<WatchEvent<Value>>>)>,
+                      _: User)
                       -> Vec<(TaxoId<Channel>, Result<Box<AdapterWatchGuard>, TaxoError>)> {
         debug!("[OpenzwaveAdapter::register_watch] Should register some watchers");
         values.drain(..).filter_map(|(id, range, sender)| {
-            if self.getter_map.find_ozw_from_taxo_id(&id).is_none() {
+            let is_inclusion_state = self.inclusion_state_map.find_ozw_from_taxo_id(&id).is_some();
+            if self.getter_map.find_ozw_from_taxo_id(&id).is_none() &&
+               self.scene_map.find_ozw_from_taxo_id(&id).is_none() && !is_inclusion_state {
                 return Some((id.clone(), Err(TaxoError::OperationNotSupported(Operation::Watch, id))))
             }
 
@@ -649,17 +1780,40 @@ impl taxonomy::adapter::Adapter for OpenzwaveAdapter {
             };
             let value_result: Result<Box<AdapterWatchGuard>, TaxoError> = Ok(Box::new(watch_guard));
 
+            if is_inclusion_state {
+                let state = self.value_cache.lock().unwrap().get(&id).cloned();
+                if let Some(state) = state {
+                    if range.should_send(&state, EventType::Enter) {
+                        let sender = sender.lock().unwrap();
+                        sender.send(
+                            WatchEvent::Enter {
+                                id: id.clone(),
+                                value: state.clone(),
+                                observed: Observation::now(Source::Cache),
+                            }
+                        ).unwrap_or_else(|_| {
+                            error!("Couldn't send the enter event {{ id: {:?}, value: {:?} }}", id, state);
+                        });
+                    }
+                }
+                return Some((id, value_result));
+            }
+
             // if there is a set value already, let's send it.
             let ozw_value: Option<ValueID> = self.getter_map.find_ozw_from_taxo_id(&id);
             if let Some(value) = ozw_value {
-                if value.is_set() && value.get_type() == ValueType::ValueType_Bool {
+                if value.is_set() {
                     if let Some(value) = ozw_vid_as_taxo_value(&value) {
                         self.value_cache.lock().unwrap().insert(id.clone(), value.clone());
                         if range.should_send(&value, EventType::Enter) {
                             debug!("[OpenzwaveAdapter::register_watch] Sending event Enter {:?} {:?}", id, value);
                             let sender = sender.lock().unwrap();
                             sender.send(
-                                WatchEvent::Enter { id: id.clone(), value: value.clone() }
+                                WatchEvent::Enter {
+                                    id: id.clone(),
+                                    value: value.clone(),
+                                    observed: Observation::now(Source::Cache),
+                                }
                             ).unwrap_or_else(|_| {
                                 error!("Couldn't send the enter event {{ id: {:?}, value: {:?} }}", id, value);
                             });
@@ -674,13 +1828,172 @@ impl taxonomy::adapter::Adapter for OpenzwaveAdapter {
 
     fn stop(&self) {
         info!("[OpenzwaveAdapter::stop] Stopping the Openzwave adapter: writing the network \
-               config.");
-        self.ozw.write_configs();
+               configs.");
+        // Write the config of each network that is still running, as the controllers that were
+        // removed or failed can't be written to anymore.
+        for controller in self.controller_map.ozw_objects() {
+            debug!("[OpenzwaveAdapter::stop] Writing the config of network {:08x}.",
+                   controller.get_home_id());
+            controller.write_config();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{EventType, InclusionState, RangeChecker, config_param_index,
+                ozw_burglar_event_as_tamper, ozw_reading_as_taxo_value};
+    use openzwave::ControllerState;
+    use taxonomy::channel::*;
+    use taxonomy::io::BinarySource;
+    use taxonomy::parse::{JSON, Path, ToJSON};
+    use taxonomy::util::Maybe;
+    use taxonomy::values::*;
+
     #[test]
     fn it_works() {}
+
+    #[test]
+    fn test_range_checker() {
+        let closed = Value::new(Percent::new(10));
+        let half_open = Value::new(Percent::new(50));
+        let open = Value::new(Percent::new(100));
+
+        let mostly_closed = Some(Value::new(Range::Leq(Percent::new(20))));
+        assert!(mostly_closed.should_send(&closed, EventType::Enter));
+        assert!(mostly_closed.should_send(&closed, EventType::Exit));
+        assert!(!mostly_closed.should_send(&half_open, EventType::Enter));
+
+        let ajar = Some(Value::new(Range::BetweenEq {
+            min: Percent::new(20),
+            max: Percent::new(80),
+        }));
+        assert!(ajar.should_send(&half_open, EventType::Enter));
+        assert!(!ajar.should_send(&open, EventType::Enter));
+
+        let not_ajar = Some(Value::new(Range::OutOfStrict {
+            min: Percent::new(20),
+            max: Percent::new(80),
+        }));
+        assert!(not_ajar.should_send(&open, EventType::Enter));
+        assert!(not_ajar.should_send(&closed, EventType::Enter));
+        assert!(!not_ajar.should_send(&half_open, EventType::Exit));
+
+        let opened = Some(Value::new(Range::Geq(Percent::new(100))));
+        assert!(opened.should_send(&open, EventType::Enter));
+        // A range of another type never matches.
+        assert!(!opened.should_send(&Value::new(OpenClosed::Open), EventType::Enter));
+
+        // Single values are compared for equality.
+        let locked = Some(Value::new(IsLocked::Locked));
+        assert!(locked.should_send(&Value::new(IsLocked::Locked), EventType::Enter));
+        assert!(!locked.should_send(&Value::new(IsLocked::Unlocked), EventType::Enter));
+
+        // Without a condition, only Enter is sent.
+        let any: Option<Value> = None;
+        assert!(any.should_send(&half_open, EventType::Enter));
+        assert!(!any.should_send(&half_open, EventType::Exit));
+    }
+
+    #[test]
+    fn test_temperature_threshold() {
+        let cool = Value::new(Temperature::C(19.5));
+        let warm = Value::new(Temperature::C(26.));
+
+        // The threshold is parsed from the format that the channel accepts for watches.
+        let accepted = match SENSOR_TEMPERATURE.supports_watch {
+            Some(Signature { accepts: Maybe::Optional(ref format), .. }) => format.clone(),
+            _ => panic!("Temperatures should accept a range to watch"),
+        };
+        let source = vec![("Geq", vec![("F", JSON::F64(77.))].to_json())].to_json();
+        let hot = Some(accepted.parse(Path::new(), &source, &BinarySource).unwrap());
+        assert!(hot.should_send(&warm, EventType::Enter));
+        assert!(!hot.should_send(&cool, EventType::Enter));
+        // Readings are compared whatever their unit.
+        assert!(hot.should_send(&Value::new(Temperature::F(80.)), EventType::Enter));
+        assert!(hot.should_send(&cool, EventType::Exit));
+        assert!(!hot.should_send(&warm, EventType::Exit));
+
+        let comfortable = Some(Value::new(Range::BetweenEq {
+            min: Temperature::C(18.),
+            max: Temperature::C(24.),
+        }));
+        assert!(comfortable.should_send(&cool, EventType::Enter));
+        assert!(!comfortable.should_send(&warm, EventType::Enter));
+
+        let dark = Some(Value::new(Range::Leq(Luminance::from_lux(50.))));
+        assert!(dark.should_send(&Value::new(Luminance::from_lux(10.)), EventType::Enter));
+        assert!(!dark.should_send(&Value::new(Luminance::from_lux(300.)), EventType::Enter));
+
+        let humid = Some(Value::new(Range::Geq(Humidity::new(60.))));
+        assert!(humid.should_send(&Value::new(Humidity::new(72.5)), EventType::Enter));
+        assert!(!humid.should_send(&Value::new(Humidity::new(40.)), EventType::Enter));
+    }
+
+    #[test]
+    fn test_sensor_readings() {
+        let celsius = |value: Option<Value>| {
+            value.and_then(|value| value.downcast::<Temperature>().map(Temperature::as_c))
+        };
+        assert_eq!(celsius(ozw_reading_as_taxo_value(&SENSOR_TEMPERATURE, 21.5, "C")),
+                   Some(21.5));
+        assert_eq!(celsius(ozw_reading_as_taxo_value(&SENSOR_TEMPERATURE, 212., "F")),
+                   Some(100.));
+
+        assert_eq!(ozw_reading_as_taxo_value(&SENSOR_LUMINANCE, 300., "lux"),
+                   Some(Value::new(Luminance::from_lux(300.))));
+        assert_eq!(ozw_reading_as_taxo_value(&SENSOR_LUMINANCE, 40., "%"), None);
+
+        assert_eq!(ozw_reading_as_taxo_value(&SENSOR_HUMIDITY, 45.6, "%"),
+                   Some(Value::new(Humidity::new(45.6))));
+        assert_eq!(ozw_reading_as_taxo_value(&SENSOR_HUMIDITY, 104., "%"),
+                   Some(Value::new(Humidity::new(100.))));
+        assert_eq!(ozw_reading_as_taxo_value(&DOOR_IS_OPEN, 1., ""), None);
+    }
+
+    #[test]
+    fn test_burglar_events() {
+        // Tampering with the cover, then motion, which doesn't clear the tamper alarm.
+        assert_eq!(ozw_burglar_event_as_tamper(3), Some(IsDetected::Detected));
+        assert_eq!(ozw_burglar_event_as_tamper(8), None);
+        assert_eq!(ozw_burglar_event_as_tamper(4), Some(IsDetected::Detected));
+        assert_eq!(ozw_burglar_event_as_tamper(0), Some(IsDetected::NotDetected));
+    }
+
+    #[test]
+    fn test_config_param_index() {
+        let param = |index: JSON| {
+            Value::new(Json(vec![("index", index), ("value", JSON::U64(3))].to_json()))
+        };
+        assert_eq!(config_param_index(&param(JSON::U64(12))).unwrap(), Some(12));
+        assert!(config_param_index(&param(JSON::U64(256))).is_err());
+        assert!(config_param_index(&param(JSON::String("12".to_owned()))).is_err());
+        assert_eq!(config_param_index(&Value::new(Json(vec![("value", JSON::U64(3))]
+                           .to_json())))
+                       .unwrap(),
+                   None);
+        assert!(config_param_index(&Value::new(Percent::new(12))).is_err());
+    }
+
+    #[test]
+    fn test_inclusion_state() {
+        let states: Vec<_> = vec![ControllerState::Starting,
+                                  ControllerState::Waiting,
+                                  ControllerState::InProgress,
+                                  ControllerState::Completed]
+            .into_iter()
+            .map(InclusionState::from_controller_state)
+            .collect();
+        assert_eq!(states,
+                   vec![InclusionState::Waiting,
+                        InclusionState::Waiting,
+                        InclusionState::NodeFound,
+                        InclusionState::Done]);
+        assert!(!InclusionState::NodeFound.is_final());
+
+        let timed_out = InclusionState::from_controller_state(ControllerState::Failed);
+        assert_eq!(timed_out, InclusionState::Failed);
+        assert!(timed_out.is_final());
+        assert_eq!(timed_out.as_taxo_value(), Value::new("Failed".to_owned()));
+    }
 }
@@ -0,0 +1,187 @@
+use taxonomy::parse::{JSON, ToJSON};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// How many values are remembered, across all networks. Once full, the value that was heard
+/// from least recently is forgotten.
+const MAX_ENTRIES: usize = 200;
+
+/// A value sent by a device that we don't expose as a channel, e.g. because we don't know
+/// its command class or its type yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnsupportedValue {
+    pub node: u8,
+    pub command_class: String,
+    pub value_type: String,
+    pub index: u8,
+    pub label: String,
+    /// The latest value, if it could be read.
+    pub value: Option<JSON>,
+}
+
+struct Entry {
+    value: UnsupportedValue,
+    /// How many times the device reported the value.
+    count: u64,
+    /// When we last heard from the value, as a sequence number.
+    seen: u64,
+}
+
+/// The values that devices send and that the adapter ignores, so that users can report what
+/// their devices actually send, and support for them can be prioritized.
+///
+/// Values are keyed by the home id of their network and their OpenZWave value id.
+#[derive(Clone)]
+pub struct UnsupportedLog {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    entries: HashMap<(u32, u64), Entry>,
+    counter: u64,
+}
+
+impl UnsupportedLog {
+    pub fn new() -> Self {
+        UnsupportedLog {
+            state: Arc::new(Mutex::new(State {
+                entries: HashMap::new(),
+                counter: 0,
+            })),
+        }
+    }
+
+    /// Record a value that the adapter ignores, as it is added or changes.
+    pub fn record(&self, home_id: u32, value_id: u64, value: UnsupportedValue) {
+        let mut state = self.state.lock().unwrap();
+        state.counter += 1;
+        let seen = state.counter;
+        let key = (home_id, value_id);
+        if !state.entries.contains_key(&key) && state.entries.len() >= MAX_ENTRIES {
+            let oldest = state.entries
+                .iter()
+                .min_by_key(|&(_, entry)| entry.seen)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+        let entry = state.entries.entry(key).or_insert(Entry {
+            value: value.clone(),
+            count: 0,
+            seen: seen,
+        });
+        entry.value = value;
+        entry.count += 1;
+        entry.seen = seen;
+    }
+
+    /// Update the latest value of a value that was recorded. Returns `false` if the value isn't
+    /// known, i.e. if the adapter supports it.
+    pub fn update(&self, home_id: u32, value_id: u64, value: Option<JSON>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.counter += 1;
+        let seen = state.counter;
+        match state.entries.get_mut(&(home_id, value_id)) {
+            Some(entry) => {
+                entry.value.value = value;
+                entry.count += 1;
+                entry.seen = seen;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget a value, e.g. once it has been removed from its node.
+    pub fn remove(&self, home_id: u32, value_id: u64) {
+        self.state.lock().unwrap().entries.remove(&(home_id, value_id));
+    }
+
+    /// Forget the values of a network, e.g. once its controller has been unplugged.
+    pub fn remove_home(&self, home_id: u32) {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<_> = state.entries
+            .keys()
+            .filter(|&&(home, _)| home == home_id)
+            .cloned()
+            .collect();
+        for key in keys {
+            state.entries.remove(&key);
+        }
+    }
+
+    /// The values of a network as JSON, sorted by node, i.e. an array of
+    /// `{"node": number, "command_class": string, "type": string, "index": number,
+    ///   "label": string, "value": ... | null, "count": number}`.
+    pub fn as_json(&self, home_id: u32) -> JSON {
+        let state = self.state.lock().unwrap();
+        let mut entries: Vec<_> = state.entries
+            .iter()
+            .filter(|&(&(home, _), _)| home == home_id)
+            .map(|(_, entry)| entry)
+            .collect();
+        entries.sort_by_key(|entry| (entry.value.node, entry.value.command_class.clone(),
+                                     entry.value.index));
+        let entries = entries.iter()
+            .map(|entry| {
+                vec![("node", JSON::U64(entry.value.node as u64)),
+                     ("command_class", entry.value.command_class.to_json()),
+                     ("type", entry.value.value_type.to_json()),
+                     ("index", JSON::U64(entry.value.index as u64)),
+                     ("label", entry.value.label.to_json()),
+                     ("value", entry.value.value.clone().unwrap_or(JSON::Null)),
+                     ("count", JSON::U64(entry.count))]
+                    .to_json()
+            })
+            .collect();
+        JSON::Array(entries)
+    }
+}
+
+#[test]
+fn test_unsupported_log() {
+    let log = UnsupportedLog::new();
+    let value = |node: u8, index: u8| {
+        UnsupportedValue {
+            node: node,
+            command_class: "Meter".to_owned(),
+            value_type: "ValueType_Decimal".to_owned(),
+            index: index,
+            label: "Energy".to_owned(),
+            value: Some(JSON::F64(1.5)),
+        }
+    };
+    assert_eq!(log.as_json(1), JSON::Array(vec![]));
+
+    log.record(1, 20, value(3, 0));
+    log.record(1, 10, value(2, 0));
+    log.record(2, 10, value(2, 0));
+    assert!(log.update(1, 20, Some(JSON::F64(2.))));
+    assert!(!log.update(1, 30, None));
+
+    let json = log.as_json(1);
+    let entries = json.as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].find("node"), Some(&JSON::U64(2)));
+    assert_eq!(entries[1].find("value"), Some(&JSON::F64(2.)));
+    assert_eq!(entries[1].find("count"), Some(&JSON::U64(2)));
+
+    log.remove_home(1);
+    assert_eq!(log.as_json(1), JSON::Array(vec![]));
+    assert_eq!(log.as_json(2).as_array().unwrap().len(), 1);
+    log.remove(2, 10);
+    assert_eq!(log.as_json(2), JSON::Array(vec![]));
+
+    // The log is capped, forgetting the value heard from least recently.
+    for id in 0..MAX_ENTRIES as u64 {
+        log.record(1, id, value(1, 0));
+    }
+    log.update(1, 0, None);
+    log.record(1, 1000, value(1, 0));
+    let state = log.state.lock().unwrap();
+    assert_eq!(state.entries.len(), MAX_ENTRIES);
+    assert!(state.entries.contains_key(&(1, 0)));
+    assert!(!state.entries.contains_key(&(1, 1)));
+}
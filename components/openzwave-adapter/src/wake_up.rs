@@ -0,0 +1,99 @@
+use taxonomy::channel::Channel;
+use taxonomy::services::ServiceId;
+use taxonomy::util::Id as TaxoId;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Battery-powered devices (sensors, thermostatic valves, ...) spend most of their time asleep,
+/// waking up every few minutes or hours (the "wake-up interval") to listen for commands. This
+/// keeps track of the sleeping nodes, and of the writes waiting for them to wake up.
+///
+/// Only the latest write to a channel is kept, as it overwrites the previous ones anyway.
+#[derive(Clone)]
+pub struct WakeUpQueue<Write> {
+    state: Arc<Mutex<State<Write>>>,
+}
+
+struct State<Write> {
+    asleep: HashSet<TaxoId<ServiceId>>,
+    pending: HashMap<TaxoId<ServiceId>, Vec<(TaxoId<Channel>, Write)>>,
+}
+
+impl<Write> WakeUpQueue<Write> {
+    pub fn new() -> Self {
+        WakeUpQueue {
+            state: Arc::new(Mutex::new(State {
+                asleep: HashSet::new(),
+                pending: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn is_asleep(&self, service: &TaxoId<ServiceId>) -> bool {
+        self.state.lock().unwrap().asleep.contains(service)
+    }
+
+    pub fn fall_asleep(&self, service: &TaxoId<ServiceId>) {
+        self.state.lock().unwrap().asleep.insert(service.clone());
+    }
+
+    /// Mark the node as awake, returning the writes waiting for it, oldest first.
+    pub fn wake_up(&self, service: &TaxoId<ServiceId>) -> Vec<(TaxoId<Channel>, Write)> {
+        let mut state = self.state.lock().unwrap();
+        state.asleep.remove(service);
+        state.pending.remove(service).unwrap_or_else(Vec::new)
+    }
+
+    /// Keep `write` until the node of `service` wakes up. If the node is awake, `write` is
+    /// returned to be performed right away.
+    pub fn push(&self,
+                service: &TaxoId<ServiceId>,
+                channel: TaxoId<Channel>,
+                write: Write)
+                -> Option<Write> {
+        let mut state = self.state.lock().unwrap();
+        if !state.asleep.contains(service) {
+            return Some(write);
+        }
+        let writes = state.pending.entry(service.clone()).or_insert_with(Vec::new);
+        writes.retain(|&(ref id, _)| *id != channel);
+        writes.push((channel, write));
+        None
+    }
+
+    /// Forget a node that left the network, along with its pending writes.
+    pub fn remove(&self, service: &TaxoId<ServiceId>) {
+        let mut state = self.state.lock().unwrap();
+        state.asleep.remove(service);
+        state.pending.remove(service);
+    }
+}
+
+#[test]
+fn test_wake_up_queue() {
+    let queue = WakeUpQueue::<u32>::new();
+    let sensor = TaxoId::new("sensor");
+    let interval = TaxoId::<Channel>::new("interval");
+    let parameter = TaxoId::<Channel>::new("parameter");
+
+    // Writes to awake nodes go through.
+    assert_eq!(queue.push(&sensor, interval.clone(), 1), Some(1));
+
+    queue.fall_asleep(&sensor);
+    assert!(queue.is_asleep(&sensor));
+    assert_eq!(queue.push(&sensor, interval.clone(), 1), None);
+    assert_eq!(queue.push(&sensor, parameter.clone(), 2), None);
+    // Only the latest write to a channel is kept.
+    assert_eq!(queue.push(&sensor, interval.clone(), 3), None);
+
+    assert_eq!(queue.wake_up(&sensor), vec![(parameter, 2), (interval.clone(), 3)]);
+    assert!(!queue.is_asleep(&sensor));
+    assert_eq!(queue.wake_up(&sensor), vec![]);
+
+    queue.fall_asleep(&sensor);
+    assert_eq!(queue.push(&sensor, interval, 4), None);
+    queue.remove(&sensor);
+    assert!(!queue.is_asleep(&sensor));
+    assert_eq!(queue.wake_up(&sensor), vec![]);
+}
@@ -1,6 +1,8 @@
-use api::{Error, Operation, User};
+use alerts::Severity;
+use api::{Error, Observation, Operation, User};
 use channel::Channel;
 use io::*;
+use parse::{JSON, ToJSON};
 use services::*;
 use values::*;
 
@@ -11,6 +13,38 @@ use std::sync::Arc;
 
 pub type ResultMap<K, T, E> = HashMap<K, Result<T, E>>;
 
+/// How an adapter is doing, as reported by the adapter itself, see
+/// `AdapterManagerHandle::report_adapter_status`.
+///
+/// # JSON
+///
+/// An object with a field `status` (`"running"`, `"degraded"` or `"failed"`) and, unless the
+/// adapter is running, a field `reason`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdapterStatus {
+    /// The adapter works.
+    Running,
+    /// The adapter works, but without something it needs, e.g. no Z-Wave stick is plugged.
+    Degraded(String),
+    /// The adapter can't do anything, e.g. its device can't be read.
+    Failed(String),
+}
+
+impl ToJSON for AdapterStatus {
+    fn to_json(&self) -> JSON {
+        let (status, reason) = match *self {
+            AdapterStatus::Running => ("running", None),
+            AdapterStatus::Degraded(ref reason) => ("degraded", Some(reason)),
+            AdapterStatus::Failed(ref reason) => ("failed", Some(reason)),
+        };
+        let mut fields = vec![("status", status.to_json())];
+        if let Some(reason) = reason {
+            fields.push(("reason", reason.to_json()));
+        }
+        fields.to_json()
+    }
+}
+
 /// A witness that we are currently watching for a value.
 /// Watching stops when the guard is dropped.
 pub trait AdapterWatchGuard: Send + Sync {}
@@ -61,6 +95,18 @@ pub trait AdapterManagerHandle: Send {
     /// cleanup before returning an error.
     fn remove_service(&self, service_id: &Id<ServiceId>) -> Result<(), Error>;
 
+    /// Update some of the properties of a service previously registered on the system,
+    /// e.g. when a device reports a new name or location. Properties that are not mentioned
+    /// are left unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no such service.
+    fn update_service_properties(&self,
+                                 service_id: &Id<ServiceId>,
+                                 properties: HashMap<String, String>)
+                                 -> Result<(), Error>;
+
     /// Add a channel to the system. Typically, this is called by the adapter when a new
     /// service has been detected/configured. Some services may gain/lose channels at
     /// runtime depending on their configuration.
@@ -76,6 +122,15 @@ pub trait AdapterManagerHandle: Send {
     /// In either cases, this method reverts all its changes.
     fn add_channel(&self, setter: Channel) -> Result<(), Error>;
 
+    /// Add a service along with its channels. Either all of them are added, or none of them,
+    /// so that a failure doesn't leave a partial service.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error that `add_service` or `add_channel` would have returned, after
+    /// having reverted the changes.
+    fn add_service_with_channels(&self, builder: ServiceBuilder) -> Result<(), Error>;
+
     /// Remove a setter previously registered on the system. Typically, called by
     /// an adapter when a service is reconfigured to remove one of its getters.
     ///
@@ -85,16 +140,46 @@ pub trait AdapterManagerHandle: Send {
     /// is not registered. In either case, it attemps to clean as much as possible, even
     /// if the state is inconsistent.
     fn remove_channel(&self, id: &Id<Channel>) -> Result<(), Error>;
+
+    /// Tell the system that the device behind a service can be reached again, e.g. a sleeping
+    /// device just woke up, to deliver the values queued for it while it couldn't be reached
+    /// (see `Error::Unreachable`).
+    ///
+    /// Values are sent to the adapter before this method returns, so it must not be called
+    /// while holding a lock needed to send values.
+    fn service_reachable(&self, id: &Id<ServiceId>);
+
+    /// Tell the user about a condition that needs their attention, e.g. a hub that was
+    /// detected but isn't paired yet, along with what they should do about it, if anything.
+    ///
+    /// Alerts are kept until the user dismisses them. Raising an alert identical to one that
+    /// is still active does nothing.
+    fn raise_alert(&self, severity: Severity, message: &str, action_hint: Option<&str>);
+
+    /// Tell the user how an adapter is doing, e.g. that it started but couldn't find its
+    /// device, rather than failing silently. The adapter doesn't need to be registered, so
+    /// that an adapter that gives up during its initialization may still report why.
+    ///
+    /// Only the latest status reported for each adapter is kept.
+    fn report_adapter_status(&self, id: &Id<AdapterId>, status: AdapterStatus);
 }
 
 pub enum WatchEvent<V> {
     /// Fired when we enter the range specified when we started watching, or if no range was
     /// specified, fired whenever a new value is available.
-    Enter { id: Id<Channel>, value: V },
+    Enter {
+        id: Id<Channel>,
+        value: V,
+        observed: Observation,
+    },
 
     /// Fired when we exit the range specified when we started watching. If no range was
     /// specified, never fired.
-    Exit { id: Id<Channel>, value: V },
+    Exit {
+        id: Id<Channel>,
+        value: V,
+        observed: Observation,
+    },
 
     Error { id: Id<Channel>, error: Error },
 }
@@ -122,7 +207,7 @@ pub trait RawAdapter: Send + Sync {
             .map(|(id, _)| (id.clone(), Err(Error::OperationNotSupported(Operation::Watch, id))))
             .collect()
     }
-    fn register_watch(&self, mut target: Vec<RawWatchTarget>) -> WatchResult {
+    fn register_watch(&self, mut target: Vec<RawWatchTarget>, _: User) -> WatchResult {
         target.drain(..)
             .map(|(id, _, _, _)| {
                 (id.clone(), Err(Error::OperationNotSupported(Operation::Watch, id)))
@@ -130,6 +215,11 @@ pub trait RawAdapter: Send + Sync {
             .collect()
     }
 
+    /// Check that the adapter is still responsive, see `Adapter::heartbeat`.
+    fn heartbeat(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
     /// Signal the adapter that it is time to stop.
     ///
     /// Ideally, the adapter should not return until all its threads have been stopped.
@@ -194,12 +284,25 @@ pub trait Adapter: Send + Sync {
     ///
     /// If a `Range` option is set, the watcher expects to receive `EnterRange`/`ExitRange` events
     /// whenever the value available on the device enters/exits the range.
-    fn register_watch(&self, mut watch: Vec<WatchTarget>) -> WatchResult {
+    ///
+    /// As with `fetch_values` and `send_values`, `User` is the user on behalf of whom the watch
+    /// is registered, e.g. to scope the watch to this user.
+    fn register_watch(&self, mut watch: Vec<WatchTarget>, _: User) -> WatchResult {
         watch.drain(..)
             .map(|(id, _, _)| (id.clone(), Err(Error::OperationNotSupported(Operation::Watch, id))))
             .collect()
     }
 
+    /// Check that the adapter is still responsive.
+    ///
+    /// Called periodically by the watchdog of the `AdapterManager`, from a thread of its own.
+    /// Adapters that return an error, or don't return within a few seconds, are reported as
+    /// unhealthy, see `AdapterManager::adapter_health`. Adapters that depend on a connection,
+    /// a process or a thread of their own should check it here.
+    fn heartbeat(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
     /// Signal the adapter that it is time to stop.
     ///
     /// Ideally, the adapter should not return until all its threads have been stopped.
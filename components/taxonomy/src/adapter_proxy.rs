@@ -0,0 +1,1313 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Running adapters in separate processes.
+//!
+//! An adapter that crashes, e.g. in its C++ bindings, takes the whole box down with it, unless
+//! it runs in a process of its own. In the box, an `AdapterProxy` stands for such an adapter:
+//! it forwards fetch, send and watch requests over a unix socket to the adapter process, which
+//! is typically kept running by `foxbox_core::managed_process::ManagedProcess`. In the adapter
+//! process, a `RemoteManager` stands for the `AdapterManager`: the adapter registers its
+//! services and channels with it as usual, and it answers the requests of the box.
+//!
+//! Messages are JSON objects, one per line. Values are serialized with the format of their
+//! channel, so the channels of out-of-process adapters may only use the formats of
+//! `values::format`.
+//!
+//! Whenever the adapter process stops, e.g. it crashed, the proxy removes its services and
+//! fails the pending calls. Once restarted, the process connects again and registers its
+//! services anew, and the proxy registers the watches again.
+
+use adapter::*;
+use alerts::Severity;
+use api::{Error, InternalError, Observation, Operation, Source, User};
+use channel::{Channel, FeatureId, Signature};
+use io::{BinarySource, BinaryTarget, Format};
+use parse::{JSON, Path};
+use services::*;
+use thread_pool::ThreadPool;
+use values::{format, Value};
+
+use serde_json;
+use transformable_channels::mpsc::ExtSender;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path as FilePath;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// How long the box waits for the adapter process to answer a fetch or a send.
+const CALL_TIMEOUT_S: u64 = 30;
+
+/// How long a write to the other process may block, after which the connection is closed.
+const WRITE_TIMEOUT_S: u64 = 10;
+
+/// The requests of the box that an adapter process handles at the same time.
+const REQUEST_THREADS: usize = 8;
+
+/// The requests waiting for a thread in the adapter process, beyond which they fail.
+const MAX_QUEUED_REQUESTS: usize = 100;
+
+/// The formats that values may have to cross the process boundary, by description.
+fn standard_format(description: &str) -> Option<Arc<Format>> {
+    let formats = vec![format::ON_OFF.clone(),
+                       format::OPEN_CLOSED.clone(),
+                       format::IS_SECURE.clone(),
+                       format::IS_LOCKED.clone(),
+                       format::COLOR.clone(),
+                       format::JSON.clone(),
+                       format::STRING.clone(),
+                       format::UNIT.clone(),
+                       format::BINARY.clone(),
+                       format::TIMESTAMP.clone(),
+                       format::DURATION.clone(),
+                       format::PERCENT.clone(),
+                       format::IS_DETECTED.clone(),
+                       format::PERCENT_RANGE.clone(),
+                       format::TEMPERATURE.clone(),
+                       format::HUMIDITY.clone(),
+                       format::LUMINANCE.clone(),
+                       format::POWER.clone(),
+                       format::ENERGY.clone()];
+    formats.into_iter().find(|format| format.description() == description)
+}
+
+/// A `Maybe<Arc<Format>>`, with the format represented by its description.
+#[derive(Debug, Serialize, Deserialize)]
+enum RemoteMaybe {
+    Required(String),
+    Optional(String),
+    Nothing,
+}
+
+impl RemoteMaybe {
+    fn new(source: &Maybe<Arc<Format>>) -> Self {
+        match *source {
+            Maybe::Required(ref format) => RemoteMaybe::Required(format.description()),
+            Maybe::Optional(ref format) => RemoteMaybe::Optional(format.description()),
+            Maybe::Nothing => RemoteMaybe::Nothing,
+        }
+    }
+
+    fn resolve(&self) -> Result<Maybe<Arc<Format>>, Error> {
+        let resolve = |description: &str| {
+            standard_format(description).ok_or_else(|| {
+                Error::Internal(InternalError::GenericError(format!("Unsupported format {}",
+                                                                    description)))
+            })
+        };
+        Ok(match *self {
+            RemoteMaybe::Required(ref description) => Maybe::Required(try!(resolve(description))),
+            RemoteMaybe::Optional(ref description) => Maybe::Optional(try!(resolve(description))),
+            RemoteMaybe::Nothing => Maybe::Nothing,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RemoteSignature {
+    accepts: RemoteMaybe,
+    returns: RemoteMaybe,
+}
+
+impl RemoteSignature {
+    fn new(source: &Option<Signature>) -> Option<Self> {
+        source.as_ref().map(|signature| {
+            RemoteSignature {
+                accepts: RemoteMaybe::new(&signature.accepts),
+                returns: RemoteMaybe::new(&signature.returns),
+            }
+        })
+    }
+
+    fn resolve(source: &Option<Self>) -> Result<Option<Signature>, Error> {
+        match *source {
+            None => Ok(None),
+            Some(ref signature) => {
+                Ok(Some(Signature {
+                    accepts: try!(signature.accepts.resolve()),
+                    returns: try!(signature.returns.resolve()),
+                }))
+            }
+        }
+    }
+}
+
+/// A service, as registered by the adapter process.
+#[derive(Debug, Serialize, Deserialize)]
+struct RemoteService {
+    id: Id<ServiceId>,
+    tags: Vec<Id<TagId>>,
+    properties: HashMap<String, String>,
+    friendly_name: Option<String>,
+    room: Option<String>,
+    icon: Option<String>,
+}
+
+impl RemoteService {
+    fn new(service: &Service) -> Self {
+        RemoteService {
+            id: service.id.clone(),
+            tags: service.tags.iter().cloned().collect(),
+            properties: service.properties.clone(),
+            friendly_name: service.metadata.friendly_name.clone(),
+            room: service.metadata.room.clone(),
+            icon: service.metadata.icon.clone(),
+        }
+    }
+
+    fn resolve(self, adapter: &Id<AdapterId>) -> Service {
+        let mut service = Service::empty(&self.id, adapter);
+        service.tags = self.tags.into_iter().collect();
+        service.properties = self.properties;
+        service.metadata = ServiceMetadata {
+            friendly_name: self.friendly_name,
+            room: self.room,
+            icon: self.icon,
+        };
+        service
+    }
+}
+
+/// A channel, as registered by the adapter process.
+#[derive(Debug, Serialize, Deserialize)]
+struct RemoteChannel {
+    id: Id<Channel>,
+    service: Id<ServiceId>,
+    feature: Id<FeatureId>,
+    tags: Vec<Id<TagId>>,
+    supports_send: Option<RemoteSignature>,
+    supports_fetch: Option<RemoteSignature>,
+    supports_watch: Option<RemoteSignature>,
+    record_history: bool,
+    valid_for_ms: Option<u64>,
+    label: Option<String>,
+    description: Option<String>,
+    icon: Option<String>,
+}
+
+impl RemoteChannel {
+    fn new(channel: &Channel) -> Self {
+        RemoteChannel {
+            id: channel.id.clone(),
+            service: channel.service.clone(),
+            feature: channel.feature.clone(),
+            tags: channel.tags.iter().cloned().collect(),
+            supports_send: RemoteSignature::new(&channel.supports_send),
+            supports_fetch: RemoteSignature::new(&channel.supports_fetch),
+            supports_watch: RemoteSignature::new(&channel.supports_watch),
+            record_history: channel.record_history,
+            valid_for_ms: channel.valid_for.map(|valid_for| {
+                valid_for.as_secs() * 1000 + (valid_for.subsec_nanos() / 1_000_000) as u64
+            }),
+            label: channel.label.clone(),
+            description: channel.description.clone(),
+            icon: channel.icon.clone(),
+        }
+    }
+
+    fn resolve(self, adapter: &Id<AdapterId>) -> Result<Channel, Error> {
+        Ok(Channel {
+            id: self.id,
+            service: self.service,
+            adapter: adapter.clone(),
+            feature: self.feature,
+            tags: self.tags.into_iter().collect(),
+            supports_send: try!(RemoteSignature::resolve(&self.supports_send)),
+            supports_fetch: try!(RemoteSignature::resolve(&self.supports_fetch)),
+            supports_watch: try!(RemoteSignature::resolve(&self.supports_watch)),
+            record_history: self.record_history,
+            valid_for: self.valid_for_ms.map(Duration::from_millis),
+            label: self.label,
+            description: self.description,
+            icon: self.icon,
+        })
+    }
+}
+
+/// An `Error` that crossed the process boundary. Only the errors that the box acts upon keep
+/// their meaning, the others are reported as internal errors.
+#[derive(Debug, Serialize, Deserialize)]
+enum RemoteError {
+    NotSupported,
+    InvalidValue,
+    Unreachable,
+    Other(String),
+}
+
+impl RemoteError {
+    fn new(error: &Error) -> Self {
+        match *error {
+            Error::OperationNotSupported(..) => RemoteError::NotSupported,
+            Error::InvalidValue => RemoteError::InvalidValue,
+            Error::Unreachable(_) => RemoteError::Unreachable,
+            ref error => RemoteError::Other(format!("{}", error)),
+        }
+    }
+
+    fn resolve(self, operation: Operation, channel: &Id<Channel>) -> Error {
+        match self {
+            RemoteError::NotSupported => Error::OperationNotSupported(operation, channel.clone()),
+            RemoteError::InvalidValue => Error::InvalidValue,
+            RemoteError::Unreachable => Error::Unreachable(channel.clone()),
+            RemoteError::Other(message) => Error::Internal(InternalError::GenericError(message)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum RemoteResult {
+    Ok(Option<JSON>),
+    Err(RemoteError),
+}
+
+/// A message from the box to the adapter process.
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    Fetch {
+        call: u64,
+        channels: Vec<Id<Channel>>,
+        user: Option<String>,
+    },
+    Send {
+        call: u64,
+        values: Vec<(Id<Channel>, JSON)>,
+        user: Option<String>,
+    },
+    Watch {
+        watch: u64,
+        channel: Id<Channel>,
+        range: Option<JSON>,
+        user: Option<String>,
+    },
+    Unwatch { watch: u64 },
+    /// Answered by `Done`, with no results if the adapter is healthy, or with the error of
+    /// its heartbeat.
+    Heartbeat { call: u64 },
+    Stop,
+}
+
+/// A message from the adapter process to the box.
+#[derive(Debug, Serialize, Deserialize)]
+enum Notification {
+    AddService(RemoteService),
+    RemoveService(Id<ServiceId>),
+    UpdateServiceProperties(Id<ServiceId>, HashMap<String, String>),
+    AddChannel(RemoteChannel),
+    RemoveChannel(Id<Channel>),
+    ServiceReachable(Id<ServiceId>),
+    RaiseAlert {
+        severity: String,
+        message: String,
+        action_hint: Option<String>,
+    },
+    Done {
+        call: u64,
+        results: Vec<(Id<Channel>, RemoteResult)>,
+    },
+    Event {
+        watch: u64,
+        channel: Id<Channel>,
+        value: JSON,
+        is_enter: bool,
+        timestamp: u64,
+        source: String,
+    },
+    WatchError {
+        watch: u64,
+        channel: Id<Channel>,
+        error: RemoteError,
+    },
+}
+
+fn user_as_remote(user: &User) -> Option<String> {
+    match *user {
+        User::Id(ref id) => Some(id.clone()),
+        User::None => None,
+    }
+}
+
+fn user_from_remote(user: Option<String>) -> User {
+    match user {
+        Some(id) => User::Id(id),
+        None => User::None,
+    }
+}
+
+/// Write a message as a single line.
+fn write_line<T: ::serde::Serialize>(stream: &mut UnixStream, message: &T) -> io::Result<()> {
+    let mut line = try!(serde_json::to_string(message)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}", err))));
+    line.push('\n');
+    stream.write_all(line.as_bytes())
+}
+
+fn parse_value(format: &Format, json: &JSON) -> Result<Value, Error> {
+    format.parse(Path::new(), json, &BinarySource)
+}
+
+fn serialize_value(format: &Format, value: &Value) -> Result<JSON, Error> {
+    format.serialize(value, &BinaryTarget::inline())
+}
+
+/// The format of the values returned by an operation on a channel, if any.
+fn returned_format(signature: &Option<Signature>) -> Option<Arc<Format>> {
+    match *signature {
+        Some(Signature { returns: Maybe::Required(ref format), .. }) |
+        Some(Signature { returns: Maybe::Optional(ref format), .. }) => Some(format.clone()),
+        _ => None,
+    }
+}
+
+/// The format of the values accepted by an operation on a channel, if any.
+fn accepted_format(signature: &Option<Signature>) -> Option<Arc<Format>> {
+    match *signature {
+        Some(Signature { accepts: Maybe::Required(ref format), .. }) |
+        Some(Signature { accepts: Maybe::Optional(ref format), .. }) => Some(format.clone()),
+        _ => None,
+    }
+}
+
+struct ProxyWatch {
+    channel: Id<Channel>,
+    range: Option<JSON>,
+    user: Option<String>,
+    on_event: Box<ExtSender<WatchEvent<Value>>>,
+}
+
+#[derive(Default)]
+struct ProxyState {
+    /// The services and channels registered by the adapter process.
+    services: HashSet<Id<ServiceId>>,
+    channels: HashMap<Id<Channel>, Channel>,
+    counter: u64,
+    /// The calls waiting for the adapter process to answer.
+    calls: HashMap<u64, Sender<Vec<(Id<Channel>, RemoteResult)>>>,
+    /// The watches, registered again whenever the adapter process connects.
+    watches: HashMap<u64, ProxyWatch>,
+}
+
+impl ProxyState {
+    fn next(&mut self) -> u64 {
+        self.counter += 1;
+        self.counter
+    }
+}
+
+/// Where requests are written, while the adapter process is connected. It is kept apart from
+/// `ProxyState`, so that an adapter process slow to read doesn't block the notifications and
+/// calls that only need the state. Writes are serialized, so that lines don't interleave.
+#[derive(Clone, Default)]
+struct Connection {
+    stream: Arc<Mutex<Option<UnixStream>>>,
+}
+
+impl Connection {
+    fn set(&self, stream: Option<UnixStream>) {
+        *self.stream.lock().unwrap() = stream;
+    }
+
+    /// Write a request to the adapter process. If it is broken, or blocks for longer than
+    /// `WRITE_TIMEOUT_S`, the connection is closed, and the adapter process is expected to
+    /// connect again.
+    fn write(&self, request: &Request) -> bool {
+        let mut stream = self.stream.lock().unwrap();
+        let is_ok = match *stream {
+            Some(ref mut stream) => write_line(stream, request).is_ok(),
+            None => return false,
+        };
+        if !is_ok {
+            if let Some(stream) = stream.take() {
+                // A line may have been written in part, don't let the other side read on.
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        is_ok
+    }
+}
+
+struct ProxyWatchGuard {
+    watch: u64,
+    state: Arc<Mutex<ProxyState>>,
+    connection: Connection,
+}
+
+impl AdapterWatchGuard for ProxyWatchGuard {}
+
+impl Drop for ProxyWatchGuard {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().watches.remove(&self.watch);
+        self.connection.write(&Request::Unwatch { watch: self.watch });
+    }
+}
+
+/// An adapter running in another process, see the module documentation.
+///
+/// The proxy must be registered with the `AdapterManager` before it starts listening for
+/// the adapter process, so that the latter may register its services.
+pub struct AdapterProxy {
+    id: Id<AdapterId>,
+    name: String,
+    vendor: String,
+    version: [u32; 4],
+    state: Arc<Mutex<ProxyState>>,
+    connection: Connection,
+}
+
+impl AdapterProxy {
+    pub fn new(id: &Id<AdapterId>, name: &str, vendor: &str, version: [u32; 4]) -> Self {
+        AdapterProxy {
+            id: id.clone(),
+            name: name.to_owned(),
+            vendor: vendor.to_owned(),
+            version: version,
+            state: Arc::new(Mutex::new(ProxyState::default())),
+            connection: Connection::default(),
+        }
+    }
+
+    /// Wait for the adapter process to connect on a unix socket at `path`, then serve it. Only
+    /// one adapter process is connected at a time: a new connection means that the adapter
+    /// process has been restarted.
+    pub fn listen<M>(&self, manager: Arc<M>, path: &FilePath) -> io::Result<()>
+        where M: AdapterManagerHandle + Send + Sync + 'static
+    {
+        // Remove the socket left over by a previous run, if any.
+        let _ = fs::remove_file(path);
+        let listener = try!(UnixListener::bind(path));
+        let id = self.id.clone();
+        let state = self.state.clone();
+        let connection = self.connection.clone();
+        try!(thread::Builder::new()
+            .name(format!("AdapterProxy-{}", id))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => Self::serve(&id, &state, &connection, &*manager, stream),
+                        Err(err) => {
+                            error!("[AdapterProxy] Could not accept the adapter process of {}: {}",
+                                   id,
+                                   err);
+                        }
+                    }
+                }
+            }));
+        Ok(())
+    }
+
+    /// Serve an adapter process until it disconnects.
+    fn serve<M: AdapterManagerHandle>(id: &Id<AdapterId>,
+                                      state: &Arc<Mutex<ProxyState>>,
+                                      connection: &Connection,
+                                      manager: &M,
+                                      stream: UnixStream) {
+        info!("[AdapterProxy] The adapter process of {} is connected", id);
+        if let Err(err) = stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_S))) {
+            error!("[AdapterProxy] Could not set the write timeout of {}: {}", id, err);
+            return;
+        }
+        let reader = match stream.try_clone() {
+            Ok(reader) => BufReader::new(reader),
+            Err(err) => {
+                error!("[AdapterProxy] Could not read from the adapter process of {}: {}",
+                       id,
+                       err);
+                return;
+            }
+        };
+        let watches: Vec<_> = {
+            let state = state.lock().unwrap();
+            connection.set(Some(stream));
+            state.watches
+                .iter()
+                .map(|(watch, proxy)| {
+                    Request::Watch {
+                        watch: *watch,
+                        channel: proxy.channel.clone(),
+                        range: proxy.range.clone(),
+                        user: proxy.user.clone(),
+                    }
+                })
+                .collect()
+        };
+        for request in watches {
+            connection.write(&request);
+        }
+        manager.report_adapter_status(id, AdapterStatus::Running);
+
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            match serde_json::from_str(&line) {
+                Ok(notification) => Self::handle(id, state, manager, notification),
+                Err(err) => {
+                    warn!("[AdapterProxy] Invalid message from the adapter process of {}: {}",
+                          id,
+                          err)
+                }
+            }
+        }
+
+        // The adapter process stopped. Dropping the senders fails the pending calls.
+        warn!("[AdapterProxy] The adapter process of {} is disconnected", id);
+        let services: Vec<_> = {
+            let mut state = state.lock().unwrap();
+            connection.set(None);
+            state.calls.clear();
+            state.channels.clear();
+            state.services.drain().collect()
+        };
+        for service in services {
+            let _ = manager.remove_service(&service);
+        }
+        manager.report_adapter_status(id,
+                                      AdapterStatus::Degraded("The adapter process stopped."
+                                          .to_owned()));
+    }
+
+    fn handle<M: AdapterManagerHandle>(id: &Id<AdapterId>,
+                                       state: &Arc<Mutex<ProxyState>>,
+                                       manager: &M,
+                                       notification: Notification) {
+        match notification {
+            Notification::AddService(service) => {
+                let service = service.resolve(id);
+                let service_id = service.id.clone();
+                match manager.add_service(service) {
+                    Ok(()) => {
+                        state.lock().unwrap().services.insert(service_id);
+                    }
+                    Err(err) => warn!("[AdapterProxy] Could not add {}: {}", service_id, err),
+                }
+            }
+            Notification::RemoveService(service_id) => {
+                state.lock().unwrap().services.remove(&service_id);
+                let _ = manager.remove_service(&service_id);
+            }
+            Notification::UpdateServiceProperties(service_id, properties) => {
+                let _ = manager.update_service_properties(&service_id, properties);
+            }
+            Notification::AddChannel(channel) => {
+                let channel_id = channel.id.clone();
+                let result = channel.resolve(id).and_then(|channel| {
+                    state.lock().unwrap().channels.insert(channel.id.clone(), channel.clone());
+                    manager.add_channel(channel)
+                });
+                if let Err(err) = result {
+                    state.lock().unwrap().channels.remove(&channel_id);
+                    warn!("[AdapterProxy] Could not add {}: {}", channel_id, err);
+                }
+            }
+            Notification::RemoveChannel(channel_id) => {
+                state.lock().unwrap().channels.remove(&channel_id);
+                let _ = manager.remove_channel(&channel_id);
+            }
+            Notification::ServiceReachable(service_id) => manager.service_reachable(&service_id),
+            Notification::RaiseAlert { severity, message, action_hint } => {
+                let severity = Severity::parse(&severity).unwrap_or(Severity::Warning);
+                manager.raise_alert(severity, &message, action_hint.as_ref().map(|s| &s[..]));
+            }
+            Notification::Done { call, results } => {
+                // Don't hold the lock while answering.
+                let tx = state.lock().unwrap().calls.remove(&call);
+                if let Some(tx) = tx {
+                    let _ = tx.send(results);
+                }
+            }
+            Notification::Event { watch, channel, value, is_enter, timestamp, source } => {
+                let (on_event, format) = {
+                    let state = state.lock().unwrap();
+                    let watch = match state.watches.get(&watch) {
+                        Some(watch) => watch,
+                        None => return,
+                    };
+                    match state.channels
+                        .get(&channel)
+                        .and_then(|data| returned_format(&data.supports_watch)) {
+                        Some(format) => (watch.on_event.clone(), format),
+                        None => return,
+                    }
+                };
+                let observed = Observation {
+                    timestamp: timestamp,
+                    source: match &source[..] {
+                        "fetch" => Source::Fetch,
+                        "cache" => Source::Cache,
+                        _ => Source::Device,
+                    },
+                };
+                let event = match parse_value(&format, &value) {
+                    Ok(value) if is_enter => {
+                        WatchEvent::Enter {
+                            id: channel,
+                            value: value,
+                            observed: observed,
+                        }
+                    }
+                    Ok(value) => {
+                        WatchEvent::Exit {
+                            id: channel,
+                            value: value,
+                            observed: observed,
+                        }
+                    }
+                    Err(err) => {
+                        WatchEvent::Error {
+                            id: channel,
+                            error: err,
+                        }
+                    }
+                };
+                let _ = on_event.send(event);
+            }
+            Notification::WatchError { watch, channel, error } => {
+                let on_event = state.lock()
+                    .unwrap()
+                    .watches
+                    .get(&watch)
+                    .map(|watch| watch.on_event.clone());
+                if let Some(on_event) = on_event {
+                    let error = error.resolve(Operation::Watch, &channel);
+                    let _ = on_event.send(WatchEvent::Error {
+                        id: channel,
+                        error: error,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Send a request to the adapter process and wait for its results, or `None` if the
+    /// adapter process is not connected or doesn't answer in time.
+    fn call<F>(&self, request: F) -> Option<HashMap<Id<Channel>, RemoteResult>>
+        where F: FnOnce(u64) -> Request
+    {
+        let (tx, rx) = channel();
+        let call = {
+            let mut state = self.state.lock().unwrap();
+            let call = state.next();
+            state.calls.insert(call, tx);
+            call
+        };
+        if !self.connection.write(&request(call)) {
+            self.state.lock().unwrap().calls.remove(&call);
+            return None;
+        }
+        match rx.recv_timeout(Duration::from_secs(CALL_TIMEOUT_S)) {
+            Ok(results) => Some(results.into_iter().collect()),
+            Err(_) => {
+                // The adapter process may still answer, too late.
+                self.state.lock().unwrap().calls.remove(&call);
+                None
+            }
+        }
+    }
+
+    /// The format of the values of a channel, as found by `find` in its declaration.
+    fn format_of<F>(&self, id: &Id<Channel>, find: F) -> Option<Arc<Format>>
+        where F: Fn(&Channel) -> Option<Arc<Format>>
+    {
+        self.state.lock().unwrap().channels.get(id).and_then(find)
+    }
+}
+
+impl Adapter for AdapterProxy {
+    fn id(&self) -> Id<AdapterId> {
+        self.id.clone()
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn vendor(&self) -> &str {
+        &self.vendor
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &self.version
+    }
+
+    fn fetch_values(&self, target: Vec<Id<Channel>>, user: User) -> OpResult<Value> {
+        let channels = target.clone();
+        let mut results = self.call(move |call| {
+                Request::Fetch {
+                    call: call,
+                    channels: channels,
+                    user: user_as_remote(&user),
+                }
+            })
+            .unwrap_or_else(HashMap::new);
+        target.into_iter()
+            .map(|id| {
+                let result = match results.remove(&id) {
+                    None => Err(Error::Unreachable(id.clone())),
+                    Some(RemoteResult::Err(err)) => Err(err.resolve(Operation::Fetch, &id)),
+                    Some(RemoteResult::Ok(None)) => Ok(None),
+                    Some(RemoteResult::Ok(Some(json))) => {
+                        let format =
+                            self.format_of(&id, |channel| returned_format(&channel.supports_fetch));
+                        match format {
+                            Some(format) => parse_value(&format, &json).map(Some),
+                            None => Err(Error::OperationNotSupported(Operation::Fetch, id.clone())),
+                        }
+                    }
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   values: HashMap<Id<Channel>, Value>,
+                   user: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        let mut output = HashMap::new();
+        let mut serialized = vec![];
+        for (id, value) in values {
+            let format = self.format_of(&id, |channel| accepted_format(&channel.supports_send));
+            let json = match format {
+                Some(format) => serialize_value(&format, &value),
+                // Channels that accept nothing, e.g. `device/identify`, receive `null`.
+                None => Ok(JSON::Null),
+            };
+            match json {
+                Ok(json) => serialized.push((id, json)),
+                Err(err) => {
+                    output.insert(id, Err(err));
+                }
+            }
+        }
+        let ids: Vec<_> = serialized.iter().map(|&(ref id, _)| id.clone()).collect();
+        let mut results = self.call(move |call| {
+                Request::Send {
+                    call: call,
+                    values: serialized,
+                    user: user_as_remote(&user),
+                }
+            })
+            .unwrap_or_else(HashMap::new);
+        for id in ids {
+            let result = match results.remove(&id) {
+                None => Err(Error::Unreachable(id.clone())),
+                Some(RemoteResult::Err(err)) => Err(err.resolve(Operation::Send, &id)),
+                Some(RemoteResult::Ok(_)) => Ok(()),
+            };
+            output.insert(id, result);
+        }
+        output
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, user: User) -> WatchResult {
+        let user = user_as_remote(&user);
+        watch.into_iter()
+            .map(|(id, range, on_event)| {
+                let range = match range {
+                    None => None,
+                    Some(range) => {
+                        let format = self.format_of(&id, |channel| {
+                            accepted_format(&channel.supports_watch)
+                        });
+                        match format.map(|format| serialize_value(&format, &range)) {
+                            Some(Ok(json)) => Some(json),
+                            Some(Err(err)) => return (id, Err(err)),
+                            None => return (id, Err(Error::InvalidValue)),
+                        }
+                    }
+                };
+                let watch = {
+                    let mut state = self.state.lock().unwrap();
+                    let watch = state.next();
+                    state.watches.insert(watch,
+                                         ProxyWatch {
+                                             channel: id.clone(),
+                                             range: range.clone(),
+                                             user: user.clone(),
+                                             on_event: on_event,
+                                         });
+                    watch
+                };
+                // If the adapter process is not connected, the watch is registered once it is.
+                self.connection.write(&Request::Watch {
+                    watch: watch,
+                    channel: id.clone(),
+                    range: range,
+                    user: user.clone(),
+                });
+                let guard: Box<AdapterWatchGuard> = Box::new(ProxyWatchGuard {
+                    watch: watch,
+                    state: self.state.clone(),
+                    connection: self.connection.clone(),
+                });
+                (id, Ok(guard))
+            })
+            .collect()
+    }
+
+    fn heartbeat(&self) -> Result<(), Error> {
+        let results = match self.call(|call| Request::Heartbeat { call: call }) {
+            Some(results) => results,
+            None => {
+                let message = format!("The adapter process of {} is not connected or doesn't \
+                                       answer",
+                                      self.id);
+                return Err(Error::Internal(InternalError::GenericError(message)));
+            }
+        };
+        for (id, result) in results {
+            if let RemoteResult::Err(err) = result {
+                return Err(err.resolve(Operation::Fetch, &id));
+            }
+        }
+        Ok(())
+    }
+
+    fn stop(&self) {
+        self.connection.write(&Request::Stop);
+    }
+}
+
+/// Stands for the `AdapterManager` in an adapter process, see the module documentation.
+#[derive(Clone)]
+pub struct RemoteManager {
+    /// Where notifications are written.
+    out: Arc<Mutex<UnixStream>>,
+    adapter: Arc<Mutex<Option<Arc<Adapter>>>>,
+    /// The channels registered by the adapter, to (de)serialize their values.
+    channels: Arc<Mutex<HashMap<Id<Channel>, Channel>>>,
+    watches: Arc<Mutex<HashMap<u64, WatchResult>>>,
+    /// The threads answering the fetches, sends and heartbeats of the box.
+    pool: ThreadPool,
+}
+
+impl RemoteManager {
+    /// Connect to the `AdapterProxy` listening at `path`.
+    pub fn connect(path: &FilePath) -> io::Result<Self> {
+        let stream = try!(UnixStream::connect(path));
+        try!(stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_S))));
+        Ok(RemoteManager {
+            out: Arc::new(Mutex::new(stream)),
+            adapter: Arc::new(Mutex::new(None)),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            watches: Arc::new(Mutex::new(HashMap::new())),
+            pool: ThreadPool::new("RemoteManager", REQUEST_THREADS, MAX_QUEUED_REQUESTS),
+        })
+    }
+
+    /// Run `job` on the pool, or fail the `call` for each of `ids` if too many requests are
+    /// already waiting.
+    fn run<F>(&self, call: u64, ids: Vec<Id<Channel>>, job: F)
+        where F: FnOnce() + Send + 'static
+    {
+        if self.pool.execute(job).is_err() {
+            let results = ids.into_iter()
+                .map(|id| {
+                    let error = RemoteError::Other("Too many requests in progress".to_owned());
+                    (id, RemoteResult::Err(error))
+                })
+                .collect();
+            let _ = self.notify(&Notification::Done {
+                call: call,
+                results: results,
+            });
+        }
+    }
+
+    /// Write a notification to the box. If it fails, e.g. because the box doesn't read for
+    /// `WRITE_TIMEOUT_S`, the connection is closed, which ends `serve`.
+    fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        let mut out = self.out.lock().unwrap();
+        let written = write_line(&mut *out, notification);
+        written.map_err(|err| {
+            let _ = out.shutdown(Shutdown::Both);
+            Error::Internal(InternalError::GenericError(format!("Could not reach the box: {}",
+                                                                err)))
+        })
+    }
+
+    /// Answer the requests of the box, until it stops the adapter or closes the connection.
+    pub fn serve(&self) -> io::Result<()> {
+        let reader = BufReader::new(try!(self.out.lock().unwrap().try_clone()));
+        for line in reader.lines() {
+            let request: Request = match serde_json::from_str(&try!(line)) {
+                Ok(request) => request,
+                Err(err) => {
+                    warn!("[RemoteManager] Invalid request from the box: {}", err);
+                    continue;
+                }
+            };
+            let adapter = match *self.adapter.lock().unwrap() {
+                Some(ref adapter) => adapter.clone(),
+                None => continue,
+            };
+            match request {
+                Request::Fetch { call, channels, user } => {
+                    // Devices may be slow to answer, don't hold the other requests meanwhile.
+                    let manager = self.clone();
+                    let ids = channels.clone();
+                    self.run(call, ids, move || {
+                        let results = adapter.fetch_values(channels, user_from_remote(user))
+                            .into_iter()
+                            .map(|(id, result)| {
+                                let result = manager.fetched_as_remote(&id, result);
+                                (id, result)
+                            })
+                            .collect();
+                        let _ = manager.notify(&Notification::Done {
+                            call: call,
+                            results: results,
+                        });
+                    });
+                }
+                Request::Send { call, values, user } => {
+                    let manager = self.clone();
+                    let ids = values.iter().map(|&(ref id, _)| id.clone()).collect();
+                    self.run(call, ids, move || {
+                        let mut results = vec![];
+                        let mut parsed = HashMap::new();
+                        for (id, json) in values {
+                            match manager.parse_sent(&id, &json) {
+                                Ok(value) => {
+                                    parsed.insert(id, value);
+                                }
+                                Err(err) => results.push((id, RemoteResult::Err(err))),
+                            }
+                        }
+                        for (id, result) in adapter.send_values(parsed, user_from_remote(user)) {
+                            let result = match result {
+                                Ok(()) => RemoteResult::Ok(None),
+                                Err(err) => RemoteResult::Err(RemoteError::new(&err)),
+                            };
+                            results.push((id, result));
+                        }
+                        let _ = manager.notify(&Notification::Done {
+                            call: call,
+                            results: results,
+                        });
+                    });
+                }
+                Request::Watch { watch, channel, range, user } => {
+                    self.watch(&*adapter, watch, channel, range, user_from_remote(user))
+                }
+                Request::Unwatch { watch } => {
+                    // Dropping the guards stops watching.
+                    self.watches.lock().unwrap().remove(&watch);
+                }
+                Request::Heartbeat { call } => {
+                    let manager = self.clone();
+                    let id = Id::new(&adapter.id().to_string());
+                    self.run(call, vec![id.clone()], move || {
+                        let results = match adapter.heartbeat() {
+                            Ok(()) => vec![],
+                            Err(err) => vec![(id, RemoteResult::Err(RemoteError::new(&err)))],
+                        };
+                        let _ = manager.notify(&Notification::Done {
+                            call: call,
+                            results: results,
+                        });
+                    });
+                }
+                Request::Stop => {
+                    adapter.stop();
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Close the connection to the box, e.g. before the adapter process exits.
+    pub fn close(&self) {
+        let _ = self.out.lock().unwrap().shutdown(Shutdown::Both);
+    }
+
+    fn fetched_as_remote(&self,
+                         id: &Id<Channel>,
+                         result: Result<Option<Value>, Error>)
+                         -> RemoteResult {
+        let format = self.channels
+            .lock()
+            .unwrap()
+            .get(id)
+            .and_then(|channel| returned_format(&channel.supports_fetch));
+        match (result, format) {
+            (Ok(None), _) => RemoteResult::Ok(None),
+            (Ok(Some(value)), Some(format)) => {
+                match serialize_value(&format, &value) {
+                    Ok(json) => RemoteResult::Ok(Some(json)),
+                    Err(err) => RemoteResult::Err(RemoteError::new(&err)),
+                }
+            }
+            (Ok(Some(_)), None) => RemoteResult::Err(RemoteError::NotSupported),
+            (Err(err), _) => RemoteResult::Err(RemoteError::new(&err)),
+        }
+    }
+
+    fn parse_sent(&self, id: &Id<Channel>, json: &JSON) -> Result<Value, RemoteError> {
+        let format = self.channels
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|channel| accepted_format(&channel.supports_send));
+        match format {
+            None => Err(RemoteError::NotSupported),
+            Some(None) => Ok(Value::new(())),
+            Some(Some(format)) => parse_value(&format, json).map_err(|err| RemoteError::new(&err)),
+        }
+    }
+
+    fn watch(&self,
+             adapter: &Adapter,
+             watch: u64,
+             channel: Id<Channel>,
+             range: Option<JSON>,
+             user: User) {
+        let (value_format, range_format) = match self.channels.lock().unwrap().get(&channel) {
+            Some(data) => {
+                (returned_format(&data.supports_watch), accepted_format(&data.supports_watch))
+            }
+            None => (None, None),
+        };
+        let error = {
+            let channel = channel.clone();
+            move |error: RemoteError| {
+                Notification::WatchError {
+                    watch: watch,
+                    channel: channel.clone(),
+                    error: error,
+                }
+            }
+        };
+        let value_format = match value_format {
+            Some(format) => format,
+            None => {
+                let _ = self.notify(&error(RemoteError::NotSupported));
+                return;
+            }
+        };
+        let range = match (range, range_format) {
+            (None, _) => None,
+            (Some(range), Some(format)) => {
+                match parse_value(&format, &range) {
+                    Ok(range) => Some(range),
+                    Err(err) => {
+                        let _ = self.notify(&error(RemoteError::new(&err)));
+                        return;
+                    }
+                }
+            }
+            (Some(_), None) => {
+                let _ = self.notify(&error(RemoteError::InvalidValue));
+                return;
+            }
+        };
+
+        let manager = self.clone();
+        let on_event = move |event| {
+            let (id, value, observed, is_enter) = match event {
+                WatchEvent::Enter { id, value, observed } => (id, value, observed, true),
+                WatchEvent::Exit { id, value, observed } => (id, value, observed, false),
+                WatchEvent::Error { id, error } => {
+                    let _ = manager.notify(&Notification::WatchError {
+                        watch: watch,
+                        channel: id,
+                        error: RemoteError::new(&error),
+                    });
+                    return;
+                }
+            };
+            let notification = match serialize_value(&value_format, &value) {
+                Ok(json) => {
+                    Notification::Event {
+                        watch: watch,
+                        channel: id,
+                        value: json,
+                        is_enter: is_enter,
+                        timestamp: observed.timestamp,
+                        source: observed.source.as_str().to_owned(),
+                    }
+                }
+                Err(err) => {
+                    Notification::WatchError {
+                        watch: watch,
+                        channel: id,
+                        error: RemoteError::new(&err),
+                    }
+                }
+            };
+            let _ = manager.notify(&notification);
+        };
+        let (tx, rx) = ::transformable_channels::mpsc::channel();
+        thread::spawn(move || {
+            for event in rx {
+                on_event(event);
+            }
+        });
+        let tx: Box<ExtSender<WatchEvent<Value>>> = Box::new(tx);
+        let guards = adapter.register_watch(vec![(channel, range, tx)], user);
+        self.watches.lock().unwrap().insert(watch, guards);
+    }
+}
+
+impl AdapterManagerHandle for RemoteManager {
+    fn add_adapter(&self, adapter: Arc<Adapter>) -> Result<(), Error> {
+        let mut current = self.adapter.lock().unwrap();
+        if current.is_some() {
+            return Err(Error::Internal(InternalError::DuplicateAdapter(adapter.id())));
+        }
+        *current = Some(adapter);
+        Ok(())
+    }
+
+    fn remove_adapter(&self, id: &Id<AdapterId>) -> Result<(), Error> {
+        match self.adapter.lock().unwrap().take() {
+            Some(_) => Ok(()),
+            None => Err(Error::Internal(InternalError::NoSuchAdapter(id.clone()))),
+        }
+    }
+
+    fn add_service(&self, service: Service) -> Result<(), Error> {
+        self.notify(&Notification::AddService(RemoteService::new(&service)))
+    }
+
+    fn remove_service(&self, service_id: &Id<ServiceId>) -> Result<(), Error> {
+        {
+            let mut channels = self.channels.lock().unwrap();
+            let removed: Vec<_> = channels.values()
+                .filter(|channel| channel.service == *service_id)
+                .map(|channel| channel.id.clone())
+                .collect();
+            for id in removed {
+                channels.remove(&id);
+            }
+        }
+        self.notify(&Notification::RemoveService(service_id.clone()))
+    }
+
+    fn update_service_properties(&self,
+                                 service_id: &Id<ServiceId>,
+                                 properties: HashMap<String, String>)
+                                 -> Result<(), Error> {
+        self.notify(&Notification::UpdateServiceProperties(service_id.clone(), properties))
+    }
+
+    fn add_channel(&self, channel: Channel) -> Result<(), Error> {
+        let remote = RemoteChannel::new(&channel);
+        self.channels.lock().unwrap().insert(channel.id.clone(), channel);
+        self.notify(&Notification::AddChannel(remote))
+    }
+
+    fn add_service_with_channels(&self, builder: ServiceBuilder) -> Result<(), Error> {
+        try!(self.add_service(builder.service));
+        for channel in builder.channels {
+            try!(self.add_channel(channel));
+        }
+        Ok(())
+    }
+
+    fn remove_channel(&self, id: &Id<Channel>) -> Result<(), Error> {
+        self.channels.lock().unwrap().remove(id);
+        self.notify(&Notification::RemoveChannel(id.clone()))
+    }
+
+    fn service_reachable(&self, id: &Id<ServiceId>) {
+        let _ = self.notify(&Notification::ServiceReachable(id.clone()));
+    }
+
+    fn raise_alert(&self, severity: Severity, message: &str, action_hint: Option<&str>) {
+        let _ = self.notify(&Notification::RaiseAlert {
+            severity: severity.as_str().to_owned(),
+            message: message.to_owned(),
+            action_hint: action_hint.map(str::to_owned),
+        });
+    }
+
+    fn report_adapter_status(&self, id: &Id<AdapterId>, status: AdapterStatus) {
+        // The box tells the status of the adapter process itself.
+        info!("[RemoteManager] Adapter {} reports {:?}", id, status);
+    }
+}
+
+#[test]
+fn test_adapter_proxy() {
+    use api::API;
+    use fake_adapter::{FakeAdapter, Tweak};
+    use manager::AdapterManager;
+    use parse::ToJSON;
+    use selector::{ChannelSelector, ServiceSelector};
+    use values::OnOff;
+
+    use std::env;
+
+    let path = env::temp_dir().join("foxbox-test-adapter-proxy.sock");
+    let adapter_id = Id::<AdapterId>::new("adapter@proxy");
+    let service_id = Id::<ServiceId>::new("service@proxy");
+    let channel_id = Id::<Channel>::new("getter@proxy");
+
+    let manager = Arc::new(AdapterManager::new(None));
+    let proxy = Arc::new(AdapterProxy::new(&adapter_id, "Proxy", "test", [0, 0, 0, 0]));
+    manager.add_adapter(proxy.clone()).unwrap();
+    proxy.listen(manager.clone(), &path).unwrap();
+
+    // The adapter process.
+    let remote = RemoteManager::connect(&path).unwrap();
+    let adapter = FakeAdapter::new(&adapter_id);
+    let tweak = adapter.get_tweak();
+    remote.add_adapter(Arc::new(adapter)).unwrap();
+    remote.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+    remote.add_channel(Channel {
+            id: channel_id.clone(),
+            service: service_id.clone(),
+            adapter: adapter_id.clone(),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+            ..Channel::default()
+        })
+        .unwrap();
+    let server = remote.clone();
+    thread::spawn(move || server.serve());
+
+    let wait_for = |count| {
+        for _ in 0..100 {
+            if manager.get_channels(vec![ChannelSelector::new()]).len() == count {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("The channels of the adapter process were not updated");
+    };
+    wait_for(1);
+
+    // Values cross the process boundary.
+    tweak(Tweak::InjectGetterValue(channel_id.clone(), Ok(Some(Value::new(OnOff::On)))));
+    let data = manager.fetch_values(vec![ChannelSelector::new()], User::None);
+    match data.get(&channel_id) {
+        Some(&Ok(Some((ref payload, _)))) => {
+            assert_eq!(payload.to_json(), JSON::String("On".to_owned()))
+        }
+        other => panic!("Unexpected result, {:?}", other),
+    }
+    assert!(proxy.heartbeat().is_ok());
+
+    // Once the adapter process stops, its services are removed and calls fail.
+    remote.close();
+    wait_for(0);
+    assert!(manager.get_services(vec![ServiceSelector::new()]).is_empty());
+    assert!(proxy.heartbeat().is_err());
+    let data = proxy.fetch_values(vec![channel_id.clone()], User::None);
+    match data.get(&channel_id) {
+        Some(&Err(Error::Unreachable(_))) => {}
+        other => panic!("Unexpected result, {:?}", other),
+    }
+
+    manager.stop();
+    let _ = fs::remove_file(&path);
+}
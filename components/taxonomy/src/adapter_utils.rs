@@ -1,6 +1,6 @@
 //! Utilities for writing adapters.
 
-use api::{Error, InternalError, User};
+use api::{Error, InternalError, Observation, Source, User};
 use channel::Channel;
 use io::*;
 use manager::*;
@@ -8,7 +8,10 @@ use util::{Id, AdapterId};
 use values::*;
 
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use transformable_channels::mpsc::*;
 
@@ -71,8 +74,12 @@ impl<T> Adapter for MakeSyncAdapter<T>
         self.lock.lock().unwrap().send_values(values, user)
     }
 
-    fn register_watch(&self, watch: Vec<WatchTarget>) -> WatchResult {
-        self.lock.lock().unwrap().register_watch(watch)
+    fn register_watch(&self, watch: Vec<WatchTarget>, user: User) -> WatchResult {
+        self.lock.lock().unwrap().register_watch(watch, user)
+    }
+
+    fn heartbeat(&self) -> Result<(), Error> {
+        self.lock.lock().unwrap().heartbeat()
     }
 }
 
@@ -93,6 +100,9 @@ impl RawAdapter for RawAdapterForAdapter {
     fn stop(&self) {
         self.adapter.stop()
     }
+    fn heartbeat(&self) -> Result<(), Error> {
+        self.adapter.heartbeat()
+    }
     fn fetch_values(&self,
                     mut target: Vec<(Id<Channel>, Arc<Format>)>,
                     user: User)
@@ -141,53 +151,12 @@ impl RawAdapter for RawAdapterForAdapter {
         results
     }
 
-    fn register_watch(&self, mut targets: Vec<RawWatchTarget>) -> WatchResult {
+    fn register_watch(&self, mut targets: Vec<RawWatchTarget>, user: User) -> WatchResult {
         let mut send: Vec<(_, _, Box<ExtSender<WatchEvent<Value>>>)> = Vec::new();
         let mut failures = Vec::new();
         for (id, filter, event_type, sender) in targets.drain(..) {
             // FIXME: Do I really need event_type? Why? Shouldn't it be part of ChannelKind?
-            let sender = Box::new(sender.map(move |event| {
-                match event {
-                    WatchEvent::Enter { id, value } => {
-                        match Payload::from_value(&value, &event_type) {
-                            Ok(payload) => {
-                                WatchEvent::Enter {
-                                    id: id,
-                                    value: (payload, event_type.clone()),
-                                }
-                            }
-                            Err(err) => {
-                                WatchEvent::Error {
-                                    id: id,
-                                    error: err,
-                                }
-                            }
-                        }
-                    }
-                    WatchEvent::Exit { id, value } => {
-                        match Payload::from_value(&value, &event_type) {
-                            Ok(payload) => {
-                                WatchEvent::Exit {
-                                    id: id,
-                                    value: (payload, event_type.clone()),
-                                }
-                            }
-                            Err(err) => {
-                                WatchEvent::Error {
-                                    id: id,
-                                    error: err,
-                                }
-                            }
-                        }
-                    }
-                    WatchEvent::Error { id, error } => {
-                        WatchEvent::Error {
-                            id: id,
-                            error: error,
-                        }
-                    }
-                }
-            }));
+            let sender = Box::new(sender.map(move |event| payload_event(event, &event_type)));
             if let Some((payload, type_)) = filter {
                 match payload.to_value(&type_) {
                     Err(err) => {
@@ -201,8 +170,330 @@ impl RawAdapter for RawAdapterForAdapter {
                 send.push((id, None, sender));
             }
         }
-        let mut result = self.adapter.register_watch(send);
+        let mut result = self.adapter.register_watch(send, user);
         result.extend(failures);
         result
     }
 }
+
+/// Convert an event from an `Adapter` into an event for a `RawAdapter`.
+fn payload_event(event: WatchEvent<Value>,
+                 format: &Arc<Format>)
+                 -> WatchEvent<(Payload, Arc<Format>)> {
+    match event {
+        WatchEvent::Enter { id, value, observed } => {
+            match Payload::from_value(&value, format) {
+                Ok(payload) => {
+                    WatchEvent::Enter {
+                        id: id,
+                        value: (payload, format.clone()),
+                        observed: observed,
+                    }
+                }
+                Err(err) => {
+                    WatchEvent::Error {
+                        id: id,
+                        error: err,
+                    }
+                }
+            }
+        }
+        WatchEvent::Exit { id, value, observed } => {
+            match Payload::from_value(&value, format) {
+                Ok(payload) => {
+                    WatchEvent::Exit {
+                        id: id,
+                        value: (payload, format.clone()),
+                        observed: observed,
+                    }
+                }
+                Err(err) => {
+                    WatchEvent::Error {
+                        id: id,
+                        error: err,
+                    }
+                }
+            }
+        }
+        WatchEvent::Error { id, error } => {
+            WatchEvent::Error {
+                id: id,
+                error: error,
+            }
+        }
+    }
+}
+
+/// A guard returned by `poll_watch`. Polling stops when the guard is dropped.
+struct PollGuard {
+    is_dropped: Arc<AtomicBool>,
+}
+
+impl Drop for PollGuard {
+    fn drop(&mut self) {
+        self.is_dropped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl AdapterWatchGuard for PollGuard {}
+
+/// Emulate `register_watch` for a channel that supports fetching but not watching: the channel
+/// is fetched immediately, then every `interval`, and each change of value is reported as with
+/// `ValueWatchers::update`. A failed fetch is reported once, until a fetch succeeds again.
+/// Channels are fetched on behalf of `user`.
+pub fn poll_watch(adapter: Arc<RawAdapter>,
+                  (id, condition, format, sender): RawWatchTarget,
+                  interval: Duration,
+                  user: User)
+                  -> WatchResult {
+    let condition = match condition {
+        None => None,
+        Some((payload, type_)) => {
+            match payload.to_value(&type_) {
+                Ok(value) => Some(value),
+                Err(err) => return vec![(id, Err(err))],
+            }
+        }
+    };
+    let watchers = ValueWatchers::new();
+    let event_format = format.clone();
+    let errors = sender.clone();
+    let guard = watchers.register(id.clone(),
+                                  condition,
+                                  Box::new(sender.map(move |event| {
+                                      payload_event(event, &event_format)
+                                  })));
+    let is_dropped = Arc::new(AtomicBool::new(false));
+    let stop = is_dropped.clone();
+    let channel = id.clone();
+    thread::Builder::new()
+        .name(format!("Poll-{}", id))
+        .spawn(move || {
+            // Stops watching once polling stops.
+            let _guard = guard;
+            let mut failing = false;
+            while !stop.load(Ordering::Relaxed) {
+                let mut results = adapter.fetch_values(vec![(channel.clone(), format.clone())],
+                                                       user.clone());
+                match results.remove(&channel) {
+                    Some(Ok(Some((payload, type_)))) => {
+                        failing = false;
+                        match payload.to_value(&type_) {
+                            // Only changes are reported.
+                            Ok(value) => {
+                                if watchers.latest(&channel).as_ref() != Some(&value) {
+                                    watchers.observe(&channel,
+                                                     value,
+                                                     Observation::now(Source::Fetch))
+                                }
+                            }
+                            Err(err) => warn!("Could not read value polled from {}: {:?}",
+                                              channel,
+                                              err),
+                        }
+                    }
+                    Some(Err(err)) => {
+                        if !failing {
+                            failing = true;
+                            let _ = errors.send(WatchEvent::Error {
+                                id: channel.clone(),
+                                error: err,
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+                thread::sleep(interval);
+            }
+        })
+        .unwrap();
+    vec![(id, Ok(Box::new(PollGuard { is_dropped: is_dropped }) as Box<AdapterWatchGuard>))]
+}
+
+fn condition_matches(condition: &Value, value: &Value) -> bool {
+    match condition.downcast::<Range<Percent>>() {
+        Some(range) => range.contains(value),
+        None => condition == value,
+    }
+}
+
+type ValueWatcher = (Id<Channel>, Option<Value>, Box<ExtSender<WatchEvent<Value>>>);
+
+struct ValueWatchersState {
+    counter: usize,
+    watchers: HashMap<usize, ValueWatcher>,
+    latest: HashMap<Id<Channel>, (Value, Observation)>,
+}
+
+/// A registry of watchers, for adapters that receive values from their devices as a stream
+/// of updates (e.g. radio telegrams) rather than by polling.
+///
+/// A watcher with condition `Some(condition)` receives `Enter` when the channel takes a value
+/// matching `condition` and `Exit` when it takes any other value, while a watcher without
+/// condition receives `Enter` for every new value. Conditions match by equality, except
+/// `Range<Percent>`, which matches the values it contains.
+#[derive(Clone)]
+pub struct ValueWatchers {
+    state: Arc<Mutex<ValueWatchersState>>,
+}
+
+impl ValueWatchers {
+    pub fn new() -> Self {
+        ValueWatchers {
+            state: Arc::new(Mutex::new(ValueWatchersState {
+                counter: 0,
+                watchers: HashMap::new(),
+                latest: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Start watching a channel. If a value is already known for this channel, the watcher
+    /// is notified immediately.
+    ///
+    /// Watching stops when the guard is dropped.
+    pub fn register(&self,
+                    id: Id<Channel>,
+                    condition: Option<Value>,
+                    sender: Box<ExtSender<WatchEvent<Value>>>)
+                    -> ValueWatchGuard {
+        let mut state = self.state.lock().unwrap();
+        let key = state.counter;
+        state.counter += 1;
+        if let Some(&(ref value, observed)) = state.latest.get(&id) {
+            let should_send = match condition {
+                None => true,
+                Some(ref condition) => condition_matches(condition, value),
+            };
+            if should_send {
+                let _ = sender.send(WatchEvent::Enter {
+                    id: id.clone(),
+                    value: value.clone(),
+                    observed: observed.cached(),
+                });
+            }
+        }
+        state.watchers.insert(key, (id, condition, sender));
+        ValueWatchGuard {
+            key: key,
+            state: Arc::downgrade(&self.state),
+        }
+    }
+
+    /// Implementation of `Adapter::register_watch` for adapters that rely entirely on
+    /// `ValueWatchers`.
+    pub fn register_watch(&self, mut targets: Vec<WatchTarget>) -> WatchResult {
+        targets.drain(..)
+            .map(|(id, condition, sender)| {
+                let guard = self.register(id.clone(), condition, sender);
+                (id, Ok(Box::new(guard) as Box<AdapterWatchGuard>))
+            })
+            .collect()
+    }
+
+    /// Record a new value reported by the device of a channel, notifying watchers as needed.
+    pub fn update(&self, id: &Id<Channel>, value: Value) {
+        self.observe(id, value, Observation::now(Source::Device))
+    }
+
+    /// Record a new value for a channel, observed as described by `observed`, notifying
+    /// watchers as needed.
+    pub fn observe(&self, id: &Id<Channel>, value: Value, observed: Observation) {
+        let mut state = self.state.lock().unwrap();
+        let previous = state.latest
+            .insert(id.clone(), (value.clone(), observed))
+            .map(|(previous, _)| previous);
+        for &(ref watched, ref condition, ref sender) in state.watchers.values() {
+            if watched != id {
+                continue;
+            }
+            let event = match *condition {
+                None => Some(true),
+                Some(ref condition) => {
+                    let was_in = previous.as_ref()
+                        .map_or(false, |previous| condition_matches(condition, previous));
+                    let is_in = condition_matches(condition, &value);
+                    if was_in == is_in { None } else { Some(is_in) }
+                }
+            };
+            match event {
+                Some(true) => {
+                    let _ = sender.send(WatchEvent::Enter {
+                        id: id.clone(),
+                        value: value.clone(),
+                        observed: observed,
+                    });
+                }
+                Some(false) => {
+                    let _ = sender.send(WatchEvent::Exit {
+                        id: id.clone(),
+                        value: value.clone(),
+                        observed: observed,
+                    });
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Notify watchers of a momentary event (e.g. a button press), which is not recorded as
+    /// the latest value of the channel. Watchers whose condition matches receive `Enter`
+    /// immediately followed by `Exit`, so that repeated events are all reported.
+    pub fn pulse(&self, id: &Id<Channel>, value: Value) {
+        let observed = Observation::now(Source::Device);
+        let state = self.state.lock().unwrap();
+        for &(ref watched, ref condition, ref sender) in state.watchers.values() {
+            if watched != id {
+                continue;
+            }
+            match *condition {
+                None => {
+                    let _ = sender.send(WatchEvent::Enter {
+                        id: id.clone(),
+                        value: value.clone(),
+                        observed: observed,
+                    });
+                }
+                Some(ref condition) if condition_matches(condition, &value) => {
+                    let _ = sender.send(WatchEvent::Enter {
+                        id: id.clone(),
+                        value: value.clone(),
+                        observed: observed,
+                    });
+                    let _ = sender.send(WatchEvent::Exit {
+                        id: id.clone(),
+                        value: value.clone(),
+                        observed: observed,
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// The latest value recorded for a channel, if any.
+    pub fn latest(&self, id: &Id<Channel>) -> Option<Value> {
+        self.state.lock().unwrap().latest.get(id).map(|&(ref value, _)| value.clone())
+    }
+
+    /// Forget everything about a channel, e.g. once it has been removed.
+    pub fn forget(&self, id: &Id<Channel>) {
+        self.state.lock().unwrap().latest.remove(id);
+    }
+}
+
+/// A guard returned by `ValueWatchers::register`. Watching stops when the guard is dropped.
+pub struct ValueWatchGuard {
+    key: usize,
+    state: Weak<Mutex<ValueWatchersState>>,
+}
+
+impl Drop for ValueWatchGuard {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.upgrade() {
+            state.lock().unwrap().watchers.remove(&self.key);
+        }
+    }
+}
+
+impl AdapterWatchGuard for ValueWatchGuard {}
@@ -0,0 +1,193 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Conditions that need the attention of the user, e.g. a hub that was detected but isn't
+//! paired yet, raised by adapters with `AdapterManagerHandle::raise_alert`.
+//!
+//! Alerts are stored in the taxonomy database, so that they survive a reboot, until the user
+//! dismisses them. Raising an alert identical to one that is still active does nothing, so
+//! adapters may raise the same alert each time they run into the condition.
+
+use parse::{JSON, ToJSON};
+
+use rusqlite::{Connection, Result};
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// Something the user may want to know, e.g. a new device was found.
+    Info,
+    /// Something doesn't work until the user does something, e.g. pair a hub.
+    Warning,
+    /// Something is broken, e.g. a device keeps failing.
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+
+    pub fn parse(source: &str) -> Option<Self> {
+        match source {
+            "info" => Some(Severity::Info),
+            "warning" => Some(Severity::Warning),
+            "critical" => Some(Severity::Critical),
+            _ => None,
+        }
+    }
+}
+
+impl ToJSON for Severity {
+    fn to_json(&self) -> JSON {
+        JSON::String(self.as_str().to_owned())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    pub id: u64,
+    pub severity: Severity,
+    /// What happened, e.g. "Philips Hue bridge 001788 detected but not paired".
+    pub message: String,
+    /// What the user should do about it, e.g. "Press the button on the bridge".
+    pub action_hint: Option<String>,
+    /// When the alert was raised, in seconds since the epoch.
+    pub raised: u64,
+}
+
+impl ToJSON for Alert {
+    fn to_json(&self) -> JSON {
+        vec![("id", JSON::U64(self.id)),
+             ("severity", self.severity.to_json()),
+             ("message", self.message.to_json()),
+             ("action_hint", self.action_hint.as_ref().map_or(JSON::Null, ToJSON::to_json)),
+             ("raised", JSON::U64(self.raised))]
+            .to_json()
+    }
+}
+
+/// A change in the active alerts, see `AdapterManager::watch_alerts`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AlertEvent {
+    Raised(Alert),
+    Dismissed(u64),
+}
+
+pub struct AlertStore {
+    db: Connection,
+}
+
+impl AlertStore {
+    /// Open the alerts stored in the database at `path`, or a store in memory.
+    pub fn new(path: Option<&PathBuf>) -> Result<Self> {
+        let db = try!(match path {
+            Some(path) => Connection::open(path),
+            None => Connection::open_in_memory(),
+        });
+        try!(db.execute("CREATE TABLE IF NOT EXISTS alerts (
+                    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+                    severity     TEXT NOT NULL,
+                    message      TEXT NOT NULL,
+                    action_hint  TEXT,
+                    raised       INTEGER NOT NULL
+            )",
+                        &[]));
+        Ok(AlertStore { db: db })
+    }
+
+    /// Record an alert. Returns `None` if the same alert is already active.
+    pub fn raise(&self,
+                 severity: Severity,
+                 message: &str,
+                 action_hint: Option<&str>)
+                 -> Result<Option<Alert>> {
+        let mut stmt = try!(self.db
+            .prepare("SELECT id FROM alerts WHERE severity=$1 AND message=$2"));
+        if try!(stmt.query(&[&severity.as_str(), &message])).next().is_some() {
+            return Ok(None);
+        }
+        let alert = Alert {
+            id: 0,
+            severity: severity,
+            message: message.to_owned(),
+            action_hint: action_hint.map(str::to_owned),
+            raised: now(),
+        };
+        try!(self.db.execute("INSERT INTO alerts (severity, message, action_hint, raised) \
+                              VALUES ($1, $2, $3, $4)",
+                             &[&alert.severity.as_str(),
+                               &alert.message,
+                               &alert.action_hint,
+                               &(alert.raised as i64)]));
+        Ok(Some(Alert { id: self.db.last_insert_rowid() as u64, ..alert }))
+    }
+
+    /// The active alerts, most recent first.
+    pub fn alerts(&self) -> Result<Vec<Alert>> {
+        let mut alerts = vec![];
+        let mut stmt = try!(self.db.prepare("SELECT id, severity, message, action_hint, raised \
+                                             FROM alerts ORDER BY id DESC"));
+        let mut rows = try!(stmt.query(&[]));
+        while let Some(result_row) = rows.next() {
+            let row = try!(result_row);
+            let id: i64 = row.get(0);
+            let severity: String = row.get(1);
+            let raised: i64 = row.get(4);
+            alerts.push(Alert {
+                id: id as u64,
+                severity: Severity::parse(&severity).unwrap_or(Severity::Warning),
+                message: row.get(2),
+                action_hint: row.get(3),
+                raised: raised as u64,
+            });
+        }
+        Ok(alerts)
+    }
+
+    /// Remove an alert. Returns `false` if there is no such alert.
+    pub fn dismiss(&self, id: u64) -> Result<bool> {
+        let removed = try!(self.db.execute("DELETE FROM alerts WHERE id=$1", &[&(id as i64)]));
+        Ok(removed > 0)
+    }
+}
+
+#[test]
+fn test_alert_store() {
+    let store = AlertStore::new(None).unwrap();
+    assert!(store.alerts().unwrap().is_empty());
+
+    let pairing = store.raise(Severity::Warning,
+                              "Philips Hue bridge detected but not paired",
+                              Some("Press the button on the bridge"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(pairing.action_hint, Some("Press the button on the bridge".to_owned()));
+    // Raising the same alert again does nothing.
+    assert_eq!(store.raise(Severity::Warning,
+                           "Philips Hue bridge detected but not paired",
+                           Some("Press the button on the bridge"))
+                   .unwrap(),
+               None);
+    let failing = store.raise(Severity::Critical, "The lock keeps failing", None)
+        .unwrap()
+        .unwrap();
+    assert!(failing.id != pairing.id);
+    assert_eq!(store.alerts().unwrap(), vec![failing.clone(), pairing.clone()]);
+    assert_eq!(failing.to_json().find("action_hint"), Some(&JSON::Null));
+
+    assert!(store.dismiss(pairing.id).unwrap());
+    assert!(!store.dismiss(pairing.id).unwrap());
+    assert_eq!(store.alerts().unwrap(), vec![failing]);
+}
@@ -13,6 +13,7 @@
 //!
 
 use channel::Channel;
+use filter::Filter;
 use io::*;
 use services::*;
 use selector::*;
@@ -24,6 +25,7 @@ use transformable_channels::mpsc::*;
 use std::{error, fmt};
 use std::error::Error as std_error;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use serde_json;
 
@@ -74,6 +76,18 @@ pub enum Error {
     /// Attempting to send an invalid value. For instance, a time of day larger than 24h.
     InvalidValue,
 
+    /// The device behind a channel cannot be reached right now, e.g. it is asleep or out of
+    /// range. The same call may succeed later, see `AdapterManager::send_values_when_reachable`.
+    Unreachable(Id<Channel>),
+
+    /// The value was not sent, or was sent then reverted, because another value of the same
+    /// transaction could not be sent, see `AdapterManager::send_values_atomic`.
+    TransactionAborted,
+
+    /// The call was still in progress when an administrator aborted it, see
+    /// `AdapterManager::abort_operation`. The adapter may still apply it eventually.
+    OperationAborted,
+
     /// An error internal to the foxbox or an adapter. Normally, these errors should never
     /// arise from the high-level API.
     Internal(InternalError),
@@ -98,6 +112,9 @@ impl ToJSON for Error {
                 vec![("GetterRequiresThresholdForWatching", id.to_json())].to_json()
             }
             InvalidValue => "InvalidValue".to_json(),
+            Unreachable(ref id) => vec![("Unreachable", id.to_json())].to_json(),
+            TransactionAborted => "TransactionAborted".to_json(),
+            OperationAborted => "OperationAborted".to_json(),
             Internal(_) => "Internal Error".to_json(), // FIXME: Implement ToJSON for InternalError as well
             Parsing(ref err) => vec![("ParseError", serde_json::to_value(err))].to_json(),
             Serializing(ref err) => vec![("SerializeError", serde_json::to_value(err))].to_json(),
@@ -117,6 +134,9 @@ impl fmt::Display for Error {
             }
             Error::WrongType(ref err) => write!(f, "{}: {}", self.description(), err),
             Error::InvalidValue => write!(f, "{}", self.description()),
+            Error::Unreachable(ref channel) => write!(f, "{}: {}", self.description(), channel),
+            Error::TransactionAborted => write!(f, "{}", self.description()),
+            Error::OperationAborted => write!(f, "{}", self.description()),
             Error::Internal(ref err) => write!(f, "{}: {:?}", self.description(), err), // TODO implement Display for InternalError as well
             Error::Parsing(ref err) => write!(f, "{}: {:?}", self.description(), err), // TODO implement Display for ParseError as well
             Error::Serializing(ref err) => write!(f, "{}: {:?}", self.description(), err), // TODO implement Display for ParseError as well
@@ -135,6 +155,9 @@ impl error::Error for Error {
             }
             Error::WrongType(_) => "Attempting to send a value with a wrong type",
             Error::InvalidValue => "Attempting to send an invalid value",
+            Error::Unreachable(_) => "The device cannot be reached right now",
+            Error::TransactionAborted => "Another value of the same transaction could not be sent",
+            Error::OperationAborted => "The call was aborted before the adapter answered",
             Error::Internal(_) => "Internal Error", // TODO implement Error for InternalError as well
             Error::Parsing(ref err) => err.description(),
             Error::Serializing(ref err) => err.description(),
@@ -179,6 +202,67 @@ pub enum InternalError {
     InvalidInitialService,
 }
 
+/// Where the value of a watch event comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The device reported the value, e.g. a Z-Wave notification.
+    Device,
+
+    /// The value was fetched, e.g. while polling a channel that can't be watched.
+    Fetch,
+
+    /// The value had been observed earlier, e.g. it is replayed to a new watcher.
+    Cache,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Source::Device => "device",
+            Source::Fetch => "fetch",
+            Source::Cache => "cache",
+        }
+    }
+}
+
+/// When and how the adapter observed the value of a watch event. As adapters have different
+/// latencies, this is what clients should use to order events across adapters.
+///
+/// # JSON
+///
+/// An object `{"timestamp": number, "source": "device" | "fetch" | "cache"}`, with the
+/// timestamp in milliseconds since the epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Observation {
+    /// Milliseconds since the epoch.
+    pub timestamp: u64,
+    pub source: Source,
+}
+
+impl Observation {
+    /// A value observed right now.
+    pub fn now(source: Source) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        Observation {
+            timestamp: now.as_secs() * 1000 + (now.subsec_nanos() / 1_000_000) as u64,
+            source: source,
+        }
+    }
+
+    /// The same observation, replayed from a cache.
+    pub fn cached(&self) -> Self {
+        Observation { source: Source::Cache, ..*self }
+    }
+}
+
+impl ToJSON for Observation {
+    fn to_json(&self) -> JSON {
+        vec![("timestamp", JSON::U64(self.timestamp)),
+             ("source", JSON::String(self.source.as_str().to_owned()))]
+            .to_json()
+    }
+}
+
 /// An event during watching.
 #[derive(Debug, Clone)]
 pub enum WatchEvent {
@@ -193,6 +277,8 @@ pub enum WatchEvent {
         value: Payload,
 
         format: Arc<Format>,
+
+        observed: Observation,
     },
 
     /// If a range was specified when we registered for watching, `ExitRange` is fired whenever
@@ -205,6 +291,24 @@ pub enum WatchEvent {
         value: Payload,
 
         format: Arc<Format>,
+
+        observed: Observation,
+    },
+
+    /// If `Delivery::Delta` was specified when we registered for watching, `EnterRangeDelta` is
+    /// fired instead of `EnterRange` whenever we have already delivered a value for this channel.
+    EnterRangeDelta {
+        /// The channel that sent the value.
+        channel: Id<Channel>,
+
+        /// A JSON Patch (RFC 6902) to apply to the latest value delivered for this channel.
+        /// See `Payload::patch`.
+        patch: Payload,
+
+        /// The format of the value, once patched.
+        format: Arc<Format>,
+
+        observed: Observation,
     },
 
     /// The set of devices being watched has changed, typically either
@@ -303,6 +407,50 @@ impl<P, T> Parser<Targetted<T, Exactly<Payload>>> for Targetted<P, Exactly<Paylo
     }
 }
 
+/// The options of a watch on a set of channels: the range of values, an optional filter on the
+/// contents of values, the way values are delivered and an optional polling interval for the
+/// channels that support fetching but not watching.
+pub type WatchOptions = (Exactly<Payload>, Option<Filter>, Delivery, Option<Duration>);
+
+impl<P, T> Parser<Targetted<T, WatchOptions>> for Targetted<P, WatchOptions>
+    where P: Parser<T>,
+          T: Clone
+{
+    fn description() -> String {
+        format!("Targetted<{}, range, filter, delivery, poll>", P::description())
+    }
+    fn parse(path: Path, source: &JSON) -> Result<Targetted<T, WatchOptions>, ParseError> {
+        let Targetted { select, payload } =
+            try!(Targetted::<P, Exactly<Payload>>::parse(path.clone(), source));
+        let filter = match path.push("filter", |path| Filter::take_opt(path, source, "filter")) {
+            Some(Ok(filter)) => Some(filter),
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
+        let delivery =
+            match path.push("delivery", |path| Delivery::take_opt(path, source, "delivery")) {
+                Some(Ok(delivery)) => delivery,
+                Some(Err(err)) => return Err(err),
+                None => Delivery::default(),
+            };
+        // The polling interval, in seconds.
+        let poll = match path.push("poll", |path| f64::take_opt(path, source, "poll")) {
+            Some(Ok(seconds)) if seconds > 0. => {
+                Some(Duration::new(seconds as u64, (seconds.fract() * 1e9) as u32))
+            }
+            Some(Ok(_)) => {
+                return Err(ParseError::type_error("poll", &path, "positive number"));
+            }
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
+        Ok(Targetted {
+            select: select,
+            payload: (payload, filter, delivery, poll),
+        })
+    }
+}
+
 /// A handle to the public API.
 pub trait API: Send {
     /// Get the metadata on services matching some conditions.
@@ -343,6 +491,20 @@ pub trait API: Send {
     /// are added after the call, they will not be affected.
     fn remove_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize;
 
+    /// Replace the metadata (friendly name, room, icon) of a set of services.
+    ///
+    /// A call to `API::set_service_metadata(vec![req1, req2, ...], metadata)` will set
+    /// `metadata` on all the services matching _either_ `req1` or `req2` or ... and return
+    /// the number of services matching any of the selectors. The metadata is persisted, and
+    /// restored when the services are registered again, e.g. after a reboot.
+    ///
+    /// Note that this call is _not live_. In other words, if services
+    /// are added after the call, they will not be affected.
+    fn set_service_metadata(&self,
+                            selectors: Vec<ServiceSelector>,
+                            metadata: ServiceMetadata)
+                            -> usize;
+
 
     /// Get a list of channels matching some conditions
     fn get_channels(&self, selectors: Vec<ChannelSelector>) -> Vec<Channel>;
@@ -408,12 +570,37 @@ pub trait API: Send {
     /// receiving *every single value coming from the channels*. This is very rarely a good idea.
     /// Many devices may reject such requests.
     ///
+    /// The watch is registered with the adapters on behalf of `user`, including for channels
+    /// added later on.
+    ///
     /// The watcher is disconnected once the `WatchGuard` returned by this method is dropped.
     fn watch_values(&self,
                     watch: TargetMap<ChannelSelector, Exactly<Payload>>,
-                    on_event: Box<ExtSender<WatchEvent>>)
+                    on_event: Box<ExtSender<WatchEvent>>,
+                    user: User)
                     -> Self::WatchGuard;
 
+    /// Watch for changes from channels, with an optional `Filter` on the contents of values.
+    ///
+    /// This behaves as `watch_values`, except that, for each channel, a value entering the
+    /// range is delivered as `EnterRange` only if it is also accepted by the filter. A value
+    /// rejected by the filter is delivered as `ExitRange` if the previous value had been
+    /// delivered as `EnterRange`, and dropped otherwise.
+    ///
+    /// With `Delivery::Delta`, the first value of each channel is delivered in full, while
+    /// subsequent values entering the range are delivered as `EnterRangeDelta`, i.e. a JSON
+    /// Patch relative to the latest value delivered for this channel.
+    ///
+    /// With a polling interval, the channels that support fetching but not watching are
+    /// fetched at this interval instead, and each change is delivered as if the channel had
+    /// reported it. As these channels don't declare a type of ranges, values only enter the
+    /// range when they are equal to it.
+    fn watch_values_filtered(&self,
+                             watch: TargetMap<ChannelSelector, WatchOptions>,
+                             on_event: Box<ExtSender<WatchEvent>>,
+                             user: User)
+                             -> Self::WatchGuard;
+
     /// A value that causes a disconnection once it is dropped.
     type WatchGuard;
 }
@@ -1,10 +1,13 @@
 //! An API for plugging in adapters.
 
 use adapter::{Adapter, AdapterWatchGuard, RawAdapter, WatchEvent as AdapterWatchEvent};
-use adapter_utils::RawAdapterForAdapter;
-use api::{Error, InternalError, TargetMap, Targetted, WatchEvent};
+use adapter_utils::{self, RawAdapterForAdapter};
+use api::{Error, InternalError, Operation, TargetMap, Targetted, User, WatchEvent, WatchOptions};
 use channel::Channel;
+use filter::Filter;
 use io::*;
+use metrics::AdapterMetrics;
+use parse::ToJSON;
 use selector::*;
 use services::*;
 use tag_storage::TagStorage;
@@ -17,10 +20,12 @@ use transformable_channels::mpsc::*;
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
 use std::hash::{Hash, Hasher};
+use std::mem;
 use std::path::PathBuf;
 use std::ops::Deref;
 use std::sync::{Arc, Mutex, Weak};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 // In release build, log an error and continue.
 // In debug build, log an error and panic.
@@ -51,6 +56,10 @@ pub type WatchRequest = AdapterRequest<Vec<(Id<Channel>,
                                             Option<(Payload, Arc<Format>)>,
                                             // values
                                             Arc<Format>,
+                                            Option<Filter>,
+                                            Delivery,
+                                            // polling interval, for channels that can't be watched
+                                            Option<Duration>,
                                             Weak<WatcherData>)>>;
 
 pub type WatchGuardCommit = Vec<(Weak<WatcherData>, Vec<(Id<Channel>, Box<AdapterWatchGuard>)>)>;
@@ -68,11 +77,19 @@ struct ServiceData {
     /// Creation time properties.
     properties: HashMap<String, String>,
 
+    /// User-editable metadata, as in `Service`.
+    metadata: ServiceMetadata,
+
     /// Information on the channels. Used to build field `channels` of `Service`.
     channels: HashMap<Id<Channel>, Arc<SubCell<ChannelData>>>,
 
     /// The adapter, as in `Service`.
     adapter: Id<AdapterId>,
+
+    /// Labels provided by the adapter, as in `Service`.
+    label: Option<String>,
+    description: Option<String>,
+    icon: Option<String>,
 }
 impl ServiceData {
     /// Instantiate a `ServiceData` from a `Service`.
@@ -86,7 +103,11 @@ impl ServiceData {
             id: service.id,
             adapter: service.adapter,
             properties: service.properties,
+            metadata: service.metadata,
             channels: HashMap::new(),
+            label: service.label,
+            description: service.description,
+            icon: service.icon,
         }
     }
     fn as_service(&self) -> Service {
@@ -94,11 +115,15 @@ impl ServiceData {
             tags: self.tags.borrow().clone(),
             id: self.id.clone(),
             properties: self.properties.clone(),
+            metadata: self.metadata.clone(),
             adapter: self.adapter.clone(),
             channels: self.channels
                 .iter()
                 .map(|(key, value)| (key.clone(), (**value).borrow().channel.clone()))
                 .collect(),
+            label: self.label.clone(),
+            description: self.description.clone(),
+            icon: self.icon.clone(),
         }
     }
 }
@@ -133,6 +158,9 @@ impl<'a> ServiceLike for ServiceView<'a> {
         }
         false
     }
+    fn property(&self, name: &str) -> Option<&str> {
+        self.data.properties.get(name).map(String::as_str)
+    }
 }
 
 /// Data and metadata on an adapter.
@@ -239,11 +267,14 @@ impl Tagged for ChannelData {
 /// yet. The `WatcherData` is materialized as a `WatchGuard` in userland.
 pub struct WatcherData {
     /// The criteria for watching.
-    watch: TargetMap<ChannelSelector, Exactly<Payload>>,
+    watch: TargetMap<ChannelSelector, WatchOptions>,
 
     /// The listener for this watch.
     on_event: Mutex<Box<ExtSender<WatchEvent>>>,
 
+    /// The user on behalf of whom the watch is registered with adapters.
+    user: User,
+
     /// A unique key used to locate the `WatcherData` in the
     /// WatchMap.
     key: WatchKey,
@@ -274,12 +305,14 @@ impl PartialEq for WatcherData {
 impl WatcherData {
     fn new(liveness: &Arc<Liveness>,
            key: WatchKey,
-           watch: TargetMap<ChannelSelector, Exactly<Payload>>,
-           on_event: Box<ExtSender<WatchEvent>>)
+           watch: TargetMap<ChannelSelector, WatchOptions>,
+           on_event: Box<ExtSender<WatchEvent>>,
+           user: User)
            -> Self {
         WatcherData {
             key: key,
             on_event: Mutex::new(on_event),
+            user: user,
             watch: watch,
             is_dropped: Arc::new(AtomicBool::new(false)),
             guards: SubCell::new(liveness, HashMap::new()),
@@ -314,12 +347,13 @@ impl WatchMap {
         }
     }
     fn create(&mut self,
-              watch: TargetMap<ChannelSelector, Exactly<Payload>>,
-              on_event: Box<ExtSender<WatchEvent>>)
+              watch: TargetMap<ChannelSelector, WatchOptions>,
+              on_event: Box<ExtSender<WatchEvent>>,
+              user: User)
               -> Arc<WatcherData> {
         let id = WatchKey(self.counter);
         self.counter += 1;
-        let watcher = Arc::new(WatcherData::new(&self.liveness, id, watch, on_event));
+        let watcher = Arc::new(WatcherData::new(&self.liveness, id, watch, on_event, user));
         self.watchers.insert(id, watcher.clone());
         watcher
     }
@@ -329,6 +363,42 @@ impl WatchMap {
     }
 }
 
+/// A service that has been merged into another service, as per the `MergePolicy`.
+struct Alias {
+    /// The service into which this service has been merged.
+    canonical: Id<ServiceId>,
+
+    /// The adapter that registered this service.
+    adapter: Id<AdapterId>,
+
+    /// The channels this service added to the canonical service.
+    channels: Vec<Id<Channel>>,
+}
+
+/// The id that a selector requires, if any, so that the objects it selects are looked up in
+/// the maps of `State` rather than searched for.
+trait ExactId<K> {
+    fn exact_id(&self) -> Option<&Id<K>>;
+}
+
+impl ExactId<ServiceId> for ServiceSelector {
+    fn exact_id(&self) -> Option<&Id<ServiceId>> {
+        match self.id {
+            Exactly::Exactly(ref id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+impl ExactId<Channel> for ChannelSelector {
+    fn exact_id(&self) -> Option<&Id<Channel>> {
+        match self.id {
+            Exactly::Exactly(ref id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
 pub struct State {
     /// Adapters, indexed by their id.
     adapter_by_id: HashMap<Id<AdapterId>, AdapterData>,
@@ -350,6 +420,15 @@ pub struct State {
     /// The database used to persist tags.
     /// The underlying SQlite is opened lazily so we can create one here.
     db: Option<Arc<Mutex<TagStorage>>>,
+
+    /// How to handle services that describe the same device as an existing service.
+    merge_policy: MergePolicy,
+
+    /// Services that have been merged into another service, indexed by their id.
+    aliases: HashMap<Id<ServiceId>, Alias>,
+
+    /// Bumped by the manager whenever the taxonomy may have changed.
+    revision: u64,
 }
 
 impl State {
@@ -366,18 +445,107 @@ impl State {
         for id in service.borrow().channels.keys() {
             let _ignored = self.channel_by_id.remove(id);
         }
+        // The services merged into this one have lost their channels along with it.
+        let orphans: Vec<_> = self.aliases
+            .iter()
+            .filter(|&(_, alias)| alias.canonical == *id)
+            .map(|(alias_id, _)| alias_id.clone())
+            .collect();
+        for alias_id in orphans {
+            warn!("Service {} was merged into {}, which is being removed", alias_id, id);
+            let _ignored = self.aliases.remove(&alias_id);
+        }
         Ok(adapter)
     }
 
+    /// Auxiliary function to find a service describing the same device as `service`, as per
+    /// the merge policy.
+    fn aux_find_canonical(&self, service: &Service) -> Option<Id<ServiceId>> {
+        for (id, data) in &self.service_by_id {
+            if *id == service.id {
+                continue;
+            }
+            let data = data.borrow();
+            if let Some(property) = self.merge_policy
+                .same_device(&service.properties, &data.properties) {
+                info!("Merging service {} into {}, as they have the same {}",
+                      service.id,
+                      id,
+                      property);
+                return Some(id.clone());
+            }
+        }
+        None
+    }
+
+    /// Auxiliary function to merge `service` into the service `canonical`. The properties and
+    /// tags of `canonical` take precedence.
+    fn aux_merge_service(&mut self,
+                         service: Service,
+                         canonical: Id<ServiceId>)
+                         -> Result<(), Error> {
+        if !self.adapter_by_id.contains_key(&service.adapter) {
+            return Err(Error::Internal(InternalError::NoSuchAdapter(service.adapter)));
+        }
+        {
+            let data = match self.service_by_id.get(&canonical) {
+                None => return Err(Error::Internal(InternalError::NoSuchService(canonical))),
+                Some(data) => data,
+            };
+            let mut data = data.borrow_mut();
+            for (key, value) in service.properties {
+                data.properties.entry(key).or_insert(value);
+            }
+            if data.label.is_none() {
+                data.label = service.label;
+            }
+            if data.description.is_none() {
+                data.description = service.description;
+            }
+            if data.icon.is_none() {
+                data.icon = service.icon;
+            }
+            data.tags.borrow_mut().extend(service.tags);
+        }
+        self.aliases.insert(service.id,
+                            Alias {
+                                canonical: canonical,
+                                adapter: service.adapter,
+                                channels: vec![],
+                            });
+        Ok(())
+    }
+
+    /// The values of `map` that `selectors` may select. If each selector requires an id, e.g.
+    /// when a client fetches a few channels by id, they are looked up in `map`. Otherwise,
+    /// e.g. for selectors by feature or tag, all the values are returned, to be filtered.
+    fn candidates<'a, S, K, V>(selectors: &[S], map: &'a HashMap<Id<K>, V>) -> Vec<&'a V>
+        where S: ExactId<K>
+    {
+        let ids: Option<Vec<&Id<K>>> = selectors.iter().map(ExactId::exact_id).collect();
+        match ids {
+            Some(ids) => {
+                let mut seen = HashSet::new();
+                ids.into_iter()
+                    .filter(|id| seen.insert(*id))
+                    .filter_map(|id| map.get(id))
+                    .collect()
+            }
+            None => map.values().collect(),
+        }
+    }
+
     fn with_services<F>(&self, selectors: Vec<ServiceSelector>, mut cb: F)
         where F: FnMut(&Arc<SubCell<ServiceData>>)
     {
-        for service in self.service_by_id.values() {
+        if selectors.is_empty() {
             // All services match when we have no selectors.
-            if selectors.is_empty() {
+            for service in self.service_by_id.values() {
                 cb(service);
-                continue;
             }
+            return;
+        }
+        for service in Self::candidates(&selectors, &self.service_by_id) {
             let matches;
             {
                 // Ensure that we release the borrow before calling `cb`.
@@ -396,9 +564,10 @@ impl State {
                                  map: &HashMap<Id<K>, Arc<SubCell<V>>>,
                                  mut cb: F)
         where F: FnMut(&V),
-              V: SelectedBy<S>
+              V: SelectedBy<S>,
+              S: ExactId<K>
     {
-        for (_, data) in map.iter() {
+        for data in Self::candidates(&selectors, map) {
             let matches = selectors.iter().any(|selector| data.borrow().matches(selector));
             if matches {
                 cb(&*data.borrow());
@@ -411,9 +580,10 @@ impl State {
                                      map: &mut HashMap<Id<K>, Arc<SubCell<V>>>,
                                      mut cb: F)
         where F: FnMut(&mut V),
-              V: SelectedBy<S>
+              V: SelectedBy<S>,
+              S: ExactId<K>
     {
-        for (_, data) in map.iter_mut() {
+        for data in Self::candidates(&selectors, map) {
             let matches = selectors.iter().any(|selector| data.borrow().matches(selector));
             if matches {
                 cb(&mut *data.borrow_mut());
@@ -425,7 +595,8 @@ impl State {
     fn aux_get_channels<S, K, V>(selectors: Vec<S>,
                                  map: &HashMap<Id<K>, Arc<SubCell<V>>>)
                                  -> Vec<Channel>
-        where V: SelectedBy<S> + Deref<Target = Channel>
+        where V: SelectedBy<S> + Deref<Target = Channel>,
+              S: ExactId<K>
     {
         let mut result = Vec::new();
         Self::with_channels(selectors, map, |data| {
@@ -561,9 +732,26 @@ impl State {
             channel_by_id: HashMap::new(),
             watchers: Arc::new(Mutex::new(WatchMap::new(liveness))),
             db: db,
+            merge_policy: MergePolicy::default(),
+            aliases: HashMap::new(),
+            revision: 0,
         }
     }
 
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn bump_revision(&mut self) {
+        self.revision += 1;
+    }
+
+    /// Set how to handle services that describe the same device as an existing service.
+    /// Only affects services added afterwards.
+    pub fn set_merge_policy(&mut self, policy: MergePolicy) {
+        self.merge_policy = policy;
+    }
+
     /// Add an adapter to the system.
     ///
     /// # Errors
@@ -587,6 +775,14 @@ impl State {
         Ok(())
     }
 
+    pub fn adapter_ids(&self) -> Vec<Id<AdapterId>> {
+        self.adapter_by_id.keys().cloned().collect()
+    }
+
+    pub fn adapters(&self) -> Vec<Arc<RawAdapter>> {
+        self.adapter_by_id.values().map(|data| data.adapter.clone()).collect()
+    }
+
     /// Remove an adapter from the system, including all its services and channels.
     ///
     /// # Errors
@@ -602,6 +798,14 @@ impl State {
         for (service_id, _) in services.drain() {
             let _ignored = self.aux_remove_service(&service_id);
         }
+        let aliases: Vec<_> = self.aliases
+            .iter()
+            .filter(|&(_, alias)| alias.adapter == *id)
+            .map(|(alias_id, _)| alias_id.clone())
+            .collect();
+        for alias_id in aliases {
+            let _ignored = self.remove_service(&alias_id);
+        }
         Ok(())
     }
 
@@ -621,7 +825,11 @@ impl State {
     /// - `service` has channels;
     /// - a service with id `service.id` is already installed on the system;
     /// - there is no adapter with id `service.adapter`.
-    pub fn add_service(&mut self, service: Service) -> Result<(), Error> {
+    ///
+    /// If the merge policy finds that `service` describes the same device as an existing
+    /// service, `service` is merged into the existing service instead of being added.
+    /// Channels later added to `service` are added to the existing service.
+    pub fn add_service(&mut self, mut service: Service) -> Result<(), Error> {
         // Sanity checks.
         if service.adapter.is_default() {
             return Err(Error::Internal(InternalError::NoSuchAdapter(service.adapter)));
@@ -634,6 +842,27 @@ impl State {
         if !service.channels.is_empty() {
             return Err(Error::Internal(InternalError::InvalidInitialService));
         }
+
+        if self.aliases.contains_key(&service.id) {
+            return Err(Error::Internal(InternalError::DuplicateService(service.id)));
+        }
+        if let Some(canonical) = self.aux_find_canonical(&service) {
+            return self.aux_merge_service(service, canonical);
+        }
+
+        // Restore the metadata set by the user, which takes precedence over the adapter's.
+        if let Some(ref mutex) = self.db {
+            let stored = match mutex.lock().unwrap().get_metadata_for(&service.id) {
+                Err(err) => return Err(Error::Internal(InternalError::GenericError(format!("{}", err)))),
+                Ok(stored) => stored,
+            };
+            for (name, value) in stored.fields() {
+                if value.is_some() {
+                    let _ = service.metadata.set(name, value.clone());
+                }
+            }
+        }
+
         let service = ServiceData::new(&self.liveness, service);
         let mut services_for_this_adapter = match self.adapter_by_id.get_mut(&service.adapter) {
             None => {
@@ -690,6 +919,13 @@ impl State {
     /// - there is an internal inconsistency, in which case this method will still attempt to
     /// cleanup before returning an error.
     pub fn remove_service(&mut self, service_id: &Id<ServiceId>) -> Result<(), Error> {
+        if let Some(alias) = self.aliases.remove(service_id) {
+            // Only remove the channels this service brought to the service it was merged into.
+            for id in &alias.channels {
+                let _ignored = self.remove_channel(id);
+            }
+            return Ok(());
+        }
         let adapter = try!(self.aux_remove_service(service_id));
         match self.adapter_by_id.get_mut(&adapter) {
             None => Err(Error::Internal(InternalError::NoSuchAdapter(adapter.clone()))),
@@ -728,6 +964,23 @@ impl State {
             return Err(Error::Internal(InternalError::NoSuchChannel(channel.id)));
         }
 
+        // If the service has been merged, the channel goes to the service it was merged into.
+        let is_merged = match self.aliases.get(&channel.service) {
+            None => false,
+            Some(alias) => {
+                if alias.adapter != channel.adapter {
+                    return Err(Error::Internal(InternalError::ConflictingAdapter(alias.adapter
+                                                                                     .clone(),
+                                                                                 channel.adapter)));
+                }
+                true
+            }
+        };
+        let alias_id = channel.service.clone();
+        if is_merged {
+            channel.service = self.aliases[&alias_id].canonical.clone();
+        }
+
         // Add the database tags to this channel.
         if let Some(ref db) = self.db {
             let mut store = db.lock().unwrap();
@@ -748,7 +1001,7 @@ impl State {
                 Some(service) => service,
             };
             let mut service = &mut *service.borrow_mut();
-            if service.adapter != channel.adapter {
+            if !is_merged && service.adapter != channel.adapter {
                 return Err(Error::Internal(InternalError::ConflictingAdapter(service.adapter
                                                                                  .clone(),
                                                                              channel.adapter)));
@@ -772,6 +1025,9 @@ impl State {
             insert_in_service.commit();
             insert_in_channels.commit();
         }
+        if let Some(alias) = self.aliases.get_mut(&alias_id) {
+            alias.channels.push(id.clone());
+        }
         Ok(self.aux_channels_may_need_registration(vec![id]))
     }
 
@@ -783,12 +1039,44 @@ impl State {
     /// This method returns an error if the channel is not registered or if the service
     /// is not registered. In either case, it attemps to clean as much as possible, even
     /// if the state is inconsistent.
+    /// Add a service along with its channels. If any of them can't be added, all the changes
+    /// are reverted and the error is returned.
+    pub fn add_service_with_channels(&mut self,
+                                     builder: ServiceBuilder)
+                                     -> Result<WatchRequest, Error> {
+        let ServiceBuilder { service, channels } = builder;
+        let service_id = service.id.clone();
+        try!(self.add_service(service));
+        let mut request = WatchRequest::new();
+        for channel in channels {
+            match self.add_channel(channel) {
+                Ok(mut channel_request) => {
+                    for (adapter_id, (adapter, mut targets)) in channel_request.drain() {
+                        request.entry(adapter_id)
+                            .or_insert_with(|| (adapter, vec![]))
+                            .1
+                            .extend(targets.drain(..));
+                    }
+                }
+                Err(err) => {
+                    // This also removes the channels added so far.
+                    let _ignored = self.remove_service(&service_id);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(request)
+    }
+
     pub fn remove_channel(&mut self, id: &Id<Channel>) -> Result<(), Error> {
         let channel = match self.channel_by_id.remove(id) {
             None => return Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
             Some(channel) => channel,
         };
         Self::aux_channel_may_need_unregistration(&mut *channel.borrow_mut(), true);
+        for alias in self.aliases.values_mut() {
+            alias.channels.retain(|channel_id| channel_id != id);
+        }
 
         let service_id = &channel.borrow().channel.service;
         match self.service_by_id.get_mut(service_id) {
@@ -867,6 +1155,53 @@ impl State {
         result
     }
 
+    /// Update some of the properties of a service. The properties of an alias fill in those
+    /// of its canonical service, as when it was merged, rather than replacing them.
+    pub fn update_service_properties(&mut self,
+                                     id: &Id<ServiceId>,
+                                     properties: HashMap<String, String>)
+                                     -> Result<(), Error> {
+        let (id, replace) = match self.aliases.get(id) {
+            Some(alias) => (alias.canonical.clone(), false),
+            None => (id.clone(), true),
+        };
+        let data = match self.service_by_id.get(&id) {
+            None => return Err(Error::Internal(InternalError::NoSuchService(id))),
+            Some(data) => data,
+        };
+        let mut data = data.borrow_mut();
+        for (key, value) in properties {
+            if replace {
+                data.properties.insert(key, value);
+            } else {
+                data.properties.entry(key).or_insert(value);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_service_metadata(&mut self,
+                                selectors: Vec<ServiceSelector>,
+                                metadata: ServiceMetadata)
+                                -> usize {
+        let mut result = 0;
+        let db = self.db.clone();
+        self.with_services(selectors, |service| {
+            let mut service = service.borrow_mut();
+            if let Some(ref storage) = db {
+                storage.lock()
+                    .unwrap()
+                    .set_metadata(&service.id, &metadata)
+                    .unwrap_or_else(|err| {
+                        error!("Storage set_metadata error: {}", err);
+                    });
+            }
+            service.metadata = metadata.clone();
+            result += 1;
+        });
+        result
+    }
+
     pub fn get_channels(&self, selectors: Vec<ChannelSelector>) -> Vec<Channel> {
         Self::aux_get_channels(selectors, &self.channel_by_id)
     }
@@ -1014,17 +1349,23 @@ impl State {
 
     fn aux_start_channel_watch(watcher: &mut Arc<WatcherData>,
                                getter_data: &mut ChannelData,
-                               filter: &Exactly<Payload>,
+                               &(ref filter, ref payload_filter, delivery, poll): &WatchOptions,
                                adapter_by_id: &HashMap<Id<AdapterId>, AdapterData>,
                                per_adapter: &mut WatchRequest) {
         use std::collections::hash_map::Entry::*;
 
         let id = getter_data.id.clone();
         let adapter = getter_data.adapter.clone();
-        let sig = if let Some(ref sig) = getter_data.supports_watch {
-            sig.clone()
-        } else {
-            return;
+        let (sig, poll) = match (&getter_data.supports_watch, &getter_data.supports_fetch, poll) {
+            (&Some(ref sig), _, _) => (sig.clone(), None),
+            (&None, &Some(ref sig), Some(interval)) => {
+                // Emulate watching by polling. Without a range type, ranges are compared by
+                // equality with the values fetched.
+                let mut sig = sig.clone();
+                sig.accepts = sig.returns.clone();
+                (sig, Some(interval))
+            }
+            _ => return,
         };
 
         let return_type = if let Maybe::Required(ref typ) = sig.returns {
@@ -1067,10 +1408,23 @@ impl State {
                     Some(adapter_data) => adapter_data.adapter.clone(),
                 };
 
-                entry.insert((adapter, (vec![(id, range, return_type, Arc::downgrade(watcher) )])));
+                entry.insert((adapter,
+                              (vec![(id,
+                                     range,
+                                     return_type,
+                                     payload_filter.clone(),
+                                     delivery,
+                                     poll,
+                                     Arc::downgrade(watcher))])));
             }
             Occupied(mut entry) => {
-                (entry.get_mut().1).push((id, range, return_type, Arc::downgrade(watcher)));
+                (entry.get_mut().1).push((id,
+                                          range,
+                                          return_type,
+                                          payload_filter.clone(),
+                                          delivery,
+                                          poll,
+                                          Arc::downgrade(watcher)));
             }
         }
 
@@ -1078,13 +1432,15 @@ impl State {
     }
 
     pub fn prepare_channel_watch(&mut self,
-                                 mut watch: TargetMap<ChannelSelector, Exactly<Payload>>,
-                                 on_event: Box<ExtSender<WatchEvent>>)
+                                 mut watch: TargetMap<ChannelSelector, WatchOptions>,
+                                 on_event: Box<ExtSender<WatchEvent>>,
+                                 user: User)
                                  -> (WatchRequest, WatchKey, Arc<AtomicBool>) {
         // Prepare the watcher and store it. Once we leave the lock, every time a channel is
         // added/removed/updated, this will cause us to reexamine whether the channel should
         // be visible to a watcher.
-        let mut watcher = self.watchers.lock().unwrap().create(watch.clone(), on_event.clone());
+        let mut watcher =
+            self.watchers.lock().unwrap().create(watch.clone(), on_event.clone(), user);
         let is_dropped = watcher.is_dropped.clone();
 
         // Regroup per adapter.
@@ -1146,7 +1502,9 @@ impl State {
     }
 
     /// Start watching a set of channels.
-    pub fn start_watch(mut per_adapter: WatchRequest) -> WatchGuardCommit {
+    pub fn start_watch(mut per_adapter: WatchRequest,
+                       metrics: &AdapterMetrics)
+                       -> WatchGuardCommit {
         // In most cases, stop_watch will take place long after start_watch. It is, however,
         // possible that the `WatchGuard` is dropped before start_watch is processed for this
         // channel. In this case, three events take place:
@@ -1167,7 +1525,8 @@ impl State {
 
         let mut to_add = vec![];
         for (_, (adapter, mut adapter_request)) in per_adapter.drain() {
-            for (id, range, event_type, weak_watch_data) in adapter_request.drain(..) {
+            for (id, range, event_type, payload_filter, delivery, poll, weak_watch_data) in
+                adapter_request.drain(..) {
                 let watch_data = match weak_watch_data.upgrade() {
                     None => {
                         // The watch_data has already been dropped, nothing to do.
@@ -1177,6 +1536,10 @@ impl State {
                     Some(watch_data) => watch_data,
                 };
                 let is_dropped = watch_data.is_dropped.clone();
+                // Whether the latest value delivered for this channel was an `EnterRange`.
+                let is_in = AtomicBool::new(false);
+                // With `Delivery::Delta`, the latest value delivered for this channel.
+                let latest = Mutex::new(None);
                 if is_dropped.load(Ordering::Relaxed) {
                     // The WatchGuard has already been dropped.
                     debug!(target: "Taxonomy-backend", "State::start_watch, the guard has been dropped, is_dropped detected, skipping.");
@@ -1192,19 +1555,63 @@ impl State {
                         // the call to `stop_watch`.
                         return None;
                     }
+                    let accepted = |payload: &Payload| {
+                        payload_filter.as_ref()
+                            .map_or(true, |filter| filter.matches(&payload.to_json()))
+                    };
                     Some(match event {
-                        AdapterWatchEvent::Enter { id, value: (payload, format) } =>
-                            WatchEvent::EnterRange {
-                                channel: id,
-                                value: payload,
-                                format: format
-                            },
-                        AdapterWatchEvent::Exit { id, value: (payload, format) } =>
+                        AdapterWatchEvent::Enter { id, value: (payload, format), observed } => {
+                            if accepted(&payload) {
+                                is_in.store(true, Ordering::Relaxed);
+                                if delivery == Delivery::Delta {
+                                    let mut latest = latest.lock().unwrap();
+                                    let previous =
+                                        mem::replace(&mut *latest, Some(payload.clone()));
+                                    if let Some(previous) = previous {
+                                        return Some(WatchEvent::EnterRangeDelta {
+                                            channel: id,
+                                            patch: payload.diff(&previous),
+                                            format: format,
+                                            observed: observed
+                                        });
+                                    }
+                                }
+                                WatchEvent::EnterRange {
+                                    channel: id,
+                                    value: payload,
+                                    format: format,
+                                    observed: observed
+                                }
+                            } else if is_in.swap(false, Ordering::Relaxed) {
+                                if delivery == Delivery::Delta {
+                                    *latest.lock().unwrap() = Some(payload.clone());
+                                }
+                                // The value is still in range but doesn't pass the filter anymore.
+                                WatchEvent::ExitRange {
+                                    channel: id,
+                                    value: payload,
+                                    format: format,
+                                    observed: observed
+                                }
+                            } else {
+                                return None;
+                            }
+                        }
+                        AdapterWatchEvent::Exit { id, value: (payload, format), observed } => {
+                            if payload_filter.is_some() && !is_in.swap(false, Ordering::Relaxed) {
+                                // We haven't reported entering, so don't report exiting.
+                                return None;
+                            }
+                            if delivery == Delivery::Delta {
+                                *latest.lock().unwrap() = Some(payload.clone());
+                            }
                             WatchEvent::ExitRange {
                                 channel: id,
                                 value: payload,
-                                format: format
-                            },
+                                format: format,
+                                observed: observed
+                            }
+                        }
                         AdapterWatchEvent::Error { id, error } =>
                             WatchEvent::Error {
                                 channel: id,
@@ -1214,8 +1621,19 @@ impl State {
                 });
 
                 let mut guards = vec![];
-                for (id, result) in
-                    adapter.register_watch(vec![(id, range, event_type, Box::new(on_ok))]) {
+                let ids = vec![id.clone()];
+                let start = Instant::now();
+                let target = (id, range, event_type, Box::new(on_ok) as Box<ExtSender<_>>);
+                let user = watch_data.user.clone();
+                let registered = match poll {
+                    Some(interval) => {
+                        adapter_utils::poll_watch(adapter.clone(), target, interval, user)
+                    }
+                    None => adapter.register_watch(vec![target], user),
+                };
+                let errors = registered.iter().filter(|&&(_, ref result)| result.is_err()).count();
+                metrics.record(&adapter.id(), Operation::Watch, &ids, start.elapsed(), errors);
+                for (id, result) in registered {
                     debug!(target: "Taxonomy-backend", "State::start_watch, registered watch for {} => {}.", id, result.is_ok());
 
                     match result {
@@ -6,6 +6,7 @@ use values::*;
 use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
+use std::time::Duration;
 
 
 #[derive(Debug, Clone)]
@@ -42,11 +43,14 @@ impl ToJSON for Signature {
     fn to_json(&self) -> JSON {
         let mut vec = vec![];
         for &(key, value) in &[("accepts", &self.accepts), ("returns", &self.returns)] {
-            let spec;
-            match *value {
+            let (kind, format) = match *value {
                 Maybe::Nothing => continue,
-                Maybe::Required(ref format) => spec = vec![("requires", format.description())],
-                Maybe::Optional(ref format) => spec = vec![("optional", format.description())],
+                Maybe::Required(ref format) => ("requires", format),
+                Maybe::Optional(ref format) => ("optional", format),
+            };
+            let mut spec = vec![(kind, format.description().to_json())];
+            if let Some(constraint) = format.constraint() {
+                spec.push(("constraint", constraint.to_json()));
             }
             vec.push((key, spec.to_json()))
         }
@@ -134,12 +138,31 @@ pub struct Channel {
     /// to determine the type of values that may serve as condition
     /// and may be notified by the channel.
     pub supports_watch: Option<Signature>,
+
+    /// If `true`, the values fetched from or reported by this channel are kept over time,
+    /// e.g. to draw graphs. See `AdapterManager::get_history`.
+    pub record_history: bool,
+
+    /// How long a value of this channel remains true once it has been observed, e.g. a few
+    /// seconds for "motion detected". Once this delay has passed, the latest value is reported
+    /// as expired, see `AdapterManager::fetch_latest`. If `None`, values don't expire.
+    pub valid_for: Option<Duration>,
+
+    /// A human-readable name provided by the adapter, e.g. "Temperature", for front-ends to
+    /// display instead of the id.
+    pub label: Option<String>,
+
+    /// A longer human-readable description provided by the adapter.
+    pub description: Option<String>,
+
+    /// The name of an icon, to be interpreted by clients.
+    pub icon: Option<String>,
 }
 
 
 impl ToJSON for Channel {
     fn to_json(&self) -> JSON {
-        vec![
+        let mut vec = vec![
             ("id", self.id.to_json()),
             ("adapter", self.adapter.to_json()),
             ("tags", self.tags.to_json()),
@@ -147,8 +170,16 @@ impl ToJSON for Channel {
             ("feature", self.feature.to_json()),
             ("supports_send", self.supports_send.to_json()),
             ("supports_fetch", self.supports_fetch.to_json()),
-        ]
-            .to_json()
+        ];
+        // Labels are optional, only serialize those that the adapter provided.
+        for &(key, value) in &[("label", &self.label),
+                               ("description", &self.description),
+                               ("icon", &self.icon)] {
+            if let Some(ref value) = *value {
+                vec.push((key, value.to_json()));
+            }
+        }
+        vec.to_json()
     }
 }
 
@@ -230,6 +261,212 @@ lazy_static! {
         .. Channel::default()
     };
 
+    /// Standardized channel: the position of a window covering (blinds, shutters, garage
+    /// doors, etc.), where 0 is fully closed and 100 is fully open.
+    ///
+    /// Features:
+    /// - fetch from this channel to determine the current position;
+    /// - send to this channel to move the covering to a given position;
+    /// - watch this channel to be informed when the covering moves.
+    pub static ref COVER_POSITION : Channel = Channel {
+        feature: Id::new("cover/position"),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::PERCENT.clone()))),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::PERCENT.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Optional(format::PERCENT.clone()),
+            returns: Maybe::Required(format::PERCENT.clone())
+        }),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: start fully opening a window covering.
+    ///
+    /// Features:
+    /// - send to this channel to open the covering.
+    pub static ref COVER_OPEN : Channel = Channel {
+        feature: Id::new("cover/open"),
+        supports_send: Some(Signature::nothing()),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: start fully closing a window covering.
+    ///
+    /// Features:
+    /// - send to this channel to close the covering.
+    pub static ref COVER_CLOSE : Channel = Channel {
+        feature: Id::new("cover/close"),
+        supports_send: Some(Signature::nothing()),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: stop a window covering that is currently moving.
+    ///
+    /// Features:
+    /// - send to this channel to stop the covering at its current position.
+    pub static ref COVER_STOP : Channel = Channel {
+        feature: Id::new("cover/stop"),
+        supports_send: Some(Signature::nothing()),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: determine whether smoke has been detected.
+    ///
+    /// Features:
+    /// - fetch from this channel to determine whether smoke is currently detected;
+    /// - watch this channel to be informed when smoke is detected/cleared.
+    pub static ref ALARM_SMOKE : Channel = Channel {
+        feature: Id::new("alarm/smoke-detected"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::IS_DETECTED.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Optional(format::IS_DETECTED.clone()),
+            returns: Maybe::Required(format::IS_DETECTED.clone())
+        }),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: determine whether carbon monoxide has been detected.
+    ///
+    /// Features:
+    /// - fetch from this channel to determine whether carbon monoxide is currently detected;
+    /// - watch this channel to be informed when carbon monoxide is detected/cleared.
+    pub static ref ALARM_CO : Channel = Channel {
+        feature: Id::new("alarm/co-detected"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::IS_DETECTED.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Optional(format::IS_DETECTED.clone()),
+            returns: Maybe::Required(format::IS_DETECTED.clone())
+        }),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: determine whether a water leak has been detected.
+    ///
+    /// Features:
+    /// - fetch from this channel to determine whether a water leak is currently detected;
+    /// - watch this channel to be informed when a water leak is detected/cleared.
+    pub static ref ALARM_LEAK : Channel = Channel {
+        feature: Id::new("alarm/leak-detected"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::IS_DETECTED.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Optional(format::IS_DETECTED.clone()),
+            returns: Maybe::Required(format::IS_DETECTED.clone())
+        }),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: determine whether tampering has been detected.
+    ///
+    /// Features:
+    /// - fetch from this channel to determine whether tampering is currently detected;
+    /// - watch this channel to be informed when tampering is detected/cleared.
+    pub static ref ALARM_TAMPER : Channel = Channel {
+        feature: Id::new("alarm/tamper-detected"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::IS_DETECTED.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Optional(format::IS_DETECTED.clone()),
+            returns: Maybe::Required(format::IS_DETECTED.clone())
+        }),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: determine whether a switch (e.g. a plug) is on.
+    ///
+    /// Features:
+    /// - fetch from this channel to determine whether the switch is on;
+    /// - send to this channel to turn the switch on/off;
+    /// - watch this channel to be informed when it is turned on/off.
+    pub static ref SWITCH_IS_ON : Channel = Channel {
+        feature: Id::new("switch/is-on"),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Optional(format::ON_OFF.clone()),
+            returns: Maybe::Required(format::ON_OFF.clone())
+        }),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: the level of a multilevel switch (e.g. a dimmer), where 0 is off
+    /// and 100 is fully on.
+    ///
+    /// Features:
+    /// - fetch from this channel to determine the current level;
+    /// - send to this channel to change the level;
+    /// - watch this channel to be informed when the level changes.
+    pub static ref SWITCH_LEVEL : Channel = Channel {
+        feature: Id::new("switch/level"),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::PERCENT.clone()))),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::PERCENT.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Optional(format::PERCENT.clone()),
+            returns: Maybe::Required(format::PERCENT.clone())
+        }),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: the charge of the battery of a device.
+    ///
+    /// Features:
+    /// - fetch from this channel to determine the current charge;
+    /// - watch this channel to be informed when the charge changes.
+    pub static ref BATTERY_LEVEL : Channel = Channel {
+        feature: Id::new("battery/level"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::PERCENT.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Optional(format::PERCENT.clone()),
+            returns: Maybe::Required(format::PERCENT.clone())
+        }),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: the temperature measured by a sensor, as a `Temperature`.
+    ///
+    /// Features:
+    /// - fetch from this channel to determine the current temperature;
+    /// - watch this channel to be informed when the temperature changes, or when it crosses
+    ///   a threshold, e.g. with `{"Geq": {"C": 25}}`.
+    pub static ref SENSOR_TEMPERATURE : Channel = Channel {
+        feature: Id::new("sensor/temperature"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::TEMPERATURE.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Optional(format::TEMPERATURE_RANGE.clone()),
+            returns: Maybe::Required(format::TEMPERATURE.clone())
+        }),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: the relative humidity measured by a sensor, as a `Humidity`.
+    ///
+    /// Features:
+    /// - fetch from this channel to determine the current humidity;
+    /// - watch this channel to be informed when the humidity changes, or when it crosses a
+    ///   threshold, e.g. with `{"Geq": 60}`.
+    pub static ref SENSOR_HUMIDITY : Channel = Channel {
+        feature: Id::new("sensor/humidity"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::HUMIDITY.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Optional(format::HUMIDITY_RANGE.clone()),
+            returns: Maybe::Required(format::HUMIDITY.clone())
+        }),
+        .. Channel::default()
+    };
+
+    /// Standardized channel: the luminance measured by a sensor, as a `Luminance`.
+    ///
+    /// Features:
+    /// - fetch from this channel to determine the current luminance;
+    /// - watch this channel to be informed when the luminance changes, or when it crosses a
+    ///   threshold, e.g. with `{"Leq": {"lux": 50}}`.
+    pub static ref SENSOR_LUMINANCE : Channel = Channel {
+        feature: Id::new("sensor/luminance"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::LUMINANCE.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Optional(format::LUMINANCE_RANGE.clone()),
+            returns: Maybe::Required(format::LUMINANCE.clone())
+        }),
+        .. Channel::default()
+    };
+
     /// Standardized channel: log text to a console, a file, etc.
     ///
     /// Features:
@@ -262,4 +499,105 @@ lazy_static! {
         supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
         .. Channel::default()
     };
+
+    /// Standardized channel: have a device make itself noticed, e.g. a bulb that blinks or a
+    /// siren that beeps.
+    ///
+    /// Features:
+    /// - send to this channel to tell which physical device corresponds to a service, e.g.
+    ///   after including a batch of devices.
+    pub static ref IDENTIFY : Channel = Channel {
+        feature: Id::new("device/identify"),
+        supports_send: Some(Signature::nothing()),
+        .. Channel::default()
+    };
+}
+
+/// A standardized channel, along with a human-readable description of its purpose.
+///
+/// Used to let applications (e.g. rule editors) and adapter authors discover the features
+/// that have already been standardized.
+#[derive(Debug, Clone)]
+pub struct StandardChannel {
+    /// The channel template. Only `feature` and the signatures are meaningful.
+    pub channel: Channel,
+
+    /// A human-readable description of the feature.
+    pub description: String,
+}
+
+impl StandardChannel {
+    fn new(channel: &Channel, description: &str) -> Self {
+        StandardChannel {
+            channel: channel.clone(),
+            description: description.to_owned(),
+        }
+    }
+}
+
+impl ToJSON for StandardChannel {
+    fn to_json(&self) -> JSON {
+        vec![
+            ("feature", self.channel.feature.to_json()),
+            ("description", self.description.to_json()),
+            ("supports_send", self.channel.supports_send.to_json()),
+            ("supports_fetch", self.channel.supports_fetch.to_json()),
+            ("supports_watch", self.channel.supports_watch.to_json()),
+        ]
+            .to_json()
+    }
+}
+
+lazy_static! {
+    /// The catalog of all standardized channels.
+    ///
+    /// Keep this in sync with the standardized channels defined above.
+    pub static ref STANDARD_CHANNELS: Vec<StandardChannel> = vec![
+        StandardChannel::new(&DOOR_IS_LOCKED,
+                             "Determine whether a door is locked (not simply closed), lock or unlock it."),
+        StandardChannel::new(&DOOR_IS_OPEN,
+                             "Determine whether a door is opened, open or close it."),
+        StandardChannel::new(&LIGHT_IS_ON,
+                             "Determine whether a light is on, turn it on or off."),
+        StandardChannel::new(&LIGHT_COLOR_HSV,
+                             "Determine or change the color of a light."),
+        StandardChannel::new(&COVER_POSITION,
+                             "Determine or change the position of a window covering, from 0 (closed) to 100 (open)."),
+        StandardChannel::new(&COVER_OPEN,
+                             "Fully open a window covering."),
+        StandardChannel::new(&COVER_CLOSE,
+                             "Fully close a window covering."),
+        StandardChannel::new(&COVER_STOP,
+                             "Stop a window covering that is currently moving."),
+        StandardChannel::new(&ALARM_SMOKE,
+                             "Determine whether smoke has been detected."),
+        StandardChannel::new(&ALARM_CO,
+                             "Determine whether carbon monoxide has been detected."),
+        StandardChannel::new(&ALARM_LEAK,
+                             "Determine whether a water leak has been detected."),
+        StandardChannel::new(&ALARM_TAMPER,
+                             "Determine whether a device has been tampered with."),
+        StandardChannel::new(&SWITCH_IS_ON,
+                             "Determine whether a switch is on, turn it on or off."),
+        StandardChannel::new(&SWITCH_LEVEL,
+                             "Determine or change the level of a dimmer, from 0 (off) to 100 (fully on)."),
+        StandardChannel::new(&BATTERY_LEVEL,
+                             "Determine the charge of the battery of a device."),
+        StandardChannel::new(&SENSOR_TEMPERATURE,
+                             "Determine the temperature measured by a sensor."),
+        StandardChannel::new(&SENSOR_HUMIDITY,
+                             "Determine the relative humidity measured by a sensor."),
+        StandardChannel::new(&SENSOR_LUMINANCE,
+                             "Determine the luminance measured by a sensor."),
+        StandardChannel::new(&LOG,
+                             "Log text to a console, a file, etc."),
+        StandardChannel::new(&USERNAME,
+                             "Access the username of a device."),
+        StandardChannel::new(&PASSWORD,
+                             "Access the password of a device."),
+        StandardChannel::new(&AVAILABLE,
+                             "Determine whether a device is currently accessible."),
+        StandardChannel::new(&IDENTIFY,
+                             "Have a device make itself noticed, e.g. blink or beep."),
+    ];
 }
@@ -2,7 +2,7 @@
 //! Used for testing.
 use adapter::*;
 
-use api::{Error, User};
+use api::{Error, Observation, Source, User};
 use channel::Channel;
 use services::*;
 use values::*;
@@ -25,6 +25,9 @@ pub enum Tweak {
     /// Inject an error in a virtual setter. All operations on this setter will
     /// raise the error until `None` is injected instead.
     InjectSetterError(Id<Channel>, Option<Error>),
+
+    /// Inject an error in the heartbeat of the adapter, until `None` is injected instead.
+    InjectHeartbeatError(Option<Error>),
 }
 
 /// Something that happened to the virtual device, e.g. a value was sent.
@@ -64,6 +67,7 @@ pub struct FakeAdapter {
     values: SyncMap<Id<Channel>, Result<Value, Error>>,
     senders: SyncMap<Id<Channel>, Error>,
     watchers: SyncMap<Id<Channel>, Vec<WatcherState>>,
+    heartbeat: Arc<Mutex<Option<Error>>>,
 }
 
 impl FakeAdapter {
@@ -74,6 +78,7 @@ impl FakeAdapter {
         let (values_main, values_thread) = dup(Arc::new(Mutex::new(HashMap::new())));
         let (senders_main, senders_thread) = dup(Arc::new(Mutex::new(HashMap::new())));
         let (watchers_main, watchers_thread) = dup(Arc::new(Mutex::new(HashMap::new())));
+        let (heartbeat_main, heartbeat_thread) = dup(Arc::new(Mutex::new(None)));
 
         let mutex = Arc::new(Mutex::new(tx));
         let tweak = move |msg| {
@@ -90,6 +95,7 @@ impl FakeAdapter {
             tx_effect: Mutex::new(Box::new(tx_effect)),
             rx_effect: Mutex::new(Some(rx_effect)),
             watchers: watchers_main,
+            heartbeat: heartbeat_main,
         };
 
         thread::spawn(move || {
@@ -109,6 +115,7 @@ impl FakeAdapter {
                                             .send(WatchEvent::Enter {
                                                 id: id.clone(),
                                                 value: value.clone(),
+                                                observed: Observation::now(Source::Device),
                                             })
                                             .unwrap();
                                     }
@@ -123,6 +130,7 @@ impl FakeAdapter {
                                                 .send(WatchEvent::Enter {
                                                     id: id.clone(),
                                                     value: value.clone(),
+                                                    observed: Observation::now(Source::Device),
                                                 })
                                                 .unwrap();
                                         } else {
@@ -134,6 +142,7 @@ impl FakeAdapter {
                                                 .send(WatchEvent::Exit {
                                                     id: id.clone(),
                                                     value: value.clone(),
+                                                    observed: Observation::now(Source::Device),
                                                 })
                                                 .unwrap();
                                         }
@@ -154,6 +163,9 @@ impl FakeAdapter {
                     InjectSetterError(id, Some(err)) => {
                         senders_thread.lock().unwrap().insert(id, err);
                     }
+                    InjectHeartbeatError(err) => {
+                        *heartbeat_thread.lock().unwrap() = err;
+                    }
                 }
                 tx.send(()).unwrap();
             }
@@ -235,7 +247,14 @@ impl Adapter for FakeAdapter {
             .collect()
     }
 
-    fn register_watch(&self, mut watch: Vec<WatchTarget>) -> WatchResult {
+    fn heartbeat(&self) -> Result<(), Error> {
+        match *self.heartbeat.lock().unwrap() {
+            None => Ok(()),
+            Some(ref err) => Err(err.clone()),
+        }
+    }
+
+    fn register_watch(&self, mut watch: Vec<WatchTarget>, _: User) -> WatchResult {
         let mut watchers = self.watchers.lock().unwrap();
         watch.drain(..)
             .filter_map(|(id, filter, on_event)| {
@@ -0,0 +1,203 @@
+//! Filters on the payload of watch events.
+//!
+//! Ranges are enough for most channels, but channels that return rich `Json` values tend
+//! to produce many events that most watchers do not care about. A `Filter` lets a watcher
+//! select these events based on the contents of the value. Filters are evaluated by the
+//! back-end, before delivery.
+//!
+//! # JSON
+//!
+//! A filter is either:
+//!
+//! - a field predicate `{"field": path, op: operand}`, where `path` is a dot-separated path
+//!   into the value (e.g. `"wind.speed"`; an empty path designates the value itself) and
+//!   `op` is one of `Eq`, `Neq` (any JSON operand), `Lt`, `Leq`, `Gt`, `Geq` (numbers),
+//!   `Contains` (a string, looked for in strings and arrays of strings) or
+//!   `Exists` (a boolean);
+//! - `{"All": [filter, ...]}`, `{"Any": [filter, ...]}` or `{"Not": filter}`.
+//!
+//! ```
+//! extern crate foxbox_taxonomy;
+//! extern crate serde_json;
+//!
+//! use foxbox_taxonomy::filter::*;
+//! use foxbox_taxonomy::parse::*;
+//!
+//! # fn main() {
+//! let filter = Filter::from_str(r#"{"All": [
+//!   {"field": "model", "Eq": "WS-2902"},
+//!   {"field": "wind.speed", "Geq": 30}
+//! ]}"#).unwrap();
+//!
+//! let calm = serde_json::from_str(r#"{"model": "WS-2902", "wind": {"speed": 12}}"#).unwrap();
+//! let windy = serde_json::from_str(r#"{"model": "WS-2902", "wind": {"speed": 42.5}}"#).unwrap();
+//! assert!(!filter.matches(&calm));
+//! assert!(filter.matches(&windy));
+//!
+//! let serialized = serde_json::to_string(&filter.to_json()).unwrap();
+//! assert_eq!(Filter::from_str(&serialized).unwrap(), filter);
+//! # }
+//! ```
+
+use parse::*;
+
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Predicate {
+    Eq(JSON),
+    Neq(JSON),
+    Lt(f64),
+    Leq(f64),
+    Gt(f64),
+    Geq(f64),
+    Contains(String),
+    Exists(bool),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    Field {
+        path: Vec<String>,
+        predicate: Predicate,
+    },
+    All(Vec<Filter>),
+    Any(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Predicate {
+    fn matches(&self, value: Option<&JSON>) -> bool {
+        use self::Predicate::*;
+        let number = value.and_then(|value| value.as_f64());
+        match (self, value) {
+            (&Exists(expected), _) => value.is_some() == expected,
+            (_, None) => false,
+            (&Eq(ref expected), Some(value)) => value == expected,
+            (&Neq(ref expected), Some(value)) => value != expected,
+            (&Lt(bound), _) => number.map_or(false, |number| number < bound),
+            (&Leq(bound), _) => number.map_or(false, |number| number <= bound),
+            (&Gt(bound), _) => number.map_or(false, |number| number > bound),
+            (&Geq(bound), _) => number.map_or(false, |number| number >= bound),
+            (&Contains(ref needle), Some(&JSON::String(ref string))) => string.contains(needle),
+            (&Contains(ref needle), Some(&JSON::Array(ref array))) => {
+                array.iter().any(|item| item.as_str() == Some(needle))
+            }
+            (&Contains(_), _) => false,
+        }
+    }
+
+    fn to_json(&self) -> (&'static str, JSON) {
+        use self::Predicate::*;
+        match *self {
+            Eq(ref value) => ("Eq", value.clone()),
+            Neq(ref value) => ("Neq", value.clone()),
+            Lt(bound) => ("Lt", JSON::F64(bound)),
+            Leq(bound) => ("Leq", JSON::F64(bound)),
+            Gt(bound) => ("Gt", JSON::F64(bound)),
+            Geq(bound) => ("Geq", JSON::F64(bound)),
+            Contains(ref needle) => ("Contains", JSON::String(needle.clone())),
+            Exists(expected) => ("Exists", JSON::Bool(expected)),
+        }
+    }
+}
+
+impl Filter {
+    /// Determine whether a value, serialized as JSON, is accepted by this filter.
+    pub fn matches(&self, value: &JSON) -> bool {
+        match *self {
+            Filter::Field { ref path, ref predicate } => {
+                let mut current = Some(value);
+                for key in path {
+                    current = current.and_then(|current| current.find(key));
+                }
+                predicate.matches(current)
+            }
+            Filter::All(ref filters) => filters.iter().all(|filter| filter.matches(value)),
+            Filter::Any(ref filters) => filters.iter().any(|filter| filter.matches(value)),
+            Filter::Not(ref filter) => !filter.matches(value),
+        }
+    }
+}
+
+impl Parser<Filter> for Filter {
+    fn description() -> String {
+        "Filter".to_owned()
+    }
+    fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
+        if !source.is_object() {
+            return Err(ParseError::type_error("Filter", &path, "object"));
+        }
+        if let Some(result) = path.push("All", |path| Filter::take_vec_opt(path, source, "All")) {
+            return result.map(Filter::All);
+        }
+        if let Some(result) = path.push("Any", |path| Filter::take_vec_opt(path, source, "Any")) {
+            return result.map(Filter::Any);
+        }
+        if let Some(result) = path.push("Not", |path| Filter::take_opt(path, source, "Not")) {
+            return result.map(|filter| Filter::Not(Box::new(filter)));
+        }
+        let field = match source.find("field") {
+            Some(&JSON::String(ref field)) => field,
+            Some(_) => return Err(ParseError::type_error("field", &path, "string")),
+            None => return Err(ParseError::missing_field("All|Any|Not|field", &path)),
+        };
+        let field_path = field.split('.')
+            .filter(|key| !key.is_empty())
+            .map(|key| key.to_owned())
+            .collect();
+        let number = |name: &str| path.push(name, |path| f64::take_opt(path, source, name));
+        let predicate = if let Some(value) = source.find("Eq") {
+            Predicate::Eq(value.clone())
+        } else if let Some(value) = source.find("Neq") {
+            Predicate::Neq(value.clone())
+        } else if let Some(result) = number("Lt") {
+            Predicate::Lt(try!(result))
+        } else if let Some(result) = number("Leq") {
+            Predicate::Leq(try!(result))
+        } else if let Some(result) = number("Gt") {
+            Predicate::Gt(try!(result))
+        } else if let Some(result) = number("Geq") {
+            Predicate::Geq(try!(result))
+        } else if let Some(value) = source.find("Contains") {
+            match value.as_str() {
+                Some(needle) => Predicate::Contains(needle.to_owned()),
+                None => return Err(ParseError::type_error("Contains", &path, "string")),
+            }
+        } else if let Some(value) = source.find("Exists") {
+            match value.as_bool() {
+                Some(expected) => Predicate::Exists(expected),
+                None => return Err(ParseError::type_error("Exists", &path, "boolean")),
+            }
+        } else {
+            return Err(ParseError::missing_field("Eq|Neq|Lt|Leq|Gt|Geq|Contains|Exists", &path));
+        };
+        Ok(Filter::Field {
+            path: field_path,
+            predicate: predicate,
+        })
+    }
+}
+
+impl ToJSON for Filter {
+    fn to_json(&self) -> JSON {
+        let mut map = BTreeMap::new();
+        match *self {
+            Filter::Field { ref path, ref predicate } => {
+                let (op, operand) = predicate.to_json();
+                map.insert("field".to_owned(), JSON::String(path.join(".")));
+                map.insert(op.to_owned(), operand);
+            }
+            Filter::All(ref filters) => {
+                map.insert("All".to_owned(), filters.to_json());
+            }
+            Filter::Any(ref filters) => {
+                map.insert("Any".to_owned(), filters.to_json());
+            }
+            Filter::Not(ref filter) => {
+                map.insert("Not".to_owned(), filter.to_json());
+            }
+        }
+        JSON::Object(map)
+    }
+}
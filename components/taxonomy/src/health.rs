@@ -0,0 +1,219 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Whether adapters are still responsive, as checked by the watchdog of the `AdapterManager`,
+//! which periodically calls `Adapter::heartbeat` on each adapter.
+//!
+//! Unlike `AdapterStatus`, which adapters report themselves, the health of an adapter is
+//! found out from the outside, so it also catches adapters that are stuck.
+
+use adapter::RawAdapter;
+use parse::{JSON, ToJSON};
+use util::{AdapterId, Id};
+
+use transformable_channels::mpsc::ExtSender;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdapterHealth {
+    /// The adapter answered its latest heartbeat.
+    Healthy,
+    /// The adapter failed its latest `failures` heartbeats in a row, or didn't answer them in
+    /// time.
+    Unhealthy {
+        reason: String,
+        failures: u32,
+        /// When the first of these failures happened, in seconds since the epoch.
+        since: u64,
+    },
+}
+
+impl ToJSON for AdapterHealth {
+    fn to_json(&self) -> JSON {
+        match *self {
+            AdapterHealth::Healthy => vec![("health", "healthy".to_json())].to_json(),
+            AdapterHealth::Unhealthy { ref reason, failures, since } => {
+                vec![("health", "unhealthy".to_json()),
+                     ("reason", reason.to_json()),
+                     ("failures", JSON::U64(failures as u64)),
+                     ("since", JSON::U64(since))]
+                    .to_json()
+            }
+        }
+    }
+}
+
+/// A change in the health of an adapter, see `AdapterManager::watch_adapter_health`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthEvent {
+    pub adapter: Id<AdapterId>,
+    pub health: AdapterHealth,
+}
+
+/// The health of each adapter, and who to tell when it changes.
+#[derive(Default)]
+pub struct HealthMonitor {
+    health: HashMap<Id<AdapterId>, AdapterHealth>,
+    watchers: Vec<Box<ExtSender<HealthEvent>>>,
+    /// The adapters that haven't returned from their latest heartbeat yet. They are not
+    /// pinged again until they do, so that a stuck adapter doesn't pile up threads.
+    in_flight: HashSet<Id<AdapterId>>,
+}
+
+impl HealthMonitor {
+    pub fn health(&self) -> HashMap<Id<AdapterId>, AdapterHealth> {
+        self.health.clone()
+    }
+
+    pub fn watch(&mut self, on_event: Box<ExtSender<HealthEvent>>) {
+        self.watchers.push(on_event);
+    }
+
+    /// Record the outcome of a heartbeat, telling the watchers if the health of the adapter
+    /// changed. Every failure counts as a change, as it bumps `failures`.
+    pub fn record(&mut self, id: &Id<AdapterId>, result: Result<(), String>, now: u64) {
+        let health = match (result, self.health.get(id)) {
+            (Ok(()), Some(&AdapterHealth::Healthy)) => return,
+            (Ok(()), _) => AdapterHealth::Healthy,
+            (Err(reason), Some(&AdapterHealth::Unhealthy { failures, since, .. })) => {
+                AdapterHealth::Unhealthy {
+                    reason: reason,
+                    failures: failures + 1,
+                    since: since,
+                }
+            }
+            (Err(reason), _) => {
+                AdapterHealth::Unhealthy {
+                    reason: reason,
+                    failures: 1,
+                    since: now,
+                }
+            }
+        };
+        match health {
+            AdapterHealth::Healthy => {
+                info!(target: "Taxonomy-manager", "Adapter {} is healthy", id)
+            }
+            AdapterHealth::Unhealthy { ref reason, failures, .. } => {
+                warn!(target: "Taxonomy-manager",
+                      "Adapter {} failed {} heartbeat(s): {}",
+                      id,
+                      failures,
+                      reason)
+            }
+        }
+        self.health.insert(id.clone(), health.clone());
+        let event = HealthEvent {
+            adapter: id.clone(),
+            health: health,
+        };
+        let mut live = Vec::with_capacity(self.watchers.len());
+        for watcher in self.watchers.drain(..) {
+            if watcher.send(event.clone()).is_ok() {
+                live.push(watcher);
+            }
+        }
+        self.watchers = live;
+    }
+
+    /// Forget the adapters that are not registered anymore.
+    pub fn keep_only(&mut self, ids: &[Id<AdapterId>]) {
+        let health = self.health.drain().filter(|&(ref id, _)| ids.contains(id)).collect();
+        self.health = health;
+    }
+
+    /// Call `heartbeat` on each adapter, on threads of their own, and record the outcome once
+    /// they have all answered, or after `timeout`. Adapters that don't answer in time fail
+    /// this heartbeat.
+    pub fn check(monitor: &Arc<Mutex<HealthMonitor>>,
+                 adapters: Vec<Arc<RawAdapter>>,
+                 timeout: Duration) {
+        let ids: Vec<_> = adapters.iter().map(|adapter| adapter.id()).collect();
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut state = monitor.lock().unwrap();
+            state.keep_only(&ids);
+            for adapter in adapters {
+                if !state.in_flight.insert(adapter.id()) {
+                    continue;
+                }
+                let tx = tx.clone();
+                let monitor = monitor.clone();
+                thread::spawn(move || {
+                    let id = adapter.id();
+                    let result = adapter.heartbeat().map_err(|err| format!("{}", err));
+                    monitor.lock().unwrap().in_flight.remove(&id);
+                    let _ = tx.send((id, result));
+                });
+            }
+        }
+        drop(tx);
+
+        let deadline = Instant::now() + timeout;
+        let mut results = HashMap::new();
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match rx.recv_timeout(deadline - now) {
+                Ok((id, result)) => {
+                    results.insert(id, result);
+                }
+                // All the heartbeats returned, or the time is up.
+                Err(_) => break,
+            }
+        }
+
+        let now = now();
+        let mut state = monitor.lock().unwrap();
+        for id in ids {
+            let result = results.remove(&id)
+                .unwrap_or_else(|| Err("No answer to heartbeat".to_owned()));
+            state.record(&id, result, now);
+        }
+    }
+}
+
+#[test]
+fn test_health_monitor() {
+    use std::sync::mpsc::channel;
+
+    let mut monitor = HealthMonitor::default();
+    let (tx, rx) = channel();
+    monitor.watch(Box::new(tx));
+    let id = Id::new("adapter@test");
+
+    monitor.record(&id, Ok(()), 10);
+    assert_eq!(rx.try_recv().unwrap().health, AdapterHealth::Healthy);
+    // Staying healthy is not a change.
+    monitor.record(&id, Ok(()), 20);
+    assert!(rx.try_recv().is_err());
+
+    monitor.record(&id, Err("Timeout".to_owned()), 30);
+    monitor.record(&id, Err("Timeout".to_owned()), 40);
+    rx.try_recv().unwrap();
+    let event = rx.try_recv().unwrap();
+    assert_eq!(event.adapter, id);
+    assert_eq!(event.health,
+               AdapterHealth::Unhealthy {
+                   reason: "Timeout".to_owned(),
+                   failures: 2,
+                   since: 30,
+               });
+
+    monitor.record(&id, Ok(()), 50);
+    assert_eq!(rx.try_recv().unwrap().health, AdapterHealth::Healthy);
+
+    monitor.keep_only(&[]);
+    assert!(monitor.health().is_empty());
+}
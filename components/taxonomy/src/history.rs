@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The values taken by the channels that record their history (see `Channel::record_history`),
+//! e.g. to draw graphs. See `AdapterManager::get_history`.
+//!
+//! Values are recorded whenever such a channel is fetched or reports a change, with the time at
+//! which they were observed, and kept in the taxonomy database for `RETENTION_DAYS`.
+
+use channel::Channel;
+use parse::{JSON, Parser, ParseError, Path, ToJSON};
+use util::Id;
+
+use rusqlite::{Connection, Result};
+use serde_json;
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Values older than this are forgotten, in days.
+const RETENTION_DAYS: u64 = 30;
+
+/// How often old values are forgotten, in milliseconds.
+const PRUNE_INTERVAL_MS: u64 = 3600 * 1000;
+
+const DAY_MS: u64 = 24 * 3600 * 1000;
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() * 1000 + (now.subsec_nanos() / 1_000_000) as u64)
+        .unwrap_or(0)
+}
+
+/// The period of time to read, in milliseconds since the epoch.
+///
+/// # JSON
+///
+/// An object with optional fields `from` and `to` (inclusive), e.g. `{"from": 1467331200000}`.
+/// Without them, the whole history is read.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HistoryRange {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl Parser<HistoryRange> for HistoryRange {
+    fn description() -> String {
+        "HistoryRange".to_owned()
+    }
+    fn parse(path: Path, source: &JSON) -> ::std::result::Result<Self, ParseError> {
+        let from = match path.push("from", |path| u64::take_opt(path, source, "from")) {
+            Some(result) => Some(try!(result)),
+            None => None,
+        };
+        let to = match path.push("to", |path| u64::take_opt(path, source, "to")) {
+            Some(result) => Some(try!(result)),
+            None => None,
+        };
+        Ok(HistoryRange {
+            from: from,
+            to: to,
+        })
+    }
+}
+
+/// A value taken by a channel.
+///
+/// # JSON
+///
+/// An object `{"timestamp": number, "value": ...}`, with the timestamp in milliseconds since
+/// the epoch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub timestamp: u64,
+    pub value: JSON,
+}
+
+impl ToJSON for HistoryEntry {
+    fn to_json(&self) -> JSON {
+        vec![("timestamp", JSON::U64(self.timestamp)), ("value", self.value.clone())].to_json()
+    }
+}
+
+pub struct ValueHistory {
+    db: Connection,
+    last_prune: u64,
+}
+
+impl ValueHistory {
+    /// Open the history stored in the database at `path`, or keep it in memory.
+    pub fn new(path: Option<&PathBuf>) -> Result<Self> {
+        let db = try!(match path {
+            Some(path) => Connection::open(path),
+            None => Connection::open_in_memory(),
+        });
+        try!(db.execute("CREATE TABLE IF NOT EXISTS value_history (
+                    channel    TEXT NOT NULL,
+                    timestamp  INTEGER NOT NULL,
+                    value      TEXT NOT NULL,
+                    PRIMARY KEY (channel, timestamp)
+            )",
+                        &[]));
+        Ok(ValueHistory {
+            db: db,
+            last_prune: 0,
+        })
+    }
+
+    /// Record the `value` of `channel`, observed at `timestamp`. A value replayed from a cache
+    /// is only recorded once.
+    pub fn record(&mut self, channel: &Id<Channel>, timestamp: u64, value: &JSON) -> Result<()> {
+        let source = serde_json::to_string(value).unwrap_or_else(|_| "null".to_owned());
+        try!(self.db.execute("INSERT OR IGNORE INTO value_history VALUES ($1, $2, $3)",
+                             &[&channel.to_string(), &(timestamp as i64), &source]));
+        let now = now_ms();
+        if now >= self.last_prune + PRUNE_INTERVAL_MS {
+            self.last_prune = now;
+            let oldest = now.saturating_sub(RETENTION_DAYS * DAY_MS) as i64;
+            try!(self.db.execute("DELETE FROM value_history WHERE timestamp < $1", &[&oldest]));
+        }
+        Ok(())
+    }
+
+    /// The values of `channel` during `range`, oldest first.
+    pub fn get(&self, channel: &Id<Channel>, range: &HistoryRange) -> Result<Vec<HistoryEntry>> {
+        let from = range.from.unwrap_or(0) as i64;
+        let to = range.to.map_or(i64::max_value(), |to| to as i64);
+        let mut stmt = try!(self.db.prepare("SELECT timestamp, value FROM value_history \
+                                             WHERE channel=$1 AND timestamp >= $2 \
+                                             AND timestamp <= $3 ORDER BY timestamp"));
+        let mut rows = try!(stmt.query(&[&channel.to_string(), &from, &to]));
+        let mut entries = vec![];
+        while let Some(result_row) = rows.next() {
+            let row = try!(result_row);
+            let timestamp: i64 = row.get(0);
+            let source: String = row.get(1);
+            entries.push(HistoryEntry {
+                timestamp: timestamp as u64,
+                value: serde_json::from_str(&source).unwrap_or(JSON::Null),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+#[test]
+fn test_value_history() {
+    let thermometer = Id::<Channel>::new("getter:temperature@test");
+    let light = Id::<Channel>::new("getter:light@test");
+    let now = now_ms();
+
+    let mut history = ValueHistory::new(None).unwrap();
+    assert!(history.get(&thermometer, &HistoryRange::default()).unwrap().is_empty());
+
+    history.record(&thermometer, now - 2000, &JSON::F64(20.5)).unwrap();
+    history.record(&thermometer, now, &JSON::F64(21.)).unwrap();
+    history.record(&thermometer, now - 1000, &JSON::F64(20.8)).unwrap();
+    // Replayed from a cache.
+    history.record(&thermometer, now, &JSON::F64(21.)).unwrap();
+    history.record(&light, now, &JSON::String("On".to_owned())).unwrap();
+
+    let entries = history.get(&thermometer, &HistoryRange::default()).unwrap();
+    assert_eq!(entries.iter().map(|entry| entry.timestamp).collect::<Vec<_>>(),
+               vec![now - 2000, now - 1000, now]);
+    assert_eq!(entries[2].value, JSON::F64(21.));
+    assert_eq!(entries[2].to_json().find("timestamp"), Some(&JSON::U64(now)));
+
+    let source = vec![("from", JSON::U64(now - 1000))].to_json();
+    let range = HistoryRange::parse(Path::new(), &source).unwrap();
+    assert_eq!(range.to, None);
+    assert_eq!(history.get(&thermometer, &range).unwrap().len(), 2);
+    let range = HistoryRange {
+        from: Some(now - 1500),
+        to: Some(now - 500),
+    };
+    assert_eq!(history.get(&thermometer, &range).unwrap()[0].value, JSON::F64(20.8));
+    assert_eq!(history.get(&light, &HistoryRange::default()).unwrap().len(), 1);
+}
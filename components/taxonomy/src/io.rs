@@ -6,6 +6,8 @@ use api::Error;
 use parse::*;
 use values::*;
 
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::error::Error as StdError;
 use std::fmt;
 use std::sync::Arc;
@@ -13,8 +15,43 @@ use std::sync::Arc;
 /// Placeholder.
 pub struct BinarySource;
 
-/// Placeholder.
-pub struct BinaryTarget;
+/// The destination of the binary components of a value, during serialization.
+///
+/// With `BinaryTarget::inline()`, binary components are embedded in the JSON. With
+/// `BinaryTarget::detached()`, they are stored aside as parts, and the JSON only contains the
+/// index of the part, so that they can be transmitted without inflating them, e.g. as binary
+/// WebSocket frames.
+pub struct BinaryTarget {
+    parts: Option<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl BinaryTarget {
+    /// A target that embeds binary components in the JSON.
+    pub fn inline() -> Self {
+        BinaryTarget { parts: None }
+    }
+
+    /// A target that stores binary components aside.
+    pub fn detached() -> Self {
+        BinaryTarget { parts: Some(RefCell::new(vec![])) }
+    }
+
+    /// Store `data` as a part, if this target is detached.
+    ///
+    /// Returns the index of the part, or `None` if the data should be embedded in the JSON.
+    pub fn push(&self, data: &[u8]) -> Option<usize> {
+        self.parts.as_ref().map(|parts| {
+            let mut parts = parts.borrow_mut();
+            parts.push(data.to_vec());
+            parts.len() - 1
+        })
+    }
+
+    /// The parts stored aside, in order.
+    pub fn into_parts(self) -> Vec<Vec<u8>> {
+        self.parts.map_or(vec![], RefCell::into_inner)
+    }
+}
 
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize)]
@@ -53,9 +90,26 @@ impl Payload {
 
     /// Serialize a `Value` into a `Payload`.
     pub fn from_value(value: &Value, format: &Arc<Format>) -> Result<Payload, Error> {
-        format.serialize(value, &BinaryTarget)
+        format.serialize(value, &BinaryTarget::inline())
             .map(Self::new)
     }
+
+    /// Serialize a `Value` into a `Payload`, storing its binary components aside.
+    ///
+    /// The resulting `Payload` references the binary parts by index.
+    pub fn from_value_detached(value: &Value,
+                               format: &Arc<Format>)
+                               -> Result<(Payload, Vec<Vec<u8>>), Error> {
+        let target = BinaryTarget::detached();
+        let json = try!(format.serialize(value, &target));
+        Ok((Self::new(json), target.into_parts()))
+    }
+
+    /// Reserialize this `Payload`, storing its binary components aside.
+    pub fn detach(&self, format: &Arc<Format>) -> Result<(Payload, Vec<Vec<u8>>), Error> {
+        let value = try!(self.to_value(format));
+        Self::from_value_detached(&value, format)
+    }
     pub fn from_data<T>(data: T, format: &Arc<Format>) -> Result<Payload, Error>
         where T: Data + PartialEq
     {
@@ -64,6 +118,183 @@ impl Payload {
     pub fn to_value(&self, format: &Arc<Format>) -> Result<Value, Error> {
         format.parse(Path::new(), &self.json, &BinarySource)
     }
+
+    /// Compute a JSON Patch (RFC 6902) transforming `previous` into `self`.
+    ///
+    /// The patch only contains `add`, `remove` and `replace` operations.
+    pub fn diff(&self, previous: &Payload) -> Payload {
+        let mut ops = vec![];
+        diff_json(&mut String::new(), &previous.json, &self.json, &mut ops);
+        Self::new(JSON::Array(ops))
+    }
+
+    /// Apply a JSON Patch (RFC 6902) produced by `diff`.
+    pub fn patch(&self, patch: &Payload) -> Result<Payload, Error> {
+        let ops = match patch.json {
+            JSON::Array(ref ops) => ops,
+            _ => {
+                return Err(Error::Parsing(ParseError::type_error("patch", &Path::new(), "array")))
+            }
+        };
+        let mut json = self.json.clone();
+        for op in ops {
+            try!(apply_op(&mut json, op));
+        }
+        Ok(Self::new(json))
+    }
+}
+
+/// How the values of a watched channel are delivered to the watcher.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Every value is delivered in full.
+    Full,
+
+    /// The first value is delivered in full, subsequent values are delivered as a JSON Patch
+    /// relative to the latest value delivered. Mostly useful for channels with large `Json`
+    /// values, in which only a few fields change at a time.
+    Delta,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Delivery::Full
+    }
+}
+
+impl Parser<Delivery> for Delivery {
+    fn description() -> String {
+        "Delivery".to_owned()
+    }
+    fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
+        match source.as_string() {
+            Some("full") => Ok(Delivery::Full),
+            Some("delta") => Ok(Delivery::Delta),
+            Some(constant) => Err(ParseError::unknown_constant(constant, &path)),
+            None => Err(ParseError::type_error("Delivery", &path, "string")),
+        }
+    }
+}
+
+impl ToJSON for Delivery {
+    fn to_json(&self) -> JSON {
+        match *self {
+            Delivery::Full => "full",
+            Delivery::Delta => "delta",
+        }.to_json()
+    }
+}
+
+/// Escape a key for use in a JSON Pointer (RFC 6901).
+fn escape_pointer(key: &str) -> String {
+    key.replace("~", "~0").replace("/", "~1")
+}
+
+fn unescape_pointer(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+fn patch_op(op: &str, path: &str, value: Option<&JSON>) -> JSON {
+    let mut map = BTreeMap::new();
+    map.insert("op".to_owned(), JSON::String(op.to_owned()));
+    map.insert("path".to_owned(), JSON::String(path.to_owned()));
+    if let Some(value) = value {
+        map.insert("value".to_owned(), value.clone());
+    }
+    JSON::Object(map)
+}
+
+fn diff_json(path: &mut String, previous: &JSON, next: &JSON, ops: &mut Vec<JSON>) {
+    if previous == next {
+        return;
+    }
+    let len = path.len();
+    match (previous, next) {
+        (&JSON::Object(ref previous), &JSON::Object(ref next)) => {
+            for key in previous.keys().filter(|key| !next.contains_key(*key)) {
+                path.push('/');
+                path.push_str(&escape_pointer(key));
+                ops.push(patch_op("remove", path, None));
+                path.truncate(len);
+            }
+            for (key, value) in next {
+                path.push('/');
+                path.push_str(&escape_pointer(key));
+                match previous.get(key) {
+                    None => ops.push(patch_op("add", path, Some(value))),
+                    Some(old) => diff_json(path, old, value, ops),
+                }
+                path.truncate(len);
+            }
+        }
+        (&JSON::Array(ref previous), &JSON::Array(ref next)) if previous.len() == next.len() => {
+            for (i, (old, value)) in previous.iter().zip(next.iter()).enumerate() {
+                path.push_str(&format!("/{}", i));
+                diff_json(path, old, value, ops);
+                path.truncate(len);
+            }
+        }
+        _ => ops.push(patch_op("replace", path, Some(next))),
+    }
+}
+
+fn apply_op(json: &mut JSON, op: &JSON) -> Result<(), Error> {
+    let error = |expected: &str| {
+        Error::Parsing(ParseError::type_error("patch", &Path::new(), expected))
+    };
+    let kind = try!(op.find("op").and_then(JSON::as_string).ok_or_else(|| error("op")));
+    let path = try!(op.find("path").and_then(JSON::as_string).ok_or_else(|| error("path")));
+    let value = op.find("value").cloned();
+    if path.is_empty() {
+        return match (kind, value) {
+            ("replace", Some(value)) | ("add", Some(value)) => {
+                *json = value;
+                Ok(())
+            }
+            _ => Err(error("replace or add with a value")),
+        };
+    }
+    let mut tokens: Vec<String> = path.split('/').skip(1).map(unescape_pointer).collect();
+    let last = tokens.pop().unwrap_or_else(String::new);
+    let mut parent = json;
+    for token in tokens {
+        let current = parent;
+        let child = match *current {
+            JSON::Object(ref mut map) => map.get_mut(&token),
+            JSON::Array(ref mut vec) => {
+                token.parse::<usize>().ok().and_then(move |i| vec.get_mut(i))
+            }
+            _ => None,
+        };
+        parent = try!(child.ok_or_else(|| error("an existing path")));
+    }
+    match (kind, parent, value) {
+        ("remove", &mut JSON::Object(ref mut map), _) => {
+            map.remove(&last).map(|_| ()).ok_or_else(|| error("an existing key"))
+        }
+        ("add", &mut JSON::Object(ref mut map), Some(value)) |
+        ("replace", &mut JSON::Object(ref mut map), Some(value)) => {
+            map.insert(last, value);
+            Ok(())
+        }
+        (kind, &mut JSON::Array(ref mut vec), value) => {
+            let index = if kind == "add" && last == "-" {
+                vec.len()
+            } else {
+                try!(last.parse::<usize>().map_err(|_| error("an array index")))
+            };
+            match (kind, value) {
+                ("remove", _) if index < vec.len() => {
+                    vec.remove(index);
+                }
+                ("add", Some(value)) if index <= vec.len() => vec.insert(index, value),
+                ("replace", Some(value)) if index < vec.len() => vec[index] = value,
+                _ => return Err(error("a valid array operation")),
+            }
+            Ok(())
+        }
+        _ => Err(error("a valid operation")),
+    }
 }
 
 impl ToJSON for Payload {
@@ -87,11 +318,118 @@ impl Parser<Payload> for Payload {
     }
 }
 
+/// A restriction on the values accepted by a channel, declared by the adapter.
+///
+/// Constraints are checked on the JSON representation of values, before they reach the
+/// adapter, and are exposed to clients so that they can render e.g. sliders with the
+/// right bounds.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constraint {
+    /// A number between `min` and `max`, both included. Numbers out of bounds are clamped
+    /// and, if `step` is specified, rounded to the nearest `min + k * step`.
+    Range {
+        min: f64,
+        max: f64,
+        step: Option<f64>,
+    },
+
+    /// One of a list of strings, e.g. the modes of a thermostat.
+    OneOf(Vec<String>),
+}
+
+impl Constraint {
+    pub fn range(min: f64, max: f64) -> Self {
+        Constraint::Range {
+            min: min,
+            max: max,
+            step: None,
+        }
+    }
+
+    pub fn range_with_step(min: f64, max: f64, step: f64) -> Self {
+        Constraint::Range {
+            min: min,
+            max: max,
+            step: Some(step),
+        }
+    }
+
+    pub fn one_of(values: &[&str]) -> Self {
+        Constraint::OneOf(values.iter().map(|value| (*value).to_owned()).collect())
+    }
+
+    /// Check a value, clamping it if it is out of range.
+    pub fn apply(&self, path: &Path, source: &JSON) -> Result<JSON, Error> {
+        match *self {
+            Constraint::Range { min, max, step } => {
+                let number = match source.as_f64() {
+                    Some(number) => number,
+                    None => {
+                        return Err(Error::Parsing(ParseError::type_error("Constraint",
+                                                                         path,
+                                                                         "number")))
+                    }
+                };
+                let mut clamped = number.max(min).min(max);
+                if let Some(step) = step {
+                    if step > 0. {
+                        clamped = min + ((clamped - min) / step).round() * step;
+                        if clamped > max {
+                            clamped -= step;
+                        }
+                    }
+                }
+                if (clamped - number).abs() < ::std::f64::EPSILON {
+                    return Ok(source.clone());
+                }
+                // Keep integers as integers, as most formats expect them.
+                let is_integer = source.is_u64() || source.is_i64();
+                if is_integer && clamped.fract() == 0. {
+                    if clamped >= 0. {
+                        Ok(JSON::U64(clamped as u64))
+                    } else {
+                        Ok(JSON::I64(clamped as i64))
+                    }
+                } else {
+                    Ok(JSON::F64(clamped))
+                }
+            }
+            Constraint::OneOf(ref values) => {
+                match source.as_str() {
+                    Some(value) if values.iter().any(|accepted| accepted == value) => {
+                        Ok(source.clone())
+                    }
+                    Some(value) => Err(Error::Parsing(ParseError::unknown_constant(value, path))),
+                    None => {
+                        Err(Error::Parsing(ParseError::type_error("Constraint", path, "string")))
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ToJSON for Constraint {
+    fn to_json(&self) -> JSON {
+        match *self {
+            Constraint::Range { min, max, step } => {
+                let mut range = vec![("min", min.to_json()), ("max", max.to_json())];
+                if let Some(step) = step {
+                    range.push(("step", step.to_json()));
+                }
+                vec![("range", range.to_json())].to_json()
+            }
+            Constraint::OneOf(ref values) => vec![("one_of", values.to_json())].to_json(),
+        }
+    }
+}
+
 pub struct Format {
     description: Box<Fn() -> String + Send + Sync>,
     #[allow(type_complexity)]
     parse: Box<Fn(Path, &JSON, &BinarySource) -> Result<Value, Error> + Send + Sync>,
     serialize: Box<Fn(&Value, &BinaryTarget) -> Result<JSON, Error> + Send + Sync>,
+    constraint: Option<Constraint>,
 }
 impl Format {
     #[allow(new_without_default)] // Clippy's warning doesn't make sense.
@@ -107,6 +445,26 @@ impl Format {
                 let data = try!(value.cast::<T>());
                 T::serialize(data, target)
             }),
+            constraint: None,
+        }
+    }
+
+    /// Restrict the values accepted by channels using this format, e.g.
+    /// `Arc::new(Format::new::<Json>().with_constraint(Constraint::range(7., 30.)))`.
+    pub fn with_constraint(mut self, constraint: Constraint) -> Self {
+        self.constraint = Some(constraint);
+        self
+    }
+
+    pub fn constraint(&self) -> Option<&Constraint> {
+        self.constraint.as_ref()
+    }
+
+    /// Check a payload against the constraint of this format, if any, clamping it if needed.
+    pub fn constrain(&self, payload: &Payload) -> Result<Payload, Error> {
+        match self.constraint {
+            None => Ok(payload.clone()),
+            Some(ref constraint) => constraint.apply(&Path::new(), &payload.json).map(Payload::new),
         }
     }
 
@@ -133,3 +491,73 @@ impl fmt::Debug for Format {
         self.description().fmt(formatter)
     }
 }
+
+#[test]
+fn test_payload_diff_patch() {
+    use serde_json;
+
+    let previous = Payload::new(serde_json::from_str(r#"{
+        "on": true, "bri": 100, "xy": [0.3, 0.4], "name": "kitchen", "a/b": 1
+    }"#).unwrap());
+    let next = Payload::new(serde_json::from_str(r#"{
+        "on": true, "bri": 120, "xy": [0.3, 0.5], "effect": "none", "a/b": 2
+    }"#).unwrap());
+
+    let patch = next.diff(&previous);
+    let ops = patch.to_json();
+    let ops = ops.as_array().unwrap();
+    assert_eq!(ops.len(), 5);
+    assert!(ops.iter().any(|op| op.find("path").unwrap().as_string() == Some("/a~1b")));
+    assert_eq!(previous.patch(&patch).unwrap(), next);
+
+    // Diffing identical values yields an empty patch.
+    assert_eq!(next.diff(&next).to_json(), JSON::Array(vec![]));
+
+    // Replacing the root.
+    let scalar = Payload::new(JSON::U64(3));
+    assert_eq!(previous.patch(&scalar.diff(&previous)).unwrap(), scalar);
+}
+
+#[test]
+fn test_payload_detach() {
+    use util::Id;
+
+    let binary = Binary {
+        data: vec![0xFF, 0xD8, 0xFF, 0xE0],
+        mimetype: Id::new("image/jpeg"),
+    };
+    let inline = Payload::from_data(binary, &format::BINARY).unwrap();
+    let (detached, parts) = inline.detach(&format::BINARY).unwrap();
+    assert_eq!(parts, vec![vec![0xFF, 0xD8, 0xFF, 0xE0]]);
+
+    let json = detached.to_json();
+    assert_eq!(json.find("part").and_then(JSON::as_u64), Some(0));
+    assert_eq!(json.find("size").and_then(JSON::as_u64), Some(4));
+    assert_eq!(json.find("mimetype").and_then(JSON::as_string), Some("image/jpeg"));
+    assert!(json.find("data").is_none());
+
+    // Values without binary components are left unchanged.
+    let on = Payload::from_data(OnOff::On, &format::ON_OFF).unwrap();
+    let (detached, parts) = on.detach(&format::ON_OFF).unwrap();
+    assert_eq!(detached, on);
+    assert!(parts.is_empty());
+}
+
+#[test]
+fn test_constraint_apply() {
+    let path = Path::new();
+    let range = Constraint::range_with_step(7., 30., 0.5);
+    assert_eq!(range.apply(&path, &JSON::F64(21.3)).unwrap(), JSON::F64(21.5));
+    assert_eq!(range.apply(&path, &JSON::U64(35)).unwrap(), JSON::U64(30));
+    assert_eq!(range.apply(&path, &JSON::I64(-5)).unwrap(), JSON::U64(7));
+    assert_eq!(range.apply(&path, &JSON::U64(20)).unwrap(), JSON::U64(20));
+    assert!(range.apply(&path, &JSON::String("20".to_owned())).is_err());
+
+    let signed = Constraint::range(-10., 10.);
+    assert_eq!(signed.apply(&path, &JSON::I64(-20)).unwrap(), JSON::I64(-10));
+
+    let modes = Constraint::one_of(&["schedule", "away"]);
+    assert!(modes.apply(&path, &JSON::String("away".to_owned())).is_ok());
+    assert!(modes.apply(&path, &JSON::String("hg".to_owned())).is_err());
+    assert!(modes.apply(&path, &JSON::U64(1)).is_err());
+}
@@ -0,0 +1,154 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The latest value of each channel, as fetched by or reported to the `AdapterManager`. See
+//! `AdapterManager::fetch_latest`.
+//!
+//! Some values go stale quickly, e.g. "motion detected" is only true for a few seconds. Channels
+//! may declare how long their values remain current (see `Channel::valid_for`), after which the
+//! cached value is reported as expired rather than as the current truth.
+
+use api::Observation;
+use channel::Channel;
+use io::{Format, Payload};
+use parse::{JSON, ToJSON};
+use util::Id;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The latest value of a channel.
+///
+/// # JSON
+///
+/// An object `{"value": ..., "observed": Observation}` if the value is current, or
+/// `{"expired": true, "observed": Observation}` if it has gone stale.
+#[derive(Clone, Debug)]
+pub enum LatestValue {
+    Current {
+        value: Payload,
+        format: Arc<Format>,
+        observed: Observation,
+    },
+    /// The value was observed too long ago to be trusted, see `Channel::valid_for`.
+    Expired { observed: Observation },
+}
+
+impl ToJSON for LatestValue {
+    fn to_json(&self) -> JSON {
+        match *self {
+            LatestValue::Current { ref value, ref observed, .. } => {
+                vec![("value", value.to_json()), ("observed", observed.to_json())].to_json()
+            }
+            LatestValue::Expired { ref observed } => {
+                vec![("expired", JSON::Bool(true)), ("observed", observed.to_json())].to_json()
+            }
+        }
+    }
+}
+
+/// The latest value observed for each channel.
+#[derive(Default)]
+pub struct LatestValues {
+    values: HashMap<Id<Channel>, (Payload, Arc<Format>, Observation)>,
+}
+
+impl LatestValues {
+    pub fn new() -> Self {
+        LatestValues::default()
+    }
+
+    /// Remember a value, unless a more recent one is already known.
+    pub fn record(&mut self,
+                  id: &Id<Channel>,
+                  value: Payload,
+                  format: Arc<Format>,
+                  observed: Observation) {
+        if let Some(&(_, _, ref known)) = self.values.get(id) {
+            if known.timestamp > observed.timestamp {
+                return;
+            }
+        }
+        self.values.insert(id.clone(), (value, format, observed));
+    }
+
+    /// The latest value of `id` at time `now`, in milliseconds since the epoch, or `None` if
+    /// no value was observed.
+    pub fn get(&self,
+               id: &Id<Channel>,
+               valid_for: Option<Duration>,
+               now: u64)
+               -> Option<LatestValue> {
+        self.values.get(id).map(|&(ref value, ref format, observed)| {
+            let observed = observed.cached();
+            let is_expired = valid_for.map_or(false, |valid_for| {
+                let valid_for_ms = valid_for.as_secs() * 1000 +
+                                   (valid_for.subsec_nanos() / 1_000_000) as u64;
+                now > observed.timestamp + valid_for_ms
+            });
+            if is_expired {
+                LatestValue::Expired { observed: observed }
+            } else {
+                LatestValue::Current {
+                    value: value.clone(),
+                    format: format.clone(),
+                    observed: observed,
+                }
+            }
+        })
+    }
+
+    /// Forget a channel, e.g. once it has been removed.
+    pub fn forget(&mut self, id: &Id<Channel>) {
+        self.values.remove(id);
+    }
+}
+
+#[test]
+fn test_latest_values() {
+    use api::Source;
+    use values::{format, OnOff};
+
+    let id = Id::<Channel>::new("getter:motion@test");
+    let mut latest = LatestValues::new();
+    let valid_for = Some(Duration::from_secs(10));
+    assert!(latest.get(&id, valid_for, 0).is_none());
+
+    let payload = |on| {
+        Payload::from_data(if on { OnOff::On } else { OnOff::Off }, &format::ON_OFF).unwrap()
+    };
+    let observed = |timestamp| {
+        Observation {
+            timestamp: timestamp,
+            source: Source::Device,
+        }
+    };
+    latest.record(&id, payload(true), format::ON_OFF.clone(), observed(1000));
+    match latest.get(&id, valid_for, 11_000) {
+        Some(LatestValue::Current { value, observed, .. }) => {
+            assert_eq!(value.to_json(), payload(true).to_json());
+            assert_eq!(observed.timestamp, 1000);
+            assert_eq!(observed.source, Source::Cache);
+        }
+        other => panic!("Unexpected latest value {:?}", other),
+    }
+    match latest.get(&id, valid_for, 11_001) {
+        Some(LatestValue::Expired { observed }) => assert_eq!(observed.timestamp, 1000),
+        other => panic!("Unexpected latest value {:?}", other),
+    }
+    // Without a validity, values never expire.
+    assert!(match latest.get(&id, None, 1_000_000) {
+        Some(LatestValue::Current { .. }) => true,
+        _ => false,
+    });
+
+    // Values received out of order don't replace more recent ones.
+    latest.record(&id, payload(false), format::ON_OFF.clone(), observed(500));
+    assert_eq!(latest.get(&id, None, 0).unwrap().to_json().find("value"),
+               Some(&payload(true).to_json()));
+
+    latest.forget(&id);
+    assert!(latest.get(&id, None, 0).is_none());
+}
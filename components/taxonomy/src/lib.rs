@@ -41,6 +41,9 @@ pub mod parse;
 /// Selecting one or more devices. Exposed through the API.
 pub mod selector;
 
+/// Filtering watch events on the contents of their values.
+pub mod filter;
+
 /// Values that may be sent to/received from devices
 pub mod values;
 
@@ -50,6 +53,9 @@ pub mod util;
 /// The back-end thread, in charge of the heavy lifting of managing adapters.
 mod backend;
 
+/// Statistics on the calls to adapters.
+pub mod metrics;
+
 /// The manager provides an API for (un)registering adapters, services, channels, and
 /// uses these to implements the taxonomy API.
 pub mod manager;
@@ -60,6 +66,9 @@ pub mod adapter;
 /// Utilities for writing Adapters.
 pub mod adapter_utils;
 
+/// Running adapters in separate processes.
+pub mod adapter_proxy;
+
 /// Utility module for inserting values in maps and keeping the insertion reversible in case of
 /// any error.
 pub mod transact;
@@ -67,6 +76,27 @@ pub mod transact;
 /// Implementation of the database storing tags.
 pub mod tag_storage;
 
+/// The commands waiting for their device to be reachable.
+pub mod offline_queue;
+
+/// A bounded pool of threads, running the calls to adapters.
+pub mod thread_pool;
+
+/// The alerts raised by adapters for the user.
+pub mod alerts;
+
+/// Whether adapters are still responsive.
+pub mod health;
+
+/// How often each channel is used.
+pub mod usage;
+
+/// The values taken by channels over time.
+pub mod history;
+
+/// The latest value of each channel.
+pub mod latest;
+
 /// Implementation of a fake adapter, controlled entirely programmatically. Designed to be used
 /// as a component of tests.
 pub mod fake_adapter;
@@ -5,20 +5,32 @@
 //! - it exposes an implementation of the taxonomy API.
 
 pub use adapter::*;
+use alerts::{Alert, AlertEvent, AlertStore, Severity};
 use api;
-use api::{API, Error, TargetMap, User};
+use api::{API, Error, InternalError, TargetMap, Targetted, User, WatchOptions};
 use backend::*;
 use channel::Channel;
+use health::{AdapterHealth, HealthEvent, HealthMonitor};
+use history::{HistoryEntry, HistoryRange, ValueHistory};
 use io::*;
+use latest::{LatestValue, LatestValues};
+use metrics::{AdapterMetrics, PendingOperation};
+use offline_queue::{Command, OfflineQueue, SendStatus};
+use parse::ToJSON;
 use selector::*;
 use services::*;
+use thread_pool::{self, ThreadPool};
+use usage::{ChannelStats, ChannelUsage};
 use util::is_sync;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex, Weak};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use sublock::atomlock::*;
 use transformable_channels::mpsc::*;
@@ -37,6 +49,48 @@ pub struct AdapterManager {
     back_end: Arc<MainLock<State>>,
 
     tx_watch: Arc<Mutex<RawSender<WatchOp>>>,
+
+    /// The watches registered on behalf of remote clients, see `watch_values_leased`.
+    leases: Arc<Mutex<Leases>>,
+
+    /// Statistics on the calls to adapters.
+    metrics: Arc<AdapterMetrics>,
+
+    /// The values waiting for their device to be reachable, see `send_values_when_reachable`.
+    offline: Mutex<OfflineQueue>,
+
+    /// The alerts raised by adapters, see `raise_alert`.
+    alerts: Mutex<AlertStore>,
+
+    /// Notified whenever an alert is raised or dismissed, see `watch_alerts`.
+    alert_watchers: Mutex<Vec<Box<ExtSender<AlertEvent>>>>,
+
+    /// The statuses reported by adapters, see `report_adapter_status`.
+    adapter_statuses: Mutex<HashMap<Id<AdapterId>, AdapterStatus>>,
+
+    /// Whether adapters answer their heartbeats, see `adapter_health`.
+    health: Arc<Mutex<HealthMonitor>>,
+
+    /// The values taken by the channels that record their history, see `get_history`.
+    history: Arc<Mutex<ValueHistory>>,
+
+    /// The channels that record their history, see `follow_history`.
+    history_channels: Arc<Mutex<HashSet<Id<Channel>>>>,
+
+    tx_history: Mutex<RawSender<HistoryOp>>,
+
+    /// The latest value fetched from or reported by each channel, see `fetch_latest`.
+    latest: Arc<Mutex<LatestValues>>,
+
+    /// The threads running the calls to each adapter, created on its first call, see
+    /// `dispatch`.
+    pools: Mutex<HashMap<Id<AdapterId>, ThreadPool>>,
+
+    /// The number of threads and queued calls of each adapter, see `set_adapter_executors`.
+    pool_limits: Mutex<(usize, usize)>,
+
+    /// How long `dispatch` waits for the adapters, see `set_call_timeout`.
+    call_timeout: Mutex<Duration>,
 }
 
 impl AdapterManager {
@@ -46,13 +100,679 @@ impl AdapterManager {
         // The code should build only if AdapterManager implements Sync.
         is_sync::<AdapterManager>();
 
+        let offline = OfflineQueue::new(db_path.as_ref())
+            .or_else(|err| {
+                error!("Unable to open the queue of offline commands, keeping it in memory: {}",
+                       err);
+                OfflineQueue::new(None)
+            })
+            .unwrap();
+        let alerts = AlertStore::new(db_path.as_ref())
+            .or_else(|err| {
+                error!("Unable to open the alerts, keeping them in memory: {}", err);
+                AlertStore::new(None)
+            })
+            .unwrap();
+        let usage = ChannelUsage::new(db_path.as_ref())
+            .or_else(|err| {
+                error!("Unable to open the use of channels, counting it in memory: {}", err);
+                ChannelUsage::new(None)
+            })
+            .unwrap();
+        let history = ValueHistory::new(db_path.as_ref())
+            .or_else(|err| {
+                error!("Unable to open the history of channels, keeping it in memory: {}", err);
+                ValueHistory::new(None)
+            })
+            .unwrap();
+        let history = Arc::new(Mutex::new(history));
+        let history_channels = Arc::new(Mutex::new(HashSet::new()));
         let state = Arc::new(MainLock::new(|liveness| State::new(liveness, db_path)));
-        let tx_watch = Arc::new(Mutex::new(Self::handle_watches(Arc::downgrade(&state))));
+        let metrics = Arc::new(AdapterMetrics::with_usage(usage));
+        let tx_watch = Arc::new(Mutex::new(Self::handle_watches(Arc::downgrade(&state),
+                                                                metrics.clone())));
+        let leases = Arc::new(Mutex::new(Leases::default()));
+        Self::handle_leases(Arc::downgrade(&leases));
+        let health = Arc::new(Mutex::new(HealthMonitor::default()));
+        Self::handle_watchdog(Arc::downgrade(&state), Arc::downgrade(&health));
+        let tx_history = Self::handle_history(Arc::downgrade(&state),
+                                              tx_watch.clone(),
+                                              history.clone(),
+                                              history_channels.clone());
         AdapterManager {
             back_end: state,
             tx_watch: tx_watch,
+            leases: leases,
+            metrics: metrics,
+            offline: Mutex::new(offline),
+            alerts: Mutex::new(alerts),
+            alert_watchers: Mutex::new(vec![]),
+            adapter_statuses: Mutex::new(HashMap::new()),
+            health: health,
+            history: history,
+            history_channels: history_channels,
+            tx_history: Mutex::new(tx_history),
+            latest: Arc::new(Mutex::new(LatestValues::new())),
+            pools: Mutex::new(HashMap::new()),
+            pool_limits: Mutex::new((DISPATCH_THREADS, DISPATCH_QUEUE)),
+            call_timeout: Mutex::new(Duration::from_secs(DISPATCH_TIMEOUT_S)),
+        }
+    }
+
+    /// Statistics on the calls to adapters.
+    pub fn metrics(&self) -> Arc<AdapterMetrics> {
+        self.metrics.clone()
+    }
+
+    /// The calls to adapters in progress, longest first, e.g. to find out which device is
+    /// wedged in the middle of a command.
+    pub fn pending_operations(&self) -> Vec<PendingOperation> {
+        self.metrics.pending_operations()
+    }
+
+    /// Abort call `id` of `pending_operations`, as a recovery tool when a device never answers.
+    /// The caller gets `Error::OperationAborted` for each channel of the call, while the
+    /// adapter is left to finish in the background. Return `false` if there is no such call
+    /// or if it was already aborted.
+    pub fn abort_operation(&self, id: usize) -> bool {
+        let aborted = self.metrics.abort(id);
+        if aborted {
+            warn!(target: "Taxonomy-manager", "Aborted call {} to an adapter", id);
+        }
+        aborted
+    }
+
+    /// How often each channel was used, most used over the last 30 days first. Channels that
+    /// were never used are included, last.
+    pub fn channel_usage(&self) -> Vec<(Channel, ChannelStats)> {
+        let mut usage = self.metrics.channel_usage();
+        let mut channels: Vec<_> = self.get_channels(vec![ChannelSelector::new()])
+            .into_iter()
+            .map(|channel| {
+                let stats = usage.remove(&channel.id).unwrap_or_else(ChannelStats::default);
+                (channel, stats)
+            })
+            .collect();
+        channels.sort_by(|&(ref a, ref a_stats), &(ref b, ref b_stats)| {
+            (b_stats.last_30_days(), b_stats.last_used, a.id.to_string())
+                .cmp(&(a_stats.last_30_days(), a_stats.last_used, b.id.to_string()))
+        });
+        channels
+    }
+
+    /// The alerts that the user hasn't dismissed yet, most recent first.
+    pub fn alerts(&self) -> Vec<Alert> {
+        self.alerts.lock().unwrap().alerts().unwrap_or_else(|err| {
+            error!("Unable to read the alerts: {}", err);
+            vec![]
+        })
+    }
+
+    /// Remove an alert, once the user has read it. Returns `false` if there is no such alert.
+    pub fn dismiss_alert(&self, id: u64) -> bool {
+        let dismissed = self.alerts.lock().unwrap().dismiss(id).unwrap_or_else(|err| {
+            error!("Unable to dismiss alert {}: {}", id, err);
+            false
+        });
+        if dismissed {
+            self.notify_alert_watchers(AlertEvent::Dismissed(id));
+        }
+        dismissed
+    }
+
+    /// Be notified whenever an alert is raised or dismissed, for as long as `on_event`
+    /// accepts the events.
+    pub fn watch_alerts(&self, on_event: Box<ExtSender<AlertEvent>>) {
+        self.alert_watchers.lock().unwrap().push(on_event);
+    }
+
+    fn notify_alert_watchers(&self, event: AlertEvent) {
+        let mut watchers = self.alert_watchers.lock().unwrap();
+        let mut live = Vec::with_capacity(watchers.len());
+        for watcher in watchers.drain(..) {
+            if watcher.send(event.clone()).is_ok() {
+                live.push(watcher);
+            }
+        }
+        *watchers = live;
+    }
+
+    /// The latest status reported by each adapter that reported one.
+    pub fn adapter_statuses(&self) -> HashMap<Id<AdapterId>, AdapterStatus> {
+        self.adapter_statuses.lock().unwrap().clone()
+    }
+
+    /// Whether each adapter answered its latest heartbeats, see `Adapter::heartbeat`. The
+    /// adapters that were not checked yet are not included.
+    pub fn adapter_health(&self) -> HashMap<Id<AdapterId>, AdapterHealth> {
+        self.health.lock().unwrap().health()
+    }
+
+    /// Be notified whenever an adapter fails a heartbeat or recovers, for as long as
+    /// `on_event` accepts events.
+    pub fn watch_adapter_health(&self, on_event: Box<ExtSender<HealthEvent>>) {
+        self.health.lock().unwrap().watch(on_event);
+    }
+
+    /// Check the health of all the adapters now, rather than waiting for the watchdog.
+    pub fn check_adapter_health(&self) {
+        let adapters = self.back_end.read().unwrap().adapters();
+        HealthMonitor::check(&self.health,
+                             adapters,
+                             Duration::from_secs(HEARTBEAT_TIMEOUT_S));
+    }
+
+    /// Remove an adapter, then tell it to stop, e.g. before starting it again. Unlike `stop`,
+    /// this doesn't wait for the adapter to stop, as it may be stuck.
+    pub fn stop_adapter(&self, id: &Id<AdapterId>) -> Result<(), Error> {
+        let adapter = self.back_end
+            .read()
+            .unwrap()
+            .adapters()
+            .into_iter()
+            .find(|adapter| adapter.id() == *id);
+        try!(self.remove_adapter(id));
+        if let Some(adapter) = adapter {
+            thread::spawn(move || adapter.stop());
+        }
+        Ok(())
+    }
+
+    /// The values taken during `range` by the channels matching `selectors`, oldest first.
+    /// Only the channels that record their history (see `Channel::record_history`) are
+    /// included.
+    pub fn get_history(&self,
+                       selectors: Vec<ChannelSelector>,
+                       range: HistoryRange)
+                       -> ResultMap<Id<Channel>, Vec<HistoryEntry>, Error> {
+        let history = self.history.lock().unwrap();
+        self.get_channels(selectors)
+            .into_iter()
+            .filter(|channel| channel.record_history)
+            .map(|channel| {
+                let entries = history.get(&channel.id, &range).map_err(|err| {
+                    Error::Internal(InternalError::GenericError(format!("{}", err)))
+                });
+                (channel.id, entries)
+            })
+            .collect()
+    }
+
+    /// Start or stop recording the history of `channel`, as it is being added.
+    fn follow_history(&self, channel: &Channel) {
+        let tx = self.tx_history.lock().unwrap();
+        if channel.record_history {
+            let on_event = if channel.supports_watch.is_some() {
+                Some(Box::new(tx.internal_clone().map(HistoryOp::Event)) as Box<ExtSender<_>>)
+            } else {
+                None
+            };
+            // Record the values fetched from now on, without waiting for the history thread.
+            self.history_channels.lock().unwrap().insert(channel.id.clone());
+            let _ = tx.send(HistoryOp::Record(channel.id.clone(), on_event));
+        } else if self.history_channels.lock().unwrap().contains(&channel.id) {
+            let _ = tx.send(HistoryOp::Forget(channel.id.clone()));
+        }
+    }
+
+    /// Record the fetched values of the channels that record their history.
+    fn record_fetched(&self, results: &OpResult<(Payload, Arc<Format>)>) {
+        let timestamp = api::Observation::now(api::Source::Fetch).timestamp;
+        let channels = self.history_channels.lock().unwrap();
+        if channels.is_empty() {
+            return;
+        }
+        let mut history = self.history.lock().unwrap();
+        for (id, result) in results {
+            if let Ok(Some((ref payload, _))) = *result {
+                if !channels.contains(id) {
+                    continue;
+                }
+                if let Err(err) = history.record(id, timestamp, &payload.to_json()) {
+                    error!("Unable to record the history of {}: {}", id, err);
+                }
+            }
+        }
+    }
+
+    /// The latest value fetched from or reported by the channels matching `selectors`, without
+    /// calling the adapters. Values older than the validity of their channel (see
+    /// `Channel::valid_for`) are reported as expired. Channels whose value was never observed
+    /// are mapped to `None`.
+    pub fn fetch_latest(&self, selectors: Vec<ChannelSelector>) -> OpResult<LatestValue> {
+        let now = api::Observation::now(api::Source::Cache).timestamp;
+        let latest = self.latest.lock().unwrap();
+        self.get_channels(selectors)
+            .into_iter()
+            .map(|channel| {
+                let value = latest.get(&channel.id, channel.valid_for, now);
+                (channel.id, Ok(value))
+            })
+            .collect()
+    }
+
+    /// Remember the fetched values, see `fetch_latest`.
+    fn remember_fetched(&self, results: &OpResult<(Payload, Arc<Format>)>) {
+        let observed = api::Observation::now(api::Source::Fetch);
+        let mut latest = self.latest.lock().unwrap();
+        for (id, result) in results {
+            if let Ok(Some((ref payload, ref format))) = *result {
+                latest.record(id, payload.clone(), format.clone(), observed);
+            }
         }
     }
+
+    /// Set how to handle services that describe the same device as an existing service,
+    /// e.g. a device both discovered through UPnP and added manually. Only affects services
+    /// added afterwards, so this should be called before starting the adapters.
+    pub fn set_merge_policy(&self, policy: MergePolicy) {
+        self.back_end.write().unwrap().set_merge_policy(policy)
+    }
+
+    /// Set the number of threads calling each adapter, and the number of calls to an adapter
+    /// that may wait for one of them. Only affects adapters that haven't been called yet, so
+    /// this should be called before starting the adapters.
+    pub fn set_adapter_executors(&self, threads: usize, max_queued: usize) {
+        *self.pool_limits.lock().unwrap() = (threads, max_queued);
+    }
+
+    /// Set how long a call waits for the adapters. The channels of an adapter that hasn't
+    /// answered in time fail with an error, while the adapter keeps the call running.
+    pub fn set_call_timeout(&self, timeout: Duration) {
+        *self.call_timeout.lock().unwrap() = timeout;
+    }
+
+    /// The threads calling `adapter`.
+    fn pool_of(&self, adapter: &Id<AdapterId>) -> ThreadPool {
+        let mut pools = self.pools.lock().unwrap();
+        if let Some(pool) = pools.get(adapter) {
+            return pool.clone();
+        }
+        let (threads, max_queued) = *self.pool_limits.lock().unwrap();
+        let pool = ThreadPool::new(&format!("AdapterCall-{}", adapter), threads, max_queued);
+        pools.insert(adapter.clone(), pool.clone());
+        pool
+    }
+
+    /// The ids of the adapters currently registered.
+    pub fn adapter_ids(&self) -> Vec<Id<AdapterId>> {
+        self.back_end.read().unwrap().adapter_ids()
+    }
+
+    /// The revision of the taxonomy, bumped whenever adapters, services, channels, tags or
+    /// metadata may have changed. Two queries returning the same revision were evaluated
+    /// against the same taxonomy.
+    pub fn revision(&self) -> u64 {
+        self.back_end.read().unwrap().revision()
+    }
+
+    /// Like `get_services`, along with the revision of the taxonomy the selectors were
+    /// evaluated against.
+    pub fn get_services_at_revision(&self, selectors: Vec<ServiceSelector>) -> (Vec<Service>, u64) {
+        let back_end = self.back_end.read().unwrap();
+        (back_end.get_services(selectors), back_end.revision())
+    }
+
+    /// Like `get_channels`, along with the revision of the taxonomy the selectors were
+    /// evaluated against.
+    pub fn get_channels_at_revision(&self, selectors: Vec<ChannelSelector>) -> (Vec<Channel>, u64) {
+        let back_end = self.back_end.read().unwrap();
+        (back_end.get_channels(selectors), back_end.revision())
+    }
+
+    /// Dispatch a request to the adapters it involves, outside of the lock. Each adapter is
+    /// called on a worker of a pool of its own, so that a slow adapter (e.g. a Z-Wave device
+    /// waking up) neither delays nor starves the others, and so that an administrator may
+    /// abort a call that never returns, see `abort_operation`. The channels of an adapter
+    /// that can't be queued because its pool is saturated, or that doesn't answer before the
+    /// call timeout, fail with an error.
+    fn dispatch<T, R, F>(&self,
+                         mut request: AdapterRequest<HashMap<Id<Channel>, T>>,
+                         operation: api::Operation,
+                         user: User,
+                         call: F)
+                         -> ResultMap<Id<Channel>, R, Error>
+        where T: Send + 'static,
+              R: Send + 'static,
+              F: Fn(&Arc<RawAdapter>, HashMap<Id<Channel>, T>) -> ResultMap<Id<Channel>, R, Error>,
+              F: Send + Sync + 'static
+    {
+        let call = Arc::new(call);
+        let timeout = *self.call_timeout.lock().unwrap();
+        let start = Instant::now();
+        let (tx, rx) = mpsc::channel();
+        let mut waiting = HashMap::new();
+        let mut results = HashMap::new();
+        for (index, (_, (adapter, channels))) in request.drain().enumerate() {
+            let ids: Vec<_> = channels.keys().cloned().collect();
+            let pool = self.pool_of(&adapter.id());
+            let metrics = self.metrics.clone();
+            let call = call.clone();
+            let operation = operation.clone();
+            let user = user.clone();
+            let tx = tx.clone();
+            waiting.insert(index, ids.clone());
+            let job = move || {
+                let start = Instant::now();
+                let on_abort = tx.clone();
+                let on_abort = Box::new(move || {
+                    let _ = on_abort.send((index, None));
+                });
+                let got = {
+                    let _pending = metrics.begin_abortable(&adapter.id(),
+                                                           operation.clone(),
+                                                           &ids,
+                                                           &user,
+                                                           on_abort);
+                    panic::catch_unwind(AssertUnwindSafe(|| call(&adapter, channels)))
+                };
+                let got = match got {
+                    Ok(got) => got,
+                    Err(_) => {
+                        // The adapter panicked, report an error for each of its channels.
+                        let err = InternalError::GenericError("Adapter panicked".to_owned());
+                        ids.iter()
+                            .map(|id| (id.clone(), Err(Error::Internal(err.clone()))))
+                            .collect()
+                    }
+                };
+                metrics.record(&adapter.id(),
+                               operation,
+                               &ids,
+                               start.elapsed(),
+                               got.values().filter(|result| result.is_err()).count());
+                // If the call was aborted, nobody listens anymore.
+                let _ = tx.send((index, Some(got)));
+            };
+            if thread_pool::is_worker_thread() {
+                // An adapter calling back the manager from a worker. Waiting for another worker
+                // could deadlock once they are all busy, so make the call right away.
+                job();
+            } else if pool.execute(job).is_err() {
+                let ids = waiting.remove(&index).unwrap_or_else(Vec::new);
+                let err = InternalError::GenericError("Too many calls to adapters in progress"
+                    .to_owned());
+                results.extend(ids.into_iter().map(|id| (id, Err(Error::Internal(err.clone())))));
+            }
+        }
+        drop(tx);
+
+        while !waiting.is_empty() {
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                break;
+            }
+            let (index, got) = match rx.recv_timeout(timeout - elapsed) {
+                Ok(msg) => msg,
+                Err(_) => break,
+            };
+            let ids = match waiting.remove(&index) {
+                Some(ids) => ids,
+                None => continue, // Aborted, then completed, or the other way around.
+            };
+            match got {
+                Some(got) => results.extend(got),
+                None => {
+                    results.extend(ids.into_iter().map(|id| (id, Err(Error::OperationAborted))));
+                }
+            }
+        }
+        // The adapters that didn't answer in time. Their calls keep running on their own pool.
+        let err = InternalError::GenericError("Call to adapter timed out".to_owned());
+        for (_, ids) in waiting {
+            results.extend(ids.into_iter().map(|id| (id, Err(Error::Internal(err.clone())))));
+        }
+        results
+    }
+
+    /// Enforce the constraints declared by the adapters on prepared values, so that they don't
+    /// need to. The values that are rejected are removed from `prepared` and returned.
+    fn constrain_prepared(prepared: &mut SendRequest) -> HashMap<Id<Channel>, Error> {
+        let mut failures = HashMap::new();
+        for (_, &mut (_, ref mut request)) in prepared {
+            let mut rejected = vec![];
+            for (id, &mut (ref mut payload, ref format)) in request.iter_mut() {
+                match format.constrain(payload) {
+                    Ok(constrained) => *payload = constrained,
+                    Err(err) => rejected.push((id.clone(), err)),
+                }
+            }
+            for (id, err) in rejected {
+                request.remove(&id);
+                failures.insert(id, err);
+            }
+        }
+        failures
+    }
+
+    /// Send prepared values, enforcing the constraints declared by the adapters.
+    fn send_prepared(&self,
+                     mut prepared: SendRequest,
+                     user: User)
+                     -> ResultMap<Id<Channel>, (), Error> {
+        let failures = Self::constrain_prepared(&mut prepared);
+
+        // Dispatch to adapter
+        let mut results = self.dispatch(prepared,
+                                         api::Operation::Send,
+                                         user.clone(),
+                                         move |adapter, request| {
+                                             adapter.send_values(request, user.clone())
+                                         });
+        results.extend(failures.into_iter().map(|(id, err)| (id, Err(err))));
+        self.note_reachable(&results);
+        results
+    }
+
+    /// Like `send_values`, but only tell which payload each channel would receive, once the
+    /// constraints declared by the adapters are enforced, without sending anything. Useful to
+    /// check a send to many channels, e.g. to a tag, before performing it.
+    pub fn preview_send_values(&self,
+                               keyvalues: TargetMap<ChannelSelector, Payload>)
+                               -> ResultMap<Id<Channel>, Payload, Error> {
+        let mut prepared;
+        {
+            // Make sure that the lock is released asap.
+            prepared = self.back_end.read().unwrap().prepare_send_values(keyvalues);
+        }
+        let mut results: ResultMap<Id<Channel>, Payload, Error> =
+            Self::constrain_prepared(&mut prepared)
+                .into_iter()
+                .map(|(id, err)| (id, Err(err)))
+                .collect();
+        for (_, (_, request)) in prepared {
+            for (id, (payload, format)) in request {
+                // Check that the adapter would be able to read the payload.
+                let result = payload.to_value(&format).map(|_| payload);
+                results.insert(id, result);
+            }
+        }
+        results
+    }
+
+    /// Like `send_values`, but all or nothing, e.g. to activate a scene, where applying only
+    /// some of the values is worse than applying none.
+    ///
+    /// If a value is rejected by the constraints of its channel, nothing is sent. If a value
+    /// cannot be sent, the channels that received theirs are reverted to the value they had
+    /// before the call. Channels whose value cannot be fetched cannot be reverted, and report
+    /// `Ok` if their value was applied. The other values report `Error::TransactionAborted`.
+    pub fn send_values_atomic(&self,
+                              keyvalues: TargetMap<ChannelSelector, Payload>,
+                              user: User)
+                              -> ResultMap<Id<Channel>, (), Error> {
+        let mut prepared;
+        {
+            // Make sure that the lock is released asap.
+            prepared = self.back_end.read().unwrap().prepare_send_values(keyvalues);
+        }
+        let failures = Self::constrain_prepared(&mut prepared);
+        let ids: Vec<_> = prepared.values()
+            .flat_map(|&(_, ref request)| request.keys().cloned())
+            .collect();
+        if !failures.is_empty() {
+            let mut results: ResultMap<Id<Channel>, (), Error> = ids.into_iter()
+                .map(|id| (id, Err(Error::TransactionAborted)))
+                .collect();
+            results.extend(failures.into_iter().map(|(id, err)| (id, Err(err))));
+            return results;
+        }
+
+        // Remember the current values, to revert to them if needed.
+        let selectors = ids.iter().map(|id| ChannelSelector::new().with_id(id)).collect();
+        let previous: HashMap<_, _> = self.fetch_values(selectors, user.clone())
+            .into_iter()
+            .filter_map(|(id, result)| match result {
+                Ok(Some((payload, _))) => Some((id, payload)),
+                _ => None,
+            })
+            .collect();
+
+        let mut results = self.send_prepared(prepared.clone(), user.clone());
+        if results.values().all(Result::is_ok) {
+            return results;
+        }
+
+        // Revert the channels that received their value.
+        let mut revert = prepared;
+        for (_, &mut (_, ref mut request)) in &mut revert {
+            let ids: Vec<_> = request.keys().cloned().collect();
+            for id in ids {
+                let applied = match results.get(&id) {
+                    Some(&Ok(())) => true,
+                    _ => false,
+                };
+                match previous.get(&id) {
+                    Some(payload) if applied => {
+                        if let Some(&mut (ref mut value, _)) = request.get_mut(&id) {
+                            *value = payload.clone();
+                        }
+                    }
+                    _ => {
+                        request.remove(&id);
+                    }
+                }
+            }
+        }
+        debug!(target: "Taxonomy-manager",
+               "manager.send_values_atomic => reverting {} channels",
+               revert.values().map(|&(_, ref request)| request.len()).sum::<usize>());
+        let reverted = self.dispatch(revert,
+                                     api::Operation::Send,
+                                     user.clone(),
+                                     move |adapter, request| {
+                                         adapter.send_values(request, user.clone())
+                                     });
+        for (id, result) in reverted {
+            match result {
+                Ok(()) => {
+                    results.insert(id, Err(Error::TransactionAborted));
+                }
+                Err(err) => warn!("Unable to revert {}: {}", id, err),
+            }
+        }
+        results
+    }
+
+    /// Like `send_values`, but the values sent to devices that cannot be reached right now
+    /// (see `Error::Unreachable`) are queued, and sent once the device is reachable again,
+    /// unless `ttl` expires first. Useful for battery-powered devices, which wake up rarely.
+    ///
+    /// The queue is persisted in the taxonomy database. Only the latest value queued for a
+    /// channel is kept.
+    pub fn send_values_when_reachable(&self,
+                                      keyvalues: TargetMap<ChannelSelector, Payload>,
+                                      user: User,
+                                      ttl: Duration)
+                                      -> ResultMap<Id<Channel>, SendStatus, Error> {
+        let prepared;
+        {
+            // Make sure that the lock is released asap.
+            prepared = self.back_end.read().unwrap().prepare_send_values(keyvalues);
+        }
+        let mut payloads = HashMap::new();
+        for &(_, ref request) in prepared.values() {
+            for (id, &(ref payload, _)) in request {
+                payloads.insert(id.clone(), payload.clone());
+            }
+        }
+
+        let results = self.send_prepared(prepared, user.clone());
+        results.into_iter()
+            .map(|(id, result)| {
+                let result = match result {
+                    Ok(()) => Ok(SendStatus::Delivered),
+                    Err(Error::Unreachable(_)) if payloads.contains_key(&id) => {
+                        let command = Command {
+                            channel: id.clone(),
+                            payload: payloads[&id].clone(),
+                            user: user.clone(),
+                            ttl: ttl,
+                        };
+                        self.queue_command(command).map(|_| SendStatus::Queued)
+                    }
+                    Err(err) => Err(err),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn queue_command(&self, command: Command) -> Result<(), Error> {
+        let service = match self.get_channels(vec![ChannelSelector::new()
+                                 .with_id(&command.channel)])
+            .pop() {
+            Some(channel) => channel.service,
+            None => {
+                return Err(Error::Internal(InternalError::NoSuchChannel(command.channel)));
+            }
+        };
+        if command.ttl.as_secs() == 0 {
+            return Err(Error::Unreachable(command.channel));
+        }
+        debug!(target: "Taxonomy-manager",
+               "Queueing a value for {} until it is reachable",
+               command.channel);
+        self.offline
+            .lock()
+            .unwrap()
+            .push(&service, &command)
+            .map_err(|err| {
+                let err = format!("Unable to queue a value for {}: {}", command.channel, err);
+                Error::Internal(InternalError::GenericError(err))
+            })
+    }
+
+    /// A successful call to a channel means that its device is reachable, deliver the values
+    /// queued for it, if any.
+    fn note_reachable<T>(&self, results: &ResultMap<Id<Channel>, T, Error>) {
+        if self.offline.lock().unwrap().is_empty() {
+            return;
+        }
+        let selectors: Vec<_> = results.iter()
+            .filter(|&(_, result)| result.is_ok())
+            .map(|(id, _)| ChannelSelector::new().with_id(id))
+            .collect();
+        if selectors.is_empty() {
+            return;
+        }
+        let services: HashSet<_> = self.get_channels(selectors)
+            .drain(..)
+            .map(|channel| channel.service)
+            .collect();
+        for service in services {
+            self.service_reachable(&service);
+        }
+    }
+
+    /// Change the taxonomy under the write lock, bumping its revision.
+    fn change<F, T>(&self, change: F) -> T
+        where F: FnOnce(&mut State) -> T
+    {
+        let mut back_end = self.back_end.write().unwrap();
+        back_end.bump_revision();
+        change(&mut *back_end)
+    }
 }
 
 impl Default for AdapterManager {
@@ -68,7 +788,7 @@ impl AdapterManagerHandle for AdapterManager {
     ///
     /// Returns an error if an adapter with the same id is already present.
     fn add_adapter(&self, adapter: Arc<Adapter>) -> Result<(), Error> {
-        self.back_end.write().unwrap().add_adapter(adapter)
+        self.change(|back_end| back_end.add_adapter(adapter))
     }
 
     /// Remove an adapter from the system, including all its services and channels.
@@ -79,7 +799,7 @@ impl AdapterManagerHandle for AdapterManager {
     /// to cleanup as much as possible, even if for some reason the system is in an
     /// inconsistent state.
     fn remove_adapter(&self, id: &Id<AdapterId>) -> Result<(), Error> {
-        self.back_end.write().unwrap().remove_adapter(id)
+        self.change(|back_end| back_end.remove_adapter(id))
     }
 
     /// Add a service to the system. Called by the adapter when a new
@@ -99,7 +819,7 @@ impl AdapterManagerHandle for AdapterManager {
     /// - a service with id `service.id` is already installed on the system;
     /// - there is no adapter with id `service.adapter`.
     fn add_service(&self, service: Service) -> Result<(), Error> {
-        self.back_end.write().unwrap().add_service(service)
+        self.change(|back_end| back_end.add_service(service))
     }
 
     /// Remove a service previously registered on the system. Typically, called by
@@ -112,7 +832,19 @@ impl AdapterManagerHandle for AdapterManager {
     /// - there is an internal inconsistency, in which case this method will still attempt to
     /// cleanup before returning an error.
     fn remove_service(&self, id: &Id<ServiceId>) -> Result<(), Error> {
-        self.back_end.write().unwrap().remove_service(id)
+        self.change(|back_end| back_end.remove_service(id))
+    }
+
+    /// Update some of the properties of a service previously registered on the system.
+    ///
+    /// # Error
+    ///
+    /// Returns an error if there is no such service.
+    fn update_service_properties(&self,
+                                 id: &Id<ServiceId>,
+                                 properties: HashMap<String, String>)
+                                 -> Result<(), Error> {
+        self.change(|back_end| back_end.update_service_properties(id, properties))
     }
 
     /// Add a setter to the system. Typically, this is called by the adapter when a new
@@ -129,14 +861,35 @@ impl AdapterManagerHandle for AdapterManager {
     /// registered, or a channel with the same identifier is already registered.
     /// In either cases, this method reverts all its changes.
     fn add_channel(&self, getter: Channel) -> Result<(), Error> {
+        let channel = getter.clone();
         let request = {
             // Acquire and release lock asap.
-            try!(self.back_end.write().unwrap().add_channel(getter))
+            try!(self.change(|back_end| back_end.add_channel(getter)))
         };
         if !request.is_empty() {
             debug!(target: "Taxonomy-manager", "manager.add_channel => need to register watches");
         }
         self.register_watches(request);
+        self.follow_history(&channel);
+        Ok(())
+    }
+
+    /// Add a service along with its channels, atomically: either all of them are added, or
+    /// none of them.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error that `add_service` or `add_channel` would have returned.
+    fn add_service_with_channels(&self, builder: ServiceBuilder) -> Result<(), Error> {
+        let channels = builder.channels.clone();
+        let request = {
+            // Acquire and release lock asap.
+            try!(self.change(|back_end| back_end.add_service_with_channels(builder)))
+        };
+        self.register_watches(request);
+        for channel in &channels {
+            self.follow_history(channel);
+        }
         Ok(())
     }
 
@@ -149,7 +902,67 @@ impl AdapterManagerHandle for AdapterManager {
     /// is not registered. In either case, it attemps to clean as much as possible, even
     /// if the state is inconsistent.
     fn remove_channel(&self, id: &Id<Channel>) -> Result<(), Error> {
-        self.back_end.write().unwrap().remove_channel(id)
+        try!(self.change(|back_end| back_end.remove_channel(id)));
+        let _ = self.tx_history.lock().unwrap().send(HistoryOp::Forget(id.clone()));
+        self.latest.lock().unwrap().forget(id);
+        Ok(())
+    }
+
+    /// Deliver the values queued while the device of a service couldn't be reached, see
+    /// `send_values_when_reachable`. Values that fail again with `Error::Unreachable` are
+    /// queued again, with whatever remains of their time-to-live.
+    fn service_reachable(&self, id: &Id<ServiceId>) {
+        let commands = {
+            let mut offline = self.offline.lock().unwrap();
+            if !offline.has_pending(id) {
+                return;
+            }
+            match offline.take(id) {
+                Ok(commands) => commands,
+                Err(err) => {
+                    error!("Unable to read the values queued for {}: {}", id, err);
+                    return;
+                }
+            }
+        };
+        for Command { channel, payload, user, ttl } in commands {
+            debug!(target: "Taxonomy-manager", "Delivering the value queued for {}", channel);
+            let target = vec![Targetted {
+                                  select: vec![ChannelSelector::new().with_id(&channel)],
+                                  payload: payload,
+                              }];
+            for (id, result) in self.send_values_when_reachable(target, user, ttl) {
+                if let Err(err) = result {
+                    warn!("Unable to deliver the value queued for {}: {}", id, err);
+                }
+            }
+        }
+    }
+
+    /// Tell the user about a condition that needs their attention, see `alerts` and
+    /// `watch_alerts`.
+    fn raise_alert(&self, severity: Severity, message: &str, action_hint: Option<&str>) {
+        let raised = self.alerts.lock().unwrap().raise(severity, message, action_hint);
+        match raised {
+            Ok(Some(alert)) => {
+                info!(target: "Taxonomy-manager", "Alert raised: {}", message);
+                self.notify_alert_watchers(AlertEvent::Raised(alert));
+            }
+            Ok(None) => {}
+            Err(err) => error!("Unable to raise alert {:?}: {}", message, err),
+        }
+    }
+
+    /// Record how an adapter is doing, see `adapter_statuses`.
+    fn report_adapter_status(&self, id: &Id<AdapterId>, status: AdapterStatus) {
+        match status {
+            AdapterStatus::Running => {}
+            AdapterStatus::Degraded(ref reason) |
+            AdapterStatus::Failed(ref reason) => {
+                warn!(target: "Taxonomy-manager", "Adapter {} reported: {}", id, reason)
+            }
+        }
+        self.adapter_statuses.lock().unwrap().insert(id.clone(), status);
     }
 }
 
@@ -178,7 +991,7 @@ impl API for AdapterManager {
     /// Note that this call is _not live_. In other words, if services
     /// are added after the call, they will not be affected.
     fn add_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize {
-        self.back_end.write().unwrap().add_service_tags(selectors, tags)
+        self.change(|back_end| back_end.add_service_tags(selectors, tags))
         // FIXME: This can cause watcher registrations
     }
 
@@ -196,7 +1009,15 @@ impl API for AdapterManager {
     /// Note that this call is _not live_. In okther words, if services
     /// are added after the call, they will not be affected.
     fn remove_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize {
-        self.back_end.write().unwrap().remove_service_tags(selectors, tags)
+        self.change(|back_end| back_end.remove_service_tags(selectors, tags))
+    }
+
+    /// Replace the metadata (friendly name, room, icon) of a set of services.
+    fn set_service_metadata(&self,
+                            selectors: Vec<ServiceSelector>,
+                            metadata: ServiceMetadata)
+                            -> usize {
+        self.change(|back_end| back_end.set_service_metadata(selectors, metadata))
     }
 
     /// Get a list of channels matching some conditions
@@ -220,7 +1041,7 @@ impl API for AdapterManager {
     fn add_channel_tags(&self, selectors: Vec<ChannelSelector>, tags: Vec<Id<TagId>>) -> usize {
         let (request, result) = {
             // Acquire and release the write lock.
-            self.back_end.write().unwrap().add_channel_tags(selectors, tags)
+            self.change(|back_end| back_end.add_channel_tags(selectors, tags))
         };
         if !request.is_empty() {
             debug!(target: "Taxonomy-manager", "manager.add_getter_tags => need to register watches");
@@ -243,7 +1064,7 @@ impl API for AdapterManager {
     /// Note that this call is _not live_. In other words, if channels
     /// are added after the call, they will not be affected.
     fn remove_channel_tags(&self, selectors: Vec<ChannelSelector>, tags: Vec<Id<TagId>>) -> usize {
-        self.back_end.write().unwrap().remove_channel_tags(selectors, tags)
+        self.change(|back_end| back_end.remove_channel_tags(selectors, tags))
     }
 
     /// Read the latest value from a set of channels
@@ -252,19 +1073,21 @@ impl API for AdapterManager {
                     user: User)
                     -> OpResult<(Payload, Arc<Format>)> {
         // First, prepare the request.
-        let mut request;
+        let request;
         {
             // Make sure that the lock is released asap.
             request = self.back_end.read().unwrap().prepare_fetch_values(selectors);
         }
         // Now fetch the values
-        let mut results = HashMap::new();
-        for (_, (adapter, mut channels)) in request.drain() {
-            let channels = channels.drain().collect();
-            let got = adapter.fetch_values(channels, user.clone());
-
-            results.extend(got);
-        }
+        let results = self.dispatch(request,
+                                    api::Operation::Fetch,
+                                    user.clone(),
+                                    move |adapter, channels| {
+                                        adapter.fetch_values(channels, user.clone())
+                                    });
+        self.note_reachable(&results);
+        self.record_fetched(&results);
+        self.remember_fetched(&results);
         results
     }
 
@@ -274,42 +1097,52 @@ impl API for AdapterManager {
                    user: User)
                    -> ResultMap<Id<Channel>, (), Error> {
         // First, prepare the request.
-        let mut prepared;
+        let prepared;
         {
             // Make sure that the lock is released asap.
             prepared = self.back_end.read().unwrap().prepare_send_values(keyvalues);
         }
-
-        // Dispatch to adapter
-        let mut results = HashMap::new();
-        for (_, (adapter, request)) in prepared.drain() {
-            let got = adapter.send_values(request, user.clone());
-            results.extend(got);
-        }
-
-        results
+        self.send_prepared(prepared, user)
     }
 
     /// Watch for any change
     fn watch_values(&self,
-                    watch: TargetMap<ChannelSelector, Exactly<Payload>>,
-                    on_event: Box<ExtSender<api::WatchEvent>>)
+                    mut watch: TargetMap<ChannelSelector, Exactly<Payload>>,
+                    on_event: Box<ExtSender<api::WatchEvent>>,
+                    user: User)
                     -> Self::WatchGuard {
-        let (request, watch_key, is_dropped) = {
-            // Acquire and release write lock.
-            self.back_end
-                .write()
-                .unwrap()
-                .prepare_channel_watch(watch, on_event)
-        };
+        let watch = watch.drain(..)
+            .map(|Targetted { select, payload }| {
+                Targetted {
+                    select: select,
+                    payload: (payload, None, Delivery::Full, None),
+                }
+            })
+            .collect();
+        self.watch_values_filtered(watch, on_event, user)
+    }
 
-        if !request.is_empty() {
-            debug!(target: "Taxonomy-manager", "manager.watch_values => need to register watches");
-        }
-        self.register_watches(request);
-        WatchGuard::new(self.tx_watch.lock().unwrap().internal_clone(),
-                        watch_key,
-                        is_dropped)
+    /// Watch for any change accepted by a filter
+    fn watch_values_filtered(&self,
+                             watch: TargetMap<ChannelSelector, WatchOptions>,
+                             on_event: Box<ExtSender<api::WatchEvent>>,
+                             user: User)
+                             -> Self::WatchGuard {
+        // Remember the values reported to watchers, see `fetch_latest`.
+        let latest = self.latest.clone();
+        let on_event = on_event.map(move |event| {
+            match event {
+                api::WatchEvent::EnterRange { ref channel, ref value, ref format, ref observed } |
+                api::WatchEvent::ExitRange { ref channel, ref value, ref format, ref observed } => {
+                    latest.lock()
+                        .unwrap()
+                        .record(channel, value.clone(), format.clone(), *observed)
+                }
+                _ => {}
+            }
+            event
+        });
+        Self::watch_with(&self.back_end, &self.tx_watch, watch, Box::new(on_event), user)
     }
 
     /// A value that causes a disconnection once it is dropped.
@@ -333,15 +1166,44 @@ impl AdapterManager {
     /// Register watches on the dedicated background thread. This must be done outside of any
     /// lock!
     fn register_watches(&self, request: WatchRequest) {
+        Self::register_watches_with(&self.tx_watch, request)
+    }
+
+    fn register_watches_with(tx_watch: &Mutex<RawSender<WatchOp>>, request: WatchRequest) {
         if !request.is_empty() {
             let (tx, rx) = channel();
-            let _ = self.tx_watch.lock().unwrap().send(WatchOp::Start(request, tx));
+            let _ = tx_watch.lock().unwrap().send(WatchOp::Start(request, tx));
             let _ = rx.recv();
         }
     }
 
+    /// The implementation of `watch_values_filtered`, also used by the history thread.
+    fn watch_with(back_end: &MainLock<State>,
+                  tx_watch: &Mutex<RawSender<WatchOp>>,
+                  watch: TargetMap<ChannelSelector, WatchOptions>,
+                  on_event: Box<ExtSender<api::WatchEvent>>,
+                  user: User)
+                  -> WatchGuard {
+        let (request, watch_key, is_dropped) = {
+            // Acquire and release write lock.
+            back_end.write()
+                .unwrap()
+                .prepare_channel_watch(watch, on_event, user)
+        };
+
+        if !request.is_empty() {
+            debug!(target: "Taxonomy-manager", "manager.watch_values => need to register watches");
+        }
+        Self::register_watches_with(tx_watch, request);
+        WatchGuard::new(tx_watch.lock().unwrap().internal_clone(),
+                        watch_key,
+                        is_dropped)
+    }
+
     /// Start the background thread .
-    fn handle_watches(state: Weak<MainLock<State>>) -> RawSender<WatchOp> {
+    fn handle_watches(state: Weak<MainLock<State>>,
+                      metrics: Arc<AdapterMetrics>)
+                      -> RawSender<WatchOp> {
         let (tx, rx) = channel();
         let state = state.clone();
         thread::spawn(move || {
@@ -351,7 +1213,7 @@ impl AdapterManager {
                     Some(backend) => {
                         match msg {
                             WatchOp::Start(request, tx) => {
-                                let add = State::start_watch(request);
+                                let add = State::start_watch(request, &metrics);
                                 backend.write().unwrap().register_ongoing_watch(add);
                                 let _ = tx.send(());
                             }
@@ -401,6 +1263,222 @@ impl Drop for WatchGuard {
 }
 
 
+/// Operations of the history thread, see `AdapterManager::handle_history`.
+enum HistoryOp {
+    /// Start recording the history of a channel, watching it with the given sender if it
+    /// supports watching.
+    Record(Id<Channel>, Option<Box<ExtSender<api::WatchEvent>>>),
+
+    /// Stop recording the history of a channel.
+    Forget(Id<Channel>),
+
+    /// A value reported by a channel that records its history.
+    Event(api::WatchEvent),
+}
+
+impl AdapterManager {
+    /// Start the background thread recording the values reported by the channels that record
+    /// their history. Watches are registered from this thread, as adapters add their channels
+    /// from their own threads, possibly holding locks that `register_watch` needs.
+    fn handle_history(state: Weak<MainLock<State>>,
+                      tx_watch: Arc<Mutex<RawSender<WatchOp>>>,
+                      history: Arc<Mutex<ValueHistory>>,
+                      channels: Arc<Mutex<HashSet<Id<Channel>>>>)
+                      -> RawSender<HistoryOp> {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut guards = HashMap::new();
+            for op in rx {
+                match op {
+                    HistoryOp::Record(id, on_event) => {
+                        channels.lock().unwrap().insert(id.clone());
+                        guards.remove(&id);
+                        if let Some(on_event) = on_event {
+                            let back_end = match state.upgrade() {
+                                None => return, // The manager has been dropped.
+                                Some(back_end) => back_end,
+                            };
+                            let watch = vec![Targetted {
+                                                 select: vec![ChannelSelector::new().with_id(&id)],
+                                                 payload: (Exactly::Always,
+                                                           None,
+                                                           Delivery::Full,
+                                                           None),
+                                             }];
+                            let guard =
+                                Self::watch_with(&back_end, &tx_watch, watch, on_event, User::None);
+                            guards.insert(id, guard);
+                        }
+                    }
+                    HistoryOp::Forget(id) |
+                    HistoryOp::Event(api::WatchEvent::ChannelRemoved(id)) => {
+                        channels.lock().unwrap().remove(&id);
+                        guards.remove(&id);
+                    }
+                    HistoryOp::Event(api::WatchEvent::EnterRange { channel,
+                                                                   value,
+                                                                   observed,
+                                                                   .. }) => {
+                        if !channels.lock().unwrap().contains(&channel) {
+                            continue;
+                        }
+                        let result = history.lock()
+                            .unwrap()
+                            .record(&channel, observed.timestamp, &value.to_json());
+                        if let Err(err) = result {
+                            error!("Unable to record the history of {}: {}", channel, err);
+                        }
+                    }
+                    HistoryOp::Event(_) => {}
+                }
+            }
+        });
+        tx
+    }
+}
+
+/// The threads calling each adapter, see `set_adapter_executors`.
+const DISPATCH_THREADS: usize = 4;
+
+/// The calls to an adapter waiting for a thread, beyond which calls fail.
+const DISPATCH_QUEUE: usize = 100;
+
+/// How long a call waits for the adapters, see `set_call_timeout`.
+const DISPATCH_TIMEOUT_S: u64 = 60;
+
+/// How often expired leases are reaped.
+const LEASE_REAP_INTERVAL_S: u64 = 5;
+
+/// How often the watchdog checks the health of the adapters.
+const WATCHDOG_INTERVAL_S: u64 = 60;
+
+/// How long an adapter may take to answer a heartbeat.
+const HEARTBEAT_TIMEOUT_S: u64 = 10;
+
+/// Identifies a watch registered with `watch_values_leased`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LeaseId(usize);
+
+impl LeaseId {
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+struct Lease {
+    /// Dropping the guard releases the watch.
+    _guard: WatchGuard,
+    ttl: Duration,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Leases {
+    counter: usize,
+    leases: HashMap<LeaseId, Lease>,
+}
+
+impl Leases {
+    /// Remove the leases that have expired at `now`, returning their guards.
+    fn reap(&mut self, now: Instant) -> Vec<Lease> {
+        let expired: Vec<_> = self.leases
+            .iter()
+            .filter(|&(_, lease)| lease.expires <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.iter().filter_map(|id| self.leases.remove(id)).collect()
+    }
+}
+
+impl AdapterManager {
+    /// Watch for changes, on behalf of a remote client that may disappear without notice,
+    /// e.g. when a websocket connection crashes.
+    ///
+    /// The watch is released unless it is renewed with `renew_lease` at least every `ttl`.
+    /// As for any other watch, releasing it lets the adapters release the corresponding
+    /// subscriptions on the devices.
+    pub fn watch_values_leased(&self,
+                               watch: TargetMap<ChannelSelector, WatchOptions>,
+                               on_event: Box<ExtSender<api::WatchEvent>>,
+                               ttl: Duration,
+                               user: User)
+                               -> LeaseId {
+        let guard = self.watch_values_filtered(watch, on_event, user);
+        let mut leases = self.leases.lock().unwrap();
+        leases.counter += 1;
+        let id = LeaseId(leases.counter);
+        leases.leases.insert(id,
+                             Lease {
+                                 _guard: guard,
+                                 ttl: ttl,
+                                 expires: Instant::now() + ttl,
+                             });
+        id
+    }
+
+    /// Extend a lease by its `ttl`. Returns `false` if the lease has already expired or been
+    /// released.
+    pub fn renew_lease(&self, id: LeaseId) -> bool {
+        match self.leases.lock().unwrap().leases.get_mut(&id) {
+            None => false,
+            Some(lease) => {
+                lease.expires = Instant::now() + lease.ttl;
+                true
+            }
+        }
+    }
+
+    /// Release a watch before its lease expires. Returns `false` if the lease has already
+    /// expired or been released.
+    pub fn release_lease(&self, id: LeaseId) -> bool {
+        let lease = self.leases.lock().unwrap().leases.remove(&id);
+        lease.is_some()
+    }
+
+    /// Release the watches whose lease has expired, returning how many there were. This is
+    /// called regularly by a background thread.
+    pub fn reap_leases(&self) -> usize {
+        let expired = self.leases.lock().unwrap().reap(Instant::now());
+        expired.len()
+    }
+
+    /// Start the background thread reaping expired leases.
+    fn handle_leases(leases: Weak<Mutex<Leases>>) {
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(LEASE_REAP_INTERVAL_S));
+                let strong = match leases.upgrade() {
+                    None => return, // The manager has been dropped.
+                    Some(strong) => strong,
+                };
+                let expired = strong.lock().unwrap().reap(Instant::now());
+                if !expired.is_empty() {
+                    info!(target: "Taxonomy-manager",
+                          "Releasing {} watches whose lease has expired",
+                          expired.len());
+                }
+            }
+        });
+    }
+
+    /// Periodically check that the adapters answer their heartbeats, see `adapter_health`.
+    fn handle_watchdog(state: Weak<MainLock<State>>, health: Weak<Mutex<HealthMonitor>>) {
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(WATCHDOG_INTERVAL_S));
+                let (strong_state, strong_health) = match (state.upgrade(), health.upgrade()) {
+                    (Some(strong_state), Some(strong_health)) => (strong_state, strong_health),
+                    _ => return, // The manager has been dropped.
+                };
+                let adapters = strong_state.read().unwrap().adapters();
+                HealthMonitor::check(&strong_health,
+                                     adapters,
+                                     Duration::from_secs(HEARTBEAT_TIMEOUT_S));
+            }
+        });
+    }
+}
+
 impl AdapterManager {
     pub fn stop(&self) {
         self.back_end.write().unwrap().stop()
@@ -0,0 +1,418 @@
+//! Statistics on the calls dispatched to adapters, to find out which adapter is slowing down
+//! the API, and on the use of each channel, see `usage`.
+
+use api::{Operation, User};
+use channel::Channel;
+use parse::{JSON, ToJSON};
+use services::AdapterId;
+use usage::{ChannelStats, ChannelUsage};
+use util::Id;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many latencies are kept per adapter and operation to compute percentiles.
+const LATENCY_SAMPLES: usize = 1000;
+
+/// Calls taking longer than this are logged, unless configured otherwise.
+const DEFAULT_SLOW_CALL_MS: u64 = 1000;
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000. + duration.subsec_nanos() as f64 / 1_000_000.
+}
+
+#[derive(Default)]
+struct CallStats {
+    calls: u64,
+    /// Channels for which the call returned an error.
+    errors: u64,
+    /// Channels involved in the calls.
+    channels: u64,
+    /// The latest latencies, in milliseconds.
+    latencies: VecDeque<f64>,
+}
+
+impl CallStats {
+    fn record(&mut self, elapsed: f64, channels: usize, errors: usize) {
+        self.calls += 1;
+        self.channels += channels as u64;
+        self.errors += errors as u64;
+        if self.latencies.len() >= LATENCY_SAMPLES {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(elapsed);
+    }
+}
+
+impl ToJSON for CallStats {
+    fn to_json(&self) -> JSON {
+        let mut sorted: Vec<f64> = self.latencies.iter().cloned().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let percentile = |p: usize| if sorted.is_empty() {
+            0.
+        } else {
+            sorted[(sorted.len() - 1) * p / 100]
+        };
+        let error_rate = if self.channels == 0 {
+            0.
+        } else {
+            self.errors as f64 / self.channels as f64
+        };
+        vec![("calls", JSON::U64(self.calls)),
+             ("errors", JSON::U64(self.errors)),
+             ("error_rate", JSON::F64(error_rate)),
+             ("p50_ms", JSON::F64(percentile(50))),
+             ("p90_ms", JSON::F64(percentile(90))),
+             ("p99_ms", JSON::F64(percentile(99))),
+             ("max_ms", JSON::F64(percentile(100)))]
+            .to_json()
+    }
+}
+
+/// Per-adapter, per-operation call statistics.
+///
+/// # JSON
+///
+/// An object with a field per adapter id, each an object with a field per operation
+/// (`Fetch`, `Send`, `Watch`), each an object with fields `calls`, `errors` (the number of
+/// channels for which the adapter returned an error), `error_rate` (per channel) and
+/// `p50_ms`, `p90_ms`, `p99_ms`, `max_ms` (over the latest calls).
+pub struct AdapterMetrics {
+    stats: Mutex<HashMap<Id<AdapterId>, HashMap<String, CallStats>>>,
+    slow_call_ms: Mutex<u64>,
+    usage: Mutex<ChannelUsage>,
+    /// The calls in progress, see `begin`.
+    pending: Mutex<PendingCalls>,
+}
+
+#[derive(Default)]
+struct PendingCalls {
+    counter: usize,
+    calls: HashMap<usize, PendingEntry>,
+}
+
+struct PendingEntry {
+    adapter: Id<AdapterId>,
+    operation: Operation,
+    channels: Vec<Id<Channel>>,
+    user: User,
+    start: Instant,
+    /// Releases whoever waits for the call, see `AdapterMetrics::abort`. `None` if the call
+    /// cannot be aborted, or was already aborted.
+    abort: Option<Box<Fn() + Send>>,
+    aborted: bool,
+}
+
+/// A call to an adapter in progress, see `AdapterMetrics::pending_operations`.
+///
+/// # JSON
+///
+/// An object with fields `id`, `adapter`, `operation`, `channels` (an array of channel ids),
+/// `user` (a user id or `null`), `running_ms` and `aborted` (whether the call was aborted
+/// while the adapter is still busy with it).
+#[derive(Clone, Debug)]
+pub struct PendingOperation {
+    /// Identifies the call for `AdapterMetrics::abort`.
+    pub id: usize,
+    pub adapter: Id<AdapterId>,
+    pub operation: Operation,
+    pub channels: Vec<Id<Channel>>,
+    pub user: User,
+    pub running: Duration,
+    pub aborted: bool,
+}
+
+impl ToJSON for PendingOperation {
+    fn to_json(&self) -> JSON {
+        let user = match self.user {
+            User::None => JSON::Null,
+            User::Id(ref id) => JSON::String(id.clone()),
+        };
+        vec![("id", JSON::U64(self.id as u64)),
+             ("adapter", self.adapter.to_json()),
+             ("operation", self.operation.to_json()),
+             ("channels", self.channels.to_json()),
+             ("user", user),
+             ("running_ms", JSON::F64(as_ms(self.running))),
+             ("aborted", JSON::Bool(self.aborted))]
+            .to_json()
+    }
+}
+
+/// A call to an adapter in progress, forgotten once dropped.
+pub struct PendingCall<'a> {
+    metrics: &'a AdapterMetrics,
+    key: usize,
+}
+
+impl<'a> Drop for PendingCall<'a> {
+    fn drop(&mut self) {
+        self.metrics.pending.lock().unwrap().calls.remove(&self.key);
+    }
+}
+
+impl Default for AdapterMetrics {
+    fn default() -> Self {
+        AdapterMetrics::with_usage(ChannelUsage::new(None)
+            .expect("Counting the use of channels in memory should not fail"))
+    }
+}
+
+impl AdapterMetrics {
+    pub fn new() -> Self {
+        AdapterMetrics::default()
+    }
+
+    /// Metrics counting the use of channels in `usage`, e.g. to keep them in a database.
+    pub fn with_usage(usage: ChannelUsage) -> Self {
+        AdapterMetrics {
+            stats: Mutex::new(HashMap::new()),
+            slow_call_ms: Mutex::new(DEFAULT_SLOW_CALL_MS),
+            usage: Mutex::new(usage),
+            pending: Mutex::new(PendingCalls::default()),
+        }
+    }
+
+    /// Note that a call to an adapter starts, until the result is dropped, e.g. to find out
+    /// which adapters are stuck, see `pending_calls`.
+    pub fn begin(&self, adapter: &Id<AdapterId>, operation: Operation) -> PendingCall {
+        self.insert_pending(PendingEntry {
+            adapter: adapter.clone(),
+            operation: operation,
+            channels: vec![],
+            user: User::None,
+            start: Instant::now(),
+            abort: None,
+            aborted: false,
+        })
+    }
+
+    /// Like `begin`, for a call on behalf of `user` that may be aborted with `abort`, which
+    /// then calls `on_abort` to release whoever waits for the call.
+    pub fn begin_abortable(&self,
+                           adapter: &Id<AdapterId>,
+                           operation: Operation,
+                           channels: &[Id<Channel>],
+                           user: &User,
+                           on_abort: Box<Fn() + Send>)
+                           -> PendingCall {
+        self.insert_pending(PendingEntry {
+            adapter: adapter.clone(),
+            operation: operation,
+            channels: channels.to_vec(),
+            user: user.clone(),
+            start: Instant::now(),
+            abort: Some(on_abort),
+            aborted: false,
+        })
+    }
+
+    fn insert_pending(&self, entry: PendingEntry) -> PendingCall {
+        let mut pending = self.pending.lock().unwrap();
+        pending.counter += 1;
+        let key = pending.counter;
+        pending.calls.insert(key, entry);
+        PendingCall {
+            metrics: self,
+            key: key,
+        }
+    }
+
+    /// The calls to adapters in progress, with how long they have been running, longest first.
+    pub fn pending_calls(&self) -> Vec<(Id<AdapterId>, Operation, Duration)> {
+        self.pending_operations()
+            .into_iter()
+            .map(|op| (op.adapter, op.operation, op.running))
+            .collect()
+    }
+
+    /// The calls to adapters in progress, with the channels and user involved, longest first.
+    /// Calls that were aborted remain listed until the adapter returns.
+    pub fn pending_operations(&self) -> Vec<PendingOperation> {
+        let pending = self.pending.lock().unwrap();
+        let mut operations: Vec<_> = pending.calls
+            .iter()
+            .map(|(&id, entry)| {
+                PendingOperation {
+                    id: id,
+                    adapter: entry.adapter.clone(),
+                    operation: entry.operation.clone(),
+                    channels: entry.channels.clone(),
+                    user: entry.user.clone(),
+                    running: entry.start.elapsed(),
+                    aborted: entry.aborted,
+                }
+            })
+            .collect();
+        operations.sort_by(|a, b| b.running.cmp(&a.running));
+        operations
+    }
+
+    /// Abort call `id` of `pending_operations`, releasing whoever waits for it. The adapter
+    /// itself isn't interrupted, as adapters have no way to cancel a call, but its result
+    /// is then ignored. Return `false` if there is no such call, if it was started with
+    /// `begin` or if it was already aborted.
+    pub fn abort(&self, id: usize) -> bool {
+        let on_abort = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.calls.get_mut(&id) {
+                Some(entry) => {
+                    entry.aborted = entry.aborted || entry.abort.is_some();
+                    entry.abort.take()
+                }
+                None => None,
+            }
+        };
+        match on_abort {
+            Some(on_abort) => {
+                on_abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How often the channels were used, for the channels used at least once.
+    pub fn channel_usage(&self) -> HashMap<Id<Channel>, ChannelStats> {
+        self.usage.lock().unwrap().stats().unwrap_or_else(|err| {
+            error!("Unable to read the use of channels: {}", err);
+            HashMap::new()
+        })
+    }
+
+    /// Calls taking longer than this are logged, with the channels involved.
+    pub fn set_slow_call_threshold(&self, threshold: Duration) {
+        *self.slow_call_ms.lock().unwrap() = as_ms(threshold) as u64;
+    }
+
+    /// Record a call to an adapter, which took `elapsed` and failed for `errors` of `channels`.
+    pub fn record(&self,
+                  adapter: &Id<AdapterId>,
+                  operation: Operation,
+                  channels: &[Id<Channel>],
+                  elapsed: Duration,
+                  errors: usize) {
+        let elapsed = as_ms(elapsed);
+        if elapsed > *self.slow_call_ms.lock().unwrap() as f64 {
+            warn!(target: "Taxonomy-manager",
+                  "Slow call: {} took {:.0}ms for {} on {:?}",
+                  adapter,
+                  elapsed,
+                  operation,
+                  channels.iter().map(|id| id.to_string()).collect::<Vec<_>>());
+        }
+        self.usage.lock().unwrap().record(operation.clone(), channels);
+        let mut stats = self.stats.lock().unwrap();
+        stats.entry(adapter.clone())
+            .or_insert_with(HashMap::new)
+            .entry(operation.to_string())
+            .or_insert_with(CallStats::default)
+            .record(elapsed, channels.len(), errors);
+    }
+}
+
+impl ToJSON for AdapterMetrics {
+    fn to_json(&self) -> JSON {
+        let stats = self.stats.lock().unwrap();
+        let map: BTreeMap<String, JSON> = stats.iter()
+            .map(|(adapter, operations)| {
+                let operations = operations.iter()
+                    .map(|(operation, stats)| (operation.clone(), stats.to_json()))
+                    .collect();
+                (adapter.to_string(), JSON::Object(operations))
+            })
+            .collect();
+        JSON::Object(map)
+    }
+}
+
+#[test]
+fn test_adapter_metrics() {
+    let metrics = AdapterMetrics::new();
+    let adapter = Id::<AdapterId>::new("adapter");
+    let channels = vec![Id::<Channel>::new("a"), Id::<Channel>::new("b")];
+    for ms in 1..101 {
+        metrics.record(&adapter,
+                       Operation::Fetch,
+                       &channels,
+                       Duration::from_millis(ms),
+                       if ms % 4 == 0 { 1 } else { 0 });
+    }
+    let json = metrics.to_json();
+    let fetch = json.find("adapter").and_then(|adapter| adapter.find("Fetch")).unwrap();
+    assert_eq!(fetch.find("calls").and_then(JSON::as_u64), Some(100));
+    assert_eq!(fetch.find("errors").and_then(JSON::as_u64), Some(25));
+    assert_eq!(fetch.find("error_rate").and_then(JSON::as_f64), Some(0.125));
+    assert_eq!(fetch.find("p50_ms").and_then(JSON::as_f64), Some(50.));
+    assert_eq!(fetch.find("max_ms").and_then(JSON::as_f64), Some(100.));
+}
+
+#[test]
+fn test_pending_calls() {
+    let metrics = AdapterMetrics::new();
+    let zwave = Id::<AdapterId>::new("zwave");
+    let hue = Id::<AdapterId>::new("hue");
+    assert!(metrics.pending_calls().is_empty());
+
+    let call = metrics.begin(&zwave, Operation::Send);
+    ::std::thread::sleep(Duration::from_millis(5));
+    {
+        let _call = metrics.begin(&hue, Operation::Fetch);
+        let pending = metrics.pending_calls();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].0, zwave);
+        assert_eq!(pending[0].1, Operation::Send);
+        assert_eq!(pending[1].0, hue);
+    }
+    assert_eq!(metrics.pending_calls().len(), 1);
+    drop(call);
+    assert!(metrics.pending_calls().is_empty());
+}
+
+#[test]
+fn test_abort_pending_operation() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let metrics = AdapterMetrics::new();
+    let zwave = Id::<AdapterId>::new("zwave");
+    let lamp = Id::<Channel>::new("lamp");
+    let released = Arc::new(AtomicBool::new(false));
+
+    let plain = metrics.begin(&zwave, Operation::Fetch);
+    let call = {
+        let released = released.clone();
+        metrics.begin_abortable(&zwave,
+                                Operation::Send,
+                                &[lamp.clone()],
+                                &User::Id("alice".to_owned()),
+                                Box::new(move || released.store(true, Ordering::SeqCst)))
+    };
+    let operations = metrics.pending_operations();
+    assert_eq!(operations.len(), 2);
+    let send = operations.iter().find(|op| op.operation == Operation::Send).unwrap().clone();
+    let fetch = operations.iter().find(|op| op.operation == Operation::Fetch).unwrap().clone();
+    assert_eq!(send.channels, vec![lamp.clone()]);
+    assert_eq!(send.user, User::Id("alice".to_owned()));
+    assert!(!send.aborted);
+
+    let json = send.to_json();
+    assert_eq!(json.find("user").and_then(JSON::as_string), Some("alice"));
+    assert_eq!(json.find("aborted").and_then(JSON::as_bool), Some(false));
+
+    // Calls started with `begin` cannot be aborted.
+    assert!(!metrics.abort(fetch.id));
+    assert!(!metrics.abort(send.id + fetch.id + 1));
+
+    assert!(metrics.abort(send.id));
+    assert!(released.load(Ordering::SeqCst));
+    assert!(!metrics.abort(send.id));
+
+    // The call remains listed until the adapter returns.
+    let operations = metrics.pending_operations();
+    assert!(operations.iter().any(|op| op.id == send.id && op.aborted));
+    drop(call);
+    drop(plain);
+    assert!(metrics.pending_operations().is_empty());
+}
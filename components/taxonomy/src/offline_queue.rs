@@ -0,0 +1,212 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Commands waiting for their device to be reachable again, e.g. a battery-powered sensor that
+//! only wakes up every few hours. See `AdapterManager::send_values_when_reachable`.
+//!
+//! Commands are stored in the taxonomy database, so that they survive a reboot, until they are
+//! delivered or they expire. A command replaces any command queued earlier for the same
+//! channel, as only the latest value matters.
+
+use api::User;
+use channel::Channel;
+use io::Payload;
+use parse::{JSON, Parser, ToJSON};
+use services::ServiceId;
+use util::Id;
+
+use rusqlite::{Connection, Result};
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+/// What became of a value sent with `AdapterManager::send_values_when_reachable`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendStatus {
+    /// The value was sent to the device.
+    Delivered,
+    /// The device couldn't be reached, the value is sent once it is reachable again.
+    Queued,
+}
+
+impl ToJSON for SendStatus {
+    fn to_json(&self) -> JSON {
+        match *self {
+            // Same as `send_values`.
+            SendStatus::Delivered => JSON::Null,
+            SendStatus::Queued => JSON::String("queued".to_owned()),
+        }
+    }
+}
+
+/// A command waiting for its device.
+#[derive(Clone, Debug)]
+pub struct Command {
+    pub channel: Id<Channel>,
+    pub payload: Payload,
+    pub user: User,
+    /// How long the command may still wait.
+    pub ttl: Duration,
+}
+
+pub struct OfflineQueue {
+    db: Connection,
+    /// The services with queued commands, so that checking for them doesn't hit the database.
+    pending: HashSet<Id<ServiceId>>,
+}
+
+impl OfflineQueue {
+    /// Open the queue stored in the database at `path`, or a queue in memory.
+    pub fn new(path: Option<&PathBuf>) -> Result<Self> {
+        let db = try!(match path {
+            Some(path) => Connection::open(path),
+            None => Connection::open_in_memory(),
+        });
+        try!(db.execute("CREATE TABLE IF NOT EXISTS offline_commands (
+                    channel  TEXT NOT NULL PRIMARY KEY,
+                    service  TEXT NOT NULL,
+                    payload  TEXT NOT NULL,
+                    user     TEXT,
+                    expires  INTEGER NOT NULL
+            )",
+                        &[]));
+        let mut queue = OfflineQueue {
+            db: db,
+            pending: HashSet::new(),
+        };
+        try!(queue.expire());
+
+        let mut services = vec![];
+        {
+            let mut stmt = try!(queue.db.prepare("SELECT DISTINCT service FROM offline_commands"));
+            let mut rows = try!(stmt.query(&[]));
+            while let Some(result_row) = rows.next() {
+                let row = try!(result_row);
+                let service: String = row.get(0);
+                services.push(Id::new(&service));
+            }
+        }
+        queue.pending.extend(services);
+        Ok(queue)
+    }
+
+    fn expire(&self) -> Result<()> {
+        try!(self.db.execute("DELETE FROM offline_commands WHERE expires <= $1",
+                             &[&(now() as i64)]));
+        Ok(())
+    }
+
+    /// Queue a command until the device of `service` is reachable again.
+    pub fn push(&mut self, service: &Id<ServiceId>, command: &Command) -> Result<()> {
+        let user = match command.user {
+            User::Id(ref id) => Some(id.clone()),
+            User::None => None,
+        };
+        let expires = (now() + command.ttl.as_secs()) as i64;
+        try!(self.db.execute("INSERT OR REPLACE INTO offline_commands VALUES ($1, $2, $3, $4, $5)",
+                             &[&command.channel.to_string(),
+                               &service.to_string(),
+                               &command.payload.to_json().to_string(),
+                               &user,
+                               &expires]));
+        self.pending.insert(service.clone());
+        Ok(())
+    }
+
+    /// Whether commands are queued for the device of `service`.
+    pub fn has_pending(&self, service: &Id<ServiceId>) -> bool {
+        self.pending.contains(service)
+    }
+
+    /// Whether commands are queued for any device.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Remove and return the commands queued for the device of `service`, oldest first.
+    pub fn take(&mut self, service: &Id<ServiceId>) -> Result<Vec<Command>> {
+        if !self.pending.remove(service) {
+            return Ok(vec![]);
+        }
+        try!(self.expire());
+
+        let now = now();
+        let mut commands = vec![];
+        {
+            let mut stmt = try!(self.db.prepare("SELECT channel, payload, user, expires \
+                                                 FROM offline_commands WHERE service=$1 \
+                                                 ORDER BY rowid"));
+            let mut rows = try!(stmt.query(&[&service.to_string()]));
+            while let Some(result_row) = rows.next() {
+                let row = try!(result_row);
+                let channel: String = row.get(0);
+                let payload: String = row.get(1);
+                let user: Option<String> = row.get(2);
+                let expires: i64 = row.get(3);
+                let payload = match Payload::from_str(&payload) {
+                    Ok(payload) => payload,
+                    Err(err) => {
+                        warn!("Dropping the command queued for {}: {:?}", channel, err);
+                        continue;
+                    }
+                };
+                commands.push(Command {
+                    channel: Id::new(&channel),
+                    payload: payload,
+                    user: user.map_or(User::None, User::Id),
+                    ttl: Duration::from_secs((expires as u64).saturating_sub(now)),
+                });
+            }
+        }
+        try!(self.db.execute("DELETE FROM offline_commands WHERE service=$1",
+                             &[&service.to_string()]));
+        Ok(commands)
+    }
+}
+
+#[test]
+fn test_offline_queue() {
+    use values::{format, Value};
+
+    let service = Id::<ServiceId>::new("service:thermostat@test");
+    let other = Id::<ServiceId>::new("service:lock@test");
+    let command = |channel: &str, value: &str| {
+        Command {
+            channel: Id::new(channel),
+            payload: Payload::from_value(&Value::new(value.to_owned()), &format::STRING).unwrap(),
+            user: User::Id("1".to_owned()),
+            ttl: Duration::from_secs(3600),
+        }
+    };
+
+    let mut queue = OfflineQueue::new(None).unwrap();
+    assert!(queue.is_empty());
+    queue.push(&service, &command("setter:target@test", "19")).unwrap();
+    queue.push(&service, &command("setter:mode@test", "away")).unwrap();
+    // Only the latest value sent to a channel is kept.
+    queue.push(&service, &command("setter:target@test", "21")).unwrap();
+    assert!(queue.has_pending(&service));
+    assert!(!queue.has_pending(&other));
+
+    assert!(queue.take(&other).unwrap().is_empty());
+    let commands = queue.take(&service).unwrap();
+    let channels: Vec<_> = commands.iter().map(|command| command.channel.to_string()).collect();
+    assert_eq!(channels, vec!["setter:mode@test", "setter:target@test"]);
+    assert_eq!(commands[1].payload, command("", "21").payload);
+    assert_eq!(commands[1].user, User::Id("1".to_owned()));
+    assert!(commands[1].ttl.as_secs() > 3500);
+    assert!(queue.is_empty());
+    assert!(queue.take(&service).unwrap().is_empty());
+
+    // Expired commands are dropped.
+    queue.push(&service,
+              &Command { ttl: Duration::from_secs(0), ..command("setter:target@test", "19") })
+        .unwrap();
+    assert!(queue.take(&service).unwrap().is_empty());
+}
@@ -31,6 +31,46 @@ pub fn check_fields(path: Path, json: &JSON) -> Result<(), ParseError> {
     }
 }
 
+/// The fields expected in a JSON value, to reject those that a lenient parser would ignore.
+pub enum Fields {
+    /// Anything, left to the parser.
+    Any,
+    /// An array, each item with these fields.
+    Array(&'static Fields),
+    /// An object with these fields only.
+    Object(&'static [(&'static str, Fields)]),
+}
+
+impl Fields {
+    /// Make sure that `source` has no unknown fields. Type errors are left to the parser.
+    pub fn check(&self, path: Path, source: &JSON) -> Result<(), ParseError> {
+        match (self, source) {
+            (&Fields::Array(item), &JSON::Array(ref items)) => {
+                for (index, value) in items.iter().enumerate() {
+                    try!(path.push_index(index, |path| item.check(path, value)));
+                }
+                Ok(())
+            }
+            (&Fields::Object(fields), &JSON::Object(ref object)) => {
+                let unknown: Vec<String> = object.keys()
+                    .filter(|key| !fields.iter().any(|&(name, _)| name == key.as_str()))
+                    .cloned()
+                    .collect();
+                if !unknown.is_empty() {
+                    return Err(ParseError::unknown_fields(unknown, &path));
+                }
+                for &(name, ref field) in fields {
+                    if let Some(value) = object.get(name) {
+                        try!(path.push(name, |path| field.check(path, value)));
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 /// A path in the JSON tree. Used for displaying error messages.
 #[derive(Clone, Debug)]
 pub struct Path {
@@ -333,6 +373,18 @@ impl Parser<u8> for u8 {
     }
 }
 
+impl Parser<u64> for u64 {
+    fn description() -> String {
+        "integer".to_owned()
+    }
+    fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
+        match source.as_u64() {
+            None => Err(ParseError::type_error("as integer", &path, "positive integer")),
+            Some(val) => Ok(val),
+        }
+    }
+}
+
 impl<T, P> Parser<Vec<T>> for Vec<P>
     where P: Parser<T>
 {
@@ -43,6 +43,7 @@ pub trait ServiceLike {
     fn adapter(&self) -> &Id<AdapterId>;
     fn with_tags<F>(&self, f: F) -> bool where F: Fn(&HashSet<Id<TagId>>) -> bool;
     fn has_channels<F>(&self, f: F) -> bool where F: Fn(&Channel) -> bool;
+    fn property(&self, name: &str) -> Option<&str>;
 }
 
 impl ServiceLike for Service {
@@ -67,6 +68,102 @@ impl ServiceLike for Service {
         }
         false
     }
+    fn property(&self, name: &str) -> Option<&str> {
+        self.properties.get(name).map(String::as_str)
+    }
+}
+
+/// How a `PropertyPredicate` compares the value of a property.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum PropertyOp {
+    /// The value is exactly the expected value.
+    Equals,
+    /// The value contains the expected value, ignoring case.
+    Contains,
+}
+
+/// A condition on a property of a service, e.g. `manufacturer == "Philips"`.
+///
+/// # JSON
+///
+/// An object with a string field `property`, and either a string field `equals` (the value of
+/// the property must be exactly this string) or a string field `contains` (the value must
+/// contain this string, ignoring case). Services that don't have the property are rejected.
+///
+/// ```
+/// use foxbox_taxonomy::selector::*;
+///
+/// let json_predicate = "{\"property\": \"location\", \"contains\": \"kitchen\"}";
+/// assert_eq!(PropertyPredicate::from_str(json_predicate).unwrap(),
+///            PropertyPredicate::contains("location", "kitchen"));
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct PropertyPredicate {
+    pub property: String,
+    pub op: PropertyOp,
+    pub value: String,
+}
+
+impl PropertyPredicate {
+    /// Accept the services whose property `property` is exactly `value`.
+    pub fn equals(property: &str, value: &str) -> Self {
+        PropertyPredicate {
+            property: property.to_owned(),
+            op: PropertyOp::Equals,
+            value: value.to_owned(),
+        }
+    }
+
+    /// Accept the services whose property `property` contains `value`, ignoring case.
+    pub fn contains(property: &str, value: &str) -> Self {
+        PropertyPredicate {
+            property: property.to_owned(),
+            op: PropertyOp::Contains,
+            value: value.to_owned(),
+        }
+    }
+
+    /// Determine if the value of the property, if any, is accepted.
+    pub fn matches(&self, value: Option<&str>) -> bool {
+        match (value, &self.op) {
+            (None, _) => false,
+            (Some(value), &PropertyOp::Equals) => value == self.value,
+            (Some(value), &PropertyOp::Contains) => {
+                value.to_lowercase().contains(&self.value.to_lowercase())
+            }
+        }
+    }
+}
+
+impl Parser<PropertyPredicate> for PropertyPredicate {
+    fn description() -> String {
+        "PropertyPredicate".to_owned()
+    }
+    fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
+        let field = |name: &str| {
+            source.find(name).map(|value| match value.as_string() {
+                Some(value) => Ok(value.to_owned()),
+                None => Err(path.push(name, |path| ParseError::type_error(name, &path, "string"))),
+            })
+        };
+        let property = match field("property") {
+            Some(result) => try!(result),
+            None => return Err(ParseError::missing_field("property", &path)),
+        };
+        let (op, value) = match (field("equals"), field("contains")) {
+            (Some(value), None) => (PropertyOp::Equals, try!(value)),
+            (None, Some(value)) => (PropertyOp::Contains, try!(value)),
+            (None, None) => return Err(ParseError::missing_field("equals", &path)),
+            (Some(_), Some(_)) => {
+                return Err(ParseError::type_error("contains", &path, "either equals or contains"))
+            }
+        };
+        Ok(PropertyPredicate {
+            property: property,
+            op: op,
+            value: value,
+        })
+    }
 }
 
 /// A selector for one or more services.
@@ -91,6 +188,8 @@ impl ServiceLike for Service {
 /// - (optional) array of string `tags`:  accept only services with all the tags in the array;
 /// - (optional) array of objects `channels` (see `ChannelSelector`): accept only services with
 ///    channels matching all the selectors in this array;
+/// - (optional) array of objects `properties` (see `PropertyPredicate`): accept only services
+///    whose properties match all the predicates in this array;
 ///
 /// While each field is optional, at least one field must be provided.
 ///
@@ -103,6 +202,10 @@ impl ServiceLike for Service {
 ///   \"tags\": [\"tag 1\", \"tag 2\"],
 ///   \"channels\": [{
 ///     \"feature\": \"chronometer/is-ready\"
+///   }],
+///   \"properties\": [{
+///     \"property\": \"manufacturer\",
+///     \"equals\": \"Philips\"
 ///   }]
 /// }";
 ///
@@ -126,6 +229,9 @@ pub struct ServiceSelector {
     /// Restrict results to services that have all the channels in `channels`.
     pub channels: Vec<ChannelSelector>,
 
+    /// Restrict results to services whose properties match all of `properties`.
+    pub properties: Vec<PropertyPredicate>,
+
     /// Make sure that we can't instantiate from another crate.
     private: (),
 }
@@ -162,6 +268,16 @@ impl Parser<ServiceSelector> for ServiceSelector {
             }
             Some(Err(err)) => return Err(err),
         };
+        let properties = match path.push("properties", |path| {
+            PropertyPredicate::take_vec_opt(path, source, "properties")
+        }) {
+            None => vec![],
+            Some(Ok(vec)) => {
+                is_empty = false;
+                vec
+            }
+            Some(Err(err)) => return Err(err),
+        };
 
         if is_empty {
             Err(ParseError::empty_object(&path))
@@ -170,6 +286,7 @@ impl Parser<ServiceSelector> for ServiceSelector {
                 id: id,
                 tags: tags,
                 channels: channels,
+                properties: properties,
                 private: (),
             })
         }
@@ -203,6 +320,17 @@ impl ServiceSelector {
         }
     }
 
+    /// Restrict results to services whose properties match all of `properties`.
+    pub fn with_properties(mut self, mut properties: Vec<PropertyPredicate>) -> Self {
+        ServiceSelector {
+            properties: {
+                self.properties.append(&mut properties);
+                self.properties
+            },
+            ..self
+        }
+    }
+
     /// Restrict results to services that are accepted by two selector.
     pub fn and(mut self, mut other: ServiceSelector) -> Self {
         ServiceSelector {
@@ -212,6 +340,10 @@ impl ServiceSelector {
                 self.channels.append(&mut other.channels);
                 self.channels
             },
+            properties: {
+                self.properties.append(&mut other.properties);
+                self.properties
+            },
             private: (),
         }
     }
@@ -225,6 +357,11 @@ impl ServiceSelector {
         if !service.with_tags(|tags| has_selected_tags(&self.tags, tags)) {
             return false;
         }
+        if !self.properties
+            .iter()
+            .all(|predicate| predicate.matches(service.property(&predicate.property))) {
+            return false;
+        }
         // If any of the getter selectors doesn't find a getter,
         // we don't match.
         let channels_fail = self.channels
@@ -269,6 +406,8 @@ impl SelectedBy<ServiceSelector> for Service {
 /// - (optional) array of string `service_tags`:  accept only channels of a service with all the
 ///        tags in the array;
 /// - (optional) string|object `kind` (see `ChannelKind`): accept only channels of a given kind.
+/// - (optional) string `feature`: accept only channels with a given feature, or with a feature
+///        matching a pattern, e.g. `"zwave/"` or `"camera/*"` (see `feature_matches`).
 ///
 /// While each field is optional, at least one field must be provided.
 ///
@@ -308,7 +447,8 @@ pub struct ChannelSelector {
     ///  Restrict results to channels offered by a service that has all the tags in `tags`.
     pub service_tags: HashSet<Id<TagId>>,
 
-    /// If `Exactly(k)`, restrict results to channels that provide feature `k`
+    /// If `Exactly(k)`, restrict results to channels that provide feature `k`, or a feature
+    /// matching `k` if it is a pattern, see `feature_matches`.
     pub feature: Exactly<Id<FeatureId>>,
 
     pub supports_send: Exactly<bool>,
@@ -421,7 +561,10 @@ impl ChannelSelector {
 
     /// Restrict to a channel with a specific kind.
     pub fn with_feature(self, feature: &Id<FeatureId>) -> Self {
-        ChannelSelector { feature: self.feature.and(Exactly::Exactly(feature.clone())), ..self }
+        ChannelSelector {
+            feature: and_features(self.feature, Exactly::Exactly(feature.clone())),
+            ..self
+        }
     }
 
     ///  Restrict to channels that have all the tags in `tags`.
@@ -453,7 +596,7 @@ impl ChannelSelector {
             parent: self.parent.and(other.parent),
             tags: self.tags.union(&other.tags).cloned().collect(),
             service_tags: self.service_tags.union(&other.service_tags).cloned().collect(),
-            feature: self.feature.and(other.feature),
+            feature: and_features(self.feature, other.feature),
             supports_send: self.supports_send.and(other.supports_send),
             supports_fetch: self.supports_fetch.and(other.supports_fetch),
             supports_watch: self.supports_watch.and(other.supports_watch),
@@ -469,7 +612,11 @@ impl ChannelSelector {
         if !self.parent.matches(&channel.service) {
             return false;
         }
-        if !self.feature.matches(&channel.feature) {
+        let has_feature = match self.feature {
+            Exactly::Exactly(ref selected) => feature_matches(selected, &channel.feature),
+            ref other => other.matches(&channel.feature),
+        };
+        if !has_feature {
             return false;
         }
         if !(&self.supports_send as &SelectedBy<_>).matches(&channel.supports_send) {
@@ -509,6 +656,73 @@ impl Parser<ChannelSelector> for ChannelSelectorWithFeature {
     }
 }
 
+/// Whether `feature` is selected by `selected`, which is either a feature or a pattern:
+///
+/// - a prefix ending with `/` selects all the features that start with it, e.g. `"zwave/"`;
+/// - `*` stands for any sequence of characters, e.g. `"camera/*"` or `"*/is-on"`.
+///
+/// ```
+/// use foxbox_taxonomy::selector::feature_matches;
+/// use foxbox_taxonomy::services::Id;
+///
+/// let feature = Id::new("camera/image-png");
+/// assert!(feature_matches(&Id::new("camera/image-png"), &feature));
+/// assert!(feature_matches(&Id::new("camera/"), &feature));
+/// assert!(feature_matches(&Id::new("camera/*"), &feature));
+/// assert!(feature_matches(&Id::new("*/image-*"), &feature));
+/// assert!(!feature_matches(&Id::new("camera"), &feature));
+/// assert!(!feature_matches(&Id::new("light/*"), &feature));
+/// ```
+pub fn feature_matches(selected: &Id<FeatureId>, feature: &Id<FeatureId>) -> bool {
+    let selected: &str = selected.as_atom();
+    let feature: &str = feature.as_atom();
+    if selected.ends_with('/') {
+        return feature.starts_with(selected);
+    }
+    glob_matches(selected, feature)
+}
+
+/// Whether `text` matches `pattern`, in which `*` stands for any sequence of characters.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !text.starts_with(first) {
+        return false;
+    }
+    let mut rest = &text[first.len()..];
+    let parts: Vec<_> = parts.collect();
+    match parts.split_last() {
+        // No wildcard, the text must be the pattern.
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(index) => rest = &rest[index + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.ends_with(last)
+        }
+    }
+}
+
+/// Combine two constraints on features, either of which may be a pattern. If one of them
+/// matches the other, e.g. `"camera/*"` and `"camera/image-png"`, keep the narrowest.
+fn and_features(a: Exactly<Id<FeatureId>>, b: Exactly<Id<FeatureId>>) -> Exactly<Id<FeatureId>> {
+    match (a, b) {
+        (Exactly::Exactly(a), Exactly::Exactly(b)) => {
+            if feature_matches(&a, &b) {
+                Exactly::Exactly(b)
+            } else if feature_matches(&b, &a) {
+                Exactly::Exactly(a)
+            } else {
+                Exactly::Never
+            }
+        }
+        (a, b) => a.and(b),
+    }
+}
+
 fn has_selected_tags(actual: &HashSet<Id<TagId>>, requested: &HashSet<Id<TagId>>) -> bool {
     for tag in &*actual {
         if !requested.contains(tag) {
@@ -517,3 +731,45 @@ fn has_selected_tags(actual: &HashSet<Id<TagId>>, requested: &HashSet<Id<TagId>>
     }
     true
 }
+
+#[test]
+fn test_feature_patterns() {
+    let channel = |feature: &str| {
+        Channel {
+            id: Id::new(feature),
+            feature: Id::new(feature),
+            ..Channel::default()
+        }
+    };
+    let tags = HashSet::new();
+    let select = |feature: &str| ChannelSelector::new().with_feature(&Id::new(feature));
+
+    let zwave = select("zwave/");
+    assert!(zwave.matches(&tags, &channel("zwave/config-param")));
+    assert!(!zwave.matches(&tags, &channel("zwave")));
+    assert!(!zwave.matches(&tags, &channel("light/is-on")));
+
+    let is_on = select("*/is-on");
+    assert!(is_on.matches(&tags, &channel("light/is-on")));
+    assert!(is_on.matches(&tags, &channel("power-plug/is-on")));
+    assert!(!is_on.matches(&tags, &channel("light/is-on-since")));
+
+    assert!(glob_matches("a*b*c", "abc"));
+    assert!(glob_matches("a*b*c", "a-b-b-c"));
+    assert!(!glob_matches("a*b*c", "a-c-b"));
+    assert!(!glob_matches("a*a", "a"));
+
+    // Combining a pattern with a feature it matches keeps the feature.
+    let png = select("camera/*").with_feature(&Id::new("camera/image-png"));
+    match png.feature {
+        Exactly::Exactly(ref feature) => assert_eq!(*feature, Id::new("camera/image-png")),
+        ref other => panic!("Unexpected feature {:?}", other),
+    }
+    assert!(png.matches(&tags, &channel("camera/image-png")));
+    assert!(!png.matches(&tags, &channel("camera/image-jpg")));
+    let never = select("camera/*").and(select("light/is-on"));
+    assert!(!never.matches(&tags, &channel("light/is-on")));
+
+    let selector = ChannelSelector::from_str(r#"{"feature": "camera/*"}"#).unwrap();
+    assert!(selector.matches(&tags, &channel("camera/image-png")));
+}
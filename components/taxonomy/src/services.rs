@@ -43,6 +43,10 @@ macro_rules! tag_id {
 /// - adapter: string;
 /// - tags: array of strings;
 /// - properties: object;
+/// - friendly_name, room: string or null (see `ServiceMetadata`);
+/// - icon: string or null - the icon chosen by the user, otherwise the one provided by the
+///   adapter;
+/// - label, description (optional): string - provided by the adapter;
 /// - getters: object (keys are string identifiers, for more details on values see Channel<Getter>);
 /// - setters: object (keys are string identifiers, for more details on values see Channel<Setter>);
 ///
@@ -68,11 +72,25 @@ pub struct Service {
     /// For instance, these can be device manufacturer, model, etc.
     pub properties: HashMap<String, String>,
 
+    /// Metadata that users may edit at any time, e.g. a friendly name.
+    pub metadata: ServiceMetadata,
+
     /// Channels connected directly to this service.
     pub channels: HashMap<Id<Channel>, Channel>,
 
     /// Identifier of the adapter for this service.
     pub adapter: Id<AdapterId>,
+
+    /// A human-readable name provided by the adapter, e.g. the model of the device. Unlike
+    /// `metadata.friendly_name`, users can't edit it.
+    pub label: Option<String>,
+
+    /// A longer human-readable description provided by the adapter.
+    pub description: Option<String>,
+
+    /// The name of an icon provided by the adapter, used unless the user picked one in
+    /// `metadata`.
+    pub icon: Option<String>,
 }
 
 impl Service {
@@ -82,21 +100,235 @@ impl Service {
             tags: HashSet::new(),
             channels: HashMap::new(),
             properties: HashMap::new(),
+            metadata: ServiceMetadata::default(),
             id: id.clone(),
             adapter: adapter.clone(),
+            label: None,
+            description: None,
+            icon: None,
         }
     }
 }
 
+/// A service along with its channels, to be registered at once with
+/// `AdapterManagerHandle::add_service_with_channels`.
+///
+/// ```
+/// use foxbox_taxonomy::channel::*;
+/// use foxbox_taxonomy::services::*;
+///
+/// let adapter = Id::<AdapterId>::new("adapter@example.org");
+/// let builder = ServiceBuilder::new(Service::empty(&Id::new("service:lamp"), &adapter))
+///     .with_property("model", "Lamp 2000")
+///     .with_channel(Channel {
+///         id: Id::new("channel:lamp/is-on"),
+///         feature: Id::new("light/is-on"),
+///         ..Channel::default()
+///     });
+/// assert_eq!(builder.channels[0].service, Id::new("service:lamp"));
+/// assert_eq!(builder.channels[0].adapter, adapter);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ServiceBuilder {
+    pub service: Service,
+    pub channels: Vec<Channel>,
+}
+
+impl ServiceBuilder {
+    /// Start from a service without channels.
+    pub fn new(service: Service) -> Self {
+        ServiceBuilder {
+            service: service,
+            channels: vec![],
+        }
+    }
+
+    pub fn with_property(mut self, key: &str, value: &str) -> Self {
+        self.service.properties.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.service.label = Some(label.to_owned());
+        self
+    }
+
+    /// Add a channel to the service. Its `service` and `adapter` are those of the service.
+    pub fn with_channel(mut self, channel: Channel) -> Self {
+        self.channels.push(Channel {
+            service: self.service.id.clone(),
+            adapter: self.service.adapter.clone(),
+            ..channel
+        });
+        self
+    }
+}
+
 impl ToJSON for Service {
     fn to_json(&self) -> JSON {
-        vec![
+        let icon = self.metadata.icon.as_ref().or(self.icon.as_ref());
+        let mut vec = vec![
             ("id", self.id.to_json()),
             ("adapter", self.adapter.to_json()),
             ("tags", self.tags.to_json()),
             ("properties", self.properties.to_json()),
+            ("friendly_name", self.metadata.friendly_name.to_json()),
+            ("room", self.metadata.room.to_json()),
+            ("icon", icon.map_or(JSON::Null, |icon| icon.to_json())),
             ("channels", self.channels.to_json()),
-        ]
+        ];
+        if let Some(ref label) = self.label {
+            vec.push(("label", label.to_json()));
+        }
+        if let Some(ref description) = self.description {
+            vec.push(("description", description.to_json()));
+        }
+        vec.to_json()
+    }
+}
+
+/// Metadata on a service that users may edit at any time, e.g. to display devices with a
+/// friendly name and an icon, grouped by room.
+///
+/// Unlike `properties`, which adapters set once and for all when they register a service,
+/// this metadata is persisted along with the tags, so it survives reboots and re-registrations.
+///
+/// # JSON
+///
+/// An object with optional fields `friendly_name`, `room` and `icon`, each a string or null.
+/// Missing fields are unset.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServiceMetadata {
+    /// A name chosen by the user, e.g. "Reading lamp".
+    pub friendly_name: Option<String>,
+
+    /// The room in which the service is located, e.g. "Living room".
+    pub room: Option<String>,
+
+    /// The name of an icon, to be interpreted by clients.
+    pub icon: Option<String>,
+}
+
+impl ServiceMetadata {
+    /// The fields, as (name, value) pairs.
+    pub fn fields(&self) -> Vec<(&'static str, &Option<String>)> {
+        vec![("friendly_name", &self.friendly_name), ("room", &self.room), ("icon", &self.icon)]
+    }
+
+    /// Set a field by name. Returns `false` if there is no such field.
+    pub fn set(&mut self, name: &str, value: Option<String>) -> bool {
+        match name {
+            "friendly_name" => self.friendly_name = value,
+            "room" => self.room = value,
+            "icon" => self.icon = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl Parser<ServiceMetadata> for ServiceMetadata {
+    fn description() -> String {
+        "ServiceMetadata".to_owned()
+    }
+    fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
+        let fields = match *source {
+            JSON::Object(ref fields) => fields,
+            _ => return Err(ParseError::type_error("ServiceMetadata", &path, "object")),
+        };
+        let mut metadata = ServiceMetadata::default();
+        let mut unknown = vec![];
+        for (name, value) in fields {
+            let value = match *value {
+                JSON::Null => None,
+                JSON::String(ref value) => Some(value.clone()),
+                _ => return Err(ParseError::type_error(name, &path, "string")),
+            };
+            if !metadata.set(name, value) {
+                unknown.push(name.clone());
+            }
+        }
+        if unknown.is_empty() {
+            Ok(metadata)
+        } else {
+            Err(ParseError::unknown_fields(unknown, &path))
+        }
+    }
+}
+
+impl ToJSON for ServiceMetadata {
+    fn to_json(&self) -> JSON {
+        self.fields()
+            .iter()
+            .map(|&(name, value)| (name, value.to_json()))
+            .collect::<Vec<_>>()
             .to_json()
     }
 }
+
+/// What to do with a service that describes the same device as a service that is already
+/// registered, e.g. because the device was both discovered through UPnP and added manually.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergePolicy {
+    /// Keep both services.
+    KeepDuplicates,
+
+    /// Merge the new service into the existing service if they have the same value for any of
+    /// these properties (e.g. "udn", "mac", "serial"). The existing service then exposes the
+    /// channels of both services, while each channel remains handled by its own adapter.
+    MergeOn(Vec<String>),
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        MergePolicy::KeepDuplicates
+    }
+}
+
+impl MergePolicy {
+    /// Parse a comma-separated list of property names. An empty list keeps duplicates.
+    pub fn from_properties(source: &str) -> Self {
+        let properties: Vec<String> = source.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if properties.is_empty() {
+            MergePolicy::KeepDuplicates
+        } else {
+            MergePolicy::MergeOn(properties)
+        }
+    }
+
+    /// The property by which two services, given their properties, are the same device, if any.
+    ///
+    /// Values are compared case-insensitively and ignoring `:` and `-`, so that e.g.
+    /// `AA:BB:CC:DD:EE:FF` and `aa-bb-cc-dd-ee-ff` are the same MAC address.
+    pub fn same_device(&self,
+                       properties: &HashMap<String, String>,
+                       other: &HashMap<String, String>)
+                       -> Option<String> {
+        fn normalize(value: &str) -> String {
+            value.trim()
+                .chars()
+                .filter(|c| *c != ':' && *c != '-')
+                .collect::<String>()
+                .to_lowercase()
+        }
+        let names = match *self {
+            MergePolicy::KeepDuplicates => return None,
+            MergePolicy::MergeOn(ref names) => names,
+        };
+        names.iter()
+            .find(|name| {
+                match (properties.get(*name), other.get(*name)) {
+                    (Some(a), Some(b)) => {
+                        let a = normalize(a);
+                        !a.is_empty() && a == normalize(b)
+                    }
+                    _ => false,
+                }
+            })
+            .cloned()
+    }
+}
@@ -5,8 +5,10 @@
 /// ! This is the database that holds tags associated to various objects.
 /// ! It provides an api to manage Id <-> tags relationships.
 /// ! All users share the same tags for objects.
+/// ! It also holds the metadata that users set on services (see `ServiceMetadata`).
 
 use rusqlite::{Connection, Result};
+use services::ServiceMetadata;
 use std::path::PathBuf;
 use util::{Id, TagId};
 
@@ -66,6 +68,17 @@ impl TagStorage {
                 panic!("Unable to create taxonomy tags database: {}", err);
             });
 
+        db.execute("CREATE TABLE IF NOT EXISTS metadata (
+                    id     TEXT NOT NULL,
+                    name   TEXT NOT NULL,
+                    value  TEXT NOT NULL,
+                    PRIMARY KEY (id, name)
+            )",
+                     &[])
+            .unwrap_or_else(|err| {
+                panic!("Unable to create taxonomy metadata database: {}", err);
+            });
+
         self.db = Some(db);
     }
 
@@ -141,6 +154,39 @@ impl TagStorage {
     }
 }
 
+impl TagStorage {
+    /// Replace the metadata of an object.
+    pub fn set_metadata<T>(&mut self, id: &Id<T>, metadata: &ServiceMetadata) -> Result<()> {
+        self.ensure_db();
+        let db = self.db.as_ref().unwrap();
+        try!(db.execute("DELETE FROM metadata WHERE id=$1", &[&escape(id)]));
+        for (name, value) in metadata.fields() {
+            if let Some(ref value) = *value {
+                try!(db.execute("INSERT INTO metadata VALUES ($1, $2, $3)",
+                                &[&escape(id), &name.to_owned(), value]));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_metadata_for<T>(&mut self, id: &Id<T>) -> Result<ServiceMetadata> {
+        self.ensure_db();
+        let mut metadata = ServiceMetadata::default();
+        let mut stmt = try!(self.db
+            .as_ref()
+            .unwrap()
+            .prepare("SELECT name, value FROM metadata WHERE id=$1"));
+        let mut rows = try!(stmt.query(&[&escape(&id)]));
+
+        while let Some(result_row) = rows.next() {
+            let row = try!(result_row);
+            let name: String = row.get(0);
+            let _ = metadata.set(&name, Some(row.get(1)));
+        }
+        Ok(metadata)
+    }
+}
+
 #[cfg(test)]
 pub fn get_db_environment() -> PathBuf {
     use libc::getpid;
@@ -257,3 +303,34 @@ fn storage_test() {
     tags = store.get_tags_for(&id1).unwrap();
     assert_eq!(tags.len(), 0);
 }
+
+#[test]
+#[allow(unused_variables)]
+fn metadata_storage_test() {
+    use util::ServiceId;
+
+    struct AutoDeleteDb { };
+    impl Drop for AutoDeleteDb {
+        fn drop(&mut self) {
+            remove_test_db();
+        }
+    }
+    let auto_db = AutoDeleteDb {};
+
+    let mut store = TagStorage::new(&get_db_environment());
+    let id = Id::<ServiceId>::new("lamp");
+    assert_eq!(store.get_metadata_for(&id).unwrap(), ServiceMetadata::default());
+
+    let metadata = ServiceMetadata {
+        friendly_name: Some("Reading lamp".to_owned()),
+        room: Some("Living room".to_owned()),
+        icon: None,
+    };
+    store.set_metadata(&id, &metadata).unwrap();
+    assert_eq!(store.get_metadata_for(&id).unwrap(), metadata);
+
+    // Setting the metadata again replaces it.
+    let metadata = ServiceMetadata { icon: Some("lamp".to_owned()), ..ServiceMetadata::default() };
+    store.set_metadata(&id, &metadata).unwrap();
+    assert_eq!(store.get_metadata_for(&id).unwrap(), metadata);
+}
@@ -0,0 +1,217 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A bounded pool of named threads for short-lived blocking work, e.g. sending notifications or
+//! querying a remote server, so that a burst of events doesn't translate into a burst of
+//! threads.
+//!
+//! Long-running loops (an adapter's main loop, a discovery listener) should keep their own
+//! thread: they would hold a worker forever.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+thread_local!(static IS_WORKER: Cell<bool> = Cell::new(false));
+
+/// Whether the current thread is a worker of a pool. A job waiting for another job of the same
+/// pool could wait forever once all the workers are busy, so it should rather do the work
+/// itself.
+pub fn is_worker_thread() -> bool {
+    IS_WORKER.with(|is_worker| is_worker.get())
+}
+
+/// A `Box<FnOnce()>` can't be called directly, so jobs go through this trait.
+trait Job: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F> Job for F
+    where F: FnOnce() + Send
+{
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Workers spawned so far, at most the size of the pool.
+    pub workers: usize,
+    /// Workers currently running a job.
+    pub busy: usize,
+    /// Jobs waiting for a worker.
+    pub queued: usize,
+    /// Jobs run to completion, including those which panicked.
+    pub completed: u64,
+    /// Jobs refused because the queue was full.
+    pub rejected: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PoolError {
+    /// The queue is full, the job was dropped.
+    QueueFull,
+}
+
+struct State {
+    jobs: VecDeque<Box<Job>>,
+    stats: PoolStats,
+}
+
+struct Inner {
+    name: String,
+    size: usize,
+    max_queued: usize,
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+#[derive(Clone)]
+pub struct ThreadPool {
+    inner: Arc<Inner>,
+}
+
+impl ThreadPool {
+    /// A pool of at most `size` threads named after `name`, queuing at most `max_queued` jobs.
+    /// Threads are spawned on demand.
+    pub fn new(name: &str, size: usize, max_queued: usize) -> Self {
+        ThreadPool {
+            inner: Arc::new(Inner {
+                name: name.to_owned(),
+                size: if size == 0 { 1 } else { size },
+                max_queued: max_queued,
+                state: Mutex::new(State {
+                    jobs: VecDeque::new(),
+                    stats: PoolStats::default(),
+                }),
+                available: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Queue a job, to be run by the first available worker.
+    pub fn execute<F>(&self, job: F) -> Result<(), PoolError>
+        where F: FnOnce() + Send + 'static
+    {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.jobs.len() >= self.inner.max_queued {
+            state.stats.rejected += 1;
+            warn!("Thread pool {} is full ({} jobs queued), dropping a job",
+                  self.inner.name,
+                  state.jobs.len());
+            return Err(PoolError::QueueFull);
+        }
+        state.jobs.push_back(Box::new(job));
+        state.stats.queued = state.jobs.len();
+
+        let idle = state.stats.workers - state.stats.busy;
+        if idle < state.jobs.len() && state.stats.workers < self.inner.size {
+            let inner = self.inner.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("{}-{}", self.inner.name, state.stats.workers))
+                .spawn(move || Self::work(inner));
+            match spawned {
+                Ok(_) => state.stats.workers += 1,
+                Err(err) => error!("Cannot spawn a worker for {}: {}", self.inner.name, err),
+            }
+        }
+        self.inner.available.notify_one();
+        Ok(())
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.inner.state.lock().unwrap().stats
+    }
+
+    fn work(inner: Arc<Inner>) {
+        IS_WORKER.with(|is_worker| is_worker.set(true));
+        loop {
+            let job = {
+                let mut state = inner.state.lock().unwrap();
+                while state.jobs.is_empty() {
+                    state = inner.available.wait(state).unwrap();
+                }
+                let job = state.jobs.pop_front().unwrap();
+                state.stats.queued = state.jobs.len();
+                state.stats.busy += 1;
+                job
+            };
+
+            let _done = Done { inner: inner.clone() };
+            job.run();
+        }
+    }
+}
+
+/// Accounts for the end of a job. If the job panicked, the worker is unwinding and a
+/// replacement is spawned, so that the pool doesn't shrink.
+struct Done {
+    inner: Arc<Inner>,
+}
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        let mut state = match self.inner.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.stats.busy -= 1;
+        state.stats.completed += 1;
+        if !thread::panicking() {
+            return;
+        }
+        error!("A job of thread pool {} panicked", self.inner.name);
+        let inner = self.inner.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("{}-{}", self.inner.name, state.stats.workers))
+            .spawn(move || ThreadPool::work(inner));
+        if spawned.is_err() {
+            state.stats.workers -= 1;
+        }
+    }
+}
+
+#[test]
+fn test_thread_pool_runs_jobs_with_bounded_threads() {
+    use std::sync::mpsc::channel;
+
+    let pool = ThreadPool::new("test-pool", 2, 100);
+    let (tx, rx) = channel();
+    for i in 0..10 {
+        let tx = tx.clone();
+        pool.execute(move || tx.send(i).unwrap()).unwrap();
+    }
+    let mut got: Vec<_> = rx.iter().take(10).collect();
+    got.sort();
+    assert_eq!(got, (0..10).collect::<Vec<_>>());
+    assert!(pool.stats().workers <= 2);
+
+    assert!(!is_worker_thread());
+    pool.execute(move || tx.send(if is_worker_thread() { 1 } else { 0 }).unwrap()).unwrap();
+    assert_eq!(rx.recv(), Ok(1));
+}
+
+#[test]
+fn test_thread_pool_rejects_when_full() {
+    use std::sync::mpsc::channel;
+
+    let pool = ThreadPool::new("test-pool", 1, 1);
+    let (tx_block, rx_block) = channel::<()>();
+    let (tx_started, rx_started) = channel();
+    pool.execute(move || {
+            tx_started.send(()).unwrap();
+            let _ = rx_block.recv();
+        })
+        .unwrap();
+    rx_started.recv().unwrap();
+
+    // The worker is busy: the next job is queued, the one after is rejected.
+    assert_eq!(pool.execute(|| {}), Ok(()));
+    assert_eq!(pool.execute(|| {}), Err(PoolError::QueueFull));
+    let stats = pool.stats();
+    assert_eq!((stats.busy, stats.queued, stats.rejected), (1, 1, 1));
+    drop(tx_block);
+}
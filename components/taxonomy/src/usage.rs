@@ -0,0 +1,252 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! How often each channel is fetched, sent to or watched, to find the devices and rules that
+//! are never used, and to sort controls by how often they are actually used. See
+//! `AdapterManager::channel_usage`.
+//!
+//! Uses are counted per channel, operation and day in the taxonomy database, so that they
+//! survive a reboot. As channels may be polled several times per second, uses are kept in
+//! memory and only written once a minute.
+
+use api::Operation;
+use channel::Channel;
+use parse::{JSON, ToJSON};
+use util::Id;
+
+use rusqlite::{Connection, Result};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How often uses are written to the database, in seconds.
+const FLUSH_INTERVAL_S: u64 = 60;
+
+/// Uses older than this are forgotten, in days.
+const RETENTION_DAYS: u64 = 365;
+
+const DAY_S: u64 = 24 * 3600;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+/// The uses of a channel for an operation.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub last_7_days: u64,
+    pub last_30_days: u64,
+    /// Since the channel was first used, at most `RETENTION_DAYS` ago.
+    pub total: u64,
+}
+
+impl ToJSON for Usage {
+    fn to_json(&self) -> JSON {
+        vec![("last_7_days", JSON::U64(self.last_7_days)),
+             ("last_30_days", JSON::U64(self.last_30_days)),
+             ("total", JSON::U64(self.total))]
+            .to_json()
+    }
+}
+
+/// The uses of a channel.
+///
+/// # JSON
+///
+/// An object with a field `last_used` (in seconds since the epoch, or `null`) and a field per
+/// operation (`Fetch`, `Send`, `Watch`), each an object with fields `last_7_days`,
+/// `last_30_days` and `total`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelStats {
+    /// When the channel was last used, in seconds since the epoch.
+    pub last_used: Option<u64>,
+    pub fetch: Usage,
+    pub send: Usage,
+    pub watch: Usage,
+}
+
+impl ChannelStats {
+    fn usage_mut(&mut self, operation: &str) -> Option<&mut Usage> {
+        match operation {
+            "Fetch" => Some(&mut self.fetch),
+            "Send" => Some(&mut self.send),
+            "Watch" => Some(&mut self.watch),
+            _ => None,
+        }
+    }
+
+    /// The uses over the last 30 days, all operations included.
+    pub fn last_30_days(&self) -> u64 {
+        self.fetch.last_30_days + self.send.last_30_days + self.watch.last_30_days
+    }
+}
+
+impl ToJSON for ChannelStats {
+    fn to_json(&self) -> JSON {
+        vec![("last_used", self.last_used.map_or(JSON::Null, JSON::U64)),
+             ("Fetch", self.fetch.to_json()),
+             ("Send", self.send.to_json()),
+             ("Watch", self.watch.to_json())]
+            .to_json()
+    }
+}
+
+pub struct ChannelUsage {
+    db: Connection,
+    /// The uses not written yet, per channel, operation and day: how many, and the latest.
+    pending: HashMap<(String, String, u64), (u64, u64)>,
+    last_flush: u64,
+}
+
+impl ChannelUsage {
+    /// Open the uses stored in the database at `path`, or count them in memory.
+    pub fn new(path: Option<&PathBuf>) -> Result<Self> {
+        let db = try!(match path {
+            Some(path) => Connection::open(path),
+            None => Connection::open_in_memory(),
+        });
+        try!(db.execute("CREATE TABLE IF NOT EXISTS channel_usage (
+                    channel    TEXT NOT NULL,
+                    operation  TEXT NOT NULL,
+                    day        INTEGER NOT NULL,
+                    count      INTEGER NOT NULL,
+                    last_used  INTEGER NOT NULL,
+                    PRIMARY KEY (channel, operation, day)
+            )",
+                        &[]));
+        Ok(ChannelUsage {
+            db: db,
+            pending: HashMap::new(),
+            last_flush: now(),
+        })
+    }
+
+    /// Count a use of each of `channels`.
+    pub fn record(&mut self, operation: Operation, channels: &[Id<Channel>]) {
+        self.record_at(operation, channels, now());
+    }
+
+    fn record_at(&mut self, operation: Operation, channels: &[Id<Channel>], now: u64) {
+        let operation = operation.to_string();
+        for channel in channels {
+            let key = (channel.to_string(), operation.clone(), now / DAY_S);
+            let entry = self.pending.entry(key).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 = now;
+        }
+        if now >= self.last_flush + FLUSH_INTERVAL_S {
+            if let Err(err) = self.flush(now) {
+                error!("Unable to record the use of channels: {}", err);
+            }
+        }
+    }
+
+    /// Write the pending uses to the database, and forget the oldest ones.
+    fn flush(&mut self, now: u64) -> Result<()> {
+        self.last_flush = now;
+        for ((channel, operation, day), (count, last_used)) in self.pending.drain() {
+            let day = day as i64;
+            try!(self.db.execute("INSERT OR IGNORE INTO channel_usage VALUES ($1, $2, $3, 0, 0)",
+                                 &[&channel, &operation, &day]));
+            try!(self.db.execute("UPDATE channel_usage \
+                                  SET count = count + $4, last_used = MAX(last_used, $5) \
+                                  WHERE channel=$1 AND operation=$2 AND day=$3",
+                                 &[&channel,
+                                   &operation,
+                                   &day,
+                                   &(count as i64),
+                                   &(last_used as i64)]));
+        }
+        let oldest = (now / DAY_S).saturating_sub(RETENTION_DAYS) as i64;
+        try!(self.db.execute("DELETE FROM channel_usage WHERE day < $1", &[&oldest]));
+        Ok(())
+    }
+
+    /// The uses of the channels that were used at least once.
+    pub fn stats(&mut self) -> Result<HashMap<Id<Channel>, ChannelStats>> {
+        let now = now();
+        self.stats_at(now)
+    }
+
+    fn stats_at(&mut self, now: u64) -> Result<HashMap<Id<Channel>, ChannelStats>> {
+        try!(self.flush(now));
+        let today = now / DAY_S;
+        let mut stats: HashMap<Id<Channel>, ChannelStats> = HashMap::new();
+        let mut stmt = try!(self.db.prepare("SELECT channel, operation, day, count, last_used \
+                                             FROM channel_usage"));
+        let mut rows = try!(stmt.query(&[]));
+        while let Some(result_row) = rows.next() {
+            let row = try!(result_row);
+            let channel: String = row.get(0);
+            let operation: String = row.get(1);
+            let day: i64 = row.get(2);
+            let count: i64 = row.get(3);
+            let last_used: i64 = row.get(4);
+            let age = today.saturating_sub(day as u64);
+            let (count, last_used) = (count as u64, last_used as u64);
+
+            let entry = stats.entry(Id::new(&channel)).or_insert_with(ChannelStats::default);
+            if entry.last_used.map_or(true, |latest| latest < last_used) {
+                entry.last_used = Some(last_used);
+            }
+            if let Some(usage) = entry.usage_mut(&operation) {
+                usage.total += count;
+                if age < 30 {
+                    usage.last_30_days += count;
+                }
+                if age < 7 {
+                    usage.last_7_days += count;
+                }
+            }
+        }
+        Ok(stats)
+    }
+}
+
+impl Drop for ChannelUsage {
+    fn drop(&mut self) {
+        let now = now();
+        if let Err(err) = self.flush(now) {
+            error!("Unable to record the use of channels: {}", err);
+        }
+    }
+}
+
+#[test]
+fn test_channel_usage() {
+    let light = Id::<Channel>::new("setter:light@test");
+    let thermometer = Id::<Channel>::new("getter:temperature@test");
+    let today = 1000 * DAY_S;
+
+    let mut usage = ChannelUsage::new(None).unwrap();
+    assert!(usage.stats_at(today).unwrap().is_empty());
+
+    usage.record_at(Operation::Send, &[light.clone()], today - 40 * DAY_S);
+    usage.record_at(Operation::Send, &[light.clone()], today - 10 * DAY_S);
+    usage.record_at(Operation::Send, &[light.clone()], today);
+    usage.record_at(Operation::Fetch, &[light.clone(), thermometer.clone()], today + 1);
+    // Uses older than the retention are forgotten.
+    usage.record_at(Operation::Fetch,
+                    &[thermometer.clone()],
+                    today - (RETENTION_DAYS + 1) * DAY_S);
+
+    let stats = usage.stats_at(today + 2).unwrap();
+    assert_eq!(stats.len(), 2);
+    let light = &stats[&light];
+    assert_eq!(light.last_used, Some(today + 1));
+    assert_eq!(light.send,
+               Usage {
+                   last_7_days: 1,
+                   last_30_days: 2,
+                   total: 3,
+               });
+    assert_eq!(light.fetch.total, 1);
+    assert_eq!(light.watch, Usage::default());
+    assert_eq!(light.last_30_days(), 3);
+    assert_eq!(stats[&thermometer].fetch.total, 1);
+
+    assert_eq!(light.to_json().find("Send").and_then(|send| send.find("total")),
+               Some(&JSON::U64(3)));
+}
@@ -285,7 +285,7 @@ pub enum OnOff {
     /// let parsed = OnOff::parse_str("\"On\"").unwrap();
     /// assert_eq!(parsed, OnOff::On);
     ///
-    /// let serialized: JSON = OnOff::serialize(&OnOff::On, &BinaryTarget).unwrap();
+    /// let serialized: JSON = OnOff::serialize(&OnOff::On, &BinaryTarget::inline()).unwrap();
     /// assert_eq!(serialized.as_str().unwrap(), "On");
     /// ```
     On,
@@ -303,7 +303,7 @@ pub enum OnOff {
     /// let parsed = OnOff::parse_str("\"Off\"").unwrap();
     /// assert_eq!(parsed, OnOff::Off);
     ///
-    /// let serialized: JSON = OnOff::serialize(&OnOff::Off, &BinaryTarget).unwrap();
+    /// let serialized: JSON = OnOff::serialize(&OnOff::Off, &BinaryTarget::inline()).unwrap();
     /// assert_eq!(serialized.as_str().unwrap(), "Off");
     /// ```
     Off,
@@ -373,7 +373,7 @@ pub enum OpenClosed {
     /// let parsed = OpenClosed::parse_str("\"Open\"").unwrap();
     /// assert_eq!(parsed, OpenClosed::Open);
     ///
-    /// let serialized: JSON = OpenClosed::serialize(&OpenClosed::Open, &BinaryTarget).unwrap();
+    /// let serialized: JSON = OpenClosed::serialize(&OpenClosed::Open, &BinaryTarget::inline()).unwrap();
     /// assert_eq!(serialized.as_str().unwrap(), "Open");
     /// ```
     Open,
@@ -391,7 +391,7 @@ pub enum OpenClosed {
     /// let parsed = OpenClosed::parse_str("\"Closed\"").unwrap();
     /// assert_eq!(parsed, OpenClosed::Closed);
     ///
-    /// let serialized: JSON = OpenClosed::serialize(&OpenClosed::Closed, &BinaryTarget).unwrap();
+    /// let serialized: JSON = OpenClosed::serialize(&OpenClosed::Closed, &BinaryTarget::inline()).unwrap();
     /// assert_eq!(serialized.as_str().unwrap(), "Closed");
     /// ```
     Closed,
@@ -554,7 +554,7 @@ pub enum IsSecure {
     /// let parsed = IsSecure::parse_str("\"Insecure\"").unwrap();
     /// assert_eq!(parsed, IsSecure::Insecure);
     ///
-    /// let serialized: JSON = IsSecure::serialize(&IsSecure::Insecure, &BinaryTarget).unwrap();
+    /// let serialized: JSON = IsSecure::serialize(&IsSecure::Insecure, &BinaryTarget::inline()).unwrap();
     /// assert_eq!(serialized.as_str().unwrap(), "Insecure");
     /// ```
     Insecure,
@@ -572,7 +572,7 @@ pub enum IsSecure {
     /// let parsed = IsSecure::parse_str("\"Secure\"").unwrap();
     /// assert_eq!(parsed, IsSecure::Secure);
     ///
-    /// let serialized: JSON = IsSecure::serialize(&IsSecure::Secure, &BinaryTarget).unwrap();
+    /// let serialized: JSON = IsSecure::serialize(&IsSecure::Secure, &BinaryTarget::inline()).unwrap();
     /// assert_eq!(serialized.as_str().unwrap(), "Secure");
     /// ```
     Secure,
@@ -702,31 +702,55 @@ pub enum Temperature {
 
 impl Temperature {
     /// Get a temperature in Fahrenheit.
+    ///
+    /// ```
+    /// use foxbox_taxonomy::values::*;
+    ///
+    /// assert_eq!(Temperature::C(100.).as_f(), 212.);
+    /// assert_eq!(Temperature::F(32.).as_f(), 32.);
+    /// ```
     pub fn as_f(&self) -> f64 {
-        unimplemented!();
+        match *self {
+            Temperature::F(val) => val,
+            Temperature::C(val) => val * 9. / 5. + 32.,
+        }
     }
 
     /// Get a temperature in Celcius.
+    ///
+    /// ```
+    /// use foxbox_taxonomy::values::*;
+    ///
+    /// assert_eq!(Temperature::F(212.).as_c(), 100.);
+    /// assert_eq!(Temperature::C(21.5).as_c(), 21.5);
+    /// assert!(Temperature::F(50.) < Temperature::C(20.));
+    /// ```
     pub fn as_c(&self) -> f64 {
-        unimplemented!();
+        match *self {
+            Temperature::F(val) => (val - 32.) * 5. / 9.,
+            Temperature::C(val) => val,
+        }
     }
 }
 
-impl Parser<Temperature> for Temperature {
+impl Data for Temperature {
     fn description() -> String {
-        "Temperature".to_owned()
+        "Temperature {C} or {F}".to_owned()
     }
-    fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
+    fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
         if !source.is_object() {
-            return Err(ParseError::type_error("Temperature", &path, "object"));
+            return Err(Error::Parsing(ParseError::type_error("Temperature", &path, "object")));
         }
         if let Some(result) = path.push("F", |path| f64::take_opt(path, source, "F")) {
-            return result.map(Temperature::F);
+            return result.map(Temperature::F).map_err(Error::Parsing);
         }
         if let Some(result) = path.push("C", |path| f64::take_opt(path, source, "C")) {
-            return result.map(Temperature::C);
+            return result.map(Temperature::C).map_err(Error::Parsing);
         }
-        Err(ParseError::missing_field("C|F", &path))
+        Err(Error::Parsing(ParseError::missing_field("C|F", &path)))
+    }
+    fn serialize(source: &Self, _binary: &BinaryTarget) -> Result<JSON, Error> {
+        Ok(source.to_json())
     }
 }
 impl ToJSON for Temperature {
@@ -778,7 +802,7 @@ pub enum Color {
     /// assert_eq!(v, 0.4);
     ///
     /// println!("Testing serialization");
-    /// let serialized : JSON = Color::serialize(&parsed, &BinaryTarget).unwrap();
+    /// let serialized : JSON = Color::serialize(&parsed, &BinaryTarget::inline()).unwrap();
     /// let h = serialized.find("h").unwrap().as_f64().unwrap();
     /// assert_eq!(h, 220.5);
     /// let s = serialized.find("s").unwrap().as_f64().unwrap();
@@ -876,6 +900,12 @@ impl PartialOrd for Json {
 /// A (probably large) binary value.
 ///
 /// Since this value is considered large, `clone()` is not implemented.
+///
+/// # JSON
+///
+/// Represented by an object `{"data": [bytes], "mimetype": string}`. When serialized to a
+/// detached `BinaryTarget`, the data is stored aside and the object becomes
+/// `{"part": index, "size": bytes, "mimetype": string}`.
 #[derive(Debug, PartialEq)]
 pub struct Binary {
     /// The binary data.
@@ -901,8 +931,16 @@ impl Data for Binary {
             mimetype: mimetype,
         })
     }
-    fn serialize(source: &Self, _binary: &BinaryTarget) -> Result<JSON, Error> {
-        Ok(source.to_json())
+    fn serialize(source: &Self, binary: &BinaryTarget) -> Result<JSON, Error> {
+        match binary.push(&source.data) {
+            None => Ok(source.to_json()),
+            Some(part) => {
+                Ok(vec![("part", JSON::U64(part as u64)),
+                        ("size", JSON::U64(source.data.len() as u64)),
+                        ("mimetype", JSON::String(source.mimetype.to_string()))]
+                    .to_json())
+            }
+        }
     }
 }
 
@@ -945,7 +983,7 @@ impl ToJSON for Binary {
 /// assert_eq!(date_time.day(), 28);
 ///
 ///
-/// let serialized: JSON = TimeStamp::serialize(&ts, &BinaryTarget).unwrap();
+/// let serialized: JSON = TimeStamp::serialize(&ts, &BinaryTarget::inline()).unwrap();
 /// assert!(serialized.as_str().unwrap().starts_with("2014-11-28"));
 ///
 /// # }
@@ -1010,6 +1048,368 @@ impl<T> From<DateTime<T>> for TimeStamp
 }
 
 
+/// A percentage, e.g. the position of a window covering.
+///
+/// # JSON
+///
+/// Values of this type are represented by a number between 0 and 100 (inclusive).
+///
+/// ```
+/// use foxbox_taxonomy::io::*;
+/// use foxbox_taxonomy::parse::*;
+/// use foxbox_taxonomy::values::*;
+///
+/// let parsed = Percent::parse_str("42").unwrap();
+/// assert_eq!(parsed.as_u8(), 42);
+///
+/// let serialized: JSON = Percent::serialize(&parsed, &BinaryTarget::inline()).unwrap();
+/// assert_eq!(serialized.as_u64().unwrap(), 42);
+///
+/// assert!(Percent::parse_str("101").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
+pub struct Percent(u8);
+impl Percent {
+    /// Build a percentage, clamping values above 100.
+    pub fn new(value: u8) -> Self {
+        Percent(if value > 100 { 100 } else { value })
+    }
+    pub fn as_u8(&self) -> u8 {
+        self.0
+    }
+}
+
+impl Data for Percent {
+    fn description() -> String {
+        "Percent (0-100)".to_owned()
+    }
+    fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
+        match source.as_u64() {
+            Some(val) if val <= 100 => Ok(Percent(val as u8)),
+            _ => {
+                Err(Error::Parsing(ParseError::type_error("Percent",
+                                                          &path,
+                                                          "integer between 0 and 100")))
+            }
+        }
+    }
+    fn serialize(source: &Self, _binary: &BinaryTarget) -> Result<JSON, Error> {
+        Ok(source.to_json())
+    }
+}
+
+impl ToJSON for Percent {
+    fn to_json(&self) -> JSON {
+        JSON::U64(self.0 as u64)
+    }
+}
+
+/// A relative humidity, in percents. Unlike `Percent`, readings keep their decimals.
+///
+/// # JSON
+///
+/// Values of this type are represented by a number between 0 and 100 (inclusive).
+///
+/// ```
+/// use foxbox_taxonomy::io::*;
+/// use foxbox_taxonomy::parse::*;
+/// use foxbox_taxonomy::values::*;
+///
+/// let parsed = Humidity::parse_str("45.5").unwrap();
+/// assert_eq!(parsed.as_percent(), 45.5);
+///
+/// let serialized: JSON = Humidity::serialize(&parsed, &BinaryTarget::inline()).unwrap();
+/// assert_eq!(serialized.as_f64().unwrap(), 45.5);
+///
+/// assert!(Humidity::parse_str("100.5").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct Humidity(f64);
+impl Humidity {
+    /// Build a relative humidity, clamping values outside of [0, 100].
+    pub fn new(percent: f64) -> Self {
+        Humidity(percent.max(0.).min(100.))
+    }
+    pub fn as_percent(&self) -> f64 {
+        self.0
+    }
+}
+
+impl Data for Humidity {
+    fn description() -> String {
+        "Humidity (%, 0-100)".to_owned()
+    }
+    fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
+        match f64::parse(path.clone(), source) {
+            Ok(val) if val >= 0. && val <= 100. => Ok(Humidity(val)),
+            _ => {
+                Err(Error::Parsing(ParseError::type_error("Humidity",
+                                                          &path,
+                                                          "number between 0 and 100")))
+            }
+        }
+    }
+    fn serialize(source: &Self, _binary: &BinaryTarget) -> Result<JSON, Error> {
+        Ok(source.to_json())
+    }
+}
+
+impl ToJSON for Humidity {
+    fn to_json(&self) -> JSON {
+        JSON::F64(self.0)
+    }
+}
+
+/// An illuminance, in lux.
+///
+/// # JSON
+///
+/// Values of this type are represented by objects `{lux: float}`.
+///
+/// ```
+/// use foxbox_taxonomy::io::*;
+/// use foxbox_taxonomy::parse::*;
+/// use foxbox_taxonomy::values::*;
+///
+/// let parsed = Luminance::parse_str("{\"lux\": 300}").unwrap();
+/// assert_eq!(parsed.as_lux(), 300.);
+///
+/// let serialized: JSON = Luminance::serialize(&parsed, &BinaryTarget::inline()).unwrap();
+/// assert_eq!(serialized.find("lux").unwrap().as_f64().unwrap(), 300.);
+///
+/// assert!(Luminance::parse_str("{\"lux\": -1}").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct Luminance(f64);
+impl Luminance {
+    pub fn from_lux(lux: f64) -> Self {
+        Luminance(lux)
+    }
+    pub fn as_lux(&self) -> f64 {
+        self.0
+    }
+}
+
+impl Data for Luminance {
+    fn description() -> String {
+        "Luminance {lux}".to_owned()
+    }
+    fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
+        let lux = try!(path.push("lux", |path| f64::take(path, source, "lux"))
+            .map_err(Error::Parsing));
+        if lux < 0. {
+            return Err(Error::Parsing(ParseError::type_error("lux", &path, "positive number")));
+        }
+        Ok(Luminance(lux))
+    }
+    fn serialize(source: &Self, _binary: &BinaryTarget) -> Result<JSON, Error> {
+        Ok(source.to_json())
+    }
+}
+
+impl ToJSON for Luminance {
+    fn to_json(&self) -> JSON {
+        vec![("lux", JSON::F64(self.0))].to_json()
+    }
+}
+
+/// An instantaneous power, e.g. the consumption of a smart plug. Internally in watts.
+///
+/// # JSON
+///
+/// Values of this type are represented by objects `{W: float}`. Objects `{kW: float}` are
+/// also accepted.
+///
+/// ```
+/// use foxbox_taxonomy::io::*;
+/// use foxbox_taxonomy::parse::*;
+/// use foxbox_taxonomy::values::*;
+///
+/// let parsed = Power::parse_str("{\"kW\": 1.5}").unwrap();
+/// assert_eq!(parsed.as_w(), 1500.);
+/// assert_eq!(parsed, Power::from_w(1500.));
+///
+/// let serialized: JSON = Power::serialize(&parsed, &BinaryTarget::inline()).unwrap();
+/// assert_eq!(serialized.find("W").unwrap().as_f64().unwrap(), 1500.);
+/// ```
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct Power(f64);
+impl Power {
+    pub fn from_w(watts: f64) -> Self {
+        Power(watts)
+    }
+    pub fn from_kw(kilowatts: f64) -> Self {
+        Power(kilowatts * 1000.)
+    }
+    pub fn as_w(&self) -> f64 {
+        self.0
+    }
+    pub fn as_kw(&self) -> f64 {
+        self.0 / 1000.
+    }
+}
+
+impl Data for Power {
+    fn description() -> String {
+        "Power {W} or {kW}".to_owned()
+    }
+    fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
+        if !source.is_object() {
+            return Err(Error::Parsing(ParseError::type_error("Power", &path, "object")));
+        }
+        if let Some(result) = path.push("W", |path| f64::take_opt(path, source, "W")) {
+            return result.map(Power::from_w).map_err(Error::Parsing);
+        }
+        if let Some(result) = path.push("kW", |path| f64::take_opt(path, source, "kW")) {
+            return result.map(Power::from_kw).map_err(Error::Parsing);
+        }
+        Err(Error::Parsing(ParseError::missing_field("W|kW", &path)))
+    }
+    fn serialize(source: &Self, _binary: &BinaryTarget) -> Result<JSON, Error> {
+        Ok(source.to_json())
+    }
+}
+
+impl ToJSON for Power {
+    fn to_json(&self) -> JSON {
+        vec![("W", JSON::F64(self.0))].to_json()
+    }
+}
+
+/// An amount of energy, e.g. the consumption of a meter since it was installed. Internally
+/// in kilowatt-hours.
+///
+/// # JSON
+///
+/// Values of this type are represented by objects `{kWh: float}`. Objects `{Wh: float}` are
+/// also accepted.
+///
+/// ```
+/// use foxbox_taxonomy::io::*;
+/// use foxbox_taxonomy::parse::*;
+/// use foxbox_taxonomy::values::*;
+///
+/// let parsed = Energy::parse_str("{\"Wh\": 2500}").unwrap();
+/// assert_eq!(parsed.as_kwh(), 2.5);
+/// assert_eq!(parsed, Energy::from_kwh(2.5));
+///
+/// let serialized: JSON = Energy::serialize(&parsed, &BinaryTarget::inline()).unwrap();
+/// assert_eq!(serialized.find("kWh").unwrap().as_f64().unwrap(), 2.5);
+/// ```
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct Energy(f64);
+impl Energy {
+    pub fn from_kwh(kilowatt_hours: f64) -> Self {
+        Energy(kilowatt_hours)
+    }
+    pub fn from_wh(watt_hours: f64) -> Self {
+        Energy(watt_hours / 1000.)
+    }
+    pub fn as_kwh(&self) -> f64 {
+        self.0
+    }
+    pub fn as_wh(&self) -> f64 {
+        self.0 * 1000.
+    }
+}
+
+impl Data for Energy {
+    fn description() -> String {
+        "Energy {kWh} or {Wh}".to_owned()
+    }
+    fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
+        if !source.is_object() {
+            return Err(Error::Parsing(ParseError::type_error("Energy", &path, "object")));
+        }
+        if let Some(result) = path.push("kWh", |path| f64::take_opt(path, source, "kWh")) {
+            return result.map(Energy::from_kwh).map_err(Error::Parsing);
+        }
+        if let Some(result) = path.push("Wh", |path| f64::take_opt(path, source, "Wh")) {
+            return result.map(Energy::from_wh).map_err(Error::Parsing);
+        }
+        Err(Error::Parsing(ParseError::missing_field("kWh|Wh", &path)))
+    }
+    fn serialize(source: &Self, _binary: &BinaryTarget) -> Result<JSON, Error> {
+        Ok(source.to_json())
+    }
+}
+
+impl ToJSON for Energy {
+    fn to_json(&self) -> JSON {
+        vec![("kWh", JSON::F64(self.0))].to_json()
+    }
+}
+
+
+/// A detected/not detected state, e.g. for smoke or leak sensors.
+///
+/// # JSON
+///
+/// Values of this type are represented by strings "Detected" | "NotDetected".
+///
+/// ```
+/// use foxbox_taxonomy::io::*;
+/// use foxbox_taxonomy::parse::*;
+/// use foxbox_taxonomy::values::*;
+///
+/// let parsed = IsDetected::parse_str("\"Detected\"").unwrap();
+/// assert_eq!(parsed, IsDetected::Detected);
+///
+/// let serialized: JSON = IsDetected::serialize(&IsDetected::NotDetected, &BinaryTarget::inline()).unwrap();
+/// assert_eq!(serialized.as_str().unwrap(), "NotDetected");
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum IsDetected {
+    Detected,
+    NotDetected,
+}
+
+impl IsDetected {
+    fn as_bool(&self) -> bool {
+        match *self {
+            IsDetected::Detected => true,
+            IsDetected::NotDetected => false,
+        }
+    }
+}
+
+impl PartialOrd for IsDetected {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IsDetected {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bool().cmp(&other.as_bool())
+    }
+}
+
+impl Data for IsDetected {
+    fn description() -> String {
+        "Detected/NotDetected".to_owned()
+    }
+    fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
+        let result = match source.as_str() {
+            Some("Detected") => IsDetected::Detected,
+            Some("NotDetected") => IsDetected::NotDetected,
+            Some(str) => return Err(Error::Parsing(ParseError::unknown_constant(str, &path))),
+            None => {
+                return Err(Error::Parsing(ParseError::type_error("IsDetected", &path, "string")))
+            }
+        };
+        Ok(result)
+    }
+    fn serialize(source: &Self, _binary: &BinaryTarget) -> Result<JSON, Error> {
+        let str = match *source {
+            IsDetected::Detected => "Detected",
+            IsDetected::NotDetected => "NotDetected",
+        };
+        Ok(JSON::String(str.to_owned()))
+    }
+}
+
+
 /// A comparison between two values.
 ///
 /// # JSON
@@ -1045,7 +1445,7 @@ pub enum Range<T>
     ///   panic!();
     /// }
     ///
-    /// let as_json = Range::<OnOff>::serialize(&parsed, &BinaryTarget).unwrap();
+    /// let as_json = Range::<OnOff>::serialize(&parsed, &BinaryTarget::inline()).unwrap();
     /// let as_str = serde_json::to_string(&as_json).unwrap();
     /// assert_eq!(as_str, "{\"Leq\":\"On\"}");
     ///
@@ -1255,5 +1655,19 @@ pub mod format {
         pub static ref BINARY : Arc<Format> = Arc::new(Format::new::<Binary>());
         pub static ref TIMESTAMP : Arc<Format> = Arc::new(Format::new::<TimeStamp>());
         pub static ref DURATION : Arc<Format> = Arc::new(Format::new::<Duration>());
+        pub static ref DURATION_RANGE : Arc<Format> = Arc::new(Format::new::<Range<Duration>>());
+        pub static ref PERCENT : Arc<Format> = Arc::new(Format::new::<Percent>());
+        pub static ref IS_DETECTED : Arc<Format> = Arc::new(Format::new::<IsDetected>());
+        pub static ref PERCENT_RANGE : Arc<Format> = Arc::new(Format::new::<Range<Percent>>());
+        pub static ref TEMPERATURE : Arc<Format> = Arc::new(Format::new::<Temperature>());
+        pub static ref TEMPERATURE_RANGE : Arc<Format> =
+            Arc::new(Format::new::<Range<Temperature>>());
+        pub static ref HUMIDITY : Arc<Format> = Arc::new(Format::new::<Humidity>());
+        pub static ref HUMIDITY_RANGE : Arc<Format> = Arc::new(Format::new::<Range<Humidity>>());
+        pub static ref LUMINANCE : Arc<Format> = Arc::new(Format::new::<Luminance>());
+        pub static ref LUMINANCE_RANGE : Arc<Format> =
+            Arc::new(Format::new::<Range<Luminance>>());
+        pub static ref POWER : Arc<Format> = Arc::new(Format::new::<Power>());
+        pub static ref ENERGY : Arc<Format> = Arc::new(Format::new::<Energy>());
     }
 }
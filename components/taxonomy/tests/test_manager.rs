@@ -1,5 +1,6 @@
 extern crate foxbox_taxonomy;
 extern crate libc;
+extern crate serde_json;
 extern crate transformable_channels;
 #[macro_use]
 extern crate assert_matches;
@@ -8,7 +9,8 @@ use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::io::*;
 use foxbox_taxonomy::manager::*;
 use foxbox_taxonomy::fake_adapter::*;
-use foxbox_taxonomy::api::{ API, Error, InternalError, TargetMap, Targetted, User, WatchEvent as Event };
+use foxbox_taxonomy::api::{ API, Error, InternalError, Observation, Source, TargetMap, Targetted, User,
+                            WatchEvent as Event };
 use foxbox_taxonomy::selector::*;
 use foxbox_taxonomy::services::*;
 use foxbox_taxonomy::values::*;
@@ -641,6 +643,117 @@ fn test_add_remove_services() {
     }
 }
 
+#[test]
+fn test_merge_services() {
+    println!("");
+    let manager = AdapterManager::new(None);
+    manager.set_merge_policy(MergePolicy::from_properties("udn, mac"));
+
+    let upnp = Id::<AdapterId>::new("upnp adapter");
+    let manual = Id::<AdapterId>::new("manual adapter");
+    manager.add_adapter(Arc::new(FakeAdapter::new(&upnp))).unwrap();
+    manager.add_adapter(Arc::new(FakeAdapter::new(&manual))).unwrap();
+
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let service_id_2 = Id::<ServiceId>::new("service id 2");
+    let service_id_3 = Id::<ServiceId>::new("service id 3");
+
+    let mut service_1 = Service::empty(&service_id_1, &upnp);
+    service_1.properties.insert("udn".to_owned(), "uuid:1234".to_owned());
+    service_1.properties.insert("model".to_owned(), "Camera".to_owned());
+    let mut service_2 = Service::empty(&service_id_2, &manual);
+    service_2.properties.insert("mac".to_owned(), "AA-BB-CC-DD-EE-FF".to_owned());
+    service_2.properties.insert("model".to_owned(), "Manual".to_owned());
+    let mut service_3 = Service::empty(&service_id_3, &manual);
+    service_3.properties.insert("udn".to_owned(), "UUID:1234".to_owned());
+    service_3.properties.insert("mac".to_owned(), "aa:bb:cc:dd:ee:ff".to_owned());
+
+    let channel = Channel {
+        feature: Id::new("light/is-on"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    };
+    let channel_1 = Channel {
+        id: Id::new("channel id 1"),
+        service: service_id_1.clone(),
+        adapter: upnp.clone(),
+        ..channel.clone()
+    };
+    let channel_3 = Channel {
+        id: Id::new("channel id 3"),
+        service: service_id_3.clone(),
+        adapter: manual.clone(),
+        ..channel.clone()
+    };
+
+    println!("* Services sharing an identity property are merged into the first one.");
+    manager.add_service(service_1).unwrap();
+    manager.add_service(service_2).unwrap();
+    manager.add_service(service_3).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services.len(), 2);
+    let merged = manager.get_services(vec![ServiceSelector::new().with_id(&service_id_1)]);
+    assert_eq!(merged[0].properties.get("model"), Some(&"Camera".to_owned()));
+    assert_eq!(merged[0].properties.get("mac"), Some(&"aa:bb:cc:dd:ee:ff".to_owned()));
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_id(&service_id_3)]).len(), 0);
+
+    println!("* Merging the same service twice should fail.");
+    match manager.add_service(Service::empty(&service_id_3, &manual)) {
+        Err(Error::Internal(InternalError::DuplicateService(ref err))) if *err == service_id_3 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Channels of the merged service are combined, and keep their adapter.");
+    manager.add_channel(channel_1).unwrap();
+    manager.add_channel(channel_3.clone()).unwrap();
+    let channels = manager.get_channels(vec![ChannelSelector::new()]);
+    assert_eq!(channels.len(), 2);
+    for channel in &channels {
+        assert_eq!(channel.service, service_id_1);
+    }
+    let merged = manager.get_services(vec![ServiceSelector::new().with_id(&service_id_1)]);
+    assert_eq!(merged[0].channels.len(), 2);
+    assert_eq!(merged[0].channels[&channel_3.id].adapter, manual);
+
+    println!("* A merged channel can only be added by the adapter of its service.");
+    let channel_4 = Channel {
+        id: Id::new("channel id 4"),
+        adapter: upnp.clone(),
+        ..channel_3.clone()
+    };
+    match manager.add_channel(channel_4) {
+        Err(Error::Internal(InternalError::ConflictingAdapter(_, _))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Removing the merged service only removes its own channels.");
+    manager.remove_service(&service_id_3).unwrap();
+    assert_eq!(manager.get_services(vec![ServiceSelector::new()]).len(), 2);
+    assert_eq!(manager.get_channels(vec![ChannelSelector::new()]).len(), 1);
+
+    println!("* Removing the adapter of a merged service removes its channels.");
+    manager.add_service(service_with_udn(&service_id_3, &manual)).unwrap();
+    manager.add_channel(channel_3).unwrap();
+    assert_eq!(manager.get_channels(vec![ChannelSelector::new()]).len(), 2);
+    manager.remove_adapter(&manual).unwrap();
+    assert_eq!(manager.get_services(vec![ServiceSelector::new()]).len(), 1);
+    assert_eq!(manager.get_channels(vec![ChannelSelector::new()]).len(), 1);
+
+    println!("* Without a merge policy, duplicates are kept.");
+    let manager = AdapterManager::new(None);
+    manager.add_adapter(Arc::new(FakeAdapter::new(&upnp))).unwrap();
+    manager.add_adapter(Arc::new(FakeAdapter::new(&manual))).unwrap();
+    manager.add_service(service_with_udn(&service_id_1, &upnp)).unwrap();
+    manager.add_service(service_with_udn(&service_id_3, &manual)).unwrap();
+    assert_eq!(manager.get_services(vec![ServiceSelector::new()]).len(), 2);
+}
+
+fn service_with_udn(id: &Id<ServiceId>, adapter: &Id<AdapterId>) -> Service {
+    let mut service = Service::empty(id, adapter);
+    service.properties.insert("udn".to_owned(), "uuid:1234".to_owned());
+    service
+}
+
 #[test]
 fn test_add_remove_tags() {
     println!("");
@@ -1361,7 +1474,7 @@ fn test_watch() {
         guards.push(manager.watch_values(target_map(vec![(
             vec![ChannelSelector::new().with_id(&Id::new("No such getter"))],
             Exactly::Always
-        )]), Box::new(tx_watch_1)));
+        )]), Box::new(tx_watch_1), User::None));
 
         println!("* With adapters, watching values from a selector that has no channels does nothing.");
         manager.add_adapter(Arc::new(adapter_1)).unwrap();
@@ -1377,14 +1490,14 @@ fn test_watch() {
         guards.push(manager.watch_values(target_map(vec![(
             vec![ChannelSelector::new().with_id(&Id::new("No such getter"))],
             Exactly::Always
-        )]), Box::new(tx_watch)));
+        )]), Box::new(tx_watch), User::None));
 
         println!("* We can observe channels being added.");
         let (tx_watch, rx_watch) = channel();
         let guard = manager.watch_values(target_map(vec![(
             vec![ChannelSelector::new()],
             Exactly::Always
-        )]), Box::new(tx_watch)); // We keep `guard` out of `guards` to drop it manually later.
+        )]), Box::new(tx_watch), User::None); // We keep `guard` out of `guards` to drop it manually later.
 
         manager.add_channel(getter_1_1.clone()).unwrap();
         manager.add_channel(getter_1_2.clone()).unwrap();
@@ -1420,7 +1533,8 @@ fn test_watch() {
                 Event::EnterRange {
                     channel,
                     value,
-                    format
+                    format,
+                    ..
                 } => (channel, (value, format).as_value()),
                 other => panic!("Unexpected event {:?}", other)
             }
@@ -1444,7 +1558,7 @@ fn test_watch() {
                     .with_tags(vec![tag_1.clone()])
             ],
             Exactly::Exactly((Payload::from_value(&Value::new(OnOff::On), &format::ON_OFF).unwrap()))
-        )]), Box::new(tx_watch_2)));
+        )]), Box::new(tx_watch_2), User::None));
 
         println!("* Value changes are observed on both watchers");
         tweak_1(Tweak::InjectGetterValue(getter_id_1_1.clone(), Ok(Some(Value::new(OnOff::Off)))));
@@ -1455,13 +1569,13 @@ fn test_watch() {
 
         let mut events : HashMap<_, _> = (0..4).map(|_| {
             match rx_watch.recv().unwrap() {
-                Event::EnterRange { channel, value, format } => (channel, (value, format).as_value() ),
+                Event::EnterRange { channel, value, format, .. } => (channel, (value, format).as_value() ),
                 other => panic!("Unexpected event {:?}", other)
             }
         }).collect();
 
         match rx_watch_2.recv().unwrap() {
-            Event::EnterRange { channel, value, format } => {
+            Event::EnterRange { channel, value, format, .. } => {
                 events.insert(channel, (value, format).as_value());
             }
             other => panic!("Unexpected event {:?}", other)
@@ -1554,3 +1668,1067 @@ fn test_watch() {
 
     println!("");
 }
+
+#[test]
+fn test_watch_filtered() {
+    use foxbox_taxonomy::filter::Filter;
+    use foxbox_taxonomy::parse::Parser;
+
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    let service_id = Id::<ServiceId>::new("service id");
+    let getter_id = Id::<Channel>::new("getter id");
+
+    let adapter = FakeAdapter::new(&adapter_id);
+    let tweak = adapter.get_tweak();
+    manager.add_adapter(Arc::new(adapter)).unwrap();
+    manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+    manager.add_channel(Channel {
+        id: getter_id.clone(),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        feature: Id::new("weather/reading"),
+        supports_watch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+        .. Channel::default()
+    }).unwrap();
+
+    let json = |source: &str| Value::new(Json(serde_json::from_str(source).unwrap()));
+    let filter = Filter::from_str(r#"{"field": "wind.speed", "Geq": 30}"#).unwrap();
+
+    println!("* Values that are not accepted by the filter are dropped.");
+    let (tx_watch, rx_watch) = channel();
+    let _guard = manager.watch_values_filtered(vec![Targetted {
+        select: vec![ChannelSelector::new().with_id(&getter_id)],
+        payload: (Exactly::Always, Some(filter), Delivery::Full, None),
+    }], Box::new(tx_watch), User::None);
+
+    tweak(Tweak::InjectGetterValue(getter_id.clone(), Ok(Some(json(r#"{"wind": {"speed": 12}}"#)))));
+    tweak(Tweak::InjectGetterValue(getter_id.clone(), Ok(Some(json(r#"{"wind": {"speed": 42}}"#)))));
+    match rx_watch.recv().unwrap() {
+        Event::EnterRange { channel, value, format, .. } => {
+            assert_eq!(channel, getter_id);
+            let value = (value, format).as_value();
+            assert_eq!(value.cast::<Json>().unwrap().0.lookup("wind.speed").unwrap().as_u64(), Some(42));
+        }
+        other => panic!("Unexpected event {:?}", other)
+    }
+
+    println!("* A value rejected by the filter after an accepted value causes ExitRange.");
+    tweak(Tweak::InjectGetterValue(getter_id.clone(), Ok(Some(json(r#"{"wind": {"speed": 10}}"#)))));
+    match rx_watch.recv().unwrap() {
+        Event::ExitRange { ref channel, .. } if *channel == getter_id => { }
+        other => panic!("Unexpected event {:?}", other)
+    }
+    tweak(Tweak::InjectGetterValue(getter_id.clone(), Ok(Some(json(r#"{"wind": {"speed": 5}}"#)))));
+
+    thread::sleep(std::time::Duration::new(1, 0));
+    assert_matches!(rx_watch.try_recv(), Err(_));
+}
+
+#[test]
+fn test_watch_delta() {
+    use foxbox_taxonomy::parse::ToJSON;
+
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    let service_id = Id::<ServiceId>::new("service id");
+    let getter_id = Id::<Channel>::new("getter id");
+
+    let adapter = FakeAdapter::new(&adapter_id);
+    let tweak = adapter.get_tweak();
+    manager.add_adapter(Arc::new(adapter)).unwrap();
+    manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+    manager.add_channel(Channel {
+        id: getter_id.clone(),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        feature: Id::new("light/state"),
+        supports_watch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+        .. Channel::default()
+    }).unwrap();
+
+    let json = |source: &str| Value::new(Json(serde_json::from_str(source).unwrap()));
+    let (tx_watch, rx_watch) = channel();
+    let _guard = manager.watch_values_filtered(vec![Targetted {
+        select: vec![ChannelSelector::new().with_id(&getter_id)],
+        payload: (Exactly::Always, None, Delivery::Delta, None),
+    }], Box::new(tx_watch), User::None);
+
+    println!("* The first value is delivered in full, along with when and how it was observed.");
+    let before = Observation::now(Source::Device).timestamp;
+    tweak(Tweak::InjectGetterValue(getter_id.clone(), Ok(Some(json(r#"{"on": true, "bri": 100}"#)))));
+    let first = match rx_watch.recv().unwrap() {
+        Event::EnterRange { channel, value, observed, .. } => {
+            assert_eq!(channel, getter_id);
+            assert_eq!(observed.source, Source::Device);
+            assert!(observed.timestamp >= before);
+            value
+        }
+        other => panic!("Unexpected event {:?}", other)
+    };
+
+    println!("* Subsequent values are delivered as patches.");
+    tweak(Tweak::InjectGetterValue(getter_id.clone(), Ok(Some(json(r#"{"on": true, "bri": 120}"#)))));
+    match rx_watch.recv().unwrap() {
+        Event::EnterRangeDelta { channel, patch, format, .. } => {
+            assert_eq!(channel, getter_id);
+            assert_eq!(patch.to_json().as_array().unwrap().len(), 1);
+            let value = (first.patch(&patch).unwrap(), format).as_value();
+            assert_eq!(value.cast::<Json>().unwrap().0.lookup("bri").unwrap().as_u64(), Some(120));
+        }
+        other => panic!("Unexpected event {:?}", other)
+    }
+}
+
+#[test]
+fn test_watch_leases() {
+    use std::time::Duration;
+
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    let service_id = Id::<ServiceId>::new("service id");
+    let getter_id = Id::<Channel>::new("getter id");
+
+    let adapter = FakeAdapter::new(&adapter_id);
+    let tweak = adapter.get_tweak();
+    manager.add_adapter(Arc::new(adapter)).unwrap();
+    manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+    manager.add_channel(Channel {
+        id: getter_id.clone(),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        feature: Id::new("light/is-on"),
+        supports_watch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    }).unwrap();
+
+    let watch = || vec![Targetted {
+        select: vec![ChannelSelector::new().with_id(&getter_id)],
+        payload: (Exactly::Always, None, Delivery::Full, None),
+    }];
+
+    println!("* A leased watch receives values until it expires.");
+    let (tx_watch, rx_watch) = channel();
+    let expired = manager.watch_values_leased(watch(), Box::new(tx_watch), Duration::from_secs(0), User::None);
+    tweak(Tweak::InjectGetterValue(getter_id.clone(), Ok(Some(Value::new(OnOff::On)))));
+    match rx_watch.recv().unwrap() {
+        Event::EnterRange { ref channel, .. } if *channel == getter_id => { }
+        other => panic!("Unexpected event {:?}", other)
+    }
+
+    println!("* Leases that are renewed in time are kept.");
+    let (tx_watch, _rx_watch) = channel();
+    let renewed = manager.watch_values_leased(watch(), Box::new(tx_watch), Duration::from_secs(3600), User::None);
+    assert!(manager.renew_lease(renewed));
+    assert_eq!(manager.reap_leases(), 1);
+    assert!(!manager.renew_lease(expired));
+    assert_eq!(manager.reap_leases(), 0);
+
+    println!("* Leases can be released early, once.");
+    assert!(manager.release_lease(renewed));
+    assert!(!manager.release_lease(renewed));
+    assert!(!manager.renew_lease(renewed));
+}
+
+/// An adapter whose sends only complete once another adapter is sending, too.
+struct RendezvousAdapter {
+    id: Id<AdapterId>,
+    barrier: Arc<std::sync::Barrier>,
+}
+
+impl Adapter for RendezvousAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        self.id.clone()
+    }
+    fn name(&self) -> &str {
+        "rendezvous"
+    }
+    fn vendor(&self) -> &str {
+        "test@foxlink"
+    }
+    fn version(&self) -> &[u32; 4] {
+        &[0, 0, 0, 0]
+    }
+    fn send_values(&self, mut op: HashMap<Id<Channel>, Value>, _: User) -> ResultMap<Id<Channel>, (), Error> {
+        self.barrier.wait();
+        op.drain().map(|(id, _)| (id, Ok(()))).collect()
+    }
+}
+
+#[test]
+fn test_send_dispatches_adapters_concurrently() {
+    println!("");
+
+    let manager = Arc::new(AdapterManager::new(None));
+    let barrier = Arc::new(std::sync::Barrier::new(2));
+    for name in vec!["1", "2"] {
+        let adapter_id = Id::<AdapterId>::new(&format!("adapter id {}", name));
+        let service_id = Id::<ServiceId>::new(&format!("service id {}", name));
+        manager.add_adapter(Arc::new(RendezvousAdapter {
+            id: adapter_id.clone(),
+            barrier: barrier.clone(),
+        })).unwrap();
+        manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+        manager.add_channel(Channel {
+            id: Id::new(&format!("setter id {}", name)),
+            service: service_id,
+            adapter: adapter_id,
+            feature: Id::new("light/is-on"),
+            supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+            .. Channel::default()
+        }).unwrap();
+    }
+
+    println!("* Sending to two adapters calls them concurrently: each waits for the other.");
+    let (tx, rx) = std::sync::mpsc::channel();
+    {
+        let manager = manager.clone();
+        thread::spawn(move || {
+            let data_on = Payload::from_value(&Value::new(OnOff::On), &format::ON_OFF).unwrap();
+            let results = manager.send_values(target_map(vec![(vec![ChannelSelector::new()], data_on)]), User::None);
+            tx.send(results).unwrap();
+        });
+    }
+    for _ in 0..500 {
+        if let Ok(results) = rx.try_recv() {
+            assert_eq!(results.len(), 2);
+            assert!(results.values().all(|result| result.is_ok()));
+            return;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    panic!("Adapters were called one after the other");
+}
+
+#[test]
+fn test_send_constraints() {
+    use foxbox_taxonomy::parse::ToJSON;
+    println!("");
+
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    let service_id = Id::<ServiceId>::new("service id");
+    let volume_id = Id::<Channel>::new("setter volume");
+    let mode_id = Id::<Channel>::new("setter mode");
+
+    let volume_format = Arc::new(Format::new::<Percent>()
+        .with_constraint(Constraint::range_with_step(0., 50., 10.)));
+    let mode_format = Arc::new(Format::new::<String>()
+        .with_constraint(Constraint::one_of(&["eco", "comfort"])));
+
+    let adapter = FakeAdapter::new(&adapter_id);
+    let rx_adapter = adapter.take_rx();
+    manager.add_adapter(Arc::new(adapter)).unwrap();
+    manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+    manager.add_channel(Channel {
+        id: volume_id.clone(),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        feature: Id::new("x-speaker/volume"),
+        supports_send: Some(Signature::accepts(Maybe::Required(volume_format.clone()))),
+        .. Channel::default()
+    }).unwrap();
+    manager.add_channel(Channel {
+        id: mode_id.clone(),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        feature: Id::new("x-heater/mode"),
+        supports_send: Some(Signature::accepts(Maybe::Required(mode_format.clone()))),
+        .. Channel::default()
+    }).unwrap();
+
+    println!("* Constraints are part of the description of channels.");
+    let channels = manager.get_channels(vec![ChannelSelector::new().with_id(&volume_id)]);
+    let json = channels[0].to_json();
+    let constraint = json.find_path(&["supports_send", "accepts", "constraint", "range"]).unwrap();
+    assert_eq!(constraint.find("max").and_then(|max| max.as_f64()), Some(50.));
+    assert_eq!(constraint.find("step").and_then(|step| step.as_f64()), Some(10.));
+
+    println!("* Numbers out of range are clamped before they reach the adapter.");
+    let payload = Payload::from_value(&Value::new(Percent::new(73)), &volume_format).unwrap();
+    let data = manager.send_values(target_map(vec![(vec![ChannelSelector::new().with_id(&volume_id)], payload)]), User::None);
+    assert_matches!(data.get(&volume_id), Some(&Ok(())));
+    let Effect::ValueSent(_, value) = rx_adapter.try_recv().unwrap();
+    assert_eq!(value.cast::<Percent>().unwrap(), &Percent::new(50));
+
+    println!("* Numbers are rounded to the step.");
+    let payload = Payload::from_value(&Value::new(Percent::new(24)), &volume_format).unwrap();
+    manager.send_values(target_map(vec![(vec![ChannelSelector::new().with_id(&volume_id)], payload)]), User::None);
+    let Effect::ValueSent(_, value) = rx_adapter.try_recv().unwrap();
+    assert_eq!(value.cast::<Percent>().unwrap(), &Percent::new(20));
+
+    println!("* Values out of an enumeration are rejected, without reaching the adapter.");
+    let payload = Payload::from_value(&Value::new("turbo".to_owned()), &mode_format).unwrap();
+    let data = manager.send_values(target_map(vec![(vec![ChannelSelector::new().with_id(&mode_id)], payload)]), User::None);
+    assert_matches!(data.get(&mode_id), Some(&Err(Error::Parsing(_))));
+    assert_matches!(rx_adapter.try_recv(), Err(_));
+
+    let payload = Payload::from_value(&Value::new("eco".to_owned()), &mode_format).unwrap();
+    let data = manager.send_values(target_map(vec![(vec![ChannelSelector::new().with_id(&mode_id)], payload)]), User::None);
+    assert_matches!(data.get(&mode_id), Some(&Ok(())));
+    let Effect::ValueSent(_, value) = rx_adapter.try_recv().unwrap();
+    assert_eq!(&**value.cast::<String>().unwrap(), "eco");
+}
+
+#[test]
+fn test_send_when_reachable() {
+    use foxbox_taxonomy::offline_queue::SendStatus;
+    use std::time::Duration;
+    println!("");
+
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    let service_id = Id::<ServiceId>::new("service id");
+    let mode_id = Id::<Channel>::new("setter mode");
+    let wake_id = Id::<Channel>::new("setter wake");
+
+    let adapter = FakeAdapter::new(&adapter_id);
+    let tweak = adapter.get_tweak();
+    let rx_adapter = adapter.take_rx();
+    manager.add_adapter(Arc::new(adapter)).unwrap();
+    manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+    for id in &[&mode_id, &wake_id] {
+        manager.add_channel(Channel {
+            id: (*id).clone(),
+            service: service_id.clone(),
+            adapter: adapter_id.clone(),
+            feature: Id::new("x-heater/mode"),
+            supports_send: Some(Signature::accepts(Maybe::Required(format::STRING.clone()))),
+            .. Channel::default()
+        }).unwrap();
+    }
+    let send = |id: &Id<Channel>, mode: &str| {
+        let payload = Payload::from_value(&Value::new(mode.to_owned()), &format::STRING).unwrap();
+        target_map(vec![(vec![ChannelSelector::new().with_id(id)], payload)])
+    };
+    let ttl = Duration::from_secs(3600);
+
+    println!("* Values sent to an unreachable device fail by default.");
+    tweak(Tweak::InjectSetterError(mode_id.clone(), Some(Error::Unreachable(mode_id.clone()))));
+    let data = manager.send_values(send(&mode_id, "eco"), User::None);
+    assert_matches!(data.get(&mode_id), Some(&Err(Error::Unreachable(_))));
+
+    println!("* Values sent when reachable are queued instead.");
+    let data = manager.send_values_when_reachable(send(&mode_id, "eco"), User::None, ttl);
+    assert_eq!(data.get(&mode_id), Some(&Ok(SendStatus::Queued)));
+    let data = manager.send_values_when_reachable(send(&mode_id, "comfort"), User::None, ttl);
+    assert_eq!(data.get(&mode_id), Some(&Ok(SendStatus::Queued)));
+    assert_matches!(rx_adapter.try_recv(), Err(_));
+
+    println!("* Queued values are delivered once the adapter reports the device reachable.");
+    tweak(Tweak::InjectSetterError(mode_id.clone(), None));
+    manager.service_reachable(&service_id);
+    let Effect::ValueSent(id, value) = rx_adapter.try_recv().unwrap();
+    assert_eq!(id, mode_id);
+    assert_eq!(&**value.cast::<String>().unwrap(), "comfort");
+    assert_matches!(rx_adapter.try_recv(), Err(_));
+    manager.service_reachable(&service_id);
+    assert_matches!(rx_adapter.try_recv(), Err(_));
+
+    println!("* Queued values are delivered once another channel of the device succeeds.");
+    tweak(Tweak::InjectSetterError(mode_id.clone(), Some(Error::Unreachable(mode_id.clone()))));
+    let data = manager.send_values_when_reachable(send(&mode_id, "eco"), User::None, ttl);
+    assert_eq!(data.get(&mode_id), Some(&Ok(SendStatus::Queued)));
+    tweak(Tweak::InjectSetterError(mode_id.clone(), None));
+    let data = manager.send_values_when_reachable(send(&wake_id, "now"), User::None, ttl);
+    assert_eq!(data.get(&wake_id), Some(&Ok(SendStatus::Delivered)));
+    let sent: HashSet<_> = (0..2).map(|_| {
+        let Effect::ValueSent(id, _) = rx_adapter.try_recv().unwrap();
+        id
+    }).collect();
+    assert!(sent.contains(&mode_id) && sent.contains(&wake_id));
+}
+
+#[test]
+fn test_send_atomic() {
+    println!("");
+
+    let manager = AdapterManager::new(None);
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let id_2 = Id::<AdapterId>::new("adapter id 2");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let service_id_2 = Id::<ServiceId>::new("service id 2");
+    let lamp_id = Id::<Channel>::new("lamp mode");
+    let heater_id = Id::<Channel>::new("heater mode");
+
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let adapter_2 = FakeAdapter::new(&id_2);
+    let tweak_1 = adapter_1.get_tweak();
+    let tweak_2 = adapter_2.get_tweak();
+    let rx_adapter_1 = adapter_1.take_rx();
+    let rx_adapter_2 = adapter_2.take_rx();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_adapter(Arc::new(adapter_2)).unwrap();
+    manager.add_service(Service::empty(&service_id_1, &id_1)).unwrap();
+    manager.add_service(Service::empty(&service_id_2, &id_2)).unwrap();
+    manager.add_channel(Channel {
+        id: lamp_id.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        feature: Id::new("x-lamp/mode"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::STRING.clone()))),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::STRING.clone()))),
+        .. Channel::default()
+    }).unwrap();
+    manager.add_channel(Channel {
+        id: heater_id.clone(),
+        service: service_id_2.clone(),
+        adapter: id_2.clone(),
+        feature: Id::new("x-heater/mode"),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::STRING.clone()))),
+        .. Channel::default()
+    }).unwrap();
+    let scene = || {
+        let payload = |mode: &str| {
+            Payload::from_value(&Value::new(mode.to_owned()), &format::STRING).unwrap()
+        };
+        target_map(vec![(vec![ChannelSelector::new().with_id(&lamp_id)], payload("dim")),
+                        (vec![ChannelSelector::new().with_id(&heater_id)], payload("eco"))])
+    };
+    let sent = |rx: &Receiver<Effect>| {
+        let mut sent = vec![];
+        while let Ok(Effect::ValueSent(id, value)) = rx.try_recv() {
+            sent.push((id, value.cast::<String>().unwrap().to_string()));
+        }
+        sent
+    };
+    tweak_1(Tweak::InjectGetterValue(lamp_id.clone(), Ok(Some(Value::new("bright".to_owned())))));
+
+    println!("* If a value cannot be sent, the values that were sent are reverted.");
+    tweak_2(Tweak::InjectSetterError(heater_id.clone(), Some(Error::Unreachable(heater_id.clone()))));
+    let data = manager.send_values_atomic(scene(), User::None);
+    assert_eq!(data.len(), 2);
+    assert_matches!(data.get(&heater_id), Some(&Err(Error::Unreachable(_))));
+    assert_matches!(data.get(&lamp_id), Some(&Err(Error::TransactionAborted)));
+    assert_eq!(sent(&rx_adapter_1), vec![(lamp_id.clone(), "dim".to_owned()),
+                                         (lamp_id.clone(), "bright".to_owned())]);
+    assert_eq!(sent(&rx_adapter_2), vec![]);
+
+    println!("* Otherwise, all the values are sent.");
+    tweak_2(Tweak::InjectSetterError(heater_id.clone(), None));
+    let data = manager.send_values_atomic(scene(), User::None);
+    assert_eq!(data.len(), 2);
+    assert!(data.values().all(Result::is_ok));
+    assert_eq!(sent(&rx_adapter_1), vec![(lamp_id.clone(), "dim".to_owned())]);
+    assert_eq!(sent(&rx_adapter_2), vec![(heater_id.clone(), "eco".to_owned())]);
+}
+
+#[test]
+fn test_select_by_property() {
+    println!("");
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    manager.add_adapter(Arc::new(FakeAdapter::new(&adapter_id))).unwrap();
+
+    let hue_id = Id::<ServiceId>::new("hue");
+    let lifx_id = Id::<ServiceId>::new("lifx");
+    let mut hue = Service::empty(&hue_id, &adapter_id);
+    hue.properties.insert("manufacturer".to_owned(), "Philips".to_owned());
+    hue.properties.insert("location".to_owned(), "Kitchen table".to_owned());
+    let mut lifx = Service::empty(&lifx_id, &adapter_id);
+    lifx.properties.insert("manufacturer".to_owned(), "LIFX".to_owned());
+    manager.add_service(hue).unwrap();
+    manager.add_service(lifx).unwrap();
+
+    let select = |predicates: Vec<PropertyPredicate>| -> Vec<Id<ServiceId>> {
+        manager.get_services(vec![ServiceSelector::new().with_properties(predicates)])
+            .into_iter()
+            .map(|service| service.id)
+            .collect()
+    };
+
+    println!("* Services can be selected by the exact value of a property.");
+    assert_eq!(select(vec![PropertyPredicate::equals("manufacturer", "Philips")]),
+               vec![hue_id.clone()]);
+    assert!(select(vec![PropertyPredicate::equals("manufacturer", "philips")]).is_empty());
+
+    println!("* Services can be selected by part of a property, ignoring case.");
+    assert_eq!(select(vec![PropertyPredicate::contains("location", "kitchen")]),
+               vec![hue_id.clone()]);
+
+    println!("* Services without the property are rejected.");
+    assert_eq!(select(vec![PropertyPredicate::contains("location", "")]), vec![hue_id.clone()]);
+
+    println!("* All the predicates must match.");
+    assert!(select(vec![PropertyPredicate::equals("manufacturer", "LIFX"),
+                        PropertyPredicate::contains("location", "kitchen")])
+        .is_empty());
+
+    println!("* Predicates can be parsed from JSON.");
+    let selector = ServiceSelector::from_str(r#"{"properties": [{"property": "manufacturer",
+                                                                 "equals": "LIFX"}]}"#)
+        .unwrap();
+    let services = manager.get_services(vec![selector]);
+    assert_eq!(services.len(), 1);
+    assert_eq!(services[0].id, lifx_id);
+    assert!(ServiceSelector::from_str(r#"{"properties": [{"property": "manufacturer"}]}"#)
+        .is_err());
+}
+
+#[test]
+fn test_update_service_properties() {
+    println!("");
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    manager.add_adapter(Arc::new(FakeAdapter::new(&adapter_id))).unwrap();
+
+    let service_id = Id::<ServiceId>::new("node");
+    let mut service = Service::empty(&service_id, &adapter_id);
+    service.properties.insert("name".to_owned(), "".to_owned());
+    service.properties.insert("manufacturer_name".to_owned(), "Aeotec".to_owned());
+    manager.add_service(service).unwrap();
+
+    println!("* Properties can be updated once the service is registered.");
+    let mut properties = HashMap::new();
+    properties.insert("name".to_owned(), "Hall sensor".to_owned());
+    properties.insert("location".to_owned(), "Hall".to_owned());
+    manager.update_service_properties(&service_id, properties).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(&service_id)]);
+    assert_eq!(services[0].properties.get("name"), Some(&"Hall sensor".to_owned()));
+    assert_eq!(services[0].properties.get("location"), Some(&"Hall".to_owned()));
+
+    println!("* Properties that are not mentioned are left unchanged.");
+    assert_eq!(services[0].properties.get("manufacturer_name"), Some(&"Aeotec".to_owned()));
+
+    println!("* Updating the properties of an unknown service fails.");
+    assert!(manager.update_service_properties(&Id::new("unknown"), HashMap::new()).is_err());
+}
+
+#[test]
+fn test_adapter_health() {
+    use foxbox_taxonomy::health::AdapterHealth;
+
+    println!("");
+    let manager = AdapterManager::new(None);
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let id_2 = Id::<AdapterId>::new("adapter id 2");
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let tweak_1 = adapter_1.get_tweak();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_2))).unwrap();
+
+    let (tx, rx) = channel();
+    manager.watch_adapter_health(Box::new(tx));
+
+    println!("* Adapters answering their heartbeat are healthy.");
+    manager.check_adapter_health();
+    let health = manager.adapter_health();
+    assert_eq!(health.get(&id_1), Some(&AdapterHealth::Healthy));
+    assert_eq!(health.get(&id_2), Some(&AdapterHealth::Healthy));
+    rx.recv().unwrap();
+    rx.recv().unwrap();
+
+    println!("* Adapters failing their heartbeat are unhealthy, and watchers are told.");
+    tweak_1(Tweak::InjectHeartbeatError(Some(Error::Internal(InternalError::GenericError("Stuck".to_owned())))));
+    manager.check_adapter_health();
+    manager.check_adapter_health();
+    assert_eq!(rx.recv().unwrap().adapter, id_1);
+    let event = rx.recv().unwrap();
+    assert_eq!(event.adapter, id_1);
+    assert_matches!(event.health, AdapterHealth::Unhealthy { failures: 2, .. });
+    assert_eq!(manager.adapter_health().get(&id_2), Some(&AdapterHealth::Healthy));
+
+    println!("* Unhealthy adapters recover once they answer again.");
+    tweak_1(Tweak::InjectHeartbeatError(None));
+    manager.check_adapter_health();
+    assert_eq!(rx.recv().unwrap().health, AdapterHealth::Healthy);
+
+    println!("* Stopped adapters are forgotten.");
+    manager.stop_adapter(&id_1).unwrap();
+    manager.check_adapter_health();
+    assert!(manager.adapter_health().get(&id_1).is_none());
+    assert_eq!(manager.adapter_ids(), vec![id_2.clone()]);
+}
+
+#[test]
+fn test_watch_polled() {
+    use std::time::Duration;
+
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    let service_id = Id::<ServiceId>::new("service id");
+    let getter_id = Id::<Channel>::new("getter id");
+
+    let adapter = FakeAdapter::new(&adapter_id);
+    let tweak = adapter.get_tweak();
+    manager.add_adapter(Arc::new(adapter)).unwrap();
+    manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+    manager.add_channel(Channel {
+        id: getter_id.clone(),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        feature: Id::new("light/is-on"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    }).unwrap();
+    tweak(Tweak::InjectGetterValue(getter_id.clone(), Ok(Some(Value::new(OnOff::Off)))));
+
+    let on = Payload::from_value(&Value::new(OnOff::On), &format::ON_OFF).unwrap();
+    let watch = |poll| vec![Targetted {
+        select: vec![ChannelSelector::new().with_id(&getter_id)],
+        payload: (Exactly::Exactly(on.clone()), None, Delivery::Full, poll),
+    }];
+
+    println!("* Without a polling interval, channels that can't be watched are ignored.");
+    let (tx_ignored, rx_ignored) = channel();
+    let _ignored = manager.watch_values_filtered(watch(None), Box::new(tx_ignored), User::None);
+
+    println!("* With a polling interval, changes are fetched and reported as enter/exit.");
+    let (tx_watch, rx_watch) = channel();
+    let guard = manager.watch_values_filtered(watch(Some(Duration::from_millis(50))),
+                                              Box::new(tx_watch),
+                                              User::None);
+    tweak(Tweak::InjectGetterValue(getter_id.clone(), Ok(Some(Value::new(OnOff::On)))));
+    match rx_watch.recv().unwrap() {
+        Event::EnterRange { ref channel, ref value, observed, .. } if *channel == getter_id => {
+            assert_eq!(*value, on);
+            assert_eq!(observed.source, Source::Fetch);
+        }
+        other => panic!("Unexpected event {:?}", other)
+    }
+    tweak(Tweak::InjectGetterValue(getter_id.clone(), Ok(Some(Value::new(OnOff::Off)))));
+    match rx_watch.recv().unwrap() {
+        Event::ExitRange { ref channel, .. } if *channel == getter_id => { }
+        other => panic!("Unexpected event {:?}", other)
+    }
+
+    println!("* Values that don't change are only reported once.");
+    thread::sleep(Duration::from_millis(200));
+    assert_matches!(rx_watch.try_recv(), Err(_));
+    assert_matches!(rx_ignored.try_recv(), Err(_));
+
+    println!("* Polling stops once the watch is dropped.");
+    drop(guard);
+    thread::sleep(Duration::from_millis(100));
+    tweak(Tweak::InjectGetterValue(getter_id.clone(), Ok(Some(Value::new(OnOff::On)))));
+    thread::sleep(Duration::from_millis(200));
+    assert_matches!(rx_watch.try_recv(), Err(_));
+}
+
+#[test]
+fn test_add_service_with_channels() {
+    println!("");
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    manager.add_adapter(Arc::new(FakeAdapter::new(&adapter_id))).unwrap();
+    let channel = |id: &str| Channel {
+        id: Id::new(id),
+        feature: Id::new("light/is-on"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    };
+
+    println!("* A service is added along with all its channels.");
+    let lamp_id = Id::<ServiceId>::new("lamp");
+    manager.add_service_with_channels(ServiceBuilder::new(Service::empty(&lamp_id, &adapter_id))
+            .with_property("model", "Lamp 2000")
+            .with_channel(channel("lamp/is-on"))
+            .with_channel(channel("lamp/power")))
+        .unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(&lamp_id)]);
+    assert_eq!(services.len(), 1);
+    assert_eq!(services[0].channels.len(), 2);
+    assert_eq!(services[0].properties.get("model"), Some(&"Lamp 2000".to_owned()));
+
+    println!("* If a channel can't be added, nothing is added.");
+    let plug_id = Id::<ServiceId>::new("plug");
+    let plug = ServiceBuilder::new(Service::empty(&plug_id, &adapter_id))
+        .with_channel(channel("plug/is-on"))
+        .with_channel(channel("lamp/power"));
+    let result = manager.add_service_with_channels(plug);
+    assert!(result.is_err());
+    assert!(manager.get_services(vec![ServiceSelector::new().with_id(&plug_id)]).is_empty());
+    assert!(manager.get_channels(vec![ChannelSelector::new().with_id(&Id::new("plug/is-on"))])
+        .is_empty());
+
+    println!("* The channels of the other services are left alone.");
+    assert_eq!(manager.get_channels(vec![ChannelSelector::new().with_parent(&lamp_id)]).len(),
+               2);
+}
+
+#[test]
+fn test_history() {
+    use foxbox_taxonomy::history::HistoryRange;
+    use serde_json::value::Value as JSON;
+    use std::time::Duration;
+
+    println!("");
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    let service_id = Id::<ServiceId>::new("service id");
+    let recorded_id = Id::<Channel>::new("getter recorded");
+    let plain_id = Id::<Channel>::new("getter plain");
+
+    let adapter = FakeAdapter::new(&adapter_id);
+    let tweak = adapter.get_tweak();
+    manager.add_adapter(Arc::new(adapter)).unwrap();
+    manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+    let channel = Channel {
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        feature: Id::new("light/is-on"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    };
+    manager.add_channel(Channel {
+        id: recorded_id.clone(),
+        record_history: true,
+        .. channel.clone()
+    }).unwrap();
+    manager.add_channel(Channel {
+        id: plain_id.clone(),
+        .. channel.clone()
+    }).unwrap();
+
+    println!("* Initially, the history of a channel that records it is empty.");
+    let history = manager.get_history(vec![ChannelSelector::new()], HistoryRange::default());
+    assert_eq!(history.len(), 1);
+    assert!(history.get(&recorded_id).unwrap().as_ref().unwrap().is_empty());
+
+    println!("* Fetched values are recorded, oldest first.");
+    for value in vec![OnOff::On, OnOff::Off] {
+        tweak(Tweak::InjectGetterValue(recorded_id.clone(), Ok(Some(Value::new(value.clone())))));
+        tweak(Tweak::InjectGetterValue(plain_id.clone(), Ok(Some(Value::new(value)))));
+        manager.fetch_values(vec![ChannelSelector::new()], User::None);
+        thread::sleep(Duration::from_millis(10));
+    }
+    let history = manager.get_history(vec![ChannelSelector::new()], HistoryRange::default());
+    assert_eq!(history.len(), 1);
+    let entries = history.get(&recorded_id).unwrap().as_ref().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].timestamp < entries[1].timestamp);
+    assert_eq!(entries[0].value, JSON::String("On".to_owned()));
+    assert_eq!(entries[1].value, JSON::String("Off".to_owned()));
+
+    println!("* The history can be restricted to a range.");
+    let range = HistoryRange {
+        from: Some(entries[1].timestamp),
+        to: None,
+    };
+    let history = manager.get_history(vec![ChannelSelector::new().with_id(&recorded_id)], range);
+    assert_eq!(history.get(&recorded_id).unwrap().as_ref().unwrap().len(), 1);
+}
+
+#[test]
+fn test_watch_user() {
+    use foxbox_taxonomy::api::Operation;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    static VERSION: [u32; 4] = [0, 0, 0, 0];
+
+    /// An adapter recording on behalf of whom each channel is watched.
+    struct UserAdapter {
+        id: Id<AdapterId>,
+        users: Arc<Mutex<Vec<(Id<Channel>, User)>>>,
+    }
+    impl Adapter for UserAdapter {
+        fn id(&self) -> Id<AdapterId> {
+            self.id.clone()
+        }
+        fn name(&self) -> &str {
+            "User adapter"
+        }
+        fn vendor(&self) -> &str {
+            "test@foxlink"
+        }
+        fn version(&self) -> &[u32; 4] {
+            &VERSION
+        }
+        fn register_watch(&self, watch: Vec<WatchTarget>, user: User) -> WatchResult {
+            watch.into_iter()
+                .map(|(id, _, _)| {
+                    self.users.lock().unwrap().push((id.clone(), user.clone()));
+                    (id.clone(), Err(Error::OperationNotSupported(Operation::Watch, id)))
+                })
+                .collect()
+        }
+    }
+
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    let service_id = Id::<ServiceId>::new("service id");
+    let getter_id_1 = Id::<Channel>::new("getter id 1");
+    let getter_id_2 = Id::<Channel>::new("getter id 2");
+    let users = Arc::new(Mutex::new(vec![]));
+
+    manager.add_adapter(Arc::new(UserAdapter {
+        id: adapter_id.clone(),
+        users: users.clone(),
+    })).unwrap();
+    manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+    let getter = |id: &Id<Channel>| Channel {
+        id: id.clone(),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        supports_watch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        .. LIGHT_IS_ON.clone()
+    };
+    manager.add_channel(getter(&getter_id_1)).unwrap();
+
+    // Watches are registered with the adapters in the background.
+    let wait_for = |count| {
+        for _ in 0..100 {
+            if users.lock().unwrap().len() == count {
+                return users.lock().unwrap().clone();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("The watches were not registered");
+    };
+
+    println!("* Watches are registered with the adapter on behalf of their user.");
+    let user = User::Id("1".to_owned());
+    let (tx_watch, _rx_watch) = channel();
+    let _guard = manager.watch_values(target_map(vec![(vec![ChannelSelector::new()], Exactly::Always)]),
+                                      Box::new(tx_watch), user.clone());
+    assert_eq!(wait_for(1), vec![(getter_id_1.clone(), user.clone())]);
+
+    println!("* Channels added later are watched on behalf of the same user.");
+    manager.add_channel(getter(&getter_id_2)).unwrap();
+    assert_eq!(wait_for(2)[1], (getter_id_2.clone(), user.clone()));
+
+    println!("* Watches without a user are registered without a user.");
+    let (tx_watch, _rx_watch) = channel();
+    let _guard = manager.watch_values(target_map(vec![(vec![ChannelSelector::new().with_id(&getter_id_1)], Exactly::Always)]),
+                                      Box::new(tx_watch), User::None);
+    assert_eq!(wait_for(3)[2], (getter_id_1.clone(), User::None));
+}
+
+/// An adapter whose sends only complete once the test releases them.
+struct WedgedAdapter {
+    id: Id<AdapterId>,
+    release: std::sync::Mutex<std::sync::mpsc::Receiver<()>>,
+}
+
+impl Adapter for WedgedAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        self.id.clone()
+    }
+    fn name(&self) -> &str {
+        "wedged"
+    }
+    fn vendor(&self) -> &str {
+        "test@foxlink"
+    }
+    fn version(&self) -> &[u32; 4] {
+        &[0, 0, 0, 0]
+    }
+    fn send_values(&self, mut op: HashMap<Id<Channel>, Value>, _: User) -> ResultMap<Id<Channel>, (), Error> {
+        self.release.lock().unwrap().recv().unwrap();
+        op.drain().map(|(id, _)| (id, Ok(()))).collect()
+    }
+}
+
+#[test]
+fn test_abort_operation() {
+    use foxbox_taxonomy::api::Operation;
+    println!("");
+
+    let manager = Arc::new(AdapterManager::new(None));
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    let service_id = Id::<ServiceId>::new("service id");
+    let setter_id = Id::<Channel>::new("setter id");
+    let (tx_release, rx_release) = std::sync::mpsc::channel();
+
+    manager.add_adapter(Arc::new(WedgedAdapter {
+        id: adapter_id.clone(),
+        release: std::sync::Mutex::new(rx_release),
+    })).unwrap();
+    manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+    manager.add_channel(Channel {
+        id: setter_id.clone(),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        feature: Id::new("light/is-on"),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    }).unwrap();
+
+    println!("* Nothing is in progress yet.");
+    assert!(manager.pending_operations().is_empty());
+    assert!(!manager.abort_operation(1));
+
+    println!("* A send to a wedged device is listed with its channels and user.");
+    let user = User::Id("1".to_owned());
+    let (tx, rx) = std::sync::mpsc::channel();
+    {
+        let manager = manager.clone();
+        let user = user.clone();
+        thread::spawn(move || {
+            let data_on = Payload::from_value(&Value::new(OnOff::On), &format::ON_OFF).unwrap();
+            let results = manager.send_values(target_map(vec![(vec![ChannelSelector::new()], data_on)]), user);
+            tx.send(results).unwrap();
+        });
+    }
+    let mut pending = vec![];
+    for _ in 0..500 {
+        pending = manager.pending_operations();
+        if !pending.is_empty() {
+            break;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(pending.len(), 1);
+    let operation = pending[0].clone();
+    assert_eq!(operation.adapter, adapter_id);
+    assert_eq!(operation.operation, Operation::Send);
+    assert_eq!(operation.channels, vec![setter_id.clone()]);
+    assert_eq!(operation.user, user);
+    assert!(!operation.aborted);
+    assert!(rx.try_recv().is_err());
+
+    println!("* Aborting the send releases the caller.");
+    assert!(manager.abort_operation(operation.id));
+    let results = rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_matches!(results.get(&setter_id), Some(&Err(Error::OperationAborted)));
+    assert!(!manager.abort_operation(operation.id));
+
+    println!("* The send remains listed until the adapter returns.");
+    let pending = manager.pending_operations();
+    assert_eq!(pending.len(), 1);
+    assert!(pending[0].aborted);
+    tx_release.send(()).unwrap();
+    for _ in 0..500 {
+        if manager.pending_operations().is_empty() {
+            return;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    panic!("The aborted send was never forgotten");
+}
+
+#[test]
+fn test_send_fails_when_the_thread_pool_is_saturated() {
+    println!("");
+
+    let manager = AdapterManager::new(None);
+    let data_on = Payload::from_value(&Value::new(OnOff::On), &format::ON_OFF).unwrap();
+    let add_setter = |adapter: &str, setter: &str| {
+        let adapter_id = Id::<AdapterId>::new(adapter);
+        let service_id = Id::<ServiceId>::new(&format!("service of {}", adapter));
+        manager.add_adapter(Arc::new(FakeAdapter::new(&adapter_id))).unwrap();
+        manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+        manager.add_channel(Channel {
+            id: Id::new(setter),
+            service: service_id,
+            adapter: adapter_id,
+            feature: Id::new("light/is-on"),
+            supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+            .. Channel::default()
+        }).unwrap();
+        Id::<Channel>::new(setter)
+    };
+
+    println!("* Calls to adapters run on the threads of each adapter.");
+    let setter_id_1 = add_setter("adapter id 1", "setter id 1");
+    let results = manager.send_values(target_map(vec![(vec![ChannelSelector::new()], data_on.clone())]), User::None);
+    assert_matches!(results.get(&setter_id_1), Some(&Ok(())));
+
+    println!("* Calls that can't be queued fail instead of piling up.");
+    manager.set_adapter_executors(1, 0);
+    let setter_id_2 = add_setter("adapter id 2", "setter id 2");
+    let results = manager.send_values(target_map(vec![(vec![ChannelSelector::new()], data_on)]), User::None);
+    assert_matches!(results.get(&setter_id_1), Some(&Ok(())));
+    assert_matches!(results.get(&setter_id_2), Some(&Err(Error::Internal(InternalError::GenericError(_)))));
+}
+
+#[test]
+fn test_wedged_adapter_times_out_without_starving_the_others() {
+    println!("");
+
+    let manager = Arc::new(AdapterManager::new(None));
+    manager.set_adapter_executors(1, 1);
+    manager.set_call_timeout(std::time::Duration::from_millis(200));
+    let wedged_id = Id::<AdapterId>::new("wedged adapter");
+    let fake_id = Id::<AdapterId>::new("fake adapter");
+    let (tx_release, rx_release) = std::sync::mpsc::channel();
+    manager.add_adapter(Arc::new(WedgedAdapter {
+        id: wedged_id.clone(),
+        release: std::sync::Mutex::new(rx_release),
+    })).unwrap();
+    manager.add_adapter(Arc::new(FakeAdapter::new(&fake_id))).unwrap();
+    for (adapter_id, setter) in vec![(wedged_id, "wedged setter"), (fake_id, "fake setter")] {
+        let service_id = Id::<ServiceId>::new(setter);
+        manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+        manager.add_channel(Channel {
+            id: Id::new(setter),
+            service: service_id,
+            adapter: adapter_id,
+            feature: Id::new("light/is-on"),
+            supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+            .. Channel::default()
+        }).unwrap();
+    }
+    let wedged_setter = Id::<Channel>::new("wedged setter");
+    let fake_setter = Id::<Channel>::new("fake setter");
+    let data_on = Payload::from_value(&Value::new(OnOff::On), &format::ON_OFF).unwrap();
+
+    println!("* A call to a wedged adapter times out, the others answer.");
+    let results = manager.send_values(target_map(vec![(vec![ChannelSelector::new()], data_on.clone())]), User::None);
+    assert_matches!(results.get(&wedged_setter), Some(&Err(Error::Internal(InternalError::GenericError(_)))));
+    assert_matches!(results.get(&fake_setter), Some(&Ok(())));
+
+    println!("* While the wedged adapter holds its threads, the others are still called.");
+    let selector = ChannelSelector::new().with_id(&fake_setter);
+    let results = manager.send_values(target_map(vec![(vec![selector], data_on)]), User::None);
+    assert_matches!(results.get(&fake_setter), Some(&Ok(())));
+
+    tx_release.send(()).unwrap();
+}
+
+#[test]
+fn test_select_by_id() {
+    println!("");
+
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let service_id_2 = Id::<ServiceId>::new("service id 2");
+    let getter_id = Id::<Channel>::new("getter id");
+    let setter_id = Id::<Channel>::new("setter id");
+    manager.add_adapter(Arc::new(FakeAdapter::new(&adapter_id))).unwrap();
+    manager.add_service(Service::empty(&service_id_1, &adapter_id)).unwrap();
+    manager.add_service(Service::empty(&service_id_2, &adapter_id)).unwrap();
+    manager.add_channel(Channel {
+        id: getter_id.clone(),
+        service: service_id_1.clone(),
+        adapter: adapter_id.clone(),
+        feature: Id::new("light/is-on"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    }).unwrap();
+    manager.add_channel(Channel {
+        id: setter_id.clone(),
+        service: service_id_1.clone(),
+        adapter: adapter_id.clone(),
+        feature: Id::new("light/is-on"),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    }).unwrap();
+
+    println!("* Selectors by id only select the objects with that id, once.");
+    let by_id = ChannelSelector::new().with_id(&getter_id);
+    let channels = manager.get_channels(vec![by_id.clone(), by_id.clone()]);
+    assert_eq!(channels.len(), 1);
+    assert_eq!(channels[0].id, getter_id);
+    let channels = manager.get_channels(vec![by_id.clone(),
+                                             ChannelSelector::new().with_id(&setter_id)]);
+    assert_eq!(channels.len(), 2);
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(&service_id_2)]);
+    assert_eq!(services.len(), 1);
+    assert_eq!(services[0].id, service_id_2);
+
+    println!("* The other criteria of the selectors still apply.");
+    let channels = manager.get_channels(vec![by_id.clone().with_feature(&Id::new("light/color"))]);
+    assert!(channels.is_empty());
+    let channels = manager.get_channels(vec![ChannelSelector::new()
+                                                 .with_id(&Id::new("unknown id"))]);
+    assert!(channels.is_empty());
+
+    println!("* Selectors by id may be mixed with other selectors.");
+    let by_feature = ChannelSelector::new().with_feature(&Id::new("light/is-on"));
+    let channels = manager.get_channels(vec![by_id, by_feature]);
+    assert_eq!(channels.len(), 2);
+}
@@ -5,11 +5,14 @@
 //! and module `run` to execute it.
 
 use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::filter::Filter;
 use foxbox_taxonomy::io::*;
 use foxbox_taxonomy::selector::*;
 use foxbox_taxonomy::services::*;
 use foxbox_taxonomy::values::*;
 
+use chrono::{Datelike, Duration as ChronoDuration};
+
 use std::marker::PhantomData;
 
 /// A thinkerbell scrip"t.
@@ -41,7 +44,7 @@ impl Parser<Script<UncheckedCtx>> for Script<UncheckedCtx> {
 }
 
 /// A single rule, i.e. "when some condition becomes true, do
-/// something".
+/// something, and once it stops being true, do something else".
 ///
 /// # JSON
 ///
@@ -50,7 +53,16 @@ impl Parser<Script<UncheckedCtx>> for Script<UncheckedCtx> {
 /// - conditions (array of Match): the conditions in which to execute
 ///   the code – *all* conditions must be met;
 /// - execute (array of Statement): the code to execute once all conditions
-///   are met.
+///   are met;
+/// - otherwise (array of Statement, optional): the code to execute once the
+///   conditions were all met and stop being met;
+/// - sustained_for (Duration, optional): if provided, `execute` only happens
+///   once all the conditions have held for `sustained_for`;
+/// - within (Duration, optional): if provided, the conditions are only met if
+///   they all became met at most `within` apart from each other, e.g. motion
+///   detected less than 30 seconds after the door opened;
+/// - cooldown (Duration, optional): if provided, `execute` doesn't happen
+///   again until `cooldown` has elapsed since it last happened.
 ///
 /// ```
 /// extern crate foxbox_thinkerbell;
@@ -71,10 +83,17 @@ impl Parser<Script<UncheckedCtx>> for Script<UncheckedCtx> {
 ///     "destination": [{"id": "my setter"}],
 ///     "value": "Off",
 ///     "feature": "light/is-on"
-///   }]
+///   }],
+///   "otherwise": [{
+///     "destination": [{"id": "my setter"}],
+///     "value": "On",
+///     "feature": "light/is-on"
+///   }],
+///   "cooldown": 600
 /// }"#;
 ///
-/// Rule::<UncheckedCtx>::from_str(&source).unwrap();
+/// let rule = Rule::<UncheckedCtx>::from_str(&source).unwrap();
+/// assert_eq!(rule.otherwise.len(), 1);
 /// # }
 /// ```
 #[derive(Debug)]
@@ -89,6 +108,26 @@ pub struct Rule<Ctx>
     /// Stuff to do once `condition` is met.
     pub execute: Vec<Statement<Ctx>>,
 
+    /// Stuff to do once `condition` was met and stops being met, e.g.
+    /// when one of the `Match` branches becomes false or its getters are
+    /// removed. May be empty.
+    pub otherwise: Vec<Statement<Ctx>>,
+
+    /// If specified, `execute` only happens once `conditions` has
+    /// remained true for `sustained_for`. Unlike `Match::duration`,
+    /// this applies to all the `Match` branches together.
+    pub sustained_for: Option<Duration>,
+
+    /// If specified, `conditions` only becomes true if all the `Match`
+    /// branches became true at most `within` apart from each other.
+    pub within: Option<Duration>,
+
+    /// If specified, `execute` doesn't happen again until `cooldown` has
+    /// elapsed, even if `conditions` becomes true again meanwhile. This
+    /// is useful to avoid firing on every transient event, e.g. of
+    /// motion sensors.
+    pub cooldown: Option<Duration>,
+
     pub phantom: PhantomData<Ctx>,
 }
 impl Parser<Rule<UncheckedCtx>> for Rule<UncheckedCtx> {
@@ -101,9 +140,41 @@ impl Parser<Rule<UncheckedCtx>> for Rule<UncheckedCtx> {
                                         |path| Match::take_vec(path, source, "conditions")));
         let execute = try!(path.push("execute",
                                      |path| Statement::take_vec(path, source, "execute")));
+        let otherwise = match path.push("otherwise", |path| {
+            Statement::take_vec_opt(path, source, "otherwise")
+        }) {
+            Some(Ok(otherwise)) => otherwise,
+            Some(Err(err)) => return Err(err),
+            None => vec![],
+        };
+        let sustained_for = match path.push("sustained_for", |path| {
+            Duration::take_opt(path, source, "sustained_for")
+        }) {
+            Some(Ok(sustained_for)) => Some(sustained_for),
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
+        let within = match path.push("within", |path| {
+            Duration::take_opt(path, source, "within")
+        }) {
+            Some(Ok(within)) => Some(within),
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
+        let cooldown = match path.push("cooldown", |path| {
+            Duration::take_opt(path, source, "cooldown")
+        }) {
+            Some(Ok(cooldown)) => Some(cooldown),
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
         Ok(Rule {
             conditions: conditions,
             execute: execute,
+            otherwise: otherwise,
+            sustained_for: sustained_for,
+            within: within,
+            cooldown: cooldown,
             phantom: PhantomData,
         })
     }
@@ -121,14 +192,30 @@ impl Parser<Rule<UncheckedCtx>> for Rule<UncheckedCtx> {
 ///
 /// A match is represented as an object with the following fields:
 ///
-/// - source (array of ChannelSelector) - the selector for getters that will
-///   provide the data;
-/// - feature (string) - the kind of channels;
-/// - condition (JSON) - the condition in whih the match is considered met –
-///   a match becomes met when any of the sources *enters* the range;
+/// - source (array of ChannelSelector, optional if there is a `schedule` or a
+///   `state`) - the selector for getters that will provide the data;
+/// - feature (string, optional if there is a `schedule` or a `state`) - the
+///   kind of channels;
+/// - when (JSON, optional if there is an `expression` or a `schedule`) - the
+///   condition in which the match is considered met – a match becomes met when
+///   any of the sources *enters* the range;
 /// - duration (Duration, optional) - if provided, the match is only considered
 ///   met if any of the sources *enters* and *remains* in the range
 ///   for `duration`
+/// - filter (Filter, optional) - if provided, values in the range are only
+///   considered if they are also accepted by the filter, e.g. to look into
+///   the fields of JSON values
+/// - expression (Test, optional) - if provided, values in the range are only
+///   considered if the test also holds, e.g. to compare them with the values
+///   of other getters
+/// - schedule (Schedule, optional) - if provided instead of `when`, the match
+///   is met during the minute that starts at each time of the schedule, e.g.
+///   `{"schedule": "30 7 * * 1-5"}` for every weekday at 7:30. The source
+///   defaults to the channels `clock/time-of-day-seconds`
+/// - state (string, optional) - if provided, the match looks at the value that
+///   the script stored under this key with a `set_state` statement instead of
+///   the values of getters, e.g. `{"state": "armed", "when": true}`. The
+///   `expression` then sees the stored value as `{"value": ""}`
 ///
 /// ```
 /// extern crate foxbox_thinkerbell;
@@ -167,8 +254,9 @@ pub struct Match<Ctx>
 
     /// The condition in which this match becomes valid. For instance,
     /// it may represent `OnOff::On` to trigger the `Statement` when some
-    /// device is set to `On`.
-    pub when: Payload,
+    /// device is set to `On`. If `None`, every value is considered, which
+    /// only makes sense with an `expression`.
+    pub when: Option<Payload>,
 
     /// If specified, the values must remain in the `range` for at least
     /// `duration` before the match is considered valid. This is useful
@@ -176,6 +264,23 @@ pub struct Match<Ctx>
     /// e.g. that a door has been forgotten open.
     pub duration: Option<Duration>,
 
+    /// If specified, values must also be accepted by this filter. This is
+    /// useful for channels that return rich JSON values.
+    pub filter: Option<Filter>,
+
+    /// If specified, values must also pass this test, evaluated whenever
+    /// the value or one of the getters of the test changes.
+    pub expression: Option<Test>,
+
+    /// If specified, the match is met at the times of this schedule, which
+    /// replaces `when`. The days of the schedule are checked when `source`
+    /// enters one of the `Schedule::ranges`.
+    pub schedule: Option<Schedule>,
+
+    /// If specified, the match looks at the value stored under this key in the state of
+    /// the script, see `Statement::set_state`, and `source` is empty.
+    pub state: Option<String>,
+
     pub phantom: PhantomData<Ctx>,
 }
 impl Parser<Match<UncheckedCtx>> for Match<UncheckedCtx> {
@@ -184,26 +289,641 @@ impl Parser<Match<UncheckedCtx>> for Match<UncheckedCtx> {
     }
 
     fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
-        let sources = try!(path.push("source",
-                                     |path| ChannelSelector::take_vec(path, source, "source")));
-        let feature = try!(path.push("feature", |path| Id::take(path, source, "feature")));
-        let when = try!(path.push("when", |path| Payload::take(path, source, "when")));
+        let schedule = match path.push("schedule",
+                                       |path| Schedule::take_opt(path, source, "schedule")) {
+            Some(Ok(schedule)) => Some(schedule),
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
+        let state = match path.push("state", |path| String::take_opt(path, source, "state")) {
+            Some(Ok(state)) => Some(state),
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
+        // A schedule watches the clock, unless told otherwise. A state doesn't watch anything.
+        let sources = if state.is_some() {
+            vec![]
+        } else if schedule.is_some() && source.find("source").is_none() {
+            vec![ChannelSelector::new()]
+        } else {
+            try!(path.push("source",
+                           |path| ChannelSelector::take_vec(path, source, "source")))
+        };
+        let feature = if state.is_some() {
+            Id::new(STATE_FEATURE)
+        } else if schedule.is_some() && source.find("feature").is_none() {
+            Id::new(SCHEDULE_FEATURE)
+        } else {
+            try!(path.push("feature", |path| Id::take(path, source, "feature")))
+        };
+        let when = match path.push("when", |path| Payload::take_opt(path, source, "when")) {
+            Some(Ok(when)) => Some(when),
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
         let duration =
             match path.push("duration", |path| Duration::take(path, source, "duration")) {
                 Err(ParseError::MissingField { .. }) => None,
                 Err(err) => return Err(err),
                 Ok(ok) => Some(ok),
             };
+        let filter = match path.push("filter", |path| Filter::take_opt(path, source, "filter")) {
+            Some(Ok(filter)) => Some(filter),
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
+        let expression = match path.push("expression",
+                                          |path| Test::take_opt(path, source, "expression")) {
+            Some(Ok(expression)) => Some(expression),
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
+        if when.is_some() && schedule.is_some() {
+            return Err(ParseError::type_error("when", &path, "no `when` with a `schedule`"));
+        }
+        if state.is_some() && schedule.is_some() {
+            return Err(ParseError::type_error("state", &path, "no `state` with a `schedule`"));
+        }
+        if when.is_none() && expression.is_none() && schedule.is_none() {
+            return Err(ParseError::missing_field("when", &path));
+        }
         Ok(Match {
             source: sources,
             feature: feature,
             when: when,
             duration: duration,
+            filter: filter,
+            expression: expression,
+            schedule: schedule,
+            state: state,
             phantom: PhantomData,
         })
     }
 }
 
+/// A test comparing two expressions, e.g. "the temperature is above the target temperature
+/// plus 2 degrees".
+///
+/// # JSON
+///
+/// A test is represented as an object `{op: [expression, expression]}`, where `op` is
+/// one of `Lt`, `Leq`, `Gt`, `Geq`, `Eq` or `Neq`.
+///
+/// An expression is either:
+///
+/// - a number;
+/// - `{"value": path}`, the value received from the source of the `Match`,
+///   where `path` is a dot-separated path into the value (e.g. `"C"`; an empty
+///   path designates the value itself);
+/// - `{"getter": [ChannelSelector], "feature": string, "field": path}`, the
+///   latest value received from any of these getters (`field` is optional);
+/// - `{op: [expression, expression]}`, where `op` is one of `+`, `-`, `*` or `/`.
+///
+/// A test doesn't hold if one of its expressions cannot be computed, e.g. because a
+/// getter hasn't sent any value yet or because a value is not a number.
+///
+/// ```
+/// extern crate foxbox_thinkerbell;
+/// extern crate foxbox_taxonomy;
+/// extern crate serde_json;
+///
+/// use foxbox_thinkerbell::ast::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// # fn main() {
+/// let source = r#"{
+///   "Gt": [{"value": "C"}, {"+": [
+///     {"getter": [{"id": "my thermostat"}], "feature": "thermostat/target-c", "field": "C"},
+///     2
+///   ]}]
+/// }"#;
+///
+/// let test = Test::from_str(&source).unwrap();
+/// assert_eq!(test.getters().len(), 1);
+///
+/// let warm = serde_json::from_str(r#"{"C": 23}"#).unwrap();
+/// let cold = serde_json::from_str(r#"{"C": 21}"#).unwrap();
+/// let target = Some(serde_json::from_str(r#"{"C": 20}"#).unwrap());
+/// assert!(test.evaluate(&warm, &[target.clone()]));
+/// assert!(!test.evaluate(&cold, &[target]));
+/// assert!(!test.evaluate(&warm, &[None]));
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Test {
+    pub comparison: Comparison,
+    pub left: Expression,
+    pub right: Expression,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Lt,
+    Leq,
+    Gt,
+    Geq,
+    Eq,
+    Neq,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// A number computed from the values of getters, see `Test`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression {
+    Number(f64),
+
+    /// A field of the value received from the source of the `Match`.
+    Value { path: Vec<String> },
+
+    /// A field of the latest value received from any of the getters of `source`.
+    Getter {
+        /// The position of this getter in `Test::getters`.
+        index: usize,
+        source: Vec<ChannelSelector>,
+        feature: Id<FeatureId>,
+        path: Vec<String>,
+    },
+
+    Binary {
+        operator: Operator,
+        left: Box<Expression>,
+        right: Box<Expression>,
+    },
+}
+
+impl Test {
+    /// Determine whether the test holds for `value`, given the latest value of each getter
+    /// of `getters()`, all serialized as JSON.
+    pub fn evaluate(&self, value: &JSON, getters: &[Option<JSON>]) -> bool {
+        let left = self.left.evaluate(value, getters);
+        let right = self.right.evaluate(value, getters);
+        match (left, right) {
+            (Some(left), Some(right)) => {
+                match self.comparison {
+                    Comparison::Lt => left < right,
+                    Comparison::Leq => left <= right,
+                    Comparison::Gt => left > right,
+                    Comparison::Geq => left >= right,
+                    Comparison::Eq => left == right,
+                    Comparison::Neq => left != right,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// The getters referenced by the test, other than the source of the `Match`, in the
+    /// order of `Expression::Getter::index`.
+    pub fn getters(&self) -> Vec<&Expression> {
+        let mut getters = Vec::new();
+        self.left.collect_getters(&mut getters);
+        self.right.collect_getters(&mut getters);
+        getters
+    }
+}
+
+impl Expression {
+    pub fn evaluate(&self, value: &JSON, getters: &[Option<JSON>]) -> Option<f64> {
+        let number_at = |json: Option<&JSON>, path: &[String]| {
+            let mut current = json;
+            for key in path {
+                current = current.and_then(|current| current.find(key));
+            }
+            current.and_then(JSON::as_f64)
+        };
+        match *self {
+            Expression::Number(number) => Some(number),
+            Expression::Value { ref path } => number_at(Some(value), path),
+            Expression::Getter { index, ref path, .. } => {
+                number_at(getters.get(index).and_then(Option::as_ref), path)
+            }
+            Expression::Binary { operator, ref left, ref right } => {
+                let (left, right) = match (left.evaluate(value, getters),
+                                           right.evaluate(value, getters)) {
+                    (Some(left), Some(right)) => (left, right),
+                    _ => return None,
+                };
+                let result = match operator {
+                    Operator::Add => left + right,
+                    Operator::Sub => left - right,
+                    Operator::Mul => left * right,
+                    Operator::Div => left / right,
+                };
+                // e.g. a division by zero.
+                if result.is_finite() { Some(result) } else { None }
+            }
+        }
+    }
+
+    fn collect_getters<'a>(&'a self, getters: &mut Vec<&'a Expression>) {
+        match *self {
+            Expression::Getter { .. } => getters.push(self),
+            Expression::Binary { ref left, ref right, .. } => {
+                left.collect_getters(getters);
+                right.collect_getters(getters);
+            }
+            Expression::Number(_) |
+            Expression::Value { .. } => {}
+        }
+    }
+
+    /// Set `Expression::Getter::index`, in the order of `Test::getters`.
+    fn number_getters(&mut self, next: &mut usize) {
+        match *self {
+            Expression::Getter { ref mut index, .. } => {
+                *index = *next;
+                *next += 1;
+            }
+            Expression::Binary { ref mut left, ref mut right, .. } => {
+                left.number_getters(next);
+                right.number_getters(next);
+            }
+            Expression::Number(_) |
+            Expression::Value { .. } => {}
+        }
+    }
+}
+
+fn field_path(field: &str) -> Vec<String> {
+    field.split('.')
+        .filter(|key| !key.is_empty())
+        .map(|key| key.to_owned())
+        .collect()
+}
+
+/// Parse the operands of `{name: [expression, expression]}`, if `source` has a field `name`.
+fn take_operands(path: &Path,
+                 source: &JSON,
+                 name: &str)
+                 -> Option<Result<(Expression, Expression), ParseError>> {
+    path.push(name, |path| {
+        Expression::take_vec_opt(path.clone(), source, name).map(|result| {
+            result.and_then(|mut operands| {
+                if operands.len() != 2 {
+                    return Err(ParseError::type_error(name, &path, "array of two expressions"));
+                }
+                let right = operands.pop().unwrap();
+                let left = operands.pop().unwrap();
+                Ok((left, right))
+            })
+        })
+    })
+}
+
+impl Parser<Test> for Test {
+    fn description() -> String {
+        "Test".to_owned()
+    }
+
+    fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
+        let comparisons = [("Lt", Comparison::Lt),
+                           ("Leq", Comparison::Leq),
+                           ("Gt", Comparison::Gt),
+                           ("Geq", Comparison::Geq),
+                           ("Eq", Comparison::Eq),
+                           ("Neq", Comparison::Neq)];
+        for &(name, comparison) in &comparisons {
+            if let Some(result) = take_operands(&path, source, name) {
+                let (left, right) = try!(result);
+                let mut test = Test {
+                    comparison: comparison,
+                    left: left,
+                    right: right,
+                };
+                let mut next = 0;
+                test.left.number_getters(&mut next);
+                test.right.number_getters(&mut next);
+                return Ok(test);
+            }
+        }
+        Err(ParseError::missing_field("Lt|Leq|Gt|Geq|Eq|Neq", &path))
+    }
+}
+
+impl Parser<Expression> for Expression {
+    fn description() -> String {
+        "Expression".to_owned()
+    }
+
+    fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
+        if let Some(number) = source.as_f64() {
+            return Ok(Expression::Number(number));
+        }
+        if !source.is_object() {
+            return Err(ParseError::type_error("Expression", &path, "number or object"));
+        }
+        let operators = [("+", Operator::Add),
+                         ("-", Operator::Sub),
+                         ("*", Operator::Mul),
+                         ("/", Operator::Div)];
+        for &(name, operator) in &operators {
+            if let Some(result) = take_operands(&path, source, name) {
+                let (left, right) = try!(result);
+                return Ok(Expression::Binary {
+                    operator: operator,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                });
+            }
+        }
+        if let Some(value) = source.find("value") {
+            return match value.as_str() {
+                Some(field) => Ok(Expression::Value { path: field_path(field) }),
+                None => Err(ParseError::type_error("value", &path, "string")),
+            };
+        }
+        if source.find("getter").is_some() {
+            let getter = try!(path.push("getter",
+                                        |path| ChannelSelector::take_vec(path, source, "getter")));
+            let feature = try!(path.push("feature", |path| Id::take(path, source, "feature")));
+            let field = match source.find("field") {
+                Some(&JSON::String(ref field)) => field_path(field),
+                Some(_) => return Err(ParseError::type_error("field", &path, "string")),
+                None => vec![],
+            };
+            return Ok(Expression::Getter {
+                index: 0,
+                source: getter,
+                feature: feature,
+                path: field,
+            });
+        }
+        Err(ParseError::missing_field("+|-|*|/|value|getter", &path))
+    }
+}
+
+/// The feature of the channels on which a `Schedule` is watched, unless the `Match` gives
+/// another one.
+pub const SCHEDULE_FEATURE: &'static str = "clock/time-of-day-seconds";
+
+/// The feature of `Match` and `Statement` that use the state of the script rather than
+/// channels. No channel offers it.
+pub const STATE_FEATURE: &'static str = "thinkerbell/state";
+
+/// How long a scheduled `Match` remains met after each of its times, in seconds.
+const SCHEDULE_WINDOW_S: i64 = 59;
+
+/// The times at which a `Match` is met, e.g. "every weekday at 7:30".
+///
+/// # JSON
+///
+/// A schedule is either:
+///
+/// - a cron-style string "minute hour day-of-month month day-of-week", where each
+///   field is `*` or a list of values and ranges separated by commas, optionally
+///   followed by a step, e.g. `"30 7 * * 1-5"` or `"*/15 8-18 * * *"`. Days of the
+///   week go from 0 (Sunday) to 6 (Saturday), and 7 is also Sunday;
+/// - an object `{"days": [string], "times": [string]}`, where days are `"Mon"`,
+///   `"Tue"`, ... `"Sun"`, `"weekdays"` or `"weekend"` (optional, every day by
+///   default) and times are `"HH:MM"`.
+///
+/// ```
+/// extern crate foxbox_thinkerbell;
+/// extern crate foxbox_taxonomy;
+/// extern crate chrono;
+///
+/// use foxbox_thinkerbell::ast::*;
+/// use foxbox_taxonomy::parse::*;
+/// use chrono::NaiveDate;
+///
+/// # fn main() {
+/// let cron = Schedule::from_str(r#""30 7 * * 1-5""#).unwrap();
+/// let days = Schedule::from_str(r#"{"days": ["weekdays"], "times": ["07:30"]}"#).unwrap();
+/// assert_eq!(cron, days);
+/// assert_eq!(cron.times, vec![7 * 3600 + 30 * 60]);
+///
+/// // 2016-10-14 is a Friday.
+/// assert!(cron.applies_to(&NaiveDate::from_ymd(2016, 10, 14)));
+/// assert!(!cron.applies_to(&NaiveDate::from_ymd(2016, 10, 15)));
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    /// The times of day, in seconds since midnight, sorted.
+    pub times: Vec<u32>,
+
+    /// The days of the week, from 0 (Sunday) to 6 (Saturday), or `None` for every day.
+    pub weekdays: Option<Vec<u32>>,
+
+    /// The days of the month, from 1, or `None` for every day.
+    pub days_of_month: Option<Vec<u32>>,
+
+    /// The months, from 1 (January), or `None` for every month.
+    pub months: Option<Vec<u32>>,
+}
+
+/// Parse a field of a cron expression, e.g. `"1-5"`, `"0,30"` or `"*/15"`, as the sorted
+/// values it accepts between `min` and `max`.
+fn parse_cron_field(source: &str, min: u32, max: u32) -> Option<Vec<u32>> {
+    let number = |source: &str| source.parse::<u32>().ok();
+    let mut values = vec![];
+    for part in source.split(',') {
+        let mut pieces = part.splitn(2, '/');
+        let range = pieces.next().unwrap_or("");
+        let step = match pieces.next().map(&number) {
+            None => 1,
+            Some(Some(step)) if step > 0 => step,
+            Some(_) => return None,
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            let mut bounds = range.splitn(2, '-');
+            let start = match bounds.next().and_then(&number) {
+                Some(start) => start,
+                None => return None,
+            };
+            // As with cron, "5/15" stands for "5-max/15".
+            let end = match bounds.next() {
+                Some(end) => {
+                    match number(end) {
+                        Some(end) => end,
+                        None => return None,
+                    }
+                }
+                None if step > 1 => max,
+                None => start,
+            };
+            (start, end)
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        values.extend((start..end + 1).filter(|value| (value - start) % step == 0));
+    }
+    values.sort();
+    values.dedup();
+    Some(values)
+}
+
+/// Parse a time of day "HH:MM", as seconds since midnight.
+fn parse_time_of_day(source: &str) -> Option<u32> {
+    let mut parts = source.trim().splitn(2, ':');
+    let hours = parts.next().and_then(|hours| hours.parse::<u32>().ok());
+    let minutes = parts.next().and_then(|minutes| minutes.parse::<u32>().ok());
+    match (hours, minutes) {
+        (Some(hours), Some(minutes)) if hours < 24 && minutes < 60 => {
+            Some(hours * 3600 + minutes * 60)
+        }
+        _ => None,
+    }
+}
+
+/// Parse a day of the week, or a group of days, as numbers from 0 (Sunday).
+fn parse_days(source: &str) -> Option<Vec<u32>> {
+    let source = source.to_lowercase();
+    match source.as_str() {
+        "weekdays" => return Some(vec![1, 2, 3, 4, 5]),
+        "weekend" => return Some(vec![0, 6]),
+        _ => {}
+    }
+    ["sun", "mon", "tue", "wed", "thu", "fri", "sat"]
+        .iter()
+        .position(|name| source.starts_with(name))
+        .map(|day| vec![day as u32])
+}
+
+impl Schedule {
+    /// Parse a cron-style expression "minute hour day-of-month month day-of-week".
+    pub fn from_cron(source: &str) -> Option<Self> {
+        let fields: Vec<_> = source.split_whitespace().collect();
+        if fields.len() != 5 {
+            return None;
+        }
+        // Days and months are checked when the match is met, so keep `*` as "any".
+        let restricted = |field: &str, min, max| if field == "*" {
+            Some(None)
+        } else {
+            parse_cron_field(field, min, max).map(Some)
+        };
+        let minutes = parse_cron_field(fields[0], 0, 59);
+        let hours = parse_cron_field(fields[1], 0, 23);
+        let days_of_month = restricted(fields[2], 1, 31);
+        let months = restricted(fields[3], 1, 12);
+        let weekdays = restricted(fields[4], 0, 7);
+        match (minutes, hours, days_of_month, months, weekdays) {
+            (Some(minutes), Some(hours), Some(days_of_month), Some(months), Some(weekdays)) => {
+                let mut times = vec![];
+                for hour in &hours {
+                    for minute in &minutes {
+                        times.push(hour * 3600 + minute * 60);
+                    }
+                }
+                Some(Schedule {
+                    times: times,
+                    weekdays: weekdays.map(|weekdays| {
+                        let mut weekdays: Vec<_> =
+                            weekdays.iter().map(|day| day % 7).collect();
+                        weekdays.sort();
+                        weekdays.dedup();
+                        weekdays
+                    }),
+                    days_of_month: days_of_month,
+                    months: months,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the schedule applies to a day. As with cron, if both the days of the month
+    /// and the days of the week are restricted, either of them will do.
+    pub fn applies_to<D: Datelike>(&self, date: &D) -> bool {
+        let contains = |values: &Option<Vec<u32>>, value| {
+            values.as_ref().map_or(true, |values| values.contains(&value))
+        };
+        if !contains(&self.months, date.month()) {
+            return false;
+        }
+        let weekday = date.weekday().num_days_from_sunday();
+        match (&self.days_of_month, &self.weekdays) {
+            (&Some(ref days), &Some(ref weekdays)) => {
+                days.contains(&date.day()) || weekdays.contains(&weekday)
+            }
+            _ => contains(&self.days_of_month, date.day()) && contains(&self.weekdays, weekday),
+        }
+    }
+
+    /// The ranges of times of day during which a scheduled `Match` is met: the minute that
+    /// starts at each of `times`.
+    pub fn ranges(&self) -> Vec<Range<Duration>> {
+        self.times
+            .iter()
+            .map(|&time| {
+                Range::BetweenEq {
+                    min: Duration::from(ChronoDuration::seconds(time as i64)),
+                    max: Duration::from(ChronoDuration::seconds(time as i64 + SCHEDULE_WINDOW_S)),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Parser<Schedule> for Schedule {
+    fn description() -> String {
+        "Schedule".to_owned()
+    }
+
+    fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
+        match *source {
+            JSON::String(ref cron) => {
+                Schedule::from_cron(cron).ok_or_else(|| {
+                    ParseError::type_error("Schedule",
+                                           &path,
+                                           "\"minute hour day-of-month month day-of-week\"")
+                })
+            }
+            JSON::Object(_) => {
+                let times =
+                    try!(path.push("times", |path| String::take_vec(path, source, "times")));
+                let mut seconds = vec![];
+                for time in &times {
+                    match parse_time_of_day(time) {
+                        Some(time) => seconds.push(time),
+                        None => return Err(ParseError::type_error("times", &path, "\"HH:MM\"")),
+                    }
+                }
+                if seconds.is_empty() {
+                    return Err(ParseError::missing_field("times", &path));
+                }
+                seconds.sort();
+                seconds.dedup();
+                let weekdays = match path.push("days",
+                                               |path| String::take_vec_opt(path, source, "days")) {
+                    Some(Ok(days)) => {
+                        let mut weekdays = vec![];
+                        for day in &days {
+                            match parse_days(day) {
+                                Some(days) => weekdays.extend(days),
+                                None => return Err(ParseError::unknown_constant(day, &path)),
+                            }
+                        }
+                        weekdays.sort();
+                        weekdays.dedup();
+                        Some(weekdays)
+                    }
+                    Some(Err(err)) => return Err(err),
+                    None => None,
+                };
+                Ok(Schedule {
+                    times: seconds,
+                    weekdays: weekdays,
+                    days_of_month: None,
+                    months: None,
+                })
+            }
+            _ => Err(ParseError::type_error("Schedule", &path, "string or object")),
+        }
+    }
+}
+
 /// Stuff to actually do. In practice, this means placing calls to devices.
 ///
 /// # JSON
@@ -213,6 +933,14 @@ impl Parser<Match<UncheckedCtx>> for Match<UncheckedCtx> {
 /// - value (Value);
 /// - feature (Id<FeatureId>);
 ///
+/// or, to store a value in the state of the script rather than send it, e.g.
+/// to remember that an alarm is armed, as an object with fields:
+/// - set_state (string): the key under which to store the value;
+/// - value (JSON).
+///
+/// The state of a script is kept across restarts, and may be checked by the
+/// `state` of a `Match`.
+///
 /// ```
 /// extern crate foxbox_thinkerbell;
 /// extern crate foxbox_taxonomy;
@@ -233,6 +961,13 @@ impl Parser<Match<UncheckedCtx>> for Match<UncheckedCtx> {
 /// let statement = Statement::<UncheckedCtx>::from_str(&source).unwrap();
 /// assert_eq!(statement.value, Payload::from_data(OnOff::Off, &*format::ON_OFF).unwrap());
 /// assert_eq!(statement.feature, Id::new("light/is-on"));
+///
+/// let statement = Statement::<UncheckedCtx>::from_str(r#"{
+///   "set_state": "armed",
+///   "value": true
+/// }"#).unwrap();
+/// assert_eq!(statement.set_state, Some("armed".to_owned()));
+/// assert!(statement.destination.is_empty());
 /// # }
 /// ```
 #[derive(Debug)]
@@ -255,6 +990,10 @@ pub struct Statement<Ctx>
     /// offer `feature`.
     pub feature: Id<FeatureId>,
 
+    /// If specified, `value` is stored under this key in the state of the script instead
+    /// of being sent, and `destination` is empty.
+    pub set_state: Option<String>,
+
     pub phantom: PhantomData<Ctx>,
 }
 impl Parser<Statement<UncheckedCtx>> for Statement<UncheckedCtx> {
@@ -263,15 +1002,31 @@ impl Parser<Statement<UncheckedCtx>> for Statement<UncheckedCtx> {
     }
 
     fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
-        let destination = try!(path.push("destination", |path| {
-            ChannelSelector::take_vec(path, source, "destination")
-        }));
-        let feature = try!(path.push("feature", |path| Id::take(path, source, "feature")));
         let value = try!(path.push("value", |path| Payload::take(path, source, "value")));
+        let set_state =
+            match path.push("set_state", |path| String::take_opt(path, source, "set_state")) {
+                Some(Ok(key)) => key,
+                Some(Err(err)) => return Err(err),
+                None => {
+                    let destination = try!(path.push("destination", |path| {
+                        ChannelSelector::take_vec(path, source, "destination")
+                    }));
+                    let feature =
+                        try!(path.push("feature", |path| Id::take(path, source, "feature")));
+                    return Ok(Statement {
+                        destination: destination,
+                        value: value,
+                        feature: feature,
+                        set_state: None,
+                        phantom: PhantomData,
+                    });
+                }
+            };
         Ok(Statement {
-            destination: destination,
+            destination: vec![],
             value: value,
-            feature: feature,
+            feature: Id::new(STATE_FEATURE),
+            set_state: Some(set_state),
             phantom: PhantomData,
         })
     }
@@ -5,9 +5,13 @@
 //!
 //! - Ensure that the `Script` has at least one `Rule`.
 //! - Ensure that each `Rule` has at least one `Match`.
-//! - Ensure that each `Rule` has at least one `Statement`.
-//! - Ensure that each `Match` has at least one `source`.
-//! - Ensure that each `Statement` has at least one `destination`.
+//! - Ensure that each `Rule` has at least one `Statement` in `execute`
+//!   (`otherwise` may be empty).
+//! - Ensure that each `Match` has at least one `source`, unless it
+//!   checks the state of the script.
+//! - Ensure that each `Statement` has at least one `destination`, unless
+//!   it sets the state of the script.
+//! - Ensure that each getter of an `Expression` has at least one `source`.
 //! - Ensure that in each `Match`, the type of `range` matches
 //!   the `kind`.
 //! - Ensure that in each `Statement`, the type of `value` matches
@@ -16,8 +20,11 @@
 //!   `source` matches the `kind`, even if devices change.
 //! - Transform each `Statement` to make sure that the kind of the
 //!   `destination` matches the `kind`, even if devices change.
+//! - Transform each getter of an `Expression` in the same way as
+//!   the `source` of a `Match`.
 
-use ast::{Script, Rule, Statement, Match, Context, UncheckedCtx};
+use ast::{Script, Rule, Statement, Match, Context, Expression, Test, UncheckedCtx};
+use run::LoopDetection;
 use util::*;
 
 use foxbox_taxonomy::api::API;
@@ -42,6 +49,12 @@ pub trait ExecutableDevEnv: Send {
     /// A guard returned by `start_timer`. When the guard is dropped, the timer is cancelled.
     type TimerGuard;
     fn start_timer(&self, duration: Duration, timer: Box<ExtSender<()>>) -> Self::TimerGuard;
+
+    /// How to detect and break rules that re-trigger themselves. Read once, when a script
+    /// starts.
+    fn loop_detection(&self) -> LoopDetection {
+        LoopDetection::default()
+    }
 }
 impl<W, A, T> Debug for ExecutableDevEnv<WatchGuard = W, API = A, TimerGuard = T> {
     fn fmt(&self, _: &mut Formatter) -> Result<(), FmtError> {
@@ -76,6 +89,9 @@ pub enum SourceError {
 
     /// A statement doesn't have any destination.
     NoStatementDestination,
+
+    /// A getter of an expression doesn't have any source.
+    NoExpressionSource,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -140,15 +156,21 @@ impl<Env> Compiler<Env>
         let conditions = try!(map(trigger.conditions, |match_| self.compile_match(match_)));
         let execute = try!(map(trigger.execute,
                                |statement| self.compile_statement(statement)));
+        let otherwise = try!(map(trigger.otherwise,
+                                 |statement| self.compile_statement(statement)));
         Ok(Rule {
             conditions: conditions,
             execute: execute,
+            otherwise: otherwise,
+            sustained_for: trigger.sustained_for,
+            within: trigger.within,
+            cooldown: trigger.cooldown,
             phantom: PhantomData,
         })
     }
 
     fn compile_match(&self, match_: Match<UncheckedCtx>) -> Result<Match<CompiledCtx<Env>>, Error> {
-        if match_.source.len() == 0 {
+        if match_.source.len() == 0 && match_.state.is_none() {
             return Err(Error::SourceError(SourceError::NoMatchSource));
         }
         let source = match_.source
@@ -159,19 +181,66 @@ impl<Env> Compiler<Env>
                     .with_supports_watch(Exactly::Exactly(true))
             })
             .collect();
+        let expression = match match_.expression {
+            Some(test) => Some(try!(self.compile_test(test))),
+            None => None,
+        };
         Ok(Match {
             source: source,
             feature: match_.feature,
             when: match_.when,
             duration: match_.duration,
+            filter: match_.filter,
+            expression: expression,
+            schedule: match_.schedule,
+            state: match_.state,
             phantom: PhantomData,
         })
     }
 
+    fn compile_test(&self, test: Test) -> Result<Test, Error> {
+        Ok(Test {
+            comparison: test.comparison,
+            left: try!(self.compile_expression(test.left)),
+            right: try!(self.compile_expression(test.right)),
+        })
+    }
+
+    fn compile_expression(&self, expression: Expression) -> Result<Expression, Error> {
+        match expression {
+            Expression::Getter { index, source, feature, path } => {
+                if source.len() == 0 {
+                    return Err(Error::SourceError(SourceError::NoExpressionSource));
+                }
+                let source = source.iter()
+                    .map(|input| {
+                        input.clone()
+                            .with_feature(&feature)
+                            .with_supports_watch(Exactly::Exactly(true))
+                    })
+                    .collect();
+                Ok(Expression::Getter {
+                    index: index,
+                    source: source,
+                    feature: feature,
+                    path: path,
+                })
+            }
+            Expression::Binary { operator, left, right } => {
+                Ok(Expression::Binary {
+                    operator: operator,
+                    left: Box::new(try!(self.compile_expression(*left))),
+                    right: Box::new(try!(self.compile_expression(*right))),
+                })
+            }
+            other => Ok(other),
+        }
+    }
+
     fn compile_statement(&self,
                          statement: Statement<UncheckedCtx>)
                          -> Result<Statement<CompiledCtx<Env>>, Error> {
-        if statement.destination.len() == 0 {
+        if statement.destination.len() == 0 && statement.set_state.is_none() {
             return Err(Error::SourceError(SourceError::NoStatementDestination));
         }
         let destination = statement.destination
@@ -185,6 +254,7 @@ impl<Env> Compiler<Env>
             destination: destination,
             value: statement.value,
             feature: statement.feature,
+            set_state: statement.set_state,
             phantom: PhantomData,
         })
     }
@@ -1,6 +1,6 @@
 use compile::ExecutableDevEnv;
 
-use foxbox_taxonomy::api::{API, Error, User};
+use foxbox_taxonomy::api::{API, Error, Observation, Source, User};
 use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::manager::*;
 use foxbox_taxonomy::parse::*;
@@ -134,22 +134,30 @@ impl TestSharedAdapterBackend {
                             continue;
                         }
                         if let Some(ref condition) = *condition {
+                            // Conditions are values, except for the ranges of times of day
+                            // watched by schedules.
+                            let is_met = |value: &Value| {
+                                condition.downcast::<Range<Duration>>()
+                                    .map_or(value == condition, |range| range.contains(value))
+                            };
                             let was_met = if let Some(Ok(ref value)) = old {
-                                value == condition
+                                is_met(value)
                             } else {
                                 false
                             };
-                            match (was_met, value == *condition) {
+                            match (was_met, is_met(&value)) {
                                 (false, true) => {
                                     let _ = cb.send(WatchEvent::Enter {
                                         id: id.clone(),
                                         value: value.clone(),
+                                        observed: Observation::now(Source::Device),
                                     });
                                 }
                                 (true, false) => {
                                     let _ = cb.send(WatchEvent::Exit {
                                         id: id.clone(),
                                         value: value.clone(),
+                                        observed: Observation::now(Source::Device),
                                     });
                                 }
                                 _ => continue,
@@ -158,6 +166,7 @@ impl TestSharedAdapterBackend {
                             let _ = cb.send(WatchEvent::Enter {
                                 id: id.clone(),
                                 value: value.clone(),
+                                observed: Observation::now(Source::Device),
                             });
                         }
                     }
@@ -335,7 +344,8 @@ impl Adapter for TestAdapter {
     fn register_watch(&self,
                       source: Vec<(Id<Channel>,
                                    Option<Value>,
-                                   Box<ExtSender<WatchEvent<Value>>>)>)
+                                   Box<ExtSender<WatchEvent<Value>>>)>,
+                      _: User)
                       -> Vec<(Id<Channel>, Result<Box<AdapterWatchGuard>, Error>)> {
         let (tx, rx) = channel();
         self.back_end
@@ -2,15 +2,17 @@ use ast::Script;
 use compile::ExecutableDevEnv;
 use run::{Execution, ExecutionEvent, Error as RunError, StartStopError};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 use std::path::{Path as FilePath, PathBuf as FilePathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use foxbox_taxonomy::api::{ResultMap, User};
 use foxbox_taxonomy::parse::*;
 use foxbox_taxonomy::util::Id;
 
 use rusqlite;
+use serde_json;
 use transformable_channels::mpsc::{channel, ExtSender, TransformableSender};
 
 /// A ScriptManager error.
@@ -33,6 +35,226 @@ pub enum Error {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
 pub struct ScriptId;
 
+/// The number of events that `ExecutionLog` keeps per script; older events are dropped.
+const MAX_LOGGED_EVENTS: i64 = 100;
+
+/// An event of the execution of a script, as kept by `ExecutionLog`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoggedEvent {
+    /// Milliseconds since the epoch.
+    pub timestamp: i64,
+
+    /// One of "started", "compile-error", "start-error", "sent", "channel-error",
+    /// "loop-detected", "state-changed" or "stopped".
+    pub kind: String,
+
+    /// What happened, depending on `kind`, e.g. the channels to which a rule sent values.
+    pub details: JSON,
+}
+
+impl LoggedEvent {
+    /// Describe an event of the execution of a script, or `None` if the event is not worth
+    /// keeping, e.g. timers.
+    pub fn from_event(event: &ExecutionEvent) -> Option<Self> {
+        let mut details = BTreeMap::new();
+        let kind = match *event {
+            ExecutionEvent::Starting { result: Ok(()) } => "started",
+            ExecutionEvent::Starting { result: Err(ref err) } => {
+                details.insert("error".to_owned(), JSON::String(format!("{:?}", err)));
+                match *err {
+                    RunError::CompileError(_) => "compile-error",
+                    _ => "start-error",
+                }
+            }
+            ExecutionEvent::Stopped { ref result } => {
+                if let Err(ref err) = *result {
+                    details.insert("error".to_owned(), JSON::String(format!("{:?}", err)));
+                }
+                "stopped"
+            }
+            ExecutionEvent::Sent { rule_index, statement_index, otherwise, ref result } => {
+                let mut succeeded = Vec::new();
+                let mut failed = BTreeMap::new();
+                for &(ref id, ref result) in result {
+                    match *result {
+                        Ok(()) => succeeded.push(JSON::String(id.to_string())),
+                        Err(ref err) => {
+                            failed.insert(id.to_string(), JSON::String(format!("{:?}", err)));
+                        }
+                    }
+                }
+                details.insert("rule".to_owned(), JSON::U64(rule_index as u64));
+                details.insert("statement".to_owned(), JSON::U64(statement_index as u64));
+                details.insert("otherwise".to_owned(), JSON::Bool(otherwise));
+                details.insert("succeeded".to_owned(), JSON::Array(succeeded));
+                details.insert("failed".to_owned(), JSON::Object(failed));
+                "sent"
+            }
+            ExecutionEvent::ChannelError { ref id, ref error } => {
+                details.insert("channel".to_owned(), JSON::String(id.to_string()));
+                details.insert("error".to_owned(), JSON::String(format!("{:?}", error)));
+                "channel-error"
+            }
+            ExecutionEvent::LoopDetected { rule_index, ref chain, policy } => {
+                details.insert("rule".to_owned(), JSON::U64(rule_index as u64));
+                details.insert("chain".to_owned(),
+                               JSON::Array(chain.iter()
+                                   .map(|id| JSON::String(id.to_string()))
+                                   .collect()));
+                details.insert("policy".to_owned(), JSON::String(format!("{:?}", policy)));
+                "loop-detected"
+            }
+            ExecutionEvent::StateChanged { ref key, ref value } => {
+                details.insert("key".to_owned(), JSON::String(key.clone()));
+                details.insert("value".to_owned(), value.clone());
+                "state-changed"
+            }
+            ExecutionEvent::TimerStart { .. } |
+            ExecutionEvent::TimerCancel { .. } => return None,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as i64 * 1000 + now.subsec_nanos() as i64 / 1_000_000)
+            .unwrap_or(0);
+        Some(LoggedEvent {
+            timestamp: timestamp,
+            kind: kind.to_owned(),
+            details: JSON::Object(details),
+        })
+    }
+
+    pub fn to_json(&self) -> JSON {
+        let mut map = BTreeMap::new();
+        map.insert("timestamp".to_owned(), JSON::I64(self.timestamp));
+        map.insert("kind".to_owned(), JSON::String(self.kind.clone()));
+        map.insert("details".to_owned(), self.details.clone());
+        JSON::Object(map)
+    }
+}
+
+/// The events of the execution of scripts, kept in the database of a `ScriptManager` so that
+/// users may find out why a script did or didn't do something.
+///
+/// Events reach the consumer of the channel passed to `ScriptManager::new`, usually on another
+/// thread, so this may be cloned and used from any thread.
+#[derive(Clone, Debug)]
+pub struct ExecutionLog {
+    /// The path to the SQLite file of the `ScriptManager`.
+    path: FilePathBuf,
+}
+
+impl ExecutionLog {
+    /// Keep an event of a script, dropping the oldest events of the script if necessary.
+    pub fn record(&self, id: &Id<ScriptId>, event: &ExecutionEvent) -> Result<(), Error> {
+        let event = match LoggedEvent::from_event(event) {
+            Some(event) => event,
+            None => return Ok(()),
+        };
+        let details = serde_json::to_string(&event.details).unwrap();
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        try!(connection.execute("INSERT INTO executions (script, timestamp, kind, details)
+                VALUES ($1, $2, $3, $4)",
+                                &[&id.to_string(), &event.timestamp, &event.kind, &details]));
+        try!(connection.execute("DELETE FROM executions WHERE script = $1 AND rowid NOT IN (
+                SELECT rowid FROM executions WHERE script = $1 ORDER BY rowid DESC LIMIT $2)",
+                                &[&id.to_string(), &MAX_LOGGED_EVENTS]));
+        Ok(())
+    }
+
+    /// The events of a script, most recent first.
+    pub fn events(&self, id: &Id<ScriptId>) -> Result<Vec<LoggedEvent>, Error> {
+        self.query(id, "SELECT timestamp, kind, details FROM executions WHERE script = $1
+                ORDER BY rowid DESC")
+    }
+
+    /// The latest time a script sent values, if any.
+    pub fn last_execution(&self, id: &Id<ScriptId>) -> Result<Option<LoggedEvent>, Error> {
+        let mut events = try!(self.query(id, "SELECT timestamp, kind, details FROM executions
+                WHERE script = $1 AND kind = 'sent' ORDER BY rowid DESC LIMIT 1"));
+        Ok(events.pop())
+    }
+
+    fn query(&self, id: &Id<ScriptId>, sql: &str) -> Result<Vec<LoggedEvent>, Error> {
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        let mut stmt = try!(connection.prepare(sql));
+        let mut rows = try!(stmt.query(&[&id.to_string()]));
+        let mut events = Vec::new();
+        while let Some(result_row) = rows.next() {
+            let row = try!(result_row);
+            let details: String = try!(row.get_checked(2));
+            events.push(LoggedEvent {
+                timestamp: try!(row.get_checked(0)),
+                kind: try!(row.get_checked(1)),
+                details: try!(serde_json::from_str(&details)
+                    .map_err(|err| Error::ParseError(format!("{:?}", err)))),
+            });
+        }
+        Ok(events)
+    }
+
+    /// Forget the events of a script, or of all scripts.
+    fn clear(&self, id: Option<&Id<ScriptId>>) -> Result<(), Error> {
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        match id {
+            Some(id) => {
+                try!(connection.execute("DELETE FROM executions WHERE script = $1",
+                                        &[&id.to_string()]))
+            }
+            None => try!(connection.execute("DELETE FROM executions", &[])),
+        };
+        Ok(())
+    }
+}
+
+/// The values that scripts store with `set_state` statements, kept in the database of a
+/// `ScriptManager` so that they survive restarts.
+#[derive(Clone, Debug)]
+pub struct ScriptState {
+    /// The path to the SQLite file of the `ScriptManager`.
+    path: FilePathBuf,
+}
+
+impl ScriptState {
+    /// The values stored by a script, by key.
+    pub fn values(&self, id: &Id<ScriptId>) -> Result<HashMap<String, JSON>, Error> {
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        let mut stmt = try!(connection.prepare("SELECT key, value FROM states WHERE script = $1"));
+        let mut rows = try!(stmt.query(&[&id.to_string()]));
+        let mut values = HashMap::new();
+        while let Some(result_row) = rows.next() {
+            let row = try!(result_row);
+            let value: String = try!(row.get_checked(1));
+            values.insert(try!(row.get_checked(0)),
+                          try!(serde_json::from_str(&value)
+                              .map_err(|err| Error::ParseError(format!("{:?}", err)))));
+        }
+        Ok(values)
+    }
+
+    /// Store a value of a script, replacing the previous value with the same key.
+    pub fn set(&self, id: &Id<ScriptId>, key: &str, value: &JSON) -> Result<(), Error> {
+        let value = serde_json::to_string(value).unwrap();
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        try!(connection.execute("INSERT OR REPLACE INTO states (script, key, value)
+                VALUES ($1, $2, $3)",
+                                &[&id.to_string(), &key.to_owned(), &value]));
+        Ok(())
+    }
+
+    /// Forget the values of a script, or of all scripts.
+    fn clear(&self, id: Option<&Id<ScriptId>>) -> Result<(), Error> {
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        match id {
+            Some(id) => {
+                try!(connection.execute("DELETE FROM states WHERE script = $1",
+                                        &[&id.to_string()]))
+            }
+            None => try!(connection.execute("DELETE FROM states", &[])),
+        };
+        Ok(())
+    }
+}
+
 /// ScriptManager stores a persistent database of scripts and executes them.
 /// Each script can be individually enabled or disabled.
 /// When a script is enabled, it is always running (unless an error occured during launch).
@@ -69,6 +291,9 @@ impl<Env, T> ScriptManager<Env, T>
     ///   owner // User identifier (String) of the owner of the rule. Defaults to no user.
     /// }
     ///
+    /// It also keeps the latest events of each script, see `ExecutionLog`, and the values
+    /// that each script stored, see `ScriptState`.
+    ///
     /// The database stores the raw script source, but only after the source has been parsed
     /// to ensure validity.
     pub fn new(env: Env, path: &FilePath, tx: Box<T>) -> Result<Self, Error> {
@@ -80,6 +305,18 @@ impl<Env, T> ScriptManager<Env, T>
             is_enabled  BOOL NOT NULL DEFAULT 1,
             owner       TEXT
         )", &[]));
+        try!(connection.execute("CREATE TABLE IF NOT EXISTS executions (
+            script      TEXT NOT NULL,
+            timestamp   INTEGER NOT NULL,
+            kind        TEXT NOT NULL,
+            details     TEXT NOT NULL
+        )", &[]));
+        try!(connection.execute("CREATE TABLE IF NOT EXISTS states (
+            script      TEXT NOT NULL,
+            key         TEXT NOT NULL,
+            value       TEXT NOT NULL,
+            PRIMARY KEY (script, key)
+        )", &[]));
 
         Ok(ScriptManager {
             path: path.to_owned(),
@@ -89,6 +326,16 @@ impl<Env, T> ScriptManager<Env, T>
         })
     }
 
+    /// The log of the events of the scripts.
+    pub fn execution_log(&self) -> ExecutionLog {
+        ExecutionLog { path: self.path.clone() }
+    }
+
+    /// The values that the scripts stored.
+    pub fn script_state(&self) -> ScriptState {
+        ScriptState { path: self.path.clone() }
+    }
+
     /// Load and launch all existing scripts from the database.
     pub fn load(&mut self) -> Result<ResultMap<Id<ScriptId>, (), Error>, Error> {
         let connection = try!(rusqlite::Connection::open(&self.path));
@@ -179,9 +426,9 @@ impl<Env, T> ScriptManager<Env, T>
     pub fn remove(&mut self, id: &Id<ScriptId>) -> Result<(), Error> {
         try!(self.set_enabled(id, false));
         let connection = try!(rusqlite::Connection::open(&self.path));
-        connection.execute("DELETE FROM scripts WHERE id = $1", &[&id.to_string()])
-            .map(|_| ())
-            .map_err(From::from)
+        try!(connection.execute("DELETE FROM scripts WHERE id = $1", &[&id.to_string()]));
+        try!(self.script_state().clear(Some(id)));
+        self.execution_log().clear(Some(id))
     }
 
 /// Remove all scripts, stopping any running scripts.
@@ -202,6 +449,8 @@ impl<Env, T> ScriptManager<Env, T>
         let connection = try!(rusqlite::Connection::open(&self.path));
         try!(connection.execute("DELETE FROM scripts", &[])
                 .map(|_| ()));
+        try!(self.execution_log().clear(None));
+        try!(self.script_state().clear(None));
         Ok(errors)
     }
 
@@ -246,11 +495,19 @@ impl<Env, T> ScriptManager<Env, T>
 // Now start it.
         let mut runner = Execution::<Env>::new();
         let tx_id = id.clone();
+        let script_state = self.script_state();
         let tx = self.tx.map(move |event| {
+// Keep the state as soon as it changes, rather than whenever `tx` is consumed.
+            if let ExecutionEvent::StateChanged { ref key, ref value } = event {
+                if let Err(err) = script_state.set(&tx_id, key, value) {
+                    warn!("Could not store state {} of script {}: {:?}", key, tx_id, err);
+                }
+            }
             (tx_id.clone(), event)
         });
         let parsed_source = try!(Path::new().push_str("recipe", |path| Script::from_str_at(path, source)));
-        try!(runner.start(self.env.clone(), parsed_source, owner.clone(), tx));
+        let state = try!(self.script_state().values(id));
+        try!(runner.start_with_state(self.env.clone(), parsed_source, owner.clone(), state, tx));
         self.runners.insert(id.clone(), runner);
         Ok(())
     }
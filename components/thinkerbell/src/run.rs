@@ -1,6 +1,6 @@
 //! Launching and running the script
 
-use ast::{Script, Statement, UncheckedCtx};
+use ast::{Expression, Script, Statement, UncheckedCtx, STATE_FEATURE};
 use compile::{Compiler, CompiledCtx, ExecutableDevEnv};
 pub use compile::{Error as CompileError, SourceError, TypeError};
 use compile;
@@ -8,17 +8,25 @@ use compile;
 use foxbox_taxonomy::api;
 use foxbox_taxonomy::api::{API, Error as APIError, Targetted, User, WatchEvent};
 use foxbox_taxonomy::channel::Channel;
+use foxbox_taxonomy::io::{Delivery, Payload};
+use foxbox_taxonomy::parse::{JSON, ToJSON};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::services::ServiceId;
 use foxbox_taxonomy::util::{Exactly, Id};
-use foxbox_taxonomy::values::Duration;
+use foxbox_taxonomy::values::{format, Duration};
 
 use transformable_channels::mpsc::*;
 
-use std::collections::HashSet;
+use chrono::Local;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::thread;
 use std::sync::Mutex;
+use std::time::{Duration as StdDuration, Instant};
 
 /// Running and controlling a single script.
 pub struct Execution<Env>
@@ -59,6 +67,21 @@ impl<Env> Execution<Env>
                     on_event: S)
                     -> Result<(), Error>
         where S: ExtSender<ExecutionEvent> + Clone
+    {
+        self.start_with_state(env, script, owner, HashMap::new(), on_event)
+    }
+
+    /// Like `start`, with the values that the script stored with `Statement::set_state`
+    /// during a previous execution. The values that the script stores are reported as
+    /// `ExecutionEvent::StateChanged`, e.g. to keep them for the next execution.
+    pub fn start_with_state<S>(&mut self,
+                               env: Env,
+                               script: Script<UncheckedCtx>,
+                               owner: User,
+                               state: HashMap<String, JSON>,
+                               on_event: S)
+                               -> Result<(), Error>
+        where S: ExtSender<ExecutionEvent> + Clone
     {
         let name = script.name.clone();
         info!("[Recipe '{}'] Starting compilation of script.", name);
@@ -73,7 +96,7 @@ impl<Env> Execution<Env>
             let (tx, rx) = channel();
             self.command_sender = Some(Box::new(tx.clone()));
             thread::spawn(move || {
-                match ExecutionTask::<Env>::new(script, owner, tx, rx) {
+                match ExecutionTask::<Env>::new(script, owner, state, tx, rx) {
                     Err(er) => {
                         info!("[Recipe '{}'] Compilation failed {:?}", name, er);
                         let _ = on_event.send(ExecutionEvent::Starting { result: Err(er.clone()) });
@@ -133,6 +156,12 @@ pub struct ExecutionTask<Env>
     script: Script<CompiledCtx<Env>>,
     owner: User,
 
+    /// The values stored by `Statement::set_state`, as JSON.
+    state: RefCell<HashMap<String, JSON>>,
+
+    /// How to detect rules that re-trigger themselves, as provided by the environment.
+    loop_detection: LoopDetection,
+
     /// Communicating with the thread running script.
     tx: Box<ExtSender<ExecutionOp>>,
     rx: Receiver<ExecutionOp>,
@@ -145,6 +174,8 @@ pub enum ExecutionEvent {
     Sent {
         rule_index: usize,
         statement_index: usize,
+        /// `true` if the statement belongs to `otherwise` rather than `execute`.
+        otherwise: bool,
         result: Vec<(Id<Channel>, Result<(), Error>)>,
     },
     TimerStart {
@@ -156,6 +187,55 @@ pub enum ExecutionEvent {
         condition_index: usize,
     },
     ChannelError { id: Id<Channel>, error: APIError },
+    /// A rule keeps re-triggering itself, see `LoopDetection`.
+    LoopDetected {
+        rule_index: usize,
+        /// The getter that triggered the rule, the channels to which it sent values, the
+        /// getter that triggered it again, etc.
+        chain: Vec<Id<Channel>>,
+        policy: LoopPolicy,
+    },
+    /// A statement stored a new value in the state of the script.
+    StateChanged { key: String, value: JSON },
+}
+
+/// What to do once a rule has re-triggered itself `LoopDetection::max_echoes` times in a row.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopPolicy {
+    /// Only log a warning.
+    Warn,
+
+    /// Skip the statements of the rule until it has been quiet for `LoopDetection::window`.
+    Suppress,
+
+    /// Stop the script.
+    Stop,
+}
+
+/// Detecting rules whose statements cause the watch events that trigger them again, e.g.
+/// "when the light is on, turn it off, otherwise turn it on". Left alone, such rules flood the
+/// adapters.
+///
+/// The statements of a rule echo if they are triggered by a channel to which the rule sent a
+/// value less than `window` earlier, or by another channel of the same service.
+#[derive(Clone, Debug)]
+pub struct LoopDetection {
+    pub window: StdDuration,
+
+    /// How many echoes in a row make a loop.
+    pub max_echoes: usize,
+
+    pub policy: LoopPolicy,
+}
+
+impl Default for LoopDetection {
+    fn default() -> Self {
+        LoopDetection {
+            window: StdDuration::from_secs(2),
+            max_echoes: 3,
+            policy: LoopPolicy::Suppress,
+        }
+    }
 }
 
 enum ExecutionOp {
@@ -171,6 +251,16 @@ enum ExecutionOp {
         condition_index: usize,
     },
 
+    /// We have received an update for a getter of `Match::expression`.
+    ExpressionUpdate {
+        event: WatchEvent,
+        rule_index: usize,
+        condition_index: usize,
+
+        /// The index of the getter in `Test::getters`.
+        getter_index: usize,
+    },
+
     /// A channel state has enter/left its target range and we
     /// have waited long enough to trigger the consequences.
     UpdateCondition {
@@ -187,16 +277,37 @@ enum ExecutionOp {
         condition_index: usize,
     },
 
+    /// The value stored under `key` in the state of the script has changed.
+    StateUpdate { key: String },
+
+    /// A timer attached to a whole rule has fired.
+    RuleTimer {
+        rule_index: usize,
+        timer: RuleTimerKind,
+    },
+
     /// Time to stop executing the script.
     Stop(Mutex<Box<Fn(Result<(), Error>) + Send>>),
 }
 
+#[derive(Clone, Copy, Debug)]
+enum RuleTimerKind {
+    /// The conditions of the rule have held for `Rule::sustained_for`.
+    Sustained,
+
+    /// `Rule::cooldown` has elapsed since the rule last triggered `execute`.
+    Cooldown,
+}
+
 impl Debug for ExecutionOp {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         use self::ExecutionOp::*;
         match *self {
             Update { .. } => formatter.write_str("Update"),
+            ExpressionUpdate { .. } => formatter.write_str("ExpressionUpdate"),
             UpdateCondition { .. } => formatter.write_str("UpdateCondition"),
+            StateUpdate { .. } => formatter.write_str("StateUpdate"),
+            RuleTimer { .. } => formatter.write_str("RuleTimer"),
             Stop(_) => formatter.write_str("Stop"),
         }
     }
@@ -212,6 +323,28 @@ struct ConditionState {
     /// condition remains true for at least `duration` before we decide whether to proceed with
     /// statements.
     duration: Option<Duration>,
+
+    /// When the match was last met, if it still is, see `Rule::within`.
+    entered_at: Option<Instant>,
+
+    /// If the match has an `expression`, what we need to evaluate it.
+    expression: Option<ExpressionState>,
+
+    /// If the match checks the state of the script, whether the value stored under
+    /// `Match::state` is in the range.
+    in_state: bool,
+}
+
+/// The latest values needed to evaluate `Match::expression`, as JSON.
+struct ExpressionState {
+    /// The latest value of each getter of the `Match` that is in range.
+    sources: HashMap<Id<Channel>, JSON>,
+
+    /// The latest value of each getter of `Test::getters`, if any.
+    getters: Vec<Option<JSON>>,
+
+    /// The getters of `sources` for which the test holds.
+    met: HashSet<Id<Channel>>,
 }
 struct RuleState<Env>
     where Env: ExecutableDevEnv
@@ -219,6 +352,39 @@ struct RuleState<Env>
     rule_is_met: bool,
     per_condition: Vec<ConditionState>,
     ongoing_timer: Option<Env::TimerGuard>, // FIXME: It's actually a guard.
+    loop_state: LoopState,
+
+    /// While the conditions have held for less than `Rule::sustained_for`, the timer, and the
+    /// getter that completed the conditions.
+    sustained_timer: Option<(Env::TimerGuard, Id<Channel>)>,
+
+    /// While `Rule::cooldown` hasn't elapsed since the rule last triggered `execute`.
+    cooldown_timer: Option<Env::TimerGuard>,
+
+    /// Whether `execute` was triggered since the conditions were last met, so that
+    /// `otherwise` only ever follows `execute`.
+    executed: bool,
+}
+
+impl<Env> RuleState<Env>
+    where Env: ExecutableDevEnv
+{
+    fn expression(&mut self, condition_index: usize) -> Option<&mut ExpressionState> {
+        self.per_condition[condition_index].expression.as_mut()
+    }
+}
+
+/// The latest values sent by a rule, to find out whether the rule re-triggers itself.
+#[derive(Default)]
+struct LoopState {
+    /// When the rule last sent values, the channels that received them, and their services.
+    last_sent: Option<(Instant, Vec<Id<Channel>>, HashSet<Id<ServiceId>>)>,
+
+    /// How many times in a row the rule was triggered by its own values.
+    echoes: usize,
+
+    /// The channels involved so far, for logging purposes.
+    chain: Vec<Id<Channel>>,
 }
 
 impl<Env> ExecutionTask<Env>
@@ -230,6 +396,7 @@ impl<Env> ExecutionTask<Env>
     /// calling `run()`.
     fn new<S>(script: Script<UncheckedCtx>,
               owner: User,
+              state: HashMap<String, JSON>,
               tx: S,
               rx: Receiver<ExecutionOp>)
               -> Result<Self, Error>
@@ -241,6 +408,8 @@ impl<Env> ExecutionTask<Env>
         Ok(ExecutionTask {
             script: script,
             owner: owner,
+            state: RefCell::new(state),
+            loop_detection: LoopDetection::default(),
             rx: rx,
             tx: Box::new(tx),
         })
@@ -256,6 +425,7 @@ impl<Env> ExecutionTask<Env>
 
         let mut witnesses = Vec::new();
         let api = env.api();
+        self.loop_detection = env.loop_detection();
 
         // Generate the state of rules, conditions, getters and start
         // listening to changes in the getters.
@@ -287,23 +457,86 @@ impl<Env> ExecutionTask<Env>
 
                         let rule_index = rule_index.clone();
                         let condition_index = condition_index.clone();
-                        witnesses.push(api.watch_values(vec![Targetted {
-                                                                 select: condition.source.clone(),
-                                                                 payload:
-                                                                     Exactly::Exactly(condition.when
-                                                                     .clone()),
-                                                             }],
-                                                        Box::new(self.tx.map(move |event| {
-                            ExecutionOp::Update {
-                                event: event,
-                                rule_index: rule_index,
-                                condition_index: condition_index,
+
+                        // A schedule is watched as one range of times of day per time. Each
+                        // range needs its own watch, as a watch may only watch a channel once.
+                        // The state of the script isn't watched, see `update_state`.
+                        let ranges = match condition.schedule {
+                            _ if condition.state.is_some() => vec![],
+                            Some(ref schedule) => {
+                                schedule.ranges()
+                                    .into_iter()
+                                    .filter_map(|range| {
+                                        Payload::from_data(range, &format::DURATION_RANGE).ok()
+                                    })
+                                    .map(Exactly::Exactly)
+                                    .collect()
+                            }
+                            None => {
+                                let when = condition.when.clone();
+                                vec![when.map_or(Exactly::Always, Exactly::Exactly)]
+                            }
+                        };
+                        for when in ranges {
+                            let targets = vec![Targetted {
+                                                   select: condition.source.clone(),
+                                                   payload: (when,
+                                                             condition.filter.clone(),
+                                                             Delivery::Full,
+                                                             None),
+                                               }];
+                            let tx = self.tx.map(move |event| {
+                                ExecutionOp::Update {
+                                    event: event,
+                                    rule_index: rule_index,
+                                    condition_index: condition_index,
+                                }
+                            });
+                            let owner = self.owner.clone();
+                            witnesses.push(api.watch_values_filtered(targets, Box::new(tx), owner));
+                        }
+
+                        // Watch every value of the other getters of the expression, since any
+                        // of them may change the outcome of the test.
+                        let expression = condition.expression.as_ref().map(|test| {
+                            let getters = test.getters();
+                            for (getter, getter_index) in getters.iter().zip(0 as usize..) {
+                                let select = match **getter {
+                                    Expression::Getter { ref source, .. } => source.clone(),
+                                    _ => continue,
+                                };
+                                let targets = vec![Targetted {
+                                                       select: select,
+                                                       payload: (Exactly::Always,
+                                                                 None,
+                                                                 Delivery::Full,
+                                                                 None),
+                                                   }];
+                                let tx = self.tx.map(move |event| {
+                                    ExecutionOp::ExpressionUpdate {
+                                        event: event,
+                                        rule_index: rule_index,
+                                        condition_index: condition_index,
+                                        getter_index: getter_index,
+                                    }
+                                });
+                                let owner = self.owner.clone();
+                                let guard = api.watch_values_filtered(targets, Box::new(tx), owner);
+                                witnesses.push(guard);
+                            }
+                            ExpressionState {
+                                sources: HashMap::new(),
+                                getters: vec![None; getters.len()],
+                                met: HashSet::new(),
                             }
-                        }))));
+                        });
                         ConditionState {
                             match_is_met: false,
                             per_getter: HashSet::new(),
                             duration: condition.duration.clone(),
+                            entered_at: None,
+                            expression: expression,
+                            in_state: false,
                         }
                     })
                     .collect();
@@ -312,14 +545,31 @@ impl<Env> ExecutionTask<Env>
                     rule_is_met: false,
                     per_condition: per_condition,
                     ongoing_timer: None,
+                    loop_state: LoopState::default(),
+                    sustained_timer: None,
+                    cooldown_timer: None,
+                    executed: false,
                 }
             })
             .collect();
 
+        // Check the state that the script stored during its previous executions.
+        let keys: HashSet<_> = self.script
+            .rules
+            .iter()
+            .flat_map(|rule| rule.conditions.iter().filter_map(|condition| condition.state.clone()))
+            .collect();
+        for key in keys {
+            let _ = self.tx.send(ExecutionOp::StateUpdate { key: key });
+        }
+
         let _ = tx_init.send(Ok(()));
 
+        // Once the script has stopped on its own, we keep the thread until we are told to
+        // `Stop`, so that the caller can still wait for it.
+        let mut stopped = false;
         for msg in self.rx.iter() {
-            match msg {
+            let result = match msg {
                 ExecutionOp::Stop(cb) => {
                     info!("[Recipe '{}'] Shutting down recipe.", self.script.name);
 
@@ -328,6 +578,7 @@ impl<Env> ExecutionTask<Env>
                     cb.lock().unwrap()(Ok(()));
                     return;
                 }
+                _ if stopped => Ok(()),
                 ExecutionOp::UpdateCondition { id, is_met, rule_index, condition_index } => {
                     debug!("[Recipe '{}'] Updating the state of rule {}, condition {} => {}",
                            self.script.name,
@@ -340,8 +591,78 @@ impl<Env> ExecutionTask<Env>
                                            &mut per_rule,
                                            rule_index,
                                            condition_index,
-                                           &api,
-                                           &on_event);
+                                           &env,
+                                           &on_event)
+                }
+                ExecutionOp::StateUpdate { key } => {
+                    self.update_state(&key, &mut per_rule, &env, &on_event);
+                    Ok(())
+                }
+                ExecutionOp::RuleTimer { rule_index, timer } => {
+                    debug!("[Recipe '{}'] Timer {:?} fired for rule {}",
+                           self.script.name,
+                           timer,
+                           rule_index);
+                    match timer {
+                        RuleTimerKind::Cooldown => {
+                            per_rule[rule_index].cooldown_timer.take();
+                            Ok(())
+                        }
+                        RuleTimerKind::Sustained => {
+                            let pending = per_rule[rule_index].sustained_timer.take();
+                            match pending {
+                                Some((_, getter)) => {
+                                    self.trigger(&self.script.name,
+                                                 &getter,
+                                                 &mut per_rule,
+                                                 rule_index,
+                                                 false,
+                                                 &env,
+                                                 &on_event)
+                                }
+                                // The conditions stopped holding meanwhile.
+                                None => Ok(()),
+                            }
+                        }
+                    }
+                }
+                ExecutionOp::ExpressionUpdate { event,
+                                                rule_index,
+                                                condition_index,
+                                                getter_index } => {
+                    match event {
+                        WatchEvent::EnterRange { channel: id, value, .. } => {
+                            debug!("[Recipe '{}'] Getter {} of the expression of rule {}, \
+                                    condition {} has changed: {:?}",
+                                   self.script.name,
+                                   id,
+                                   rule_index,
+                                   condition_index,
+                                   value);
+                            if let Some(state) = per_rule[rule_index].expression(condition_index) {
+                                state.getters[getter_index] = Some(value.to_json());
+                            }
+                            self.update_expression(&mut per_rule,
+                                                   rule_index,
+                                                   condition_index,
+                                                   &env,
+                                                   &on_event);
+                        }
+                        WatchEvent::Error { channel, error } => {
+                            warn!("[Recipe '{}'] Initialization error for {}: {}",
+                                  self.script.name,
+                                  channel,
+                                  error);
+                            let _ = on_event.send(ExecutionEvent::ChannelError {
+                                id: channel,
+                                error: error,
+                            });
+                        }
+                        // The latest value of a getter remains valid until another getter
+                        // sends a value.
+                        _ => {}
+                    }
+                    Ok(())
                 }
                 ExecutionOp::Update { event, rule_index, condition_index } => {
                     match event {
@@ -361,6 +682,10 @@ impl<Env> ExecutionTask<Env>
                                    self.script.name,
                                    id);
                             // A channel was removed. Its condition is therefore not met anymore.
+                            if let Some(state) = per_rule[rule_index].expression(condition_index) {
+                                state.sources.remove(&id);
+                                state.met.remove(&id);
+                            }
                             let msg = ExecutionOp::UpdateCondition {
                                 id: id.clone(),
                                 is_met: false,
@@ -382,49 +707,43 @@ impl<Env> ExecutionTask<Env>
                                    rule_index,
                                    condition_index,
                                    value);
-                            // We have entered a range. If there is a
-                            // timer, start it, otherwise update conditions.
-                            let msg = move || {
-                                ExecutionOp::UpdateCondition {
-                                    id: id.clone(),
-                                    is_met: true,
-                                    rule_index: rule_index,
-                                    condition_index: condition_index,
+                            // A schedule is only met on its days.
+                            let scheduled = match self.script.rules[rule_index].conditions
+                                [condition_index]
+                                .schedule {
+                                Some(ref schedule) => schedule.applies_to(&Local::today()),
+                                None => true,
+                            };
+                            if !scheduled {
+                                debug!("[Recipe '{}'] Rule {}, condition {} is not scheduled \
+                                        today.",
+                                       self.script.name,
+                                       rule_index,
+                                       condition_index);
+                                continue;
+                            }
+                            // With an expression, the value only counts if the test holds.
+                            let tested = match per_rule[rule_index].expression(condition_index) {
+                                Some(state) => {
+                                    state.sources.insert(id.clone(), value.to_json());
+                                    true
                                 }
+                                None => false,
                             };
-                            let duration =
-                                match per_rule[rule_index].per_condition[condition_index]
-                                    .duration {
-                                    None => {
-                                        debug!("[Recipe '{}'] No timer for rule {}, condition \
-                                                {}, we should trigger the execution immediately.",
-                                               self.script.name,
-                                               rule_index,
-                                               condition_index);
-                                        let _ = self.tx.send(msg());
-                                        continue;
-                                    }
-                                    Some(ref duration) => {
-                                        debug!("[Recipe '{}'] There is a timer for rule {}, \
-                                                condition {}, we should trigger the execution in \
-                                                {:?}s.",
-                                               self.script.name,
-                                               rule_index,
-                                               condition_index,
-                                               duration);
-                                        duration.clone()
-                                    }
-                                };
-
-                            let tx = self.tx.map(move |()| {
-                                msg()
-                            });
-                            per_rule[rule_index].ongoing_timer =
-                                Some(env.start_timer(duration.clone(), Box::new(tx)));
-                            let _ = on_event.send(ExecutionEvent::TimerStart {
-                                rule_index: rule_index,
-                                condition_index: condition_index,
-                            });
+                            if tested {
+                                self.update_expression(&mut per_rule,
+                                                       rule_index,
+                                                       condition_index,
+                                                       &env,
+                                                       &on_event);
+                            } else {
+                                self.enter_range(id,
+                                                 &mut per_rule,
+                                                 rule_index,
+                                                 condition_index,
+                                                 &env,
+                                                 &on_event);
+                            }
                         }
                         WatchEvent::ExitRange { channel: id, value, .. } => {
                             debug!("[Recipe '{}'] Getter {} has left the range for rule {}, \
@@ -434,27 +753,245 @@ impl<Env> ExecutionTask<Env>
                                    rule_index,
                                    condition_index,
                                    value);
-                            if per_rule[rule_index].ongoing_timer.is_some() {
-                                debug!("[Recipe '{}'] I need to cancel the timer for rule {}, \
-                                        condition {}",
-                                       self.script.name,
-                                       id,
-                                       rule_index);
-                                // Cancel the timer.
-                                per_rule[rule_index].ongoing_timer.take();
-                                let _ = on_event.send(ExecutionEvent::TimerCancel {
-                                    rule_index: rule_index,
-                                    condition_index: condition_index,
-                                });
-                            }
-                            // Regardless, update the condition.
-                            let msg = ExecutionOp::UpdateCondition {
-                                id: id,
-                                is_met: false,
-                                rule_index: rule_index,
-                                condition_index: condition_index,
+                            // With an expression, the value only counted if the test held.
+                            let was_met = match per_rule[rule_index].expression(condition_index) {
+                                Some(state) => {
+                                    state.sources.remove(&id);
+                                    state.met.remove(&id)
+                                }
+                                None => true,
                             };
-                            let _ = self.tx.send(msg);
+                            if was_met {
+                                self.exit_range(id,
+                                                &mut per_rule,
+                                                rule_index,
+                                                condition_index,
+                                                &on_event);
+                            }
+                        }
+                        WatchEvent::EnterRangeDelta { .. } => {
+                            // We only register watches with `Delivery::Full`.
+                            warn!("[Recipe '{}'] Unexpected delta event.", self.script.name);
+                        }
+                    }
+                    Ok(())
+                }
+            };
+            if let Err(err) = result {
+                warn!("[Recipe '{}'] Stopping recipe: {:?}", self.script.name, err);
+                stopped = true;
+                witnesses.clear();
+                for rule in &mut per_rule {
+                    rule.ongoing_timer.take();
+                    rule.sustained_timer.take();
+                    rule.cooldown_timer.take();
+                }
+                let _ = on_event.send(ExecutionEvent::Stopped { result: Err(err) });
+            }
+        }
+    }
+
+    /// Getter `id` has entered the range of a condition. If the condition has a duration,
+    /// start its timer, otherwise update the condition immediately.
+    fn enter_range<S>(&self,
+                      id: Id<Channel>,
+                      per_rule: &mut Vec<RuleState<Env>>,
+                      rule_index: usize,
+                      condition_index: usize,
+                      env: &Env,
+                      on_event: &S)
+        where S: ExtSender<ExecutionEvent> + Clone
+    {
+        let msg = move || {
+            ExecutionOp::UpdateCondition {
+                id: id.clone(),
+                is_met: true,
+                rule_index: rule_index,
+                condition_index: condition_index,
+            }
+        };
+        let duration = match per_rule[rule_index].per_condition[condition_index].duration {
+            None => {
+                debug!("[Recipe '{}'] No timer for rule {}, condition {}, we should trigger the \
+                        execution immediately.",
+                       self.script.name,
+                       rule_index,
+                       condition_index);
+                let _ = self.tx.send(msg());
+                return;
+            }
+            Some(ref duration) => {
+                debug!("[Recipe '{}'] There is a timer for rule {}, condition {}, we should \
+                        trigger the execution in {:?}s.",
+                       self.script.name,
+                       rule_index,
+                       condition_index,
+                       duration);
+                duration.clone()
+            }
+        };
+
+        let tx = self.tx.map(move |()| msg());
+        per_rule[rule_index].ongoing_timer = Some(env.start_timer(duration, Box::new(tx)));
+        let _ = on_event.send(ExecutionEvent::TimerStart {
+            rule_index: rule_index,
+            condition_index: condition_index,
+        });
+    }
+
+    /// Getter `id` has left the range of a condition. Cancel the timer, if any, and update
+    /// the condition.
+    fn exit_range<S>(&self,
+                     id: Id<Channel>,
+                     per_rule: &mut Vec<RuleState<Env>>,
+                     rule_index: usize,
+                     condition_index: usize,
+                     on_event: &S)
+        where S: ExtSender<ExecutionEvent> + Clone
+    {
+        if per_rule[rule_index].ongoing_timer.is_some() {
+            debug!("[Recipe '{}'] I need to cancel the timer for rule {}, condition {}",
+                   self.script.name,
+                   rule_index,
+                   condition_index);
+            // Cancel the timer.
+            per_rule[rule_index].ongoing_timer.take();
+            let _ = on_event.send(ExecutionEvent::TimerCancel {
+                rule_index: rule_index,
+                condition_index: condition_index,
+            });
+        }
+        // Regardless, update the condition.
+        let msg = ExecutionOp::UpdateCondition {
+            id: id,
+            is_met: false,
+            rule_index: rule_index,
+            condition_index: condition_index,
+        };
+        let _ = self.tx.send(msg);
+    }
+
+    /// Evaluate `Match::expression` again for each getter of the condition, after a value
+    /// changed. Getters for which the test starts or stops holding enter or leave the range.
+    fn update_expression<S>(&self,
+                            per_rule: &mut Vec<RuleState<Env>>,
+                            rule_index: usize,
+                            condition_index: usize,
+                            env: &Env,
+                            on_event: &S)
+        where S: ExtSender<ExecutionEvent> + Clone
+    {
+        let test = match self.script.rules[rule_index].conditions[condition_index].expression {
+            Some(ref test) => test,
+            None => return,
+        };
+        let (entered, exited) = match per_rule[rule_index].expression(condition_index) {
+            Some(state) => {
+                let (entered, exited): (Vec<_>, Vec<_>) = state.sources
+                    .iter()
+                    .filter(|&(id, value)| {
+                        test.evaluate(value, &state.getters) != state.met.contains(id)
+                    })
+                    .map(|(id, _)| id.clone())
+                    .partition(|id| !state.met.contains(id));
+                for id in &entered {
+                    state.met.insert(id.clone());
+                }
+                for id in &exited {
+                    state.met.remove(id);
+                }
+                (entered, exited)
+            }
+            None => return,
+        };
+        debug!("[Recipe '{}'] Evaluated the expression of rule {}, condition {}: entered {:?}, \
+                exited {:?}",
+               self.script.name,
+               rule_index,
+               condition_index,
+               entered,
+               exited);
+        for id in exited {
+            self.exit_range(id, per_rule, rule_index, condition_index, on_event);
+        }
+        for id in entered {
+            self.enter_range(id, per_rule, rule_index, condition_index, env, on_event);
+        }
+    }
+
+    /// The value stored under `key` in the state of the script has changed. The matches that
+    /// check this key enter or leave their range, as if the state were a getter.
+    fn update_state<S>(&self,
+                       key: &str,
+                       per_rule: &mut Vec<RuleState<Env>>,
+                       env: &Env,
+                       on_event: &S)
+        where S: ExtSender<ExecutionEvent> + Clone
+    {
+        use std::mem::replace;
+
+        let value = self.state.borrow().get(key).cloned();
+        let id = state_channel(key);
+        for (rule, rule_index) in self.script.rules.iter().zip(0 as usize..) {
+            for (condition, condition_index) in rule.conditions.iter().zip(0 as usize..) {
+                if condition.state.as_ref().map_or(true, |state| state != key) {
+                    continue;
+                }
+                let in_range = match value {
+                    Some(ref value) => condition.when.as_ref().map_or(true, |when| {
+                        when.to_json() == *value
+                    }),
+                    None => false,
+                };
+                debug!("[Recipe '{}'] State {} is now {:?}, in the range of rule {}, \
+                        condition {}: {}",
+                       self.script.name,
+                       key,
+                       value,
+                       rule_index,
+                       condition_index,
+                       in_range);
+                // With an expression, the value only counts if the test holds.
+                let was_met = match per_rule[rule_index].expression(condition_index) {
+                    Some(state) => {
+                        match value {
+                            Some(ref value) if in_range => {
+                                state.sources.insert(id.clone(), value.clone());
+                                Some(false)
+                            }
+                            _ => {
+                                state.sources.remove(&id);
+                                Some(state.met.remove(&id))
+                            }
+                        }
+                    }
+                    None => None,
+                };
+                match was_met {
+                    Some(true) => {
+                        self.exit_range(id.clone(), per_rule, rule_index, condition_index, on_event)
+                    }
+                    Some(false) => {
+                        self.update_expression(per_rule, rule_index, condition_index, env, on_event)
+                    }
+                    None => {
+                        let was_in_range =
+                            replace(&mut per_rule[rule_index].per_condition[condition_index]
+                                        .in_state,
+                                    in_range);
+                        if in_range && !was_in_range {
+                            self.enter_range(id.clone(),
+                                             per_rule,
+                                             rule_index,
+                                             condition_index,
+                                             env,
+                                             on_event);
+                        } else if !in_range && was_in_range {
+                            self.exit_range(id.clone(),
+                                            per_rule,
+                                            rule_index,
+                                            condition_index,
+                                            on_event);
                         }
                     }
                 }
@@ -462,8 +999,30 @@ impl<Env> ExecutionTask<Env>
         }
     }
 
+    /// Store `value` under `key` in the state of the script, then update the matches that
+    /// check it.
+    fn set_state<S>(&self, key: &str, value: JSON, on_event: &S)
+        where S: ExtSender<ExecutionEvent>
+    {
+        let previous = self.state.borrow_mut().insert(key.to_owned(), value.clone());
+        if previous.as_ref() == Some(&value) {
+            return;
+        }
+        debug!("[Recipe '{}'] Setting state {} to {:?}",
+               self.script.name,
+               key,
+               value);
+        let _ = on_event.send(ExecutionEvent::StateChanged {
+            key: key.to_owned(),
+            value: value,
+        });
+        let _ = self.tx.send(ExecutionOp::StateUpdate { key: key.to_owned() });
+    }
+
     /// A getter just entered/left a range. Update the conditions to determine whether
     /// we now need to fire the statements.
+    ///
+    /// Fails if the script needs to stop, see `LoopPolicy::Stop`.
     fn update_conditions<S>(&self,
                             name: &str,
                             id: Id<Channel>,
@@ -471,8 +1030,9 @@ impl<Env> ExecutionTask<Env>
                             per_rule: &mut Vec<RuleState<Env>>,
                             rule_index: usize,
                             condition_index: usize,
-                            api: &Env::API,
+                            env: &Env,
                             on_event: &S)
+                            -> Result<(), Error>
         where S: ExtSender<ExecutionEvent> + Clone
     {
         use std::mem::replace;
@@ -480,7 +1040,7 @@ impl<Env> ExecutionTask<Env>
         let was_met = if getter_is_met {
             !per_rule[rule_index].per_condition[condition_index]
                 .per_getter
-                .insert(id)
+                .insert(id.clone())
         } else {
             per_rule[rule_index].per_condition[condition_index]
                 .per_getter
@@ -495,7 +1055,7 @@ impl<Env> ExecutionTask<Env>
             debug!("[Thinkerbell update_condition {}] Nothing has changed.",
                    name);
             // Nothing has changed, no need to update any further.
-            return;
+            return Ok(());
         }
 
         // 1. Is the match met?
@@ -507,20 +1067,48 @@ impl<Env> ExecutionTask<Env>
             .per_getter
             .len() > 0;
 
-        per_rule[rule_index].per_condition[condition_index].match_is_met = some_getter_is_met;
+        {
+            let condition = &mut per_rule[rule_index].per_condition[condition_index];
+            if condition.match_is_met != some_getter_is_met {
+                condition.entered_at = if some_getter_is_met {
+                    Some(Instant::now())
+                } else {
+                    None
+                };
+            }
+            condition.match_is_met = some_getter_is_met;
+        }
 
         // 2. Is the condition met?
         //
         // The condition is met iff all of the
-        // matches are met.
-        let condition_is_met = per_rule[rule_index]
+        // matches are met and, if the rule has a
+        // `within`, they became met close enough
+        // to each other.
+        let rule = &self.script.rules[rule_index];
+        let all_met = per_rule[rule_index]
             .per_condition
             .iter()
-            .find(|condition_state| condition_state.match_is_met)
-            .is_some();
+            .all(|condition_state| condition_state.match_is_met);
+        let condition_is_met = all_met &&
+                               match rule.within {
+            Some(_) if per_rule[rule_index].rule_is_met => true,
+            Some(ref within) => {
+                let entered: Vec<_> = per_rule[rule_index]
+                    .per_condition
+                    .iter()
+                    .filter_map(|condition_state| condition_state.entered_at)
+                    .collect();
+                match (entered.iter().min(), entered.iter().max(), within.as_duration().to_std()) {
+                    (Some(first), Some(last), Ok(within)) => last.duration_since(*first) <= within,
+                    _ => false,
+                }
+            }
+            None => true,
+        };
 
-        // 3. Are we in a case in which the
-        // condition was not met and is now met?
+        // 3. Are we in a case in which the condition was not met
+        // and is now met, or the other way around?
         let condition_was_met = replace(&mut per_rule[rule_index].rule_is_met, condition_is_met);
 
         debug!("[Thinkerbell update_condition {}] Updating condition for rule: {} => {}",
@@ -529,40 +1117,204 @@ impl<Env> ExecutionTask<Env>
                condition_is_met);
 
         if !condition_was_met && condition_is_met {
-            // Ahah, we have just triggered the statements!
-            debug!("[Thinkerbell update_condition {}] Triggering {} statements.",
-                   name,
-                   self.script.rules[rule_index].execute.len());
-            for (statement, statement_index) in self.script.rules[rule_index]
-                .execute
-                .iter()
-                .zip(0..) {
-                debug!("[Thinkerbell update_condition {}] Triggering statement {}/{}.",
-                       name,
-                       statement_index,
-                       self.script.rules[rule_index].execute.len());
-                let result = statement.eval(&api, &self.owner);
-                debug!("[Thinkerbell update_condition {}] Statement result {}/{}: {:?}.",
-                       name,
-                       statement_index,
-                       self.script.rules[rule_index].execute.len(),
-                       result);
-                if result.is_empty() {
-                    warn!("[Recipe '{}'] In rule {}, attempting to trigger statement {}, \
-                           couldn't find any receiver channel.",
-                          name,
-                          rule_index,
-                          condition_index);
+            match rule.sustained_for {
+                None => return self.trigger(name, &id, per_rule, rule_index, false, env, on_event),
+                Some(ref duration) => {
+                    debug!("[Thinkerbell update_condition {}] The conditions must now hold for \
+                            {:?}.",
+                           name,
+                           duration);
+                    let tx = self.tx.map(move |()| {
+                        ExecutionOp::RuleTimer {
+                            rule_index: rule_index,
+                            timer: RuleTimerKind::Sustained,
+                        }
+                    });
+                    let guard = env.start_timer(duration.clone(), Box::new(tx));
+                    per_rule[rule_index].sustained_timer = Some((guard, id));
                 }
+            }
+        } else if condition_was_met && !condition_is_met {
+            // If the conditions didn't hold for `sustained_for`, `execute` didn't happen, so
+            // there is nothing for `otherwise` to follow.
+            per_rule[rule_index].sustained_timer.take();
+            if replace(&mut per_rule[rule_index].executed, false) {
+                return self.trigger(name, &id, per_rule, rule_index, true, env, on_event);
+            }
+        }
+        debug!("[Thinkerbell update_condition {}] done.", name);
+        Ok(())
+    }
 
-                let _ = on_event.send(ExecutionEvent::Sent {
-                    rule_index: rule_index,
-                    statement_index: statement_index,
-                    result: result,
+    /// Trigger the statements of rule `rule_index`, those of `otherwise` or `execute`, because
+    /// of `getter`.
+    ///
+    /// Fails if the script needs to stop, see `LoopPolicy::Stop`.
+    fn trigger<S>(&self,
+                  name: &str,
+                  getter: &Id<Channel>,
+                  per_rule: &mut Vec<RuleState<Env>>,
+                  rule_index: usize,
+                  otherwise: bool,
+                  env: &Env,
+                  on_event: &S)
+                  -> Result<(), Error>
+        where S: ExtSender<ExecutionEvent> + Clone
+    {
+        let api = env.api();
+        let rule = &self.script.rules[rule_index];
+        let statements = if otherwise {
+            &rule.otherwise
+        } else {
+            &rule.execute
+        };
+        if statements.is_empty() {
+            // Nothing sent, so nothing to echo.
+            per_rule[rule_index].loop_state.last_sent = None;
+            return Ok(());
+        }
+        if !otherwise && per_rule[rule_index].cooldown_timer.is_some() {
+            debug!("[Thinkerbell update_condition {}] Cooling down, skipping the statements.",
+                   name);
+            return Ok(());
+        }
+        if !try!(self.check_loop(name,
+                                 getter,
+                                 rule_index,
+                                 &mut per_rule[rule_index].loop_state,
+                                 api,
+                                 on_event)) {
+            debug!("[Thinkerbell update_condition {}] Suppressing the statements of a loop.",
+                   name);
+            return Ok(());
+        }
+        if !otherwise {
+            per_rule[rule_index].executed = true;
+            if let Some(ref cooldown) = rule.cooldown {
+                let tx = self.tx.map(move |()| {
+                    ExecutionOp::RuleTimer {
+                        rule_index: rule_index,
+                        timer: RuleTimerKind::Cooldown,
+                    }
                 });
+                per_rule[rule_index].cooldown_timer =
+                    Some(env.start_timer(cooldown.clone(), Box::new(tx)));
+            }
+        }
+
+        // Ahah, we have just triggered the statements!
+        debug!("[Thinkerbell update_condition {}] Triggering {} statements.",
+               name,
+               statements.len());
+        let mut sent = vec![];
+        for (statement, statement_index) in statements.iter().zip(0..) {
+            debug!("[Thinkerbell update_condition {}] Triggering statement {}/{}.",
+                   name,
+                   statement_index,
+                   statements.len());
+            if let Some(ref key) = statement.set_state {
+                self.set_state(key, statement.value.to_json(), on_event);
+                continue;
+            }
+            let result = statement.eval(api, &self.owner);
+            debug!("[Thinkerbell update_condition {}] Statement result {}/{}: {:?}.",
+                   name,
+                   statement_index,
+                   statements.len(),
+                   result);
+            if result.is_empty() {
+                warn!("[Recipe '{}'] In rule {}, attempting to trigger statement {}, couldn't \
+                       find any receiver channel.",
+                      name,
+                      rule_index,
+                      statement_index);
+            }
+            sent.extend(result.iter()
+                .filter(|&&(_, ref result)| result.is_ok())
+                .map(|&(ref id, _)| id.clone()));
+
+            let _ = on_event.send(ExecutionEvent::Sent {
+                rule_index: rule_index,
+                statement_index: statement_index,
+                otherwise: otherwise,
+                result: result,
+            });
+        }
+
+        let state = &mut per_rule[rule_index].loop_state;
+        if state.echoes < self.loop_detection.max_echoes {
+            state.chain.extend(sent.iter().cloned());
+        }
+        let services = api.get_channels(sent.iter()
+                .map(|id| ChannelSelector::new().with_id(id))
+                .collect())
+            .into_iter()
+            .map(|channel| channel.service)
+            .collect();
+        state.last_sent = Some((Instant::now(), sent, services));
+        Ok(())
+    }
+
+    /// Rule `rule_index` is about to trigger its statements because of `getter`. Find out
+    /// whether the rule is re-triggering itself and what to do about it: `Ok(true)` if the
+    /// statements should proceed, `Ok(false)` if they should be skipped, or an error if the
+    /// script should stop.
+    fn check_loop<S>(&self,
+                     name: &str,
+                     getter: &Id<Channel>,
+                     rule_index: usize,
+                     state: &mut LoopState,
+                     api: &Env::API,
+                     on_event: &S)
+                     -> Result<bool, Error>
+        where S: ExtSender<ExecutionEvent>
+    {
+        let is_echo = match state.last_sent {
+            Some((ref at, ref channels, ref services)) if at.elapsed() <
+                                                          self.loop_detection.window => {
+                channels.contains(getter) ||
+                api.get_channels(vec![ChannelSelector::new().with_id(getter)])
+                    .iter()
+                    .any(|channel| services.contains(&channel.service))
+            }
+            _ => false,
+        };
+        if !is_echo {
+            state.echoes = 0;
+            state.chain = vec![getter.clone()];
+            return Ok(true);
+        }
+        state.echoes += 1;
+        if state.echoes > self.loop_detection.max_echoes {
+            // We have already reported this loop.
+            return Ok(self.loop_detection.policy == LoopPolicy::Warn);
+        }
+        state.chain.push(getter.clone());
+        if state.echoes < self.loop_detection.max_echoes {
+            return Ok(true);
+        }
+
+        let policy = self.loop_detection.policy;
+        warn!("[Recipe '{}'] Rule {} keeps re-triggering itself ({:?}): {}",
+              name,
+              rule_index,
+              policy,
+              state.chain.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(" -> "));
+        let _ = on_event.send(ExecutionEvent::LoopDetected {
+            rule_index: rule_index,
+            chain: state.chain.clone(),
+            policy: policy,
+        });
+        match policy {
+            LoopPolicy::Warn => Ok(true),
+            LoopPolicy::Suppress => Ok(false),
+            LoopPolicy::Stop => {
+                Err(Error::LoopDetected {
+                    rule_index: rule_index,
+                    chain: state.chain.clone(),
+                })
             }
         }
-        debug!("[Thinkerbell update_condition {}] done.", name);
     }
 }
 
@@ -580,6 +1332,96 @@ impl<Env> Statement<CompiledCtx<Env>>
             .map(|(id, result)| (id, result.map_err(|err| Error::APIError(err))))
             .collect()
     }
+
+    /// The channels to which `eval` would send the value, without sending anything.
+    fn resolve(&self, api: &Env::API) -> Vec<Id<Channel>> {
+        api.get_channels(self.destination.clone())
+            .into_iter()
+            .filter(|channel| channel.supports_send.is_some())
+            .map(|channel| channel.id)
+            .collect()
+    }
+}
+
+/// The getter that stands for the value stored under `key` in the state of a script, see
+/// `update_state`. No such channel exists.
+fn state_channel(key: &str) -> Id<Channel> {
+    Id::new(&format!("{}:{}", STATE_FEATURE, key))
+}
+
+/// A statement that a script would execute, as recorded by `simulate`.
+#[derive(Clone, Debug)]
+pub struct SimulatedStatement {
+    pub rule_index: usize,
+    pub statement_index: usize,
+    /// `true` if the statement belongs to `otherwise` rather than `execute`.
+    pub otherwise: bool,
+    /// The channels that would receive the value. Empty if no channel matches the
+    /// destination, which usually means that the script is wrong, or if the statement
+    /// sets the state of the script.
+    pub channels: Vec<Id<Channel>>,
+    pub value: Payload,
+    /// The key under which the value would be stored, see `Statement::set_state`.
+    pub state: Option<String>,
+}
+
+/// What a script would do, as found out by `simulate`.
+#[derive(Clone, Debug, Default)]
+pub struct Simulation {
+    /// For each rule, for each condition, the getters that the condition would watch.
+    pub getters: Vec<Vec<Vec<Id<Channel>>>>,
+    /// The statements that would be executed, in order, if the conditions of each rule
+    /// became met and then stopped being met.
+    pub trace: Vec<SimulatedStatement>,
+}
+
+/// Compile a script and resolve its selectors against the devices of `env`, but record the
+/// statements instead of sending their values, e.g. to check a script before starting it.
+///
+/// Conditions are not evaluated: each rule is assumed to trigger `execute`, then `otherwise`.
+pub fn simulate<Env>(env: &Env, script: Script<UncheckedCtx>) -> Result<Simulation, Error>
+    where Env: ExecutableDevEnv
+{
+    let compiler = try!(Compiler::<Env>::new().map_err(Error::CompileError));
+    let script = try!(compiler.compile(script).map_err(Error::CompileError));
+    let api = env.api();
+    let mut simulation = Simulation::default();
+    for (rule, rule_index) in script.rules.iter().zip(0..) {
+        simulation.getters.push(rule.conditions
+            .iter()
+            .map(|condition| if condition.state.is_some() {
+                vec![]
+            } else {
+                api.get_channels(condition.source.clone())
+                    .into_iter()
+                    .map(|channel| channel.id)
+                    .collect()
+            })
+            .collect());
+        let statements = rule.execute
+            .iter()
+            .zip(0..)
+            .map(|(statement, index)| (statement, index, false))
+            .chain(rule.otherwise
+                .iter()
+                .zip(0..)
+                .map(|(statement, index)| (statement, index, true)));
+        for (statement, statement_index, otherwise) in statements {
+            simulation.trace.push(SimulatedStatement {
+                rule_index: rule_index,
+                statement_index: statement_index,
+                otherwise: otherwise,
+                channels: if statement.set_state.is_some() {
+                    vec![]
+                } else {
+                    statement.resolve(api)
+                },
+                value: statement.value.clone(),
+                state: statement.set_state.clone(),
+            });
+        }
+    }
+    Ok(simulation)
 }
 
 
@@ -596,4 +1438,9 @@ pub enum Error {
     CompileError(compile::Error),
     StartStopError(StartStopError),
     APIError(api::Error),
+    /// A rule kept re-triggering itself, see `LoopPolicy::Stop`.
+    LoopDetected {
+        rule_index: usize,
+        chain: Vec<Id<Channel>>,
+    },
 }
@@ -13,8 +13,10 @@ use transformable_channels::mpsc::*;
 
 use foxbox_thinkerbell::fake_env::FakeEnv;
 use foxbox_thinkerbell::manager::*;
+use foxbox_thinkerbell::run::{Error as RunError, ExecutionEvent, StartStopError};
 
 use foxbox_taxonomy::api::User;
+use foxbox_taxonomy::channel::Channel;
 use foxbox_taxonomy::util::Id;
 
 fn load_json(path: &str) -> String {
@@ -83,3 +85,108 @@ fn test_database_add_remove_script() {
         .unwrap();
     assert_eq!(db.get_running_count(), 1);
 }
+
+#[test]
+fn test_execution_log() {
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    println!("* Cleaning up the database.");
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env,
+                                    Path::new("./test_execution_log_database.sqlite"),
+                                    Box::new(tx))
+        .unwrap();
+    db.remove_all().unwrap();
+
+    let name = Id::<ScriptId>::new("Sample Ruleset");
+    db.put(&name,
+             &load_json("./examples/ruleset.json"),
+             &User::None)
+        .unwrap();
+    let log = db.execution_log();
+
+    println!("* Initially, the log of the recipe is empty.");
+    assert_eq!(log.events(&name).unwrap(), vec![]);
+    assert_eq!(log.last_execution(&name).unwrap(), None);
+
+    println!("* Timers are not worth logging.");
+    log.record(&name, &ExecutionEvent::TimerStart { rule_index: 0, condition_index: 0 }).unwrap();
+    assert_eq!(log.events(&name).unwrap(), vec![]);
+
+    println!("* Events are logged most recent first.");
+    log.record(&name, &ExecutionEvent::Starting { result: Ok(()) }).unwrap();
+    log.record(&name, &ExecutionEvent::Sent {
+        rule_index: 1,
+        statement_index: 0,
+        otherwise: false,
+        result: vec![(Id::<Channel>::new("light 1"), Ok(())),
+                     (Id::<Channel>::new("light 2"),
+                      Err(RunError::StartStopError(StartStopError::ThreadError)))],
+    }).unwrap();
+    log.record(&name, &ExecutionEvent::Stopped { result: Ok(()) }).unwrap();
+    let kinds: Vec<_> = log.events(&name).unwrap().into_iter().map(|event| event.kind).collect();
+    assert_eq!(kinds, vec!["stopped", "sent", "started"]);
+
+    println!("* The last execution tells which channels the recipe sent values to.");
+    let last = log.last_execution(&name).unwrap().unwrap();
+    assert_eq!(last.kind, "sent");
+    assert_eq!(last.details.find("rule").and_then(|rule| rule.as_u64()), Some(1));
+    let succeeded = last.details.find("succeeded").and_then(|succeeded| succeeded.as_array());
+    assert_eq!(succeeded.unwrap().len(), 1);
+    assert!(last.details.find("failed").and_then(|failed| failed.find("light 2")).is_some());
+
+    println!("* Only the latest events are kept.");
+    for _ in 0..150 {
+        log.record(&name, &ExecutionEvent::Starting { result: Ok(()) }).unwrap();
+    }
+    assert_eq!(log.events(&name).unwrap().len(), 100);
+
+    println!("* Removing the recipe forgets its log.");
+    db.remove(&name).unwrap();
+    assert_eq!(log.events(&name).unwrap(), vec![]);
+}
+
+#[test]
+fn test_script_state() {
+    use foxbox_taxonomy::parse::JSON;
+
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    println!("* Cleaning up the database.");
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env,
+                                    Path::new("./test_script_state_database.sqlite"),
+                                    Box::new(tx))
+        .unwrap();
+    db.remove_all().unwrap();
+
+    let name = Id::<ScriptId>::new("Sample Ruleset");
+    db.put(&name,
+             &load_json("./examples/ruleset.json"),
+             &User::None)
+        .unwrap();
+    let state = db.script_state();
+
+    println!("* Initially, the recipe has no state.");
+    assert!(state.values(&name).unwrap().is_empty());
+
+    println!("* Values are stored per key, the latest value replacing the previous one.");
+    state.set(&name, "armed", &JSON::Bool(false)).unwrap();
+    state.set(&name, "armed", &JSON::Bool(true)).unwrap();
+    state.set(&name, "count", &JSON::U64(3)).unwrap();
+    let values = state.values(&name).unwrap();
+    assert_eq!(values.len(), 2);
+    assert_eq!(values.get("armed"), Some(&JSON::Bool(true)));
+    assert_eq!(values.get("count").and_then(|count| count.as_u64()), Some(3));
+
+    println!("* The state survives restarting the recipe.");
+    db.set_enabled(&name, false).unwrap();
+    db.set_enabled(&name, true).unwrap();
+    assert_eq!(state.values(&name).unwrap().len(), 2);
+
+    println!("* Removing the recipe forgets its state.");
+    db.remove(&name).unwrap();
+    assert!(state.values(&name).unwrap().is_empty());
+}
@@ -1,10 +1,13 @@
 extern crate foxbox_thinkerbell;
 extern crate foxbox_taxonomy;
 extern crate serde_json;
+extern crate chrono;
 
 use foxbox_taxonomy::parse::*;
 use foxbox_thinkerbell::ast::*;
 
+use chrono::NaiveDate;
+
 #[test]
 fn test_parse_bad_field() {
     let src = "{
@@ -40,3 +43,108 @@ fn test_parse_simple_rule() {
     Script::from_str(src).unwrap();
 }
 
+
+#[test]
+fn test_parse_expression() {
+    let src = r#"{
+      "source": [{"id": "thermometer"}],
+      "feature": "sensor/temperature",
+      "expression": {"Geq": [{"value": "C"}, {"*": [
+        {"getter": [{"id": "setpoint"}], "feature": "sensor/temperature", "field": "C"},
+        {"getter": [{"id": "margin"}], "feature": "sensor/temperature", "field": "C"}
+      ]}]}
+    }"#;
+    let match_ = Match::<UncheckedCtx>::from_str(src).unwrap();
+    assert!(match_.when.is_none());
+    let test = match_.expression.unwrap();
+    assert_eq!(test.comparison, Comparison::Geq);
+    let indices: Vec<_> = test.getters()
+        .into_iter()
+        .map(|getter| match *getter {
+            Expression::Getter { index, .. } => index,
+            _ => panic!("Not a getter"),
+        })
+        .collect();
+    assert_eq!(indices, vec![0, 1]);
+
+    // Without an expression, `when` is required.
+    let src = r#"{"source": [{"id": "thermometer"}], "feature": "sensor/temperature"}"#;
+    assert!(Match::<UncheckedCtx>::from_str(src).is_err());
+
+    // Operators take exactly two operands.
+    assert!(Test::from_str(r#"{"Gt": [{"value": ""}]}"#).is_err());
+    assert!(Test::from_str(r#"{"Gt": [1, {"/": [1, 2, 3]}]}"#).is_err());
+    assert!(Test::from_str(r#"{"Between": [1, 2]}"#).is_err());
+}
+
+#[test]
+fn test_parse_schedule() {
+    let src = r#"{"schedule": "0,30 7 * * 1-5"}"#;
+    let match_ = Match::<UncheckedCtx>::from_str(src).unwrap();
+    assert!(match_.when.is_none());
+    assert_eq!(match_.feature.to_string(), SCHEDULE_FEATURE);
+    assert_eq!(match_.source.len(), 1);
+    let schedule = match_.schedule.unwrap();
+    assert_eq!(schedule.times, vec![7 * 3600, 7 * 3600 + 30 * 60]);
+    assert_eq!(schedule.weekdays, Some(vec![1, 2, 3, 4, 5]));
+    assert_eq!(schedule.ranges().len(), 2);
+
+    // Steps, and 7 for Sunday.
+    let schedule = Schedule::from_str(r#""*/20 8 * * 0,7""#).unwrap();
+    assert_eq!(schedule.times, vec![8 * 3600, 8 * 3600 + 1200, 8 * 3600 + 2400]);
+    assert_eq!(schedule.weekdays, Some(vec![0]));
+
+    let schedule = Schedule::from_str(r#"{"days": ["Sat", "Sun"], "times": ["22:15", "09:00"]}"#).unwrap();
+    assert_eq!(schedule.times, vec![9 * 3600, 22 * 3600 + 15 * 60]);
+    assert_eq!(schedule.weekdays, Some(vec![0, 6]));
+    assert_eq!(schedule.days_of_month, None);
+
+    // As with cron, restricting both kinds of days accepts either.
+    // 2016-10-01 is a Saturday, 2016-10-03 is a Monday.
+    let schedule = Schedule::from_str(r#""0 12 1 * 1""#).unwrap();
+    assert!(schedule.applies_to(&NaiveDate::from_ymd(2016, 10, 1)));
+    assert!(schedule.applies_to(&NaiveDate::from_ymd(2016, 10, 3)));
+    assert!(!schedule.applies_to(&NaiveDate::from_ymd(2016, 10, 4)));
+    let schedule = Schedule::from_str(r#""0 12 * 12 *""#).unwrap();
+    assert!(!schedule.applies_to(&NaiveDate::from_ymd(2016, 10, 1)));
+    assert!(schedule.applies_to(&NaiveDate::from_ymd(2016, 12, 25)));
+
+    // Invalid schedules.
+    assert!(Schedule::from_str(r#""30 7 * *""#).is_err());
+    assert!(Schedule::from_str(r#""60 7 * * *""#).is_err());
+    assert!(Schedule::from_str(r#""30 7 * * 1-""#).is_err());
+    assert!(Schedule::from_str(r#"{"times": ["7h30"]}"#).is_err());
+    assert!(Schedule::from_str(r#"{"times": []}"#).is_err());
+    assert!(Schedule::from_str(r#"{"days": ["Someday"], "times": ["07:30"]}"#).is_err());
+
+    // A schedule replaces `when`.
+    let src = r#"{"schedule": "30 7 * * *", "when": {"Duration": 0}}"#;
+    assert!(Match::<UncheckedCtx>::from_str(src).is_err());
+}
+
+#[test]
+fn test_parse_state() {
+    let src = r#"{"state": "armed", "when": true}"#;
+    let match_ = Match::<UncheckedCtx>::from_str(src).unwrap();
+    assert_eq!(match_.state, Some("armed".to_owned()));
+    assert_eq!(match_.feature.to_string(), STATE_FEATURE);
+    assert!(match_.source.is_empty());
+
+    // The stored value may also be tested.
+    let src = r#"{"state": "count", "expression": {"Geq": [{"value": ""}, 3]}}"#;
+    assert!(Match::<UncheckedCtx>::from_str(src).is_ok());
+
+    // A state replaces the schedule.
+    let src = r#"{"state": "armed", "schedule": "30 7 * * *"}"#;
+    assert!(Match::<UncheckedCtx>::from_str(src).is_err());
+
+    let src = r#"{"set_state": "armed", "value": {"since": 1476525600}}"#;
+    let statement = Statement::<UncheckedCtx>::from_str(src).unwrap();
+    assert_eq!(statement.set_state, Some("armed".to_owned()));
+    assert_eq!(statement.value.to_json(),
+               serde_json::from_str::<JSON>(r#"{"since": 1476525600}"#).unwrap());
+    assert!(statement.destination.is_empty());
+
+    // Storing requires a value.
+    assert!(Statement::<UncheckedCtx>::from_str(r#"{"set_state": "armed"}"#).is_err());
+}
@@ -14,7 +14,7 @@ use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::io::*;
 use foxbox_taxonomy::selector::*;
 use foxbox_taxonomy::services::*;
-use foxbox_taxonomy::values::{ format, Duration, OnOff, OpenClosed, TimeStamp, TypeError as APITypeError , Value };
+use foxbox_taxonomy::values::{ format, Duration, OnOff, OpenClosed, Temperature, TimeStamp, TypeError as APITypeError , Value };
 
 use std::fmt::Debug;
 use std::marker::PhantomData;
@@ -106,8 +106,12 @@ fn test_run() {
                             ChannelSelector::new()
                         ],
                         feature: Id::new("light/is-on"),
-                        when: data_on.clone(),
+                        when: Some(data_on.clone()),
                         duration: None,
+                        filter: None,
+                        expression: None,
+                        schedule: None,
+                        state: None,
                         phantom: PhantomData
                     }
                 ],
@@ -118,9 +122,14 @@ fn test_run() {
                         ],
                         value: data_off,
                         feature: Id::new("light/is-on"),
+                        set_state: None,
                         phantom: PhantomData,
                     }
                 ],
+                otherwise: vec![],
+                sustained_for: None,
+                within: None,
+                cooldown: None,
                 phantom: PhantomData
             }
         ],
@@ -501,8 +510,12 @@ fn test_run_with_delay() {
                             ChannelSelector::new()
                         ],
                         feature: Id::new("light/is-on"),
-                        when: data_on.clone(),
+                        when: Some(data_on.clone()),
                         duration: Some(Duration::from(chrono::Duration::seconds(10))),
+                        filter: None,
+                        expression: None,
+                        schedule: None,
+                        state: None,
                         phantom: PhantomData
                     }
                 ],
@@ -513,9 +526,14 @@ fn test_run_with_delay() {
                         ],
                         value: data_off,
                         feature: Id::new("light/is-on"),
+                        set_state: None,
                         phantom: PhantomData,
                     }
                 ],
+                otherwise: vec![],
+                sustained_for: None,
+                within: None,
+                cooldown: None,
                 phantom: PhantomData
             }
         ],
@@ -729,3 +747,1060 @@ fn test_run_with_delay() {
 
     println!("* Drop complete.");
 }
+
+#[test]
+fn test_run_otherwise() {
+    println!("* Starting test_run_otherwise.");
+    let (tx, rx) : (_, Receiver<Event>) = channel();
+
+    let tx_env = Box::new(tx.map(|event| Event::Env(event)));
+    let tx_run = tx.map(|event| Event::Run(event));
+    let (tx_done, rx_done) = channel();
+    let (tx_send, rx_send) = channel();
+
+    let env = FakeEnv::new(tx_env);
+    let mut exec = Execution::<FakeEnv>::new();
+
+    let data_off = Payload::from_data(OnOff::Off, &format::ON_OFF).unwrap();
+    let data_on = Payload::from_data(OnOff::On, &format::ON_OFF).unwrap();
+
+    thread::spawn(move || {
+        for msg in rx {
+            if let Event::Env(FakeEnvEvent::Done) = msg {
+                tx_done.send(()).unwrap();
+            } else if let Event::Env(FakeEnvEvent::Send { id, value }) = msg {
+                tx_send.send((id, value)).unwrap();
+            }
+        }
+    });
+
+    let adapter_id_1 = Id::<AdapterId>::new("Adapter 1");
+    let service_id_1 = Id::<ServiceId>::new("Service 1");
+    let getter_id_1 = Id::<Channel>::new("Getter 1");
+    let getter_id_2 = Id::<Channel>::new("Getter 2");
+    let setter_id_1 = Id::<Channel>::new("Setter 1");
+
+    let match_on = |id: &Id<Channel>| Match {
+        source: vec![
+            ChannelSelector::new().with_id(id)
+        ],
+        feature: Id::new("light/is-on"),
+        when: Some(data_on.clone()),
+        duration: None,
+        filter: None,
+        expression: None,
+        schedule: None,
+        state: None,
+        phantom: PhantomData
+    };
+    let send = |value: &Payload| Statement {
+        destination: vec![
+            ChannelSelector::new()
+        ],
+        value: value.clone(),
+        feature: Id::new("light/is-on"),
+        set_state: None,
+        phantom: PhantomData,
+    };
+
+    println!("* Preparing a script that turns the setter off once both getters are on, and back on otherwise.");
+    let script = Script {
+        name: "Test script".to_owned(),
+        rules: vec![
+            Rule {
+                conditions: vec![match_on(&getter_id_1), match_on(&getter_id_2)],
+                execute: vec![send(&data_off)],
+                otherwise: vec![send(&data_on)],
+                sustained_for: None,
+                within: None,
+                cooldown: None,
+                phantom: PhantomData
+            }
+        ],
+        phantom: PhantomData,
+    };
+    exec.start(env.clone(), script, User::None, tx_run).unwrap();
+
+    env.execute(Instruction::AddAdapters(vec![adapter_id_1.to_string()]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddServices(vec![
+        Service::empty(&service_id_1, &adapter_id_1)
+    ]));
+    rx_done.recv().unwrap();
+
+    for getter_id in vec![&getter_id_1, &getter_id_2] {
+        env.execute(Instruction::AddChannels(vec![
+            Channel {
+                id: getter_id.clone(),
+                service: service_id_1.clone(),
+                adapter: adapter_id_1.clone(),
+                supports_send: None,
+                .. LIGHT_IS_ON.clone()
+            }
+        ]));
+        rx_done.recv().unwrap();
+    }
+
+    env.execute(Instruction::AddChannels(vec![
+        Channel {
+            id: setter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_fetch: None,
+            supports_watch: None,
+            .. LIGHT_IS_ON.clone()
+        }
+    ]));
+    rx_done.recv().unwrap();
+
+    println!("* Meeting only one of the conditions does not trigger the send.");
+    env.execute(Instruction::InjectGetterValues(vec![
+        (getter_id_1.clone(), Ok(Value::new(OnOff::On)))
+    ]));
+    rx_done.recv().unwrap();
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("* Meeting all the conditions triggers `execute`.");
+    env.execute(Instruction::InjectGetterValues(vec![
+        (getter_id_2.clone(), Ok(Value::new(OnOff::On)))
+    ]));
+    rx_done.recv().unwrap();
+    let (id, value) = rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::new(OnOff::Off));
+
+    println!("* Once a condition stops being met, `otherwise` is triggered.");
+    env.execute(Instruction::InjectGetterValues(vec![
+        (getter_id_1.clone(), Ok(Value::new(OnOff::Off)))
+    ]));
+    rx_done.recv().unwrap();
+    let (id, value) = rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::new(OnOff::On));
+
+    println!("* `otherwise` is not triggered again while the conditions remain unmet.");
+    env.execute(Instruction::InjectGetterValues(vec![
+        (getter_id_2.clone(), Ok(Value::new(OnOff::Off)))
+    ]));
+    rx_done.recv().unwrap();
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("");
+}
+
+#[test]
+fn test_run_loop() {
+    println!("* Starting test_run_loop.");
+    let (tx, rx) : (_, Receiver<Event>) = channel();
+
+    let tx_env = Box::new(tx.map(|event| Event::Env(event)));
+    let tx_run = tx.map(|event| Event::Run(event));
+    let (tx_done, rx_done) = channel();
+    let (tx_send, rx_send) = channel();
+    let (tx_loop, rx_loop) = channel();
+
+    let env = FakeEnv::new(tx_env);
+    let mut exec = Execution::<FakeEnv>::new();
+
+    let data_off = Payload::from_data(OnOff::Off, &format::ON_OFF).unwrap();
+    let data_on = Payload::from_data(OnOff::On, &format::ON_OFF).unwrap();
+
+    thread::spawn(move || {
+        for msg in rx {
+            if let Event::Env(FakeEnvEvent::Done) = msg {
+                tx_done.send(()).unwrap();
+            } else if let Event::Env(FakeEnvEvent::Send { id, value }) = msg {
+                tx_send.send((id, value)).unwrap();
+            } else if let Event::Run(ExecutionEvent::LoopDetected { chain, policy, .. }) = msg {
+                tx_loop.send((chain, policy)).unwrap();
+            }
+        }
+    });
+
+    let adapter_id_1 = Id::<AdapterId>::new("Adapter 1");
+    let service_id_1 = Id::<ServiceId>::new("Service 1");
+    let getter_id_1 = Id::<Channel>::new("Getter 1");
+    let setter_id_1 = Id::<Channel>::new("Setter 1");
+
+    let send = |value: &Payload| Statement {
+        destination: vec![
+            ChannelSelector::new()
+        ],
+        value: value.clone(),
+        feature: Id::new("light/is-on"),
+        set_state: None,
+        phantom: PhantomData,
+    };
+
+    println!("* Preparing a script that turns off a light once it is on, and back on otherwise.");
+    let script = Script {
+        name: "Test script".to_owned(),
+        rules: vec![
+            Rule {
+                conditions: vec![
+                    Match {
+                        source: vec![
+                            ChannelSelector::new()
+                        ],
+                        feature: Id::new("light/is-on"),
+                        when: Some(data_on.clone()),
+                        duration: None,
+                        filter: None,
+                        expression: None,
+                        schedule: None,
+                        state: None,
+                        phantom: PhantomData
+                    }
+                ],
+                execute: vec![send(&data_off)],
+                otherwise: vec![send(&data_on)],
+                sustained_for: None,
+                within: None,
+                cooldown: None,
+                phantom: PhantomData
+            }
+        ],
+        phantom: PhantomData,
+    };
+    exec.start(env.clone(), script, User::None, tx_run).unwrap();
+
+    env.execute(Instruction::AddAdapters(vec![adapter_id_1.to_string()]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddServices(vec![
+        Service::empty(&service_id_1, &adapter_id_1)
+    ]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddChannels(vec![
+        Channel {
+            id: getter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_send: None,
+            .. LIGHT_IS_ON.clone()
+        },
+        Channel {
+            id: setter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_fetch: None,
+            supports_watch: None,
+            .. LIGHT_IS_ON.clone()
+        }
+    ]));
+    rx_done.recv().unwrap();
+
+    println!("* The light reacting to the values sent by the rule, the rule keeps re-triggering itself.");
+    let mut on = true;
+    for _ in 0..3 {
+        env.execute(Instruction::InjectGetterValues(vec![
+            (getter_id_1.clone(), Ok(Value::new(if on { OnOff::On } else { OnOff::Off })))
+        ]));
+        rx_done.recv().unwrap();
+        let (id, value) = rx_send.recv().unwrap();
+        assert_eq!(id, setter_id_1);
+        assert_eq!(value, Value::new(if on { OnOff::Off } else { OnOff::On }));
+        on = !on;
+    }
+    rx_loop.try_recv().unwrap_err();
+
+    println!("* Once the rule has echoed too many times, the loop is reported and broken.");
+    env.execute(Instruction::InjectGetterValues(vec![
+        (getter_id_1.clone(), Ok(Value::new(if on { OnOff::On } else { OnOff::Off })))
+    ]));
+    rx_done.recv().unwrap();
+    let (chain, policy) = rx_loop.recv().unwrap();
+    assert_eq!(policy, LoopPolicy::Suppress);
+    assert_eq!(chain, vec![getter_id_1.clone(), setter_id_1.clone(),
+                           getter_id_1.clone(), setter_id_1.clone(),
+                           getter_id_1.clone(), setter_id_1.clone(),
+                           getter_id_1.clone()]);
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("");
+}
+
+#[test]
+fn test_run_sustained_and_cooldown() {
+    println!("* Starting test_run_sustained_and_cooldown.");
+    let (tx, rx) : (_, Receiver<Event>) = channel();
+
+    let tx_env = Box::new(tx.map(|event| Event::Env(event)));
+    let tx_run = tx.map(|event| Event::Run(event));
+    let (tx_done, rx_done) = channel();
+    let (tx_send, rx_send) = channel();
+
+    let env = FakeEnv::new(tx_env);
+    let mut exec = Execution::<FakeEnv>::new();
+
+    let data_off = Payload::from_data(OnOff::Off, &format::ON_OFF).unwrap();
+    let data_on = Payload::from_data(OnOff::On, &format::ON_OFF).unwrap();
+
+    thread::spawn(move || {
+        for msg in rx {
+            if let Event::Env(FakeEnvEvent::Done) = msg {
+                tx_done.send(()).unwrap();
+            } else if let Event::Env(FakeEnvEvent::Send { id, value }) = msg {
+                tx_send.send((id, value)).unwrap();
+            }
+        }
+    });
+
+    let adapter_id_1 = Id::<AdapterId>::new("Adapter 1");
+    let service_id_1 = Id::<ServiceId>::new("Service 1");
+    let getter_id_1 = Id::<Channel>::new("Getter 1");
+    let setter_id_1 = Id::<Channel>::new("Setter 1");
+
+    println!("* Preparing a script that needs its condition to hold for 10s, then cools down for 60s.");
+    let script = Script {
+        name: "Test script".to_owned(),
+        rules: vec![
+            Rule {
+                conditions: vec![
+                    Match {
+                        source: vec![
+                            ChannelSelector::new()
+                        ],
+                        feature: Id::new("light/is-on"),
+                        when: Some(data_on.clone()),
+                        duration: None,
+                        filter: None,
+                        expression: None,
+                        schedule: None,
+                        state: None,
+                        phantom: PhantomData
+                    }
+                ],
+                execute: vec![
+                    Statement {
+                        destination: vec![
+                            ChannelSelector::new()
+                        ],
+                        value: data_off,
+                        feature: Id::new("light/is-on"),
+                        set_state: None,
+                        phantom: PhantomData,
+                    }
+                ],
+                otherwise: vec![],
+                sustained_for: Some(Duration::from(chrono::Duration::seconds(10))),
+                within: None,
+                cooldown: Some(Duration::from(chrono::Duration::seconds(60))),
+                phantom: PhantomData
+            }
+        ],
+        phantom: PhantomData,
+    };
+    exec.start(env.clone(), script, User::None, tx_run).unwrap();
+
+    env.execute(Instruction::AddAdapters(vec![adapter_id_1.to_string()]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddServices(vec![
+        Service::empty(&service_id_1, &adapter_id_1)
+    ]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddChannels(vec![
+        Channel {
+            id: getter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_send: None,
+            .. LIGHT_IS_ON.clone()
+        },
+        Channel {
+            id: setter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_fetch: None,
+            supports_watch: None,
+            .. LIGHT_IS_ON.clone()
+        }
+    ]));
+    rx_done.recv().unwrap();
+
+    let inject = |on: bool| {
+        env.execute(Instruction::InjectGetterValues(vec![
+            (getter_id_1.clone(), Ok(Value::new(if on { OnOff::On } else { OnOff::Off })))
+        ]));
+        rx_done.recv().unwrap();
+        thread::sleep(std::time::Duration::from_millis(100));
+    };
+
+    println!("* Meeting the condition is not sufficient to trigger the send.");
+    inject(true);
+    rx_send.try_recv().unwrap_err();
+
+    println!("* Once the condition has held long enough, the send is triggered.");
+    env.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(15))));
+    rx_done.recv().unwrap();
+    let (id, value) = rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::new(OnOff::Off));
+
+    println!("* While cooling down, the send is not triggered again.");
+    inject(false);
+    inject(true);
+    rx_send.try_recv().unwrap_err();
+
+    println!("* Once the cooldown has elapsed, the send can be triggered again.");
+    env.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(75))));
+    rx_done.recv().unwrap();
+    inject(false);
+    inject(true);
+    let (id, value) = rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::new(OnOff::Off));
+
+    println!("");
+}
+
+#[test]
+fn test_run_within() {
+    println!("* Starting test_run_within.");
+    let (tx, rx) : (_, Receiver<Event>) = channel();
+
+    let tx_env = Box::new(tx.map(|event| Event::Env(event)));
+    let tx_run = tx.map(|event| Event::Run(event));
+    let (tx_done, rx_done) = channel();
+    let (tx_send, rx_send) = channel();
+
+    let env = FakeEnv::new(tx_env);
+    let mut exec = Execution::<FakeEnv>::new();
+
+    let data_off = Payload::from_data(OnOff::Off, &format::ON_OFF).unwrap();
+    let data_on = Payload::from_data(OnOff::On, &format::ON_OFF).unwrap();
+
+    thread::spawn(move || {
+        for msg in rx {
+            if let Event::Env(FakeEnvEvent::Done) = msg {
+                tx_done.send(()).unwrap();
+            } else if let Event::Env(FakeEnvEvent::Send { id, value }) = msg {
+                tx_send.send((id, value)).unwrap();
+            }
+        }
+    });
+
+    let adapter_id_1 = Id::<AdapterId>::new("Adapter 1");
+    let service_id_1 = Id::<ServiceId>::new("Service 1");
+    let getter_id_1 = Id::<Channel>::new("Getter 1");
+    let getter_id_2 = Id::<Channel>::new("Getter 2");
+    let setter_id_1 = Id::<Channel>::new("Setter 1");
+
+    let match_on = |id: &Id<Channel>| Match {
+        source: vec![
+            ChannelSelector::new().with_id(id)
+        ],
+        feature: Id::new("light/is-on"),
+        when: Some(data_on.clone()),
+        duration: None,
+        filter: None,
+        expression: None,
+        schedule: None,
+        state: None,
+        phantom: PhantomData
+    };
+
+    println!("* Preparing a script that needs both getters to turn on at most 0.2s apart.");
+    let script = Script {
+        name: "Test script".to_owned(),
+        rules: vec![
+            Rule {
+                conditions: vec![match_on(&getter_id_1), match_on(&getter_id_2)],
+                execute: vec![
+                    Statement {
+                        destination: vec![
+                            ChannelSelector::new()
+                        ],
+                        value: data_off,
+                        feature: Id::new("light/is-on"),
+                        set_state: None,
+                        phantom: PhantomData,
+                    }
+                ],
+                otherwise: vec![],
+                sustained_for: None,
+                within: Some(Duration::from(chrono::Duration::milliseconds(200))),
+                cooldown: None,
+                phantom: PhantomData
+            }
+        ],
+        phantom: PhantomData,
+    };
+    exec.start(env.clone(), script, User::None, tx_run).unwrap();
+
+    env.execute(Instruction::AddAdapters(vec![adapter_id_1.to_string()]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddServices(vec![
+        Service::empty(&service_id_1, &adapter_id_1)
+    ]));
+    rx_done.recv().unwrap();
+
+    for getter_id in vec![&getter_id_1, &getter_id_2] {
+        env.execute(Instruction::AddChannels(vec![
+            Channel {
+                id: getter_id.clone(),
+                service: service_id_1.clone(),
+                adapter: adapter_id_1.clone(),
+                supports_send: None,
+                .. LIGHT_IS_ON.clone()
+            }
+        ]));
+        rx_done.recv().unwrap();
+    }
+
+    env.execute(Instruction::AddChannels(vec![
+        Channel {
+            id: setter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_fetch: None,
+            supports_watch: None,
+            .. LIGHT_IS_ON.clone()
+        }
+    ]));
+    rx_done.recv().unwrap();
+
+    let inject = |id: &Id<Channel>, on: bool| {
+        env.execute(Instruction::InjectGetterValues(vec![
+            (id.clone(), Ok(Value::new(if on { OnOff::On } else { OnOff::Off })))
+        ]));
+        rx_done.recv().unwrap();
+    };
+
+    println!("* Getters turning on too far apart do not trigger the send.");
+    inject(&getter_id_1, true);
+    thread::sleep(std::time::Duration::from_millis(400));
+    inject(&getter_id_2, true);
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("* Getters turning on close to each other trigger the send.");
+    inject(&getter_id_1, false);
+    inject(&getter_id_2, false);
+    inject(&getter_id_1, true);
+    inject(&getter_id_2, true);
+    let (id, value) = rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::new(OnOff::Off));
+
+    println!("");
+}
+
+#[test]
+fn test_simulate() {
+    println!("* Starting test_simulate.");
+    let (tx, rx) : (_, Receiver<Event>) = channel();
+
+    let tx_env = Box::new(tx.map(|event| Event::Env(event)));
+    let (tx_done, rx_done) = channel();
+    let (tx_send, rx_send) = channel();
+
+    let env = FakeEnv::new(tx_env);
+
+    let data_off = Payload::from_data(OnOff::Off, &format::ON_OFF).unwrap();
+    let data_on = Payload::from_data(OnOff::On, &format::ON_OFF).unwrap();
+
+    thread::spawn(move || {
+        for msg in rx {
+            if let Event::Env(FakeEnvEvent::Done) = msg {
+                tx_done.send(()).unwrap();
+            } else if let Event::Env(FakeEnvEvent::Send { id, value }) = msg {
+                tx_send.send((id, value)).unwrap();
+            }
+        }
+    });
+
+    let adapter_id_1 = Id::<AdapterId>::new("Adapter 1");
+    let service_id_1 = Id::<ServiceId>::new("Service 1");
+    let getter_id_1 = Id::<Channel>::new("Getter 1");
+    let setter_id_1 = Id::<Channel>::new("Setter 1");
+
+    env.execute(Instruction::AddAdapters(vec![adapter_id_1.to_string()]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddServices(vec![
+        Service::empty(&service_id_1, &adapter_id_1)
+    ]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddChannels(vec![
+        Channel {
+            id: getter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_send: None,
+            .. LIGHT_IS_ON.clone()
+        },
+        Channel {
+            id: setter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_fetch: None,
+            supports_watch: None,
+            .. LIGHT_IS_ON.clone()
+        }
+    ]));
+    rx_done.recv().unwrap();
+
+    let statement = |id: &Id<Channel>, value: &Payload| Statement {
+        destination: vec![
+            ChannelSelector::new().with_id(id)
+        ],
+        value: value.clone(),
+        feature: Id::new("light/is-on"),
+        set_state: None,
+        phantom: PhantomData,
+    };
+
+    println!("* Simulating a script whose otherwise statement targets a missing channel.");
+    let script = Script {
+        name: "Test script".to_owned(),
+        rules: vec![
+            Rule {
+                conditions: vec![
+                    Match {
+                        source: vec![
+                            ChannelSelector::new().with_id(&getter_id_1)
+                        ],
+                        feature: Id::new("light/is-on"),
+                        when: Some(data_on.clone()),
+                        duration: None,
+                        filter: None,
+                        expression: None,
+                        schedule: None,
+                        state: None,
+                        phantom: PhantomData
+                    }
+                ],
+                execute: vec![statement(&setter_id_1, &data_off)],
+                otherwise: vec![statement(&Id::new("Missing setter"), &data_on)],
+                sustained_for: None,
+                within: None,
+                cooldown: None,
+                phantom: PhantomData
+            }
+        ],
+        phantom: PhantomData,
+    };
+    let simulation = simulate(&env, script).unwrap();
+    assert_eq!(simulation.getters, vec![vec![vec![getter_id_1.clone()]]]);
+    assert_eq!(simulation.trace.len(), 2);
+    assert!(!simulation.trace[0].otherwise);
+    assert_eq!(simulation.trace[0].channels, vec![setter_id_1.clone()]);
+    assert_eq!(simulation.trace[0].value, data_off);
+    assert!(simulation.trace[1].otherwise);
+    assert!(simulation.trace[1].channels.is_empty());
+
+    println!("* Nothing was actually sent.");
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("* Scripts that don't compile cannot be simulated.");
+    let script = Script::<UncheckedCtx> {
+        name: "Empty script".to_owned(),
+        rules: vec![],
+        phantom: PhantomData,
+    };
+    match simulate(&env, script) {
+        Err(Error::CompileError(_)) => {}
+        other => panic!("Unexpected simulation {:?}", other),
+    }
+
+    println!("");
+}
+
+#[test]
+fn test_run_expression() {
+    println!("* Starting test_run_expression.");
+    let (tx, rx) : (_, Receiver<Event>) = channel();
+
+    let tx_env = Box::new(tx.map(|event| Event::Env(event)));
+    let tx_run = tx.map(|event| Event::Run(event));
+    let (tx_done, rx_done) = channel();
+    let (tx_send, rx_send) = channel();
+
+    let env = FakeEnv::new(tx_env);
+    let mut exec = Execution::<FakeEnv>::new();
+
+    let data_on = Payload::from_data(OnOff::On, &format::ON_OFF).unwrap();
+
+    thread::spawn(move || {
+        for msg in rx {
+            if let Event::Env(FakeEnvEvent::Done) = msg {
+                tx_done.send(()).unwrap();
+            } else if let Event::Env(FakeEnvEvent::Send { id, value }) = msg {
+                tx_send.send((id, value)).unwrap();
+            }
+        }
+    });
+
+    let adapter_id_1 = Id::<AdapterId>::new("Adapter 1");
+    let service_id_1 = Id::<ServiceId>::new("Service 1");
+    let thermometer_id = Id::<Channel>::new("Thermometer");
+    let setpoint_id = Id::<Channel>::new("Setpoint");
+    let setter_id_1 = Id::<Channel>::new("Setter 1");
+
+    println!("* Preparing a script that turns the setter on once the temperature is above the setpoint + 2.");
+    let expression = Test::from_str(r#"{"Gt": [
+        {"value": "C"},
+        {"+": [{"getter": [{"id": "Setpoint"}], "feature": "sensor/temperature", "field": "C"}, 2]}
+    ]}"#).unwrap();
+    let script = Script {
+        name: "Test script".to_owned(),
+        rules: vec![
+            Rule {
+                conditions: vec![
+                    Match {
+                        source: vec![
+                            ChannelSelector::new().with_id(&thermometer_id)
+                        ],
+                        feature: Id::new("sensor/temperature"),
+                        when: None,
+                        duration: None,
+                        filter: None,
+                        expression: Some(expression),
+                        schedule: None,
+                        state: None,
+                        phantom: PhantomData
+                    }
+                ],
+                execute: vec![
+                    Statement {
+                        destination: vec![
+                            ChannelSelector::new().with_id(&setter_id_1)
+                        ],
+                        value: data_on.clone(),
+                        feature: Id::new("light/is-on"),
+                        set_state: None,
+                        phantom: PhantomData,
+                    }
+                ],
+                otherwise: vec![],
+                sustained_for: None,
+                within: None,
+                cooldown: None,
+                phantom: PhantomData
+            }
+        ],
+        phantom: PhantomData,
+    };
+    exec.start(env.clone(), script, User::None, tx_run).unwrap();
+
+    env.execute(Instruction::AddAdapters(vec![adapter_id_1.to_string()]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddServices(vec![
+        Service::empty(&service_id_1, &adapter_id_1)
+    ]));
+    rx_done.recv().unwrap();
+
+    for getter_id in vec![&thermometer_id, &setpoint_id] {
+        env.execute(Instruction::AddChannels(vec![
+            Channel {
+                id: getter_id.clone(),
+                service: service_id_1.clone(),
+                adapter: adapter_id_1.clone(),
+                .. SENSOR_TEMPERATURE.clone()
+            }
+        ]));
+        rx_done.recv().unwrap();
+    }
+
+    env.execute(Instruction::AddChannels(vec![
+        Channel {
+            id: setter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_fetch: None,
+            supports_watch: None,
+            .. LIGHT_IS_ON.clone()
+        }
+    ]));
+    rx_done.recv().unwrap();
+
+    println!("* The test doesn't hold until the setpoint is known.");
+    env.execute(Instruction::InjectGetterValues(vec![
+        (thermometer_id.clone(), Ok(Value::new(Temperature::C(25.))))
+    ]));
+    rx_done.recv().unwrap();
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("* Once the setpoint is known, the test is evaluated again: 25 > 20 + 2.");
+    env.execute(Instruction::InjectGetterValues(vec![
+        (setpoint_id.clone(), Ok(Value::new(Temperature::C(20.))))
+    ]));
+    rx_done.recv().unwrap();
+    let (id, value) = rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::new(OnOff::On));
+
+    println!("* Raising the setpoint stops meeting the condition: 25 <= 24 + 2.");
+    env.execute(Instruction::InjectGetterValues(vec![
+        (setpoint_id.clone(), Ok(Value::new(Temperature::C(24.))))
+    ]));
+    rx_done.recv().unwrap();
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("* Other temperatures are compared with the latest setpoint: 27 > 24 + 2.");
+    env.execute(Instruction::InjectGetterValues(vec![
+        (thermometer_id.clone(), Ok(Value::new(Temperature::C(27.))))
+    ]));
+    rx_done.recv().unwrap();
+    let (id, value) = rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::new(OnOff::On));
+
+    println!("");
+}
+
+#[test]
+fn test_run_schedule() {
+    println!("* Starting test_run_schedule.");
+    let (tx, rx) : (_, Receiver<Event>) = channel();
+
+    let tx_env = Box::new(tx.map(|event| Event::Env(event)));
+    let tx_run = tx.map(|event| Event::Run(event));
+    let (tx_done, rx_done) = channel();
+    let (tx_send, rx_send) = channel();
+
+    let env = FakeEnv::new(tx_env);
+    let mut exec = Execution::<FakeEnv>::new();
+
+    let data_on = Payload::from_data(OnOff::On, &format::ON_OFF).unwrap();
+
+    thread::spawn(move || {
+        for msg in rx {
+            if let Event::Env(FakeEnvEvent::Done) = msg {
+                tx_done.send(()).unwrap();
+            } else if let Event::Env(FakeEnvEvent::Send { id, value }) = msg {
+                tx_send.send((id, value)).unwrap();
+            }
+        }
+    });
+
+    let adapter_id_1 = Id::<AdapterId>::new("Adapter 1");
+    let service_id_1 = Id::<ServiceId>::new("Service 1");
+    let clock_id = Id::<Channel>::new("Clock");
+    let setter_id_1 = Id::<Channel>::new("Setter 1");
+
+    println!("* Preparing a script that turns the setter on every day at 7:30 and 18:00.");
+    let match_ = Match::<UncheckedCtx>::from_str(r#"{"schedule": {"times": ["07:30", "18:00"]}}"#).unwrap();
+    let script = Script {
+        name: "Test script".to_owned(),
+        rules: vec![
+            Rule {
+                conditions: vec![match_],
+                execute: vec![
+                    Statement {
+                        destination: vec![
+                            ChannelSelector::new().with_id(&setter_id_1)
+                        ],
+                        value: data_on.clone(),
+                        feature: Id::new("light/is-on"),
+                        set_state: None,
+                        phantom: PhantomData,
+                    }
+                ],
+                otherwise: vec![],
+                sustained_for: None,
+                within: None,
+                cooldown: None,
+                phantom: PhantomData
+            }
+        ],
+        phantom: PhantomData,
+    };
+    exec.start(env.clone(), script, User::None, tx_run).unwrap();
+
+    env.execute(Instruction::AddAdapters(vec![adapter_id_1.to_string()]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddServices(vec![
+        Service::empty(&service_id_1, &adapter_id_1)
+    ]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddChannels(vec![
+        Channel {
+            id: clock_id.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            feature: Id::new(SCHEDULE_FEATURE),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::DURATION.clone()))),
+            supports_watch: Some(Signature {
+                accepts: Maybe::Required(format::DURATION_RANGE.clone()),
+                returns: Maybe::Required(format::DURATION.clone()),
+            }),
+            .. Channel::default()
+        },
+        Channel {
+            id: setter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_fetch: None,
+            supports_watch: None,
+            .. LIGHT_IS_ON.clone()
+        }
+    ]));
+    rx_done.recv().unwrap();
+
+    let inject = |hours: i64, minutes: i64, seconds: i64| {
+        let time = ChronoDuration::hours(hours) + ChronoDuration::minutes(minutes) + ChronoDuration::seconds(seconds);
+        env.execute(Instruction::InjectGetterValues(vec![
+            (clock_id.clone(), Ok(Value::new(Duration::from(time))))
+        ]));
+        rx_done.recv().unwrap();
+        thread::sleep(std::time::Duration::from_millis(100));
+    };
+
+    println!("* Before 7:30, the send is not triggered.");
+    inject(7, 29, 0);
+    rx_send.try_recv().unwrap_err();
+
+    println!("* At 7:30, the send is triggered.");
+    inject(7, 30, 0);
+    let (id, value) = rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::new(OnOff::On));
+
+    println!("* During the rest of the minute, the send is not triggered again.");
+    inject(7, 30, 30);
+    rx_send.try_recv().unwrap_err();
+
+    println!("* Between the times of the schedule, the send is not triggered.");
+    inject(7, 31, 0);
+    inject(12, 0, 0);
+    rx_send.try_recv().unwrap_err();
+
+    println!("* At 18:00, the send is triggered again.");
+    inject(18, 0, 10);
+    let (id, value) = rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::new(OnOff::On));
+
+    println!("");
+}
+
+#[test]
+fn test_run_state() {
+    println!("* Starting test_run_state.");
+    let (tx, rx) : (_, Receiver<Event>) = channel();
+
+    let tx_env = Box::new(tx.map(|event| Event::Env(event)));
+    let (tx_done, rx_done) = channel();
+    let (tx_send, rx_send) = channel();
+    let (tx_state, rx_state) = channel();
+
+    let env = FakeEnv::new(tx_env);
+
+    thread::spawn(move || {
+        for msg in rx {
+            if let Event::Env(FakeEnvEvent::Done) = msg {
+                tx_done.send(()).unwrap();
+            } else if let Event::Env(FakeEnvEvent::Send { id, value }) = msg {
+                tx_send.send((id, value)).unwrap();
+            } else if let Event::Run(ExecutionEvent::StateChanged { key, value }) = msg {
+                tx_state.send((key, value)).unwrap();
+            }
+        }
+    });
+
+    let adapter_id_1 = Id::<AdapterId>::new("Adapter 1");
+    let service_id_1 = Id::<ServiceId>::new("Service 1");
+    let getter_id_1 = Id::<Channel>::new("Getter 1");
+    let setter_id_1 = Id::<Channel>::new("Setter 1");
+
+    env.execute(Instruction::AddAdapters(vec![adapter_id_1.to_string()]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddServices(vec![
+        Service::empty(&service_id_1, &adapter_id_1)
+    ]));
+    rx_done.recv().unwrap();
+
+    env.execute(Instruction::AddChannels(vec![
+        Channel {
+            id: getter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_send: None,
+            .. LIGHT_IS_ON.clone()
+        },
+        Channel {
+            id: setter_id_1.clone(),
+            service: service_id_1.clone(),
+            adapter: adapter_id_1.clone(),
+            supports_fetch: None,
+            supports_watch: None,
+            .. LIGHT_IS_ON.clone()
+        }
+    ]));
+    rx_done.recv().unwrap();
+
+    let alarm = r#"{
+        "conditions": [{"state": "armed", "when": true}],
+        "execute": [{"destination": [{"id": "Setter 1"}], "value": "On", "feature": "light/is-on"}]
+    }"#;
+    let arm = r#"{
+        "conditions": [{"source": [{"id": "Getter 1"}], "feature": "light/is-on", "when": "On"}],
+        "execute": [{"set_state": "armed", "value": true}]
+    }"#;
+
+    println!("* Preparing a script that arms when the getter is on, then turns the setter on.");
+    let script = Script::from_str(&format!(r#"{{"name": "Test script", "rules": [{}, {}]}}"#, arm, alarm)).unwrap();
+    let mut exec = Execution::<FakeEnv>::new();
+    exec.start(env.clone(), script, User::None, tx.map(|event| Event::Run(event))).unwrap();
+
+    println!("* Nothing happens until the state is set.");
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+    rx_state.try_recv().unwrap_err();
+
+    println!("* Once the getter is on, the state is set and reported, then checked.");
+    env.execute(Instruction::InjectGetterValues(vec![
+        (getter_id_1.clone(), Ok(Value::new(OnOff::On)))
+    ]));
+    rx_done.recv().unwrap();
+    let (key, value) = rx_state.recv().unwrap();
+    assert_eq!(key, "armed");
+    assert_eq!(value, JSON::Bool(true));
+    let (id, value) = rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::new(OnOff::On));
+
+    println!("* Storing the same value again changes nothing.");
+    env.execute(Instruction::InjectGetterValues(vec![
+        (getter_id_1.clone(), Ok(Value::new(OnOff::Off)))
+    ]));
+    rx_done.recv().unwrap();
+    env.execute(Instruction::InjectGetterValues(vec![
+        (getter_id_1.clone(), Ok(Value::new(OnOff::On)))
+    ]));
+    rx_done.recv().unwrap();
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_state.try_recv().unwrap_err();
+    rx_send.try_recv().unwrap_err();
+    drop(exec);
+
+    println!("* A script started with a previous state checks it immediately.");
+    let script = Script::from_str(&format!(r#"{{"name": "Test script", "rules": [{}]}}"#, alarm)).unwrap();
+    let mut state = HashMap::new();
+    state.insert("armed".to_owned(), JSON::Bool(true));
+    let mut exec = Execution::<FakeEnv>::new();
+    exec.start_with_state(env.clone(), script, User::None, state, tx.map(|event| Event::Run(event))).unwrap();
+    let (id, value) = rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::new(OnOff::On));
+
+    println!("");
+}
@@ -56,6 +56,12 @@ impl CertificateManager {
         self.directory.clone()
     }
 
+    /// Where the tokens of pending http-01 challenges are written. This is next to the
+    /// certificates directory rather than inside it, since `reload` scans the latter.
+    pub fn get_acme_challenge_dir(&self) -> PathBuf {
+        self.directory.with_file_name("acme-challenges")
+    }
+
     pub fn get_box_certificate(&self) -> io::Result<CertificateRecord> {
         self.get_or_generate_self_signed_certificate(DEFAULT_BOX_NAME)
     }
@@ -9,7 +9,10 @@ use openssl::ssl::error::SslError;
 use serde_json;
 use std::collections::BTreeMap;
 use std::io;
+use certificate_manager::CertificateManager;
 use certificate_record::CertificateRecord;
+use dns_cloudflare::CloudflareDnsProvider;
+use dns_route53::Route53DnsProvider;
 
 const DNS_API_VERSION: &'static str = "v1";
 
@@ -19,6 +22,103 @@ pub struct DnsRecord<'a> {
     pub value: &'a str,
 }
 
+/// A DNS service in which the box publishes its names, and the TXT records of the dns-01
+/// challenge.
+pub trait DnsProvider: Send + Sync {
+    /// Create `dns_record`, or replace the record of the same type and name.
+    fn register(&self, dns_record: &DnsRecord) -> io::Result<()>;
+}
+
+/// Where the box publishes its DNS records.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DnsConfig {
+    /// The DNS API of the registration server, which serves the names under box.knilxof.org
+    /// and authenticates the box by its certificate.
+    Knilxof { api_endpoint: String },
+    /// A zone of the user's own domain, hosted by Cloudflare.
+    Cloudflare {
+        email: String,
+        api_key: String,
+        zone_id: String,
+    },
+    /// A hosted zone of the user's own domain, on Amazon Route53.
+    Route53 {
+        access_key_id: String,
+        secret_access_key: String,
+        hosted_zone_id: String,
+    },
+}
+
+impl DnsConfig {
+    pub fn provider(&self,
+                    certificate_manager: &CertificateManager)
+                    -> io::Result<Box<DnsProvider>> {
+        Ok(match *self {
+            DnsConfig::Knilxof { ref api_endpoint } => {
+                Box::new(KnilxofDnsProvider::new(try!(certificate_manager.get_box_certificate()),
+                                                 api_endpoint))
+            }
+            DnsConfig::Cloudflare { ref email, ref api_key, ref zone_id } => {
+                Box::new(CloudflareDnsProvider::new(email, api_key, zone_id))
+            }
+            DnsConfig::Route53 { ref access_key_id, ref secret_access_key, ref hosted_zone_id } => {
+                Box::new(Route53DnsProvider::new(access_key_id, secret_access_key, hosted_zone_id))
+            }
+        })
+    }
+
+    /// The environment variables from which `from_env` reads this configuration, e.g. in the
+    /// `dnschallenge` hook of the LetsEncrypt client.
+    pub fn to_env(&self) -> Vec<(&'static str, String)> {
+        match *self {
+            DnsConfig::Knilxof { ref api_endpoint } => {
+                vec![("DNS_PROVIDER", "knilxof".to_owned()),
+                     ("DNS_API_ENDPOINT", api_endpoint.clone())]
+            }
+            DnsConfig::Cloudflare { ref email, ref api_key, ref zone_id } => {
+                vec![("DNS_PROVIDER", "cloudflare".to_owned()),
+                     ("CLOUDFLARE_EMAIL", email.clone()),
+                     ("CLOUDFLARE_API_KEY", api_key.clone()),
+                     ("CLOUDFLARE_ZONE_ID", zone_id.clone())]
+            }
+            DnsConfig::Route53 { ref access_key_id, ref secret_access_key, ref hosted_zone_id } => {
+                vec![("DNS_PROVIDER", "route53".to_owned()),
+                     ("AWS_ACCESS_KEY_ID", access_key_id.clone()),
+                     ("AWS_SECRET_ACCESS_KEY", secret_access_key.clone()),
+                     ("ROUTE53_HOSTED_ZONE_ID", hosted_zone_id.clone())]
+            }
+        }
+    }
+
+    /// Read a configuration written by `to_env`, given a way to read environment variables.
+    /// `DNS_PROVIDER` defaults to "knilxof".
+    pub fn from_env<F>(var: F) -> Result<Self, String>
+        where F: Fn(&str) -> Option<String>
+    {
+        let required = |name: &str| var(name).ok_or(format!("{} should be set", name));
+        match var("DNS_PROVIDER").as_ref().map_or("knilxof", |provider| provider.as_str()) {
+            "knilxof" => {
+                Ok(DnsConfig::Knilxof { api_endpoint: try!(required("DNS_API_ENDPOINT")) })
+            }
+            "cloudflare" => {
+                Ok(DnsConfig::Cloudflare {
+                    email: try!(required("CLOUDFLARE_EMAIL")),
+                    api_key: try!(required("CLOUDFLARE_API_KEY")),
+                    zone_id: try!(required("CLOUDFLARE_ZONE_ID")),
+                })
+            }
+            "route53" => {
+                Ok(DnsConfig::Route53 {
+                    access_key_id: try!(required("AWS_ACCESS_KEY_ID")),
+                    secret_access_key: try!(required("AWS_SECRET_ACCESS_KEY")),
+                    hosted_zone_id: try!(required("ROUTE53_HOSTED_ZONE_ID")),
+                })
+            }
+            other => Err(format!("Unknown DNS provider {}", other)),
+        }
+    }
+}
+
 fn create_https_client(client: CertificateRecord) -> Result<Client, SslError> {
 
     let ssl_ctx = try!(Openssl::with_cert_and_key(&client.cert_file, &client.private_key_file));
@@ -26,6 +126,27 @@ fn create_https_client(client: CertificateRecord) -> Result<Client, SslError> {
     Ok(Client::with_connector(HttpsConnector::new(ssl_ctx)))
 }
 
+/// The DNS API of the registration server, see `DnsConfig::Knilxof`.
+pub struct KnilxofDnsProvider {
+    client: CertificateRecord,
+    api_endpoint: String,
+}
+
+impl KnilxofDnsProvider {
+    pub fn new(client: CertificateRecord, api_endpoint: &str) -> Self {
+        KnilxofDnsProvider {
+            client: client,
+            api_endpoint: api_endpoint.to_owned(),
+        }
+    }
+}
+
+impl DnsProvider for KnilxofDnsProvider {
+    fn register(&self, dns_record: &DnsRecord) -> io::Result<()> {
+        register_dns_record(self.client.clone(), dns_record, &self.api_endpoint)
+    }
+}
+
 pub fn register_dns_record(client: CertificateRecord,
                            dns_record: &DnsRecord,
                            api_endpoint: &str)
@@ -76,3 +197,48 @@ pub fn register_dns_record(client: CertificateRecord,
                            "Failed to create an HTTPS client to set up a DNS record"))
     }
 }
+
+#[cfg(test)]
+mod dns_client_test {
+    use std::collections::HashMap;
+    use super::DnsConfig;
+
+    #[test]
+    fn test_dns_config_env() {
+        let knilxof = DnsConfig::Knilxof { api_endpoint: "https://knilxof.org:5300".to_owned() };
+        let configs = vec![knilxof,
+                           DnsConfig::Cloudflare {
+                               email: "admin@example.org".to_owned(),
+                               api_key: "c2547eb745079dac9320b638f5e225cf483cc5cfdda41".to_owned(),
+                               zone_id: "023e105f4ecef8ad9ca31a8372d0c353".to_owned(),
+                           },
+                           DnsConfig::Route53 {
+                               access_key_id: "AKIDEXAMPLE".to_owned(),
+                               secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY"
+                                   .to_owned(),
+                               hosted_zone_id: "Z1D633PJN98FT9".to_owned(),
+                           }];
+        for config in configs {
+            let env: HashMap<_, _> = config.to_env().into_iter().collect();
+            assert_eq!(DnsConfig::from_env(|name| env.get(name).cloned()), Ok(config));
+        }
+    }
+
+    #[test]
+    fn test_dns_config_env_checks_fields() {
+        // Older hooks only set the endpoint of the DNS API.
+        assert_eq!(DnsConfig::from_env(|name| if name == "DNS_API_ENDPOINT" {
+                       Some("https://knilxof.org:5300".to_owned())
+                   } else {
+                       None
+                   }),
+                   Ok(DnsConfig::Knilxof { api_endpoint: "https://knilxof.org:5300".to_owned() }));
+        assert!(DnsConfig::from_env(|_| None).is_err());
+        assert!(DnsConfig::from_env(|name| if name == "DNS_PROVIDER" {
+                    Some("cloudflare".to_owned())
+                } else {
+                    None
+                })
+            .is_err());
+    }
+}
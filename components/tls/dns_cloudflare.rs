@@ -0,0 +1,151 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Publish DNS records in a zone hosted by Cloudflare, through the v4 API. The zone id is
+//! shown in the overview of the domain, the API key in the account settings.
+
+use hyper::client::{Body, Client};
+use hyper::header::{ContentType, Headers};
+use hyper::method::Method;
+use serde_json;
+use serde_json::value::Value as JSON;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Read;
+use dns_client::{DnsProvider, DnsRecord};
+
+const CLOUDFLARE_API: &'static str = "https://api.cloudflare.com/client/v4";
+
+/// Short enough that a new address of the box is seen soon.
+const RECORD_TTL_S: u64 = 120;
+
+header! { (XAuthEmail, "X-Auth-Email") => [String] }
+header! { (XAuthKey, "X-Auth-Key") => [String] }
+
+pub struct CloudflareDnsProvider {
+    email: String,
+    api_key: String,
+    zone_id: String,
+}
+
+/// The body of a request creating or updating `dns_record`.
+fn record_body(dns_record: &DnsRecord) -> JSON {
+    let mut map = BTreeMap::new();
+    map.insert("type".to_owned(), JSON::String(dns_record.record_type.to_owned()));
+    map.insert("name".to_owned(), JSON::String(dns_record.name.to_owned()));
+    map.insert("content".to_owned(), JSON::String(dns_record.value.to_owned()));
+    map.insert("ttl".to_owned(), JSON::U64(RECORD_TTL_S));
+    JSON::Object(map)
+}
+
+/// The id of the first record listed in a response, if any.
+fn first_record_id(response: &JSON) -> Option<String> {
+    response.find("result")
+        .and_then(JSON::as_array)
+        .and_then(|records| records.first())
+        .and_then(|record| record.find("id"))
+        .and_then(JSON::as_string)
+        .map(|id| id.to_owned())
+}
+
+impl CloudflareDnsProvider {
+    pub fn new(email: &str, api_key: &str, zone_id: &str) -> Self {
+        CloudflareDnsProvider {
+            email: email.to_owned(),
+            api_key: api_key.to_owned(),
+            zone_id: zone_id.to_owned(),
+        }
+    }
+
+    fn request(&self, method: Method, url: &str, body: Option<&JSON>) -> io::Result<JSON> {
+        let mut headers = Headers::new();
+        headers.set(XAuthEmail(self.email.clone()));
+        headers.set(XAuthKey(self.api_key.clone()));
+        headers.set(ContentType::json());
+
+        let payload = body.map(|body| serde_json::to_vec(body).unwrap());
+        let client = Client::new();
+        let mut request = client.request(method, url).headers(headers);
+        if let Some(ref payload) = payload {
+            request = request.body(Body::BufBody(&payload[..], payload.len()));
+        }
+
+        let mut response = try!(request.send().map_err(|err| {
+            io::Error::new(io::ErrorKind::Other,
+                           format!("Could not reach the Cloudflare API: {}", err))
+        }));
+        let mut source = String::new();
+        try!(response.read_to_string(&mut source));
+
+        // The API tells whether the call succeeded in the body, along with the errors.
+        if let Ok(json) = serde_json::from_str::<JSON>(&source) {
+            if json.find("success").and_then(JSON::as_bool) == Some(true) {
+                return Ok(json);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::Other,
+                           format!("The Cloudflare API failed (Was response code: {}): {}",
+                                   response.status,
+                                   source)))
+    }
+}
+
+impl DnsProvider for CloudflareDnsProvider {
+    fn register(&self, dns_record: &DnsRecord) -> io::Result<()> {
+        let records_url = format!("{}/zones/{}/dns_records", CLOUDFLARE_API, self.zone_id);
+
+        // Cloudflare keeps several records of the same name, so update the existing one
+        // rather than adding another.
+        let existing = try!(self.request(Method::Get,
+                                         &format!("{}?type={}&name={}",
+                                                  records_url,
+                                                  dns_record.record_type,
+                                                  dns_record.name),
+                                         None));
+        let body = record_body(dns_record);
+        let result = match first_record_id(&existing) {
+            Some(id) => self.request(Method::Put, &format!("{}/{}", records_url, id), Some(&body)),
+            None => self.request(Method::Post, &records_url, Some(&body)),
+        };
+        if result.is_ok() {
+            info!("Cloudflare: registered {} record for {}",
+                  dns_record.record_type,
+                  dns_record.name);
+        } else {
+            error!("Could not register a DNS entry for {}", dns_record.name);
+        }
+        result.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod dns_cloudflare_test {
+    use serde_json;
+    use dns_client::DnsRecord;
+    use super::{first_record_id, record_body};
+
+    #[test]
+    fn test_record_body() {
+        let body = record_body(&DnsRecord {
+            record_type: "TXT",
+            name: "_acme-challenge.local.box.example.org",
+            value: "challenge",
+        });
+        assert_eq!(serde_json::to_string(&body).unwrap(),
+                   "{\"content\":\"challenge\",\"name\":\"_acme-challenge.local.box.example.org\",\
+                    \"ttl\":120,\"type\":\"TXT\"}");
+    }
+
+    #[test]
+    fn test_first_record_id() {
+        let existing = serde_json::from_str(r#"{"success": true, "result": [
+            {"id": "372e67954025e0ba6aaa6d586b9e0b59", "type": "A"}]}"#)
+            .unwrap();
+        assert_eq!(first_record_id(&existing),
+                   Some("372e67954025e0ba6aaa6d586b9e0b59".to_owned()));
+
+        let missing = serde_json::from_str(r#"{"success": true, "result": []}"#).unwrap();
+        assert_eq!(first_record_id(&missing), None);
+    }
+}
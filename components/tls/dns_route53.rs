@@ -0,0 +1,207 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Publish DNS records in a hosted zone of Amazon Route53. Requests are signed with AWS
+//! Signature Version 4, so the access key only needs the permission
+//! `route53:ChangeResourceRecordSets` on the zone.
+
+use chrono::UTC;
+use hyper::client::{Body, Client};
+use hyper::header::{Authorization, ContentType, Headers};
+use hyper::status::StatusCode;
+use openssl::crypto::hash::{hash, Type};
+use openssl::crypto::hmac::hmac;
+use std::io;
+use std::io::Read;
+use dns_client::{DnsProvider, DnsRecord};
+
+const ROUTE53_HOST: &'static str = "route53.amazonaws.com";
+
+/// Route53 is a global service, signed as if it was in this region.
+const ROUTE53_REGION: &'static str = "us-east-1";
+const ROUTE53_SERVICE: &'static str = "route53";
+
+const RECORD_TTL_S: u64 = 120;
+
+header! { (XAmzDate, "X-Amz-Date") => [String] }
+
+pub struct Route53DnsProvider {
+    access_key_id: String,
+    secret_access_key: String,
+    hosted_zone_id: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn escape_xml(source: &str) -> String {
+    source.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// The body of a request creating or replacing `dns_record`.
+fn change_batch(dns_record: &DnsRecord) -> String {
+    // Route53 expects the value of a TXT record between quotes.
+    let value = if dns_record.record_type == "TXT" {
+        format!("\"{}\"", dns_record.value)
+    } else {
+        dns_record.value.to_owned()
+    };
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<ChangeResourceRecordSetsRequest xmlns=\"https://route53.amazonaws.com/doc/2013-04-01/\">
+<ChangeBatch><Changes><Change><Action>UPSERT</Action><ResourceRecordSet>
+<Name>{name}</Name><Type>{record_type}</Type><TTL>{ttl}</TTL>
+<ResourceRecords><ResourceRecord><Value>{value}</Value></ResourceRecord></ResourceRecords>
+</ResourceRecordSet></Change></Changes></ChangeBatch>
+</ChangeResourceRecordSetsRequest>
+",
+            name = escape_xml(dns_record.name),
+            record_type = escape_xml(dns_record.record_type),
+            ttl = RECORD_TTL_S,
+            value = escape_xml(&value))
+}
+
+/// The key deriving the signatures of a day, see
+/// http://docs.aws.amazon.com/general/latest/gr/sigv4-calculate-signature.html
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(Type::SHA256,
+                   format!("AWS4{}", secret_access_key).as_bytes(),
+                   date.as_bytes());
+    let key = hmac(Type::SHA256, &key, region.as_bytes());
+    let key = hmac(Type::SHA256, &key, service.as_bytes());
+    hmac(Type::SHA256, &key, b"aws4_request")
+}
+
+impl Route53DnsProvider {
+    pub fn new(access_key_id: &str, secret_access_key: &str, hosted_zone_id: &str) -> Self {
+        Route53DnsProvider {
+            access_key_id: access_key_id.to_owned(),
+            secret_access_key: secret_access_key.to_owned(),
+            // The console shows ids such as "Z1D633PJN98FT9", the API "/hostedzone/Z1D633PJN98FT9".
+            hosted_zone_id: hosted_zone_id.trim_left_matches("/hostedzone/").to_owned(),
+        }
+    }
+
+    fn path(&self) -> String {
+        format!("/2013-04-01/hostedzone/{}/rrset/", self.hosted_zone_id)
+    }
+
+    /// The `Authorization` header of a POST of `payload` to `path` at `amz_date`, which is
+    /// formatted as "YYYYMMDDTHHMMSSZ".
+    fn authorization(&self, amz_date: &str, path: &str, payload: &str) -> String {
+        let signed_headers = "content-type;host;x-amz-date";
+        let canonical_request = format!("POST\n{}\n\ncontent-type:text/xml\nhost:{}\n\
+                                         x-amz-date:{}\n\n{}\n{}",
+                                        path,
+                                        ROUTE53_HOST,
+                                        amz_date,
+                                        signed_headers,
+                                        to_hex(&hash(Type::SHA256, payload.as_bytes())));
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/{}/aws4_request", date, ROUTE53_REGION, ROUTE53_SERVICE);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                                     amz_date,
+                                     scope,
+                                     to_hex(&hash(Type::SHA256, canonical_request.as_bytes())));
+        let key = signing_key(&self.secret_access_key, date, ROUTE53_REGION, ROUTE53_SERVICE);
+        format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key_id,
+                scope,
+                signed_headers,
+                to_hex(&hmac(Type::SHA256, &key, string_to_sign.as_bytes())))
+    }
+}
+
+impl DnsProvider for Route53DnsProvider {
+    fn register(&self, dns_record: &DnsRecord) -> io::Result<()> {
+        let path = self.path();
+        let payload = change_batch(dns_record);
+        let amz_date = UTC::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+        let mut headers = Headers::new();
+        headers.set(ContentType("text/xml".parse().unwrap()));
+        headers.set(XAmzDate(amz_date.clone()));
+        headers.set(Authorization(self.authorization(&amz_date, &path, &payload)));
+
+        let result = Client::new()
+            .post(&format!("https://{}{}", ROUTE53_HOST, path))
+            .headers(headers)
+            .body(Body::BufBody(payload.as_bytes(), payload.len()))
+            .send();
+
+        match result {
+            Ok(mut response) => {
+                if response.status == StatusCode::Ok {
+                    info!("Route53: registered {} record for {}",
+                          dns_record.record_type,
+                          dns_record.name);
+                    Ok(())
+                } else {
+                    error!("Could not register a DNS entry for {}", dns_record.name);
+                    let mut source = String::new();
+                    let _ = response.read_to_string(&mut source);
+                    Err(io::Error::new(io::ErrorKind::Other,
+                                       format!("Failed to register DNS record (Was response \
+                                                code: {}): {}",
+                                               response.status,
+                                               source)))
+                }
+            }
+            Err(e) => {
+                error!("Could not register a DNS entry for {}", dns_record.name);
+                Err(io::Error::new(io::ErrorKind::Other,
+                                   format!("Failed to register DNS record: {}", e)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod dns_route53_test {
+    use dns_client::DnsRecord;
+    use super::{change_batch, signing_key, to_hex, Route53DnsProvider};
+
+    #[test]
+    fn test_signing_key() {
+        // From the examples of the AWS documentation.
+        assert_eq!(to_hex(&signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+                                       "20120215",
+                                       "us-east-1",
+                                       "iam")),
+                   "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+    }
+
+    #[test]
+    fn test_change_batch_quotes_txt_records() {
+        let batch = change_batch(&DnsRecord {
+            record_type: "TXT",
+            name: "_acme-challenge.local.box.example.org",
+            value: "challenge",
+        });
+        assert!(batch.contains("<Action>UPSERT</Action>"));
+        assert!(batch.contains("<Name>_acme-challenge.local.box.example.org</Name>"));
+        assert!(batch.contains("<Value>\"challenge\"</Value>"));
+
+        let batch = change_batch(&DnsRecord {
+            record_type: "A",
+            name: "local.box.example.org",
+            value: "192.168.1.10",
+        });
+        assert!(batch.contains("<Value>192.168.1.10</Value>"));
+    }
+
+    #[test]
+    fn test_authorization() {
+        let provider = Route53DnsProvider::new("AKIDEXAMPLE",
+                                               "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+                                               "/hostedzone/Z1D633PJN98FT9");
+        let path = provider.path();
+        assert_eq!(path, "/2013-04-01/hostedzone/Z1D633PJN98FT9/rrset/");
+        assert_eq!(provider.authorization("20161015T120000Z", &path, "<payload/>"),
+                   "AWS4-HMAC-SHA256 \
+                    Credential=AKIDEXAMPLE/20161015/us-east-1/route53/aws4_request, \
+                    SignedHeaders=content-type;host;x-amz-date, \
+                    Signature=92e1b93280fe6bb1ffb71d34ca5a98735dd08540b310657b3e65c902e257e56b");
+    }
+}
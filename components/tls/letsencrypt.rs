@@ -1,23 +1,41 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use iron::{Handler, IronResult, Request, Response};
+use iron::mime::Mime;
+use iron::status::Status;
 use mktemp::Temp;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::mpsc::{channel, Receiver};
 use std::thread;
 use CertificateManager;
+use DnsConfig;
 
 const LETS_ENCRYPT_CLIENT: &'static str = include_str!("scripts/letsencrypt.sh");
 
+/// Where `AcmeChallengeHandler` expects to be mounted.
+pub const ACME_CHALLENGE_PATH: &'static str = "/.well-known/acme-challenge";
+
+/// How `LetsEncrypt` checks that we own the names of a certificate.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AcmeChallenge {
+    /// Publish a TXT record for each name with the DNS provider of `dns`.
+    Dns01 { dns: DnsConfig },
+    /// Serve a token at `http://<name>/.well-known/acme-challenge/<token>`, see
+    /// `AcmeChallengeHandler`. Port 80 of each name must reach the box, e.g. by port
+    /// forwarding.
+    Http01,
+}
+
 /// Get a SAN certificate from `LetsEncrypt` for a given list of names.
 pub fn get_san_cert_for<T>(names: T,
                            certificate_manager: CertificateManager,
-                           dns_endpoint: String)
+                           challenge: AcmeChallenge)
                            -> Receiver<io::Result<()>>
     where T: Iterator<Item = String>,
           T: DoubleEndedIterator,
@@ -27,7 +45,7 @@ pub fn get_san_cert_for<T>(names: T,
     let (tx, rx) = channel();
 
     thread::spawn(move || {
-        tx.send(_get_san_cert_for(names, certificate_manager, &dns_endpoint))
+        tx.send(_get_san_cert_for(names, certificate_manager, &challenge))
             .unwrap();
     });
 
@@ -37,7 +55,7 @@ pub fn get_san_cert_for<T>(names: T,
 /// Blocking version of `get_san_cert_for`
 fn _get_san_cert_for<T>(names: T,
                         certificate_manager: CertificateManager,
-                        dns_endpoint: &str)
+                        challenge: &AcmeChallenge)
                         -> io::Result<()>
     where T: Iterator<Item = String>,
           T: DoubleEndedIterator,
@@ -66,33 +84,57 @@ fn _get_san_cert_for<T>(names: T,
         try!(File::create(domains_file).and_then(|mut f| f.write_all(domains_txt.as_bytes())));
     }
 
-    let mut dns_challenge_file = temp_dir.to_path_buf();
-    {
-        dns_challenge_file.push("dns-challenge.sh");
+    let client_command = match *challenge {
+        AcmeChallenge::Dns01 { .. } => {
+            let mut dns_challenge_file = temp_dir.to_path_buf();
+            dns_challenge_file.push("dns-challenge.sh");
 
-        try!(File::create(dns_challenge_file.clone()).and_then(|mut f| {
-            f.write_all(create_challenge_script(&certificate_manager.get_certs_dir(),
-                                                &dns_endpoint)
-                .as_bytes())
-        }));
+            try!(File::create(dns_challenge_file.clone()).and_then(|mut f| {
+                f.write_all(create_challenge_script(&certificate_manager.get_certs_dir())
+                    .as_bytes())
+            }));
 
-        assert!(dns_challenge_file.as_path().exists());
-    }
+            assert!(dns_challenge_file.as_path().exists());
+
+            format!("chmod +x {} && bash {} --cron --challenge dns-01 --hook {}",
+                    dns_challenge_file.to_str().unwrap(),
+                    letsencrypt_file.to_str().unwrap(),
+                    dns_challenge_file.to_str().unwrap())
+        }
+        AcmeChallenge::Http01 => {
+            let challenge_dir = certificate_manager.get_acme_challenge_dir();
+            try!(fs::create_dir_all(&challenge_dir));
+
+            // The client only reads the challenge directory from a config file. It sits next
+            // to the client, so that the client keeps working in `temp_dir`.
+            let mut config_file = temp_dir.to_path_buf();
+            config_file.push("config");
+
+            try!(File::create(config_file.clone())
+                .and_then(|mut f| f.write_all(create_http_config(&challenge_dir).as_bytes())));
 
-    let command = format!("chmod +x {} && bash {} --cron --challenge dns-01 --hook {} && cp -R \
-                           {}/certs/* {}",
-                          dns_challenge_file.to_str().unwrap(),
-                          letsencrypt_file.to_str().unwrap(),
-                          dns_challenge_file.to_str().unwrap(),
+            format!("bash {} --cron --challenge http-01 --config {}",
+                    letsencrypt_file.to_str().unwrap(),
+                    config_file.to_str().unwrap())
+        }
+    };
+
+    let command = format!("{} && cp -R {}/certs/* {}",
+                          client_command,
                           temp_dir.to_path_buf().to_str().unwrap(),
                           certificate_manager.get_certs_dir().to_str().unwrap());
 
     debug!("Spawning letsencrypt client {}", command);
-    let mut child = try!(Command::new("/usr/bin/env")
-        .arg("sh")
-        .arg("-c")
-        .arg(command)
-        .spawn());
+    let mut client = Command::new("/usr/bin/env");
+    client.arg("sh").arg("-c").arg(command);
+    // The credentials of the DNS provider reach the `dnschallenge` hook through its
+    // environment, so that they are never written to disk.
+    if let AcmeChallenge::Dns01 { ref dns } = *challenge {
+        for (name, value) in dns.to_env() {
+            client.env(name, value);
+        }
+    }
+    let mut child = try!(client.spawn());
 
     let ecode = try!(child.wait());
 
@@ -120,16 +162,77 @@ fn _get_san_cert_for<T>(names: T,
     Ok(())
 }
 
-fn create_challenge_script<T: AsRef<Path>>(cert_path: T, dns_endpoint: &str) -> String {
+fn create_challenge_script<T: AsRef<Path>>(cert_path: T) -> String {
     format!("#!/usr/bin/env bash
 echo $@
 if [[ $1 == \"deploy_challenge\" ]];then
     \
-             RUST_BACKTRACE=1 CERTIFICATE_DIRECTORY={cert_path:?} \
-             DNS_API_ENDPOINT={dns_endpoint:?} dnschallenge $2 \"$4\"
+             RUST_BACKTRACE=1 CERTIFICATE_DIRECTORY={cert_path:?} dnschallenge $2 \"$4\"
 fi
 ",
 
-            cert_path = cert_path.as_ref(),
-            dns_endpoint = dns_endpoint)
+            cert_path = cert_path.as_ref())
+}
+
+fn create_http_config<T: AsRef<Path>>(challenge_dir: T) -> String {
+    format!("WELLKNOWN={:?}\n", challenge_dir.as_ref())
+}
+
+/// Tokens are base64url, so anything else, e.g. "..", is not the name of a token.
+fn is_acme_token(token: &str) -> bool {
+    !token.is_empty() &&
+    token.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => true,
+        _ => false,
+    })
+}
+
+/// Serves the tokens of the pending http-01 challenges, to be mounted at
+/// `ACME_CHALLENGE_PATH` of a plain HTTP server listening on port 80.
+pub struct AcmeChallengeHandler {
+    directory: PathBuf,
+}
+
+impl AcmeChallengeHandler {
+    pub fn new(certificate_manager: &CertificateManager) -> Self {
+        AcmeChallengeHandler { directory: certificate_manager.get_acme_challenge_dir() }
+    }
+}
+
+impl Handler for AcmeChallengeHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let path = req.url.path();
+        if path.len() != 1 || !is_acme_token(path[0]) {
+            return Ok(Response::with(Status::NotFound));
+        }
+        let mut key_authorization = String::new();
+        match File::open(self.directory.join(path[0]))
+            .and_then(|mut f| f.read_to_string(&mut key_authorization)) {
+            Ok(_) => {
+                let mime: Mime = "text/plain".parse().unwrap();
+                Ok(Response::with((Status::Ok, mime, key_authorization)))
+            }
+            Err(_) => Ok(Response::with(Status::NotFound)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod letsencrypt_test {
+    use std::path::PathBuf;
+    use super::{create_http_config, is_acme_token};
+
+    #[test]
+    fn test_is_acme_token() {
+        assert!(is_acme_token("evaGxfADs6pSRb2LAv9IZf17Dt3juxGJ-PCt92wr-oA"));
+        assert!(!is_acme_token(""));
+        assert!(!is_acme_token(".."));
+        assert!(!is_acme_token("../certs"));
+    }
+
+    #[test]
+    fn test_create_http_config() {
+        assert_eq!(create_http_config(PathBuf::from("/profile/acme-challenges")),
+                   "WELLKNOWN=\"/profile/acme-challenges\"\n");
+    }
 }
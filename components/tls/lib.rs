@@ -9,6 +9,7 @@
 #![deny(clippy)]
 
 
+extern crate chrono;
 #[macro_use]
 extern crate hyper;
 extern crate iron;
@@ -43,6 +44,8 @@ macro_rules! current_dir {
 mod certificate_manager;
 mod certificate_record;
 mod dns_client;
+mod dns_cloudflare;
+mod dns_route53;
 mod letsencrypt;
 mod ssl_context;
 mod utils;
@@ -50,6 +53,8 @@ mod utils;
 pub use certificate_manager::*;
 pub use certificate_record::*;
 pub use dns_client::*;
+pub use dns_cloudflare::*;
+pub use dns_route53::*;
 pub use letsencrypt::*;
 pub use ssl_context::*;
 
@@ -0,0 +1,224 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter exposing the alerts raised by the other adapters, see
+//! `AdapterManagerHandle::raise_alert`.
+//!
+//! The `alerts` service exposes `alerts/active` (fetch/watch): the alerts that the user hasn't
+//! dismissed yet, most recent first, as a JSON array of
+//! `{"id": number, "severity": "info" | "warning" | "critical", "message": string,
+//!   "action_hint": string | null, "raised": number}`.
+//!
+//! Alerts are listed by `GET api/v1/alerts` and dismissed by `DELETE api/v1/alerts/:id`. If
+//! `alerts.push` is "true", each new alert is also delivered as a WebPush notification on
+//! resource `alerts.webpush_resource` ("alerts" by default).
+
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::alerts::{Alert, AlertEvent};
+use foxbox_taxonomy::api::{API, Error, InternalError, Operation, Targetted, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::io::Payload;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::parse::{Parser, Path, ToJSON};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, Value};
+
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+static ADAPTER_NAME: &'static str = "Alerts adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+pub struct AlertsAdapter {
+    manager: Arc<AdapterManager>,
+    watchers: ValueWatchers,
+}
+
+fn alerts_value(alerts: &[Alert]) -> Value {
+    Value::new(Json(JSON::Array(alerts.iter().map(ToJSON::to_json).collect())))
+}
+
+impl AlertsAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("alerts@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:alerts@link.mozilla.org")
+    }
+
+    pub fn channel_active_id() -> Id<Channel> {
+        Id::new("channel:active.alerts@link.mozilla.org")
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let push = config.get_or_set_default("alerts", "push", "false") == "true";
+        let resource = config.get_or_set_default("alerts", "webpush_resource", "alerts");
+
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(AlertsAdapter {
+            manager: manager.clone(),
+            watchers: watchers.clone(),
+        })));
+        try!(manager.add_service(Service::empty(&Self::service_id(), &Self::id())));
+        try!(manager.add_channel(Channel {
+            id: Self::channel_active_id(),
+            service: Self::service_id(),
+            adapter: Self::id(),
+            feature: Id::new("alerts/active"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            supports_watch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            ..Channel::default()
+        }));
+
+        let (tx, rx) = mpsc::channel();
+        manager.watch_alerts(Box::new(tx));
+        watchers.update(&Self::channel_active_id(), alerts_value(&manager.alerts()));
+
+        let manager = manager.clone();
+        thread::Builder::new()
+            .name("Alerts".to_owned())
+            .spawn(move || {
+                for event in rx {
+                    watchers.update(&Self::channel_active_id(), alerts_value(&manager.alerts()));
+                    if let AlertEvent::Raised(alert) = event {
+                        if push {
+                            Self::push(&manager, &resource, &alert);
+                        }
+                    }
+                }
+            })
+            .unwrap();
+        Ok(())
+    }
+
+    /// Deliver an alert as a WebPush notification. This goes through the `AdapterManager`, so
+    /// we must not be called from one of its callbacks.
+    fn push(manager: &AdapterManager, resource: &str, alert: &Alert) {
+        let message = match alert.action_hint {
+            Some(ref hint) => format!("{} {}", alert.message, hint),
+            None => alert.message.clone(),
+        };
+        let mut notification = BTreeMap::new();
+        notification.insert("resource".to_owned(), JSON::String(resource.to_owned()));
+        notification.insert("message".to_owned(), JSON::String(message));
+        let payload = match Payload::parse(Path::new(), &JSON::Object(notification)) {
+            Ok(payload) => payload,
+            Err(err) => return error!("[alerts] Could not build notification: {:?}", err),
+        };
+        let results = manager.send_values(vec![Targetted {
+                                              select: vec![ChannelSelector::new()
+                                                  .with_feature(&Id::new("webpush/notify-msg"))],
+                                              payload: payload,
+                                          }],
+                                          User::None);
+        for (id, result) in results {
+            if let Err(err) = result {
+                warn!("[alerts] Could not push alert to {}: {:?}", id, err);
+            }
+        }
+    }
+}
+
+impl Adapter for AlertsAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                if id == Self::channel_active_id() {
+                    return (id, Ok(Some(alerts_value(&self.manager.alerts()))));
+                }
+                (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, _)| (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id))))
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
+
+#[cfg(test)]
+describe! alerts_adapter {
+    before_each {
+        use super::*;
+        use foxbox_taxonomy::alerts::Severity;
+        use foxbox_taxonomy::manager::AdapterManagerHandle;
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        AlertsAdapter::init(&harness.manager, harness.controller.clone()).unwrap();
+    }
+
+    it "should list, fetch and dismiss the alerts raised by adapters" {
+        harness.manager.raise_alert(Severity::Warning,
+                                    "Philips Hue bridge detected but not paired",
+                                    Some("Press the button on the bridge"));
+        // Raising the same alert again doesn't duplicate it.
+        harness.manager.raise_alert(Severity::Warning,
+                                    "Philips Hue bridge detected but not paired",
+                                    Some("Press the button on the bridge"));
+
+        let (status, json) = harness.request_json(Method::Get, "/api/v1/alerts", "", false);
+        assert_eq!(status, Status::Ok);
+        let alerts = json.as_array().unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].find("severity").and_then(|s| s.as_string()), Some("warning"));
+        assert_eq!(alerts[0].find("action_hint").and_then(|s| s.as_string()),
+                   Some("Press the button on the bridge"));
+
+        let body = r#"[{"id": "channel:active.alerts@link.mozilla.org",
+                        "feature": "alerts/active"}]"#;
+        let (status, json) = harness.request_json(Method::Put, "/api/v1/channels/get", body, false);
+        assert_eq!(status, Status::Ok);
+        let active = json.find("channel:active.alerts@link.mozilla.org")
+            .and_then(|value| value.as_array())
+            .map(|alerts| alerts.len());
+        assert_eq!(active, Some(1));
+
+        let id = alerts[0].find("id").and_then(|id| id.as_u64()).unwrap();
+        let path = format!("/api/v1/alerts/{}", id);
+        let (status, _) = harness.request(Method::Delete, &path, "", false);
+        assert_eq!(status, Status::NoContent);
+        let (status, _) = harness.request(Method::Delete, &path, "", false);
+        assert_eq!(status, Status::NotFound);
+        assert!(harness.manager.alerts().is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Baselines of numeric channels.
+//!
+//! Most household readings follow a daily pattern (e.g. power draw is low at night and high
+//! around dinner), so we keep the mean and variance of readings separately for each hour of
+//! the day. A reading is anomalous if it is more than `threshold` standard deviations away
+//! from the mean of its hour.
+//!
+//! Statistics are exponentially weighted once we have seen `MAX_WEIGHT` readings for an hour,
+//! so that the baseline slowly follows lasting changes (new appliance, change of season).
+
+use serde_json;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+
+/// Don't report anything for an hour before we have seen this many readings.
+pub const MIN_SAMPLES: u64 = 10;
+
+/// Older readings weigh less once we have seen this many readings for an hour.
+const MAX_WEIGHT: u64 = 200;
+
+/// Deviations smaller than this fraction of the mean are never anomalous, even if the signal
+/// used to be perfectly stable.
+const MIN_RELATIVE_DEVIATION: f64 = 0.01;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    pub count: u64,
+    pub mean: f64,
+    pub variance: f64,
+}
+
+impl Stats {
+    pub fn add(&mut self, reading: f64) {
+        self.count += 1;
+        let weight = if self.count < MAX_WEIGHT { self.count } else { MAX_WEIGHT };
+        let alpha = 1.0 / weight as f64;
+        let delta = reading - self.mean;
+        self.mean += alpha * delta;
+        self.variance = (1.0 - alpha) * (self.variance + alpha * delta * delta);
+    }
+
+    pub fn is_anomalous(&self, reading: f64, threshold: f64) -> bool {
+        if self.count < MIN_SAMPLES {
+            return false;
+        }
+        let deviation = self.variance
+            .sqrt()
+            .max(self.mean.abs() * MIN_RELATIVE_DEVIATION)
+            .max(::std::f64::EPSILON);
+        (reading - self.mean).abs() > threshold * deviation
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Baseline {
+    /// One entry per hour of the day.
+    pub hours: Vec<Stats>,
+}
+
+impl Default for Baseline {
+    fn default() -> Self {
+        Baseline { hours: vec![Stats::default(); 24] }
+    }
+}
+
+impl Baseline {
+    /// Check whether a reading is anomalous for this hour of the day, then learn it.
+    pub fn observe(&mut self, hour: usize, reading: f64, threshold: f64) -> bool {
+        let stats = &mut self.hours[hour % 24];
+        let is_anomalous = stats.is_anomalous(reading, threshold);
+        stats.add(reading);
+        is_anomalous
+    }
+}
+
+/// The baselines of all the channels, by channel id.
+pub type Baselines = HashMap<String, Baseline>;
+
+pub fn load(path: &str) -> Baselines {
+    let mut source = String::new();
+    if File::open(path).and_then(|mut file| file.read_to_string(&mut source)).is_err() {
+        return HashMap::new();
+    }
+    serde_json::from_str(&source).unwrap_or_else(|err| {
+        warn!("[analytics] Ignoring invalid baselines in {}: {}", path, err);
+        HashMap::new()
+    })
+}
+
+pub fn save(path: &str, baselines: &Baselines) -> Result<(), String> {
+    let source = try!(serde_json::to_string(baselines).map_err(|err| format!("{}", err)));
+    File::create(path)
+        .and_then(|mut file| file.write_all(source.as_bytes()))
+        .map_err(|err| format!("Could not write {}: {}", path, err))
+}
+
+#[cfg(test)]
+describe! analytics_baseline {
+    before_each {
+        use super::*;
+    }
+
+    it "should compute the mean and variance" {
+        let mut stats = Stats::default();
+        for reading in &[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+            stats.add(*reading);
+        }
+        assert!((stats.mean - 5.0).abs() < 1e-9);
+        assert!((stats.variance - 4.0).abs() < 1e-9);
+    }
+
+    it "should only flag readings far from the baseline of their hour" {
+        let mut baseline = Baseline::default();
+        for day in 0..MIN_SAMPLES {
+            // Around 100W at 3am, around 2kW at 7pm.
+            let noise = (day % 3) as f64 * 10.0;
+            assert!(!baseline.observe(3, 95.0 + noise, 4.0));
+            assert!(!baseline.observe(19, 1990.0 + noise, 4.0));
+        }
+        assert!(!baseline.observe(3, 110.0, 4.0));
+        assert!(baseline.observe(3, 1500.0, 4.0));
+        assert!(!baseline.observe(19, 2000.0, 4.0));
+        assert!(baseline.observe(19, 100.0, 4.0));
+    }
+
+    it "should tolerate small changes of perfectly stable signals" {
+        let mut baseline = Baseline::default();
+        for _ in 0..MIN_SAMPLES {
+            baseline.observe(0, 1000.0, 4.0);
+        }
+        assert!(!baseline.observe(0, 1020.0, 4.0));
+        assert!(baseline.observe(0, 1100.0, 4.0));
+    }
+}
@@ -0,0 +1,243 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter detecting anomalies in numeric channels.
+//!
+//! The channels to monitor are configured as a comma separated list of channel ids
+//! (`analytics.channels`). The adapter learns a baseline of each of them (see `baseline`) and,
+//! for each of them, exposes an `analytics/anomaly-detected` channel (fetch, watch), whose
+//! value is `Detected` while the latest reading is unusual for this time of the day. The
+//! sensitivity is `analytics.threshold`, in standard deviations (4 by default).
+//!
+//! Readings may be JSON numbers, objects with a numeric `value` field, or percentages.
+//! Baselines are kept in the profile, so they survive restarts.
+
+mod baseline;
+
+use self::baseline::Baselines;
+
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{API, Error, InternalError, Operation, Targetted, User, WatchEvent};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::parse::ToJSON;
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::util::Exactly;
+use foxbox_taxonomy::values::{format, IsDetected, Value};
+
+use chrono::{Local, Timelike};
+use serde_json::value::Value as JSON;
+
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static ADAPTER_NAME: &'static str = "Analytics adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const SAVE_INTERVAL_S: u64 = 600;
+
+pub struct AnalyticsAdapter {
+    /// The monitored channel, for each anomaly channel.
+    sources: HashMap<Id<Channel>, Id<Channel>>,
+    watchers: ValueWatchers,
+}
+
+/// Parse a comma separated list of channel ids.
+fn parse_channels(source: &str) -> Vec<Id<Channel>> {
+    source.split(',')
+        .map(|id| id.trim())
+        .filter(|id| !id.is_empty())
+        .map(Id::new)
+        .collect()
+}
+
+/// A numeric reading, either as a number or as an object with a numeric `value`.
+fn reading(value: &JSON) -> Option<f64> {
+    value.as_f64().or_else(|| value.find("value").and_then(JSON::as_f64))
+}
+
+impl AnalyticsAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("analytics@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:analytics@link.mozilla.org")
+    }
+
+    pub fn anomaly_id(source: &Id<Channel>) -> Id<Channel> {
+        Id::new(&format!("channel:anomaly.{}.analytics@link.mozilla.org", source))
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let channels = parse_channels(&config.get("analytics", "channels")
+            .unwrap_or_else(String::new));
+        let threshold = config.get_or_set_default("analytics", "threshold", "4")
+            .parse()
+            .unwrap_or(4.0);
+        let path = controller.get_profile().path_for("analytics_baselines.json");
+
+        let watchers = ValueWatchers::new();
+        let sources = channels.iter()
+            .map(|source| (Self::anomaly_id(source), source.clone()))
+            .collect();
+        try!(manager.add_adapter(Arc::new(AnalyticsAdapter {
+            sources: sources,
+            watchers: watchers.clone(),
+        })));
+        let mut service = Service::empty(&Self::service_id(), &Self::id());
+        service.properties.insert("model".to_owned(), "Anomaly detection".to_owned());
+        try!(manager.add_service(service));
+
+        let is_detected = format::IS_DETECTED.clone();
+        for source in &channels {
+            try!(manager.add_channel(Channel {
+                id: Self::anomaly_id(source),
+                service: Self::service_id(),
+                adapter: Self::id(),
+                feature: Id::new("analytics/anomaly-detected"),
+                supports_fetch: Some(Signature::returns(Maybe::Required(is_detected.clone()))),
+                supports_watch: Some(Signature {
+                    accepts: Maybe::Optional(is_detected.clone()),
+                    returns: Maybe::Required(is_detected.clone()),
+                }),
+                ..Channel::default()
+            }));
+        }
+
+        if channels.is_empty() {
+            info!("[analytics] No channel to monitor.");
+            return Ok(());
+        }
+        let manager = manager.clone();
+        thread::Builder::new()
+            .name("Analytics".to_owned())
+            .spawn(move || Self::monitor(&manager, channels, watchers, threshold, &path))
+            .unwrap();
+        Ok(())
+    }
+
+    /// Learn from the readings of the monitored channels, forever.
+    fn monitor(manager: &AdapterManager,
+               channels: Vec<Id<Channel>>,
+               watchers: ValueWatchers,
+               threshold: f64,
+               path: &str) {
+        let mut baselines: Baselines = baseline::load(path);
+        let mut last_save = Instant::now();
+        let (tx, rx) = mpsc::channel::<WatchEvent>();
+        // Keep the guard alive for as long as we are monitoring.
+        let _guard = manager.watch_values(vec![Targetted {
+                                               select: channels.iter()
+                                                   .map(|id| ChannelSelector::new().with_id(id))
+                                                   .collect(),
+                                               payload: Exactly::Always,
+                                           }],
+                                          Box::new(tx),
+                                          User::None);
+        for event in rx {
+            let (channel, value) = match event {
+                WatchEvent::EnterRange { channel, value, .. } => (channel, value),
+                _ => continue,
+            };
+            let reading = match reading(&value.to_json()) {
+                Some(reading) => reading,
+                None => {
+                    debug!("[analytics] Ignoring non numeric value of {}", channel);
+                    continue;
+                }
+            };
+            let is_anomalous = baselines.entry(channel.to_string())
+                .or_insert_with(Default::default)
+                .observe(Local::now().hour() as usize, reading, threshold);
+            if is_anomalous {
+                info!("[analytics] Unusual reading of {}: {}", channel, reading);
+            }
+            watchers.update(&Self::anomaly_id(&channel),
+                            Value::new(if is_anomalous {
+                                IsDetected::Detected
+                            } else {
+                                IsDetected::NotDetected
+                            }));
+            if last_save.elapsed() >= Duration::from_secs(SAVE_INTERVAL_S) {
+                if let Err(err) = baseline::save(path, &baselines) {
+                    warn!("[analytics] {}", err);
+                }
+                last_save = Instant::now();
+            }
+        }
+    }
+}
+
+impl Adapter for AnalyticsAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                if !self.sources.contains_key(&id) {
+                    return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))));
+                }
+                let value = self.watchers.latest(&id);
+                (id, Ok(value))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, _)| (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id))))
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
+
+#[cfg(test)]
+describe! analytics {
+    before_each {
+        use super::{parse_channels, reading};
+        use foxbox_taxonomy::util::Id;
+        use serde_json;
+    }
+
+    it "should parse the monitored channels" {
+        assert_eq!(parse_channels(" channel:a.modbus@link.mozilla.org,,channel:b "),
+                   vec![Id::new("channel:a.modbus@link.mozilla.org"), Id::new("channel:b")]);
+    }
+
+    it "should extract readings" {
+        assert_eq!(reading(&serde_json::from_str("1.5").unwrap()), Some(1.5));
+        assert_eq!(reading(&serde_json::from_str(r#"{"value": 42}"#).unwrap()), Some(42.0));
+        assert_eq!(reading(&serde_json::from_str(r#""On""#).unwrap()), None);
+    }
+}
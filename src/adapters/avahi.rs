@@ -0,0 +1,125 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! mDNS discovery.
+//!
+//! We rely on the avahi daemon that already runs on the box to perform the actual
+//! discovery, and parse the output of `avahi-browse`.
+
+use std::process::Command;
+
+/// A resolved mDNS service instance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resolved {
+    /// The instance name, e.g. "HP OfficeJet 3830".
+    pub name: String,
+    /// The IPv4 address.
+    pub address: String,
+    pub port: u16,
+    /// The TXT records, as `(key, value)`.
+    pub txt: Vec<(String, String)>,
+}
+
+impl Resolved {
+    /// The value of a TXT record.
+    pub fn txt(&self, key: &str) -> Option<&str> {
+        self.txt.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v as &str)
+    }
+}
+
+/// avahi escapes non-printable characters (including spaces) as `\DDD`.
+fn unescape(source: &str) -> String {
+    let mut result = Vec::new();
+    let bytes = source.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() &&
+           bytes[i + 1..i + 4].iter().all(|b| (*b as char).is_digit(10)) {
+            let code = (bytes[i + 1] - b'0') as u32 * 100 + (bytes[i + 2] - b'0') as u32 * 10 +
+                       (bytes[i + 3] - b'0') as u32;
+            result.push(code as u8);
+            i += 4;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Parse one line of `avahi-browse --resolve --parsable` output. Only resolved IPv4
+/// entries are considered.
+pub fn parse_line(line: &str) -> Option<Resolved> {
+    let fields: Vec<&str> = line.splitn(10, ';').collect();
+    if fields.len() < 10 || fields[0] != "=" || fields[2] != "IPv4" {
+        return None;
+    }
+    let port = match fields[8].parse() {
+        Ok(port) => port,
+        Err(_) => return None,
+    };
+    let txt = fields[9]
+        .split("\" \"")
+        .map(|record| record.trim_matches('"'))
+        .filter(|record| !record.is_empty())
+        .map(|record| {
+            let mut split = record.splitn(2, '=');
+            (split.next().unwrap_or("").to_owned(), split.next().unwrap_or("").to_owned())
+        })
+        .collect();
+    Some(Resolved {
+        name: unescape(fields[3]),
+        address: fields[7].to_owned(),
+        port: port,
+        txt: txt,
+    })
+}
+
+/// Run a single discovery round for a service type, e.g. `_ipp._tcp`.
+pub fn browse(service_type: &str) -> Vec<Resolved> {
+    let output = match Command::new("avahi-browse")
+        .args(&["--resolve", "--parsable", "--terminate", service_type])
+        .output() {
+        Ok(output) => output,
+        Err(err) => {
+            warn!("[avahi] Could not run avahi-browse: {}", err);
+            return vec![];
+        }
+    };
+    let mut instances: Vec<Resolved> = vec![];
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some(instance) = parse_line(line) {
+            // The same instance is reported once per interface.
+            if instances.iter().all(|known| known.name != instance.name) {
+                instances.push(instance);
+            }
+        }
+    }
+    instances
+}
+
+#[cfg(test)]
+describe! avahi {
+    before_each {
+        use super::*;
+    }
+
+    it "should parse resolved entries" {
+        let line = "=;wlan0;IPv4;HP\\032OfficeJet\\0323830;Internet Printer;local;hp.local;\
+                    192.168.1.12;631;\"txtvers=1\" \"rp=ipp/print\" \"ty=HP OfficeJet 3830\"";
+        let resolved = parse_line(line).unwrap();
+        assert_eq!(resolved.name, "HP OfficeJet 3830");
+        assert_eq!(resolved.address, "192.168.1.12");
+        assert_eq!(resolved.port, 631);
+        assert_eq!(resolved.txt("rp"), Some("ipp/print"));
+        assert_eq!(resolved.txt("ty"), Some("HP OfficeJet 3830"));
+        assert_eq!(resolved.txt("note"), None);
+    }
+
+    it "should ignore unresolved and IPv6 entries" {
+        assert_eq!(parse_line("+;wlan0;IPv4;HP;Internet Printer;local"), None);
+        assert_eq!(parse_line("=;wlan0;IPv6;HP;Internet Printer;local;hp.local;fe80::1;631;\"\""),
+                   None);
+    }
+}
@@ -1,7 +1,7 @@
 //! An adapter providing time-related services, such as the current
 //! timestamp or the current time of day.
 
-use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::api::{Error, InternalError, Observation, Operation, Source, User};
 use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::manager::*;
 use foxbox_taxonomy::services::*;
@@ -109,7 +109,7 @@ impl Adapter for Clock {
             .collect()
     }
 
-    fn register_watch(&self, mut watch: Vec<WatchTarget>) -> WatchResult {
+    fn register_watch(&self, mut watch: Vec<WatchTarget>, _: User) -> WatchResult {
         watch.drain(..)
             .map(|(id, filter, tx)| {
                 let tx = tx.map(|msg| {
@@ -118,12 +118,14 @@ impl Adapter for Clock {
                             WatchEvent::Enter {
                                 id: id,
                                 value: value,
+                                observed: Observation::now(Source::Device),
                             }
                         }
                         Op::Exit(id, value) => {
                             WatchEvent::Exit {
                                 id: id,
                                 value: value,
+                                observed: Observation::now(Source::Device),
                             }
                         }
                     }
@@ -350,7 +352,7 @@ impl Clock {
             feature: Id::new("clock/time-of-day-seconds"),
             supports_fetch: Some(Signature::returns(Maybe::Required(format::DURATION.clone()))),
             supports_watch: Some(Signature {
-                accepts: Maybe::Required(format::DURATION.clone()),
+                accepts: Maybe::Required(format::DURATION_RANGE.clone()),
                 returns: Maybe::Required(format::DURATION.clone())
             }),
             id: getter_time_of_day_id,
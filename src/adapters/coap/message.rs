@@ -0,0 +1,303 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Encoding and decoding of CoAP messages (RFC 7252).
+
+pub const VERSION: u8 = 1;
+
+// Method codes.
+pub const GET: u8 = 0x01;
+pub const POST: u8 = 0x02;
+pub const PUT: u8 = 0x03;
+pub const DELETE: u8 = 0x04;
+
+// Response codes, as `class << 5 | detail`.
+pub const CREATED: u8 = 0x41; // 2.01
+pub const DELETED: u8 = 0x42; // 2.02
+pub const CHANGED: u8 = 0x44; // 2.04
+pub const CONTENT: u8 = 0x45; // 2.05
+pub const BAD_REQUEST: u8 = 0x80; // 4.00
+pub const NOT_FOUND: u8 = 0x84; // 4.04
+pub const METHOD_NOT_ALLOWED: u8 = 0x85; // 4.05
+
+// Option numbers.
+pub const OBSERVE: u16 = 6;
+pub const LOCATION_PATH: u16 = 8;
+pub const URI_PATH: u16 = 11;
+pub const CONTENT_FORMAT: u16 = 12;
+pub const URI_QUERY: u16 = 15;
+pub const ACCEPT: u16 = 17;
+
+// Content formats.
+pub const TEXT_PLAIN: u8 = 0;
+pub const LINK_FORMAT: u8 = 40;
+
+const PAYLOAD_MARKER: u8 = 0xFF;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+impl Kind {
+    fn from_bits(bits: u8) -> Kind {
+        match bits & 0x03 {
+            0 => Kind::Confirmable,
+            1 => Kind::NonConfirmable,
+            2 => Kind::Acknowledgement,
+            _ => Kind::Reset,
+        }
+    }
+
+    fn to_bits(&self) -> u8 {
+        match *self {
+            Kind::Confirmable => 0,
+            Kind::NonConfirmable => 1,
+            Kind::Acknowledgement => 2,
+            Kind::Reset => 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub kind: Kind,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    /// Options, sorted by option number.
+    pub options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(kind: Kind, code: u8, message_id: u16, token: &[u8]) -> Self {
+        Message {
+            kind: kind,
+            code: code,
+            message_id: message_id,
+            token: token.to_vec(),
+            options: vec![],
+            payload: vec![],
+        }
+    }
+
+    /// Build the response to a request. Confirmable requests are answered with a piggybacked
+    /// acknowledgement.
+    pub fn response_to(request: &Message, code: u8) -> Self {
+        let kind = if request.kind == Kind::Confirmable {
+            Kind::Acknowledgement
+        } else {
+            Kind::NonConfirmable
+        };
+        Message::new(kind, code, request.message_id, &request.token)
+    }
+
+    pub fn is_request(&self) -> bool {
+        self.code >= GET && self.code <= DELETE
+    }
+
+    pub fn add_option(&mut self, number: u16, value: &[u8]) {
+        // Keep the options sorted, preserving the order of repeated options.
+        let position = self.options
+            .iter()
+            .position(|&(n, _)| n > number)
+            .unwrap_or(self.options.len());
+        self.options.insert(position, (number, value.to_vec()));
+    }
+
+    pub fn set_path(&mut self, path: &str) {
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            self.add_option(URI_PATH, segment.as_bytes());
+        }
+    }
+
+    fn strings(&self, number: u16) -> Vec<String> {
+        self.options
+            .iter()
+            .filter(|&&(n, _)| n == number)
+            .map(|&(_, ref value)| String::from_utf8_lossy(value).into_owned())
+            .collect()
+    }
+
+    pub fn path(&self) -> Vec<String> {
+        self.strings(URI_PATH)
+    }
+
+    /// The value of a `key=value` query parameter.
+    pub fn query(&self, key: &str) -> Option<String> {
+        self.strings(URI_QUERY)
+            .iter()
+            .filter_map(|param| {
+                let mut split = param.splitn(2, '=');
+                match (split.next(), split.next()) {
+                    (Some(k), Some(v)) if k == key => Some(v.to_owned()),
+                    _ => None,
+                }
+            })
+            .next()
+    }
+
+    /// The value of the Observe option, if any.
+    pub fn observe(&self) -> Option<u32> {
+        self.options
+            .iter()
+            .find(|&&(n, _)| n == OBSERVE)
+            .map(|&(_, ref value)| value.iter().fold(0, |acc, byte| (acc << 8) | *byte as u32))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + self.token.len() + self.payload.len());
+        buf.push(VERSION << 6 | self.kind.to_bits() << 4 | self.token.len() as u8 & 0x0F);
+        buf.push(self.code);
+        buf.push((self.message_id >> 8) as u8);
+        buf.push(self.message_id as u8);
+        buf.extend_from_slice(&self.token);
+
+        let mut previous = 0;
+        for &(number, ref value) in &self.options {
+            let (delta, delta_ext) = nibble(number - previous);
+            let (length, length_ext) = nibble(value.len() as u16);
+            buf.push(delta << 4 | length);
+            buf.extend_from_slice(&delta_ext);
+            buf.extend_from_slice(&length_ext);
+            buf.extend_from_slice(value);
+            previous = number;
+        }
+
+        if !self.payload.is_empty() {
+            buf.push(PAYLOAD_MARKER);
+            buf.extend_from_slice(&self.payload);
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Message, String> {
+        if buf.len() < 4 {
+            return Err("Message too short".to_owned());
+        }
+        if buf[0] >> 6 != VERSION {
+            return Err(format!("Unsupported version {}", buf[0] >> 6));
+        }
+        let token_length = (buf[0] & 0x0F) as usize;
+        if token_length > 8 || buf.len() < 4 + token_length {
+            return Err("Invalid token".to_owned());
+        }
+        let mut message = Message::new(Kind::from_bits(buf[0] >> 4),
+                                       buf[1],
+                                       (buf[2] as u16) << 8 | buf[3] as u16,
+                                       &buf[4..4 + token_length]);
+
+        let mut pos = 4 + token_length;
+        let mut number: u16 = 0;
+        while pos < buf.len() {
+            if buf[pos] == PAYLOAD_MARKER {
+                message.payload = buf[pos + 1..].to_vec();
+                break;
+            }
+            let header = buf[pos];
+            pos += 1;
+            let delta = try!(extended(header >> 4, buf, &mut pos));
+            let length = try!(extended(header & 0x0F, buf, &mut pos)) as usize;
+            if pos + length > buf.len() {
+                return Err("Truncated option".to_owned());
+            }
+            number = try!(number.checked_add(delta)
+                .ok_or_else(|| "Option number too large".to_owned()));
+            message.options.push((number, buf[pos..pos + length].to_vec()));
+            pos += length;
+        }
+        Ok(message)
+    }
+}
+
+/// Split an option delta or length into its 4-bit nibble and extended bytes.
+fn nibble(value: u16) -> (u8, Vec<u8>) {
+    if value < 13 {
+        (value as u8, vec![])
+    } else if value < 269 {
+        (13, vec![(value - 13) as u8])
+    } else {
+        let value = value - 269;
+        (14, vec![(value >> 8) as u8, value as u8])
+    }
+}
+
+fn extended(nibble: u8, buf: &[u8], pos: &mut usize) -> Result<u16, String> {
+    match nibble {
+        13 if *pos < buf.len() => {
+            *pos += 1;
+            Ok(buf[*pos - 1] as u16 + 13)
+        }
+        14 if *pos + 1 < buf.len() => {
+            *pos += 2;
+            ((buf[*pos - 2] as u16) << 8 | buf[*pos - 1] as u16)
+                .checked_add(269)
+                .ok_or_else(|| "Option delta or length too large".to_owned())
+        }
+        n if n < 13 => Ok(n as u16),
+        _ => Err("Invalid option header".to_owned()),
+    }
+}
+
+/// Encode an unsigned integer as the shortest big-endian byte sequence, as used by options
+/// such as Observe.
+pub fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8];
+    bytes.iter().skip_while(|byte| **byte == 0).cloned().collect()
+}
+
+#[cfg(test)]
+describe! coap_message {
+    before_each {
+        use super::*;
+    }
+
+    it "should round-trip a request" {
+        let mut message = Message::new(Kind::Confirmable, POST, 0x1234, &[1, 2, 3]);
+        message.set_path("/rd");
+        message.add_option(URI_QUERY, b"ep=esp-kitchen");
+        message.add_option(URI_QUERY, b"lt=300");
+        message.add_option(CONTENT_FORMAT, &[LINK_FORMAT]);
+        message.payload = b"</3303/0>,</3311/0>".to_vec();
+
+        let decoded = Message::decode(&message.encode()).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.path(), vec!["rd".to_owned()]);
+        assert_eq!(decoded.query("ep"), Some("esp-kitchen".to_owned()));
+        assert_eq!(decoded.query("lt"), Some("300".to_owned()));
+        assert_eq!(decoded.query("b"), None);
+        assert!(decoded.is_request());
+    }
+
+    it "should encode extended option lengths" {
+        let mut message = Message::new(Kind::NonConfirmable, CONTENT, 1, &[]);
+        let long = vec![b'x'; 300];
+        message.add_option(URI_PATH, &long);
+        message.add_option(OBSERVE, &encode_uint(258));
+        let encoded = message.encode();
+        // Observe (6) comes first, with a 2 bytes value.
+        assert_eq!(encoded[4], 0x62);
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(decoded.observe(), Some(258));
+        assert_eq!(decoded.path()[0].len(), 300);
+    }
+
+    it "should reject malformed messages" {
+        assert!(Message::decode(&[0x40, 0x01]).is_err());
+        assert!(Message::decode(&[0x80, 0x01, 0, 0]).is_err());
+        assert!(Message::decode(&[0x40, 0x01, 0, 0, 0xD1]).is_err());
+    }
+
+    it "should reject option deltas that overflow" {
+        // A 14-nibble delta of 0xFFFF, beyond the largest option number once 269 is added.
+        assert!(Message::decode(&[0x40, 0x01, 0, 0, 0xE0, 0xFF, 0xFF]).is_err());
+        // Deltas that add up beyond the largest option number.
+        assert!(Message::decode(&[0x40, 0x01, 0, 0, 0xE0, 0xFE, 0xF2, 0xE0, 0xFE, 0xF2])
+            .is_err());
+    }
+}
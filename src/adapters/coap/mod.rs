@@ -0,0 +1,594 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter for constrained devices speaking CoAP.
+//!
+//! The adapter runs a CoAP server (port `coap.port`, 5683 by default) implementing the
+//! registration interface of LwM2M: devices register with `POST /rd?ep=<name>&lt=<lifetime>`
+//! and a link-format list of their object instances, e.g. `</3303/0>,</3311/0>`, refresh their
+//! registration with `POST /rd/<name>` and deregister with `DELETE /rd/<name>`.
+//!
+//! Each registered device is exposed as a service. Object instances of the following IPSO
+//! objects are mapped to channels, whose values are kept up to date by observing the
+//! corresponding resource on the device:
+//! - 3303 (temperature): `temperature/reading` (fetch, watch), as JSON `{"C": value}`;
+//! - 3304 (humidity): `humidity/reading` (fetch, watch), as a `Percent`;
+//! - 3302 (presence): `motion/is-detected` (fetch, watch);
+//! - 3342 (on/off switch): `switch/is-on` (fetch, watch);
+//! - 3311 (light control): `light/is-on` (fetch, watch, send).
+//!
+//! Devices whose registration expires are removed.
+
+mod message;
+
+use self::message::*;
+
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::io::Format;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, IsDetected, Json, OnOff, Percent, Value};
+
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static ADAPTER_NAME: &'static str = "CoAP/LwM2M adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const DEFAULT_LIFETIME_S: u64 = 86400;
+const EXPIRATION_CHECK_S: u64 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ResourceKind {
+    Temperature,
+    Humidity,
+    Presence,
+    Switch,
+    Light,
+}
+
+impl ResourceKind {
+    /// The kind of an IPSO object, and the resource holding its value.
+    fn for_object(object: u32) -> Option<(ResourceKind, u32)> {
+        match object {
+            3302 => Some((ResourceKind::Presence, 5500)),
+            3303 => Some((ResourceKind::Temperature, 5700)),
+            3304 => Some((ResourceKind::Humidity, 5700)),
+            3311 => Some((ResourceKind::Light, 5850)),
+            3342 => Some((ResourceKind::Switch, 5500)),
+            _ => None,
+        }
+    }
+
+    fn feature(&self) -> &'static str {
+        match *self {
+            ResourceKind::Temperature => "temperature/reading",
+            ResourceKind::Humidity => "humidity/reading",
+            ResourceKind::Presence => "motion/is-detected",
+            ResourceKind::Switch => "switch/is-on",
+            ResourceKind::Light => "light/is-on",
+        }
+    }
+
+    fn format(&self) -> Arc<Format> {
+        match *self {
+            ResourceKind::Temperature => format::JSON.clone(),
+            ResourceKind::Humidity => format::PERCENT.clone(),
+            ResourceKind::Presence => format::IS_DETECTED.clone(),
+            ResourceKind::Switch | ResourceKind::Light => format::ON_OFF.clone(),
+        }
+    }
+
+    fn is_writable(&self) -> bool {
+        *self == ResourceKind::Light
+    }
+
+    /// Parse a value in the LwM2M plain text format.
+    fn parse(&self, text: &str) -> Option<Value> {
+        let text = text.trim();
+        let flag = match text {
+            "1" | "true" => Some(true),
+            "0" | "false" => Some(false),
+            _ => None,
+        };
+        match *self {
+            ResourceKind::Temperature => {
+                text.parse::<f64>().ok().map(|celsius| {
+                    let mut reading = BTreeMap::new();
+                    reading.insert("C".to_owned(), JSON::F64(celsius));
+                    Value::new(Json(JSON::Object(reading)))
+                })
+            }
+            ResourceKind::Humidity => {
+                text.parse::<f64>()
+                    .ok()
+                    .map(|value| Value::new(Percent::new(value.max(0.).min(100.) as u8)))
+            }
+            ResourceKind::Presence => {
+                flag.map(|flag| {
+                    Value::new(if flag {
+                        IsDetected::Detected
+                    } else {
+                        IsDetected::NotDetected
+                    })
+                })
+            }
+            ResourceKind::Switch | ResourceKind::Light => {
+                flag.map(|flag| Value::new(if flag { OnOff::On } else { OnOff::Off }))
+            }
+        }
+    }
+}
+
+/// Parse the link-format payload of a registration into (object, instance) pairs.
+fn parse_links(payload: &str) -> Vec<(u32, u32)> {
+    payload.split(',')
+        .filter_map(|link| {
+            let path = link.trim().split(';').next().unwrap_or("");
+            let path = path.trim_left_matches('<').trim_right_matches('>');
+            let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+            if segments.len() != 2 {
+                return None;
+            }
+            match (segments[0].parse(), segments[1].parse()) {
+                (Ok(object), Ok(instance)) => Some((object, instance)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// An endpoint name, made suitable for use in an id.
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .to_lowercase()
+}
+
+struct Resource {
+    endpoint: String,
+    /// The path of the resource on the device, e.g. "3303/0/5700".
+    path: String,
+    kind: ResourceKind,
+}
+
+struct Endpoint {
+    address: SocketAddr,
+    expires: Instant,
+    channels: Vec<Id<Channel>>,
+}
+
+struct State {
+    endpoints: HashMap<String, Endpoint>,
+    resources: HashMap<Id<Channel>, Resource>,
+    /// The channel observed with each token.
+    tokens: HashMap<Vec<u8>, Id<Channel>>,
+    next_message_id: u16,
+    next_token: u32,
+}
+
+impl State {
+    fn message_id(&mut self) -> u16 {
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        self.next_message_id
+    }
+
+    fn token(&mut self) -> Vec<u8> {
+        self.next_token = self.next_token.wrapping_add(1);
+        encode_uint(self.next_token)
+    }
+}
+
+pub struct CoapAdapter {
+    socket: UdpSocket,
+    state: Arc<Mutex<State>>,
+    watchers: ValueWatchers,
+}
+
+impl CoapAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("coap@link.mozilla.org")
+    }
+
+    fn service_id(endpoint: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}@coap.link.mozilla.org", slug(endpoint)))
+    }
+
+    fn channel_id(endpoint: &str, object: u32, instance: u32) -> Id<Channel> {
+        Id::new(&format!("channel:{}-{}.{}@coap.link.mozilla.org",
+                         object,
+                         instance,
+                         slug(endpoint)))
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let port = controller.get_config()
+            .get_or_set_default("coap", "port", "5683")
+            .parse()
+            .unwrap_or(5683);
+        let socket = try!(UdpSocket::bind(("::", port))
+            .and_then(|socket| {
+                try!(socket.set_read_timeout(Some(Duration::from_secs(EXPIRATION_CHECK_S))));
+                Ok(socket)
+            })
+            .map_err(|err| {
+                let message = format!("Cannot bind CoAP port {}: {}", port, err);
+                Error::Internal(InternalError::GenericError(message))
+            }));
+        let server_socket = try!(socket.try_clone()
+            .map_err(|err| Error::Internal(InternalError::GenericError(format!("{}", err)))));
+
+        let state = Arc::new(Mutex::new(State {
+            endpoints: HashMap::new(),
+            resources: HashMap::new(),
+            tokens: HashMap::new(),
+            next_message_id: 0,
+            next_token: 0,
+        }));
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(CoapAdapter {
+            socket: socket,
+            state: state.clone(),
+            watchers: watchers.clone(),
+        })));
+        info!("[coap] Listening on port {}", port);
+
+        let server = Server {
+            socket: server_socket,
+            state: state,
+            watchers: watchers,
+            manager: manager.clone(),
+        };
+        thread::Builder::new()
+            .name("CoAP server".to_owned())
+            .spawn(move || server.run())
+            .unwrap();
+
+        Ok(())
+    }
+}
+
+struct Server {
+    socket: UdpSocket,
+    state: Arc<Mutex<State>>,
+    watchers: ValueWatchers,
+    manager: Arc<AdapterManager>,
+}
+
+impl Server {
+    fn run(&self) {
+        let mut buf = [0; 2048];
+        loop {
+            if let Ok((len, address)) = self.socket.recv_from(&mut buf) {
+                match Message::decode(&buf[..len]) {
+                    Ok(message) => self.handle(message, address),
+                    Err(err) => {
+                        debug!("[coap] Dropping malformed message from {}: {}", address, err)
+                    }
+                }
+            }
+            self.expire();
+        }
+    }
+
+    fn send(&self, message: &Message, address: SocketAddr) {
+        if let Err(err) = self.socket.send_to(&message.encode(), address) {
+            warn!("[coap] Could not send to {}: {}", address, err);
+        }
+    }
+
+    fn handle(&self, message: Message, address: SocketAddr) {
+        if !message.is_request() {
+            return self.handle_response(message, address);
+        }
+        let path = message.path();
+        let is_registration = path.first().map_or(false, |segment| segment == "rd");
+        let response = match (message.code, is_registration, path.len()) {
+            (POST, true, 1) => self.register(&message, address),
+            (POST, true, 2) => {
+                let mut state = self.state.lock().unwrap();
+                match state.endpoints.get_mut(&path[1]) {
+                    Some(data) => {
+                        let lifetime = lifetime(&message);
+                        data.address = address;
+                        data.expires = Instant::now() + Duration::from_secs(lifetime);
+                        Message::response_to(&message, CHANGED)
+                    }
+                    None => Message::response_to(&message, NOT_FOUND),
+                }
+            }
+            (DELETE, true, 2) => {
+                if self.deregister(&path[1]) {
+                    Message::response_to(&message, DELETED)
+                } else {
+                    Message::response_to(&message, NOT_FOUND)
+                }
+            }
+            (_, true, 1) | (_, true, 2) => Message::response_to(&message, METHOD_NOT_ALLOWED),
+            _ => Message::response_to(&message, NOT_FOUND),
+        };
+        self.send(&response, address);
+    }
+
+    fn register(&self, message: &Message, address: SocketAddr) -> Message {
+        let endpoint = match message.query("ep") {
+            Some(endpoint) => endpoint,
+            None => return Message::response_to(message, BAD_REQUEST),
+        };
+        // A device registering again has typically rebooted.
+        self.deregister(&endpoint);
+
+        let service_id = CoapAdapter::service_id(&endpoint);
+        let mut service = Service::empty(&service_id, &CoapAdapter::id());
+        service.properties.insert("model".to_owned(), "CoAP device".to_owned());
+        service.properties.insert("endpoint".to_owned(), endpoint.clone());
+        if let Err(err) = self.manager.add_service(service) {
+            warn!("[coap] Could not add service for {}: {:?}", endpoint, err);
+            return Message::response_to(message, BAD_REQUEST);
+        }
+
+        let mut channels = vec![];
+        let links = parse_links(&String::from_utf8_lossy(&message.payload));
+        for (object, instance) in links {
+            let (kind, resource) = match ResourceKind::for_object(object) {
+                Some(mapping) => mapping,
+                None => continue,
+            };
+            let id = CoapAdapter::channel_id(&endpoint, object, instance);
+            let format = kind.format();
+            let result = self.manager.add_channel(Channel {
+                id: id.clone(),
+                service: service_id.clone(),
+                adapter: CoapAdapter::id(),
+                feature: Id::new(kind.feature()),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format.clone()))),
+                supports_watch: Some(Signature::returns(Maybe::Required(format.clone()))),
+                supports_send: if kind.is_writable() {
+                    Some(Signature::accepts(Maybe::Required(format)))
+                } else {
+                    None
+                },
+                ..Channel::default()
+            });
+            if let Err(err) = result {
+                warn!("[coap] Could not add channel {}: {:?}", id, err);
+                continue;
+            }
+            let path = format!("{}/{}/{}", object, instance, resource);
+            self.observe(&id, &path, address);
+            let resource = Resource {
+                endpoint: endpoint.clone(),
+                path: path,
+                kind: kind,
+            };
+            self.state.lock().unwrap().resources.insert(id.clone(), resource);
+            channels.push(id);
+        }
+
+        info!("[coap] Registered {} from {} with {} channels",
+              endpoint,
+              address,
+              channels.len());
+        let data = Endpoint {
+            address: address,
+            expires: Instant::now() + Duration::from_secs(lifetime(message)),
+            channels: channels,
+        };
+        self.state.lock().unwrap().endpoints.insert(endpoint.clone(), data);
+
+        let mut response = Message::response_to(message, CREATED);
+        response.add_option(LOCATION_PATH, b"rd");
+        response.add_option(LOCATION_PATH, endpoint.as_bytes());
+        response
+    }
+
+    /// Start observing a resource on a device.
+    fn observe(&self, id: &Id<Channel>, path: &str, address: SocketAddr) {
+        let request = {
+            let mut state = self.state.lock().unwrap();
+            let token = state.token();
+            state.tokens.insert(token.clone(), id.clone());
+            let mut request = Message::new(Kind::Confirmable, GET, state.message_id(), &token);
+            request.add_option(OBSERVE, &[]);
+            request.set_path(path);
+            request.add_option(ACCEPT, &[TEXT_PLAIN]);
+            request
+        };
+        self.send(&request, address);
+    }
+
+    /// Remove a device and its channels. Returns `false` if the device is not registered.
+    fn deregister(&self, endpoint: &str) -> bool {
+        let data = {
+            let mut state = self.state.lock().unwrap();
+            let data = match state.endpoints.remove(endpoint) {
+                Some(data) => data,
+                None => return false,
+            };
+            for id in &data.channels {
+                state.resources.remove(id);
+            }
+            state.tokens.retain(|_, id| !data.channels.contains(id));
+            data
+        };
+        for id in &data.channels {
+            self.watchers.forget(id);
+        }
+        if let Err(err) = self.manager.remove_service(&CoapAdapter::service_id(endpoint)) {
+            warn!("[coap] Could not remove service for {}: {:?}", endpoint, err);
+        }
+        info!("[coap] Deregistered {}", endpoint);
+        true
+    }
+
+    fn handle_response(&self, message: Message, address: SocketAddr) {
+        if message.kind == Kind::Confirmable {
+            // Notifications may be confirmable, acknowledge them.
+            self.send(&Message::new(Kind::Acknowledgement, 0, message.message_id, &[]), address);
+        }
+        if message.token.is_empty() {
+            // An empty acknowledgement.
+            return;
+        }
+        let (id, kind) = {
+            let state = self.state.lock().unwrap();
+            let id = match state.tokens.get(&message.token) {
+                Some(id) => id.clone(),
+                None => return,
+            };
+            match state.resources.get(&id) {
+                Some(resource) => (id, resource.kind),
+                None => return,
+            }
+        };
+        if message.code != CONTENT {
+            warn!("[coap] Could not observe {}: code {}.{:02}",
+                  id,
+                  message.code >> 5,
+                  message.code & 0x1F);
+            return;
+        }
+        match kind.parse(&String::from_utf8_lossy(&message.payload)) {
+            Some(value) => self.watchers.update(&id, value),
+            None => debug!("[coap] Could not parse the value of {}", id),
+        }
+    }
+
+    fn expire(&self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self.state
+            .lock()
+            .unwrap()
+            .endpoints
+            .iter()
+            .filter(|&(_, data)| data.expires <= now)
+            .map(|(endpoint, _)| endpoint.clone())
+            .collect();
+        for endpoint in expired {
+            info!("[coap] The registration of {} has expired", endpoint);
+            self.deregister(&endpoint);
+        }
+    }
+}
+
+fn lifetime(message: &Message) -> u64 {
+    message.query("lt")
+        .and_then(|lifetime| lifetime.parse().ok())
+        .unwrap_or(DEFAULT_LIFETIME_S)
+}
+
+impl Adapter for CoapAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        let state = self.state.lock().unwrap();
+        set.drain(..)
+            .map(|id| {
+                if !state.resources.contains_key(&id) {
+                    return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))));
+                }
+                let value = self.watchers.latest(&id);
+                (id, Ok(value))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        let mut state = self.state.lock().unwrap();
+        values.drain()
+            .map(|(id, value)| {
+                let (address, path) = match state.resources.get(&id) {
+                    Some(resource) if resource.kind.is_writable() => {
+                        match state.endpoints.get(&resource.endpoint) {
+                            Some(endpoint) => (endpoint.address, resource.path.clone()),
+                            None => {
+                                return (id.clone(),
+                                        Err(Error::Internal(InternalError::NoSuchChannel(id))))
+                            }
+                        }
+                    }
+                    Some(_) => {
+                        return (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id)))
+                    }
+                    None => {
+                        return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+                    }
+                };
+                let payload = match value.cast::<OnOff>() {
+                    Ok(&OnOff::On) => "1",
+                    Ok(&OnOff::Off) => "0",
+                    Err(err) => return (id, Err(err)),
+                };
+                let mut request = Message::new(Kind::Confirmable, PUT, state.message_id(), &[]);
+                request.set_path(&path);
+                request.add_option(CONTENT_FORMAT, &[TEXT_PLAIN]);
+                request.payload = payload.as_bytes().to_vec();
+                let result = self.socket
+                    .send_to(&request.encode(), address)
+                    .map(|_| ())
+                    .map_err(|err| {
+                        Error::Internal(InternalError::GenericError(format!("{}", err)))
+                    });
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
+
+#[cfg(test)]
+describe! coap {
+    before_each {
+        use super::{parse_links, ResourceKind};
+        use foxbox_taxonomy::values::{IsDetected, OnOff};
+    }
+
+    it "should parse registration links" {
+        assert_eq!(parse_links("</1/0>,</3/0>;ver=1.0, </3303/0>,</3303/1>,</3311>"),
+                   vec![(1, 0), (3, 0), (3303, 0), (3303, 1)]);
+        assert_eq!(parse_links(""), vec![]);
+    }
+
+    it "should parse plain text values" {
+        let value = ResourceKind::Light.parse("1").unwrap();
+        assert_eq!(value.cast::<OnOff>().unwrap(), &OnOff::On);
+        let value = ResourceKind::Presence.parse("0").unwrap();
+        assert_eq!(value.cast::<IsDetected>().unwrap(), &IsDetected::NotDetected);
+        assert!(ResourceKind::Temperature.parse("21.5").is_some());
+        assert!(ResourceKind::Temperature.parse("warm").is_none());
+    }
+}
@@ -0,0 +1,206 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Composite devices, and how the values of their members translate into their states.
+//!
+//! Each member is a selector for a channel of another adapter, under a name local to the
+//! device, e.g. `relay` or `tilt`. Each state of the device is a list of cases: the value of
+//! the first case whose members all have the expected values, or the default value if none
+//! does. A member that hasn't reported a value yet matches no case.
+
+use foxbox_taxonomy::parse::{Parser, Path};
+use foxbox_taxonomy::selector::ChannelSelector;
+
+use serde_json;
+use serde_json::value::Value as JSON;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Case {
+    /// The expected value of each member, as JSON.
+    pub when: Vec<(String, JSON)>,
+    pub value: String,
+}
+
+impl Case {
+    fn matches(&self, values: &HashMap<String, JSON>) -> bool {
+        self.when.iter().all(|&(ref member, ref expected)| values.get(member) == Some(expected))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct State {
+    pub feature: String,
+    pub cases: Vec<Case>,
+    pub default: Option<String>,
+}
+
+impl State {
+    /// The state of the device, given the latest value of each member.
+    pub fn derive(&self, values: &HashMap<String, JSON>) -> Option<String> {
+        self.cases
+            .iter()
+            .find(|case| case.matches(values))
+            .map(|case| case.value.clone())
+            .or_else(|| self.default.clone())
+    }
+}
+
+pub struct Composite {
+    pub id: String,
+    pub name: String,
+    pub model: String,
+    pub members: Vec<(String, ChannelSelector)>,
+    pub states: Vec<State>,
+}
+
+fn parse_state(device: &str,
+               json: &JSON,
+               members: &[(String, ChannelSelector)])
+               -> Result<State, String> {
+    let feature = try!(json.find("feature")
+        .and_then(JSON::as_string)
+        .ok_or_else(|| format!("Every state of device {} needs a `feature`", device)));
+    let cases = try!(json.find("cases")
+        .and_then(JSON::as_array)
+        .ok_or_else(|| format!("State {} of device {} has no `cases`", feature, device)));
+    let mut result = vec![];
+    for case in cases {
+        let when = try!(case.find("when")
+            .and_then(JSON::as_object)
+            .ok_or_else(|| format!("Every case of state {} needs a `when` object", feature)));
+        for member in when.keys() {
+            if !members.iter().any(|&(ref name, _)| name == member) {
+                return Err(format!("State {} of device {} refers to unknown member {}",
+                                   feature,
+                                   device,
+                                   member));
+            }
+        }
+        let value = try!(case.find("value")
+            .and_then(JSON::as_string)
+            .ok_or_else(|| format!("Every case of state {} needs a `value`", feature)));
+        result.push(Case {
+            when: when.iter().map(|(member, value)| (member.clone(), value.clone())).collect(),
+            value: value.to_owned(),
+        });
+    }
+    Ok(State {
+        feature: feature.to_owned(),
+        cases: result,
+        default: json.find("default").and_then(JSON::as_string).map(str::to_owned),
+    })
+}
+
+/// Read the composite devices from the file at `path`, if it exists.
+pub fn from_file(path: &str) -> Result<Vec<Composite>, String> {
+    let mut source = String::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            try!(file.read_to_string(&mut source)
+                .map_err(|err| format!("Could not read {}: {}", path, err)));
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(format!("Could not read {}: {}", path, err)),
+    }
+    parse_composites(&source)
+}
+
+/// Parse the composite devices, as defined in `composites.json` in the profile:
+///
+/// ```json
+/// [{ "id": "garage-door", "name": "Garage door", "model": "Garage door",
+///    "members": { "relay": { "id": "channel:relay.garage" },
+///                 "tilt": { "feature": "door/is-open", "tags": ["garage"] } },
+///    "states": [{ "feature": "garage-door/state", "default": "unknown",
+///                 "cases": [{ "when": { "tilt": "Open" }, "value": "open" },
+///                           { "when": { "tilt": "Closed", "relay": "On" },
+///                             "value": "moving" },
+///                           { "when": { "tilt": "Closed" }, "value": "closed" }] }] }]
+/// ```
+pub fn parse_composites(source: &str) -> Result<Vec<Composite>, String> {
+    let json: JSON = try!(serde_json::from_str(source).map_err(|err| format!("{}", err)));
+    let devices = try!(json.as_array().ok_or_else(|| "Expected an array of devices".to_owned()));
+    let mut result = Vec::new();
+    for device in devices {
+        let id = try!(device.find("id")
+            .and_then(JSON::as_string)
+            .ok_or_else(|| "Every device needs an `id`".to_owned()));
+        let name = device.find("name").and_then(JSON::as_string).unwrap_or(id);
+        let model = device.find("model").and_then(JSON::as_string).unwrap_or("Composite");
+        let members = match device.find("members").and_then(JSON::as_object) {
+            Some(members) => members,
+            None => return Err(format!("Device {} has no `members`", id)),
+        };
+        let mut selectors = vec![];
+        for (member, selector) in members {
+            let selector = try!(ChannelSelector::parse(Path::new(), selector)
+                .map_err(|err| format!("Invalid member {} of device {}: {:?}", member, id, err)));
+            selectors.push((member.clone(), selector));
+        }
+        let states = match device.find("states").and_then(JSON::as_array) {
+            Some(states) if !states.is_empty() => states,
+            _ => return Err(format!("Device {} has no `states`", id)),
+        };
+        let states = try!(states.iter()
+            .map(|state| parse_state(id, state, &selectors))
+            .collect::<Result<Vec<_>, _>>());
+        result.push(Composite {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            model: model.to_owned(),
+            members: selectors,
+            states: states,
+        });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+describe! composite_device {
+    before_each {
+        use super::*;
+        use serde_json::value::Value as JSON;
+        use std::collections::HashMap;
+
+        let source = r#"[{ "id": "garage-door", "name": "Garage door",
+            "members": { "relay": { "id": "channel:relay" }, "tilt": { "id": "channel:tilt" } },
+            "states": [{ "feature": "garage-door/state", "default": "unknown",
+                         "cases": [{ "when": { "tilt": "Open" }, "value": "open" },
+                                   { "when": { "tilt": "Closed", "relay": "On" },
+                                     "value": "moving" },
+                                   { "when": { "tilt": "Closed" }, "value": "closed" }] }] }]"#;
+    }
+
+    it "should parse composite devices" {
+        let devices = parse_composites(source).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "Garage door");
+        assert_eq!(devices[0].model, "Composite");
+        assert_eq!(devices[0].members.len(), 2);
+        assert_eq!(devices[0].states[0].cases.len(), 3);
+
+        assert!(parse_composites(r#"[{ "id": "garage-door", "members": {} }]"#).is_err());
+        assert!(parse_composites(r#"[{ "id": "garage-door", "members": {},
+            "states": [{ "feature": "garage-door/state",
+                         "cases": [{ "when": { "tilt": "Open" }, "value": "open" }] }] }]"#)
+            .is_err());
+    }
+
+    it "should derive states from the values of the members" {
+        let devices = parse_composites(source).unwrap();
+        let state = &devices[0].states[0];
+        let mut values = HashMap::new();
+        assert_eq!(state.derive(&values), Some("unknown".to_owned()));
+        values.insert("tilt".to_owned(), JSON::String("Closed".to_owned()));
+        assert_eq!(state.derive(&values), Some("closed".to_owned()));
+        values.insert("relay".to_owned(), JSON::String("On".to_owned()));
+        assert_eq!(state.derive(&values), Some("moving".to_owned()));
+        values.insert("tilt".to_owned(), JSON::String("Open".to_owned()));
+        assert_eq!(state.derive(&values), Some("open".to_owned()));
+    }
+}
@@ -0,0 +1,258 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter exposing composite devices, made of the channels of other adapters, e.g. a
+//! garage door made of a Z-Wave relay and a tilt sensor.
+//!
+//! Composite devices are defined in `composites.json` in the profile (see
+//! `device::parse_composites`). Each device is exposed as a service, with a channel (fetch,
+//! watch) per state of the device, e.g. `garage-door/state`, whose `String` value is derived
+//! from the latest values of the members. The members themselves remain available through
+//! their own adapters, e.g. to open the garage door.
+
+mod device;
+
+use self::device::Composite;
+
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{API, Error, InternalError, Operation, Targetted, User, WatchEvent};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::parse::ToJSON;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::util::Exactly;
+use foxbox_taxonomy::values::{format, Value};
+
+use serde_json::value::Value as JSON;
+
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+static ADAPTER_NAME: &'static str = "Composite devices adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+struct State {
+    devices: Vec<Composite>,
+    /// The latest value of each member of each device.
+    values: Vec<HashMap<String, JSON>>,
+    /// The latest value we have derived, for each state of each device.
+    derived: Vec<Vec<Option<String>>>,
+    /// The devices and member names of each member channel.
+    members: HashMap<Id<Channel>, Vec<(usize, String)>>,
+}
+
+impl State {
+    /// Derive the states of the devices again, and report those that have changed.
+    fn refresh(&mut self, watchers: &ValueWatchers) {
+        for (index, device) in self.devices.iter().enumerate() {
+            for (state_index, state) in device.states.iter().enumerate() {
+                let value = state.derive(&self.values[index]);
+                if value == self.derived[index][state_index] {
+                    continue;
+                }
+                if let Some(ref value) = value {
+                    watchers.update(&CompositeAdapter::channel_id(&device.id, &state.feature),
+                                    Value::new(value.clone()));
+                }
+                self.derived[index][state_index] = value;
+            }
+        }
+    }
+
+    /// Find out which channels are the members of which devices.
+    fn find_members(&mut self, manager: &AdapterManager) {
+        self.members.clear();
+        for (index, device) in self.devices.iter().enumerate() {
+            for &(ref name, ref selector) in &device.members {
+                for channel in manager.get_channels(vec![selector.clone()]) {
+                    // Composing composite devices could loop forever.
+                    if channel.adapter == CompositeAdapter::id() {
+                        continue;
+                    }
+                    self.members
+                        .entry(channel.id.clone())
+                        .or_insert_with(Vec::new)
+                        .push((index, name.clone()));
+                }
+            }
+        }
+    }
+}
+
+pub struct CompositeAdapter {
+    /// The device and state of each channel.
+    channels: HashMap<Id<Channel>, (usize, usize)>,
+    state: Arc<Mutex<State>>,
+    watchers: ValueWatchers,
+}
+
+impl CompositeAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("composite@link.mozilla.org")
+    }
+
+    pub fn service_id(device: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.composite@link.mozilla.org", device))
+    }
+
+    pub fn channel_id(device: &str, feature: &str) -> Id<Channel> {
+        Id::new(&format!("channel:{}.{}.composite@link.mozilla.org",
+                         feature.replace('/', "-"),
+                         device))
+    }
+
+    pub fn init(manager: &Arc<AdapterManager>, path: &str) -> Result<(), Error> {
+        let devices = try!(device::from_file(path)
+            .map_err(|err| Error::Internal(InternalError::GenericError(err))));
+
+        let mut channels = HashMap::new();
+        for (index, device) in devices.iter().enumerate() {
+            for (state_index, state) in device.states.iter().enumerate() {
+                channels.insert(Self::channel_id(&device.id, &state.feature),
+                                (index, state_index));
+            }
+        }
+        let state = Arc::new(Mutex::new(State {
+            values: vec![HashMap::new(); devices.len()],
+            derived: devices.iter().map(|device| vec![None; device.states.len()]).collect(),
+            devices: devices,
+            members: HashMap::new(),
+        }));
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(CompositeAdapter {
+            channels: channels,
+            state: state.clone(),
+            watchers: watchers.clone(),
+        })));
+
+        let is_empty = {
+            let state = state.lock().unwrap();
+            for device in &state.devices {
+                let service_id = Self::service_id(&device.id);
+                let mut service = Service::empty(&service_id, &Self::id());
+                service.properties.insert("model".to_owned(), device.model.clone());
+                service.properties.insert("name".to_owned(), device.name.clone());
+                try!(manager.add_service(service));
+                for derived in &device.states {
+                    try!(manager.add_channel(Channel {
+                        id: Self::channel_id(&device.id, &derived.feature),
+                        service: service_id.clone(),
+                        adapter: Self::id(),
+                        feature: Id::new(&derived.feature),
+                        supports_fetch: Some(Signature::returns(Maybe::Required(format::STRING
+                            .clone()))),
+                        supports_watch: Some(Signature {
+                            accepts: Maybe::Optional(format::STRING.clone()),
+                            returns: Maybe::Required(format::STRING.clone()),
+                        }),
+                        ..Channel::default()
+                    }));
+                }
+            }
+            state.devices.is_empty()
+        };
+        if is_empty {
+            info!("[composite] No composite device defined.");
+            return Ok(());
+        }
+
+        let manager = manager.clone();
+        thread::Builder::new()
+            .name("Composite".to_owned())
+            .spawn(move || Self::follow_members(&manager, &state, &watchers))
+            .unwrap();
+        Ok(())
+    }
+
+    /// Feed the values of the members to their devices, forever.
+    fn follow_members(manager: &AdapterManager, state: &Mutex<State>, watchers: &ValueWatchers) {
+        let (tx, rx) = mpsc::channel::<WatchEvent>();
+        let selectors = state.lock()
+            .unwrap()
+            .devices
+            .iter()
+            .flat_map(|device| device.members.iter().map(|&(_, ref selector)| selector.clone()))
+            .collect();
+        // Keep the guard alive for as long as we are following members.
+        let _guard = manager.watch_values(vec![Targetted {
+                                               select: selectors,
+                                               payload: Exactly::Always,
+                                           }],
+                                          Box::new(tx),
+                                          User::None);
+        state.lock().unwrap().find_members(manager);
+        for event in rx {
+            let (channel, value) = match event {
+                WatchEvent::ChannelAdded(_) |
+                WatchEvent::ChannelRemoved(_) => {
+                    state.lock().unwrap().find_members(manager);
+                    continue;
+                }
+                WatchEvent::EnterRange { channel, value, .. } => (channel, value),
+                _ => continue,
+            };
+            let mut state = state.lock().unwrap();
+            let members = match state.members.get(&channel) {
+                Some(members) => members.clone(),
+                None => continue,
+            };
+            let value = value.to_json();
+            for (index, name) in members {
+                state.values[index].insert(name, value.clone());
+            }
+            state.refresh(watchers);
+        }
+    }
+}
+
+impl Adapter for CompositeAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        let state = self.state.lock().unwrap();
+        set.drain(..)
+            .map(|id| {
+                let value = match self.channels.get(&id) {
+                    Some(&(index, state_index)) => state.derived[index][state_index].clone(),
+                    None => {
+                        return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+                    }
+                };
+                (id, Ok(value.map(Value::new)))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, _)| (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id))))
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
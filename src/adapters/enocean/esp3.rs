@@ -0,0 +1,228 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Parsing the EnOcean Serial Protocol 3 (ESP3), as spoken by USB300 sticks, and the
+//! radio telegrams it carries.
+
+pub const SYNC_BYTE: u8 = 0x55;
+pub const PACKET_TYPE_RADIO_ERP1: u8 = 0x01;
+
+const HEADER_LEN: usize = 6; // Sync byte, data length (2), optional length, packet type, CRC.
+
+const RORG_RPS: u8 = 0xF6;
+const RORG_1BS: u8 = 0xD5;
+
+/// A single ESP3 packet, after checking its CRCs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub packet_type: u8,
+    pub data: Vec<u8>,
+    pub optional: Vec<u8>,
+}
+
+/// CRC8 as used by ESP3 (polynomial 0x07).
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for byte in bytes {
+        crc ^= *byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// An incremental parser, turning a stream of bytes into packets.
+///
+/// Garbage and corrupted packets are skipped by resynchronizing on the next sync byte.
+#[derive(Default)]
+pub struct Parser {
+    buffer: Vec<u8>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Parser { buffer: Vec::new() }
+    }
+
+    /// Feed bytes to the parser, returning all the packets that are now complete.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Packet> {
+        self.buffer.extend_from_slice(bytes);
+        let mut packets = Vec::new();
+        loop {
+            match self.buffer.iter().position(|byte| *byte == SYNC_BYTE) {
+                None => {
+                    self.buffer.clear();
+                    break;
+                }
+                Some(start) => {
+                    self.buffer.drain(..start);
+                }
+            }
+            if self.buffer.len() < HEADER_LEN {
+                break;
+            }
+            if crc8(&self.buffer[1..5]) != self.buffer[5] {
+                // Not a real header, skip this sync byte.
+                self.buffer.remove(0);
+                continue;
+            }
+            let data_len = ((self.buffer[1] as usize) << 8) | self.buffer[2] as usize;
+            let optional_len = self.buffer[3] as usize;
+            let total_len = HEADER_LEN + data_len + optional_len + 1;
+            if self.buffer.len() < total_len {
+                break;
+            }
+            let body_end = HEADER_LEN + data_len + optional_len;
+            if crc8(&self.buffer[HEADER_LEN..body_end]) != self.buffer[body_end] {
+                warn!("[enocean] Dropping a packet with an invalid CRC");
+                self.buffer.remove(0);
+                continue;
+            }
+            packets.push(Packet {
+                packet_type: self.buffer[4],
+                data: self.buffer[HEADER_LEN..HEADER_LEN + data_len].to_vec(),
+                optional: self.buffer[HEADER_LEN + data_len..body_end].to_vec(),
+            });
+            self.buffer.drain(..total_len);
+        }
+        packets
+    }
+}
+
+/// One of the two rockers of a rocker switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rocker {
+    A,
+    B,
+}
+
+/// A radio telegram from a device we know how to interpret.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Telegram {
+    /// A rocker switch (EEP F6-02) has been pressed. `on` is `true` for the "I" position,
+    /// `false` for the "O" position.
+    Rocker { sender: u32, rocker: Rocker, on: bool },
+
+    /// A contact sensor (EEP D5-00-01) has been opened or closed.
+    Contact { sender: u32, closed: bool },
+}
+
+/// Interpret an ERP1 radio packet, i.e. `[RORG, payload..., sender (4 bytes), status]`.
+///
+/// Returns `None` for packets that we do not support, as well as teach-in telegrams and
+/// rocker releases, which do not carry any state.
+pub fn parse_erp1(packet: &Packet) -> Option<Telegram> {
+    let data = &packet.data;
+    if packet.packet_type != PACKET_TYPE_RADIO_ERP1 || data.len() < 7 {
+        return None;
+    }
+    let status = data[data.len() - 1];
+    let sender_start = data.len() - 5;
+    let sender = data[sender_start..sender_start + 4]
+        .iter()
+        .fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+    match (data[0], data.len()) {
+        (RORG_RPS, 7) => {
+            // Only "N-messages" (T21 and NU set) describe a single button.
+            if status & 0x30 != 0x30 {
+                return None;
+            }
+            let payload = data[1];
+            let energy_bow = payload & 0x10 != 0;
+            if !energy_bow {
+                // The button has been released.
+                return None;
+            }
+            let (rocker, on) = match payload >> 5 {
+                0 => (Rocker::A, true),
+                1 => (Rocker::A, false),
+                2 => (Rocker::B, true),
+                _ => (Rocker::B, false),
+            };
+            Some(Telegram::Rocker {
+                sender: sender,
+                rocker: rocker,
+                on: on,
+            })
+        }
+        (RORG_1BS, 7) => {
+            let payload = data[1];
+            if payload & 0x08 == 0 {
+                // Teach-in telegram.
+                return None;
+            }
+            Some(Telegram::Contact {
+                sender: sender,
+                closed: payload & 0x01 != 0,
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+describe! esp3 {
+    before_each {
+        use super::*;
+
+        // Build a complete ESP3 packet around an ERP1 payload.
+        fn frame(data: &[u8]) -> Vec<u8> {
+            let mut header = vec![0x00, data.len() as u8, 0x00, PACKET_TYPE_RADIO_ERP1];
+            let header_crc = crc8(&header);
+            header.push(header_crc);
+            let mut result = vec![SYNC_BYTE];
+            result.extend_from_slice(&header);
+            result.extend_from_slice(data);
+            result.push(crc8(data));
+            result
+        }
+    }
+
+    it "should parse a rocker switch press" {
+        let mut parser = Parser::new();
+        let packets = parser.push(&frame(&[0xF6, 0x30, 0x00, 0x25, 0x84, 0x1F, 0x30]));
+        assert_eq!(packets.len(), 1);
+        assert_eq!(parse_erp1(&packets[0]),
+                   Some(Telegram::Rocker { sender: 0x0025841F, rocker: Rocker::A, on: false }));
+    }
+
+    it "should ignore rocker releases" {
+        let mut parser = Parser::new();
+        let packets = parser.push(&frame(&[0xF6, 0x00, 0x00, 0x25, 0x84, 0x1F, 0x20]));
+        assert_eq!(packets.len(), 1);
+        assert_eq!(parse_erp1(&packets[0]), None);
+    }
+
+    it "should parse a contact sensor" {
+        let mut parser = Parser::new();
+        let packets = parser.push(&frame(&[0xD5, 0x09, 0x01, 0x82, 0x3A, 0x7B, 0x00]));
+        assert_eq!(parse_erp1(&packets[0]),
+                   Some(Telegram::Contact { sender: 0x01823A7B, closed: true }));
+    }
+
+    it "should handle packets split across reads and garbage" {
+        let mut parser = Parser::new();
+        let bytes = frame(&[0xD5, 0x08, 0x01, 0x82, 0x3A, 0x7B, 0x00]);
+        let mut first = vec![0x12, 0x55, 0x42];
+        first.extend_from_slice(&bytes[..4]);
+        assert!(parser.push(&first).is_empty());
+        let packets = parser.push(&bytes[4..]);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(parse_erp1(&packets[0]),
+                   Some(Telegram::Contact { sender: 0x01823A7B, closed: false }));
+    }
+
+    it "should drop packets with a bad CRC" {
+        let mut parser = Parser::new();
+        let mut bytes = frame(&[0xD5, 0x09, 0x01, 0x82, 0x3A, 0x7B, 0x00]);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        assert!(parser.push(&bytes).is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter for EnOcean devices, through a USB300 (or compatible) stick.
+//!
+//! EnOcean devices are battery-less: they only ever talk when something happens (a button
+//! is pressed, a window is opened), so all their channels are watchable and fetching returns
+//! the last value received. Devices are added as services the first time we hear from them.
+//!
+//! Supported equipment profiles:
+//! - F6-02 rocker switches, exposed as two `switch/is-on` channels (one per rocker);
+//! - D5-00-01 contact sensors, exposed as a `door/is-open` channel.
+
+mod esp3;
+
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, OnOff, OpenClosed, Value};
+
+use self::esp3::{Parser, Rocker, Telegram};
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+
+static ADAPTER_NAME: &'static str = "EnOcean adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+// The baud rate of the ESP3 serial line.
+static BAUD_RATE: &'static str = "57600";
+
+pub struct EnOceanAdapter {
+    watchers: ValueWatchers,
+}
+
+impl EnOceanAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("enocean@link.mozilla.org")
+    }
+
+    pub fn service_id(sender: u32) -> Id<ServiceId> {
+        Id::new(&format!("service:{:08x}.enocean@link.mozilla.org", sender))
+    }
+
+    pub fn rocker_id(sender: u32, rocker: Rocker) -> Id<Channel> {
+        let rocker = match rocker {
+            Rocker::A => "a",
+            Rocker::B => "b",
+        };
+        Id::new(&format!("getter:rocker-{}.{:08x}.enocean@link.mozilla.org", rocker, sender))
+    }
+
+    pub fn contact_id(sender: u32) -> Id<Channel> {
+        Id::new(&format!("getter:contact.{:08x}.enocean@link.mozilla.org", sender))
+    }
+
+    /// Start listening to the EnOcean stick plugged at `device` (e.g. "/dev/ttyUSB0").
+    pub fn init(manager: &Arc<AdapterManager>, device: &str) -> Result<(), Error> {
+        // Configure the serial line. This is best-effort, as the device may already be set up.
+        match Command::new("stty").args(&["-F", device, BAUD_RATE, "raw", "-echo"]).status() {
+            Ok(ref status) if status.success() => {}
+            _ => warn!("[enocean] Could not configure serial device {}", device),
+        }
+        let mut file = try!(File::open(device).map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("Cannot open EnOcean device {}: {}",
+                                                                device,
+                                                                err)))
+        }));
+
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(EnOceanAdapter { watchers: watchers.clone() })));
+
+        let manager = manager.clone();
+        thread::spawn(move || {
+            info!("[enocean] Listening to EnOcean telegrams.");
+            let mut parser = Parser::new();
+            let mut known = HashSet::new();
+            let mut buffer = [0; 256];
+            loop {
+                let len = match file.read(&mut buffer) {
+                    Ok(0) => {
+                        warn!("[enocean] The EnOcean device has been disconnected.");
+                        break;
+                    }
+                    Ok(len) => len,
+                    Err(err) => {
+                        error!("[enocean] Error while reading from the EnOcean device: {}", err);
+                        break;
+                    }
+                };
+                for packet in parser.push(&buffer[..len]) {
+                    let telegram = match esp3::parse_erp1(&packet) {
+                        Some(telegram) => telegram,
+                        None => continue,
+                    };
+                    debug!("[enocean] Received {:?}", telegram);
+                    match telegram {
+                        Telegram::Rocker { sender, rocker, on } => {
+                            if known.insert(sender) {
+                                Self::add_rocker_switch(&manager, sender);
+                            }
+                            watchers.update(&Self::rocker_id(sender, rocker),
+                                            Value::new(if on { OnOff::On } else { OnOff::Off }));
+                        }
+                        Telegram::Contact { sender, closed } => {
+                            if known.insert(sender) {
+                                Self::add_contact_sensor(&manager, sender);
+                            }
+                            watchers.update(&Self::contact_id(sender),
+                                            Value::new(if closed {
+                                                OpenClosed::Closed
+                                            } else {
+                                                OpenClosed::Open
+                                            }));
+                        }
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn add_service(manager: &AdapterManager, sender: u32, model: &str) {
+        let mut service = Service::empty(&Self::service_id(sender), &Self::id());
+        service.properties.insert("model".to_owned(), model.to_owned());
+        service.properties.insert("enocean_id".to_owned(), format!("{:08x}", sender));
+        manager.add_service(service).unwrap_or_else(|err| {
+            error!("[enocean] Could not add service for device {:08x}: {}", sender, err);
+        });
+    }
+
+    fn add_rocker_switch(manager: &AdapterManager, sender: u32) {
+        Self::add_service(manager, sender, "EnOcean rocker switch (F6-02)");
+        for rocker in &[Rocker::A, Rocker::B] {
+            manager.add_channel(Channel {
+                    feature: Id::new("switch/is-on"),
+                    supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+                    supports_watch: Some(Signature {
+                        accepts: Maybe::Optional(format::ON_OFF.clone()),
+                        returns: Maybe::Required(format::ON_OFF.clone()),
+                    }),
+                    id: Self::rocker_id(sender, *rocker),
+                    service: Self::service_id(sender),
+                    adapter: Self::id(),
+                    ..Channel::default()
+                })
+                .unwrap_or_else(|err| {
+                    error!("[enocean] Could not add rocker for device {:08x}: {}", sender, err);
+                });
+        }
+    }
+
+    fn add_contact_sensor(manager: &AdapterManager, sender: u32) {
+        Self::add_service(manager, sender, "EnOcean contact sensor (D5-00-01)");
+        manager.add_channel(Channel {
+                id: Self::contact_id(sender),
+                service: Self::service_id(sender),
+                adapter: Self::id(),
+                // We can't open or close the door.
+                supports_send: None,
+                ..DOOR_IS_OPEN.clone()
+            })
+            .unwrap_or_else(|err| {
+                error!("[enocean] Could not add contact for device {:08x}: {}", sender, err);
+            });
+    }
+}
+
+impl Adapter for EnOceanAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                let value = self.watchers.latest(&id);
+                (id, Ok(value))
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Parsing of the event stream of the ESPHome web server.
+//!
+//! Upon connection to `/events`, the device sends a `state` event for each of its components,
+//! then another one whenever the state of a component changes. Events are server-sent events,
+//! whose data is a JSON object such as `{"id": "sensor-living_room", "value": 21.5,
+//! "state": "21.5 °C"}`.
+
+use serde_json;
+use serde_json::value::Value as JSON;
+
+/// The kinds of components we expose.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Domain {
+    Sensor,
+    BinarySensor,
+    Switch,
+    Light,
+}
+
+impl Domain {
+    fn parse(source: &str) -> Option<Domain> {
+        match source {
+            "sensor" => Some(Domain::Sensor),
+            "binary_sensor" => Some(Domain::BinarySensor),
+            "switch" => Some(Domain::Switch),
+            "light" => Some(Domain::Light),
+            _ => None,
+        }
+    }
+
+    /// The prefix of urls for this domain, in the REST API of the web server.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Domain::Sensor => "sensor",
+            Domain::BinarySensor => "binary_sensor",
+            Domain::Switch => "switch",
+            Domain::Light => "light",
+        }
+    }
+
+    pub fn is_writable(&self) -> bool {
+        *self == Domain::Switch || *self == Domain::Light
+    }
+}
+
+/// The state of a component.
+#[derive(Clone, Debug, PartialEq)]
+pub struct State {
+    pub domain: Domain,
+    /// The object id of the component, e.g. "living_room".
+    pub object_id: String,
+    /// For sensors, the numeric value.
+    pub value: Option<f64>,
+    /// The state, e.g. "21.5 °C" or "ON".
+    pub state: String,
+}
+
+impl State {
+    /// Parse the data of a `state` event. Returns `None` for components we do not expose.
+    pub fn parse(data: &str) -> Option<State> {
+        let json: JSON = match serde_json::from_str(data) {
+            Ok(json) => json,
+            Err(_) => return None,
+        };
+        let id = match json.find("id").and_then(JSON::as_string) {
+            Some(id) => id,
+            None => return None,
+        };
+        let mut split = id.splitn(2, '-');
+        let domain = match split.next().and_then(Domain::parse) {
+            Some(domain) => domain,
+            None => return None,
+        };
+        let object_id = match split.next() {
+            Some(object_id) if !object_id.is_empty() => object_id.to_owned(),
+            _ => return None,
+        };
+        Some(State {
+            domain: domain,
+            object_id: object_id,
+            value: json.find("value").and_then(JSON::as_f64),
+            state: json.find("state").and_then(JSON::as_string).unwrap_or("").to_owned(),
+        })
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.state == "ON"
+    }
+}
+
+/// An incremental parser for server-sent events.
+#[derive(Default)]
+pub struct EventParser {
+    event: String,
+    data: String,
+}
+
+impl EventParser {
+    pub fn new() -> Self {
+        EventParser::default()
+    }
+
+    /// Feed one line of the stream, without its line terminator. Returns `(event, data)`
+    /// once an event is complete.
+    pub fn feed(&mut self, line: &str) -> Option<(String, String)> {
+        if line.is_empty() {
+            if self.data.is_empty() {
+                self.event.clear();
+                return None;
+            }
+            let event = if self.event.is_empty() {
+                "message".to_owned()
+            } else {
+                self.event.clone()
+            };
+            let data = self.data.clone();
+            self.event.clear();
+            self.data.clear();
+            return Some((event, data));
+        }
+        if line.starts_with(':') {
+            // A comment, e.g. a keep-alive.
+            return None;
+        }
+        let mut split = line.splitn(2, ':');
+        let field = split.next().unwrap_or("");
+        let value = split.next().unwrap_or("");
+        let value = if value.starts_with(' ') { &value[1..] } else { value };
+        match field {
+            "event" => self.event = value.to_owned(),
+            "data" => {
+                if !self.data.is_empty() {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value)
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+describe! esphome_events {
+    before_each {
+        use super::*;
+    }
+
+    it "should parse server-sent events" {
+        let mut parser = EventParser::new();
+        assert_eq!(parser.feed(": keep-alive"), None);
+        assert_eq!(parser.feed("event: state"), None);
+        assert_eq!(parser.feed(r#"data: {"id": "switch-relay", "state": "ON"}"#), None);
+        assert_eq!(parser.feed(""),
+                   Some(("state".to_owned(),
+                         r#"{"id": "switch-relay", "state": "ON"}"#.to_owned())));
+        assert_eq!(parser.feed(""), None);
+        assert_eq!(parser.feed("data: ping"), None);
+        assert_eq!(parser.feed(""), Some(("message".to_owned(), "ping".to_owned())));
+    }
+
+    it "should parse component states" {
+        let data = r#"{"id": "sensor-living_room", "value": 21.5, "state": "21.5 °C"}"#;
+        let state = State::parse(data).unwrap();
+        assert_eq!(state.domain, Domain::Sensor);
+        assert_eq!(state.object_id, "living_room");
+        assert_eq!(state.value, Some(21.5));
+
+        let state = State::parse(r#"{"id": "binary_sensor-door", "state": "ON"}"#).unwrap();
+        assert_eq!(state.domain, Domain::BinarySensor);
+        assert!(state.is_on());
+
+        assert_eq!(State::parse(r#"{"id": "fan-ceiling", "state": "ON"}"#), None);
+        assert_eq!(State::parse("not json"), None);
+    }
+}
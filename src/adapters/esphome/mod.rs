@@ -0,0 +1,350 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter for ESPHome devices (ESP8266/ESP32), through the ESPHome web server.
+//!
+//! Devices are discovered through the `_esphomelib._tcp` mDNS service type, or configured
+//! statically as a comma separated list of `host[:port]` (`esphome.devices`). The web server
+//! of the devices must be enabled, on port `esphome.web_port` (80 by default) for discovered
+//! devices.
+//!
+//! Each device is exposed as a service. Its components are exposed as channels as soon as the
+//! device reports their state:
+//! - `sensor`: `sensor/value` (fetch, watch), as JSON `{"value": number, "state": string}`;
+//! - `binary_sensor`: `binary-sensor/is-on` (fetch, watch);
+//! - `switch`: `switch/is-on` (fetch, watch, send);
+//! - `light`: `light/is-on` (fetch, watch, send).
+
+mod events;
+
+use self::events::{Domain, EventParser, State};
+
+use adapters::avahi;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, OnOff, Value};
+
+use hyper;
+use hyper::header::Connection;
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static ADAPTER_NAME: &'static str = "ESPHome adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const DISCOVERY_INTERVAL_S: u64 = 300;
+const RECONNECT_DELAY_S: u64 = 10;
+
+/// The base url of the device, the domain and the object id of the component, for each
+/// channel.
+type Channels = Arc<Mutex<HashMap<Id<Channel>, (String, Domain, String)>>>;
+
+pub struct EsphomeAdapter {
+    channels: Channels,
+    watchers: ValueWatchers,
+}
+
+/// A device name, made suitable for use in an id.
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Parse a comma separated list of `host[:port]` into base urls.
+fn parse_devices(source: &str) -> Vec<(String, String)> {
+    source.split(',')
+        .map(|device| device.trim())
+        .filter(|device| !device.is_empty())
+        .map(|device| {
+            let host = device.split(':').next().unwrap_or(device);
+            (host.to_owned(), format!("http://{}", device))
+        })
+        .collect()
+}
+
+fn feature(domain: Domain) -> &'static str {
+    match domain {
+        Domain::Sensor => "sensor/value",
+        Domain::BinarySensor => "binary-sensor/is-on",
+        Domain::Switch => "switch/is-on",
+        Domain::Light => "light/is-on",
+    }
+}
+
+fn value(state: &State) -> Value {
+    match state.domain {
+        Domain::Sensor => {
+            let mut reading = BTreeMap::new();
+            if let Some(value) = state.value {
+                reading.insert("value".to_owned(), JSON::F64(value));
+            }
+            reading.insert("state".to_owned(), JSON::String(state.state.clone()));
+            Value::new(Json(JSON::Object(reading)))
+        }
+        _ => Value::new(if state.is_on() { OnOff::On } else { OnOff::Off }),
+    }
+}
+
+impl EsphomeAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("esphome@link.mozilla.org")
+    }
+
+    pub fn service_id(device: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.esphome@link.mozilla.org", device))
+    }
+
+    pub fn channel_id(device: &str, domain: Domain, object_id: &str) -> Id<Channel> {
+        Id::new(&format!("channel:{}-{}.{}.esphome@link.mozilla.org",
+                         domain.as_str(),
+                         object_id,
+                         device))
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let devices = config.get("esphome", "devices").unwrap_or_else(String::new);
+        let devices = parse_devices(&devices);
+        let web_port = config.get_or_set_default("esphome", "web_port", "80");
+
+        let channels: Channels = Arc::new(Mutex::new(HashMap::new()));
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(EsphomeAdapter {
+            channels: channels.clone(),
+            watchers: watchers.clone(),
+        })));
+
+        let manager = manager.clone();
+        thread::spawn(move || {
+            let mut known = HashSet::new();
+            for (name, url) in devices {
+                Self::add_device(&manager, &channels, &watchers, &mut known, &name, url);
+            }
+            loop {
+                for device in avahi::browse("_esphomelib._tcp") {
+                    let url = format!("http://{}:{}", device.address, web_port);
+                    Self::add_device(&manager, &channels, &watchers, &mut known, &device.name, url);
+                }
+                thread::sleep(Duration::from_secs(DISCOVERY_INTERVAL_S));
+            }
+        });
+        Ok(())
+    }
+
+    fn add_device(manager: &Arc<AdapterManager>,
+                  channels: &Channels,
+                  watchers: &ValueWatchers,
+                  known: &mut HashSet<String>,
+                  name: &str,
+                  url: String) {
+        let device = slug(name);
+        if !known.insert(device.clone()) {
+            return;
+        }
+        let mut service = Service::empty(&Self::service_id(&device), &Self::id());
+        service.properties.insert("model".to_owned(), "ESPHome device".to_owned());
+        service.properties.insert("name".to_owned(), name.to_owned());
+        if let Err(err) = manager.add_service(service) {
+            error!("[esphome] Could not add device {}: {:?}", device, err);
+            return;
+        }
+        info!("[esphome] Found device {} at {}", name, url);
+
+        let manager = manager.clone();
+        let channels = channels.clone();
+        let watchers = watchers.clone();
+        thread::spawn(move || {
+            loop {
+                if let Err(err) = Self::listen(&manager, &channels, &watchers, &device, &url) {
+                    warn!("[esphome] Lost connection to {}: {}", device, err);
+                }
+                thread::sleep(Duration::from_secs(RECONNECT_DELAY_S));
+            }
+        });
+    }
+
+    /// Follow the event stream of a device, until the connection is lost.
+    fn listen(manager: &AdapterManager,
+              channels: &Channels,
+              watchers: &ValueWatchers,
+              device: &str,
+              url: &str)
+              -> Result<(), String> {
+        let client = hyper::Client::new();
+        let response = try!(client.get(&format!("{}/events", url))
+            .send()
+            .map_err(|err| format!("{}", err)));
+        if !response.status.is_success() {
+            return Err(format!("/events returned {}", response.status));
+        }
+        let mut parser = EventParser::new();
+        for line in BufReader::new(response).lines() {
+            let line = try!(line.map_err(|err| format!("{}", err)));
+            let state = match parser.feed(line.trim_right_matches('\r')) {
+                Some((ref event, ref data)) if event == "state" => State::parse(data),
+                _ => None,
+            };
+            if let Some(state) = state {
+                let id = Self::channel_id(device, state.domain, &state.object_id);
+                let is_new = !channels.lock().unwrap().contains_key(&id);
+                if is_new {
+                    try!(Self::add_channel(manager, device, &id, &state)
+                        .map_err(|err| format!("{:?}", err)));
+                    let channel = (url.to_owned(), state.domain, state.object_id.clone());
+                    channels.lock().unwrap().insert(id.clone(), channel);
+                }
+                watchers.update(&id, value(&state));
+            }
+        }
+        Err("End of stream".to_owned())
+    }
+
+    fn add_channel(manager: &AdapterManager,
+                   device: &str,
+                   id: &Id<Channel>,
+                   state: &State)
+                   -> Result<(), Error> {
+        let format = match state.domain {
+            Domain::Sensor => format::JSON.clone(),
+            _ => format::ON_OFF.clone(),
+        };
+        manager.add_channel(Channel {
+            id: id.clone(),
+            service: Self::service_id(device),
+            adapter: Self::id(),
+            feature: Id::new(feature(state.domain)),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format.clone()))),
+            supports_watch: Some(Signature::returns(Maybe::Required(format.clone()))),
+            supports_send: if state.domain.is_writable() {
+                Some(Signature::accepts(Maybe::Required(format)))
+            } else {
+                None
+            },
+            ..Channel::default()
+        })
+    }
+}
+
+impl Adapter for EsphomeAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        let channels = self.channels.lock().unwrap();
+        set.drain(..)
+            .map(|id| {
+                if !channels.contains_key(&id) {
+                    return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))));
+                }
+                let value = self.watchers.latest(&id);
+                (id, Ok(value))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                let channel = self.channels.lock().unwrap().get(&id).cloned();
+                let (url, domain, object_id) = match channel {
+                    Some((ref url, domain, ref object_id)) if domain.is_writable() => {
+                        (url.clone(), domain, object_id.clone())
+                    }
+                    Some(_) => {
+                        return (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id)))
+                    }
+                    None => {
+                        return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+                    }
+                };
+                let action = match value.cast::<OnOff>() {
+                    Ok(&OnOff::On) => "turn_on",
+                    Ok(&OnOff::Off) => "turn_off",
+                    Err(err) => return (id, Err(err)),
+                };
+                let client = hyper::Client::new();
+                let result = client.post(&format!("{}/{}/{}/{}",
+                                  url,
+                                  domain.as_str(),
+                                  object_id,
+                                  action))
+                    .header(Connection::close())
+                    .send();
+                let result = match result {
+                    Ok(ref response) if response.status.is_success() => Ok(()),
+                    Ok(response) => {
+                        Err(Error::Internal(InternalError::GenericError(format!("{} returned {}",
+                                                                                 id,
+                                                                                 response.status))))
+                    }
+                    Err(err) => {
+                        Err(Error::Internal(InternalError::GenericError(format!("{}", err))))
+                    }
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
+
+#[cfg(test)]
+describe! esphome {
+    before_each {
+        use super::{parse_devices, value};
+        use super::events::State;
+        use foxbox_taxonomy::values::{Json, OnOff};
+    }
+
+    it "should parse the configured devices" {
+        assert_eq!(parse_devices("esp-kitchen.local, 192.168.1.40:8080,"),
+                   vec![("esp-kitchen.local".to_owned(), "http://esp-kitchen.local".to_owned()),
+                        ("192.168.1.40".to_owned(), "http://192.168.1.40:8080".to_owned())]);
+    }
+
+    it "should convert states to values" {
+        let state = State::parse(r#"{"id": "switch-relay", "state": "OFF"}"#).unwrap();
+        assert_eq!(value(&state).cast::<OnOff>().unwrap(), &OnOff::Off);
+
+        let state = State::parse(r#"{"id": "sensor-temp", "value": 21.5, "state": "21.5 °C"}"#)
+            .unwrap();
+        let value = value(&state);
+        let json = &value.cast::<Json>().unwrap().0;
+        assert_eq!(json.find("value").and_then(|value| value.as_f64()), Some(21.5));
+    }
+}
@@ -0,0 +1,165 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter exposing the health of the other adapters, as checked by the watchdog of the
+//! `AdapterManager`, see `Adapter::heartbeat`.
+//!
+//! The `adapter-health` service exposes `adapter/health` (fetch/watch): the health of each
+//! adapter checked so far, as a JSON object `{<adapter id>: {"health": "healthy"}}` or
+//! `{<adapter id>: {"health": "unhealthy", "reason": string, "failures": number,
+//!   "since": number}}`.
+//!
+//! If `watchdog.restart` is "true", adapters that fail `watchdog.restart_after` heartbeats in
+//! a row (3 by default) are started again, see `adapters::AdapterManager::start`.
+
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::health::AdapterHealth;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::parse::ToJSON;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, Value};
+
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+static ADAPTER_NAME: &'static str = "Adapter health adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+pub struct HealthAdapter {
+    manager: Arc<AdapterManager>,
+    watchers: ValueWatchers,
+}
+
+fn health_value(health: &HashMap<Id<AdapterId>, AdapterHealth>) -> Value {
+    let map: BTreeMap<_, _> = health.iter()
+        .map(|(id, health)| (id.to_string(), health.to_json()))
+        .collect();
+    Value::new(Json(JSON::Object(map)))
+}
+
+impl HealthAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("adapter-health@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:adapter-health@link.mozilla.org")
+    }
+
+    pub fn channel_health_id() -> Id<Channel> {
+        Id::new("channel:health.adapter-health@link.mozilla.org")
+    }
+
+    pub fn init(manager: &Arc<AdapterManager>) -> Result<(), Error> {
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(HealthAdapter {
+            manager: manager.clone(),
+            watchers: watchers.clone(),
+        })));
+        try!(manager.add_service(Service::empty(&Self::service_id(), &Self::id())));
+        try!(manager.add_channel(Channel {
+            id: Self::channel_health_id(),
+            service: Self::service_id(),
+            adapter: Self::id(),
+            feature: Id::new("adapter/health"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            supports_watch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            ..Channel::default()
+        }));
+
+        let (tx, rx) = mpsc::channel();
+        manager.watch_adapter_health(Box::new(tx));
+        watchers.update(&Self::channel_health_id(),
+                        health_value(&manager.adapter_health()));
+
+        let manager = manager.clone();
+        thread::Builder::new()
+            .name("Adapter health".to_owned())
+            .spawn(move || {
+                for _ in rx {
+                    watchers.update(&Self::channel_health_id(),
+                                    health_value(&manager.adapter_health()));
+                }
+            })
+            .unwrap();
+        Ok(())
+    }
+}
+
+impl Adapter for HealthAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                if id == Self::channel_health_id() {
+                    return (id, Ok(Some(health_value(&self.manager.adapter_health()))));
+                }
+                (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, _)| (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id))))
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
+
+#[cfg(test)]
+describe! health_adapter {
+    before_each {
+        use super::*;
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        HealthAdapter::init(&harness.manager).unwrap();
+    }
+
+    it "should tell the health of the adapters" {
+        harness.manager.check_adapter_health();
+
+        let body = r#"[{"feature": "adapter/health"}]"#;
+        let (status, json) = harness.request_json(Method::Put, "/api/v1/channels/get", body, false);
+        assert_eq!(status, Status::Ok);
+        let health = json.find("channel:health.adapter-health@link.mozilla.org")
+            .and_then(|health| health.find("adapter-health@link.mozilla.org"))
+            .and_then(|health| health.find("health"))
+            .and_then(|health| health.as_string());
+        assert_eq!(health, Some("healthy"));
+    }
+}
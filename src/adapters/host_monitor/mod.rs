@@ -0,0 +1,186 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter monitoring the box itself.
+//!
+//! The `host-monitor` service exposes, as fetch/watch channels:
+//! - `system/cpu-load`, `system/memory-used` and `system/disk-free` (of the filesystem
+//!   holding the profile), as `Percent`. These can be watched with a `Range<Percent>`
+//!   threshold, e.g. `{"Leq": 10}` to be warned before the SD card fills up;
+//! - `system/soc-temperature`, as JSON `{"C": float}`.
+//!
+//! Values are sampled every `host_monitor.poll_interval` seconds.
+
+mod probes;
+
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, Percent, Value};
+
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+static ADAPTER_NAME: &'static str = "Host monitor adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+pub struct HostMonitor {
+    watchers: ValueWatchers,
+}
+
+fn temperature_value(celsius: f64) -> Value {
+    let mut object = BTreeMap::new();
+    object.insert("C".to_owned(), JSON::F64(celsius));
+    Value::new(Json(JSON::Object(object)))
+}
+
+impl HostMonitor {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("host-monitor@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:host-monitor@link.mozilla.org")
+    }
+
+    pub fn getter_cpu_load_id() -> Id<Channel> {
+        Id::new("getter:cpu-load.host-monitor@link.mozilla.org")
+    }
+
+    pub fn getter_memory_used_id() -> Id<Channel> {
+        Id::new("getter:memory-used.host-monitor@link.mozilla.org")
+    }
+
+    pub fn getter_disk_free_id() -> Id<Channel> {
+        Id::new("getter:disk-free.host-monitor@link.mozilla.org")
+    }
+
+    pub fn getter_soc_temperature_id() -> Id<Channel> {
+        Id::new("getter:soc-temperature.host-monitor@link.mozilla.org")
+    }
+
+    pub fn init(manager: &Arc<AdapterManager>,
+                disk_path: &str,
+                poll_interval: Duration)
+                -> Result<(), Error> {
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(HostMonitor { watchers: watchers.clone() })));
+
+        let mut service = Service::empty(&Self::service_id(), &Self::id());
+        service.properties.insert("model".to_owned(), "Host monitor".to_owned());
+        try!(manager.add_service(service));
+
+        for &(ref id, feature) in &[(Self::getter_cpu_load_id(), "system/cpu-load"),
+                                    (Self::getter_memory_used_id(), "system/memory-used"),
+                                    (Self::getter_disk_free_id(), "system/disk-free")] {
+            try!(manager.add_channel(Channel {
+                id: id.clone(),
+                service: Self::service_id(),
+                adapter: Self::id(),
+                feature: Id::new(feature),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::PERCENT.clone()))),
+                supports_watch: Some(Signature {
+                    accepts: Maybe::Optional(format::PERCENT_RANGE.clone()),
+                    returns: Maybe::Required(format::PERCENT.clone()),
+                }),
+                ..Channel::default()
+            }));
+        }
+        try!(manager.add_channel(Channel {
+            id: Self::getter_soc_temperature_id(),
+            service: Self::service_id(),
+            adapter: Self::id(),
+            feature: Id::new("system/soc-temperature"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            supports_watch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            ..Channel::default()
+        }));
+
+        let disk_path = disk_path.to_owned();
+        thread::spawn(move || {
+            let mut previous_cpu = probes::cpu_times();
+            loop {
+                thread::sleep(poll_interval);
+                let cpu = probes::cpu_times();
+                if let (Some(earlier), Some(now)) = (previous_cpu, cpu) {
+                    watchers.update(&Self::getter_cpu_load_id(),
+                                    Value::new(Percent::new(now.load_since(&earlier))));
+                }
+                previous_cpu = cpu;
+                if let Some(used) = probes::memory_used() {
+                    watchers.update(&Self::getter_memory_used_id(),
+                                    Value::new(Percent::new(used)));
+                }
+                if let Some(free) = probes::disk_free(&disk_path) {
+                    watchers.update(&Self::getter_disk_free_id(), Value::new(Percent::new(free)));
+                }
+                if let Some(celsius) = probes::soc_temperature() {
+                    watchers.update(&Self::getter_soc_temperature_id(),
+                                    temperature_value(celsius));
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+impl Adapter for HostMonitor {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                let known = id == Self::getter_cpu_load_id() ||
+                            id == Self::getter_memory_used_id() ||
+                            id == Self::getter_disk_free_id() ||
+                            id == Self::getter_soc_temperature_id();
+                if !known {
+                    return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))));
+                }
+                // Values are sampled by the polling thread, so that the cpu load is measured
+                // over a meaningful period.
+                let value = self.watchers.latest(&id);
+                (id, Ok(value))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, _)| (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id))))
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
@@ -0,0 +1,134 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reading the state of the box from `/proc`, `/sys` and `statvfs`.
+
+use libc;
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+
+/// Cumulated CPU time, as found in the first line of `/proc/stat`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuTimes {
+    pub idle: u64,
+    pub total: u64,
+}
+
+impl CpuTimes {
+    /// The percentage of time spent doing something else than idling since `earlier`.
+    pub fn load_since(&self, earlier: &CpuTimes) -> u8 {
+        let total = self.total.saturating_sub(earlier.total);
+        if total == 0 {
+            return 0;
+        }
+        let idle = self.idle.saturating_sub(earlier.idle);
+        (100 - idle * 100 / total) as u8
+    }
+}
+
+fn read_file(path: &str) -> Option<String> {
+    let mut content = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut content)) {
+        Ok(_) => Some(content),
+        Err(_) => None,
+    }
+}
+
+pub fn parse_stat(source: &str) -> Option<CpuTimes> {
+    let line = match source.lines().next() {
+        Some(line) if line.starts_with("cpu ") => line,
+        _ => return None,
+    };
+    let fields: Vec<u64> = line.split_whitespace()
+        .skip(1)
+        .filter_map(|field| field.parse().ok())
+        .collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    Some(CpuTimes {
+        // idle + iowait
+        idle: fields[3] + fields.get(4).cloned().unwrap_or(0),
+        total: fields.iter().fold(0, |sum, field| sum + field),
+    })
+}
+
+pub fn cpu_times() -> Option<CpuTimes> {
+    read_file("/proc/stat").and_then(|source| parse_stat(&source))
+}
+
+/// The percentage of memory in use, not counting caches that can be reclaimed.
+pub fn parse_meminfo(source: &str) -> Option<u8> {
+    let field = |name: &str| -> Option<u64> {
+        source.lines()
+            .find(|line| line.starts_with(name))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|value| value.parse().ok())
+    };
+    match (field("MemTotal:"), field("MemAvailable:")) {
+        (Some(total), Some(available)) if total > 0 && available <= total => {
+            Some(((total - available) * 100 / total) as u8)
+        }
+        _ => None,
+    }
+}
+
+pub fn memory_used() -> Option<u8> {
+    read_file("/proc/meminfo").and_then(|source| parse_meminfo(&source))
+}
+
+/// The percentage of free space on the filesystem holding `path`, as available to
+/// unprivileged users.
+pub fn disk_free(path: &str) -> Option<u8> {
+    let path = match CString::new(path) {
+        Ok(path) => path,
+        Err(_) => return None,
+    };
+    let mut stats: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 || stats.f_blocks == 0 {
+        return None;
+    }
+    Some((stats.f_bavail as u64 * 100 / stats.f_blocks as u64) as u8)
+}
+
+/// The temperature of the SoC, in degrees Celsius.
+pub fn parse_thermal_zone(source: &str) -> Option<f64> {
+    source.trim().parse::<i64>().ok().map(|millis| millis as f64 / 1000.)
+}
+
+pub fn soc_temperature() -> Option<f64> {
+    read_file("/sys/class/thermal/thermal_zone0/temp")
+        .and_then(|source| parse_thermal_zone(&source))
+}
+
+#[cfg(test)]
+describe! host_monitor_probes {
+    before_each {
+        use super::*;
+    }
+
+    it "should compute the cpu load from /proc/stat" {
+        let earlier = parse_stat("cpu  100 0 100 700 100 0 0 0 0 0\ncpu0 1 2 3 4").unwrap();
+        assert_eq!(earlier, CpuTimes { idle: 800, total: 1000 });
+        let later = parse_stat("cpu  250 0 250 850 150 0 0 0 0 0\n").unwrap();
+        assert_eq!(later.load_since(&earlier), 60);
+        assert_eq!(later.load_since(&later), 0);
+        assert_eq!(parse_stat("intr 1 2 3"), None);
+    }
+
+    it "should compute the memory usage from /proc/meminfo" {
+        let source = "MemTotal:        1000000 kB\nMemFree:          100000 kB\n\
+                      MemAvailable:     250000 kB\n";
+        assert_eq!(parse_meminfo(source), Some(75));
+        assert_eq!(parse_meminfo("MemTotal: 1000 kB\n"), None);
+    }
+
+    it "should read the SoC temperature" {
+        assert_eq!(parse_thermal_zone("48312\n"), Some(48.312));
+        assert_eq!(parse_thermal_zone(""), None);
+    }
+}
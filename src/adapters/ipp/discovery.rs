@@ -0,0 +1,52 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Discovery of IPP printers, through the `_ipp._tcp` mDNS service type.
+
+use adapters::avahi::{self, Resolved};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Printer {
+    /// The mDNS instance name, e.g. "HP OfficeJet 3830".
+    pub name: String,
+    /// The uri to use for IPP requests, e.g. "ipp://192.168.1.12:631/ipp/print".
+    pub uri: String,
+    /// The `ty` TXT record, if present.
+    pub model: Option<String>,
+}
+
+fn printer(resolved: &Resolved) -> Printer {
+    Printer {
+        name: resolved.name.clone(),
+        uri: format!("ipp://{}:{}/{}",
+                     resolved.address,
+                     resolved.port,
+                     resolved.txt("rp").unwrap_or("ipp/print")),
+        model: resolved.txt("ty").map(|model| model.to_owned()),
+    }
+}
+
+/// Run a single discovery round.
+pub fn discover() -> Vec<Printer> {
+    avahi::browse("_ipp._tcp").iter().map(printer).collect()
+}
+
+#[cfg(test)]
+describe! ipp_discovery {
+    before_each {
+        use super::*;
+        use adapters::avahi::parse_line;
+    }
+
+    it "should build the printer uri" {
+        let line = "=;wlan0;IPv4;HP\\032OfficeJet\\0323830;Internet Printer;local;hp.local;\
+                    192.168.1.12;631;\"txtvers=1\" \"rp=ipp/print\" \"ty=HP OfficeJet 3830\"";
+        assert_eq!(printer(&parse_line(line).unwrap()),
+                   Printer {
+                       name: "HP OfficeJet 3830".to_owned(),
+                       uri: "ipp://192.168.1.12:631/ipp/print".to_owned(),
+                       model: Some("HP OfficeJet 3830".to_owned()),
+                   });
+    }
+}
@@ -0,0 +1,319 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter for network printers speaking IPP.
+//!
+//! Printers are discovered through mDNS. Each printer is exposed as a service with:
+//! - `printer/ink-levels` (fetch, watch): a JSON object mapping each marker to its level in
+//!   percent, or -1 if unknown;
+//! - `printer/paper-status` (fetch, watch): one of "ok", "low", "empty" or "jammed";
+//! - `printer/job-count` (fetch, watch): the number of queued jobs, as a JSON number;
+//! - `printer/print` (send): prints a `Binary` document, provided that the printer
+//!   supports its mime type.
+//!
+//! Printers are polled periodically, so that rules can e.g. notify when ink runs low.
+
+mod discovery;
+mod protocol;
+
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Binary, Json, Value};
+
+use self::protocol::Attributes;
+
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static ADAPTER_NAME: &'static str = "IPP printer adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const POLL_INTERVAL_S: u64 = 300;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ChannelKind {
+    InkLevels,
+    PaperStatus,
+    JobCount,
+    Print,
+}
+
+/// printer uri and kind of channel, for each channel.
+type Channels = Arc<Mutex<HashMap<Id<Channel>, (String, ChannelKind)>>>;
+
+pub struct IppAdapter {
+    channels: Channels,
+    watchers: ValueWatchers,
+}
+
+/// A printer name, made suitable for use in an id.
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn ink_levels(attributes: &Attributes) -> JSON {
+    let mut levels = BTreeMap::new();
+    if let (Some(names), Some(values)) = (attributes.get("marker-names"),
+                                          attributes.get("marker-levels")) {
+        for (name, level) in names.iter().zip(values.iter()) {
+            if let (Some(name), Some(level)) = (name.as_text(), level.as_integer()) {
+                levels.insert(name.to_owned(), JSON::I64(level as i64));
+            }
+        }
+    }
+    JSON::Object(levels)
+}
+
+fn paper_status(attributes: &Attributes) -> String {
+    let reasons: Vec<&str> = attributes.get("printer-state-reasons")
+        .map(|reasons| reasons.iter().filter_map(|reason| reason.as_text()).collect())
+        .unwrap_or_else(Vec::new);
+    let has = |prefix: &str| reasons.iter().any(|reason| reason.starts_with(prefix));
+    if has("media-jam") {
+        "jammed"
+    } else if has("media-empty") || has("media-needed") {
+        "empty"
+    } else if has("media-low") {
+        "low"
+    } else {
+        "ok"
+    }
+    .to_owned()
+}
+
+fn job_count(attributes: &Attributes) -> JSON {
+    let count = attributes.get("queued-job-count")
+        .and_then(|values| values.first())
+        .and_then(|value| value.as_integer())
+        .unwrap_or(0);
+    JSON::I64(count as i64)
+}
+
+fn extract(kind: ChannelKind, attributes: &Attributes) -> Option<Value> {
+    match kind {
+        ChannelKind::InkLevels => Some(Value::new(Json(ink_levels(attributes)))),
+        ChannelKind::PaperStatus => Some(Value::new(paper_status(attributes))),
+        ChannelKind::JobCount => Some(Value::new(Json(job_count(attributes)))),
+        ChannelKind::Print => None,
+    }
+}
+
+impl IppAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("ipp@link.mozilla.org")
+    }
+
+    pub fn service_id(printer: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.ipp@link.mozilla.org", printer))
+    }
+
+    pub fn getter_ink_levels_id(printer: &str) -> Id<Channel> {
+        Id::new(&format!("getter:ink-levels.{}.ipp@link.mozilla.org", printer))
+    }
+
+    pub fn getter_paper_status_id(printer: &str) -> Id<Channel> {
+        Id::new(&format!("getter:paper-status.{}.ipp@link.mozilla.org", printer))
+    }
+
+    pub fn getter_job_count_id(printer: &str) -> Id<Channel> {
+        Id::new(&format!("getter:job-count.{}.ipp@link.mozilla.org", printer))
+    }
+
+    pub fn setter_print_id(printer: &str) -> Id<Channel> {
+        Id::new(&format!("setter:print.{}.ipp@link.mozilla.org", printer))
+    }
+
+    pub fn init(manager: &Arc<AdapterManager>) -> Result<(), Error> {
+        let channels: Channels = Arc::new(Mutex::new(HashMap::new()));
+        let watchers = ValueWatchers::new();
+        let adapter = Arc::new(IppAdapter {
+            channels: channels.clone(),
+            watchers: watchers.clone(),
+        });
+        try!(manager.add_adapter(adapter));
+
+        let manager = manager.clone();
+        thread::spawn(move || {
+            let mut printers = HashMap::new();
+            loop {
+                for printer in discovery::discover() {
+                    let slug = slug(&printer.name);
+                    if !printers.contains_key(&slug) {
+                        match Self::add_printer(&manager, &channels, &slug, &printer) {
+                            Ok(()) => {
+                                info!("[ipp] Found printer {} at {}", printer.name, printer.uri);
+                                printers.insert(slug, printer.uri);
+                            }
+                            Err(err) => error!("[ipp] Could not add printer {}: {:?}", slug, err),
+                        }
+                    }
+                }
+                for uri in printers.values() {
+                    Self::poll(&channels, &watchers, uri);
+                }
+                thread::sleep(Duration::from_secs(POLL_INTERVAL_S));
+            }
+        });
+        Ok(())
+    }
+
+    fn add_printer(manager: &AdapterManager,
+                   channels: &Channels,
+                   slug: &str,
+                   printer: &discovery::Printer)
+                   -> Result<(), Error> {
+        let service_id = Self::service_id(slug);
+        let mut service = Service::empty(&service_id, &Self::id());
+        service.properties.insert("model".to_owned(),
+                                  printer.model.clone().unwrap_or_else(|| printer.name.clone()));
+        service.properties.insert("name".to_owned(), printer.name.clone());
+        try!(manager.add_service(service));
+
+        let getters = vec![(Self::getter_ink_levels_id(slug),
+                            "printer/ink-levels",
+                            format::JSON.clone(),
+                            ChannelKind::InkLevels),
+                           (Self::getter_paper_status_id(slug),
+                            "printer/paper-status",
+                            format::STRING.clone(),
+                            ChannelKind::PaperStatus),
+                           (Self::getter_job_count_id(slug),
+                            "printer/job-count",
+                            format::JSON.clone(),
+                            ChannelKind::JobCount)];
+        for (id, feature, format, kind) in getters {
+            try!(manager.add_channel(Channel {
+                id: id.clone(),
+                service: service_id.clone(),
+                adapter: Self::id(),
+                feature: Id::new(feature),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format.clone()))),
+                supports_watch: Some(Signature::returns(Maybe::Required(format))),
+                ..Channel::default()
+            }));
+            channels.lock().unwrap().insert(id, (printer.uri.clone(), kind));
+        }
+
+        let print_id = Self::setter_print_id(slug);
+        try!(manager.add_channel(Channel {
+            id: print_id.clone(),
+            service: service_id.clone(),
+            adapter: Self::id(),
+            feature: Id::new("printer/print"),
+            supports_send: Some(Signature::accepts(Maybe::Required(format::BINARY.clone()))),
+            ..Channel::default()
+        }));
+        channels.lock().unwrap().insert(print_id, (printer.uri.clone(), ChannelKind::Print));
+        Ok(())
+    }
+
+    fn poll(channels: &Channels, watchers: &ValueWatchers, uri: &str) {
+        let attributes = match protocol::get_printer_attributes(uri) {
+            Ok(attributes) => attributes,
+            Err(err) => {
+                warn!("[ipp] Could not poll {}: {}", uri, err);
+                return;
+            }
+        };
+        let channels = channels.lock().unwrap();
+        for (id, &(ref channel_uri, kind)) in channels.iter() {
+            if channel_uri == uri {
+                if let Some(value) = extract(kind, &attributes) {
+                    watchers.update(id, value);
+                }
+            }
+        }
+    }
+
+    fn channel(&self, id: &Id<Channel>) -> Result<(String, ChannelKind), Error> {
+        match self.channels.lock().unwrap().get(id) {
+            Some(channel) => Ok(channel.clone()),
+            None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+        }
+    }
+}
+
+impl Adapter for IppAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                let (uri, kind) = match self.channel(&id) {
+                    Ok(channel) => channel,
+                    Err(err) => return (id, Err(err)),
+                };
+                match protocol::get_printer_attributes(&uri) {
+                    Ok(attributes) => {
+                        let value = extract(kind, &attributes);
+                        if let Some(ref value) = value {
+                            self.watchers.update(&id, value.clone());
+                        }
+                        (id, Ok(value))
+                    }
+                    Err(err) => (id, Err(Error::Internal(InternalError::GenericError(err)))),
+                }
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                let uri = match self.channel(&id) {
+                    Ok((uri, ChannelKind::Print)) => uri,
+                    Ok(_) => {
+                        return (id.clone(),
+                                Err(Error::OperationNotSupported(Operation::Send, id)))
+                    }
+                    Err(err) => return (id, Err(err)),
+                };
+                let document = match value.cast::<Binary>() {
+                    Ok(document) => document,
+                    Err(err) => return (id, Err(err)),
+                };
+                let result = protocol::print_job(&uri,
+                                                 &document.mimetype.to_string(),
+                                                 &document.data)
+                    .map_err(|err| Error::Internal(InternalError::GenericError(err)));
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
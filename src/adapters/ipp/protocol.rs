@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A minimal IPP/1.1 client (RFC 8010/8011): `Get-Printer-Attributes` and `Print-Job`.
+
+use hyper;
+use hyper::header::{ContentType, Connection};
+
+use std::collections::HashMap;
+use std::io::Read;
+
+pub const OPERATION_PRINT_JOB: u16 = 0x0002;
+pub const OPERATION_GET_PRINTER_ATTRIBUTES: u16 = 0x000B;
+
+const TAG_OPERATION_ATTRIBUTES: u8 = 0x01;
+const TAG_END_OF_ATTRIBUTES: u8 = 0x03;
+const TAG_INTEGER: u8 = 0x21;
+const TAG_BOOLEAN: u8 = 0x22;
+const TAG_ENUM: u8 = 0x23;
+const TAG_URI: u8 = 0x45;
+const TAG_CHARSET: u8 = 0x47;
+const TAG_NATURAL_LANGUAGE: u8 = 0x48;
+const TAG_MIME_MEDIA_TYPE: u8 = 0x49;
+const TAG_KEYWORD: u8 = 0x44;
+const TAG_NAME: u8 = 0x42;
+
+/// The value of an attribute. Types we don't care about are kept as strings.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    Integer(i32),
+    Boolean(bool),
+    Text(String),
+}
+
+impl AttributeValue {
+    pub fn as_integer(&self) -> Option<i32> {
+        match *self {
+            AttributeValue::Integer(value) => Some(value),
+            _ => None,
+        }
+    }
+    pub fn as_text(&self) -> Option<&str> {
+        match *self {
+            AttributeValue::Text(ref value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Attributes returned by the printer, flattened across groups. Multi-valued attributes
+/// keep all their values, in order.
+pub type Attributes = HashMap<String, Vec<AttributeValue>>;
+
+fn push_attribute(buf: &mut Vec<u8>, tag: u8, name: &str, value: &[u8]) {
+    buf.push(tag);
+    buf.push((name.len() >> 8) as u8);
+    buf.push(name.len() as u8);
+    buf.extend_from_slice(name.as_bytes());
+    buf.push((value.len() >> 8) as u8);
+    buf.push(value.len() as u8);
+    buf.extend_from_slice(value);
+}
+
+/// Build a request, with the mandatory operation attributes followed by `extra`.
+pub fn encode_request(operation: u16,
+                      request_id: u32,
+                      printer_uri: &str,
+                      extra: &[(u8, &str, &[u8])])
+                      -> Vec<u8> {
+    let mut buf = vec![1, 1, (operation >> 8) as u8, operation as u8];
+    buf.push((request_id >> 24) as u8);
+    buf.push((request_id >> 16) as u8);
+    buf.push((request_id >> 8) as u8);
+    buf.push(request_id as u8);
+    buf.push(TAG_OPERATION_ATTRIBUTES);
+    push_attribute(&mut buf, TAG_CHARSET, "attributes-charset", b"utf-8");
+    push_attribute(&mut buf, TAG_NATURAL_LANGUAGE, "attributes-natural-language", b"en");
+    push_attribute(&mut buf, TAG_URI, "printer-uri", printer_uri.as_bytes());
+    for &(tag, name, value) in extra {
+        push_attribute(&mut buf, tag, name, value);
+    }
+    buf.push(TAG_END_OF_ATTRIBUTES);
+    buf
+}
+
+/// Decode a response into its status code and attributes.
+pub fn decode_response(data: &[u8]) -> Result<(u16, Attributes), String> {
+    if data.len() < 8 {
+        return Err("Truncated IPP response".to_owned());
+    }
+    let status = (data[2] as u16) << 8 | data[3] as u16;
+    let mut attributes = Attributes::new();
+    let mut pos = 8;
+    let mut last_name = String::new();
+    let read_u16 = |pos: usize| -> Result<usize, String> {
+        if pos + 2 > data.len() {
+            return Err("Truncated IPP attribute".to_owned());
+        }
+        Ok((data[pos] as usize) << 8 | data[pos + 1] as usize)
+    };
+    while pos < data.len() {
+        let tag = data[pos];
+        pos += 1;
+        if tag == TAG_END_OF_ATTRIBUTES {
+            break;
+        }
+        if tag < 0x10 {
+            // Beginning of a new group.
+            continue;
+        }
+        let name_len = try!(read_u16(pos));
+        pos += 2;
+        if pos + name_len > data.len() {
+            return Err("Truncated IPP attribute name".to_owned());
+        }
+        if name_len > 0 {
+            last_name = String::from_utf8_lossy(&data[pos..pos + name_len]).into_owned();
+        }
+        pos += name_len;
+        let value_len = try!(read_u16(pos));
+        pos += 2;
+        if pos + value_len > data.len() {
+            return Err("Truncated IPP attribute value".to_owned());
+        }
+        let raw = &data[pos..pos + value_len];
+        pos += value_len;
+        let value = match tag {
+            TAG_INTEGER | TAG_ENUM if raw.len() == 4 => {
+                AttributeValue::Integer(((raw[0] as u32) << 24 | (raw[1] as u32) << 16 |
+                                         (raw[2] as u32) << 8 |
+                                         raw[3] as u32) as i32)
+            }
+            TAG_BOOLEAN if raw.len() == 1 => AttributeValue::Boolean(raw[0] != 0),
+            _ => AttributeValue::Text(String::from_utf8_lossy(raw).into_owned()),
+        };
+        attributes.entry(last_name.clone()).or_insert_with(Vec::new).push(value);
+    }
+    Ok((status, attributes))
+}
+
+fn post(url: &str, body: &[u8]) -> Result<(u16, Attributes), String> {
+    let client = hyper::Client::new();
+    let mut response = try!(client.post(url)
+        .header(ContentType("application/ipp".parse().unwrap()))
+        .header(Connection::close())
+        .body(body)
+        .send()
+        .map_err(|err| format!("{}", err)));
+    let mut content = Vec::new();
+    try!(response.read_to_end(&mut content).map_err(|err| format!("{}", err)));
+    let (status, attributes) = try!(decode_response(&content));
+    // 0x0000-0x00FF are successful statuses.
+    if status > 0x00FF {
+        return Err(format!("Printer returned status {:#06x}", status));
+    }
+    Ok((status, attributes))
+}
+
+/// IPP is carried over HTTP, on the port and path of the `ipp://` uri.
+fn http_url(printer_uri: &str) -> String {
+    if printer_uri.starts_with("ipp://") {
+        format!("http://{}", &printer_uri[6..])
+    } else if printer_uri.starts_with("ipps://") {
+        format!("https://{}", &printer_uri[7..])
+    } else {
+        printer_uri.to_owned()
+    }
+}
+
+pub fn get_printer_attributes(printer_uri: &str) -> Result<Attributes, String> {
+    let request = encode_request(OPERATION_GET_PRINTER_ATTRIBUTES,
+                                 1,
+                                 printer_uri,
+                                 &[(TAG_KEYWORD, "requested-attributes", b"all")]);
+    post(&http_url(printer_uri), &request).map(|(_, attributes)| attributes)
+}
+
+pub fn print_job(printer_uri: &str, mimetype: &str, document: &[u8]) -> Result<(), String> {
+    let mut request = encode_request(OPERATION_PRINT_JOB,
+                                     2,
+                                     printer_uri,
+                                     &[(TAG_NAME, "requesting-user-name", b"foxbox"),
+                                       (TAG_NAME, "job-name", b"foxbox"),
+                                       (TAG_MIME_MEDIA_TYPE,
+                                        "document-format",
+                                        mimetype.as_bytes())]);
+    request.extend_from_slice(document);
+    post(&http_url(printer_uri), &request).map(|_| ())
+}
+
+#[cfg(test)]
+describe! ipp_protocol {
+    before_each {
+        use super::*;
+        use super::{TAG_ENUM, TAG_INTEGER, TAG_KEYWORD, push_attribute};
+    }
+
+    it "should encode a request" {
+        let request = encode_request(OPERATION_GET_PRINTER_ATTRIBUTES, 7, "ipp://p/ipp", &[]);
+        assert_eq!(&request[0..8], &[1, 1, 0x00, 0x0B, 0, 0, 0, 7]);
+        assert_eq!(request[8], 0x01);
+        assert_eq!(*request.last().unwrap(), 0x03);
+    }
+
+    it "should decode multi-valued attributes" {
+        let mut response = vec![1, 1, 0, 0, 0, 0, 0, 1, 0x04];
+        push_attribute(&mut response, TAG_ENUM, "printer-state", &[0, 0, 0, 3]);
+        push_attribute(&mut response, TAG_INTEGER, "marker-levels", &[0, 0, 0, 80]);
+        push_attribute(&mut response, TAG_INTEGER, "", &[0xFF, 0xFF, 0xFF, 0xFF]);
+        push_attribute(&mut response, TAG_KEYWORD, "printer-state-reasons", b"none");
+        response.push(0x03);
+        let (status, attributes) = decode_response(&response).unwrap();
+        assert_eq!(status, 0);
+        assert_eq!(attributes["printer-state"], vec![AttributeValue::Integer(3)]);
+        assert_eq!(attributes["marker-levels"],
+                   vec![AttributeValue::Integer(80), AttributeValue::Integer(-1)]);
+        assert_eq!(attributes["printer-state-reasons"][0].as_text(), Some("none"));
+    }
+
+    it "should reject truncated responses" {
+        assert!(decode_response(&[1, 1, 0, 0]).is_err());
+        assert!(decode_response(&[1, 1, 0, 0, 0, 0, 0, 1, 0x04, 0x21, 0, 10, b'a']).is_err());
+    }
+}
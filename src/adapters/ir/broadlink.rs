@@ -0,0 +1,262 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A client for the local UDP protocol of Broadlink RM IR blasters.
+//!
+//! Each exchange is a 0x38 bytes header followed by a payload encrypted with AES-128-CBC. The
+//! device first tells us its type and MAC address (`hello`), then hands out a session id and
+//! key (`authenticate`), used for all the following commands.
+
+extern crate crypto;
+
+use self::crypto::aes::{cbc_decryptor, cbc_encryptor, KeySize};
+use self::crypto::blockmodes::NoPadding;
+use self::crypto::buffer::{RefReadBuffer, RefWriteBuffer};
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+/// The key used until the device hands out a session key.
+const INITIAL_KEY: [u8; 16] = [0x09, 0x76, 0x28, 0x34, 0x3f, 0xe9, 0x9e, 0x23, 0x76, 0x5c, 0x15,
+                               0x13, 0xac, 0xcf, 0x8b, 0x02];
+const IV: [u8; 16] = [0x56, 0x2e, 0x17, 0x99, 0x6d, 0x09, 0x3d, 0x28, 0xdd, 0xb3, 0xba, 0x69,
+                      0x5a, 0x2e, 0x6f, 0x58];
+
+const PORT: u16 = 80;
+const HEADER_LENGTH: usize = 0x38;
+
+const COMMAND_HELLO: u8 = 0x06;
+const COMMAND_AUTH: u8 = 0x65;
+const COMMAND_RM: u8 = 0x6a;
+
+const RM_SEND_DATA: u8 = 0x02;
+const RM_ENTER_LEARNING: u8 = 0x03;
+const RM_CHECK_DATA: u8 = 0x04;
+
+fn checksum(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xbeaf_u16, |sum, byte| sum.wrapping_add(*byte as u16))
+}
+
+fn crypt(key: &[u8; 16], data: &[u8], encrypt: bool) -> Result<Vec<u8>> {
+    let mut output = vec![0; data.len()];
+    let result = {
+        let mut input = RefReadBuffer::new(data);
+        let mut writer = RefWriteBuffer::new(&mut output);
+        if encrypt {
+            cbc_encryptor(KeySize::KeySize128, key, &IV, NoPadding)
+                .encrypt(&mut input, &mut writer, true)
+        } else {
+            cbc_decryptor(KeySize::KeySize128, key, &IV, NoPadding)
+                .decrypt(&mut input, &mut writer, true)
+        }
+    };
+    match result {
+        Ok(_) => Ok(output),
+        Err(err) => Err(Error::new(ErrorKind::InvalidData, format!("AES failed: {:?}", err))),
+    }
+}
+
+/// Encode a command. The payload is padded to a multiple of the AES block size.
+pub fn encode_command(key: &[u8; 16],
+                      device_type: u16,
+                      mac: &[u8; 6],
+                      session: &[u8; 4],
+                      count: u16,
+                      command: u8,
+                      payload: &[u8])
+                      -> Result<Vec<u8>> {
+    let mut payload = payload.to_vec();
+    while payload.len() % 16 != 0 {
+        payload.push(0);
+    }
+    let mut packet = vec![0; HEADER_LENGTH];
+    packet[..8].copy_from_slice(&[0x5a, 0xa5, 0xaa, 0x55, 0x5a, 0xa5, 0xaa, 0x55]);
+    packet[0x24] = device_type as u8;
+    packet[0x25] = (device_type >> 8) as u8;
+    packet[0x26] = command;
+    packet[0x28] = count as u8;
+    packet[0x29] = (count >> 8) as u8;
+    packet[0x2a..0x30].copy_from_slice(mac);
+    packet[0x30..0x34].copy_from_slice(session);
+    let payload_checksum = checksum(&payload);
+    packet[0x34] = payload_checksum as u8;
+    packet[0x35] = (payload_checksum >> 8) as u8;
+    packet.extend(try!(crypt(key, &payload, true)));
+    let packet_checksum = checksum(&packet);
+    packet[0x20] = packet_checksum as u8;
+    packet[0x21] = (packet_checksum >> 8) as u8;
+    Ok(packet)
+}
+
+/// Decode the response to a command, returning its decrypted payload.
+pub fn decode_response(key: &[u8; 16], packet: &[u8]) -> Result<Vec<u8>> {
+    if packet.len() < HEADER_LENGTH {
+        return Err(Error::new(ErrorKind::InvalidData, "Truncated response"));
+    }
+    let status = packet[0x22] as u16 | (packet[0x23] as u16) << 8;
+    if status != 0 {
+        return Err(Error::new(ErrorKind::Other, format!("Device returned error {:#x}", status)));
+    }
+    let encrypted = &packet[HEADER_LENGTH..];
+    if encrypted.len() % 16 != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid payload length"));
+    }
+    crypt(key, encrypted, false)
+}
+
+pub struct Broadlink {
+    socket: UdpSocket,
+    address: SocketAddr,
+    device_type: u16,
+    mac: [u8; 6],
+    session: [u8; 4],
+    key: [u8; 16],
+    count: u16,
+}
+
+impl Broadlink {
+    /// Connect to the blaster at `host`, and authenticate.
+    pub fn connect(host: &str) -> Result<Self> {
+        let address = match try!((host, PORT).to_socket_addrs()).next() {
+            Some(address) => address,
+            None => return Err(Error::new(ErrorKind::NotFound, format!("Unknown host {}", host))),
+        };
+        let socket = try!(UdpSocket::bind("0.0.0.0:0"));
+        try!(socket.set_read_timeout(Some(Duration::from_secs(5))));
+        let mut blaster = Broadlink {
+            socket: socket,
+            address: address,
+            device_type: 0,
+            mac: [0; 6],
+            session: [0; 4],
+            key: INITIAL_KEY,
+            count: 0,
+        };
+        try!(blaster.hello());
+        try!(blaster.authenticate());
+        Ok(blaster)
+    }
+
+    fn exchange(&self, packet: &[u8]) -> Result<Vec<u8>> {
+        try!(self.socket.send_to(packet, self.address));
+        let mut buf = [0; 1024];
+        let (len, _) = try!(self.socket.recv_from(&mut buf));
+        Ok(buf[..len].to_vec())
+    }
+
+    /// Learn the type and MAC address of the device.
+    fn hello(&mut self) -> Result<()> {
+        let mut packet = vec![0; 0x30];
+        packet[0x26] = COMMAND_HELLO;
+        let sum = checksum(&packet);
+        packet[0x20] = sum as u8;
+        packet[0x21] = (sum >> 8) as u8;
+        let response = try!(self.exchange(&packet));
+        if response.len() < 0x40 {
+            return Err(Error::new(ErrorKind::InvalidData, "Truncated hello response"));
+        }
+        self.device_type = response[0x34] as u16 | (response[0x35] as u16) << 8;
+        self.mac.copy_from_slice(&response[0x3a..0x40]);
+        Ok(())
+    }
+
+    fn command(&mut self, command: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        self.count = self.count.wrapping_add(1);
+        let packet = try!(encode_command(&self.key,
+                                         self.device_type,
+                                         &self.mac,
+                                         &self.session,
+                                         self.count,
+                                         command,
+                                         payload));
+        let response = try!(self.exchange(&packet));
+        decode_response(&self.key, &response)
+    }
+
+    fn authenticate(&mut self) -> Result<()> {
+        let mut payload = vec![0; 0x50];
+        for byte in &mut payload[0x04..0x13] {
+            *byte = 0x31;
+        }
+        payload[0x1e] = 0x01;
+        payload[0x2d] = 0x01;
+        payload[0x30..0x36].copy_from_slice(b"foxbox");
+        let response = try!(self.command(COMMAND_AUTH, &payload));
+        if response.len() < 0x14 {
+            return Err(Error::new(ErrorKind::InvalidData, "Truncated authentication response"));
+        }
+        self.session.copy_from_slice(&response[..4]);
+        self.key.copy_from_slice(&response[4..0x14]);
+        Ok(())
+    }
+
+    /// Wait for the next IR code received by the device, see `check_data`.
+    pub fn enter_learning(&mut self) -> Result<()> {
+        try!(self.command(COMMAND_RM, &[RM_ENTER_LEARNING, 0, 0, 0]));
+        Ok(())
+    }
+
+    /// The code received since `enter_learning`, if any.
+    pub fn check_data(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.command(COMMAND_RM, &[RM_CHECK_DATA, 0, 0, 0]) {
+            Ok(response) => {
+                if response.len() <= 4 {
+                    return Ok(None);
+                }
+                // The code is followed by the padding of the payload.
+                let mut code = response[4..].to_vec();
+                while code.last() == Some(&0) {
+                    code.pop();
+                }
+                Ok(if code.is_empty() { None } else { Some(code) })
+            }
+            // Nothing received yet.
+            Err(ref err) if err.kind() == ErrorKind::Other => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Replay a code returned by `check_data`.
+    pub fn send_data(&mut self, code: &[u8]) -> Result<()> {
+        let mut payload = vec![RM_SEND_DATA, 0, 0, 0];
+        payload.extend_from_slice(code);
+        try!(self.command(COMMAND_RM, &payload));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+describe! broadlink {
+    before_each {
+        use super::*;
+        use super::INITIAL_KEY;
+    }
+
+    it "should encode and decode commands" {
+        let mac = [1, 2, 3, 4, 5, 6];
+        let packet = encode_command(&INITIAL_KEY, 0x2712, &mac, &[0; 4], 1, 0x6a, &[4, 0, 0, 0])
+            .unwrap();
+        assert_eq!(packet.len(), 0x38 + 16);
+        assert_eq!(&packet[..8], &[0x5a, 0xa5, 0xaa, 0x55, 0x5a, 0xa5, 0xaa, 0x55]);
+        assert_eq!((packet[0x24], packet[0x25], packet[0x26]), (0x12, 0x27, 0x6a));
+        assert_eq!(&packet[0x2a..0x30], &mac);
+        // The checksum of the payload, before encryption.
+        assert_eq!((packet[0x34], packet[0x35]), (0xb3, 0xbe));
+
+        // The device answers with the same layout.
+        let payload = decode_response(&INITIAL_KEY, &packet).unwrap();
+        assert_eq!(&payload[..4], &[4, 0, 0, 0]);
+        assert!(payload[4..].iter().all(|byte| *byte == 0));
+    }
+
+    it "should report the errors of the device" {
+        let mut packet = encode_command(&INITIAL_KEY, 0x2712, &[0; 6], &[0; 4], 1, 0x6a, &[4])
+            .unwrap();
+        packet[0x22] = 0xf6;
+        packet[0x23] = 0xff;
+        assert!(decode_response(&INITIAL_KEY, &packet).is_err());
+        assert!(decode_response(&INITIAL_KEY, &packet[..0x20]).is_err());
+    }
+}
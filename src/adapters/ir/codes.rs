@@ -0,0 +1,73 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The IR codes learnt by each blaster, stored as hexadecimal strings.
+
+use serde_json;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+
+/// The codes of each blaster, by name.
+pub type Codes = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Names end up in channel ids, so we only accept lower case letters, digits and dashes,
+/// e.g. `tv-power`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 &&
+    name.chars().all(|c| match c {
+        'a'...'z' | '0'...'9' | '-' => true,
+        _ => false,
+    })
+}
+
+pub fn load(path: &str) -> Codes {
+    let mut source = String::new();
+    if File::open(path).and_then(|mut file| file.read_to_string(&mut source)).is_err() {
+        return BTreeMap::new();
+    }
+    serde_json::from_str(&source).unwrap_or_else(|err| {
+        warn!("[ir] Ignoring invalid codes in {}: {}", path, err);
+        BTreeMap::new()
+    })
+}
+
+pub fn save(path: &str, codes: &Codes) -> Result<(), String> {
+    let source = try!(serde_json::to_string(codes).map_err(|err| format!("{}", err)));
+    File::create(path)
+        .and_then(|mut file| file.write_all(source.as_bytes()))
+        .map_err(|err| format!("Could not write {}: {}", path, err))
+}
+
+#[cfg(test)]
+describe! ir_codes {
+    before_each {
+        use super::*;
+        use std::collections::BTreeMap;
+        use tempdir::TempDir;
+    }
+
+    it "should only accept names fit for ids" {
+        assert!(is_valid_name("tv-power"));
+        assert!(is_valid_name("input2"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("TV power"));
+        assert!(!is_valid_name("tv.power@link.mozilla.org"));
+    }
+
+    it "should save and load codes" {
+        let dir = TempDir::new("ir").unwrap();
+        let path = dir.path().join("ir_codes.json");
+        let path = path.to_str().unwrap();
+        assert!(load(path).is_empty());
+
+        let mut codes = BTreeMap::new();
+        let mut living_room = BTreeMap::new();
+        living_room.insert("tv-power".to_owned(), "26001a00".to_owned());
+        codes.insert("living-room".to_owned(), living_room);
+        save(path, &codes).unwrap();
+        assert_eq!(load(path), codes);
+    }
+}
@@ -0,0 +1,354 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A virtual remote control, through network IR blasters, so that rules can drive TVs,
+//! amplifiers and other equipment that only understands infrared.
+//!
+//! Only Broadlink RM blasters are supported for now. They are declared in the configuration,
+//! as a comma separated list of `name=host` (e.g. `living-room=192.168.1.20`) in
+//! `ir.blasters`.
+//!
+//! Each blaster is a service exposing:
+//! - `ir-blaster/learn` (send), as a `String`: the name of a new code, e.g. `tv-power`. The
+//!   blaster waits for the user to press a button of the original remote, for up to 30
+//!   seconds, and the request fails if nothing was received;
+//! - `ir-blaster/forget` (send), as a `String`: the name of a code to remove;
+//! - one `ir-blaster/send` channel (send, no value) per code learnt, tagged with the name of
+//!   the code.
+//!
+//! Codes are kept in `ir_codes.json`, in the profile directory.
+
+mod broadlink;
+mod codes;
+
+use self::broadlink::Broadlink;
+use self::codes::Codes;
+
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Value};
+
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static ADAPTER_NAME: &'static str = "IR blaster adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+/// How long the user has to press a button of the original remote while learning.
+const LEARN_TIMEOUT_S: u64 = 30;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Kind {
+    Learn,
+    Forget,
+    Send(String),
+}
+
+struct Blaster {
+    host: String,
+    /// Connected on demand, and reset after an error so that we reconnect if the blaster
+    /// was restarted or got a new session.
+    connection: Option<Broadlink>,
+}
+
+impl Blaster {
+    fn with_connection<F, T>(&mut self, f: F) -> Result<T, Error>
+        where F: FnOnce(&mut Broadlink) -> ::std::io::Result<T>
+    {
+        if self.connection.is_none() {
+            let connection = try!(Broadlink::connect(&self.host).map_err(|err| {
+                Error::Internal(InternalError::GenericError(format!("Could not connect to {}: {}",
+                                                                    self.host,
+                                                                    err)))
+            }));
+            self.connection = Some(connection);
+        }
+        let result = f(self.connection.as_mut().unwrap());
+        if result.is_err() {
+            self.connection = None;
+        }
+        result.map_err(|err| Error::Internal(InternalError::GenericError(format!("{}", err))))
+    }
+}
+
+/// Parse the list of blasters `name=host, name=host`.
+fn parse_blasters(source: &str) -> Vec<(String, String)> {
+    source.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .filter_map(|item| {
+            let mut parts = item.splitn(2, '=');
+            match (parts.next().map(str::trim), parts.next().map(str::trim)) {
+                (Some(name), Some(host)) if codes::is_valid_name(name) && !host.is_empty() => {
+                    Some((name.to_owned(), host.to_owned()))
+                }
+                _ => {
+                    warn!("[ir] Ignoring invalid blaster definition {}", item);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+pub struct IrAdapter {
+    manager: Arc<AdapterManager>,
+    blasters: HashMap<String, Mutex<Blaster>>,
+    codes: Mutex<Codes>,
+    codes_path: String,
+    /// The blaster and kind of each channel.
+    channels: Mutex<HashMap<Id<Channel>, (String, Kind)>>,
+}
+
+impl IrAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("ir@link.mozilla.org")
+    }
+
+    fn service_id(blaster: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.ir@link.mozilla.org", blaster))
+    }
+
+    fn channel_id(blaster: &str, kind: &Kind) -> Id<Channel> {
+        match *kind {
+            Kind::Learn => Id::new(&format!("setter:learn.{}.ir@link.mozilla.org", blaster)),
+            Kind::Forget => Id::new(&format!("setter:forget.{}.ir@link.mozilla.org", blaster)),
+            Kind::Send(ref code) => {
+                Id::new(&format!("setter:send.{}.{}.ir@link.mozilla.org", code, blaster))
+            }
+        }
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let blasters = match controller.get_config().get("ir", "blasters") {
+            Some(list) => parse_blasters(&list),
+            None => vec![],
+        };
+        if blasters.is_empty() {
+            info!("[ir] No IR blaster configured.");
+            return Ok(());
+        }
+        let codes_path = controller.get_profile().path_for("ir_codes.json");
+
+        let adapter = Arc::new(IrAdapter {
+            manager: manager.clone(),
+            blasters: blasters.iter()
+                .map(|&(ref name, ref host)| {
+                    (name.clone(),
+                     Mutex::new(Blaster {
+                         host: host.clone(),
+                         connection: None,
+                     }))
+                })
+                .collect(),
+            codes: Mutex::new(codes::load(&codes_path)),
+            codes_path: codes_path,
+            channels: Mutex::new(HashMap::new()),
+        });
+        try!(manager.add_adapter(adapter.clone()));
+
+        for &(ref name, ref host) in &blasters {
+            let service_id = Self::service_id(name);
+            let mut service = Service::empty(&service_id, &Self::id());
+            service.properties.insert("model".to_owned(), "Broadlink RM".to_owned());
+            service.properties.insert("host".to_owned(), host.clone());
+            try!(manager.add_service(service));
+            for (kind, feature) in vec![(Kind::Learn, "ir-blaster/learn"),
+                                        (Kind::Forget, "ir-blaster/forget")] {
+                let id = Self::channel_id(name, &kind);
+                try!(manager.add_channel(Channel {
+                    id: id.clone(),
+                    service: service_id.clone(),
+                    adapter: Self::id(),
+                    feature: Id::new(feature),
+                    supports_send: Some(Signature::accepts(Maybe::Required(format::STRING
+                        .clone()))),
+                    ..Channel::default()
+                }));
+                adapter.channels.lock().unwrap().insert(id, (name.clone(), kind));
+            }
+            let known: Vec<String> = adapter.codes
+                .lock()
+                .unwrap()
+                .get(name)
+                .map_or(vec![], |codes| codes.keys().cloned().collect());
+            for code in known {
+                try!(adapter.add_code_channel(name, &code));
+            }
+        }
+        Ok(())
+    }
+
+    fn add_code_channel(&self, blaster: &str, code: &str) -> Result<(), Error> {
+        let kind = Kind::Send(code.to_owned());
+        let id = Self::channel_id(blaster, &kind);
+        let mut tags = HashSet::new();
+        tags.insert(tag_id!(code));
+        try!(self.manager.add_channel(Channel {
+            id: id.clone(),
+            service: Self::service_id(blaster),
+            adapter: Self::id(),
+            feature: Id::new("ir-blaster/send"),
+            supports_send: Some(Signature::nothing()),
+            tags: tags,
+            ..Channel::default()
+        }));
+        self.channels.lock().unwrap().insert(id, (blaster.to_owned(), kind));
+        Ok(())
+    }
+
+    fn save_codes(&self, codes: &Codes) -> Result<(), Error> {
+        codes::save(&self.codes_path, codes)
+            .map_err(|err| Error::Internal(InternalError::GenericError(err)))
+    }
+
+    /// Capture the next code received by a blaster, and store it as `name`.
+    fn learn(&self, blaster: &str, name: &str) -> Result<(), Error> {
+        if !codes::is_valid_name(name) {
+            return Err(Error::InvalidValue);
+        }
+        let code = {
+            let mut blaster = self.blasters[blaster].lock().unwrap();
+            try!(blaster.with_connection(|connection| connection.enter_learning()));
+            let deadline = Instant::now() + Duration::from_secs(LEARN_TIMEOUT_S);
+            let mut code = None;
+            while code.is_none() {
+                if Instant::now() >= deadline {
+                    return Err(Error::Internal(InternalError::GenericError("No IR code \
+                                                                            received"
+                        .to_owned())));
+                }
+                thread::sleep(Duration::from_millis(500));
+                code = try!(blaster.with_connection(|connection| connection.check_data()));
+            }
+            code.unwrap()
+        };
+        info!("[ir] Learnt code {} on {}", name, blaster);
+
+        let is_new = {
+            let mut codes = self.codes.lock().unwrap();
+            let is_new = codes.entry(blaster.to_owned())
+                .or_insert_with(Default::default)
+                .insert(name.to_owned(), code.to_hex())
+                .is_none();
+            try!(self.save_codes(&codes));
+            is_new
+        };
+        if is_new {
+            try!(self.add_code_channel(blaster, name));
+        }
+        Ok(())
+    }
+
+    fn forget(&self, blaster: &str, name: &str) -> Result<(), Error> {
+        {
+            let mut codes = self.codes.lock().unwrap();
+            let removed = codes.get_mut(blaster).and_then(|codes| codes.remove(name));
+            if removed.is_none() {
+                return Err(Error::InvalidValue);
+            }
+            try!(self.save_codes(&codes));
+        }
+        let id = Self::channel_id(blaster, &Kind::Send(name.to_owned()));
+        self.channels.lock().unwrap().remove(&id);
+        self.manager.remove_channel(&id)
+    }
+
+    fn send_code(&self, blaster: &str, name: &str) -> Result<(), Error> {
+        let code = self.codes
+            .lock()
+            .unwrap()
+            .get(blaster)
+            .and_then(|codes| codes.get(name))
+            .and_then(|code| code.from_hex().ok());
+        let code = match code {
+            Some(code) => code,
+            None => {
+                return Err(Error::Internal(InternalError::GenericError(format!("Unknown code \
+                                                                                 {}",
+                                                                                name))))
+            }
+        };
+        let mut blaster = self.blasters[blaster].lock().unwrap();
+        blaster.with_connection(|connection| connection.send_data(&code))
+    }
+}
+
+impl Adapter for IrAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        let channels = self.channels.lock().unwrap();
+        set.drain(..)
+            .map(|id| {
+                if channels.contains_key(&id) {
+                    (id.clone(), Err(Error::OperationNotSupported(Operation::Fetch, id)))
+                } else {
+                    (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+                }
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                let channel = self.channels.lock().unwrap().get(&id).cloned();
+                let result = match channel {
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                    Some((blaster, Kind::Send(code))) => self.send_code(&blaster, &code),
+                    Some((blaster, Kind::Learn)) => {
+                        value.cast::<String>().and_then(|name| self.learn(&blaster, name))
+                    }
+                    Some((blaster, Kind::Forget)) => {
+                        value.cast::<String>().and_then(|name| self.forget(&blaster, name))
+                    }
+                };
+                (id, result)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+describe! ir {
+    before_each {
+        use super::parse_blasters;
+    }
+
+    it "should parse the list of blasters" {
+        assert_eq!(parse_blasters("living-room=192.168.1.20, bedroom = rm-mini.local,"),
+                   vec![("living-room".to_owned(), "192.168.1.20".to_owned()),
+                        ("bedroom".to_owned(), "rm-mini.local".to_owned())]);
+        assert_eq!(parse_blasters("Living Room=192.168.1.20, nohost="), vec![]);
+    }
+}
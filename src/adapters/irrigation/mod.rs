@@ -0,0 +1,401 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter driving irrigation zones.
+//!
+//! Zones are configured in `irrigation.zones` (see `zones`), each with the selector of the
+//! valve that waters it and its programs. Each zone is exposed as a service with:
+//! - `irrigation/is-running` (fetch, watch, send): `On` while the zone is watered. Sending `On`
+//!   runs the zone for `irrigation.manual_minutes` minutes (10 by default), `Off` stops it;
+//! - `irrigation/run-for` (send): runs the zone for a `Duration`.
+//!
+//! If `irrigation.rain` is set to a selector of rain sensors (or of weather stations), programs
+//! are skipped for `irrigation.rain_delay` hours (24 by default) after rain has been reported.
+//! The `irrigation` service exposes this delay as `irrigation/rain-delay`, which is `On` while
+//! programs are skipped, and which accepts a `Duration` to start a delay by hand (`0` to cancel
+//! it). Manual runs are never skipped.
+
+mod zones;
+
+use self::zones::{Action, Sequencer, ZoneConfig};
+
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{API, Error, InternalError, Targetted, User, WatchEvent};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::io::Payload;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::parse::{Parser, Path, ToJSON};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::util::Exactly;
+use foxbox_taxonomy::values::{self, format, OnOff, Value};
+
+use chrono::{Local, Timelike};
+use serde_json;
+
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static ADAPTER_NAME: &'static str = "Irrigation adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const TICK_S: u64 = 1;
+
+enum Command {
+    Run(usize, Duration),
+    Stop(usize),
+    /// Skip programs for this long. `None` cancels the current delay.
+    RainDelay(Option<Duration>),
+}
+
+pub struct IrrigationAdapter {
+    zones: Vec<String>,
+    manual_run: Duration,
+    commands: Mutex<mpsc::Sender<Command>>,
+    watchers: ValueWatchers,
+}
+
+impl IrrigationAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("irrigation@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:irrigation@link.mozilla.org")
+    }
+
+    pub fn zone_service_id(zone: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.irrigation@link.mozilla.org", zone))
+    }
+
+    pub fn running_id(zone: &str) -> Id<Channel> {
+        Id::new(&format!("channel:running.{}.irrigation@link.mozilla.org", zone))
+    }
+
+    pub fn run_for_id(zone: &str) -> Id<Channel> {
+        Id::new(&format!("channel:run-for.{}.irrigation@link.mozilla.org", zone))
+    }
+
+    pub fn rain_delay_id() -> Id<Channel> {
+        Id::new("channel:rain-delay.irrigation@link.mozilla.org")
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let zones = match config.get("irrigation", "zones") {
+            Some(source) => {
+                try!(zones::parse_zones(&source)
+                    .map_err(|err| Error::Internal(InternalError::GenericError(err))))
+            }
+            None => {
+                info!("[irrigation] No zone configured.");
+                return Ok(());
+            }
+        };
+        let rain = match config.get("irrigation", "rain") {
+            Some(source) => {
+                let json = try!(serde_json::from_str(&source).map_err(|err| {
+                    Error::Internal(InternalError::GenericError(format!("{}", err)))
+                }));
+                try!(Vec::<ChannelSelector>::parse(Path::new(), &json).map_err(|err| {
+                    Error::Internal(InternalError::GenericError(format!("{:?}", err)))
+                }))
+            }
+            None => vec![],
+        };
+        let rain_delay = config.get_or_set_default("irrigation", "rain_delay", "24")
+            .parse()
+            .unwrap_or(24);
+        let manual_minutes = config.get_or_set_default("irrigation", "manual_minutes", "10")
+            .parse()
+            .unwrap_or(10);
+
+        let (tx, rx) = mpsc::channel();
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(IrrigationAdapter {
+            zones: zones.iter().map(|zone| zone.id.clone()).collect(),
+            manual_run: Duration::from_secs(manual_minutes * 60),
+            commands: Mutex::new(tx),
+            watchers: watchers.clone(),
+        })));
+
+        let mut service = Service::empty(&Self::service_id(), &Self::id());
+        service.properties.insert("model".to_owned(), "Irrigation controller".to_owned());
+        try!(manager.add_service(service));
+        try!(manager.add_channel(Channel {
+            id: Self::rain_delay_id(),
+            service: Self::service_id(),
+            adapter: Self::id(),
+            feature: Id::new("irrigation/rain-delay"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+            supports_watch: Some(Signature {
+                accepts: Maybe::Optional(format::ON_OFF.clone()),
+                returns: Maybe::Required(format::ON_OFF.clone()),
+            }),
+            supports_send: Some(Signature::accepts(Maybe::Required(format::DURATION.clone()))),
+            ..Channel::default()
+        }));
+        watchers.update(&Self::rain_delay_id(), Value::new(OnOff::Off));
+
+        for zone in &zones {
+            let service_id = Self::zone_service_id(&zone.id);
+            let mut service = Service::empty(&service_id, &Self::id());
+            service.properties.insert("model".to_owned(), "Irrigation zone".to_owned());
+            service.properties.insert("name".to_owned(), zone.name.clone());
+            try!(manager.add_service(service));
+            try!(manager.add_channel(Channel {
+                id: Self::running_id(&zone.id),
+                service: service_id.clone(),
+                adapter: Self::id(),
+                feature: Id::new("irrigation/is-running"),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+                supports_watch: Some(Signature {
+                    accepts: Maybe::Optional(format::ON_OFF.clone()),
+                    returns: Maybe::Required(format::ON_OFF.clone()),
+                }),
+                supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+                ..Channel::default()
+            }));
+            try!(manager.add_channel(Channel {
+                id: Self::run_for_id(&zone.id),
+                service: service_id,
+                adapter: Self::id(),
+                feature: Id::new("irrigation/run-for"),
+                supports_send: Some(Signature::accepts(Maybe::Required(format::DURATION.clone()))),
+                ..Channel::default()
+            }));
+            watchers.update(&Self::running_id(&zone.id), Value::new(OnOff::Off));
+        }
+
+        let manager = manager.clone();
+        thread::Builder::new()
+            .name("Irrigation".to_owned())
+            .spawn(move || {
+                Self::run(&manager,
+                          zones,
+                          rain,
+                          Duration::from_secs(rain_delay * 3600),
+                          rx,
+                          &watchers)
+            })
+            .unwrap();
+        Ok(())
+    }
+
+    /// Open or close the valve of a zone. This goes through the `AdapterManager`, so we must not
+    /// be called from one of its callbacks.
+    fn set_valve(manager: &AdapterManager, zone: &ZoneConfig, open: bool) {
+        let value = if open { OnOff::On } else { OnOff::Off };
+        let payload = match Payload::from_data(value, &format::ON_OFF) {
+            Ok(payload) => payload,
+            Err(err) => return error!("[irrigation] Could not build payload: {:?}", err),
+        };
+        let results = manager.send_values(vec![Targetted {
+                                              select: zone.valve.clone(),
+                                              payload: payload,
+                                          }],
+                                          User::None);
+        if results.is_empty() {
+            warn!("[irrigation] No valve found for zone {}", zone.id);
+        }
+        for (id, result) in results {
+            if let Err(err) = result {
+                warn!("[irrigation] Could not operate valve {} of zone {}: {:?}",
+                      id,
+                      zone.id,
+                      err);
+            }
+        }
+    }
+
+    /// Run programs, commands and rain delays, forever.
+    fn run(manager: &AdapterManager,
+           zones: Vec<ZoneConfig>,
+           rain: Vec<ChannelSelector>,
+           rain_delay: Duration,
+           commands: mpsc::Receiver<Command>,
+           watchers: &ValueWatchers) {
+        let (tx, readings) = mpsc::channel::<WatchEvent>();
+        // Keep the guard alive for as long as we are watering.
+        let _guard = if rain.is_empty() {
+            None
+        } else {
+            Some(manager.watch_values(vec![Targetted {
+                                              select: rain,
+                                              payload: Exactly::Always,
+                                          }],
+                                      Box::new(tx),
+                                      User::None))
+        };
+
+        let mut sequencer = Sequencer::new();
+        let mut delayed_until: Option<Instant> = None;
+        let mut reported_delay = false;
+        let mut last_minute = None;
+        loop {
+            let now = Instant::now();
+            while let Ok(command) = commands.try_recv() {
+                match command {
+                    Command::Run(zone, duration) => sequencer.enqueue(zone, duration, now),
+                    Command::Stop(zone) => sequencer.stop(zone, now),
+                    Command::RainDelay(delay) => delayed_until = delay.map(|delay| now + delay),
+                }
+            }
+            while let Ok(event) = readings.try_recv() {
+                if let WatchEvent::EnterRange { channel, value, .. } = event {
+                    if zones::is_raining(&value.to_json()) {
+                        info!("[irrigation] Rain reported by {}, delaying programs", channel);
+                        delayed_until = Some(now + rain_delay);
+                    }
+                }
+            }
+            if delayed_until.map_or(false, |until| until <= now) {
+                delayed_until = None;
+            }
+            if delayed_until.is_some() != reported_delay {
+                reported_delay = delayed_until.is_some();
+                let value = if reported_delay { OnOff::On } else { OnOff::Off };
+                watchers.update(&Self::rain_delay_id(), Value::new(value));
+            }
+
+            // Programs start at most once per minute.
+            let local = Local::now().naive_local();
+            let minute = Some((local.date(), local.hour(), local.minute()));
+            if minute != last_minute {
+                last_minute = minute;
+                for (index, zone) in zones.iter().enumerate() {
+                    for program in &zone.programs {
+                        if !program.starts_at(&local) {
+                            continue;
+                        }
+                        if delayed_until.is_some() {
+                            info!("[irrigation] Skipping program of zone {}: rain delay", zone.id);
+                            continue;
+                        }
+                        sequencer.enqueue(index, Duration::from_secs(program.minutes * 60), now);
+                    }
+                }
+            }
+
+            for action in sequencer.tick(now) {
+                let (index, open) = match action {
+                    Action::Open(index) => (index, true),
+                    Action::Close(index) => (index, false),
+                };
+                let zone = &zones[index];
+                let value = if open { OnOff::On } else { OnOff::Off };
+                debug!("[irrigation] Turning zone {} {:?}", zone.id, value);
+                Self::set_valve(manager, zone, open);
+                watchers.update(&Self::running_id(&zone.id), Value::new(value));
+            }
+            thread::sleep(Duration::from_secs(TICK_S));
+        }
+    }
+
+    fn zone_of(&self, id: &Id<Channel>) -> Option<(usize, bool)> {
+        self.zones
+            .iter()
+            .enumerate()
+            .filter_map(|(index, zone)| if *id == Self::running_id(zone) {
+                Some((index, true))
+            } else if *id == Self::run_for_id(zone) {
+                Some((index, false))
+            } else {
+                None
+            })
+            .next()
+    }
+
+    fn command(&self, id: &Id<Channel>, value: &Value) -> Result<Command, Error> {
+        if *id == Self::rain_delay_id() {
+            let delay = try!(value.cast::<values::Duration>()).as_duration().num_seconds();
+            return Ok(Command::RainDelay(if delay > 0 {
+                Some(Duration::from_secs(delay as u64))
+            } else {
+                None
+            }));
+        }
+        match self.zone_of(id) {
+            Some((zone, true)) => {
+                Ok(match *try!(value.cast::<OnOff>()) {
+                    OnOff::On => Command::Run(zone, self.manual_run),
+                    OnOff::Off => Command::Stop(zone),
+                })
+            }
+            Some((zone, false)) => {
+                let seconds = try!(value.cast::<values::Duration>()).as_duration().num_seconds();
+                Ok(if seconds > 0 {
+                    Command::Run(zone, Duration::from_secs(seconds as u64))
+                } else {
+                    Command::Stop(zone)
+                })
+            }
+            None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+        }
+    }
+}
+
+impl Adapter for IrrigationAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                let is_getter = id == Self::rain_delay_id() ||
+                                self.zone_of(&id).map_or(false, |(_, running)| running);
+                if !is_getter {
+                    return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))));
+                }
+                let value = self.watchers.latest(&id);
+                (id, Ok(value))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                let result = self.command(&id, &value).and_then(|command| {
+                    self.commands
+                        .lock()
+                        .unwrap()
+                        .send(command)
+                        .map_err(|_| {
+                            Error::Internal(InternalError::GenericError("Irrigation stopped"
+                                .to_owned()))
+                        })
+                });
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
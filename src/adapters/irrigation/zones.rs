@@ -0,0 +1,229 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Irrigation zones, their programs, and the sequencing of their valves.
+//!
+//! Zones are configured in `irrigation.zones`:
+//!
+//! ```json
+//! [{ "id": "lawn", "name": "Front lawn",
+//!    "valve": { "id": "channel:switch-lawn.esphome@link.mozilla.org" },
+//!    "programs": [{ "start": "06:00", "minutes": 20, "days": [0, 2, 4] }] }]
+//! ```
+//!
+//! `valve` is a channel selector for an `OnOff` channel (a relay, a Z-Wave switch, ...).
+//! `days` are counted from Monday (0) and default to every day.
+//!
+//! Most installations can't provide enough pressure to water several zones at once, so zones
+//! are run one at a time, in the order in which they were requested.
+
+use foxbox_taxonomy::parse::{Parser, Path};
+use foxbox_taxonomy::selector::ChannelSelector;
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use serde_json;
+use serde_json::value::Value as JSON;
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub hour: u32,
+    pub minute: u32,
+    pub minutes: u64,
+    /// Days of the week, from Monday (0). Empty for every day.
+    pub days: Vec<u32>,
+}
+
+impl Program {
+    /// Whether the program starts at this minute.
+    pub fn starts_at(&self, now: &NaiveDateTime) -> bool {
+        now.hour() == self.hour && now.minute() == self.minute &&
+        (self.days.is_empty() || self.days.contains(&now.weekday().num_days_from_monday()))
+    }
+}
+
+pub struct ZoneConfig {
+    pub id: String,
+    pub name: String,
+    pub valve: Vec<ChannelSelector>,
+    pub programs: Vec<Program>,
+}
+
+fn parse_program(source: &JSON) -> Result<Program, String> {
+    let invalid = || format!("Invalid program {:?}", source);
+    let start = try!(source.find("start").and_then(JSON::as_string).ok_or_else(&invalid));
+    let mut start = start.splitn(2, ':');
+    let hour = try!(start.next().and_then(|hour| hour.parse().ok()).ok_or_else(&invalid));
+    let minute = try!(start.next().and_then(|minute| minute.parse().ok()).ok_or_else(&invalid));
+    let minutes = try!(source.find("minutes").and_then(JSON::as_u64).ok_or_else(&invalid));
+    let days = match source.find("days").and_then(JSON::as_array) {
+        Some(days) => {
+            try!(days.iter()
+                .map(|day| day.as_u64().map(|day| day as u32).ok_or_else(&invalid))
+                .collect())
+        }
+        None => vec![],
+    };
+    if hour > 23 || minute > 59 || days.iter().any(|day| *day > 6) {
+        return Err(invalid());
+    }
+    Ok(Program {
+        hour: hour,
+        minute: minute,
+        minutes: minutes,
+        days: days,
+    })
+}
+
+pub fn parse_zones(source: &str) -> Result<Vec<ZoneConfig>, String> {
+    let json: JSON = try!(serde_json::from_str(source).map_err(|err| format!("{}", err)));
+    let zones = try!(json.as_array().ok_or_else(|| "Expected an array of zones".to_owned()));
+    let mut result = Vec::new();
+    for zone in zones {
+        let id = try!(zone.find("id")
+            .and_then(JSON::as_string)
+            .ok_or_else(|| "Every zone needs an `id`".to_owned()));
+        let valve = match zone.find("valve") {
+            Some(valve) => {
+                try!(Vec::<ChannelSelector>::parse(Path::new(), valve)
+                    .map_err(|err| format!("Invalid valve for zone {}: {:?}", id, err)))
+            }
+            None => return Err(format!("Zone {} has no `valve`", id)),
+        };
+        let programs = match zone.find("programs").and_then(JSON::as_array) {
+            Some(programs) => try!(programs.iter().map(parse_program).collect()),
+            None => vec![],
+        };
+        result.push(ZoneConfig {
+            id: id.to_owned(),
+            name: zone.find("name").and_then(JSON::as_string).unwrap_or(id).to_owned(),
+            valve: valve,
+            programs: programs,
+        });
+    }
+    Ok(result)
+}
+
+/// Whether the reading of a rain sensor (or of a weather station) means that it is raining:
+/// `On`, `Detected`, `true`, a positive number, or an object with a positive `rain` field.
+pub fn is_raining(value: &JSON) -> bool {
+    match *value {
+        JSON::String(ref state) => state == "On" || state == "Detected",
+        JSON::Bool(raining) => raining,
+        JSON::Object(_) => value.find("rain").map_or(false, is_raining),
+        _ => value.as_f64().map_or(false, |rain| rain > 0.0),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Open(usize),
+    Close(usize),
+}
+
+/// Runs zones one at a time.
+#[derive(Default)]
+pub struct Sequencer {
+    queue: VecDeque<(usize, Duration)>,
+    running: Option<(usize, Instant)>,
+}
+
+impl Sequencer {
+    pub fn new() -> Self {
+        Sequencer::default()
+    }
+
+    pub fn running(&self) -> Option<usize> {
+        self.running.map(|(zone, _)| zone)
+    }
+
+    /// Request a run of a zone. Requesting a zone that is already running or queued
+    /// replaces its duration.
+    pub fn enqueue(&mut self, zone: usize, duration: Duration, now: Instant) {
+        if self.running() == Some(zone) {
+            self.running = Some((zone, now + duration));
+            return;
+        }
+        match self.queue.iter().position(|&(queued, _)| queued == zone) {
+            Some(index) => self.queue[index].1 = duration,
+            None => self.queue.push_back((zone, duration)),
+        }
+    }
+
+    /// Stop a zone, or cancel its run if it is queued.
+    pub fn stop(&mut self, zone: usize, now: Instant) {
+        self.queue.retain(|&(queued, _)| queued != zone);
+        if self.running() == Some(zone) {
+            self.running = Some((zone, now));
+        }
+    }
+
+    /// Move on. Valves are always closed before the next one is opened.
+    pub fn tick(&mut self, now: Instant) -> Vec<Action> {
+        let mut actions = vec![];
+        if let Some((zone, until)) = self.running {
+            if now < until {
+                return actions;
+            }
+            actions.push(Action::Close(zone));
+            self.running = None;
+        }
+        if let Some((zone, duration)) = self.queue.pop_front() {
+            actions.push(Action::Open(zone));
+            self.running = Some((zone, now + duration));
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+describe! irrigation_zones {
+    before_each {
+        use super::*;
+    }
+
+    it "should parse zones and programs" {
+        use chrono::NaiveDate;
+
+        let zones = parse_zones(r#"[{ "id": "lawn", "valve": { "id": "channel:relay" },
+            "programs": [{ "start": "06:30", "minutes": 20, "days": [0, 2] }] }]"#).unwrap();
+        assert_eq!(zones[0].name, "lawn");
+        let program = &zones[0].programs[0];
+        // 2016-07-04 is a Monday.
+        assert!(program.starts_at(&NaiveDate::from_ymd(2016, 7, 4).and_hms(6, 30, 10)));
+        assert!(!program.starts_at(&NaiveDate::from_ymd(2016, 7, 4).and_hms(6, 31, 0)));
+        assert!(!program.starts_at(&NaiveDate::from_ymd(2016, 7, 5).and_hms(6, 30, 0)));
+
+        assert!(parse_zones(r#"[{ "id": "lawn" }]"#).is_err());
+        assert!(parse_zones(r#"[{ "id": "lawn", "valve": { "id": "channel:relay" },
+            "programs": [{ "start": "06:30", "minutes": 20, "days": [7] }] }]"#).is_err());
+    }
+
+    it "should recognize rain" {
+        use serde_json;
+
+        assert!(is_raining(&serde_json::from_str(r#""On""#).unwrap()));
+        assert!(is_raining(&serde_json::from_str(r#"{"rain": 1.2, "temperature": 15}"#).unwrap()));
+        assert!(!is_raining(&serde_json::from_str(r#"{"temperature": 15}"#).unwrap()));
+        assert!(!is_raining(&serde_json::from_str("0").unwrap()));
+    }
+
+    it "should run zones one at a time" {
+        use std::time::{Duration, Instant};
+
+        let now = Instant::now();
+        let minute = Duration::from_secs(60);
+        let mut sequencer = Sequencer::new();
+        sequencer.enqueue(0, minute * 10, now);
+        sequencer.enqueue(1, minute * 5, now);
+        assert_eq!(sequencer.tick(now), vec![Action::Open(0)]);
+        assert_eq!(sequencer.tick(now + minute), vec![]);
+        assert_eq!(sequencer.tick(now + minute * 10), vec![Action::Close(0), Action::Open(1)]);
+        sequencer.stop(1, now + minute * 11);
+        assert_eq!(sequencer.tick(now + minute * 11), vec![Action::Close(1)]);
+        assert_eq!(sequencer.running(), None);
+    }
+}
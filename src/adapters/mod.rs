@@ -2,20 +2,122 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+/// An adapter exposing the alerts raised by the other adapters.
+mod alerts;
+
+/// An adapter exposing the health of the other adapters.
+mod health;
+
 /// An adapter providing time services.
 pub mod clock;
 
 /// An adapter displaying messages on the console.
 pub mod console;
 
+/// An adapter providing countdown timers.
+mod timers;
+
 /// A Text To Speak adapter
 #[cfg(target_os = "linux")]
 pub mod tts;
 
+/// An adapter monitoring the resources of the box.
+#[cfg(target_os = "linux")]
+mod host_monitor;
+
+/// An adapter for constrained devices speaking CoAP/LwM2M.
+#[cfg(feature = "coap")]
+mod coap;
+
+/// mDNS discovery, shared by adapters.
+#[cfg(any(feature = "ipp", feature = "esphome"))]
+mod avahi;
+
+/// An adapter providing access to ESPHome devices.
+#[cfg(feature = "esphome")]
+mod esphome;
+
+/// An adapter providing access to EnOcean devices.
+#[cfg(feature = "enocean")]
+mod enocean;
+
+/// An adapter providing access to 433MHz devices.
+#[cfg(feature = "rf433")]
+mod rf433;
+
+/// An adapter providing access to IPP network printers.
+#[cfg(feature = "ipp")]
+mod ipp;
+
 /// An adapter providing access to IP cameras.
 #[cfg(feature = "ip_camera")]
 mod ip_camera;
 
+/// An adapter providing access to Modbus devices.
+#[cfg(feature = "modbus")]
+mod modbus;
+
+/// An adapter detecting anomalies in numeric channels.
+#[cfg(feature = "analytics")]
+mod analytics;
+
+/// An adapter inferring the occupancy of rooms.
+#[cfg(feature = "occupancy")]
+mod occupancy;
+
+/// An adapter holding the security mode, armed when everybody is away.
+#[cfg(feature = "security")]
+mod security;
+
+/// An adapter turning lights on and off to make the house look occupied.
+#[cfg(feature = "presence")]
+mod presence;
+
+/// An adapter exposing devices made of the channels of other adapters.
+#[cfg(feature = "composite")]
+mod composite;
+
+/// An adapter exposing energy prices.
+#[cfg(feature = "tariff")]
+mod tariff;
+
+/// An adapter driving irrigation zones.
+#[cfg(feature = "irrigation")]
+mod irrigation;
+
+/// An adapter composing summary reports.
+#[cfg(feature = "reports")]
+mod reports;
+
+/// An adapter polling SNMP agents.
+#[cfg(feature = "snmp")]
+mod snmp;
+
+/// An adapter monitoring the Internet connection.
+#[cfg(feature = "wan")]
+mod wan;
+
+/// An adapter for Netatmo thermostats, through the Netatmo cloud.
+#[cfg(feature = "netatmo")]
+mod netatmo;
+
+/// An adapter controlling Spotify Connect devices.
+#[cfg(feature = "spotify")]
+mod spotify;
+
+/// An adapter driving legacy equipment through network IR blasters.
+#[cfg(feature = "ir")]
+mod ir;
+
+/// An adapter supervising external helper processes.
+mod supervisor;
+
+/// Adapters running in processes of their own.
+mod sandbox;
+
+/// An adapter switching between the global modes, e.g. "home" or "away".
+mod modes;
+
 /// An adapter dedicated to the Philips Hue
 #[cfg(feature = "philips_hue")]
 mod philips_hue;
@@ -28,7 +130,11 @@ mod thinkerbell;
 #[cfg(feature = "webpush")]
 pub mod webpush;
 
+use foxbox_core::adapter_status::{AdapterStatus, AdapterStatuses};
+use foxbox_taxonomy::adapter::AdapterManagerHandle;
+use foxbox_taxonomy::health::{AdapterHealth, HealthEvent};
 use foxbox_taxonomy::manager::AdapterManager as TaxoManager;
+use foxbox_taxonomy::util::{AdapterId, Id};
 
 #[cfg(feature = "thinkerbell")]
 use self::thinkerbell::ThinkerbellAdapter;
@@ -37,94 +143,689 @@ use foxbox_core::traits::Controller;
 #[cfg(feature = "zwave")]
 use openzwave;
 
-use std::sync::Arc;
+use std::cmp;
+use std::fmt::Debug;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Delay before retrying to start an adapter that failed, doubled after each failure.
+const INITIAL_RETRY_DELAY_S: u64 = 10;
+
+/// Longest delay between two attempts to start an adapter.
+const MAX_RETRY_DELAY_S: u64 = 600;
+
+type Init = Box<Fn(&Arc<TaxoManager>) -> Result<(), String> + Send>;
+
+/// An adapter that started, with the ids of the adapters that it registered, so that it may
+/// be started again if the watchdog finds it unhealthy.
+struct StartedInit {
+    name: String,
+    init: Init,
+    ids: Vec<Id<AdapterId>>,
+}
+
+type Started = Arc<Mutex<Vec<StartedInit>>>;
+
+/// An adapter that failed to start, waiting for its next attempt.
+struct PendingInit {
+    name: String,
+    init: Init,
+    attempts: u32,
+    delay: Duration,
+    next_attempt: Instant,
+}
 
 #[allow(dead_code)] // workaround for buggy "struct field is never used: `controller`" warning.
 pub struct AdapterManager<T> {
     controller: T,
+    statuses: AdapterStatuses,
+    pending: Vec<PendingInit>,
+    started: Started,
 }
 
 impl<T: Controller> AdapterManager<T> {
     pub fn new(controller: T) -> Self {
         debug!("Creating Adapter Manager");
-        AdapterManager { controller: controller }
+        AdapterManager {
+            statuses: controller.get_adapter_statuses(),
+            controller: controller,
+            pending: vec![],
+            started: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Run one attempt at starting an adapter, returning the ids of the adapters that it
+    /// registered. If it fails, remove whatever it registered, so that the next attempt starts
+    /// from a clean slate.
+    fn attempt(name: &str,
+               init: &Init,
+               manager: &Arc<TaxoManager>)
+               -> Result<Vec<Id<AdapterId>>, String> {
+        let before = manager.adapter_ids();
+        let result = init(manager);
+        let registered: Vec<_> = manager.adapter_ids()
+            .into_iter()
+            .filter(|id| !before.contains(id))
+            .collect();
+        if result.is_err() {
+            for id in &registered {
+                let _ = manager.remove_adapter(id);
+            }
+        }
+        match result {
+            Ok(()) => {
+                info!("Adapter {} started", name);
+                Ok(registered)
+            }
+            Err(err) => {
+                warn!("Adapter {} failed to start: {}", name, err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Start an adapter. If this fails, the rest of the box keeps booting and the adapter is
+    /// retried in the background, see `retry_failed`.
+    fn init<F, E>(&mut self, name: &str, manager: &Arc<TaxoManager>, init: F)
+        where F: Fn(&Arc<TaxoManager>) -> Result<(), E> + Send + 'static,
+              E: Debug
+    {
+        let init: Init = Box::new(move |manager: &Arc<TaxoManager>| {
+            init(manager).map_err(|err| format!("{:?}", err))
+        });
+        match Self::attempt(name, &init, manager) {
+            Ok(ids) => {
+                self.statuses.set(name, AdapterStatus::Running);
+                self.started.lock().unwrap().push(StartedInit {
+                    name: name.to_owned(),
+                    init: init,
+                    ids: ids,
+                });
+            }
+            Err(err) => {
+                let delay = Duration::from_secs(INITIAL_RETRY_DELAY_S);
+                self.statuses.set(name,
+                                  AdapterStatus::Failed {
+                                      error: err,
+                                      attempts: 1,
+                                      retry_in: delay.as_secs(),
+                                  });
+                self.pending.push(PendingInit {
+                    name: name.to_owned(),
+                    init: init,
+                    attempts: 1,
+                    delay: delay,
+                    next_attempt: Instant::now() + delay,
+                });
+            }
+        }
+    }
+
+    /// Retry the adapters that failed to start, with exponential backoff, until they all
+    /// started.
+    fn retry_failed(mut pending: Vec<PendingInit>,
+                    manager: Arc<TaxoManager>,
+                    statuses: AdapterStatuses,
+                    started: Started) {
+        while !pending.is_empty() {
+            // Wait for the earliest attempt.
+            let now = Instant::now();
+            let next = pending.iter().map(|item| item.next_attempt).min().unwrap();
+            if next > now {
+                thread::sleep(next - now);
+            }
+
+            let now = Instant::now();
+            let mut still_pending = vec![];
+            for mut item in pending.drain(..) {
+                if item.next_attempt > now {
+                    still_pending.push(item);
+                    continue;
+                }
+                match Self::attempt(&item.name, &item.init, &manager) {
+                    Ok(ids) => {
+                        statuses.set(&item.name, AdapterStatus::Running);
+                        started.lock().unwrap().push(StartedInit {
+                            name: item.name,
+                            init: item.init,
+                            ids: ids,
+                        });
+                    }
+                    Err(err) => {
+                        item.attempts += 1;
+                        item.delay = cmp::min(item.delay * 2,
+                                              Duration::from_secs(MAX_RETRY_DELAY_S));
+                        item.next_attempt = now + item.delay;
+                        statuses.set(&item.name,
+                                     AdapterStatus::Failed {
+                                         error: err,
+                                         attempts: item.attempts,
+                                         retry_in: item.delay.as_secs(),
+                                     });
+                        still_pending.push(item);
+                    }
+                }
+            }
+            pending = still_pending;
+        }
+    }
+
+    /// Start again the adapters that fail `restart_after` heartbeats in a row. If they fail to
+    /// start, they are retried in the background, as if they had failed to start at boot.
+    fn restart_unhealthy(events: mpsc::Receiver<HealthEvent>,
+                         restart_after: u32,
+                         manager: Arc<TaxoManager>,
+                         statuses: AdapterStatuses,
+                         started: Started) {
+        for event in events {
+            match event.health {
+                AdapterHealth::Unhealthy { failures, .. } if failures == restart_after => {}
+                _ => continue,
+            }
+            let item = {
+                let mut started = started.lock().unwrap();
+                match started.iter().position(|item| item.ids.contains(&event.adapter)) {
+                    Some(index) => started.remove(index),
+                    None => continue,
+                }
+            };
+            warn!("Restarting adapter {}, as {} does not answer its heartbeat",
+                  item.name,
+                  event.adapter);
+            for id in &item.ids {
+                let _ = manager.stop_adapter(id);
+            }
+            match Self::attempt(&item.name, &item.init, &manager) {
+                Ok(ids) => {
+                    statuses.set(&item.name, AdapterStatus::Running);
+                    started.lock().unwrap().push(StartedInit { ids: ids, ..item });
+                }
+                Err(err) => {
+                    let delay = Duration::from_secs(INITIAL_RETRY_DELAY_S);
+                    statuses.set(&item.name,
+                                 AdapterStatus::Failed {
+                                     error: err,
+                                     attempts: 1,
+                                     retry_in: delay.as_secs(),
+                                 });
+                    let pending = vec![PendingInit {
+                                           name: item.name,
+                                           init: item.init,
+                                           attempts: 1,
+                                           delay: delay,
+                                           next_attempt: Instant::now() + delay,
+                                       }];
+                    let manager = manager.clone();
+                    let statuses = statuses.clone();
+                    let started = started.clone();
+                    thread::Builder::new()
+                        .name("AdapterRetry".to_owned())
+                        .spawn(move || Self::retry_failed(pending, manager, statuses, started))
+                        .unwrap();
+                }
+            }
+        }
     }
 
     #[cfg(target_os = "linux")]
-    fn start_tts(&self, manager: &Arc<TaxoManager>) {
-        tts::init(manager).unwrap();
+    fn start_tts(&mut self, manager: &Arc<TaxoManager>) {
+        let config = self.controller.get_config();
+        let coalesce_window = config.get_or_set_default("tts", "coalesce_window", "10")
+            .parse()
+            .unwrap_or(10);
+        self.init("tts", manager, move |manager| {
+            tts::init(manager, Duration::from_secs(coalesce_window), config.clone())
+        });
     }
 
     #[cfg(not(target_os = "linux"))]
-    fn start_tts(&self, _: &Arc<TaxoManager>) {
+    fn start_tts(&mut self, _: &Arc<TaxoManager>) {
         info!("No tts support on this platform.");
     }
 
+    #[cfg(target_os = "linux")]
+    fn start_host_monitor(&mut self, manager: &Arc<TaxoManager>) {
+        let config = self.controller.get_config();
+        let poll_interval = config.get_or_set_default("host_monitor", "poll_interval", "30")
+            .parse()
+            .unwrap_or(30);
+        let path = self.controller.get_profile().path_for("");
+        self.init("host_monitor", manager, move |manager| {
+            host_monitor::HostMonitor::init(manager, &path, Duration::from_secs(poll_interval))
+        });
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn start_host_monitor(&mut self, _: &Arc<TaxoManager>) {
+        info!("No host monitoring on this platform.");
+    }
+
     #[cfg(feature = "zwave")]
-    fn start_zwave(&self, manager: &Arc<TaxoManager>) {
-        let profile_openzwave = &self.controller.get_profile().path_for("openzwave");
+    fn start_zwave(&mut self, manager: &Arc<TaxoManager>) {
+        // The adapter may rather run in a process of its own, see `sandbox`.
+        if self.controller.get_config().get_or_set_default("openzwave", "sandboxed", "false") ==
+           "true" {
+            info!("The OpenZWave adapter runs out of process.");
+            return;
+        }
+        let profile_openzwave = self.controller.get_profile().path_for("openzwave");
 
         let openzwave_devices = self.controller.clone().get_config().get("openzwave", "devices");
-        openzwave::Adapter::init(manager, profile_openzwave, openzwave_devices).unwrap(); // FIXME convert to a local error
+        // Exposing the configuration parameters of devices is reserved to advanced users.
+        let openzwave_advanced = self.controller
+            .get_config()
+            .get_or_set_default("openzwave", "advanced", "false") == "true";
+        self.init("zwave", manager, move |manager| {
+            openzwave::Adapter::init(manager,
+                                     &profile_openzwave,
+                                     openzwave_devices.clone(),
+                                     openzwave_advanced)
+        });
     }
 
     #[cfg(not(feature = "zwave"))]
-    fn start_zwave(&self, _: &Arc<TaxoManager>) {
+    fn start_zwave(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "enocean")]
+    fn start_enocean(&mut self, manager: &Arc<TaxoManager>) {
+        match self.controller.get_config().get("enocean", "device") {
+            Some(device) => {
+                self.init("enocean", manager, move |manager| {
+                    enocean::EnOceanAdapter::init(manager, &device)
+                })
+            }
+            None => info!("No EnOcean device configured."),
+        }
+    }
+
+    #[cfg(not(feature = "enocean"))]
+    fn start_enocean(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "rf433")]
+    fn start_rf433(&mut self, manager: &Arc<TaxoManager>) {
+        if !rf433::Rf433Adapter::is_configured(&self.controller.get_config()) {
+            info!("No 433MHz device configured and rtl_433 not found.");
+            return;
+        }
+        let controller = self.controller.clone();
+        self.init("rf433", manager, move |manager| {
+            rf433::Rf433Adapter::init(manager, controller.clone())
+        });
+    }
+
+    #[cfg(not(feature = "rf433"))]
+    fn start_rf433(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "modbus")]
+    fn start_modbus(&mut self, manager: &Arc<TaxoManager>) {
+        match self.controller.get_config().get("modbus", "descriptor") {
+            Some(path) => {
+                self.init("modbus", manager, move |manager| {
+                    modbus::ModbusAdapter::init(manager, &path)
+                })
+            }
+            None => info!("No Modbus register map configured."),
+        }
+    }
+
+    #[cfg(not(feature = "modbus"))]
+    fn start_modbus(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "snmp")]
+    fn start_snmp(&mut self, manager: &Arc<TaxoManager>) {
+        match self.controller.get_config().get("snmp", "descriptor") {
+            Some(path) => {
+                self.init("snmp",
+                          manager,
+                          move |manager| snmp::SnmpAdapter::init(manager, &path))
+            }
+            None => info!("No SNMP object map configured."),
+        }
+    }
+
+    #[cfg(not(feature = "snmp"))]
+    fn start_snmp(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "ipp")]
+    fn start_ipp(&mut self, manager: &Arc<TaxoManager>) {
+        self.init("ipp", manager, ipp::IppAdapter::init);
+    }
+
+    #[cfg(not(feature = "ipp"))]
+    fn start_ipp(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "wan")]
+    fn start_wan(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("wan",
+                  manager,
+                  move |manager| wan::WanAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "wan"))]
+    fn start_wan(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "netatmo")]
+    fn start_netatmo(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("netatmo",
+                  manager,
+                  move |manager| netatmo::NetatmoAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "netatmo"))]
+    fn start_netatmo(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "spotify")]
+    fn start_spotify(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("spotify",
+                  manager,
+                  move |manager| spotify::SpotifyAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "spotify"))]
+    fn start_spotify(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "ir")]
+    fn start_ir(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("ir",
+                  manager,
+                  move |manager| ir::IrAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "ir"))]
+    fn start_ir(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "coap")]
+    fn start_coap(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("coap",
+                  manager,
+                  move |manager| coap::CoapAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "coap"))]
+    fn start_coap(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "esphome")]
+    fn start_esphome(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("esphome",
+                  manager,
+                  move |manager| esphome::EsphomeAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "esphome"))]
+    fn start_esphome(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "reports")]
+    fn start_reports(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("reports",
+                  manager,
+                  move |manager| reports::ReportsAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "reports"))]
+    fn start_reports(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "analytics")]
+    fn start_analytics(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("analytics",
+                  manager,
+                  move |manager| analytics::AnalyticsAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "analytics"))]
+    fn start_analytics(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "occupancy")]
+    fn start_occupancy(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("occupancy",
+                  manager,
+                  move |manager| occupancy::OccupancyAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "occupancy"))]
+    fn start_occupancy(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "security")]
+    fn start_security(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("security",
+                  manager,
+                  move |manager| security::SecurityAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "security"))]
+    fn start_security(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "presence")]
+    fn start_presence(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("presence",
+                  manager,
+                  move |manager| presence::PresenceAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "presence"))]
+    fn start_presence(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "composite")]
+    fn start_composite(&mut self, manager: &Arc<TaxoManager>) {
+        let path = self.controller.get_profile().path_for("composites.json");
+        self.init("composite",
+                  manager,
+                  move |manager| composite::CompositeAdapter::init(manager, &path));
+    }
+
+    #[cfg(not(feature = "composite"))]
+    fn start_composite(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "tariff")]
+    fn start_tariff(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("tariff",
+                  manager,
+                  move |manager| tariff::TariffAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "tariff"))]
+    fn start_tariff(&mut self, _: &Arc<TaxoManager>) {
+        // nothing to see :)
+    }
+
+    #[cfg(feature = "irrigation")]
+    fn start_irrigation(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("irrigation",
+                  manager,
+                  move |manager| irrigation::IrrigationAdapter::init(manager, controller.clone()));
+    }
+
+    #[cfg(not(feature = "irrigation"))]
+    fn start_irrigation(&mut self, _: &Arc<TaxoManager>) {
         // nothing to see :)
     }
 
     #[cfg(feature = "philips_hue")]
-    fn start_philips_hue(&self, manager: &Arc<TaxoManager>) {
-        philips_hue::PhilipsHueAdapter::init(manager, self.controller.clone()).unwrap();
+    fn start_philips_hue(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("philips_hue",
+                  manager,
+                  move |manager| philips_hue::PhilipsHueAdapter::init(manager, controller.clone()));
     }
 
     #[cfg(not(feature = "philips_hue"))]
-    fn start_philips_hue(&self, _: &Arc<TaxoManager>) {
+    fn start_philips_hue(&mut self, _: &Arc<TaxoManager>) {
         // nothing to see :)
     }
 
     #[cfg(feature = "thinkerbell")]
-    fn start_thinkerbell(&self, manager: &Arc<TaxoManager>) {
-        let scripts_path = &self.controller.get_profile().path_for("thinkerbell_scripts.sqlite");
-        ThinkerbellAdapter::init(manager, scripts_path).unwrap(); // FIXME: no unwrap!
+    fn start_thinkerbell(&mut self, manager: &Arc<TaxoManager>) {
+        let scripts_path = self.controller.get_profile().path_for("thinkerbell_scripts.sqlite");
+        let timeline = self.controller.get_timeline();
+        let config = self.controller.get_config();
+        self.init("thinkerbell", manager, move |manager| {
+            ThinkerbellAdapter::init(manager, &scripts_path, timeline.clone(), config.clone())
+        });
     }
 
     #[cfg(not(feature = "thinkerbell"))]
-    fn start_thinkerbell(&self, _: &Arc<TaxoManager>) {
+    fn start_thinkerbell(&mut self, _: &Arc<TaxoManager>) {
         // nothing to see :)
     }
 
     #[cfg(feature = "webpush")]
-    fn start_webpush(&self, manager: &Arc<TaxoManager>) {
-        webpush::WebPush::init(self.controller.clone(), manager).unwrap();
+    fn start_webpush(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("webpush",
+                  manager,
+                  move |manager| webpush::WebPush::init(controller.clone(), manager));
     }
 
     #[cfg(not(feature = "webpush"))]
-    fn start_webpush(&self, _: &Arc<TaxoManager>) {
+    fn start_webpush(&mut self, _: &Arc<TaxoManager>) {
         // nothing to see :)
     }
 
     #[cfg(feature = "ip_camera")]
-    fn start_ip_camera(&self, manager: &Arc<TaxoManager>) {
-        ip_camera::IPCameraAdapter::init(manager, self.controller.clone()).unwrap();
+    fn start_ip_camera(&mut self, manager: &Arc<TaxoManager>) {
+        let controller = self.controller.clone();
+        self.init("ip_camera", manager, move |manager| {
+            ip_camera::IPCameraAdapter::init(manager, controller.clone())
+        });
     }
 
     #[cfg(not(feature = "ip_camera"))]
-    fn start_ip_camera(&self, _: &Arc<TaxoManager>) {
+    fn start_ip_camera(&mut self, _: &Arc<TaxoManager>) {
         // nothing to see :)
     }
 
-    /// Start all the adapters.
+    /// Start all the adapters. Adapters failing to start don't prevent the others from
+    /// starting, and are retried in the background. Their status is available through
+    /// `Controller::get_adapter_statuses`.
     pub fn start(&mut self, manager: &Arc<TaxoManager>) {
-        console::Console::init(manager).unwrap(); // FIXME: We should have a way to report errors
-        clock::Clock::init(manager).unwrap(); // FIXME: We should have a way to report errors
+        self.init("console", manager, console::Console::init);
+        self.init("clock", manager, clock::Clock::init);
+        let controller = self.controller.clone();
+        self.init("timers", manager, move |manager| {
+            timers::TimersAdapter::init(manager, controller.clone())
+        });
+        let controller = self.controller.clone();
+        self.init("alerts", manager, move |manager| {
+            alerts::AlertsAdapter::init(manager, controller.clone())
+        });
+        self.init("health", manager, health::HealthAdapter::init);
+        let controller = self.controller.clone();
+        self.init("supervisor", manager, move |manager| {
+            supervisor::Supervisor::init(manager, controller.clone())
+        });
+        let controller = self.controller.clone();
+        self.init("sandbox", manager, move |manager| {
+            sandbox::SandboxedAdapter::init(manager, controller.clone())
+        });
+        let controller = self.controller.clone();
+        self.init("modes", manager, move |manager| {
+            modes::ModesAdapter::init(manager, controller.clone())
+        });
 
         self.start_webpush(manager);
         self.start_ip_camera(manager);
         self.start_thinkerbell(manager);
         self.start_philips_hue(manager);
         self.start_zwave(manager);
+        self.start_enocean(manager);
+        self.start_rf433(manager);
+        self.start_modbus(manager);
+        self.start_ipp(manager);
+        self.start_wan(manager);
+        self.start_netatmo(manager);
+        self.start_spotify(manager);
+        self.start_ir(manager);
+        self.start_coap(manager);
+        self.start_esphome(manager);
+        self.start_snmp(manager);
+        self.start_reports(manager);
+        self.start_analytics(manager);
+        self.start_occupancy(manager);
+        self.start_security(manager);
+        self.start_presence(manager);
+        self.start_composite(manager);
+        self.start_tariff(manager);
+        self.start_irrigation(manager);
         self.start_tts(manager);
+        self.start_host_monitor(manager);
+
+        if !self.pending.is_empty() {
+            let pending = self.pending.drain(..).collect();
+            let manager = manager.clone();
+            let statuses = self.statuses.clone();
+            let started = self.started.clone();
+            thread::Builder::new()
+                .name("AdapterRetry".to_owned())
+                .spawn(move || Self::retry_failed(pending, manager, statuses, started))
+                .unwrap();
+        }
+
+        let config = self.controller.get_config();
+        if config.get_or_set_default("watchdog", "restart", "false") == "true" {
+            let restart_after = config.get_or_set_default("watchdog", "restart_after", "3")
+                .parse()
+                .unwrap_or(3);
+            let (tx, rx) = mpsc::channel();
+            manager.watch_adapter_health(Box::new(tx));
+            let manager = manager.clone();
+            let statuses = self.statuses.clone();
+            let started = self.started.clone();
+            thread::Builder::new()
+                .name("AdapterRestart".to_owned())
+                .spawn(move || {
+                    Self::restart_unhealthy(rx, restart_after, manager, statuses, started)
+                })
+                .unwrap();
+        }
     }
 
     /// Stop all the adapters.
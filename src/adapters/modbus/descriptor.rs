@@ -0,0 +1,208 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The register map describing which Modbus devices to talk to, and how their registers
+//! map to channels.
+//!
+//! ```json
+//! { "devices": [{
+//!     "id": "heat-pump",
+//!     "model": "Acme HP-9",
+//!     "tcp": "192.168.1.20:502",
+//!     "unit": 1,
+//!     "poll_interval": 30,
+//!     "registers": [
+//!       { "address": 40, "kind": "input", "type": "i16", "scale": 0.1,
+//!         "feature": "heat-pump/flow-temperature" },
+//!       { "address": 12, "kind": "holding", "type": "u16", "feature": "heat-pump/mode",
+//!         "writable": true }
+//!     ]
+//! }]}
+//! ```
+//!
+//! RTU devices use `"rtu": "/dev/ttyUSB0"` and an optional `"baud_rate"` instead of `"tcp"`.
+
+use serde_json;
+
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+
+#[derive(Deserialize, Debug)]
+pub struct Descriptor {
+    pub devices: Vec<DeviceDescriptor>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeviceDescriptor {
+    pub id: String,
+    pub model: Option<String>,
+    pub tcp: Option<String>,
+    pub rtu: Option<String>,
+    pub baud_rate: Option<u32>,
+    pub unit: Option<u8>,
+    /// Seconds between two polls. Defaults to 60.
+    pub poll_interval: Option<u64>,
+    pub registers: Vec<RegisterDescriptor>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RegisterDescriptor {
+    pub address: u16,
+    /// One of "holding" (the default), "input", "coil" or "discrete".
+    pub kind: Option<String>,
+    /// One of "u16" (the default), "i16", "u32", "i32" or "f32". 32 bits values are
+    /// stored high word first. Ignored for coils and discrete inputs.
+    #[serde(rename="type")]
+    pub data_type: Option<String>,
+    /// The raw value is multiplied by `scale` before being exposed.
+    pub scale: Option<f64>,
+    pub feature: String,
+    pub writable: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegisterKind {
+    Holding,
+    Input,
+    Coil,
+    Discrete,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataType {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl DataType {
+    /// The number of 16 bits registers used by a value of this type.
+    pub fn words(&self) -> u16 {
+        match *self {
+            DataType::U16 | DataType::I16 => 1,
+            DataType::U32 | DataType::I32 | DataType::F32 => 2,
+        }
+    }
+
+    pub fn decode(&self, words: &[u16]) -> f64 {
+        let long = if words.len() >= 2 {
+            (words[0] as u32) << 16 | words[1] as u32
+        } else {
+            0
+        };
+        match *self {
+            DataType::U16 => words[0] as f64,
+            DataType::I16 => words[0] as i16 as f64,
+            DataType::U32 => long as f64,
+            DataType::I32 => long as i32 as f64,
+            DataType::F32 => unsafe { mem::transmute::<u32, f32>(long) } as f64,
+        }
+    }
+
+    pub fn encode(&self, value: f64) -> Vec<u16> {
+        let long = match *self {
+            DataType::U16 => return vec![value.round() as u16],
+            DataType::I16 => return vec![value.round() as i16 as u16],
+            DataType::U32 => value.round() as u32,
+            DataType::I32 => value.round() as i32 as u32,
+            DataType::F32 => unsafe { mem::transmute::<f32, u32>(value as f32) },
+        };
+        vec![(long >> 16) as u16, long as u16]
+    }
+}
+
+impl RegisterDescriptor {
+    pub fn kind(&self) -> Result<RegisterKind, String> {
+        match self.kind.as_ref().map(|kind| kind as &str) {
+            None | Some("holding") => Ok(RegisterKind::Holding),
+            Some("input") => Ok(RegisterKind::Input),
+            Some("coil") => Ok(RegisterKind::Coil),
+            Some("discrete") => Ok(RegisterKind::Discrete),
+            Some(other) => Err(format!("Unknown register kind {}", other)),
+        }
+    }
+
+    pub fn data_type(&self) -> Result<DataType, String> {
+        match self.data_type.as_ref().map(|data_type| data_type as &str) {
+            None | Some("u16") => Ok(DataType::U16),
+            Some("i16") => Ok(DataType::I16),
+            Some("u32") => Ok(DataType::U32),
+            Some("i32") => Ok(DataType::I32),
+            Some("f32") => Ok(DataType::F32),
+            Some(other) => Err(format!("Unknown register type {}", other)),
+        }
+    }
+
+    /// Only holding registers and coils may be written to.
+    pub fn is_writable(&self) -> bool {
+        self.writable.unwrap_or(false) &&
+        match self.kind() {
+            Ok(RegisterKind::Holding) | Ok(RegisterKind::Coil) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Descriptor {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let mut source = String::new();
+        try!(File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut source))
+            .map_err(|err| format!("Could not read {}: {}", path, err)));
+        Self::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let descriptor: Descriptor = try!(serde_json::from_str(source)
+            .map_err(|err| format!("Invalid Modbus descriptor: {}", err)));
+        // Check everything now rather than when polling.
+        for device in &descriptor.devices {
+            if device.tcp.is_some() == device.rtu.is_some() {
+                return Err(format!("Device {} needs exactly one of `tcp` or `rtu`", device.id));
+            }
+            for register in &device.registers {
+                try!(register.kind());
+                try!(register.data_type());
+            }
+        }
+        Ok(descriptor)
+    }
+}
+
+#[cfg(test)]
+describe! modbus_descriptor {
+    before_each {
+        use super::*;
+    }
+
+    it "should decode and encode register values" {
+        assert_eq!(DataType::I16.decode(&[0xFFF6]), -10.0);
+        assert_eq!(DataType::U32.decode(&[0x0001, 0x0002]), 65538.0);
+        assert_eq!(DataType::F32.decode(&DataType::F32.encode(21.5)), 21.5);
+        assert_eq!(DataType::I32.encode(-2.0), vec![0xFFFF, 0xFFFE]);
+    }
+
+    it "should parse a descriptor" {
+        let descriptor = Descriptor::parse(r#"{"devices": [{
+            "id": "meter", "tcp": "127.0.0.1:502",
+            "registers": [
+                { "address": 12, "kind": "input", "type": "f32", "feature": "energy/power" },
+                { "address": 3, "kind": "coil", "feature": "relay/is-on", "writable": true }
+            ]}]}"#).unwrap();
+        let registers = &descriptor.devices[0].registers;
+        assert_eq!(registers[0].data_type(), Ok(DataType::F32));
+        assert!(!registers[0].is_writable());
+        assert_eq!(registers[1].kind(), Ok(RegisterKind::Coil));
+        assert!(registers[1].is_writable());
+    }
+
+    it "should reject inconsistent descriptors" {
+        assert!(Descriptor::parse(r#"{"devices": [{"id": "a", "registers": []}]}"#).is_err());
+        assert!(Descriptor::parse(r#"{"devices": [{"id": "a", "tcp": "h:502",
+            "registers": [{ "address": 1, "type": "u64", "feature": "x/y" }]}]}"#).is_err());
+    }
+}
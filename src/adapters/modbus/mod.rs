@@ -0,0 +1,335 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter for Modbus TCP and RTU devices, such as heat pumps, inverters and energy
+//! meters.
+//!
+//! Modbus devices can't describe themselves, so the adapter is driven by a register map
+//! (see `descriptor`), whose path is configured in `modbus.descriptor`. Each device becomes
+//! a service and each register a channel. Registers are exposed as JSON numbers (after
+//! scaling), coils and discrete inputs as `OnOff`. Devices are polled periodically so that
+//! channels can be watched.
+
+mod descriptor;
+mod protocol;
+
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, OnOff, Value};
+
+use self::descriptor::{DataType, Descriptor, DeviceDescriptor, RegisterDescriptor, RegisterKind};
+use self::protocol::Link;
+
+use serde_json::value::Value as JSON;
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static ADAPTER_NAME: &'static str = "Modbus adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const DEFAULT_POLL_INTERVAL_S: u64 = 60;
+const DEFAULT_BAUD_RATE: u32 = 9600;
+
+/// A device, and the connection to it, opened lazily.
+struct Device {
+    tcp: Option<String>,
+    rtu: Option<String>,
+    baud_rate: u32,
+    unit: u8,
+    link: Option<Link>,
+}
+
+impl Device {
+    fn new(descriptor: &DeviceDescriptor) -> Self {
+        Device {
+            tcp: descriptor.tcp.clone(),
+            rtu: descriptor.rtu.clone(),
+            baud_rate: descriptor.baud_rate.unwrap_or(DEFAULT_BAUD_RATE),
+            // 255 is the conventional unit for TCP devices that don't care.
+            unit: descriptor.unit.unwrap_or(if descriptor.tcp.is_some() { 255 } else { 1 }),
+            link: None,
+        }
+    }
+
+    fn transact(&mut self, pdu: &[u8]) -> io::Result<Vec<u8>> {
+        if self.link.is_none() {
+            self.link = Some(try!(match (&self.tcp, &self.rtu) {
+                (&Some(ref address), _) => Link::tcp(address),
+                (_, &Some(ref device)) => Link::rtu(device, self.baud_rate),
+                _ => unreachable!(), // Rejected when parsing the descriptor.
+            }));
+        }
+        let unit = self.unit;
+        let result = self.link.as_mut().unwrap().transact(unit, pdu);
+        if result.is_err() {
+            // Reconnect next time, the link may be in an unknown state.
+            self.link = None;
+        }
+        result
+    }
+
+    fn read(&mut self, register: &RegisterDescriptor) -> Result<Value, Error> {
+        let (kind, data_type) = try!(checked_register(register));
+        let result = match kind {
+            RegisterKind::Coil | RegisterKind::Discrete => {
+                let function = if kind == RegisterKind::Coil {
+                    protocol::READ_COILS
+                } else {
+                    protocol::READ_DISCRETE_INPUTS
+                };
+                let response = try!(self.transact(&protocol::read_request(function,
+                                                                          register.address,
+                                                                          1))
+                    .map_err(io_error));
+                protocol::parse_bit(function, &response)
+                    .map(|on| Value::new(if on { OnOff::On } else { OnOff::Off }))
+            }
+            RegisterKind::Holding | RegisterKind::Input => {
+                let function = if kind == RegisterKind::Holding {
+                    protocol::READ_HOLDING_REGISTERS
+                } else {
+                    protocol::READ_INPUT_REGISTERS
+                };
+                let response = try!(self.transact(&protocol::read_request(function,
+                                                                          register.address,
+                                                                          data_type.words()))
+                    .map_err(io_error));
+                protocol::parse_registers(function, &response).and_then(|words| {
+                    if words.len() < data_type.words() as usize {
+                        return Err(format!("Expected {} registers", data_type.words()));
+                    }
+                    let raw = data_type.decode(&words);
+                    Ok(Value::new(Json(number_to_json(raw, data_type, register.scale))))
+                })
+            }
+        };
+        result.map_err(|err| Error::Internal(InternalError::GenericError(err)))
+    }
+
+    fn write(&mut self, register: &RegisterDescriptor, value: &Value) -> Result<(), Error> {
+        let (kind, data_type) = try!(checked_register(register));
+        let (function, pdu) = if kind == RegisterKind::Coil {
+            let on = try!(value.cast::<OnOff>()) == &OnOff::On;
+            (protocol::WRITE_SINGLE_COIL, protocol::write_coil_request(register.address, on))
+        } else {
+            let json = try!(value.cast::<Json>());
+            let number = match json.0.as_f64() {
+                Some(number) => number,
+                None => return Err(Error::InvalidValue),
+            };
+            let words = data_type.encode(number / register.scale.unwrap_or(1.0));
+            let function = if words.len() == 1 {
+                protocol::WRITE_SINGLE_REGISTER
+            } else {
+                protocol::WRITE_MULTIPLE_REGISTERS
+            };
+            (function, protocol::write_registers_request(register.address, &words))
+        };
+        let response = try!(self.transact(&pdu).map_err(io_error));
+        protocol::check_response(function, &response)
+            .map(|_| ())
+            .map_err(|err| Error::Internal(InternalError::GenericError(err)))
+    }
+}
+
+fn checked_register(register: &RegisterDescriptor) -> Result<(RegisterKind, DataType), Error> {
+    match (register.kind(), register.data_type()) {
+        (Ok(kind), Ok(data_type)) => Ok((kind, data_type)),
+        (Err(err), _) | (_, Err(err)) => Err(Error::Internal(InternalError::GenericError(err))),
+    }
+}
+
+fn io_error(err: io::Error) -> Error {
+    Error::Internal(InternalError::GenericError(format!("{}", err)))
+}
+
+/// Integer registers without a scale are exposed as integers, everything else as floats.
+fn number_to_json(raw: f64, data_type: DataType, scale: Option<f64>) -> JSON {
+    match (data_type, scale) {
+        (DataType::F32, _) | (_, Some(_)) => JSON::F64(raw * scale.unwrap_or(1.0)),
+        _ if raw < 0.0 => JSON::I64(raw as i64),
+        _ => JSON::U64(raw as u64),
+    }
+}
+
+struct Register {
+    device: Arc<Mutex<Device>>,
+    descriptor: RegisterDescriptor,
+}
+
+pub struct ModbusAdapter {
+    registers: HashMap<Id<Channel>, Register>,
+    watchers: ValueWatchers,
+}
+
+impl ModbusAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("modbus@link.mozilla.org")
+    }
+
+    pub fn service_id(device: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.modbus@link.mozilla.org", device))
+    }
+
+    pub fn channel_id(device: &str, register: &RegisterDescriptor) -> Id<Channel> {
+        Id::new(&format!("channel:{}-{}.{}.modbus@link.mozilla.org",
+                         register.kind.clone().unwrap_or_else(|| "holding".to_owned()),
+                         register.address,
+                         device))
+    }
+
+    pub fn init(manager: &Arc<AdapterManager>, descriptor_path: &str) -> Result<(), Error> {
+        let descriptor = try!(Descriptor::from_file(descriptor_path)
+            .map_err(|err| Error::Internal(InternalError::GenericError(err))));
+
+        let mut registers = HashMap::new();
+        let mut pollers = Vec::new();
+        for device_descriptor in &descriptor.devices {
+            let device = Arc::new(Mutex::new(Device::new(device_descriptor)));
+            let mut polled = Vec::new();
+            for register in &device_descriptor.registers {
+                let id = Self::channel_id(&device_descriptor.id, register);
+                registers.insert(id.clone(),
+                                 Register {
+                                     device: device.clone(),
+                                     descriptor: register.clone(),
+                                 });
+                polled.push((id, register.clone()));
+            }
+            let interval = device_descriptor.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL_S);
+            pollers.push((device, polled, interval));
+        }
+
+        let watchers = ValueWatchers::new();
+        let adapter = Arc::new(ModbusAdapter {
+            registers: registers,
+            watchers: watchers.clone(),
+        });
+        try!(manager.add_adapter(adapter.clone()));
+
+        for device in &descriptor.devices {
+            let service_id = Self::service_id(&device.id);
+            let mut service = Service::empty(&service_id, &Self::id());
+            service.properties.insert("model".to_owned(),
+                                      device.model
+                                          .clone()
+                                          .unwrap_or_else(|| "Modbus device".to_owned()));
+            try!(manager.add_service(service));
+            for register in &device.registers {
+                let format = match register.kind() {
+                    Ok(RegisterKind::Coil) |
+                    Ok(RegisterKind::Discrete) => format::ON_OFF.clone(),
+                    _ => format::JSON.clone(),
+                };
+                try!(manager.add_channel(Channel {
+                    id: Self::channel_id(&device.id, register),
+                    service: service_id.clone(),
+                    adapter: Self::id(),
+                    feature: Id::new(&register.feature),
+                    supports_fetch: Some(Signature::returns(Maybe::Required(format.clone()))),
+                    supports_watch: Some(Signature::returns(Maybe::Required(format.clone()))),
+                    supports_send: if register.is_writable() {
+                        Some(Signature::accepts(Maybe::Required(format)))
+                    } else {
+                        None
+                    },
+                    ..Channel::default()
+                }));
+            }
+        }
+
+        for (device, polled, interval) in pollers {
+            let watchers = watchers.clone();
+            thread::spawn(move || {
+                loop {
+                    for &(ref id, ref register) in &polled {
+                        let result = device.lock().unwrap().read(register);
+                        match result {
+                            Ok(value) => watchers.update(id, value),
+                            Err(err) => warn!("[modbus] Could not poll {}: {:?}", id, err),
+                        }
+                    }
+                    thread::sleep(Duration::from_secs(interval));
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Adapter for ModbusAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                let register = match self.registers.get(&id) {
+                    Some(register) => register,
+                    None => {
+                        return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+                    }
+                };
+                let result = register.device.lock().unwrap().read(&register.descriptor);
+                match result {
+                    Ok(value) => {
+                        self.watchers.update(&id, value.clone());
+                        (id, Ok(Some(value)))
+                    }
+                    Err(err) => (id, Err(err)),
+                }
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                let register = match self.registers.get(&id) {
+                    Some(register) if register.descriptor.is_writable() => register,
+                    Some(_) => {
+                        return (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id)))
+                    }
+                    None => {
+                        return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+                    }
+                };
+                let result = register.device.lock().unwrap().write(&register.descriptor, &value);
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Just enough of the Modbus protocol to poll and write registers and coils, over either
+//! TCP (MBAP header) or RTU (serial line, CRC16).
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::time::Duration;
+
+pub const READ_COILS: u8 = 0x01;
+pub const READ_DISCRETE_INPUTS: u8 = 0x02;
+pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+pub const READ_INPUT_REGISTERS: u8 = 0x04;
+pub const WRITE_SINGLE_COIL: u8 = 0x05;
+pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+pub const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+/// A request for reading `count` items starting at `address`.
+pub fn read_request(function: u8, address: u16, count: u16) -> Vec<u8> {
+    vec![function, (address >> 8) as u8, address as u8, (count >> 8) as u8, count as u8]
+}
+
+pub fn write_coil_request(address: u16, on: bool) -> Vec<u8> {
+    vec![WRITE_SINGLE_COIL, (address >> 8) as u8, address as u8, if on { 0xFF } else { 0x00 }, 0x00]
+}
+
+pub fn write_registers_request(address: u16, words: &[u16]) -> Vec<u8> {
+    if words.len() == 1 {
+        return vec![WRITE_SINGLE_REGISTER,
+                    (address >> 8) as u8,
+                    address as u8,
+                    (words[0] >> 8) as u8,
+                    words[0] as u8];
+    }
+    let mut pdu = vec![WRITE_MULTIPLE_REGISTERS,
+                       (address >> 8) as u8,
+                       address as u8,
+                       0,
+                       words.len() as u8,
+                       (words.len() * 2) as u8];
+    for word in words {
+        pdu.push((word >> 8) as u8);
+        pdu.push(*word as u8);
+    }
+    pdu
+}
+
+/// Extract the registers from the response to a `READ_HOLDING_REGISTERS` or
+/// `READ_INPUT_REGISTERS` request.
+pub fn parse_registers(function: u8, pdu: &[u8]) -> Result<Vec<u16>, String> {
+    let payload = try!(check_response(function, pdu));
+    if payload.is_empty() || payload[0] as usize != payload.len() - 1 || payload.len() % 2 == 0 {
+        return Err(format!("Malformed response {:?}", pdu));
+    }
+    Ok(payload[1..].chunks(2).map(|word| (word[0] as u16) << 8 | word[1] as u16).collect())
+}
+
+/// Extract the first bit from the response to a `READ_COILS` or `READ_DISCRETE_INPUTS`
+/// request.
+pub fn parse_bit(function: u8, pdu: &[u8]) -> Result<bool, String> {
+    let payload = try!(check_response(function, pdu));
+    if payload.len() < 2 {
+        return Err(format!("Malformed response {:?}", pdu));
+    }
+    Ok(payload[1] & 1 == 1)
+}
+
+/// Check that a response matches the function we invoked, returning the rest of the PDU.
+pub fn check_response(function: u8, pdu: &[u8]) -> Result<&[u8], String> {
+    match pdu.first() {
+        Some(code) if *code == function => Ok(&pdu[1..]),
+        Some(code) if *code == function | 0x80 => {
+            Err(format!("Device returned exception code {}", pdu.get(1).cloned().unwrap_or(0)))
+        }
+        _ => Err(format!("Unexpected response {:?}", pdu)),
+    }
+}
+
+/// The CRC used by Modbus RTU. It is transmitted low byte first.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+pub fn rtu_frame(unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(pdu.len() + 3);
+    frame.push(unit);
+    frame.extend_from_slice(pdu);
+    let crc = crc16(&frame);
+    frame.push(crc as u8);
+    frame.push((crc >> 8) as u8);
+    frame
+}
+
+pub fn tcp_frame(transaction: u16, unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let length = pdu.len() + 1;
+    let mut frame = vec![(transaction >> 8) as u8,
+                         transaction as u8,
+                         0,
+                         0, // Protocol identifier: always 0 for Modbus.
+                         (length >> 8) as u8,
+                         length as u8,
+                         unit];
+    frame.extend_from_slice(pdu);
+    frame
+}
+
+/// A connection to a Modbus device.
+pub enum Link {
+    Tcp {
+        stream: TcpStream,
+        transaction: u16,
+    },
+    Rtu { file: File },
+}
+
+impl Link {
+    pub fn tcp(address: &str) -> io::Result<Self> {
+        let stream = try!(TcpStream::connect(address));
+        try!(stream.set_read_timeout(Some(Duration::from_secs(5))));
+        Ok(Link::Tcp {
+            stream: stream,
+            transaction: 0,
+        })
+    }
+
+    pub fn rtu(device: &str, baud_rate: u32) -> io::Result<Self> {
+        // Modbus RTU is almost always 8N1.
+        let status = try!(Command::new("stty")
+            .args(&["-F", device, &format!("{}", baud_rate), "raw", "-echo", "cs8", "-cstopb",
+                    "-parenb"])
+            .status());
+        if !status.success() {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("Could not configure {}", device)));
+        }
+        let file = try!(OpenOptions::new().read(true).write(true).open(device));
+        Ok(Link::Rtu { file: file })
+    }
+
+    /// Send a request to a unit and return the PDU of its response.
+    pub fn transact(&mut self, unit: u8, pdu: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            Link::Tcp { ref mut stream, ref mut transaction } => {
+                *transaction = transaction.wrapping_add(1);
+                try!(stream.write_all(&tcp_frame(*transaction, unit, pdu)));
+                let mut header = [0; 7];
+                try!(stream.read_exact(&mut header));
+                let length = (header[4] as usize) << 8 | header[5] as usize;
+                if length < 2 || (header[0] as u16) << 8 | header[1] as u16 != *transaction {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad MBAP header"));
+                }
+                let mut response = vec![0; length - 1];
+                try!(stream.read_exact(&mut response));
+                Ok(response)
+            }
+            Link::Rtu { ref mut file } => {
+                try!(file.write_all(&rtu_frame(unit, pdu)));
+                let mut frame = vec![0; 2];
+                try!(file.read_exact(&mut frame));
+                // The length of the rest of the frame depends on the function.
+                let remaining = match frame[1] {
+                    code if code & 0x80 != 0 => 1,
+                    READ_COILS | READ_DISCRETE_INPUTS | READ_HOLDING_REGISTERS |
+                    READ_INPUT_REGISTERS => {
+                        let mut count = [0];
+                        try!(file.read_exact(&mut count));
+                        frame.push(count[0]);
+                        count[0] as usize
+                    }
+                    _ => 4,
+                };
+                let start = frame.len();
+                frame.resize(start + remaining + 2, 0);
+                try!(file.read_exact(&mut frame[start..]));
+                let (body, crc) = frame.split_at(frame.len() - 2);
+                if crc16(body) != (crc[1] as u16) << 8 | crc[0] as u16 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad CRC"));
+                }
+                Ok(body[1..].to_vec())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+describe! modbus_protocol {
+    before_each {
+        use super::*;
+    }
+
+    it "should compute the RTU CRC" {
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]), 0xCDC5);
+        assert_eq!(rtu_frame(1, &read_request(READ_HOLDING_REGISTERS, 0, 10)),
+                   vec![0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0xCD]);
+    }
+
+    it "should frame TCP requests" {
+        assert_eq!(tcp_frame(0x0102, 0x11, &read_request(READ_INPUT_REGISTERS, 0x6B, 3)),
+                   vec![0x01, 0x02, 0x00, 0x00, 0x00, 0x06, 0x11, 0x04, 0x00, 0x6B, 0x00, 0x03]);
+    }
+
+    it "should encode register writes" {
+        assert_eq!(write_registers_request(1, &[0x0003]), vec![0x06, 0x00, 0x01, 0x00, 0x03]);
+        assert_eq!(write_registers_request(1, &[0x000A, 0x0102]),
+                   vec![0x10, 0x00, 0x01, 0x00, 0x02, 0x04, 0x00, 0x0A, 0x01, 0x02]);
+    }
+
+    it "should parse responses and exceptions" {
+        assert_eq!(parse_registers(READ_HOLDING_REGISTERS, &[0x03, 0x04, 0x02, 0x2B, 0x00, 0x64]),
+                   Ok(vec![0x022B, 0x0064]));
+        assert!(parse_registers(READ_HOLDING_REGISTERS, &[0x83, 0x02]).is_err());
+        assert!(parse_registers(READ_HOLDING_REGISTERS, &[0x03, 0x04, 0x02]).is_err());
+        assert_eq!(parse_bit(READ_COILS, &[0x01, 0x01, 0x01]), Ok(true));
+    }
+}
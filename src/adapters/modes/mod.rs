@@ -0,0 +1,308 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter switching between the global modes of the box, e.g. "home", "away" or
+//! "vacation", see `foxbox_core::modes`.
+//!
+//! The `modes` service exposes `mode/current` (fetch/send/watch): the name of the current
+//! mode, as a string. Entering a mode:
+//! - overrides the properties of the configuration listed by the mode, until another mode
+//!   is entered;
+//! - enables and disables the Thinkerbell rules listed by the mode;
+//! - happens when a name is sent to `mode/current`, or at the times of day listed by the
+//!   mode (`starts_at`).
+//!
+//! Modes are defined through `api/v1/modes`.
+
+use foxbox_core::config_store::ConfigService;
+use foxbox_core::modes::{Mode, ModeRegistry, OVERRIDABLE_NAMESPACES};
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{API, Error, InternalError, Operation, Targetted, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::io::Payload;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, OnOff, Value};
+
+use chrono::{Local, Timelike};
+
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static ADAPTER_NAME: &'static str = "Modes adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const SCHEDULE_POLL_INTERVAL_S: u64 = 20;
+
+pub struct ModesAdapter {
+    modes: ModeRegistry,
+    watchers: ValueWatchers,
+    /// Modes are entered on a thread of their own, as entering a mode sends values to other
+    /// adapters through the `AdapterManager`.
+    tx: Mutex<mpsc::Sender<String>>,
+}
+
+/// Enters modes, and keeps track of the properties of the configuration that they override.
+struct Switch {
+    manager: Arc<AdapterManager>,
+    config: Arc<ConfigService>,
+    modes: ModeRegistry,
+    watchers: ValueWatchers,
+    /// The properties overridden by the current mode, as (namespace, key).
+    overridden: Vec<(String, String)>,
+}
+
+impl Switch {
+    fn apply_config(&mut self, mode: &Mode) {
+        for (namespace, key) in self.overridden.drain(..) {
+            self.config.remove_override(&namespace, &key);
+        }
+        for item in &mode.config {
+            if !OVERRIDABLE_NAMESPACES.contains(&item.namespace.as_str()) {
+                warn!("[modes] Mode {} may not override namespace {}", mode.name, item.namespace);
+                continue;
+            }
+            self.config.set_override(&item.namespace, &item.key, &item.value);
+            self.overridden.push((item.namespace.clone(), item.key.clone()));
+        }
+    }
+
+    fn set_rule_enabled(&self, rule: &str, enabled: bool) {
+        let payload =
+            Payload::from_data(if enabled { OnOff::On } else { OnOff::Off }, &format::ON_OFF)
+                .unwrap();
+        let selector = ChannelSelector::new()
+            .with_parent(&Id::new(&format!("thinkerbell/{}", rule)))
+            .with_feature(&Id::new("thinkerbell/is-rule-enabled"));
+        let results = self.manager.send_values(vec![Targetted {
+                                                        select: vec![selector],
+                                                        payload: payload,
+                                                    }],
+                                               User::None);
+        if results.is_empty() {
+            warn!("[modes] No rule {}", rule);
+        }
+        for (id, result) in results {
+            if let Err(err) = result {
+                warn!("[modes] Could not change rule {} through {}: {:?}", rule, id, err);
+            }
+        }
+    }
+
+    fn enter(&mut self, name: &str) {
+        let mode = match self.modes.get(name) {
+            Some(mode) => mode,
+            None => return warn!("[modes] Cannot enter unknown mode {}", name),
+        };
+        info!("[modes] Entering mode {}", name);
+        self.apply_config(&mode);
+        for rule in &mode.disable_rules {
+            self.set_rule_enabled(rule, false);
+        }
+        for rule in &mode.enable_rules {
+            self.set_rule_enabled(rule, true);
+        }
+        self.modes.set_current(name);
+        self.watchers.update(&ModesAdapter::channel_current_id(),
+                             Value::new(name.to_owned()));
+    }
+}
+
+impl ModesAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("modes@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:modes@link.mozilla.org")
+    }
+
+    pub fn channel_current_id() -> Id<Channel> {
+        Id::new("channel:current.modes@link.mozilla.org")
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let modes = controller.get_modes();
+        let watchers = ValueWatchers::new();
+        let (tx, rx) = mpsc::channel();
+        try!(manager.add_adapter(Arc::new(ModesAdapter {
+            modes: modes.clone(),
+            watchers: watchers.clone(),
+            tx: Mutex::new(tx.clone()),
+        })));
+        try!(manager.add_service(Service::empty(&Self::service_id(), &Self::id())));
+        try!(manager.add_channel(Channel {
+            id: Self::channel_current_id(),
+            service: Self::service_id(),
+            adapter: Self::id(),
+            feature: Id::new("mode/current"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::STRING.clone()))),
+            supports_send: Some(Signature::accepts(Maybe::Required(format::STRING.clone()))),
+            supports_watch: Some(Signature::returns(Maybe::Required(format::STRING.clone()))),
+            ..Channel::default()
+        }));
+
+        let mut switch = Switch {
+            manager: manager.clone(),
+            config: controller.get_config(),
+            modes: modes.clone(),
+            watchers: watchers.clone(),
+            overridden: vec![],
+        };
+        // Overrides don't survive restarts, unlike the rules, which Thinkerbell remembers.
+        if let Some(mode) = modes.current().and_then(|current| modes.get(&current)) {
+            switch.apply_config(&mode);
+            watchers.update(&Self::channel_current_id(), Value::new(mode.name.clone()));
+        }
+        thread::Builder::new()
+            .name("Modes".to_owned())
+            .spawn(move || {
+                for name in rx {
+                    switch.enter(&name);
+                }
+            })
+            .unwrap();
+
+        thread::Builder::new()
+            .name("Modes schedule".to_owned())
+            .spawn(move || {
+                let mut last = None;
+                loop {
+                    let now = Local::now();
+                    let minute = (now.hour(), now.minute());
+                    if last != Some(minute) {
+                        last = Some(minute);
+                        for name in modes.starting_at(minute.0, minute.1) {
+                            if tx.send(name).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    thread::sleep(Duration::from_secs(SCHEDULE_POLL_INTERVAL_S));
+                }
+            })
+            .unwrap();
+        Ok(())
+    }
+}
+
+impl Adapter for ModesAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                if id == Self::channel_current_id() {
+                    return (id, Ok(self.modes.current().map(Value::new)));
+                }
+                (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                if id != Self::channel_current_id() {
+                    return (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id)));
+                }
+                let result = value.cast::<String>().and_then(|name| {
+                    if self.modes.get(name).is_none() {
+                        return Err(Error::InvalidValue);
+                    }
+                    let _ = self.tx.lock().unwrap().send(name.clone());
+                    Ok(())
+                });
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
+
+#[cfg(test)]
+describe! modes_adapter {
+    before_each {
+        use super::*;
+        use foxbox_core::modes::Mode;
+        use foxbox_core::traits::Controller;
+        use iron::method::Method;
+        use iron::status::Status;
+        use serde_json;
+        use std::thread;
+        use std::time::Duration;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        ModesAdapter::init(&harness.manager, harness.controller.clone()).unwrap();
+        let vacation = r#"{"name": "vacation",
+            "config": [{"namespace": "occupancy", "key": "simulate", "value": "true"}]}"#;
+        harness.controller
+            .get_modes()
+            .put(Mode::from_json(&serde_json::from_str(vacation).unwrap()).unwrap());
+        let home = Mode::from_json(&json_value!({ name: "home" })).unwrap();
+        harness.controller.get_modes().put(home);
+    }
+
+    it "should override the configuration while a mode is current" {
+        let config = harness.controller.get_config();
+        config.set("occupancy", "simulate", "false");
+        let enter = |name| {
+            let body = format!(r#"{{"select": {{"feature": "mode/current"}}, "value": "{}"}}"#,
+                               name);
+            harness.request(Method::Put, "/api/v1/channels/set", &body, false).0
+        };
+        let wait_for = |name: &str| {
+            for _ in 0..100 {
+                if harness.controller.get_modes().current() == Some(name.to_owned()) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            panic!("Mode {} was not entered", name);
+        };
+
+        assert_eq!(enter("vacation"), Status::Ok);
+        wait_for("vacation");
+        assert_eq!(config.get("occupancy", "simulate"), Some("true".to_owned()));
+
+        assert_eq!(enter("home"), Status::Ok);
+        wait_for("home");
+        assert_eq!(config.get("occupancy", "simulate"), Some("false".to_owned()));
+
+        // Unknown modes are rejected.
+        let body = r#"{"select": {"feature": "mode/current"}, "value": "party"}"#;
+        let (_, body) = harness.request(Method::Put, "/api/v1/channels/set", body, false);
+        assert!(body.contains("InvalidValue"));
+        assert_eq!(harness.controller.get_modes().current(), Some("home".to_owned()));
+    }
+}
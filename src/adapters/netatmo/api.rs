@@ -0,0 +1,235 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The subset of the Netatmo Energy API used by the adapter.
+//!
+//! See https://dev.netatmo.com/apidocumentation/energy.
+
+use foxbox_core::oauth2::{OAuth2Broker, OAuth2Error, Provider};
+
+use hyper;
+use hyper::header::{Authorization, Bearer, Connection, ContentType};
+use serde_json;
+use serde_json::value::Value as JSON;
+use url::form_urlencoded;
+
+use std::io::Read;
+
+static API_URL: &'static str = "https://api.netatmo.com/api";
+
+/// The name of the provider in the OAuth2 broker.
+pub static PROVIDER: &'static str = "netatmo";
+
+pub fn provider(client_id: &str, client_secret: &str) -> Provider {
+    Provider {
+        name: PROVIDER.to_owned(),
+        authorize_url: "https://api.netatmo.com/oauth2/authorize".to_owned(),
+        token_url: "https://api.netatmo.com/oauth2/token".to_owned(),
+        client_id: client_id.to_owned(),
+        client_secret: client_secret.to_owned(),
+        scopes: vec!["read_thermostat".to_owned(), "write_thermostat".to_owned()],
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// The user hasn't linked the box to their Netatmo account yet.
+    NotLinked,
+    Other(String),
+}
+
+impl From<OAuth2Error> for ApiError {
+    fn from(err: OAuth2Error) -> Self {
+        match err {
+            OAuth2Error::NotAuthorized(_) => ApiError::NotLinked,
+            err => ApiError::Other(format!("{}", err)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Room {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Home {
+    pub id: String,
+    pub name: String,
+    /// `schedule`, `away` or `hg` (frost guard).
+    pub mode: Option<String>,
+    pub rooms: Vec<Room>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomStatus {
+    pub id: String,
+    /// In degrees Celsius.
+    pub temperature: Option<f64>,
+    /// In degrees Celsius.
+    pub setpoint: Option<f64>,
+}
+
+fn string_field(json: &JSON, field: &str) -> Option<String> {
+    json.find(field).and_then(JSON::as_string).map(str::to_owned)
+}
+
+/// Parse the response of `homesdata`. Homes without a thermostat have no rooms.
+pub fn parse_homes(json: &JSON) -> Vec<Home> {
+    let homes = match json.find_path(&["body", "homes"]).and_then(JSON::as_array) {
+        Some(homes) => homes,
+        None => return vec![],
+    };
+    homes.iter()
+        .filter_map(|home| {
+            string_field(home, "id").map(|id| {
+                let rooms = home.find("rooms")
+                    .and_then(JSON::as_array)
+                    .map_or(vec![], |rooms| {
+                        rooms.iter()
+                            .filter_map(|room| {
+                                string_field(room, "id").map(|id| {
+                                    Room {
+                                        name: string_field(room, "name").unwrap_or(id.clone()),
+                                        id: id,
+                                    }
+                                })
+                            })
+                            .collect()
+                    });
+                Home {
+                    name: string_field(home, "name").unwrap_or(id.clone()),
+                    id: id,
+                    mode: string_field(home, "therm_mode"),
+                    rooms: rooms,
+                }
+            })
+        })
+        .collect()
+}
+
+/// Parse the response of `homestatus`.
+pub fn parse_home_status(json: &JSON) -> Vec<RoomStatus> {
+    let rooms = match json.find_path(&["body", "home", "rooms"]).and_then(JSON::as_array) {
+        Some(rooms) => rooms,
+        None => return vec![],
+    };
+    rooms.iter()
+        .filter_map(|room| {
+            string_field(room, "id").map(|id| {
+                RoomStatus {
+                    id: id,
+                    temperature: room.find("therm_measured_temperature").and_then(JSON::as_f64),
+                    setpoint: room.find("therm_setpoint_temperature").and_then(JSON::as_f64),
+                }
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct NetatmoApi {
+    broker: OAuth2Broker,
+}
+
+impl NetatmoApi {
+    pub fn new(broker: OAuth2Broker) -> Self {
+        NetatmoApi { broker: broker }
+    }
+
+    fn call(&self, method: &str, params: &[(&str, &str)]) -> Result<JSON, ApiError> {
+        let token = try!(self.broker.access_token(PROVIDER));
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params)
+            .finish();
+        let url = format!("{}/{}", API_URL, method);
+        let mut response = try!(hyper::Client::new()
+            .post(&url)
+            .header(Authorization(Bearer { token: token }))
+            .header(ContentType::form_url_encoded())
+            .header(Connection::close())
+            .body(&body[..])
+            .send()
+            .map_err(|err| ApiError::Other(format!("{}", err))));
+        let mut source = String::new();
+        try!(response.read_to_string(&mut source)
+            .map_err(|err| ApiError::Other(format!("{}", err))));
+        if !response.status.is_success() {
+            return Err(ApiError::Other(format!("{} returned {}: {}",
+                                               method,
+                                               response.status,
+                                               source)));
+        }
+        serde_json::from_str(&source)
+            .map_err(|err| ApiError::Other(format!("Invalid response to {}: {}", method, err)))
+    }
+
+    pub fn homes(&self) -> Result<Vec<Home>, ApiError> {
+        let json = try!(self.call("homesdata", &[("gateway_types", "NATherm1")]));
+        Ok(parse_homes(&json))
+    }
+
+    pub fn home_status(&self, home: &str) -> Result<Vec<RoomStatus>, ApiError> {
+        let json = try!(self.call("homestatus", &[("home_id", home)]));
+        Ok(parse_home_status(&json))
+    }
+
+    /// Set the target temperature of a room until the next change of the schedule.
+    pub fn set_setpoint(&self, home: &str, room: &str, celsius: f64) -> Result<(), ApiError> {
+        let temp = format!("{}", celsius);
+        try!(self.call("setroomthermpoint",
+                       &[("home_id", home),
+                         ("room_id", room),
+                         ("mode", "manual"),
+                         ("temp", &temp[..])]));
+        Ok(())
+    }
+
+    pub fn set_mode(&self, home: &str, mode: &str) -> Result<(), ApiError> {
+        try!(self.call("setthermmode", &[("home_id", home), ("mode", mode)]));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+describe! netatmo_api {
+    before_each {
+        use super::*;
+        use serde_json;
+    }
+
+    it "should parse homes" {
+        let json = serde_json::from_str(r#"{"body": {"homes": [
+            {"id": "h1", "name": "Home", "therm_mode": "schedule",
+             "rooms": [{"id": "r1", "name": "Living room"}, {"id": "r2"}]},
+            {"name": "No id"}]}}"#).unwrap();
+        assert_eq!(parse_homes(&json),
+                   vec![Home {
+                            id: "h1".to_owned(),
+                            name: "Home".to_owned(),
+                            mode: Some("schedule".to_owned()),
+                            rooms: vec![Room {
+                                            id: "r1".to_owned(),
+                                            name: "Living room".to_owned(),
+                                        },
+                                        Room {
+                                            id: "r2".to_owned(),
+                                            name: "r2".to_owned(),
+                                        }],
+                        }]);
+    }
+
+    it "should parse the status of rooms" {
+        let json = serde_json::from_str(r#"{"body": {"home": {"id": "h1", "rooms": [
+            {"id": "r1", "therm_measured_temperature": 19.5,
+             "therm_setpoint_temperature": 21}]}}}"#).unwrap();
+        assert_eq!(parse_home_status(&json),
+                   vec![RoomStatus {
+                            id: "r1".to_owned(),
+                            temperature: Some(19.5),
+                            setpoint: Some(21.0),
+                        }]);
+    }
+}
@@ -0,0 +1,363 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter for Netatmo thermostats, through the Netatmo cloud.
+//!
+//! The adapter needs a Netatmo application, configured as `netatmo.client_id` and
+//! `netatmo.client_secret`, then a user links the box to their Netatmo account through the
+//! OAuth2 broker (`POST /oauth2/providers/netatmo/authorize`).
+//!
+//! Each room with a thermostat or a valve is a service exposing:
+//! - `thermostat/current-temperature` (fetch, watch), as JSON `{"C": float}`;
+//! - `thermostat/target-temperature` (fetch, watch, send), as JSON `{"C": float}`. Sending a
+//!   temperature overrides the schedule until its next change.
+//!
+//! Each home is a service exposing `thermostat/mode` (fetch, watch, send), as a string:
+//! `schedule`, `away` or `hg` (frost guard).
+//!
+//! The Netatmo Energy API doesn't push events, so watches are served by polling the homes
+//! every `netatmo.poll_interval` seconds (at least 60, as requested by Netatmo). Values sent
+//! through the box are reflected immediately.
+
+mod api;
+
+use self::api::{ApiError, Home, NetatmoApi};
+
+use foxbox_core::config_store::ConfigType;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::io::{Constraint, Format};
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, Value};
+
+use serde_json::value::Value as JSON;
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static ADAPTER_NAME: &'static str = "Netatmo thermostat adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+lazy_static! {
+    /// The modes accepted by `thermostat/mode`.
+    static ref MODE_FORMAT: Arc<Format> = Arc::new(Format::new::<String>()
+        .with_constraint(Constraint::one_of(&["schedule", "away", "hg"])));
+}
+
+/// Netatmo asks applications not to poll more often than this.
+const MIN_POLL_INTERVAL_S: u64 = 60;
+
+/// The interval between two polls, from `netatmo.poll_interval`.
+fn parse_poll_interval(seconds: &str) -> Duration {
+    Duration::from_secs(cmp::max(seconds.parse().unwrap_or(300), MIN_POLL_INTERVAL_S))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Target {
+    Temperature { home: String, room: String },
+    Setpoint { home: String, room: String },
+    Mode { home: String },
+}
+
+fn temperature_value(celsius: f64) -> Value {
+    let mut object = BTreeMap::new();
+    object.insert("C".to_owned(), JSON::F64(celsius));
+    Value::new(Json(JSON::Object(object)))
+}
+
+/// Parse a temperature sent as JSON `{"C": float}`.
+fn parse_temperature(value: &Value) -> Result<f64, Error> {
+    let json = try!(value.cast::<Json>());
+    match json.0.find("C").and_then(JSON::as_f64) {
+        Some(celsius) if celsius >= 5.0 && celsius <= 30.0 => Ok(celsius),
+        _ => Err(Error::InvalidValue),
+    }
+}
+
+fn parse_mode(value: &Value) -> Result<String, Error> {
+    let mode = try!(value.cast::<String>());
+    match &mode[..] {
+        "schedule" | "away" | "hg" => Ok(mode.clone()),
+        _ => Err(Error::InvalidValue),
+    }
+}
+
+pub struct NetatmoAdapter {
+    api: NetatmoApi,
+    watchers: ValueWatchers,
+    targets: Arc<Mutex<HashMap<Id<Channel>, Target>>>,
+}
+
+impl NetatmoAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("netatmo@link.mozilla.org")
+    }
+
+    fn service_id(id: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.netatmo@link.mozilla.org", id))
+    }
+
+    fn temperature_id(room: &str) -> Id<Channel> {
+        Id::new(&format!("getter:temperature.{}.netatmo@link.mozilla.org", room))
+    }
+
+    fn setpoint_id(room: &str) -> Id<Channel> {
+        Id::new(&format!("setpoint.{}.netatmo@link.mozilla.org", room))
+    }
+
+    fn mode_id(home: &str) -> Id<Channel> {
+        Id::new(&format!("mode.{}.netatmo@link.mozilla.org", home))
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        config.register("netatmo", "poll_interval", ConfigType::Integer);
+        let (client_id, client_secret) = match (config.get("netatmo", "client_id"),
+                                                config.get("netatmo", "client_secret")) {
+            (Some(client_id), Some(client_secret)) => (client_id, client_secret),
+            _ => {
+                info!("[netatmo] No Netatmo application configured.");
+                return Ok(());
+            }
+        };
+        let poll_interval =
+            parse_poll_interval(&config.get_or_set_default("netatmo", "poll_interval", "300"));
+        let (config_tx, config_rx) = mpsc::channel();
+        config.watch("netatmo", config_tx);
+
+        let broker = controller.get_oauth2();
+        broker.register_provider(api::provider(&client_id, &client_secret));
+
+        let adapter = Arc::new(NetatmoAdapter {
+            api: NetatmoApi::new(broker),
+            watchers: ValueWatchers::new(),
+            targets: Arc::new(Mutex::new(HashMap::new())),
+        });
+        try!(manager.add_adapter(adapter.clone()));
+
+        let manager = manager.clone();
+        thread::Builder::new()
+            .name("Netatmo".to_owned())
+            .spawn(move || {
+                let mut linked = true;
+                let mut poll_interval = poll_interval;
+                loop {
+                    match adapter.poll(&manager) {
+                        Ok(()) => linked = true,
+                        Err(ApiError::NotLinked) => {
+                            if linked {
+                                info!("[netatmo] Waiting for the box to be linked to a Netatmo \
+                                       account.");
+                            }
+                            linked = false;
+                        }
+                        Err(ApiError::Other(err)) => warn!("[netatmo] Polling failed: {}", err),
+                    }
+                    thread::sleep(poll_interval);
+                    while let Ok(change) = config_rx.try_recv() {
+                        if let ("poll_interval", Some(value)) = (&*change.property, change.value) {
+                            poll_interval = parse_poll_interval(&value);
+                            info!("[netatmo] Polling every {}s", poll_interval.as_secs());
+                        }
+                    }
+                }
+            })
+            .unwrap();
+
+        Ok(())
+    }
+
+    /// Register the services and channels of the homes not seen yet.
+    fn add_home(&self, manager: &Arc<AdapterManager>, home: &Home) -> Result<(), Error> {
+        let mut targets = self.targets.lock().unwrap();
+        let mode_id = Self::mode_id(&home.id);
+        if !targets.contains_key(&mode_id) {
+            let mut service = Service::empty(&Self::service_id(&home.id), &Self::id());
+            service.properties.insert("model".to_owned(), "Netatmo home".to_owned());
+            service.properties.insert("name".to_owned(), home.name.clone());
+            try!(manager.add_service(service));
+            try!(manager.add_channel(Channel {
+                id: mode_id.clone(),
+                service: Self::service_id(&home.id),
+                adapter: Self::id(),
+                feature: Id::new("thermostat/mode"),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::STRING.clone()))),
+                supports_watch: Some(Signature::returns(Maybe::Required(format::STRING.clone()))),
+                supports_send: Some(Signature::accepts(Maybe::Required(MODE_FORMAT.clone()))),
+                ..Channel::default()
+            }));
+            targets.insert(mode_id, Target::Mode { home: home.id.clone() });
+        }
+
+        for room in &home.rooms {
+            let temperature_id = Self::temperature_id(&room.id);
+            if targets.contains_key(&temperature_id) {
+                continue;
+            }
+            let service_id = Self::service_id(&room.id);
+            let mut service = Service::empty(&service_id, &Self::id());
+            service.properties.insert("model".to_owned(), "Netatmo room".to_owned());
+            service.properties.insert("name".to_owned(), room.name.clone());
+            try!(manager.add_service(service));
+
+            let setpoint_id = Self::setpoint_id(&room.id);
+            try!(manager.add_channel(Channel {
+                id: temperature_id.clone(),
+                service: service_id.clone(),
+                adapter: Self::id(),
+                feature: Id::new("thermostat/current-temperature"),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                supports_watch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                ..Channel::default()
+            }));
+            try!(manager.add_channel(Channel {
+                id: setpoint_id.clone(),
+                service: service_id,
+                adapter: Self::id(),
+                feature: Id::new("thermostat/target-temperature"),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                supports_watch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                supports_send: Some(Signature::accepts(Maybe::Required(format::JSON.clone()))),
+                ..Channel::default()
+            }));
+            targets.insert(temperature_id,
+                           Target::Temperature {
+                               home: home.id.clone(),
+                               room: room.id.clone(),
+                           });
+            targets.insert(setpoint_id,
+                           Target::Setpoint {
+                               home: home.id.clone(),
+                               room: room.id.clone(),
+                           });
+        }
+        Ok(())
+    }
+
+    /// Discover the homes and rooms, and update the watchers with their latest values.
+    fn poll(&self, manager: &Arc<AdapterManager>) -> Result<(), ApiError> {
+        for home in try!(self.api.homes()) {
+            if let Err(err) = self.add_home(manager, &home) {
+                error!("[netatmo] Could not add home {}: {:?}", home.id, err);
+                continue;
+            }
+            if let Some(ref mode) = home.mode {
+                self.watchers.update(&Self::mode_id(&home.id), Value::new(mode.clone()));
+            }
+            if home.rooms.is_empty() {
+                continue;
+            }
+            for room in try!(self.api.home_status(&home.id)) {
+                if let Some(celsius) = room.temperature {
+                    self.watchers.update(&Self::temperature_id(&room.id),
+                                         temperature_value(celsius));
+                }
+                if let Some(celsius) = room.setpoint {
+                    self.watchers.update(&Self::setpoint_id(&room.id), temperature_value(celsius));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&self, target: &Target, value: &Value) -> Result<(), Error> {
+        let result = match *target {
+            Target::Setpoint { ref home, ref room } => {
+                let celsius = try!(parse_temperature(value));
+                self.api.set_setpoint(home, room, celsius)
+            }
+            Target::Mode { ref home } => {
+                let mode = try!(parse_mode(value));
+                self.api.set_mode(home, &mode)
+            }
+            Target::Temperature { .. } => unreachable!(),
+        };
+        result.map_err(|err| Error::Internal(InternalError::GenericError(format!("{:?}", err))))
+    }
+}
+
+impl Adapter for NetatmoAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        let targets = self.targets.lock().unwrap();
+        set.drain(..)
+            .map(|id| {
+                if !targets.contains_key(&id) {
+                    return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))));
+                }
+                // Values are kept up to date by the polling thread, so that fetching doesn't
+                // eat into the quota of requests to the Netatmo API.
+                let value = self.watchers.latest(&id);
+                (id, Ok(value))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                let target = self.targets.lock().unwrap().get(&id).cloned();
+                let result = match target {
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                    Some(Target::Temperature { .. }) => {
+                        Err(Error::OperationNotSupported(Operation::Send, id.clone()))
+                    }
+                    Some(target) => {
+                        self.send(&target, &value).map(|()| self.watchers.update(&id, value))
+                    }
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
+
+#[cfg(test)]
+describe! netatmo {
+    before_each {
+        use super::{parse_mode, parse_temperature, temperature_value};
+        use foxbox_taxonomy::values::Value;
+    }
+
+    it "should parse the values sent to thermostats" {
+        assert_eq!(parse_temperature(&temperature_value(20.5)).ok(), Some(20.5));
+        assert!(parse_temperature(&temperature_value(80.0)).is_err());
+        assert!(parse_temperature(&Value::new("warm".to_owned())).is_err());
+        assert_eq!(parse_mode(&Value::new("away".to_owned())).ok(),
+                   Some("away".to_owned()));
+        assert!(parse_mode(&Value::new("party".to_owned())).is_err());
+    }
+}
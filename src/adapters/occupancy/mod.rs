@@ -0,0 +1,246 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter inferring the occupancy of rooms from their sensors.
+//!
+//! Rooms are configured in `occupancy.rooms` (see `room::parse_rooms`), each with a set of
+//! selectors for its motion, presence, door and light channels. Each room is exposed as a
+//! service with an `occupancy/is-occupied` channel (fetch, watch), which is `On` while the
+//! sensors of the room suggest that someone is there. This is more robust than watching a
+//! single motion sensor, e.g. to turn the lights off when everybody has left.
+
+mod room;
+
+use self::room::{Room, Signal};
+
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{API, Error, InternalError, Operation, Targetted, User, WatchEvent};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::parse::ToJSON;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::util::Exactly;
+use foxbox_taxonomy::values::{format, OnOff, Value};
+
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static ADAPTER_NAME: &'static str = "Occupancy adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const TICK_S: u64 = 5;
+
+struct State {
+    rooms: Vec<Room>,
+    /// The latest occupancy we have reported, for each room.
+    reported: Vec<Option<bool>>,
+    /// The feature and the rooms of each sensor.
+    sensors: HashMap<Id<Channel>, (String, Vec<usize>)>,
+}
+
+impl State {
+    /// Report the rooms whose occupancy has changed.
+    fn refresh(&mut self, watchers: &ValueWatchers, now: Instant) {
+        for (index, room) in self.rooms.iter().enumerate() {
+            let is_occupied = room.is_occupied(now);
+            if self.reported[index] != Some(is_occupied) {
+                self.reported[index] = Some(is_occupied);
+                let value = if is_occupied { OnOff::On } else { OnOff::Off };
+                watchers.update(&OccupancyAdapter::channel_id(&room.id), Value::new(value));
+            }
+        }
+    }
+
+    /// Find out which channels are the sensors of which rooms.
+    fn find_sensors(&mut self, manager: &AdapterManager) {
+        self.sensors.clear();
+        for (index, room) in self.rooms.iter().enumerate() {
+            for channel in manager.get_channels(room.sensors.clone()) {
+                if channel.adapter == OccupancyAdapter::id() {
+                    continue;
+                }
+                self.sensors
+                    .entry(channel.id.clone())
+                    .or_insert_with(|| (channel.feature.to_string(), vec![]))
+                    .1
+                    .push(index);
+            }
+        }
+    }
+}
+
+pub struct OccupancyAdapter {
+    rooms: Vec<Id<Channel>>,
+    watchers: ValueWatchers,
+}
+
+impl OccupancyAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("occupancy@link.mozilla.org")
+    }
+
+    pub fn service_id(room: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.occupancy@link.mozilla.org", room))
+    }
+
+    pub fn channel_id(room: &str) -> Id<Channel> {
+        Id::new(&format!("channel:occupied.{}.occupancy@link.mozilla.org", room))
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let rooms = match controller.get_config().get("occupancy", "rooms") {
+            Some(source) => {
+                try!(room::parse_rooms(&source)
+                    .map_err(|err| Error::Internal(InternalError::GenericError(err))))
+            }
+            None => vec![],
+        };
+
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(OccupancyAdapter {
+            rooms: rooms.iter().map(|room| Self::channel_id(&room.id)).collect(),
+            watchers: watchers.clone(),
+        })));
+        for room in &rooms {
+            let service_id = Self::service_id(&room.id);
+            let mut service = Service::empty(&service_id, &Self::id());
+            service.properties.insert("model".to_owned(), "Room".to_owned());
+            service.properties.insert("name".to_owned(), room.name.clone());
+            try!(manager.add_service(service));
+            try!(manager.add_channel(Channel {
+                id: Self::channel_id(&room.id),
+                service: service_id,
+                adapter: Self::id(),
+                feature: Id::new("occupancy/is-occupied"),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+                supports_watch: Some(Signature {
+                    accepts: Maybe::Optional(format::ON_OFF.clone()),
+                    returns: Maybe::Required(format::ON_OFF.clone()),
+                }),
+                ..Channel::default()
+            }));
+        }
+        if rooms.is_empty() {
+            info!("[occupancy] No room configured.");
+            return Ok(());
+        }
+
+        let state = Arc::new(Mutex::new(State {
+            reported: vec![None; rooms.len()],
+            rooms: rooms,
+            sensors: HashMap::new(),
+        }));
+        {
+            let state = state.clone();
+            let watchers = watchers.clone();
+            thread::spawn(move || {
+                loop {
+                    state.lock().unwrap().refresh(&watchers, Instant::now());
+                    thread::sleep(Duration::from_secs(TICK_S));
+                }
+            });
+        }
+        let manager = manager.clone();
+        thread::Builder::new()
+            .name("Occupancy".to_owned())
+            .spawn(move || Self::follow_sensors(&manager, &state, &watchers))
+            .unwrap();
+        Ok(())
+    }
+
+    /// Feed the readings of the sensors to their rooms, forever.
+    fn follow_sensors(manager: &AdapterManager, state: &Mutex<State>, watchers: &ValueWatchers) {
+        let (tx, rx) = mpsc::channel::<WatchEvent>();
+        let selectors = state.lock()
+            .unwrap()
+            .rooms
+            .iter()
+            .flat_map(|room| room.sensors.clone())
+            .collect();
+        // Keep the guard alive for as long as we are following sensors.
+        let _guard = manager.watch_values(vec![Targetted {
+                                               select: selectors,
+                                               payload: Exactly::Always,
+                                           }],
+                                          Box::new(tx),
+                                          User::None);
+        state.lock().unwrap().find_sensors(manager);
+        for event in rx {
+            let (channel, value) = match event {
+                WatchEvent::ChannelAdded(_) |
+                WatchEvent::ChannelRemoved(_) => {
+                    state.lock().unwrap().find_sensors(manager);
+                    continue;
+                }
+                WatchEvent::EnterRange { channel, value, .. } => (channel, value),
+                _ => continue,
+            };
+            let now = Instant::now();
+            let mut state = state.lock().unwrap();
+            let signal = match state.sensors.get(&channel) {
+                Some(&(ref feature, ref rooms)) => {
+                    Signal::from_value(feature, &value.to_json())
+                        .map(|signal| (signal, rooms.clone()))
+                }
+                None => None,
+            };
+            if let Some((signal, rooms)) = signal {
+                for index in rooms {
+                    state.rooms[index].signal(&channel.to_string(), signal.clone(), now);
+                }
+                state.refresh(watchers, now);
+            }
+        }
+    }
+}
+
+impl Adapter for OccupancyAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                if !self.rooms.contains(&id) {
+                    return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))));
+                }
+                let value = self.watchers.latest(&id);
+                (id, Ok(value))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, _)| (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id))))
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
@@ -0,0 +1,174 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Rooms, and how the readings of their sensors translate into occupancy.
+//!
+//! Sensors contribute in two ways:
+//! - motion and presence sensors (features containing `motion`, `presence` or `occupancy`)
+//!   hold the room occupied for as long as they detect someone;
+//! - doors (features starting with `door/`) and lights (features starting with `light/`)
+//!   report activity when they open or are turned on.
+//!
+//! Once nothing holds the room occupied, it remains occupied for `decay` seconds after the
+//! latest activity, so that someone reading quietly doesn't find themselves in the dark.
+
+use foxbox_taxonomy::parse::{Parser, Path};
+use foxbox_taxonomy::selector::ChannelSelector;
+
+use serde_json;
+use serde_json::value::Value as JSON;
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+const DEFAULT_DECAY_S: u64 = 600;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Signal {
+    /// A sensor starts (`true`) or stops (`false`) detecting someone.
+    Hold(bool),
+    /// Someone just did something.
+    Activity,
+}
+
+impl Signal {
+    /// Interpret the value of a channel, depending on its feature.
+    pub fn from_value(feature: &str, value: &JSON) -> Option<Signal> {
+        let active = match *value {
+            JSON::String(ref state) => {
+                match state as &str {
+                    "Detected" | "On" | "Open" => true,
+                    "NotDetected" | "Off" | "Closed" => false,
+                    _ => return None,
+                }
+            }
+            JSON::Bool(active) => active,
+            _ => return None,
+        };
+        if feature.contains("motion") || feature.contains("presence") ||
+           feature.contains("occupancy") {
+            Some(Signal::Hold(active))
+        } else if active && (feature.starts_with("door/") || feature.starts_with("light/")) {
+            Some(Signal::Activity)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Room {
+    pub id: String,
+    pub name: String,
+    pub sensors: Vec<ChannelSelector>,
+    pub decay: Duration,
+    /// The sensors currently detecting someone.
+    holding: HashSet<String>,
+    last_activity: Option<Instant>,
+}
+
+impl Room {
+    pub fn new(id: &str, name: &str, sensors: Vec<ChannelSelector>, decay: Duration) -> Self {
+        Room {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            sensors: sensors,
+            decay: decay,
+            holding: HashSet::new(),
+            last_activity: None,
+        }
+    }
+
+    pub fn signal(&mut self, channel: &str, signal: Signal, now: Instant) {
+        // Both the start and the end of a detection are signs of activity.
+        let is_activity = match signal {
+            Signal::Hold(true) => {
+                self.holding.insert(channel.to_owned());
+                true
+            }
+            Signal::Hold(false) => self.holding.remove(channel),
+            Signal::Activity => true,
+        };
+        if is_activity {
+            self.last_activity = Some(now);
+        }
+    }
+
+    pub fn is_occupied(&self, now: Instant) -> bool {
+        !self.holding.is_empty() ||
+        self.last_activity.map_or(false, |last| now.duration_since(last) < self.decay)
+    }
+}
+
+/// Parse the rooms, as configured in `occupancy.rooms`:
+///
+/// ```json
+/// [{ "id": "living-room", "name": "Living room", "decay": 900,
+///    "sensors": [{ "tags": ["living room"] }, { "id": "channel:motion.hall" }] }]
+/// ```
+pub fn parse_rooms(source: &str) -> Result<Vec<Room>, String> {
+    let json: JSON = try!(serde_json::from_str(source).map_err(|err| format!("{}", err)));
+    let rooms = try!(json.as_array().ok_or_else(|| "Expected an array of rooms".to_owned()));
+    let mut result = Vec::new();
+    for room in rooms {
+        let id = try!(room.find("id")
+            .and_then(JSON::as_string)
+            .ok_or_else(|| "Every room needs an `id`".to_owned()));
+        let name = room.find("name").and_then(JSON::as_string).unwrap_or(id);
+        let decay = room.find("decay").and_then(JSON::as_u64).unwrap_or(DEFAULT_DECAY_S);
+        let sensors: Vec<ChannelSelector> = match room.find("sensors") {
+            Some(sensors) => {
+                try!(Vec::<ChannelSelector>::parse(Path::new(), sensors)
+                    .map_err(|err| format!("Invalid sensors for room {}: {:?}", id, err)))
+            }
+            None => return Err(format!("Room {} has no `sensors`", id)),
+        };
+        result.push(Room::new(id, name, sensors, Duration::from_secs(decay)));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+describe! occupancy_room {
+    before_each {
+        use super::*;
+        use serde_json::value::Value as JSON;
+        use std::time::{Duration, Instant};
+    }
+
+    it "should interpret sensor values" {
+        let detected = JSON::String("Detected".to_owned());
+        let on = JSON::String("On".to_owned());
+        let off = JSON::String("Off".to_owned());
+        assert_eq!(Signal::from_value("motion/is-detected", &detected), Some(Signal::Hold(true)));
+        assert_eq!(Signal::from_value("presence/is-on", &off), Some(Signal::Hold(false)));
+        assert_eq!(Signal::from_value("light/is-on", &on), Some(Signal::Activity));
+        assert_eq!(Signal::from_value("light/is-on", &off), None);
+        assert_eq!(Signal::from_value("door/is-open", &JSON::String("Open".to_owned())),
+                   Some(Signal::Activity));
+        assert_eq!(Signal::from_value("switch/is-on", &on), None);
+    }
+
+    it "should stay occupied while someone is detected, then decay" {
+        let now = Instant::now();
+        let mut room = Room::new("hall", "Hall", vec![], Duration::from_secs(60));
+        room.signal("motion", Signal::Hold(false), now);
+        assert!(!room.is_occupied(now));
+        room.signal("motion", Signal::Hold(true), now);
+        assert!(room.is_occupied(now + Duration::from_secs(3600)));
+        room.signal("motion", Signal::Hold(false), now + Duration::from_secs(3600));
+        assert!(room.is_occupied(now + Duration::from_secs(3630)));
+        assert!(!room.is_occupied(now + Duration::from_secs(3660)));
+        room.signal("door", Signal::Activity, now + Duration::from_secs(4000));
+        assert!(room.is_occupied(now + Duration::from_secs(4030)));
+    }
+
+    it "should parse rooms" {
+        let rooms = parse_rooms(r#"[{ "id": "hall", "decay": 120,
+            "sensors": [{ "feature": "motion/is-detected", "tags": ["hall"] }] }]"#).unwrap();
+        assert_eq!(rooms[0].name, "hall");
+        assert_eq!(rooms[0].decay, Duration::from_secs(120));
+        assert_eq!(rooms[0].sensors.len(), 1);
+        assert!(parse_rooms(r#"[{ "id": "hall" }]"#).is_err());
+    }
+}
@@ -17,7 +17,6 @@ use foxbox_core::traits::Controller;
 use foxbox_core::upnp::{UpnpListener, UpnpManager, UpnpService};
 use serde_json;
 use std::sync::{Arc, Mutex};
-use std::thread;
 use super::{HueAction, http, PhilipsHueAdapter};
 use transformable_channels::mpsc::*;
 
@@ -48,7 +47,7 @@ impl<C: Controller> Discovery<C> {
     pub fn do_nupnp_discovery(&self) {
         let controller = self.adapter.controller.clone();
         let tx = self.adapter.tx.clone();
-        thread::spawn(move || {
+        let _ = self.adapter.controller.get_thread_pool().execute(move || {
             let nupnp_enabled = controller.get_config()
                 .get_or_set_default("philips_hue", "nupnp_enabled", "true");
             if nupnp_enabled == "true" {
@@ -8,15 +8,27 @@
 //! It handles pairing and light enumeration. Detected lights are
 //! reported to the adapter's main loop via IPC.
 //!
-//! The module spawns a management thread for every hub.
+//! The module spawns a management thread for every hub. Once the hub is
+//! paired, this thread follows the changes made to its lights, through the
+//! event stream of the bridge or by polling bridges that do not have one.
 
 use serde_json;
+use std::error::Error;
+use std::io::{BufRead, BufReader};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use super::hub_api::HubApi;
 use super::{HueAction, PhilipsHueAdapter, structs};
 use foxbox_core::traits::Controller;
+use foxbox_taxonomy::alerts::Severity;
+use foxbox_taxonomy::manager::AdapterManagerHandle;
+
+/// Delay between two polls of the lights, for bridges without event stream.
+const POLL_INTERVAL_MS: u64 = 1000;
+
+/// Number of polls before trying to open the event stream again.
+const POLLS_BEFORE_RETRY: u32 = 60;
 
 pub struct Hub<C> {
     pub adapter: PhilipsHueAdapter<C>,
@@ -62,6 +74,12 @@ impl<C: Controller> Hub<C> {
                 if !api.lock().unwrap().is_paired() {
                     warn!("Philips Hue detected but not paired. Please, push pairing \
                            button on Philips Hue Bridge ID {} to start using it.", id);
+                    adapter.manager.raise_alert(Severity::Warning,
+                                                &format!("Philips Hue bridge {} detected but \
+                                                          not paired.",
+                                                         id),
+                                                Some("Press the pairing button on the bridge \
+                                                      to start using it."));
 
                     // Try pairing for 120 seconds.
                     for _ in 0..120 {
@@ -95,6 +113,13 @@ impl<C: Controller> Hub<C> {
                                 hub: id }));
                     } else {
                         warn!("Pairing timeout with Philips Hue Bridge ID {}", id);
+                        adapter.manager.raise_alert(Severity::Warning,
+                                                    &format!("Could not pair with Philips Hue \
+                                                              bridge {}.",
+                                                             id),
+                                                    Some("Press the pairing button on the \
+                                                          bridge, pairing is retried every \
+                                                          hour."));
                         adapter.controller.adapter_notification(
                             json_value!({ adapter: "philips_hue", message: "PairingTimeout",
                                 hub: id }));
@@ -120,10 +145,18 @@ impl<C: Controller> Hub<C> {
                     adapter.send(HueAction::AddLight(id.to_owned(), light_id.to_owned()));
                 }
 
+                // Follow the changes made to the lights from any source (e.g. the Hue
+                // app or a wall switch), to notify watchers.
+                let hub_api = api.lock().unwrap().clone();
                 loop {
                     // Forever
-                    // TODO: add hub monitoring (polling) here
-                    thread::sleep(Duration::from_millis(60 * 1000));
+                    if let Err(err) = follow_event_stream(&adapter, &id, &hub_api) {
+                        debug!("No event stream for Philips Hue bridge {}, polling: {}", id, err);
+                    }
+                    for _ in 0..POLLS_BEFORE_RETRY {
+                        refresh_lights(&adapter, &id, &hub_api);
+                        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                    }
                 }
             }
         });
@@ -136,3 +169,40 @@ impl<C: Controller> Hub<C> {
         debug!("Stopping Hue Hub Service for {}", self.id)
     }
 }
+
+/// Report the state of all the lights of a hub to the adapter.
+fn refresh_lights<C: Controller>(adapter: &PhilipsHueAdapter<C>, hub_id: &str, api: &HubApi) {
+    if let Some(lights) = api.get_lights_status() {
+        for (light_id, light) in lights {
+            adapter.observe_light(hub_id, &light_id, &light.state);
+        }
+    }
+}
+
+/// Follow the event stream of a hub, until the connection is lost.
+fn follow_event_stream<C: Controller>(adapter: &PhilipsHueAdapter<C>,
+                                      hub_id: &str,
+                                      api: &HubApi)
+                                      -> Result<(), Box<Error>> {
+    let response = try!(api.event_stream());
+    info!("Following the event stream of Philips Hue bridge {}", hub_id);
+
+    // Catch up with the changes made while we were not listening.
+    refresh_lights(adapter, hub_id, api);
+
+    for line in BufReader::new(response).lines() {
+        let line = try!(line);
+        // Each event is a single `data:` line, holding a JSON array of messages.
+        if !line.starts_with("data:") {
+            continue;
+        }
+        let light_ids = structs::event_light_ids(line["data:".len()..].trim());
+        if !light_ids.is_empty() {
+            // Messages only hold what changed, in terms of the v2 API, so
+            // fetch the complete state of lights.
+            debug!("Lights {:?} of Philips Hue bridge {} changed", light_ids, hub_id);
+            refresh_lights(adapter, hub_id, api);
+        }
+    }
+    Err(From::from("End of stream"))
+}
@@ -7,6 +7,7 @@
 //! This module is used in various places, for example in the Hub
 //! objects and in the Light objects.
 
+use hyper::client::Response;
 use serde_json;
 use std;
 use std::collections::BTreeMap;
@@ -158,6 +159,28 @@ impl HubApi {
         lights
     }
 
+    /// The status of all the lights of the bridge, in a single request.
+    pub fn get_lights_status(&self) -> Option<BTreeMap<String, structs::SettingsLightEntry>> {
+        match self.get("lights") {
+            Ok(res) => structs::parse_json(&res),
+            Err(err) => {
+                debug!("Could not get the status of lights from Philips Hue bridge {}: {}",
+                       self.id,
+                       err);
+                None
+            }
+        }
+    }
+
+    /// Open the event stream of the bridge, which pushes changes made from any source (e.g.
+    /// the Hue app or a wall switch) as server-sent events. Only bridges that support the
+    /// CLIP v2 API offer it.
+    pub fn event_stream(&self) -> Result<Response, Box<Error>> {
+        let url = format!("https://{}/eventstream/clip/v2", self.ip);
+        debug!("Opening event stream of Philips Hue bridge {}: {}", self.id, url);
+        http::get_event_stream(&url, &self.token)
+    }
+
     pub fn get_light_status(&self, id: &str) -> structs::SettingsLightEntry {
         let url = format!("lights/{}", id);
         let res = self.get(&url).unwrap(); // TODO: remove unwrap
@@ -182,4 +205,11 @@ impl HubApi {
         let cmd = json!({ bri: bri });
         let _ = self.put(&url, &cmd);
     }
+
+    pub fn identify_light(&self, light_id: &str) {
+        // "lselect" makes the light breathe for 15 seconds, "select" only once.
+        let url = format!("lights/{}/state", light_id);
+        let cmd = json!({ alert: "lselect" });
+        let _ = self.put(&url, &cmd);
+    }
 }
@@ -12,8 +12,10 @@ use foxbox_taxonomy::api::Error;
 use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::manager::*;
 use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::format;
 use super::*;
 use super::hub_api::HubApi;
+use super::structs::SettingsLightState;
 use std::sync::{Arc, Mutex};
 
 const CUSTOM_PROPERTY_MANUFACTURER: &'static str = "manufacturer";
@@ -30,6 +32,7 @@ pub struct Light {
     pub get_available_id: Id<Channel>,
     pub channel_power_id: Id<Channel>,
     pub channel_color_id: Id<Channel>,
+    pub channel_identify_id: Id<Channel>,
 }
 
 impl Light {
@@ -42,6 +45,7 @@ impl Light {
             get_available_id: create_channel_id("available", &hub_id, &light_id),
             channel_power_id: create_channel_id("power", &hub_id, &light_id),
             channel_color_id: create_channel_id("color", &hub_id, &light_id),
+            channel_identify_id: create_channel_id("identify", &hub_id, &light_id),
         }
     }
     pub fn start(&self) {
@@ -81,6 +85,7 @@ impl Light {
                 id: self.get_available_id.clone(),
                 service: self.service_id.clone(),
                 adapter: adapter_id.clone(),
+                supports_watch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
                 ..AVAILABLE.clone()
             }));
 
@@ -88,7 +93,6 @@ impl Light {
                 id: self.channel_power_id.clone(),
                 service: self.service_id.clone(),
                 adapter: adapter_id.clone(),
-                supports_watch: None,
                 ..LIGHT_IS_ON.clone()
             }));
 
@@ -96,16 +100,23 @@ impl Light {
                 id: self.channel_color_id.clone(),
                 service: self.service_id.clone(),
                 adapter: adapter_id.clone(),
-                supports_watch: None,
                 ..LIGHT_COLOR_HSV.clone()
             }));
 
+            try!(manager.add_channel(Channel {
+                id: self.channel_identify_id.clone(),
+                service: self.service_id.clone(),
+                adapter: adapter_id.clone(),
+                ..IDENTIFY.clone()
+            }));
+
             let mut services_lock = services.lock().unwrap();
             services_lock.getters.insert(self.get_available_id.clone(), self.clone());
             services_lock.getters.insert(self.channel_power_id.clone(), self.clone());
             services_lock.setters.insert(self.channel_power_id.clone(), self.clone());
             services_lock.getters.insert(self.channel_color_id.clone(), self.clone());
             services_lock.setters.insert(self.channel_color_id.clone(), self.clone());
+            services_lock.setters.insert(self.channel_identify_id.clone(), self.clone());
 
         } else if status.lighttype == "Dimmable light" {
             info!("New Philips Hue `Dimmable Light` service for light {} on bridge {}",
@@ -125,6 +136,7 @@ impl Light {
                 id: self.get_available_id.clone(),
                 service: self.service_id.clone(),
                 adapter: adapter_id.clone(),
+                supports_watch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
                 ..AVAILABLE.clone()
             }));
 
@@ -132,14 +144,21 @@ impl Light {
                 id: self.channel_power_id.clone(),
                 service: self.service_id.clone(),
                 adapter: adapter_id.clone(),
-                supports_watch: None,
                 ..LIGHT_IS_ON.clone()
             }));
 
+            try!(manager.add_channel(Channel {
+                id: self.channel_identify_id.clone(),
+                service: self.service_id.clone(),
+                adapter: adapter_id.clone(),
+                ..IDENTIFY.clone()
+            }));
+
             let mut services_lock = services.lock().unwrap();
             services_lock.getters.insert(self.get_available_id.clone(), self.clone());
             services_lock.getters.insert(self.channel_power_id.clone(), self.clone());
             services_lock.setters.insert(self.channel_power_id.clone(), self.clone());
+            services_lock.setters.insert(self.channel_identify_id.clone(), self.clone());
 
         } else {
             warn!("Ignoring unsupported Hue light type {}, ID {} on bridge {}",
@@ -162,6 +181,11 @@ impl Light {
         self.api.lock().unwrap().set_light_power(&self.light_id, on);
     }
 
+    /// Make the light blink for a few seconds, without changing its state.
+    pub fn identify(&self) {
+        self.api.lock().unwrap().identify_light(&self.light_id);
+    }
+
     #[allow(dead_code)]
     pub fn get_brightness(&self) -> f64 {
         // Hue API gives brightness value in [0, 254]
@@ -181,12 +205,8 @@ impl Light {
     }
 
     pub fn get_color(&self) -> (f64, f64, f64) {
-        // Hue API gives hue angle in [0, 65535], and sat and val in [0, 254]
         let ls = self.api.lock().unwrap().get_light_status(&self.light_id);
-        let hue: f64 = ls.state.hue.unwrap_or(0) as f64 / 65536f64 * 360f64;
-        let sat: f64 = ls.state.sat.unwrap_or(0) as f64 / 254f64;
-        let val: f64 = ls.state.bri as f64 / 254f64;
-        (hue, sat, val)
+        state_color(&ls.state)
     }
 
     pub fn set_color(&self, hsv: (f64, f64, f64)) {
@@ -204,3 +224,12 @@ impl Light {
         self.api.lock().unwrap().set_light_color(&self.light_id, (hue, sat, val));
     }
 }
+
+/// The color of a light, as HSV.
+pub fn state_color(state: &SettingsLightState) -> (f64, f64, f64) {
+    // Hue API gives hue angle in [0, 65535], and sat and val in [0, 254]
+    let hue: f64 = state.hue.unwrap_or(0) as f64 / 65536f64 * 360f64;
+    let sat: f64 = state.sat.unwrap_or(0) as f64 / 254f64;
+    let val: f64 = state.bri as f64 / 254f64;
+    (hue, sat, val)
+}
@@ -18,6 +18,7 @@ pub mod lights;
 pub mod structs;
 
 use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
 use foxbox_taxonomy::api::{Error, InternalError, User};
 use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::manager::*;
@@ -67,6 +68,9 @@ pub struct PhilipsHueAdapter<C> {
 
     /// The ID of this adapter (permanently fixed)
     adapter_id: Id<AdapterId>,
+
+    /// Watchers of the `available`, `power` and `color` channels, fed by the hubs.
+    watchers: ValueWatchers,
 }
 
 impl<C: Controller> PhilipsHueAdapter<C> {
@@ -85,6 +89,7 @@ impl<C: Controller> PhilipsHueAdapter<C> {
             services: services.clone(),
             tx: Arc::new(Mutex::new(tx.clone())),
             adapter_id: create_adapter_id(),
+            watchers: ValueWatchers::new(),
         };
 
         try!(manager.add_adapter(Arc::new(adapter.clone())));
@@ -193,6 +198,27 @@ impl<C: Controller> PhilipsHueAdapter<C> {
     pub fn send(&self, action: HueAction) {
         let _ = self.tx.lock().unwrap().send(action);
     }
+
+    /// Report the state of a light to the watchers of the channels whose value changed.
+    pub fn observe_light(&self,
+                         hub_id: &str,
+                         light_id: &str,
+                         state: &structs::SettingsLightState) {
+        let on_off = |on: bool| Value::new(if on { OnOff::On } else { OnOff::Off });
+        let mut values = vec![(create_channel_id("available", hub_id, light_id),
+                               on_off(state.reachable)),
+                              (create_channel_id("power", hub_id, light_id), on_off(state.on))];
+        if state.hue.is_some() {
+            let (h, s, v) = lights::state_color(state);
+            values.push((create_channel_id("color", hub_id, light_id),
+                         Value::new(Color::HSV(h, s, v))));
+        }
+        for (id, value) in values {
+            if self.watchers.latest(&id).as_ref() != Some(&value) {
+                self.watchers.update(&id, value);
+            }
+        }
+    }
 }
 
 pub fn create_adapter_id() -> Id<AdapterId> {
@@ -299,9 +325,17 @@ impl<C: Controller> Adapter for PhilipsHueAdapter<C> {
                     }
                     return (id, Ok(()));
                 }
+                if id == light.channel_identify_id {
+                    light.identify();
+                    return (id, Ok(()));
+                }
 
                 (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
             })
             .collect()
     }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
 }
@@ -91,6 +91,41 @@ pub struct SettingsLightState {
     pub alert: String,
 }
 
+/// A message of the event stream of a bridge (CLIP v2 API), e.g. `{"type": "update",
+/// "data": [{"type": "light", "id_v1": "/lights/3", "on": {"on": false}, ...}], ...}`.
+#[derive(Deserialize, Debug)]
+pub struct EventStreamMessage {
+    #[serde(rename="type")]
+    pub message_type: String,
+    pub data: Vec<EventStreamResource>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EventStreamResource {
+    #[serde(rename="type")]
+    pub resource_type: String,
+    /// The path of the resource in the v1 API, e.g. "/lights/3".
+    pub id_v1: Option<String>,
+}
+
+/// The ids of the lights concerned by the data of an event of the event stream.
+pub fn event_light_ids(data: &str) -> Vec<String> {
+    let messages: Vec<EventStreamMessage> = parse_json(data).unwrap_or_else(Vec::new);
+    let mut ids = Vec::new();
+    for message in messages {
+        for resource in message.data {
+            let light_id = match resource.id_v1 {
+                Some(ref id) if id.starts_with("/lights/") => id["/lights/".len()..].to_owned(),
+                _ => continue,
+            };
+            if !ids.contains(&light_id) {
+                ids.push(light_id);
+            }
+        }
+    }
+    ids
+}
+
 impl Settings {
     pub fn new(json: &str) -> Option<Settings> {
         parse_json(json)
@@ -138,4 +173,17 @@ describe! philips_hue_struct {
         assert_eq!(res.state.on, true);
     }
 
+    it "should find the lights concerned by an event" {
+        let data = r#"[{"creationtime": "2021-03-02T10:12:01Z", "id": "a1b2", "type": "update",
+        "data": [{"id": "c3d4", "id_v1": "/lights/3", "on": {"on": false}, "type": "light"},
+        {"id": "e5f6", "id_v1": "/lights/3", "status": "connected",
+        "type": "zigbee_connectivity"},
+        {"id": "a7b8", "id_v1": "/sensors/12", "button": {"last_event": "short_release"},
+        "type": "button"}]},
+        {"id": "c9d0", "type": "update", "data": [{"id": "e1f2", "id_v1": "/lights/1",
+        "dimming": {"brightness": 50.0}, "type": "light"}]}]"#;
+        assert_eq!(event_light_ids(data), vec!["3".to_owned(), "1".to_owned()]);
+        assert!(event_light_ids("hi").is_empty());
+    }
+
 }
@@ -0,0 +1,307 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter making the house look occupied while everybody is away, by turning lights on
+//! and off.
+//!
+//! The `presence` service exposes `presence/simulate` (fetch, watch, send): `On` while the
+//! simulation runs. It follows `presence.enabled` ("false" by default), so that a mode such as
+//! "vacation" may also start the simulation by overriding this property.
+//!
+//! The simulation drives the lights matching `presence.lights` (by default, all the channels
+//! `light/is-on`). Lights that record their history replay what they did at the same time
+//! `presence.replay_days` days ago (7 by default). The other lights are turned on and off at
+//! random during `presence.window` ("18:00-23:30" by default). Once the simulation stops, the
+//! lights that it turned on are turned off.
+
+mod schedule;
+
+use self::schedule::RandomSchedule;
+
+use foxbox_core::config_store::{ConfigChange, ConfigService};
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{API, Error, InternalError, Targetted, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::history::HistoryRange;
+use foxbox_taxonomy::io::Payload;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::parse::{Parser, Path};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, OnOff, Value};
+
+use chrono::{Local, Timelike};
+use rand;
+use serde_json;
+
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static ADAPTER_NAME: &'static str = "Presence simulation adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+static DEFAULT_LIGHTS: &'static str = r#"[{"feature": "light/is-on"}]"#;
+
+static DEFAULT_WINDOW: &'static str = "18:00-23:30";
+
+/// How often the lights are updated.
+const TICK_S: u64 = 60;
+
+const MS_PER_DAY: u64 = 24 * 3600 * 1000;
+
+fn on_off(on: bool) -> Value {
+    Value::new(if on { OnOff::On } else { OnOff::Off })
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() * 1000 + now.subsec_nanos() as u64 / 1_000_000)
+        .unwrap_or(0)
+}
+
+fn is_enabled(config: &ConfigService) -> bool {
+    config.get_or_set_default("presence", "enabled", "false") == "true"
+}
+
+pub struct PresenceAdapter {
+    config: Arc<ConfigService>,
+    watchers: ValueWatchers,
+}
+
+/// Drives the lights while the simulation runs.
+struct Simulation {
+    manager: Arc<AdapterManager>,
+    lights: Vec<ChannelSelector>,
+    replay_days: u64,
+    schedule: RandomSchedule,
+    /// The state in which the simulation last put each light.
+    driven: HashMap<Id<Channel>, bool>,
+}
+
+impl Simulation {
+    fn send(&self, id: &Id<Channel>, on: bool) {
+        let payload =
+            Payload::from_data(if on { OnOff::On } else { OnOff::Off }, &format::ON_OFF)
+                .unwrap();
+        let results = self.manager.send_values(vec![Targetted {
+                                                        select: vec![ChannelSelector::new()
+                                                                         .with_id(id)],
+                                                        payload: payload,
+                                                    }],
+                                               User::None);
+        for (id, result) in results {
+            if let Err(err) = result {
+                warn!("[presence] Could not turn light {} {}: {:?}",
+                      id,
+                      if on { "on" } else { "off" },
+                      err);
+            }
+        }
+    }
+
+    /// Put each light in the state it should be in now. Lights are only sent a value when
+    /// the simulation changes its mind, so that somebody may still turn them on or off by
+    /// hand meanwhile.
+    fn tick(&mut self) {
+        let then = now_ms() - self.replay_days * MS_PER_DAY;
+        let history = self.manager.get_history(self.lights.clone(),
+                                               HistoryRange {
+                                                   from: Some(then - MS_PER_DAY),
+                                                   to: Some(then),
+                                               });
+        let now = Local::now();
+        let minute = now.hour() * 60 + now.minute();
+        let mut rng = rand::thread_rng();
+        let lights = self.manager
+            .get_channels(self.lights.clone())
+            .into_iter()
+            .filter(|channel| channel.supports_send.is_some());
+        for light in lights {
+            let replayed = match history.get(&light.id) {
+                Some(&Ok(ref entries)) => schedule::replayed_state(entries, then),
+                _ => None,
+            };
+            let on = match replayed {
+                Some(on) => on,
+                None => self.schedule.is_on(&light.id.to_string(), minute, &mut rng),
+            };
+            if self.driven.get(&light.id) != Some(&on) {
+                self.send(&light.id, on);
+                self.driven.insert(light.id, on);
+            }
+        }
+    }
+
+    /// Turn off the lights that the simulation turned on.
+    fn stop(&mut self) {
+        let driven: Vec<_> = self.driven.drain().collect();
+        for (id, on) in driven {
+            self.schedule.forget(&id.to_string());
+            if on {
+                self.send(&id, false);
+            }
+        }
+    }
+}
+
+impl PresenceAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("presence@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:presence@link.mozilla.org")
+    }
+
+    pub fn simulate_id() -> Id<Channel> {
+        Id::new("channel:simulate.presence@link.mozilla.org")
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let source = config.get_or_set_default("presence", "lights", DEFAULT_LIGHTS);
+        let json = try!(serde_json::from_str(&source).map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        let lights = try!(Vec::<ChannelSelector>::parse(Path::new(), &json).map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{:?}", err)))
+        }));
+        let replay_days = config.get_or_set_default("presence", "replay_days", "7")
+            .parse()
+            .unwrap_or(7);
+        let source = config.get_or_set_default("presence", "window", DEFAULT_WINDOW);
+        let window = try!(schedule::parse_window(&source).ok_or_else(|| {
+            Error::Internal(InternalError::GenericError(format!("Invalid presence.window {}, \
+                                                                 expected HH:MM-HH:MM",
+                                                                source)))
+        }));
+
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(PresenceAdapter {
+            config: config.clone(),
+            watchers: watchers.clone(),
+        })));
+        let mut service = Service::empty(&Self::service_id(), &Self::id());
+        service.properties.insert("model".to_owned(), "Presence simulation".to_owned());
+        try!(manager.add_service(service));
+        try!(manager.add_channel(Channel {
+            id: Self::simulate_id(),
+            service: Self::service_id(),
+            adapter: Self::id(),
+            feature: Id::new("presence/simulate"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+            supports_watch: Some(Signature {
+                accepts: Maybe::Optional(format::ON_OFF.clone()),
+                returns: Maybe::Required(format::ON_OFF.clone()),
+            }),
+            supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+            ..Channel::default()
+        }));
+        let enabled = is_enabled(&config);
+        watchers.update(&Self::simulate_id(), on_off(enabled));
+
+        let (tx, rx) = mpsc::channel();
+        config.watch("presence", tx);
+        let simulation = Simulation {
+            manager: manager.clone(),
+            lights: lights,
+            replay_days: replay_days,
+            schedule: RandomSchedule::new(window),
+            driven: HashMap::new(),
+        };
+        thread::Builder::new()
+            .name("Presence simulation".to_owned())
+            .spawn(move || Self::run(simulation, enabled, rx, &watchers))
+            .unwrap();
+        Ok(())
+    }
+
+    /// Follow `presence.enabled`, and drive the lights while it is "true", forever.
+    fn run(mut simulation: Simulation,
+           mut enabled: bool,
+           changes: mpsc::Receiver<ConfigChange>,
+           watchers: &ValueWatchers) {
+        loop {
+            if enabled {
+                simulation.tick();
+            }
+            match changes.recv_timeout(Duration::from_secs(TICK_S)) {
+                Ok(ConfigChange { ref property, ref value, .. }) if property == "enabled" => {
+                    let on = value.as_ref().map_or(false, |value| value == "true");
+                    if on == enabled {
+                        continue;
+                    }
+                    enabled = on;
+                    info!("[presence] Simulation {}", if on { "started" } else { "stopped" });
+                    if !on {
+                        simulation.stop();
+                    }
+                    watchers.update(&Self::simulate_id(), on_off(on));
+                }
+                Ok(_) |
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+}
+
+impl Adapter for PresenceAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                if id != Self::simulate_id() {
+                    return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))));
+                }
+                (id, Ok(Some(on_off(is_enabled(&self.config)))))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                if id != Self::simulate_id() {
+                    return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))));
+                }
+                let result = value.cast::<OnOff>().map(|on| {
+                    let enabled = if *on == OnOff::On { "true" } else { "false" };
+                    self.config.set("presence", "enabled", enabled);
+                });
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Whether each simulated light should be on.
+//!
+//! A light that records its history replays what it did at the same time some days ago. A
+//! light without history follows a randomized schedule instead: off outside of the evening
+//! window, and turned on and off at random intervals during it, so that the house doesn't look
+//! the same every night.
+
+use foxbox_taxonomy::history::HistoryEntry;
+
+use rand::Rng;
+use serde_json::value::Value as JSON;
+
+use std::collections::HashMap;
+
+/// Shortest and longest time that a light stays on, in minutes, when following the
+/// randomized schedule.
+const ON_MINUTES: (u32, u32) = (15, 90);
+
+/// Shortest and longest time that a light stays off, in minutes, during the evening window.
+const OFF_MINUTES: (u32, u32) = (5, 45);
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Parse a window of time of day "HH:MM-HH:MM", as (first minute, last minute) of the day.
+/// The window may span midnight, e.g. "22:00-01:00".
+pub fn parse_window(source: &str) -> Option<(u32, u32)> {
+    let minute = |time: &str| {
+        let mut parts = time.trim().splitn(2, ':');
+        let hours = parts.next().and_then(|hours| hours.parse::<u32>().ok());
+        let minutes = parts.next().and_then(|minutes| minutes.parse::<u32>().ok());
+        match (hours, minutes) {
+            (Some(hours), Some(minutes)) if hours < 24 && minutes < 60 => {
+                Some(hours * 60 + minutes)
+            }
+            _ => None,
+        }
+    };
+    let mut parts = source.splitn(2, '-');
+    match (parts.next().and_then(&minute), parts.next().and_then(&minute)) {
+        (Some(start), Some(end)) => Some((start, end)),
+        _ => None,
+    }
+}
+
+/// Whether a light was on at `timestamp`, from its history, oldest first. `None` if the
+/// history doesn't tell.
+pub fn replayed_state(history: &[HistoryEntry], timestamp: u64) -> Option<bool> {
+    history.iter()
+        .rev()
+        .find(|entry| entry.timestamp <= timestamp)
+        .and_then(|entry| match entry.value {
+            JSON::String(ref value) if value == "On" => Some(true),
+            JSON::String(ref value) if value == "Off" => Some(false),
+            _ => None,
+        })
+}
+
+/// The state of a light following the randomized schedule.
+struct RandomLight {
+    is_on: bool,
+    /// The minute of the day at which to toggle the light.
+    toggle_at: u32,
+}
+
+pub struct RandomSchedule {
+    window: (u32, u32),
+    lights: HashMap<String, RandomLight>,
+}
+
+impl RandomSchedule {
+    pub fn new(window: (u32, u32)) -> Self {
+        RandomSchedule {
+            window: window,
+            lights: HashMap::new(),
+        }
+    }
+
+    fn in_window(&self, minute: u32) -> bool {
+        let (start, end) = self.window;
+        if start <= end {
+            minute >= start && minute <= end
+        } else {
+            minute >= start || minute <= end
+        }
+    }
+
+    /// Whether `light` should be on at `minute` of the day. Lights are turned on at the start
+    /// of the window, then toggled after random delays, and off outside of the window.
+    pub fn is_on<R: Rng>(&mut self, light: &str, minute: u32, rng: &mut R) -> bool {
+        if !self.in_window(minute) {
+            self.lights.remove(light);
+            return false;
+        }
+        let next = |rng: &mut R, (shortest, longest): (u32, u32)| {
+            (minute + rng.gen_range(shortest, longest + 1)) % MINUTES_PER_DAY
+        };
+        if !self.lights.contains_key(light) {
+            let toggle_at = next(rng, ON_MINUTES);
+            self.lights.insert(light.to_owned(),
+                               RandomLight {
+                                   is_on: true,
+                                   toggle_at: toggle_at,
+                               });
+            return true;
+        }
+        let state = self.lights.get_mut(light).unwrap();
+        if state.toggle_at == minute {
+            state.is_on = !state.is_on;
+            state.toggle_at = next(rng, if state.is_on { ON_MINUTES } else { OFF_MINUTES });
+        }
+        state.is_on
+    }
+
+    /// Forget a light, e.g. once the simulation stops.
+    pub fn forget(&mut self, light: &str) {
+        self.lights.remove(light);
+    }
+}
+
+#[cfg(test)]
+describe! presence_schedule {
+    before_each {
+        use super::*;
+        use foxbox_taxonomy::history::HistoryEntry;
+        use rand::{SeedableRng, XorShiftRng};
+        use serde_json::value::Value as JSON;
+
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    }
+
+    it "should parse windows of time of day" {
+        assert_eq!(parse_window("18:00-23:30"), Some((18 * 60, 23 * 60 + 30)));
+        assert_eq!(parse_window("22:00 - 01:00"), Some((22 * 60, 60)));
+        assert_eq!(parse_window("18:00"), None);
+        assert_eq!(parse_window("25:00-26:00"), None);
+    }
+
+    it "should replay the history of a light" {
+        let entry = |timestamp, value: &str| {
+            HistoryEntry {
+                timestamp: timestamp,
+                value: JSON::String(value.to_owned()),
+            }
+        };
+        let history = vec![entry(1000, "On"), entry(2000, "Off"), entry(3000, "On")];
+        assert_eq!(replayed_state(&history, 500), None);
+        assert_eq!(replayed_state(&history, 1500), Some(true));
+        assert_eq!(replayed_state(&history, 2000), Some(false));
+        assert_eq!(replayed_state(&history, 5000), Some(true));
+        assert_eq!(replayed_state(&[], 5000), None);
+    }
+
+    it "should keep lights off outside of the window" {
+        let mut schedule = RandomSchedule::new((18 * 60, 23 * 60));
+        assert!(!schedule.is_on("hall", 12 * 60, &mut rng));
+        assert!(schedule.is_on("hall", 18 * 60, &mut rng));
+        assert!(!schedule.is_on("hall", 23 * 60 + 1, &mut rng));
+    }
+
+    it "should toggle lights during the window" {
+        let mut schedule = RandomSchedule::new((18 * 60, 23 * 60 + 59));
+        let states: Vec<_> = (18 * 60..24 * 60)
+            .map(|minute| schedule.is_on("hall", minute, &mut rng))
+            .collect();
+        assert!(states[0]);
+        // Lights stay on for a while, then go off at some point.
+        assert!(states[..ON_MINUTES.0 as usize].iter().all(|&on| on));
+        assert!(states.iter().any(|&on| !on));
+    }
+
+    it "should support windows spanning midnight" {
+        let mut schedule = RandomSchedule::new((22 * 60, 60));
+        assert!(schedule.is_on("hall", 22 * 60, &mut rng));
+        assert!(!schedule.is_on("hall", 2 * 60, &mut rng));
+    }
+}
@@ -0,0 +1,254 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter composing daily and weekly summary reports.
+//!
+//! The adapter watches all the channels of the box and keeps a journal of the past week (see
+//! `report`). The `reports` service exposes:
+//! - `report/daily` and `report/weekly` (fetch): the report of the past day or week, as JSON;
+//! - `report/daily-html` and `report/weekly-html` (fetch): the same, as a printer-friendly
+//!   HTML page;
+//! - `report/deliver` (send): send `"daily"` or `"weekly"` to compose the report and deliver
+//!   a summary of it as a WebPush notification on resource `reports.webpush_resource`
+//!   ("reports" by default), on behalf of the sender.
+//!
+//! To receive a report every morning, write a Thinkerbell rule that watches the
+//! `clock/time-of-day-seconds` channel and sends `"daily"` to `report/deliver`.
+
+mod report;
+
+use self::report::{Journal, Period};
+
+use foxbox_core::thread_pool::ThreadPool;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::api::{API, Error, InternalError, Operation, Targetted, User, WatchEvent};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::io::Payload;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::parse::{Parser, Path, ToJSON};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::util::Exactly;
+use foxbox_taxonomy::values::{format, Json, Value};
+
+use chrono::UTC;
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+static ADAPTER_NAME: &'static str = "Reports adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+pub struct ReportsAdapter {
+    journal: Arc<Mutex<Journal>>,
+    manager: Arc<AdapterManager>,
+    resource: String,
+    pool: ThreadPool,
+}
+
+impl ReportsAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("reports@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:reports@link.mozilla.org")
+    }
+
+    pub fn channel_id(name: &str) -> Id<Channel> {
+        Id::new(&format!("channel:{}.reports@link.mozilla.org", name))
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let resource = controller.get_config()
+            .get_or_set_default("reports", "webpush_resource", "reports");
+        let journal = Arc::new(Mutex::new(Journal::new()));
+        try!(manager.add_adapter(Arc::new(ReportsAdapter {
+            journal: journal.clone(),
+            manager: manager.clone(),
+            resource: resource,
+            pool: controller.get_thread_pool(),
+        })));
+        try!(manager.add_service(Service::empty(&Self::service_id(), &Self::id())));
+
+        for period in &[Period::Daily, Period::Weekly] {
+            let name = period.as_str();
+            try!(manager.add_channel(Channel {
+                id: Self::channel_id(name),
+                service: Self::service_id(),
+                adapter: Self::id(),
+                feature: Id::new(&format!("report/{}", name)),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                ..Channel::default()
+            }));
+            try!(manager.add_channel(Channel {
+                id: Self::channel_id(&format!("{}-html", name)),
+                service: Self::service_id(),
+                adapter: Self::id(),
+                feature: Id::new(&format!("report/{}-html", name)),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::STRING.clone()))),
+                ..Channel::default()
+            }));
+        }
+        try!(manager.add_channel(Channel {
+            id: Self::channel_id("deliver"),
+            service: Self::service_id(),
+            adapter: Self::id(),
+            feature: Id::new("report/deliver"),
+            supports_send: Some(Signature::accepts(Maybe::Required(format::STRING.clone()))),
+            ..Channel::default()
+        }));
+
+        let manager = manager.clone();
+        thread::Builder::new()
+            .name("ReportsJournal".to_owned())
+            .spawn(move || Self::keep_journal(&manager, &journal))
+            .unwrap();
+        Ok(())
+    }
+
+    /// Record all the values reported by channels, forever.
+    fn keep_journal(manager: &AdapterManager, journal: &Mutex<Journal>) {
+        let (tx, rx) = mpsc::channel::<WatchEvent>();
+        // Keep the guard alive for as long as we are recording.
+        let _guard = manager.watch_values(vec![Targetted {
+                                               select: vec![ChannelSelector::new()],
+                                               payload: Exactly::Always,
+                                           }],
+                                          Box::new(tx),
+                                          User::None);
+        for channel in manager.get_channels(vec![ChannelSelector::new()]) {
+            journal.lock()
+                .unwrap()
+                .add_channel(&channel.id.to_string(), &channel.feature.to_string());
+        }
+        for event in rx {
+            match event {
+                WatchEvent::ChannelAdded(id) => {
+                    let channels = manager.get_channels(vec![ChannelSelector::new().with_id(&id)]);
+                    let mut journal = journal.lock().unwrap();
+                    for channel in channels {
+                        journal.add_channel(&channel.id.to_string(), &channel.feature.to_string());
+                    }
+                }
+                WatchEvent::ChannelRemoved(id) => {
+                    journal.lock().unwrap().remove_channel(&id.to_string())
+                }
+                WatchEvent::EnterRange { channel, value, .. } => {
+                    journal.lock()
+                        .unwrap()
+                        .record(&channel.to_string(), value.to_json(), UTC::now())
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Send a summary of a report as a WebPush notification. This goes through the
+    /// `AdapterManager`, so we must not be called from one of its callbacks.
+    fn deliver(manager: &AdapterManager, resource: &str, message: String, user: User) {
+        let mut notification = BTreeMap::new();
+        notification.insert("resource".to_owned(), JSON::String(resource.to_owned()));
+        notification.insert("message".to_owned(), JSON::String(message));
+        let payload = match Payload::parse(Path::new(), &JSON::Object(notification)) {
+            Ok(payload) => payload,
+            Err(err) => return error!("[reports] Could not build notification: {:?}", err),
+        };
+        let results = manager.send_values(vec![Targetted {
+                                              select: vec![ChannelSelector::new()
+                                                  .with_feature(&Id::new("webpush/notify-msg"))],
+                                              payload: payload,
+                                          }],
+                                          user);
+        if results.is_empty() {
+            warn!("[reports] No notification channel to deliver reports to");
+        }
+        for (id, result) in results {
+            if let Err(err) = result {
+                warn!("[reports] Could not deliver report to {}: {:?}", id, err);
+            }
+        }
+    }
+}
+
+impl Adapter for ReportsAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        let journal = self.journal.lock().unwrap();
+        set.drain(..)
+            .map(|id| {
+                for period in &[Period::Daily, Period::Weekly] {
+                    if id == Self::channel_id(period.as_str()) {
+                        let report = journal.compose(*period, UTC::now());
+                        return (id, Ok(Some(Value::new(Json(report.to_json())))));
+                    }
+                    if id == Self::channel_id(&format!("{}-html", period.as_str())) {
+                        let report = journal.compose(*period, UTC::now());
+                        return (id, Ok(Some(Value::new(report.to_html()))));
+                    }
+                }
+                if id == Self::channel_id("deliver") {
+                    return (id.clone(), Err(Error::OperationNotSupported(Operation::Fetch, id)));
+                }
+                (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   user: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                if id != Self::channel_id("deliver") {
+                    return (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id)));
+                }
+                let period = match value.cast::<String>() {
+                    Ok(source) => {
+                        match Period::parse(source) {
+                            Some(period) => period,
+                            None => {
+                                let err = format!("Unknown report period {}", source);
+                                return (id, Err(Error::Internal(InternalError::GenericError(err))));
+                            }
+                        }
+                    }
+                    Err(err) => return (id, Err(err)),
+                };
+                let message = self.journal.lock().unwrap().compose(period, UTC::now()).to_text();
+                let manager = self.manager.clone();
+                let resource = self.resource.clone();
+                let user = user.clone();
+                let job = move || Self::deliver(&manager, &resource, message, user);
+                if self.pool.execute(job).is_err() {
+                    let err = "Too many pending deliveries".to_owned();
+                    return (id, Err(Error::Internal(InternalError::GenericError(err))));
+                }
+                (id, Ok(()))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,338 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Composition and rendering of summary reports.
+//!
+//! The `Journal` keeps the values reported by channels over the past week. A `Report` is a
+//! summary of a period of the journal:
+//! - the number of events per channel;
+//! - the energy used, for meter channels (features starting with `energy/`), computed as the
+//!   difference between the last and the first reading of the period;
+//! - the alerts that are still open, i.e. channels with a feature starting with `alarm/` whose
+//!   latest value is `"Detected"`.
+
+use chrono::{DateTime, Duration, UTC};
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// We never keep more events than this, whatever their age.
+const MAX_EVENTS: usize = 50000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    Daily,
+    Weekly,
+}
+
+impl Period {
+    pub fn parse(source: &str) -> Option<Period> {
+        match source {
+            "daily" => Some(Period::Daily),
+            "weekly" => Some(Period::Weekly),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Period::Daily => "daily",
+            Period::Weekly => "weekly",
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        match *self {
+            Period::Daily => Duration::days(1),
+            Period::Weekly => Duration::weeks(1),
+        }
+    }
+}
+
+struct Event {
+    timestamp: DateTime<UTC>,
+    channel: String,
+    value: JSON,
+}
+
+/// A reading of a meter, either as a number or as an object with a numeric `value`.
+fn reading(value: &JSON) -> Option<f64> {
+    value.as_f64().or_else(|| value.find("value").and_then(JSON::as_f64))
+}
+
+#[derive(Default)]
+pub struct Journal {
+    features: HashMap<String, String>,
+    latest: HashMap<String, JSON>,
+    events: VecDeque<Event>,
+}
+
+impl Journal {
+    pub fn new() -> Self {
+        Journal::default()
+    }
+
+    /// Channels are only reported once we know their feature.
+    pub fn add_channel(&mut self, channel: &str, feature: &str) {
+        self.features.insert(channel.to_owned(), feature.to_owned());
+    }
+
+    pub fn remove_channel(&mut self, channel: &str) {
+        self.features.remove(channel);
+        self.latest.remove(channel);
+    }
+
+    pub fn record(&mut self, channel: &str, value: JSON, now: DateTime<UTC>) {
+        self.latest.insert(channel.to_owned(), value.clone());
+        self.events.push_back(Event {
+            timestamp: now,
+            channel: channel.to_owned(),
+            value: value,
+        });
+        let oldest = now - Period::Weekly.duration();
+        while self.events.len() > MAX_EVENTS ||
+              self.events.front().map_or(false, |event| event.timestamp < oldest) {
+            self.events.pop_front();
+        }
+    }
+
+    pub fn compose(&self, period: Period, now: DateTime<UTC>) -> Report {
+        let from = now - period.duration();
+        let mut events = BTreeMap::new();
+        let mut meters: BTreeMap<&str, (f64, f64)> = BTreeMap::new();
+        let mut total = 0;
+        for event in self.events.iter().filter(|event| event.timestamp >= from) {
+            let feature = match self.features.get(&event.channel) {
+                Some(feature) => feature,
+                None => continue,
+            };
+            total += 1;
+            *events.entry(event.channel.clone()).or_insert(0) += 1;
+            if feature.starts_with("energy/") {
+                if let Some(reading) = reading(&event.value) {
+                    meters.entry(&event.channel).or_insert((reading, reading)).1 = reading;
+                }
+            }
+        }
+        let alerts = self.latest
+            .iter()
+            .filter(|&(channel, value)| {
+                let is_alarm = self.features
+                    .get(channel)
+                    .map_or(false, |feature| feature.starts_with("alarm/"));
+                is_alarm && value.as_string() == Some("Detected")
+            })
+            .map(|(channel, _)| channel.clone())
+            .collect::<Vec<_>>();
+        let feature = |channel: &str| {
+            self.features.get(channel).cloned().unwrap_or_else(String::new)
+        };
+        let mut report = Report {
+            period: period,
+            from: from,
+            to: now,
+            total_events: total,
+            events: events.into_iter()
+                .map(|(channel, count)| {
+                    let feature = feature(&channel);
+                    (channel, feature, count)
+                })
+                .collect(),
+            energy: meters.into_iter()
+                .map(|(channel, (first, last))| {
+                    (channel.to_owned(), feature(channel), last - first)
+                })
+                .collect(),
+            alerts: alerts.into_iter()
+                .map(|channel| {
+                    let feature = feature(&channel);
+                    (channel, feature)
+                })
+                .collect(),
+        };
+        report.alerts.sort();
+        report
+    }
+}
+
+/// A summary of a period.
+#[derive(Debug)]
+pub struct Report {
+    pub period: Period,
+    pub from: DateTime<UTC>,
+    pub to: DateTime<UTC>,
+    pub total_events: usize,
+    /// `(channel, feature, number of events)`.
+    pub events: Vec<(String, String, usize)>,
+    /// `(channel, feature, energy used)`.
+    pub energy: Vec<(String, String, f64)>,
+    /// `(channel, feature)`.
+    pub alerts: Vec<(String, String)>,
+}
+
+fn escape(source: &str) -> String {
+    let mut result = String::with_capacity(source.len());
+    for c in source.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn object(fields: Vec<(&str, JSON)>) -> JSON {
+    JSON::Object(fields.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+}
+
+impl Report {
+    pub fn to_json(&self) -> JSON {
+        object(vec![
+            ("period", JSON::String(self.period.as_str().to_owned())),
+            ("from", JSON::String(self.from.to_rfc3339())),
+            ("to", JSON::String(self.to.to_rfc3339())),
+            ("total_events", JSON::U64(self.total_events as u64)),
+            ("events", JSON::Array(self.events.iter().map(|&(ref channel, ref feature, count)| {
+                object(vec![("channel", JSON::String(channel.clone())),
+                            ("feature", JSON::String(feature.clone())),
+                            ("count", JSON::U64(count as u64))])
+            }).collect())),
+            ("energy", JSON::Array(self.energy.iter().map(|&(ref channel, ref feature, used)| {
+                object(vec![("channel", JSON::String(channel.clone())),
+                            ("feature", JSON::String(feature.clone())),
+                            ("used", JSON::F64(used))])
+            }).collect())),
+            ("alerts", JSON::Array(self.alerts.iter().map(|&(ref channel, ref feature)| {
+                object(vec![("channel", JSON::String(channel.clone())),
+                            ("feature", JSON::String(feature.clone()))])
+            }).collect())),
+        ])
+    }
+
+    /// A standalone, printer-friendly HTML page.
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">");
+        html.push_str("<style>body{font-family:sans-serif}table{border-collapse:collapse}");
+        html.push_str("td,th{border:1px solid #999;padding:2px 8px;text-align:left}</style>");
+        html.push_str(&format!("<title>FoxBox {} report</title></head><body>\n",
+                               self.period.as_str()));
+        html.push_str(&format!("<h1>FoxBox {} report</h1>\n<p>From {} to {}.</p>\n",
+                               self.period.as_str(),
+                               self.from.format("%Y-%m-%d %H:%M UTC"),
+                               self.to.format("%Y-%m-%d %H:%M UTC")));
+
+        html.push_str("<h2>Open alerts</h2>\n");
+        if self.alerts.is_empty() {
+            html.push_str("<p>None.</p>\n");
+        } else {
+            html.push_str("<table><tr><th>Alert</th><th>Channel</th></tr>\n");
+            for &(ref channel, ref feature) in &self.alerts {
+                html.push_str(&format!("<tr><td>{}</td><td>{}</td></tr>\n",
+                                       escape(feature),
+                                       escape(channel)));
+            }
+            html.push_str("</table>\n");
+        }
+
+        html.push_str("<h2>Energy</h2>\n");
+        if self.energy.is_empty() {
+            html.push_str("<p>No meter readings.</p>\n");
+        } else {
+            html.push_str("<table><tr><th>Meter</th><th>Channel</th><th>Used</th></tr>\n");
+            for &(ref channel, ref feature, used) in &self.energy {
+                html.push_str(&format!("<tr><td>{}</td><td>{}</td><td>{:.2}</td></tr>\n",
+                                       escape(feature),
+                                       escape(channel),
+                                       used));
+            }
+            html.push_str("</table>\n");
+        }
+
+        html.push_str(&format!("<h2>Events</h2>\n<p>{} events.</p>\n", self.total_events));
+        if !self.events.is_empty() {
+            html.push_str("<table><tr><th>Feature</th><th>Channel</th><th>Events</th></tr>\n");
+            for &(ref channel, ref feature, count) in &self.events {
+                html.push_str(&format!("<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                                       escape(feature),
+                                       escape(channel),
+                                       count));
+            }
+            html.push_str("</table>\n");
+        }
+        html.push_str("</body></html>\n");
+        html
+    }
+
+    /// A one line summary, short enough for a push notification.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{} report: {} events", self.period.as_str(), self.total_events);
+        if !self.energy.is_empty() {
+            let used = self.energy.iter().fold(0.0, |sum, &(_, _, used)| sum + used);
+            text.push_str(&format!(", {:.2} energy used", used));
+        }
+        match self.alerts.len() {
+            0 => text.push_str(", no open alert."),
+            1 => text.push_str(&format!(", 1 open alert ({}).", self.alerts[0].1)),
+            count => text.push_str(&format!(", {} open alerts.", count)),
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+describe! reports_report {
+    before_each {
+        use super::*;
+        use chrono::{Duration, TimeZone, UTC};
+        use serde_json::value::Value as JSON;
+
+        let now = UTC.ymd(2016, 7, 1).and_hms(8, 0, 0);
+        let mut journal = Journal::new();
+        journal.add_channel("meter", "energy/consumption");
+        journal.add_channel("smoke", "alarm/smoke-detected");
+        journal.add_channel("door", "door/is-open");
+        journal.record("meter", JSON::F64(100.0), now - Duration::days(2));
+        journal.record("meter", JSON::F64(110.0), now - Duration::hours(20));
+        journal.record("door", JSON::String("Open".to_owned()), now - Duration::hours(3));
+        journal.record("smoke", JSON::String("Detected".to_owned()), now - Duration::hours(2));
+        journal.record("meter", JSON::F64(112.5), now - Duration::hours(1));
+    }
+
+    it "should compose daily and weekly reports" {
+        journal.remove_channel("door");
+        let daily = journal.compose(Period::Daily, now);
+        assert_eq!(daily.total_events, 3);
+        assert_eq!(daily.energy, vec![("meter".to_owned(), "energy/consumption".to_owned(), 2.5)]);
+        assert_eq!(daily.alerts,
+                   vec![("smoke".to_owned(), "alarm/smoke-detected".to_owned())]);
+
+        let weekly = journal.compose(Period::Weekly, now);
+        assert_eq!(weekly.total_events, 4);
+        assert_eq!(weekly.energy[0].2, 12.5);
+        let json = weekly.to_json();
+        assert_eq!(json.find("period").and_then(JSON::as_string), Some("weekly"));
+    }
+
+    it "should forget old events and unknown channels" {
+        journal.record("meter", JSON::F64(113.0), now + Duration::days(8));
+        journal.record("ghost", JSON::F64(1.0), now + Duration::days(8));
+        let weekly = journal.compose(Period::Weekly, now + Duration::days(8));
+        assert_eq!(weekly.total_events, 1);
+        assert_eq!(weekly.energy[0].2, 0.0);
+    }
+
+    it "should render escaped html and text" {
+        journal.add_channel("smoke", "alarm/<smoke>");
+        let daily = journal.compose(Period::Daily, now);
+        let html = daily.to_html();
+        assert!(html.contains("<td>alarm/&lt;smoke&gt;</td>"));
+        assert!(!html.contains("<smoke>"));
+        assert_eq!(daily.to_text(),
+                   "daily report: 4 events, 2.50 energy used, 1 open alert (alarm/<smoke>).");
+    }
+}
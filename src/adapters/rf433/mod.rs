@@ -0,0 +1,370 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter for cheap 433MHz devices.
+//!
+//! Receiving is delegated to an `rtl_433` subprocess (with an RTL-SDR dongle), whose decoded
+//! events are exposed as services: weather stations (a `weather/reading` channel, as JSON)
+//! and door/window sensors (a `door/is-open` channel). Devices are added the first time we
+//! hear from them.
+//!
+//! Power plugs can't be discovered, so they are declared in the configuration, as a comma
+//! separated list of `address/unit` (e.g. `0x123abcd/1`) in `rf433.ac_plugs`. Commands are
+//! sent through an RFXtrx433 transceiver, configured in `rf433.rfxtrx_device`.
+//!
+//! `rtl_433` is only started if it is configured in `rf433.rtl_433` or found in the `PATH`, and
+//! the adapter itself only if there is something to receive from or send to.
+
+mod rfxtrx;
+mod rtl433;
+
+use foxbox_core::config_store::ConfigService;
+use foxbox_core::managed_process::ManagedProcess;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, OnOff, OpenClosed, Value};
+
+use self::rfxtrx::Rfxtrx;
+use self::rtl433::Event;
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+static ADAPTER_NAME: &'static str = "433MHz adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+/// A power plug using the Lighting2/AC protocol.
+#[derive(Clone, Copy)]
+struct Plug {
+    address: u32,
+    unit: u8,
+}
+
+pub struct Rf433Adapter {
+    watchers: ValueWatchers,
+    plugs: HashMap<Id<Channel>, Plug>,
+    rfxtrx: Option<Mutex<Rfxtrx>>,
+    rtl_433: Mutex<Option<ManagedProcess>>,
+}
+
+impl Rf433Adapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("rf433@link.mozilla.org")
+    }
+
+    pub fn service_id(device: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.rf433@link.mozilla.org", device))
+    }
+
+    pub fn getter_weather_id(device: &str) -> Id<Channel> {
+        Id::new(&format!("getter:weather.{}.rf433@link.mozilla.org", device))
+    }
+
+    pub fn getter_contact_id(device: &str) -> Id<Channel> {
+        Id::new(&format!("getter:contact.{}.rf433@link.mozilla.org", device))
+    }
+
+    pub fn channel_plug_id(device: &str) -> Id<Channel> {
+        Id::new(&format!("channel:power.{}.rf433@link.mozilla.org", device))
+    }
+
+    /// The `rtl_433` command to run, if configured or found in the `PATH`.
+    fn rtl_433_command(config: &ConfigService) -> Option<String> {
+        if let Some(command) = config.get("rf433", "rtl_433") {
+            return Some(command);
+        }
+        let paths = match env::var_os("PATH") {
+            Some(paths) => paths,
+            None => return None,
+        };
+        env::split_paths(&paths)
+            .map(|dir| dir.join("rtl_433"))
+            .find(|path| path.is_file())
+            .map(|path| path.to_string_lossy().into_owned())
+    }
+
+    /// Whether there is any 433MHz device to use: an RFXtrx433 or plugs to send to, or
+    /// `rtl_433` to receive from.
+    pub fn is_configured(config: &ConfigService) -> bool {
+        config.get("rf433", "rfxtrx_device").is_some() ||
+        config.get("rf433", "ac_plugs").is_some() || Self::rtl_433_command(config).is_some()
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let rfxtrx = match config.get("rf433", "rfxtrx_device") {
+            None => None,
+            Some(device) => {
+                match Rfxtrx::open(&device) {
+                    Ok(rfxtrx) => Some(Mutex::new(rfxtrx)),
+                    Err(err) => {
+                        error!("[rf433] Could not open RFXtrx device {}: {}", device, err);
+                        None
+                    }
+                }
+            }
+        };
+
+        let mut plugs = HashMap::new();
+        let mut plug_devices = Vec::new();
+        if let Some(list) = config.get("rf433", "ac_plugs") {
+            for item in list.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+                match Self::parse_plug(item) {
+                    Some(plug) => {
+                        let device = format!("ac-plug-{:07x}-{}", plug.address, plug.unit);
+                        plugs.insert(Self::channel_plug_id(&device), plug);
+                        plug_devices.push(device);
+                    }
+                    None => warn!("[rf433] Ignoring invalid plug definition {}", item),
+                }
+            }
+        }
+
+        let watchers = ValueWatchers::new();
+        let adapter = Arc::new(Rf433Adapter {
+            watchers: watchers.clone(),
+            plugs: plugs,
+            rfxtrx: rfxtrx,
+            rtl_433: Mutex::new(None),
+        });
+        try!(manager.add_adapter(adapter.clone()));
+
+        for device in plug_devices {
+            let mut service = Service::empty(&Self::service_id(&device), &Self::id());
+            service.properties.insert("model".to_owned(), "433MHz power plug (AC)".to_owned());
+            try!(manager.add_service(service));
+            try!(manager.add_channel(Channel {
+                feature: Id::new("power-plug/is-on"),
+                supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+                // The plugs can't report their state, so this is the last state we have sent.
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+                id: Self::channel_plug_id(&device),
+                service: Self::service_id(&device),
+                adapter: Self::id(),
+                ..Channel::default()
+            }));
+        }
+
+        // Now start receiving, if we can.
+        let command = match Self::rtl_433_command(&config) {
+            Some(command) => command,
+            None => {
+                info!("[rf433] rtl_433 is not available, not receiving.");
+                return Ok(());
+            }
+        };
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        let process = ManagedProcess::start(move || {
+            let mut child = try!(Command::new(&command)
+                .args(&["-F", "json"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn());
+            if let Some(stdout) = child.stdout.take() {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    for line in BufReader::new(stdout).lines() {
+                        match line {
+                            Ok(line) => {
+                                if tx.send(line).is_err() {
+                                    break;
+                                }
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                });
+            }
+            Ok(child)
+        });
+        match process {
+            Ok(process) => *adapter.rtl_433.lock().unwrap() = Some(process),
+            Err(err) => error!("[rf433] Could not start rtl_433: {}", err),
+        }
+
+        let manager = manager.clone();
+        thread::spawn(move || {
+            let mut known = HashSet::new();
+            for line in rx {
+                let event = match rtl433::parse_line(&line) {
+                    Some(event) => event,
+                    None => continue,
+                };
+                debug!("[rf433] Received {:?}", event);
+                match event {
+                    Event::Weather { device, model, reading } => {
+                        if known.insert(device.clone()) {
+                            Self::add_weather_station(&manager, &device, &model);
+                        }
+                        watchers.update(&Self::getter_weather_id(&device),
+                                        Value::new(Json(reading)));
+                    }
+                    Event::Contact { device, model, open } => {
+                        if known.insert(device.clone()) {
+                            Self::add_contact_sensor(&manager, &device, &model);
+                        }
+                        watchers.update(&Self::getter_contact_id(&device),
+                                        Value::new(if open {
+                                            OpenClosed::Open
+                                        } else {
+                                            OpenClosed::Closed
+                                        }));
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Parse a plug definition `address/unit`, where `address` is hexadecimal.
+    fn parse_plug(source: &str) -> Option<Plug> {
+        let mut parts = source.splitn(2, '/');
+        let address = parts.next().map(|address| address.trim_left_matches("0x"));
+        let unit = parts.next();
+        match (address, unit) {
+            (Some(address), Some(unit)) => {
+                match (u32::from_str_radix(address, 16), unit.parse::<u8>()) {
+                    (Ok(address), Ok(unit)) if address < 1 << 26 && unit >= 1 && unit <= 16 => {
+                        Some(Plug {
+                            address: address,
+                            unit: unit,
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn add_service(manager: &AdapterManager, device: &str, model: &str) {
+        let mut service = Service::empty(&Self::service_id(device), &Self::id());
+        service.properties.insert("model".to_owned(), model.to_owned());
+        manager.add_service(service).unwrap_or_else(|err| {
+            error!("[rf433] Could not add service for device {}: {}", device, err);
+        });
+    }
+
+    fn add_weather_station(manager: &AdapterManager, device: &str, model: &str) {
+        Self::add_service(manager, device, model);
+        manager.add_channel(Channel {
+                feature: Id::new("weather/reading"),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                supports_watch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                id: Self::getter_weather_id(device),
+                service: Self::service_id(device),
+                adapter: Self::id(),
+                ..Channel::default()
+            })
+            .unwrap_or_else(|err| {
+                error!("[rf433] Could not add weather channel for {}: {}", device, err);
+            });
+    }
+
+    fn add_contact_sensor(manager: &AdapterManager, device: &str, model: &str) {
+        Self::add_service(manager, device, model);
+        manager.add_channel(Channel {
+                id: Self::getter_contact_id(device),
+                service: Self::service_id(device),
+                adapter: Self::id(),
+                // We can't open or close the door.
+                supports_send: None,
+                ..DOOR_IS_OPEN.clone()
+            })
+            .unwrap_or_else(|err| {
+                error!("[rf433] Could not add contact channel for {}: {}", device, err);
+            });
+    }
+}
+
+impl Adapter for Rf433Adapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                let value = self.watchers.latest(&id);
+                (id, Ok(value))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                let plug = match self.plugs.get(&id) {
+                    Some(plug) => *plug,
+                    None => {
+                        return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+                    }
+                };
+                let on = match value.cast::<OnOff>() {
+                    Ok(&OnOff::On) => true,
+                    Ok(&OnOff::Off) => false,
+                    Err(err) => return (id, Err(err)),
+                };
+                let rfxtrx = match self.rfxtrx {
+                    Some(ref rfxtrx) => rfxtrx,
+                    None => {
+                        return (id,
+                                Err(Error::Internal(InternalError::GenericError("No RFXtrx \
+                                                                                 transceiver \
+                                                                                 configured"
+                                    .to_owned()))))
+                    }
+                };
+                let result = rfxtrx.lock().unwrap().send_lighting2(plug.address, plug.unit, on);
+                match result {
+                    Ok(()) => {
+                        self.watchers.update(&id, value.clone());
+                        (id, Ok(()))
+                    }
+                    Err(err) => {
+                        (id, Err(Error::Internal(InternalError::GenericError(format!("{}", err)))))
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+
+    fn stop(&self) {
+        if let Some(process) = self.rtl_433.lock().unwrap().take() {
+            let _ = process.shutdown();
+        }
+    }
+}
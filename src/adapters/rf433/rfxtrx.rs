@@ -0,0 +1,78 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Sending commands to 433MHz power plugs through an RFXtrx433 transceiver.
+//!
+//! Only the "Lighting2 / AC" protocol (KlikAanKlikUit, HomeEasy EU, Chacon, ...) is
+//! supported for now.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+// The baud rate of the RFXtrx serial line.
+static BAUD_RATE: &'static str = "38400";
+
+const PACKET_TYPE_LIGHTING2: u8 = 0x11;
+const SUBTYPE_AC: u8 = 0x00;
+
+/// Encode a Lighting2/AC command. `id` is the 26-bit address of the remote, `unit` the
+/// button (1-16) it controls.
+pub fn encode_lighting2(sequence: u8, id: u32, unit: u8, on: bool) -> [u8; 12] {
+    [0x0B, // Packet length, excluding this byte.
+     PACKET_TYPE_LIGHTING2,
+     SUBTYPE_AC,
+     sequence,
+     ((id >> 24) & 0x03) as u8,
+     (id >> 16) as u8,
+     (id >> 8) as u8,
+     id as u8,
+     unit,
+     if on { 0x01 } else { 0x00 },
+     0x0F, // Level, ignored by on/off commands.
+     0x00]
+}
+
+pub struct Rfxtrx {
+    file: File,
+    sequence: u8,
+}
+
+impl Rfxtrx {
+    /// Open and reset the transceiver plugged at `device` (e.g. "/dev/ttyUSB1").
+    pub fn open(device: &str) -> Result<Self> {
+        // Configure the serial line. This is best-effort, as the device may already be set up.
+        match Command::new("stty").args(&["-F", device, BAUD_RATE, "raw", "-echo"]).status() {
+            Ok(ref status) if status.success() => {}
+            _ => warn!("[rf433] Could not configure serial device {}", device),
+        }
+        let mut file = try!(OpenOptions::new().read(true).write(true).open(device));
+        // Reset the transceiver, then give it time to flush its buffers, as per the spec.
+        try!(file.write_all(&[0x0D, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        thread::sleep(Duration::from_millis(500));
+        Ok(Rfxtrx {
+            file: file,
+            sequence: 0,
+        })
+    }
+
+    pub fn send_lighting2(&mut self, id: u32, unit: u8, on: bool) -> Result<()> {
+        let packet = encode_lighting2(self.sequence, id, unit, on);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.file.write_all(&packet)
+    }
+}
+
+#[cfg(test)]
+describe! rfxtrx {
+    it "should encode lighting2 commands" {
+        use super::encode_lighting2;
+
+        assert_eq!(encode_lighting2(3, 0x0123ABCD, 2, true),
+                   [0x0B, 0x11, 0x00, 0x03, 0x01, 0x23, 0xAB, 0xCD, 0x02, 0x01, 0x0F, 0x00]);
+        assert_eq!(encode_lighting2(4, 0x0123ABCD, 2, false)[9], 0x00);
+    }
+}
@@ -0,0 +1,144 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Interpreting the JSON lines produced by `rtl_433 -F json`.
+
+use serde_json;
+use serde_json::value::Value as JSON;
+
+use std::ascii::AsciiExt;
+use std::collections::BTreeMap;
+
+// The fields of weather station readings that we forward.
+static WEATHER_FIELDS: [&'static str; 7] = ["temperature_C",
+                                            "temperature_F",
+                                            "humidity",
+                                            "wind_speed",
+                                            "wind_direction",
+                                            "rain",
+                                            "pressure_hPa"];
+
+/// A decoded event, for the devices we know how to interpret.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A reading from a weather station. `reading` is a JSON object holding the subset of
+    /// `WEATHER_FIELDS` reported by the station.
+    Weather {
+        device: String,
+        model: String,
+        reading: JSON,
+    },
+
+    /// A door/window sensor has been opened or closed.
+    Contact {
+        device: String,
+        model: String,
+        open: bool,
+    },
+}
+
+/// Build an identifier for a device, stable across reboots, from the model and the id
+/// (and channel, if any) broadcast by the device.
+fn device_key(model: &str, obj: &BTreeMap<String, JSON>) -> Option<String> {
+    let slug: String = model.chars()
+        .map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    let id = match obj.get("id") {
+        Some(&JSON::String(ref id)) => id.clone(),
+        Some(id) if id.is_number() => format!("{}", id),
+        _ => return None,
+    };
+    match obj.get("channel") {
+        Some(&JSON::String(ref channel)) => Some(format!("{}-{}-{}", slug, id, channel)),
+        Some(channel) if channel.is_number() => Some(format!("{}-{}-{}", slug, id, channel)),
+        _ => Some(format!("{}-{}", slug, id)),
+    }
+}
+
+/// Interpret a single line of output. Returns `None` for anything we do not support.
+pub fn parse_line(line: &str) -> Option<Event> {
+    let json: JSON = match serde_json::from_str(line) {
+        Ok(json) => json,
+        Err(_) => return None,
+    };
+    let obj = match json.as_object() {
+        Some(obj) => obj,
+        None => return None,
+    };
+    let model = match obj.get("model").and_then(|model| model.as_str()) {
+        Some(model) => model.to_owned(),
+        None => return None,
+    };
+    let device = match device_key(&model, obj) {
+        Some(device) => device,
+        None => return None,
+    };
+
+    // Door/window sensors report their state as a string.
+    if let Some(state) = obj.get("state").and_then(|state| state.as_str()) {
+        let open = match state {
+            "open" => true,
+            "close" | "closed" => false,
+            _ => return None,
+        };
+        return Some(Event::Contact {
+            device: device,
+            model: model,
+            open: open,
+        });
+    }
+
+    let mut reading = BTreeMap::new();
+    for field in &WEATHER_FIELDS {
+        if let Some(value) = obj.get(*field) {
+            if value.is_number() {
+                reading.insert((*field).to_owned(), value.clone());
+            }
+        }
+    }
+    if reading.is_empty() {
+        return None;
+    }
+    Some(Event::Weather {
+        device: device,
+        model: model,
+        reading: JSON::Object(reading),
+    })
+}
+
+#[cfg(test)]
+describe! rtl433 {
+    before_each {
+        use super::*;
+    }
+
+    it "should parse weather station readings" {
+        let line = r#"{"time" : "2016-09-01 10:00:00", "model" : "Acurite tower sensor", "id" : 1234, "channel" : "A", "battery" : "OK", "temperature_C" : 21.3, "humidity" : 45}"#;
+        match parse_line(line) {
+            Some(Event::Weather { device, model, reading }) => {
+                assert_eq!(device, "acurite-tower-sensor-1234-A");
+                assert_eq!(model, "Acurite tower sensor");
+                assert_eq!(reading.find("temperature_C").unwrap().as_f64(), Some(21.3));
+                assert_eq!(reading.find("humidity").unwrap().as_u64(), Some(45));
+                assert!(reading.find("battery").is_none());
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    it "should parse door sensors" {
+        let line = r#"{"time" : "2016-09-01 10:00:00", "model" : "Kerui Security", "id" : 555, "cmd" : 14, "state" : "open"}"#;
+        assert_eq!(parse_line(line),
+                   Some(Event::Contact {
+                       device: "kerui-security-555".to_owned(),
+                       model: "Kerui Security".to_owned(),
+                       open: true,
+                   }));
+    }
+
+    it "should ignore unknown events and garbage" {
+        assert_eq!(parse_line(r#"{"model" : "Some remote", "id" : 1, "button" : 3}"#), None);
+        assert_eq!(parse_line("Registered 95 out of 101 device decoding protocols"), None);
+    }
+}
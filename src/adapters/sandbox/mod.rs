@@ -0,0 +1,151 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Adapters running in processes of their own, so that a crash (e.g. in the OpenZWave C++
+//! bindings) doesn't take down the whole box. See `foxbox_taxonomy::adapter_proxy`.
+//!
+//! Sandboxed adapters are declared in the configuration:
+//! - `sandbox.adapters` is a comma separated list of adapter names;
+//! - `sandbox.<name>.command` is the command line of the adapter process, e.g.
+//!   `adapter_host openzwave <profile dir>` to run the built-in OpenZWave adapter, see
+//!   `src/bin/adapter_host.rs`.
+//!
+//! The adapter process is told where to connect by environment variable
+//! `FOXBOX_ADAPTER_SOCKET`, and is restarted with a backoff if it crashes. Its services are
+//! registered under adapter id `<name>.sandbox@link.mozilla.org`.
+
+use foxbox_core::managed_process::ManagedProcess;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_proxy::AdapterProxy;
+use foxbox_taxonomy::api::{Error, InternalError, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::Value;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+static ADAPTER_NAME: &'static str = "Sandboxed adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+/// The environment variable telling the adapter process where to connect.
+pub static SOCKET_VAR: &'static str = "FOXBOX_ADAPTER_SOCKET";
+
+pub struct SandboxedAdapter {
+    name: String,
+    proxy: AdapterProxy,
+    process: Mutex<Option<ManagedProcess>>,
+}
+
+impl SandboxedAdapter {
+    pub fn id(name: &str) -> Id<AdapterId> {
+        Id::new(&format!("{}.sandbox@link.mozilla.org", name))
+    }
+
+    fn start(&self, command: Vec<String>, socket: &Path) -> Result<(), Error> {
+        let socket = socket.to_owned();
+        let process = try!(ManagedProcess::start(move || {
+                Command::new(&command[0])
+                    .args(&command[1..])
+                    .env(SOCKET_VAR, &socket)
+                    .spawn()
+            })
+            .map_err(|err| Error::Internal(InternalError::GenericError(format!("{}", err)))));
+        *self.process.lock().unwrap() = Some(process);
+        info!("[sandbox] Started adapter {}", self.name);
+        Ok(())
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let names = match config.get("sandbox", "adapters") {
+            Some(names) => names,
+            None => return Ok(()),
+        };
+        let dir = PathBuf::from(controller.get_profile().path_for("sandbox"));
+        try!(fs::create_dir_all(&dir)
+            .map_err(|err| Error::Internal(InternalError::GenericError(format!("{}", err)))));
+
+        for name in names.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            let command: Vec<String> = config.get("sandbox", &format!("{}.command", name))
+                .map(|command| command.split_whitespace().map(|arg| arg.to_owned()).collect())
+                .unwrap_or_else(Vec::new);
+            if command.is_empty() {
+                warn!("[sandbox] No command configured for adapter {}", name);
+                continue;
+            }
+            let adapter = Arc::new(SandboxedAdapter {
+                name: name.to_owned(),
+                proxy: AdapterProxy::new(&Self::id(name), name, ADAPTER_VENDOR, ADAPTER_VERSION),
+                process: Mutex::new(None),
+            });
+            try!(manager.add_adapter(adapter.clone()));
+
+            let socket = dir.join(format!("{}.sock", name));
+            if let Err(err) = adapter.proxy.listen(manager.clone(), &socket) {
+                error!("[sandbox] Could not listen for adapter {}: {}", name, err);
+                continue;
+            }
+            if let Err(err) = adapter.start(command, &socket) {
+                error!("[sandbox] Could not start adapter {}: {}", name, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Adapter for SandboxedAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        self.proxy.id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    set: Vec<Id<Channel>>,
+                    user: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        self.proxy.fetch_values(set, user)
+    }
+
+    fn send_values(&self,
+                   values: HashMap<Id<Channel>, Value>,
+                   user: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        self.proxy.send_values(values, user)
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, user: User) -> WatchResult {
+        self.proxy.register_watch(watch, user)
+    }
+
+    fn heartbeat(&self) -> Result<(), Error> {
+        self.proxy.heartbeat()
+    }
+
+    fn stop(&self) {
+        self.proxy.stop();
+        if let Some(process) = self.process.lock().unwrap().take() {
+            info!("[sandbox] Stopping adapter {}", self.name);
+            if let Err(err) = process.shutdown() {
+                warn!("[sandbox] Could not stop adapter {}: {}", self.name, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,285 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter holding the security mode of the home, and arming it when everybody is away.
+//!
+//! The `security` service exposes:
+//! - `security/is-armed` (fetch, watch, send): `On` while the home is armed, e.g. for rules
+//!   to sound a siren when an `alarm/*` channel or a door is triggered. Sending arms or
+//!   disarms by hand, which the automatic policy won't undo until somebody leaves or comes
+//!   home again;
+//! - `security/auto-arm` (fetch, watch, send): `On` while the mode follows presence. Sending
+//!   `Off` overrides the policy, e.g. when guests stay home alone.
+//!
+//! Presence is read from the channels matching `security.presence` (by default, the rooms of
+//! the occupancy adapter). Once all of them report nobody, the home is armed after
+//! `security.arm_delay` seconds (600 by default); once one of them reports somebody, it is
+//! disarmed after `security.disarm_delay` seconds (0 by default). Automatic arming is off
+//! unless `security.auto_arm` is "true". The mode survives a reboot.
+
+mod policy;
+
+use self::policy::Policy;
+
+use foxbox_core::config_store::ConfigService;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{API, Error, InternalError, Targetted, User, WatchEvent};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::parse::{Parser, Path, ToJSON};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::util::Exactly;
+use foxbox_taxonomy::values::{format, OnOff, Value};
+
+use serde_json;
+
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static ADAPTER_NAME: &'static str = "Security mode adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+static DEFAULT_PRESENCE: &'static str = r#"[{"feature": "occupancy/is-occupied"}]"#;
+
+const TICK_S: u64 = 1;
+
+enum Command {
+    Arm(bool),
+    AutoArm(bool),
+}
+
+fn on_off(on: bool) -> Value {
+    Value::new(if on { OnOff::On } else { OnOff::Off })
+}
+
+pub struct SecurityAdapter {
+    commands: Mutex<mpsc::Sender<Command>>,
+    watchers: ValueWatchers,
+}
+
+impl SecurityAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("security@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:security@link.mozilla.org")
+    }
+
+    pub fn armed_id() -> Id<Channel> {
+        Id::new("channel:armed.security@link.mozilla.org")
+    }
+
+    pub fn auto_arm_id() -> Id<Channel> {
+        Id::new("channel:auto-arm.security@link.mozilla.org")
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let source = config.get_or_set_default("security", "presence", DEFAULT_PRESENCE);
+        let json = try!(serde_json::from_str(&source).map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        let presence = try!(Vec::<ChannelSelector>::parse(Path::new(), &json).map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{:?}", err)))
+        }));
+        let arm_delay = config.get_or_set_default("security", "arm_delay", "600")
+            .parse()
+            .unwrap_or(600);
+        let disarm_delay = config.get_or_set_default("security", "disarm_delay", "0")
+            .parse()
+            .unwrap_or(0);
+        let auto_arm = config.get_or_set_default("security", "auto_arm", "false") == "true";
+        let armed = config.get_or_set_default("security", "armed", "false") == "true";
+
+        let (tx, rx) = mpsc::channel();
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(SecurityAdapter {
+            commands: Mutex::new(tx),
+            watchers: watchers.clone(),
+        })));
+
+        let mut service = Service::empty(&Self::service_id(), &Self::id());
+        service.properties.insert("model".to_owned(), "Security mode".to_owned());
+        try!(manager.add_service(service));
+        for &(ref id, feature) in &[(Self::armed_id(), "security/is-armed"),
+                                    (Self::auto_arm_id(), "security/auto-arm")] {
+            try!(manager.add_channel(Channel {
+                id: id.clone(),
+                service: Self::service_id(),
+                adapter: Self::id(),
+                feature: Id::new(feature),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+                supports_watch: Some(Signature {
+                    accepts: Maybe::Optional(format::ON_OFF.clone()),
+                    returns: Maybe::Required(format::ON_OFF.clone()),
+                }),
+                supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+                ..Channel::default()
+            }));
+        }
+        watchers.update(&Self::armed_id(), on_off(armed));
+        watchers.update(&Self::auto_arm_id(), on_off(auto_arm));
+
+        let policy = Policy::new(Duration::from_secs(arm_delay),
+                                 Duration::from_secs(disarm_delay));
+        let manager = manager.clone();
+        thread::Builder::new()
+            .name("Security".to_owned())
+            .spawn(move || {
+                Self::run(&manager,
+                          &config,
+                          presence,
+                          policy,
+                          (armed, auto_arm),
+                          rx,
+                          &watchers)
+            })
+            .unwrap();
+        Ok(())
+    }
+
+    /// Follow presence and commands, forever.
+    fn run(manager: &AdapterManager,
+           config: &ConfigService,
+           presence: Vec<ChannelSelector>,
+           mut policy: Policy,
+           (mut armed, mut auto_arm): (bool, bool),
+           commands: mpsc::Receiver<Command>,
+           watchers: &ValueWatchers) {
+        let (tx, readings) = mpsc::channel::<WatchEvent>();
+        // Keep the guard alive for as long as we are following presence.
+        let _guard = manager.watch_values(vec![Targetted {
+                                               select: presence,
+                                               payload: Exactly::Always,
+                                           }],
+                                          Box::new(tx),
+                                          User::None);
+        loop {
+            let now = Instant::now();
+            let mut arm = None;
+            while let Ok(command) = commands.try_recv() {
+                match command {
+                    Command::Arm(on) => {
+                        policy.cancel();
+                        arm = Some(on);
+                    }
+                    Command::AutoArm(on) => {
+                        if on != auto_arm {
+                            auto_arm = on;
+                            info!("[security] Automatic arming turned {}",
+                                  if on { "on" } else { "off" });
+                            config.set("security", "auto_arm", if on { "true" } else { "false" });
+                            watchers.update(&Self::auto_arm_id(), on_off(on));
+                        }
+                    }
+                }
+            }
+            while let Ok(event) = readings.try_recv() {
+                match event {
+                    WatchEvent::EnterRange { channel, value, .. } => {
+                        if let Some(present) = policy::is_present(&value.to_json()) {
+                            policy.presence(&channel.to_string(), present, now);
+                        }
+                    }
+                    WatchEvent::ChannelRemoved(channel) => {
+                        policy.forget(&channel.to_string(), now);
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(on) = policy.tick(now) {
+                if auto_arm {
+                    info!("[security] {} home",
+                          if on { "Everybody left" } else { "Somebody is" });
+                    arm = Some(on);
+                }
+            }
+
+            if let Some(on) = arm {
+                if on != armed {
+                    armed = on;
+                    info!("[security] {}", if on { "Armed" } else { "Disarmed" });
+                    config.set("security", "armed", if on { "true" } else { "false" });
+                    watchers.update(&Self::armed_id(), on_off(on));
+                }
+            }
+            thread::sleep(Duration::from_secs(TICK_S));
+        }
+    }
+
+    fn command(&self, id: &Id<Channel>, value: &Value) -> Result<Command, Error> {
+        let on = *try!(value.cast::<OnOff>()) == OnOff::On;
+        if *id == Self::armed_id() {
+            Ok(Command::Arm(on))
+        } else if *id == Self::auto_arm_id() {
+            Ok(Command::AutoArm(on))
+        } else {
+            Err(Error::Internal(InternalError::NoSuchChannel(id.clone())))
+        }
+    }
+}
+
+impl Adapter for SecurityAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                if id != Self::armed_id() && id != Self::auto_arm_id() {
+                    return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))));
+                }
+                let value = self.watchers.latest(&id);
+                (id, Ok(value))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                let result = self.command(&id, &value).and_then(|command| {
+                    self.commands
+                        .lock()
+                        .unwrap()
+                        .send(command)
+                        .map_err(|_| {
+                            Error::Internal(InternalError::GenericError("Security mode stopped"
+                                .to_owned()))
+                        })
+                });
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
@@ -0,0 +1,158 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! When to arm or disarm the security mode, depending on whether anybody is home.
+//!
+//! The policy only acts on transitions: once everybody has left, the mode is armed after
+//! `arm_delay`; once somebody comes home, it is disarmed after `disarm_delay`. In between, the
+//! user may arm or disarm by hand, and the policy won't undo it until the next transition.
+
+use serde_json::value::Value as JSON;
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+pub struct Policy {
+    pub arm_delay: Duration,
+    pub disarm_delay: Duration,
+    /// The presence channels currently reporting somebody.
+    present: HashSet<String>,
+    /// Whether anybody is home, once a presence channel has reported.
+    anybody_home: Option<bool>,
+    /// The mode to switch to (`true` for armed), and when.
+    pending: Option<(bool, Instant)>,
+}
+
+impl Policy {
+    pub fn new(arm_delay: Duration, disarm_delay: Duration) -> Self {
+        Policy {
+            arm_delay: arm_delay,
+            disarm_delay: disarm_delay,
+            present: HashSet::new(),
+            anybody_home: None,
+            pending: None,
+        }
+    }
+
+    /// Take into account a reading of a presence channel.
+    pub fn presence(&mut self, channel: &str, is_present: bool, now: Instant) {
+        if is_present {
+            self.present.insert(channel.to_owned());
+        } else {
+            self.present.remove(channel);
+        }
+        self.refresh(now);
+    }
+
+    /// Forget a presence channel, e.g. once it has been removed.
+    pub fn forget(&mut self, channel: &str, now: Instant) {
+        self.present.remove(channel);
+        if self.anybody_home.is_some() {
+            self.refresh(now);
+        }
+    }
+
+    fn refresh(&mut self, now: Instant) {
+        let anybody_home = !self.present.is_empty();
+        if self.anybody_home.map_or(false, |previous| previous != anybody_home) {
+            self.pending = Some(if anybody_home {
+                (false, now + self.disarm_delay)
+            } else {
+                (true, now + self.arm_delay)
+            });
+        }
+        self.anybody_home = Some(anybody_home);
+    }
+
+    /// Drop the pending switch, e.g. because the user armed or disarmed by hand.
+    pub fn cancel(&mut self) {
+        self.pending = None;
+    }
+
+    /// The mode to switch to, if the grace period of the latest transition has expired.
+    pub fn tick(&mut self, now: Instant) -> Option<bool> {
+        match self.pending {
+            Some((armed, at)) if at <= now => {
+                self.pending = None;
+                Some(armed)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Interpret the value of a presence channel, e.g. `occupancy/is-occupied`.
+pub fn is_present(value: &JSON) -> Option<bool> {
+    match *value {
+        JSON::String(ref state) => {
+            match state as &str {
+                "On" | "Detected" | "Present" => Some(true),
+                "Off" | "NotDetected" | "Absent" => Some(false),
+                _ => None,
+            }
+        }
+        JSON::Bool(present) => Some(present),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+describe! security_policy {
+    before_each {
+        use super::*;
+        use std::time::{Duration, Instant};
+    }
+
+    it "should arm once everybody has left, after the grace period" {
+        let now = Instant::now();
+        let minutes = |count: u64| now + Duration::from_secs(count * 60);
+        let mut policy = Policy::new(Duration::from_secs(600), Duration::from_secs(0));
+        policy.presence("hall", true, now);
+        policy.presence("kitchen", true, now);
+        assert_eq!(policy.tick(minutes(1)), None);
+
+        policy.presence("hall", false, minutes(1));
+        assert_eq!(policy.tick(minutes(20)), None);
+        policy.presence("kitchen", false, minutes(2));
+        assert_eq!(policy.tick(minutes(11)), None);
+        assert_eq!(policy.tick(minutes(12)), Some(true));
+        assert_eq!(policy.tick(minutes(13)), None);
+
+        policy.presence("hall", true, minutes(30));
+        assert_eq!(policy.tick(minutes(30)), Some(false));
+    }
+
+    it "should not arm if somebody comes back during the grace period" {
+        let now = Instant::now();
+        let minutes = |count: u64| now + Duration::from_secs(count * 60);
+        let mut policy = Policy::new(Duration::from_secs(600), Duration::from_secs(0));
+        policy.presence("hall", true, now);
+        policy.presence("hall", false, minutes(1));
+        policy.presence("hall", true, minutes(5));
+        assert_eq!(policy.tick(minutes(20)), Some(false));
+        assert_eq!(policy.tick(minutes(30)), None);
+    }
+
+    it "should let the user override until the next transition" {
+        let now = Instant::now();
+        let minutes = |count: u64| now + Duration::from_secs(count * 60);
+        let mut policy = Policy::new(Duration::from_secs(600), Duration::from_secs(0));
+        policy.presence("hall", true, now);
+        policy.presence("hall", false, minutes(1));
+        policy.cancel();
+        assert_eq!(policy.tick(minutes(20)), None);
+
+        policy.forget("hall", minutes(30));
+        assert_eq!(policy.tick(minutes(60)), None);
+    }
+
+    it "should interpret presence values" {
+        use serde_json::value::Value as JSON;
+
+        assert_eq!(is_present(&JSON::String("On".to_owned())), Some(true));
+        assert_eq!(is_present(&JSON::String("NotDetected".to_owned())), Some(false));
+        assert_eq!(is_present(&JSON::Bool(true)), Some(true));
+        assert_eq!(is_present(&JSON::U64(1)), None);
+    }
+}
@@ -0,0 +1,165 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The map describing which SNMP agents to poll, and how their objects map to channels.
+//!
+//! ```json
+//! { "agents": [{
+//!     "id": "ups",
+//!     "model": "APC Smart-UPS 750",
+//!     "address": "192.168.1.30:161",
+//!     "community": "public",
+//!     "version": "1",
+//!     "poll_interval": 30,
+//!     "objects": [
+//!       { "oid": "1.3.6.1.2.1.33.1.2.4.0", "type": "percent", "feature": "ups/battery-level" },
+//!       { "oid": "1.3.6.1.2.1.33.1.2.3.0", "type": "number", "scale": 60,
+//!         "feature": "ups/runtime-remaining" },
+//!       { "oid": "1.3.6.1.2.1.1.1.0", "type": "string", "feature": "ups/description" }
+//!     ]
+//! }]}
+//! ```
+//!
+//! The port defaults to 161, the community to "public" and the version to "2c".
+
+use super::protocol::Version;
+
+use serde_json;
+
+use std::fs::File;
+use std::io::Read;
+
+#[derive(Deserialize, Debug)]
+pub struct Descriptor {
+    pub agents: Vec<AgentDescriptor>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AgentDescriptor {
+    pub id: String,
+    pub model: Option<String>,
+    pub address: String,
+    pub community: Option<String>,
+    /// One of "1" or "2c" (the default).
+    pub version: Option<String>,
+    /// Seconds between two polls. Defaults to 60.
+    pub poll_interval: Option<u64>,
+    pub objects: Vec<ObjectDescriptor>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ObjectDescriptor {
+    /// The numeric OID of the object, e.g. "1.3.6.1.2.1.33.1.2.4.0".
+    pub oid: String,
+    /// One of "number" (the default), "percent" or "string".
+    #[serde(rename="type")]
+    pub object_type: Option<String>,
+    /// Numeric values are multiplied by `scale` before being exposed.
+    pub scale: Option<f64>,
+    pub feature: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObjectType {
+    Number,
+    Percent,
+    String,
+}
+
+impl AgentDescriptor {
+    /// The address of the agent, with the default port if none is given.
+    pub fn address(&self) -> String {
+        if self.address.contains(':') {
+            self.address.clone()
+        } else {
+            format!("{}:161", self.address)
+        }
+    }
+
+    pub fn community(&self) -> &str {
+        self.community.as_ref().map(|community| community as &str).unwrap_or("public")
+    }
+
+    pub fn version(&self) -> Result<Version, String> {
+        match self.version.as_ref().map(|version| version as &str) {
+            Some("1") => Ok(Version::V1),
+            None | Some("2c") => Ok(Version::V2c),
+            Some(other) => Err(format!("Unsupported SNMP version {}", other)),
+        }
+    }
+}
+
+impl ObjectDescriptor {
+    pub fn object_type(&self) -> Result<ObjectType, String> {
+        match self.object_type.as_ref().map(|object_type| object_type as &str) {
+            None | Some("number") => Ok(ObjectType::Number),
+            Some("percent") => Ok(ObjectType::Percent),
+            Some("string") => Ok(ObjectType::String),
+            Some(other) => Err(format!("Unknown object type {}", other)),
+        }
+    }
+}
+
+impl Descriptor {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let mut source = String::new();
+        try!(File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut source))
+            .map_err(|err| format!("Could not read {}: {}", path, err)));
+        Self::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let descriptor: Descriptor = try!(serde_json::from_str(source)
+            .map_err(|err| format!("Invalid SNMP descriptor: {}", err)));
+        // Check everything now rather than when polling.
+        for agent in &descriptor.agents {
+            try!(agent.version());
+            for object in &agent.objects {
+                try!(object.object_type());
+                let valid = !object.oid.is_empty() &&
+                            object.oid
+                    .trim_left_matches('.')
+                    .split('.')
+                    .all(|arc| arc.parse::<u32>().is_ok());
+                if !valid {
+                    return Err(format!("Invalid OID {} for agent {}", object.oid, agent.id));
+                }
+            }
+        }
+        Ok(descriptor)
+    }
+}
+
+#[cfg(test)]
+describe! snmp_descriptor {
+    before_each {
+        use super::*;
+        use super::super::protocol::Version;
+    }
+
+    it "should parse a descriptor" {
+        let descriptor = Descriptor::parse(r#"{"agents": [{
+            "id": "ups", "address": "192.168.1.30", "version": "1",
+            "objects": [
+                { "oid": "1.3.6.1.2.1.33.1.2.4.0", "type": "percent", "feature": "ups/battery" },
+                { "oid": "1.3.6.1.2.1.1.1.0", "type": "string", "feature": "ups/description" }
+            ]}]}"#).unwrap();
+        let agent = &descriptor.agents[0];
+        assert_eq!(agent.address(), "192.168.1.30:161");
+        assert_eq!(agent.community(), "public");
+        assert_eq!(agent.version(), Ok(Version::V1));
+        assert_eq!(agent.objects[0].object_type(), Ok(ObjectType::Percent));
+        assert_eq!(agent.objects[1].object_type(), Ok(ObjectType::String));
+    }
+
+    it "should reject inconsistent descriptors" {
+        assert!(Descriptor::parse(r#"{"agents": [{"id": "a", "address": "h", "version": "3",
+            "objects": []}]}"#).is_err());
+        assert!(Descriptor::parse(r#"{"agents": [{"id": "a", "address": "h",
+            "objects": [{ "oid": "1.3.six", "feature": "x/y" }]}]}"#).is_err());
+        assert!(Descriptor::parse(r#"{"agents": [{"id": "a", "address": "h",
+            "objects": [{ "oid": "1.3.6", "type": "bool", "feature": "x/y" }]}]}"#).is_err());
+    }
+}
@@ -0,0 +1,312 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter for SNMP agents, such as UPSes, managed switches and NASes.
+//!
+//! The adapter is driven by a map of OIDs (see `descriptor`), whose path is configured in
+//! `snmp.descriptor`. Each agent becomes a service and each object a channel. Objects are
+//! exposed as JSON numbers (after scaling), as `Percent` (which can be watched with a
+//! `Range<Percent>`, e.g. to shut down when the battery of the UPS drops below 20%) or as
+//! strings. Agents are polled periodically so that channels can be watched.
+
+mod descriptor;
+mod protocol;
+
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, Percent, Value};
+
+use self::descriptor::{AgentDescriptor, Descriptor, ObjectDescriptor, ObjectType};
+use self::protocol::{SnmpValue, Version};
+
+use serde_json::value::Value as JSON;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+static ADAPTER_NAME: &'static str = "SNMP adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const DEFAULT_POLL_INTERVAL_S: u64 = 60;
+const TIMEOUT_MS: u64 = 2000;
+
+/// An agent, as needed to query it.
+#[derive(Clone)]
+struct Agent {
+    address: String,
+    version: Version,
+    community: String,
+}
+
+impl Agent {
+    fn new(descriptor: &AgentDescriptor) -> Result<Self, String> {
+        Ok(Agent {
+            address: descriptor.address(),
+            version: try!(descriptor.version()),
+            community: descriptor.community().to_owned(),
+        })
+    }
+
+    fn get(&self, oids: &[String]) -> Result<Vec<(String, SnmpValue)>, String> {
+        protocol::get(&self.address,
+                      self.version,
+                      &self.community,
+                      oids,
+                      Duration::from_millis(TIMEOUT_MS))
+    }
+}
+
+struct Object {
+    agent: Agent,
+    descriptor: ObjectDescriptor,
+}
+
+/// Convert the value of an object, as returned by the agent.
+fn convert(object: &ObjectDescriptor, value: &SnmpValue) -> Result<Value, String> {
+    let scale = object.scale.unwrap_or(1.0);
+    let number = || {
+        value.as_f64()
+            .map(|number| number * scale)
+            .ok_or_else(|| format!("{} is not a number: {:?}", object.oid, value))
+    };
+    match try!(object.object_type()) {
+        ObjectType::Number => Ok(Value::new(Json(JSON::F64(try!(number()))))),
+        ObjectType::Percent => {
+            let number = try!(number()).round();
+            let percent = if number < 0.0 {
+                0
+            } else if number > 100.0 {
+                100
+            } else {
+                number as u8
+            };
+            Ok(Value::new(Percent::new(percent)))
+        }
+        ObjectType::String => {
+            value.as_string()
+                .map(Value::new)
+                .ok_or_else(|| format!("{} has no value: {:?}", object.oid, value))
+        }
+    }
+}
+
+pub struct SnmpAdapter {
+    objects: HashMap<Id<Channel>, Object>,
+    watchers: ValueWatchers,
+}
+
+impl SnmpAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("snmp@link.mozilla.org")
+    }
+
+    pub fn service_id(agent: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.snmp@link.mozilla.org", agent))
+    }
+
+    pub fn channel_id(agent: &str, object: &ObjectDescriptor) -> Id<Channel> {
+        Id::new(&format!("channel:{}.{}.snmp@link.mozilla.org", object.oid, agent))
+    }
+
+    pub fn init(manager: &Arc<AdapterManager>, descriptor_path: &str) -> Result<(), Error> {
+        let descriptor = try!(Descriptor::from_file(descriptor_path)
+            .map_err(|err| Error::Internal(InternalError::GenericError(err))));
+
+        let mut objects = HashMap::new();
+        let mut pollers = Vec::new();
+        for agent_descriptor in &descriptor.agents {
+            let agent = try!(Agent::new(agent_descriptor)
+                .map_err(|err| Error::Internal(InternalError::GenericError(err))));
+            let mut polled = Vec::new();
+            for object in &agent_descriptor.objects {
+                let id = Self::channel_id(&agent_descriptor.id, object);
+                objects.insert(id.clone(),
+                               Object {
+                                   agent: agent.clone(),
+                                   descriptor: object.clone(),
+                               });
+                polled.push((id, object.clone()));
+            }
+            let interval = agent_descriptor.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL_S);
+            pollers.push((agent, polled, interval));
+        }
+
+        let watchers = ValueWatchers::new();
+        let adapter = Arc::new(SnmpAdapter {
+            objects: objects,
+            watchers: watchers.clone(),
+        });
+        try!(manager.add_adapter(adapter.clone()));
+
+        for agent in &descriptor.agents {
+            let service_id = Self::service_id(&agent.id);
+            let mut service = Service::empty(&service_id, &Self::id());
+            service.properties.insert("model".to_owned(),
+                                      agent.model
+                                          .clone()
+                                          .unwrap_or_else(|| "SNMP agent".to_owned()));
+            try!(manager.add_service(service));
+            for object in &agent.objects {
+                let (fetch, watch) = match object.object_type() {
+                    Ok(ObjectType::Percent) => {
+                        (Signature::returns(Maybe::Required(format::PERCENT.clone())),
+                         Signature {
+                            accepts: Maybe::Optional(format::PERCENT_RANGE.clone()),
+                            returns: Maybe::Required(format::PERCENT.clone()),
+                        })
+                    }
+                    Ok(ObjectType::String) => {
+                        (Signature::returns(Maybe::Required(format::STRING.clone())),
+                         Signature::returns(Maybe::Required(format::STRING.clone())))
+                    }
+                    _ => {
+                        (Signature::returns(Maybe::Required(format::JSON.clone())),
+                         Signature::returns(Maybe::Required(format::JSON.clone())))
+                    }
+                };
+                try!(manager.add_channel(Channel {
+                    id: Self::channel_id(&agent.id, object),
+                    service: service_id.clone(),
+                    adapter: Self::id(),
+                    feature: Id::new(&object.feature),
+                    supports_fetch: Some(fetch),
+                    supports_watch: Some(watch),
+                    ..Channel::default()
+                }));
+            }
+        }
+
+        for (agent, polled, interval) in pollers {
+            let watchers = watchers.clone();
+            let oids: Vec<String> =
+                polled.iter().map(|&(_, ref object)| object.oid.clone()).collect();
+            thread::spawn(move || {
+                loop {
+                    match agent.get(&oids) {
+                        Ok(bindings) => {
+                            for (&(ref id, ref object), &(_, ref value)) in
+                                polled.iter().zip(bindings.iter()) {
+                                match convert(object, value) {
+                                    Ok(value) => watchers.update(id, value),
+                                    Err(err) => warn!("[snmp] Could not poll {}: {}", id, err),
+                                }
+                            }
+                        }
+                        Err(err) => warn!("[snmp] Could not poll {}: {}", agent.address, err),
+                    }
+                    thread::sleep(Duration::from_secs(interval));
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Adapter for SnmpAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                let object = match self.objects.get(&id) {
+                    Some(object) => object,
+                    None => {
+                        return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+                    }
+                };
+                let result = object.agent
+                    .get(&[object.descriptor.oid.clone()])
+                    .and_then(|bindings| {
+                        bindings.first()
+                            .ok_or_else(|| format!("No value for {}", object.descriptor.oid))
+                            .and_then(|&(_, ref value)| convert(&object.descriptor, value))
+                    });
+                match result {
+                    Ok(value) => {
+                        self.watchers.update(&id, value.clone());
+                        (id, Ok(Some(value)))
+                    }
+                    Err(err) => {
+                        // The agent may be unreachable for a while, e.g. while the UPS
+                        // reboots, so fall back to the latest known value.
+                        warn!("[snmp] Could not fetch {}: {}", id, err);
+                        let latest = self.watchers.latest(&id);
+                        (id, Ok(latest))
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, _)| (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id))))
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
+
+#[cfg(test)]
+describe! snmp {
+    before_each {
+        use super::convert;
+        use super::descriptor::Descriptor;
+        use super::protocol::SnmpValue;
+        use foxbox_taxonomy::values::{Json, Percent};
+    }
+
+    it "should convert object values" {
+        let descriptor = Descriptor::parse(r#"{"agents": [{
+            "id": "ups", "address": "192.168.1.30",
+            "objects": [
+                { "oid": "1.3.6.1.2.1.33.1.2.4.0", "type": "percent", "feature": "ups/battery" },
+                { "oid": "1.3.6.1.2.1.33.1.2.3.0", "scale": 60, "feature": "ups/runtime" },
+                { "oid": "1.3.6.1.2.1.1.1.0", "type": "string", "feature": "ups/description" }
+            ]}]}"#).unwrap();
+        let objects = &descriptor.agents[0].objects;
+
+        let value = convert(&objects[0], &SnmpValue::Integer(18)).unwrap();
+        assert_eq!(value.cast::<Percent>().unwrap(), &Percent::new(18));
+        let value = convert(&objects[0], &SnmpValue::Gauge(250)).unwrap();
+        assert_eq!(value.cast::<Percent>().unwrap(), &Percent::new(100));
+
+        let value = convert(&objects[1], &SnmpValue::TimeTicks(12)).unwrap();
+        assert_eq!(value.cast::<Json>().unwrap().0.as_f64(), Some(720.0));
+
+        let value = convert(&objects[2], &SnmpValue::String(b"Smart-UPS 750".to_vec())).unwrap();
+        assert_eq!(value.cast::<String>().unwrap(), "Smart-UPS 750");
+
+        assert!(convert(&objects[1], &SnmpValue::NoSuchObject).is_err());
+    }
+}
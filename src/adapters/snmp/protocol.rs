@@ -0,0 +1,396 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A minimal SNMP v1/v2c client, supporting `GetRequest` only.
+
+use std::mem;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::Duration;
+
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const IP_ADDRESS: u8 = 0x40;
+const COUNTER32: u8 = 0x41;
+const GAUGE32: u8 = 0x42;
+const TIME_TICKS: u8 = 0x43;
+const COUNTER64: u8 = 0x46;
+const NO_SUCH_OBJECT: u8 = 0x80;
+const NO_SUCH_INSTANCE: u8 = 0x81;
+const END_OF_MIB_VIEW: u8 = 0x82;
+const GET_REQUEST: u8 = 0xA0;
+const GET_RESPONSE: u8 = 0xA2;
+
+const RETRIES: usize = 2;
+
+static NEXT_REQUEST_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    V1,
+    V2c,
+}
+
+impl Version {
+    fn code(&self) -> i64 {
+        match *self {
+            Version::V1 => 0,
+            Version::V2c => 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnmpValue {
+    Integer(i64),
+    String(Vec<u8>),
+    Oid(String),
+    IpAddress(String),
+    Counter(u64),
+    Gauge(u64),
+    TimeTicks(u64),
+    Null,
+    /// The agent does not know this object.
+    NoSuchObject,
+}
+
+impl SnmpValue {
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            SnmpValue::Integer(value) => Some(value as f64),
+            SnmpValue::Counter(value) |
+            SnmpValue::Gauge(value) |
+            SnmpValue::TimeTicks(value) => Some(value as f64),
+            SnmpValue::String(ref bytes) => String::from_utf8_lossy(bytes).trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_string(&self) -> Option<String> {
+        match *self {
+            SnmpValue::String(ref bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+            SnmpValue::Oid(ref oid) |
+            SnmpValue::IpAddress(ref oid) => Some(oid.clone()),
+            SnmpValue::NoSuchObject | SnmpValue::Null => None,
+            _ => self.as_f64().map(|value| format!("{}", value)),
+        }
+    }
+}
+
+fn encode_length(length: usize, buf: &mut Vec<u8>) {
+    if length < 0x80 {
+        buf.push(length as u8);
+    } else if length <= 0xFF {
+        buf.push(0x81);
+        buf.push(length as u8);
+    } else {
+        buf.push(0x82);
+        buf.push((length >> 8) as u8);
+        buf.push(length as u8);
+    }
+}
+
+fn encode_tlv(tag: u8, content: &[u8], buf: &mut Vec<u8>) {
+    buf.push(tag);
+    encode_length(content.len(), buf);
+    buf.extend_from_slice(content);
+}
+
+fn encode_integer(value: i64, buf: &mut Vec<u8>) {
+    let mut bytes: Vec<u8> = (0..8).rev().map(|i| (value >> (i * 8)) as u8).collect();
+    // Strip redundant leading bytes, keeping the sign bit.
+    while bytes.len() > 1 &&
+          ((bytes[0] == 0 && bytes[1] & 0x80 == 0) || (bytes[0] == 0xFF && bytes[1] & 0x80 != 0)) {
+        bytes.remove(0);
+    }
+    encode_tlv(INTEGER, &bytes, buf);
+}
+
+pub fn encode_oid(oid: &str, buf: &mut Vec<u8>) -> Result<(), String> {
+    let arcs: Result<Vec<u64>, _> = oid.trim_left_matches('.').split('.').map(str::parse).collect();
+    let arcs = try!(arcs.map_err(|_| format!("Invalid OID {}", oid)));
+    if arcs.len() < 2 || arcs[0] > 2 {
+        return Err(format!("Invalid OID {}", oid));
+    }
+    let mut content = vec![];
+    encode_arc(arcs[0] * 40 + arcs[1], &mut content);
+    for arc in &arcs[2..] {
+        encode_arc(*arc, &mut content);
+    }
+    encode_tlv(OBJECT_IDENTIFIER, &content, buf);
+    Ok(())
+}
+
+/// Encode an OID arc in base 128, most significant group first.
+fn encode_arc(arc: u64, buf: &mut Vec<u8>) {
+    let mut bytes = vec![arc as u8 & 0x7F];
+    let mut rest = arc >> 7;
+    while rest > 0 {
+        bytes.push(rest as u8 & 0x7F | 0x80);
+        rest >>= 7;
+    }
+    buf.extend(bytes.iter().rev());
+}
+
+/// Build a `GetRequest` message.
+pub fn get_request(version: Version,
+                   community: &str,
+                   request_id: i32,
+                   oids: &[String])
+                   -> Result<Vec<u8>, String> {
+    let mut bindings = vec![];
+    for oid in oids {
+        let mut binding = vec![];
+        try!(encode_oid(oid, &mut binding));
+        encode_tlv(NULL, &[], &mut binding);
+        encode_tlv(SEQUENCE, &binding, &mut bindings);
+    }
+    let mut pdu = vec![];
+    encode_integer(request_id as i64, &mut pdu);
+    encode_integer(0, &mut pdu); // error-status
+    encode_integer(0, &mut pdu); // error-index
+    encode_tlv(SEQUENCE, &bindings, &mut pdu);
+
+    let mut message = vec![];
+    encode_integer(version.code(), &mut message);
+    encode_tlv(OCTET_STRING, community.as_bytes(), &mut message);
+    encode_tlv(GET_REQUEST, &pdu, &mut message);
+
+    let mut buf = vec![];
+    encode_tlv(SEQUENCE, &message, &mut buf);
+    Ok(buf)
+}
+
+/// A reader of BER encoded TLVs.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf: buf, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        if self.pos >= self.buf.len() {
+            return Err("Truncated message".to_owned());
+        }
+        self.pos += 1;
+        Ok(self.buf[self.pos - 1])
+    }
+
+    /// Read a TLV, returning its tag and content.
+    fn read(&mut self) -> Result<(u8, &'a [u8]), String> {
+        let tag = try!(self.byte());
+        let first = try!(self.byte());
+        let length = if first & 0x80 == 0 {
+            first as usize
+        } else {
+            // The long form gives the number of bytes of the length, which must fit a usize.
+            let bytes = (first & 0x7F) as usize;
+            if bytes > mem::size_of::<usize>() {
+                return Err(format!("Invalid length of {} bytes", bytes));
+            }
+            let mut length = 0;
+            for _ in 0..bytes {
+                length = length << 8 | try!(self.byte()) as usize;
+            }
+            length
+        };
+        let end = try!(self.pos
+            .checked_add(length)
+            .ok_or_else(|| "Invalid length".to_owned()));
+        if end > self.buf.len() {
+            return Err("Truncated message".to_owned());
+        }
+        let start = self.pos;
+        self.pos = end;
+        Ok((tag, &self.buf[start..end]))
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<&'a [u8], String> {
+        let (tag, content) = try!(self.read());
+        if tag != expected {
+            return Err(format!("Expected tag {:x}, got {:x}", expected, tag));
+        }
+        Ok(content)
+    }
+}
+
+fn decode_integer(content: &[u8]) -> i64 {
+    let initial = if content.first().map_or(false, |byte| byte & 0x80 != 0) {
+        -1
+    } else {
+        0
+    };
+    content.iter().fold(initial, |acc, byte| acc << 8 | *byte as i64)
+}
+
+fn decode_unsigned(content: &[u8]) -> u64 {
+    content.iter().fold(0, |acc, byte| acc << 8 | *byte as u64)
+}
+
+fn decode_oid(content: &[u8]) -> String {
+    let mut arcs = vec![];
+    let mut arc: u64 = 0;
+    for byte in content {
+        arc = arc << 7 | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = if arc < 80 { arc / 40 } else { 2 };
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+    arcs.iter().map(|arc| format!("{}", arc)).collect::<Vec<_>>().join(".")
+}
+
+fn decode_value(tag: u8, content: &[u8]) -> SnmpValue {
+    match tag {
+        INTEGER => SnmpValue::Integer(decode_integer(content)),
+        OCTET_STRING => SnmpValue::String(content.to_vec()),
+        OBJECT_IDENTIFIER => SnmpValue::Oid(decode_oid(content)),
+        IP_ADDRESS => {
+            SnmpValue::IpAddress(content.iter()
+                .map(|byte| format!("{}", byte))
+                .collect::<Vec<_>>()
+                .join("."))
+        }
+        COUNTER32 | COUNTER64 => SnmpValue::Counter(decode_unsigned(content)),
+        GAUGE32 => SnmpValue::Gauge(decode_unsigned(content)),
+        TIME_TICKS => SnmpValue::TimeTicks(decode_unsigned(content)),
+        NO_SUCH_OBJECT | NO_SUCH_INSTANCE | END_OF_MIB_VIEW => SnmpValue::NoSuchObject,
+        _ => SnmpValue::Null,
+    }
+}
+
+pub struct Response {
+    pub request_id: i32,
+    pub error_status: i64,
+    pub bindings: Vec<(String, SnmpValue)>,
+}
+
+pub fn parse_response(buf: &[u8]) -> Result<Response, String> {
+    let mut message = Reader::new(try!(Reader::new(buf).expect(SEQUENCE)));
+    try!(message.expect(INTEGER)); // version
+    try!(message.expect(OCTET_STRING)); // community
+    let mut pdu = Reader::new(try!(message.expect(GET_RESPONSE)));
+    let request_id = decode_integer(try!(pdu.expect(INTEGER))) as i32;
+    let error_status = decode_integer(try!(pdu.expect(INTEGER)));
+    try!(pdu.expect(INTEGER)); // error-index
+    let mut list = Reader::new(try!(pdu.expect(SEQUENCE)));
+    let mut bindings = vec![];
+    while !list.is_empty() {
+        let mut binding = Reader::new(try!(list.expect(SEQUENCE)));
+        let oid = decode_oid(try!(binding.expect(OBJECT_IDENTIFIER)));
+        let (tag, content) = try!(binding.read());
+        bindings.push((oid, decode_value(tag, content)));
+    }
+    Ok(Response {
+        request_id: request_id,
+        error_status: error_status,
+        bindings: bindings,
+    })
+}
+
+/// Get the values of a set of objects from an agent.
+pub fn get(address: &str,
+           version: Version,
+           community: &str,
+           oids: &[String],
+           timeout: Duration)
+           -> Result<Vec<(String, SnmpValue)>, String> {
+    let address = try!(try!(address.to_socket_addrs().map_err(|err| format!("{}", err)))
+        .next()
+        .ok_or_else(|| format!("Cannot resolve {}", address)));
+    let socket = try!(UdpSocket::bind("0.0.0.0:0").map_err(|err| format!("{}", err)));
+    try!(socket.set_read_timeout(Some(timeout)).map_err(|err| format!("{}", err)));
+
+    let request_id = (NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed) & 0x7FFFFFFF) as i32;
+    let request = try!(get_request(version, community, request_id, oids));
+    let mut buf = [0; 65535];
+    for _ in 0..RETRIES + 1 {
+        try!(socket.send_to(&request, address).map_err(|err| format!("{}", err)));
+        loop {
+            let len = match socket.recv_from(&mut buf) {
+                Ok((len, _)) => len,
+                Err(_) => break, // Timeout, retry.
+            };
+            let response = match parse_response(&buf[..len]) {
+                Ok(ref response) if response.request_id != request_id => continue,
+                Ok(response) => response,
+                Err(err) => return Err(err),
+            };
+            if response.error_status != 0 {
+                return Err(format!("Agent {} returned error {}", address, response.error_status));
+            }
+            return Ok(response.bindings);
+        }
+    }
+    Err(format!("No response from {}", address))
+}
+
+#[cfg(test)]
+describe! snmp_protocol {
+    before_each {
+        use super::*;
+    }
+
+    it "should encode a GetRequest" {
+        let request = get_request(Version::V1, "public", 1, &["1.3.6.1.2.1.1.1.0".to_owned()])
+            .unwrap();
+        assert_eq!(request,
+                   vec![0x30, 0x26, 0x02, 0x01, 0x00, 0x04, 0x06, b'p', b'u', b'b', b'l', b'i',
+                        b'c', 0xA0, 0x19, 0x02, 0x01, 0x01, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00,
+                        0x30, 0x0E, 0x30, 0x0C, 0x06, 0x08, 0x2B, 0x06, 0x01, 0x02, 0x01, 0x01,
+                        0x01, 0x00, 0x05, 0x00]);
+    }
+
+    it "should encode large arcs and reject invalid OIDs" {
+        let mut buf = vec![];
+        encode_oid("1.3.6.1.4.1.318", &mut buf).unwrap();
+        assert_eq!(buf, vec![0x06, 0x07, 0x2B, 0x06, 0x01, 0x04, 0x01, 0x82, 0x3E]);
+        assert!(encode_oid("1.3.six", &mut vec![]).is_err());
+        assert!(encode_oid("7", &mut vec![]).is_err());
+    }
+
+    it "should parse a GetResponse" {
+        // Battery charge (Gauge32 87) and a missing object, in a v2c response.
+        let response = vec![0x30, 0x35, 0x02, 0x01, 0x01, 0x04, 0x06, b'p', b'u', b'b', b'l',
+                            b'i', b'c', 0xA2, 0x28, 0x02, 0x01, 0x2A, 0x02, 0x01, 0x00, 0x02,
+                            0x01, 0x00, 0x30, 0x1D, 0x30, 0x0D, 0x06, 0x08, 0x2B, 0x06, 0x01,
+                            0x02, 0x01, 0x21, 0x01, 0x02, 0x42, 0x01, 0x57, 0x30, 0x0C, 0x06,
+                            0x08, 0x2B, 0x06, 0x01, 0x02, 0x01, 0x21, 0x01, 0x03, 0x80, 0x00];
+        let response = parse_response(&response).unwrap();
+        assert_eq!(response.request_id, 42);
+        assert_eq!(response.error_status, 0);
+        assert_eq!(response.bindings,
+                   vec![("1.3.6.1.2.1.33.1.2".to_owned(), SnmpValue::Gauge(87)),
+                        ("1.3.6.1.2.1.33.1.3".to_owned(), SnmpValue::NoSuchObject)]);
+        assert_eq!(response.bindings[0].1.as_f64(), Some(87.));
+    }
+
+    it "should reject lengths that overflow" {
+        // A length of 9 bytes, more than a usize holds.
+        let mut response = vec![0x30, 0x89];
+        response.extend_from_slice(&[0xFF; 9]);
+        assert!(parse_response(&response).is_err());
+        // A length of usize::MAX, which overflows once added to the position.
+        let mut response = vec![0x30, 0x80 | ::std::mem::size_of::<usize>() as u8];
+        response.extend_from_slice(&[0xFF; 8][..::std::mem::size_of::<usize>()]);
+        assert!(parse_response(&response).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The subset of the Spotify Web API used by the adapter.
+//!
+//! See https://developer.spotify.com/web-api/web-api-connect-endpoint-reference/.
+
+use foxbox_core::oauth2::{OAuth2Broker, OAuth2Error, Provider};
+
+use hyper;
+use hyper::header::{Authorization, Bearer, Connection, ContentType};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use serde_json;
+use serde_json::value::Value as JSON;
+use url::form_urlencoded;
+
+use std::io::Read;
+
+static API_URL: &'static str = "https://api.spotify.com/v1/me/player";
+
+/// The name of the provider in the OAuth2 broker.
+pub static PROVIDER: &'static str = "spotify";
+
+pub fn provider(client_id: &str, client_secret: &str) -> Provider {
+    Provider {
+        name: PROVIDER.to_owned(),
+        authorize_url: "https://accounts.spotify.com/authorize".to_owned(),
+        token_url: "https://accounts.spotify.com/api/token".to_owned(),
+        client_id: client_id.to_owned(),
+        client_secret: client_secret.to_owned(),
+        scopes: vec!["user-read-playback-state".to_owned(),
+                     "user-modify-playback-state".to_owned()],
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// The user hasn't linked the box to their Spotify account yet.
+    NotLinked,
+    Other(String),
+}
+
+impl From<OAuth2Error> for ApiError {
+    fn from(err: OAuth2Error) -> Self {
+        match err {
+            OAuth2Error::NotAuthorized(_) => ApiError::NotLinked,
+            err => ApiError::Other(format!("{}", err)),
+        }
+    }
+}
+
+/// A Spotify Connect device, e.g. a speaker, a phone or a computer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    /// e.g. `Speaker`, `Smartphone` or `Computer`.
+    pub kind: String,
+    pub is_active: bool,
+    /// Unknown for devices whose volume can't be controlled.
+    pub volume: Option<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Playback {
+    /// The device playing, if any.
+    pub device: Option<String>,
+    pub is_playing: bool,
+    pub track: Option<String>,
+    pub artists: Vec<String>,
+}
+
+fn string_field(json: &JSON, field: &str) -> Option<String> {
+    json.find(field).and_then(JSON::as_string).map(str::to_owned)
+}
+
+/// Parse the response of `GET /me/player/devices`. Restricted devices, which can't be
+/// controlled through the API, are left out.
+pub fn parse_devices(json: &JSON) -> Vec<Device> {
+    let devices = match json.find("devices").and_then(JSON::as_array) {
+        Some(devices) => devices,
+        None => return vec![],
+    };
+    devices.iter()
+        .filter(|device| device.find("is_restricted").and_then(JSON::as_bool) != Some(true))
+        .filter_map(|device| {
+            string_field(device, "id").map(|id| {
+                Device {
+                    name: string_field(device, "name").unwrap_or(id.clone()),
+                    id: id,
+                    kind: string_field(device, "type").unwrap_or("Unknown".to_owned()),
+                    is_active: device.find("is_active").and_then(JSON::as_bool) == Some(true),
+                    volume: device.find("volume_percent")
+                        .and_then(JSON::as_u64)
+                        .map(|volume| volume as u8),
+                }
+            })
+        })
+        .collect()
+}
+
+/// Parse the response of `GET /me/player`.
+pub fn parse_playback(json: &JSON) -> Playback {
+    let item = json.find("item");
+    Playback {
+        device: json.find("device").and_then(|device| string_field(device, "id")),
+        is_playing: json.find("is_playing").and_then(JSON::as_bool) == Some(true),
+        track: item.and_then(|item| string_field(item, "name")),
+        artists: item.and_then(|item| item.find("artists"))
+            .and_then(JSON::as_array)
+            .map_or(vec![], |artists| {
+                artists.iter().filter_map(|artist| string_field(artist, "name")).collect()
+            }),
+    }
+}
+
+#[derive(Clone)]
+pub struct SpotifyApi {
+    broker: OAuth2Broker,
+}
+
+impl SpotifyApi {
+    pub fn new(broker: OAuth2Broker) -> Self {
+        SpotifyApi { broker: broker }
+    }
+
+    /// Call the API. Returns `None` for responses without a body.
+    fn call(&self,
+            method: Method,
+            path: &str,
+            query: &[(&str, &str)],
+            body: Option<JSON>)
+            -> Result<Option<JSON>, ApiError> {
+        let token = try!(self.broker.access_token(PROVIDER));
+        let mut url = format!("{}{}", API_URL, path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(query)
+                .finish());
+        }
+        let body = body.map_or(String::new(), |body| serde_json::to_string(&body).unwrap());
+        let client = hyper::Client::new();
+        let mut request = client.request(method, &url)
+            .header(Authorization(Bearer { token: token }))
+            .header(Connection::close());
+        if !body.is_empty() {
+            request = request.header(ContentType::json());
+        }
+        let mut response = try!(request.body(&body[..])
+            .send()
+            .map_err(|err| ApiError::Other(format!("{}", err))));
+        let mut source = String::new();
+        try!(response.read_to_string(&mut source)
+            .map_err(|err| ApiError::Other(format!("{}", err))));
+        if !response.status.is_success() {
+            return Err(ApiError::Other(format!("{} returned {}: {}",
+                                               path,
+                                               response.status,
+                                               source)));
+        }
+        if response.status == StatusCode::NoContent || source.trim().is_empty() {
+            return Ok(None);
+        }
+        serde_json::from_str(&source)
+            .map(Some)
+            .map_err(|err| ApiError::Other(format!("Invalid response to {}: {}", path, err)))
+    }
+
+    pub fn devices(&self) -> Result<Vec<Device>, ApiError> {
+        let json = try!(self.call(Method::Get, "/devices", &[], None));
+        Ok(json.map_or(vec![], |json| parse_devices(&json)))
+    }
+
+    /// The current playback, if the account is playing or paused on any device.
+    pub fn playback(&self) -> Result<Option<Playback>, ApiError> {
+        let json = try!(self.call(Method::Get, "", &[], None));
+        Ok(json.map(|json| parse_playback(&json)))
+    }
+
+    /// Resume playback on a device, transferring it there if needed.
+    pub fn play(&self, device: &str) -> Result<(), ApiError> {
+        try!(self.call(Method::Put, "/play", &[("device_id", device)], None));
+        Ok(())
+    }
+
+    pub fn pause(&self, device: &str) -> Result<(), ApiError> {
+        try!(self.call(Method::Put, "/pause", &[("device_id", device)], None));
+        Ok(())
+    }
+
+    pub fn set_volume(&self, device: &str, volume: u8) -> Result<(), ApiError> {
+        let volume = format!("{}", volume);
+        try!(self.call(Method::Put,
+                       "/volume",
+                       &[("device_id", device), ("volume_percent", &volume[..])],
+                       None));
+        Ok(())
+    }
+
+    /// Move the playback to a device, keeping it playing or paused.
+    pub fn transfer(&self, device: &str) -> Result<(), ApiError> {
+        let body = json_value!({ device_ids: vec![device] });
+        try!(self.call(Method::Put, "", &[], Some(body)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+describe! spotify_api {
+    before_each {
+        use super::*;
+        use serde_json;
+    }
+
+    it "should parse devices" {
+        let json = serde_json::from_str(r#"{"devices": [
+            {"id": "d1", "is_active": true, "is_restricted": false, "name": "Kitchen",
+             "type": "Speaker", "volume_percent": 40},
+            {"id": "d2", "is_active": false, "is_restricted": true, "name": "TV",
+             "type": "TV", "volume_percent": null}]}"#).unwrap();
+        assert_eq!(parse_devices(&json),
+                   vec![Device {
+                            id: "d1".to_owned(),
+                            name: "Kitchen".to_owned(),
+                            kind: "Speaker".to_owned(),
+                            is_active: true,
+                            volume: Some(40),
+                        }]);
+    }
+
+    it "should parse the playback" {
+        let json = serde_json::from_str(r#"{"device": {"id": "d1"}, "is_playing": true,
+            "item": {"name": "Song", "artists": [{"name": "A"}, {"name": "B"}]}}"#).unwrap();
+        assert_eq!(parse_playback(&json),
+                   Playback {
+                       device: Some("d1".to_owned()),
+                       is_playing: true,
+                       track: Some("Song".to_owned()),
+                       artists: vec!["A".to_owned(), "B".to_owned()],
+                   });
+    }
+}
@@ -0,0 +1,334 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter controlling Spotify Connect devices, through the Spotify Web API.
+//!
+//! The adapter needs a Spotify application, configured as `spotify.client_id` and
+//! `spotify.client_secret`, then a user links the box to their Spotify account through the
+//! OAuth2 broker (`POST /oauth2/providers/spotify/authorize`).
+//!
+//! Each Connect device (speaker, phone, computer...) is a service exposing:
+//! - `playback/state` (fetch, watch), as JSON `{"active": bool, "is_playing": bool,
+//!   "track": string|null, "artists": [string]}`;
+//! - `playback/is-playing` (fetch, watch, send), as `OnOff`. Sending `On` resumes the
+//!   playback on this device, `Off` pauses it. This lets scenes "pause everything";
+//! - `playback/volume` (fetch, watch, send), as `Percent`;
+//! - `playback/transfer` (send): move the playback to this device.
+//!
+//! The Web API doesn't push events, so watches are served by polling the devices and the
+//! playback every `spotify.poll_interval` seconds.
+
+mod api;
+
+use self::api::{ApiError, Device, Playback, SpotifyApi};
+
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, OnOff, Percent, Value};
+
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static ADAPTER_NAME: &'static str = "Spotify Connect adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    State,
+    IsPlaying,
+    Volume,
+    Transfer,
+}
+
+/// The state of a device, as exposed by `playback/state`.
+fn state_value(device: &str, playback: Option<&Playback>) -> Value {
+    let mut object = BTreeMap::new();
+    let (active, is_playing, track, artists) = match playback {
+        Some(playback) if playback.device.as_ref().map_or(false, |id| id == device) => {
+            (true, playback.is_playing, playback.track.clone(), playback.artists.clone())
+        }
+        _ => (false, false, None, vec![]),
+    };
+    object.insert("active".to_owned(), JSON::Bool(active));
+    object.insert("is_playing".to_owned(), JSON::Bool(is_playing));
+    object.insert("track".to_owned(), track.map_or(JSON::Null, JSON::String));
+    object.insert("artists".to_owned(),
+                  JSON::Array(artists.into_iter().map(JSON::String).collect()));
+    Value::new(Json(JSON::Object(object)))
+}
+
+pub struct SpotifyAdapter {
+    api: SpotifyApi,
+    watchers: ValueWatchers,
+    /// The Spotify device and kind of each channel.
+    channels: Arc<Mutex<HashMap<Id<Channel>, (String, Kind)>>>,
+}
+
+impl SpotifyAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("spotify@link.mozilla.org")
+    }
+
+    fn service_id(device: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.spotify@link.mozilla.org", device))
+    }
+
+    fn channel_id(device: &str, kind: Kind) -> Id<Channel> {
+        let name = match kind {
+            Kind::State => "getter:state",
+            Kind::IsPlaying => "is-playing",
+            Kind::Volume => "volume",
+            Kind::Transfer => "setter:transfer",
+        };
+        Id::new(&format!("{}.{}.spotify@link.mozilla.org", name, device))
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let (client_id, client_secret) = match (config.get("spotify", "client_id"),
+                                                config.get("spotify", "client_secret")) {
+            (Some(client_id), Some(client_secret)) => (client_id, client_secret),
+            _ => {
+                info!("[spotify] No Spotify application configured.");
+                return Ok(());
+            }
+        };
+        let poll_interval = config.get_or_set_default("spotify", "poll_interval", "10")
+            .parse()
+            .unwrap_or(10);
+        let poll_interval = Duration::from_secs(poll_interval);
+
+        let broker = controller.get_oauth2();
+        broker.register_provider(api::provider(&client_id, &client_secret));
+
+        let adapter = Arc::new(SpotifyAdapter {
+            api: SpotifyApi::new(broker),
+            watchers: ValueWatchers::new(),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+        });
+        try!(manager.add_adapter(adapter.clone()));
+
+        let manager = manager.clone();
+        thread::Builder::new()
+            .name("Spotify".to_owned())
+            .spawn(move || {
+                let mut linked = true;
+                loop {
+                    match adapter.poll(&manager) {
+                        Ok(()) => linked = true,
+                        Err(ApiError::NotLinked) => {
+                            if linked {
+                                info!("[spotify] Waiting for the box to be linked to a Spotify \
+                                       account.");
+                            }
+                            linked = false;
+                        }
+                        Err(ApiError::Other(err)) => warn!("[spotify] Polling failed: {}", err),
+                    }
+                    thread::sleep(poll_interval);
+                }
+            })
+            .unwrap();
+
+        Ok(())
+    }
+
+    /// Register the service and channels of a device not seen yet.
+    fn add_device(&self, manager: &Arc<AdapterManager>, device: &Device) -> Result<(), Error> {
+        let mut channels = self.channels.lock().unwrap();
+        if channels.contains_key(&Self::channel_id(&device.id, Kind::State)) {
+            return Ok(());
+        }
+        let service_id = Self::service_id(&device.id);
+        let mut service = Service::empty(&service_id, &Self::id());
+        service.properties.insert("model".to_owned(), format!("Spotify {}", device.kind));
+        service.properties.insert("name".to_owned(), device.name.clone());
+        try!(manager.add_service(service));
+
+        let kinds = vec![(Kind::State, "playback/state", format::JSON.clone(), false),
+                         (Kind::IsPlaying, "playback/is-playing", format::ON_OFF.clone(), true),
+                         (Kind::Volume, "playback/volume", format::PERCENT.clone(), true)];
+        for (kind, feature, format, supports_send) in kinds {
+            let id = Self::channel_id(&device.id, kind);
+            try!(manager.add_channel(Channel {
+                id: id.clone(),
+                service: service_id.clone(),
+                adapter: Self::id(),
+                feature: Id::new(feature),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format.clone()))),
+                supports_watch: Some(Signature::returns(Maybe::Required(format.clone()))),
+                supports_send: if supports_send {
+                    Some(Signature::accepts(Maybe::Required(format)))
+                } else {
+                    None
+                },
+                ..Channel::default()
+            }));
+            channels.insert(id, (device.id.clone(), kind));
+        }
+        let id = Self::channel_id(&device.id, Kind::Transfer);
+        try!(manager.add_channel(Channel {
+            id: id.clone(),
+            service: service_id,
+            adapter: Self::id(),
+            feature: Id::new("playback/transfer"),
+            supports_send: Some(Signature::nothing()),
+            ..Channel::default()
+        }));
+        channels.insert(id, (device.id.clone(), Kind::Transfer));
+        Ok(())
+    }
+
+    /// Discover the devices, and update the watchers with the latest playback.
+    fn poll(&self, manager: &Arc<AdapterManager>) -> Result<(), ApiError> {
+        let devices = try!(self.api.devices());
+        let playback = try!(self.api.playback());
+        for device in &devices {
+            if let Err(err) = self.add_device(manager, device) {
+                error!("[spotify] Could not add device {}: {:?}", device.id, err);
+            }
+            if let Some(volume) = device.volume {
+                self.watchers.update(&Self::channel_id(&device.id, Kind::Volume),
+                                     Value::new(Percent::new(volume)));
+            }
+        }
+
+        // Devices which went offline are no longer in the list, but they aren't playing.
+        let known: Vec<String> = {
+            let channels = self.channels.lock().unwrap();
+            channels.values()
+                .filter(|&&(_, kind)| kind == Kind::State)
+                .map(|&(ref device, _)| device.clone())
+                .collect()
+        };
+        for device in &known {
+            let state = state_value(device, playback.as_ref());
+            let is_playing = playback.as_ref().map_or(false, |playback| {
+                playback.is_playing && playback.device.as_ref() == Some(device)
+            });
+            self.watchers.update(&Self::channel_id(device, Kind::State), state);
+            self.watchers.update(&Self::channel_id(device, Kind::IsPlaying),
+                                 Value::new(if is_playing { OnOff::On } else { OnOff::Off }));
+        }
+        Ok(())
+    }
+
+    fn send(&self, device: &str, kind: Kind, value: &Value) -> Result<(), Error> {
+        let result = match kind {
+            Kind::IsPlaying => {
+                match *try!(value.cast::<OnOff>()) {
+                    OnOff::On => self.api.play(device),
+                    OnOff::Off => self.api.pause(device),
+                }
+            }
+            Kind::Volume => self.api.set_volume(device, try!(value.cast::<Percent>()).as_u8()),
+            Kind::Transfer => self.api.transfer(device),
+            Kind::State => unreachable!(),
+        };
+        result.map_err(|err| Error::Internal(InternalError::GenericError(format!("{:?}", err))))
+    }
+}
+
+impl Adapter for SpotifyAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        let channels = self.channels.lock().unwrap();
+        set.drain(..)
+            .map(|id| {
+                match channels.get(&id) {
+                    None => (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id)))),
+                    Some(&(_, Kind::Transfer)) => {
+                        (id.clone(), Err(Error::OperationNotSupported(Operation::Fetch, id)))
+                    }
+                    // Values are kept up to date by the polling thread.
+                    Some(_) => {
+                        let value = self.watchers.latest(&id);
+                        (id, Ok(value))
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                let channel = self.channels.lock().unwrap().get(&id).cloned();
+                let result = match channel {
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                    Some((_, Kind::State)) => {
+                        Err(Error::OperationNotSupported(Operation::Send, id.clone()))
+                    }
+                    Some((device, Kind::Transfer)) => self.send(&device, Kind::Transfer, &value),
+                    Some((device, kind)) => {
+                        self.send(&device, kind, &value).map(|()| self.watchers.update(&id, value))
+                    }
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
+
+#[cfg(test)]
+describe! spotify {
+    before_each {
+        use super::api::Playback;
+        use super::state_value;
+        use foxbox_taxonomy::values::Json;
+        use serde_json::value::Value as JSON;
+    }
+
+    it "should tell the state of each device" {
+        let playback = Playback {
+            device: Some("d1".to_owned()),
+            is_playing: true,
+            track: Some("Song".to_owned()),
+            artists: vec!["A".to_owned()],
+        };
+        let state = state_value("d1", Some(&playback));
+        let json = &state.cast::<Json>().unwrap().0;
+        assert_eq!(json.find("active").and_then(JSON::as_bool), Some(true));
+        assert_eq!(json.find("track").and_then(JSON::as_string), Some("Song"));
+
+        let state = state_value("d2", Some(&playback));
+        let json = &state.cast::<Json>().unwrap().0;
+        assert_eq!(json.find("is_playing").and_then(JSON::as_bool), Some(false));
+        assert_eq!(json.find("track"), Some(&JSON::Null));
+    }
+}
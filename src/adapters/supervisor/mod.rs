@@ -0,0 +1,339 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter supervising external helper processes (e.g. `rtl_433`, `motion`).
+//!
+//! Processes are declared in the configuration:
+//! - `supervisor.processes` is a comma separated list of process names;
+//! - `supervisor.<name>.command` is the command line of the process;
+//! - `supervisor.<name>.autostart` (default "true") decides whether it starts with the box.
+//!
+//! Processes are restarted with a backoff if they crash. Each process is a service with
+//! channels `process/is-running` (fetch, watch), `process/start` and `process/stop`
+//! (send), and `process/log` (fetch), which returns the latest lines of the output of the
+//! process as a JSON array. Output is also forwarded to our own log.
+
+use foxbox_core::log_buffer::LogBuffer;
+use foxbox_core::managed_process::ManagedProcess;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, OnOff, Value};
+
+use serde_json::value::Value as JSON;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static ADAPTER_NAME: &'static str = "Process supervisor adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const LOG_LINES: usize = 200;
+const STATUS_POLL_INTERVAL_S: u64 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ChannelKind {
+    IsRunning,
+    Start,
+    Stop,
+    Log,
+}
+
+struct Process {
+    name: String,
+    command: Vec<String>,
+    log: LogBuffer,
+    process: Mutex<Option<ManagedProcess>>,
+}
+
+/// Forward each line read from `source` to the log buffer and to our own log.
+fn capture<R: Read + Send + 'static>(name: String, source: R, log: LogBuffer) {
+    thread::spawn(move || {
+        for line in BufReader::new(source).lines() {
+            match line {
+                Ok(line) => {
+                    debug!("[supervisor] {}: {}", name, line);
+                    log.push(line);
+                }
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+impl Process {
+    fn start(&self) -> io::Result<()> {
+        let mut process = self.process.lock().unwrap();
+        if process.is_some() {
+            return Ok(());
+        }
+        let name = self.name.clone();
+        let command = self.command.clone();
+        let log = self.log.clone();
+        *process = Some(try!(ManagedProcess::start(move || {
+            let mut child = try!(Command::new(&command[0])
+                .args(&command[1..])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn());
+            if let Some(stdout) = child.stdout.take() {
+                capture(name.clone(), stdout, log.clone());
+            }
+            if let Some(stderr) = child.stderr.take() {
+                capture(name.clone(), stderr, log.clone());
+            }
+            Ok(child)
+        })));
+        info!("[supervisor] Started {}", self.name);
+        Ok(())
+    }
+
+    fn stop(&self) -> io::Result<()> {
+        match self.process.lock().unwrap().take() {
+            Some(process) => {
+                info!("[supervisor] Stopping {}", self.name);
+                process.shutdown()
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.process.lock().unwrap().as_ref().map_or(false, |process| process.is_running())
+    }
+}
+
+pub struct Supervisor {
+    processes: Vec<Arc<Process>>,
+    channels: HashMap<Id<Channel>, (Arc<Process>, ChannelKind)>,
+    watchers: ValueWatchers,
+}
+
+fn is_running_value(running: bool) -> Value {
+    Value::new(if running { OnOff::On } else { OnOff::Off })
+}
+
+impl Supervisor {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("supervisor@link.mozilla.org")
+    }
+
+    pub fn service_id(name: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.supervisor@link.mozilla.org", name))
+    }
+
+    pub fn getter_is_running_id(name: &str) -> Id<Channel> {
+        Id::new(&format!("getter:is-running.{}.supervisor@link.mozilla.org", name))
+    }
+
+    pub fn setter_start_id(name: &str) -> Id<Channel> {
+        Id::new(&format!("setter:start.{}.supervisor@link.mozilla.org", name))
+    }
+
+    pub fn setter_stop_id(name: &str) -> Id<Channel> {
+        Id::new(&format!("setter:stop.{}.supervisor@link.mozilla.org", name))
+    }
+
+    pub fn getter_log_id(name: &str) -> Id<Channel> {
+        Id::new(&format!("getter:log.{}.supervisor@link.mozilla.org", name))
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let names = match config.get("supervisor", "processes") {
+            Some(names) => names,
+            None => return Ok(()),
+        };
+
+        let mut processes = Vec::new();
+        let mut autostart = Vec::new();
+        for name in names.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            let command: Vec<String> = config.get("supervisor", &format!("{}.command", name))
+                .map(|command| command.split_whitespace().map(|arg| arg.to_owned()).collect())
+                .unwrap_or_else(Vec::new);
+            if command.is_empty() {
+                warn!("[supervisor] No command configured for process {}", name);
+                continue;
+            }
+            let process = Arc::new(Process {
+                name: name.to_owned(),
+                command: command,
+                log: LogBuffer::new(LOG_LINES),
+                process: Mutex::new(None),
+            });
+            if config.get_or_set_default("supervisor", &format!("{}.autostart", name), "true") ==
+               "true" {
+                autostart.push(process.clone());
+            }
+            processes.push(process);
+        }
+
+        let mut channels = HashMap::new();
+        for process in &processes {
+            let name = &process.name;
+            channels.insert(Self::getter_is_running_id(name),
+                            (process.clone(), ChannelKind::IsRunning));
+            channels.insert(Self::setter_start_id(name), (process.clone(), ChannelKind::Start));
+            channels.insert(Self::setter_stop_id(name), (process.clone(), ChannelKind::Stop));
+            channels.insert(Self::getter_log_id(name), (process.clone(), ChannelKind::Log));
+        }
+
+        let watchers = ValueWatchers::new();
+        let adapter = Arc::new(Supervisor {
+            processes: processes.clone(),
+            channels: channels,
+            watchers: watchers.clone(),
+        });
+        try!(manager.add_adapter(adapter));
+
+        for process in &processes {
+            let name = &process.name;
+            let mut service = Service::empty(&Self::service_id(name), &Self::id());
+            service.properties.insert("model".to_owned(), "Supervised process".to_owned());
+            service.properties.insert("command".to_owned(), process.command.join(" "));
+            try!(manager.add_service(service));
+            try!(manager.add_channel(Channel {
+                id: Self::getter_is_running_id(name),
+                service: Self::service_id(name),
+                adapter: Self::id(),
+                feature: Id::new("process/is-running"),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+                supports_watch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+                ..Channel::default()
+            }));
+            try!(manager.add_channel(Channel {
+                id: Self::setter_start_id(name),
+                service: Self::service_id(name),
+                adapter: Self::id(),
+                feature: Id::new("process/start"),
+                supports_send: Some(Signature::nothing()),
+                ..Channel::default()
+            }));
+            try!(manager.add_channel(Channel {
+                id: Self::setter_stop_id(name),
+                service: Self::service_id(name),
+                adapter: Self::id(),
+                feature: Id::new("process/stop"),
+                supports_send: Some(Signature::nothing()),
+                ..Channel::default()
+            }));
+            try!(manager.add_channel(Channel {
+                id: Self::getter_log_id(name),
+                service: Self::service_id(name),
+                adapter: Self::id(),
+                feature: Id::new("process/log"),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                ..Channel::default()
+            }));
+        }
+
+        for process in autostart {
+            if let Err(err) = process.start() {
+                error!("[supervisor] Could not start {}: {}", process.name, err);
+            }
+        }
+
+        // `ManagedProcess` restarts crashed processes on its own, so we only need to keep
+        // an eye on the status for the sake of watchers.
+        thread::spawn(move || {
+            loop {
+                for process in &processes {
+                    watchers.update(&Self::getter_is_running_id(&process.name),
+                                    is_running_value(process.is_running()));
+                }
+                thread::sleep(Duration::from_secs(STATUS_POLL_INTERVAL_S));
+            }
+        });
+
+        Ok(())
+    }
+}
+
+impl Adapter for Supervisor {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                let result = match self.channels.get(&id) {
+                    Some(&(ref process, ChannelKind::IsRunning)) => {
+                        Ok(Some(is_running_value(process.is_running())))
+                    }
+                    Some(&(ref process, ChannelKind::Log)) => {
+                        let lines = process.log
+                            .lines()
+                            .drain(..)
+                            .map(JSON::String)
+                            .collect();
+                        Ok(Some(Value::new(Json(JSON::Array(lines)))))
+                    }
+                    Some(_) => Err(Error::OperationNotSupported(Operation::Fetch, id.clone())),
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, _)| {
+                let result = match self.channels.get(&id) {
+                    Some(&(ref process, ChannelKind::Start)) => process.start(),
+                    Some(&(ref process, ChannelKind::Stop)) => process.stop(),
+                    Some(_) => {
+                        return (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id)))
+                    }
+                    None => {
+                        return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+                    }
+                };
+                let result = result.map_err(|err| {
+                    Error::Internal(InternalError::GenericError(format!("{}", err)))
+                });
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+
+    fn stop(&self) {
+        for process in &self.processes {
+            if let Err(err) = process.stop() {
+                warn!("[supervisor] Could not stop {}: {}", process.name, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter exposing energy prices, to run appliances when energy is cheap.
+//!
+//! Prices come from `tariff.provider_url` if it is set, otherwise from the time-of-use
+//! schedule `tariff.schedule` (see `prices`). The `tariff` service exposes:
+//! - `tariff/current-price` (fetch, watch): JSON `{"price", "currency"}`;
+//! - `tariff/cheapest-window-next-12h` (fetch, watch): the cheapest window of
+//!   `tariff.window_hours` hours (3 by default) starting within the next 12 hours, as JSON
+//!   `{"start", "end", "average_price", "currency"}`;
+//! - `tariff/in-cheapest-window` (fetch, watch): `On` during this window, which makes it easy
+//!   to write rules such as "start the dishwasher when entering the cheapest window".
+
+mod prices;
+
+use self::prices::Slot;
+
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, OnOff, Value};
+
+use chrono::{self, Local, UTC};
+use hyper;
+use hyper::header::Connection;
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static ADAPTER_NAME: &'static str = "Energy tariff adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const UPDATE_INTERVAL_S: u64 = 60;
+const PROVIDER_INTERVAL_S: u64 = 3600;
+const HORIZON_H: i64 = 12;
+
+enum Source {
+    Schedule(Vec<(u32, u32, f64)>),
+    Provider(String),
+}
+
+impl Source {
+    fn fetch(&self) -> Result<Vec<Slot>, String> {
+        match *self {
+            Source::Schedule(ref schedule) => Ok(prices::expand_schedule(schedule, Local::now())),
+            Source::Provider(ref url) => {
+                let client = hyper::Client::new();
+                let mut response = try!(client.get(url)
+                    .header(Connection::close())
+                    .send()
+                    .map_err(|err| format!("{}", err)));
+                if !response.status.is_success() {
+                    return Err(format!("{} returned {}", url, response.status));
+                }
+                let mut content = String::new();
+                try!(response.read_to_string(&mut content).map_err(|err| format!("{}", err)));
+                prices::parse_provider(&content)
+            }
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        match *self {
+            // Schedules are cheap to expand, but days have to move on.
+            Source::Schedule(_) => Duration::from_secs(UPDATE_INTERVAL_S),
+            Source::Provider(_) => Duration::from_secs(PROVIDER_INTERVAL_S),
+        }
+    }
+}
+
+pub struct TariffAdapter {
+    watchers: ValueWatchers,
+}
+
+impl TariffAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("tariff@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:tariff@link.mozilla.org")
+    }
+
+    pub fn getter_current_price_id() -> Id<Channel> {
+        Id::new("getter:current-price.tariff@link.mozilla.org")
+    }
+
+    pub fn getter_cheapest_window_id() -> Id<Channel> {
+        Id::new("getter:cheapest-window.tariff@link.mozilla.org")
+    }
+
+    pub fn getter_in_cheapest_window_id() -> Id<Channel> {
+        Id::new("getter:in-cheapest-window.tariff@link.mozilla.org")
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let provider_url = config.get("tariff", "provider_url");
+        let source = match (provider_url, config.get("tariff", "schedule")) {
+            (Some(url), _) => Source::Provider(url),
+            (None, Some(schedule)) => {
+                Source::Schedule(try!(prices::parse_schedule(&schedule)
+                    .map_err(|err| Error::Internal(InternalError::GenericError(err)))))
+            }
+            (None, None) => {
+                info!("No energy tariff configured.");
+                return Ok(());
+            }
+        };
+        let currency = config.get_or_set_default("tariff", "currency", "EUR");
+        let window_hours = config.get_or_set_default("tariff", "window_hours", "3")
+            .parse()
+            .unwrap_or(3);
+
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(TariffAdapter { watchers: watchers.clone() })));
+
+        let mut service = Service::empty(&Self::service_id(), &Self::id());
+        service.properties.insert("model".to_owned(), "Energy tariff".to_owned());
+        try!(manager.add_service(service));
+
+        let channels = vec![(Self::getter_current_price_id(), "tariff/current-price"),
+                            (Self::getter_cheapest_window_id(),
+                             "tariff/cheapest-window-next-12h")];
+        for (id, feature) in channels {
+            try!(manager.add_channel(Channel {
+                id: id,
+                service: Self::service_id(),
+                adapter: Self::id(),
+                feature: Id::new(feature),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                supports_watch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+                ..Channel::default()
+            }));
+        }
+        try!(manager.add_channel(Channel {
+            id: Self::getter_in_cheapest_window_id(),
+            service: Self::service_id(),
+            adapter: Self::id(),
+            feature: Id::new("tariff/in-cheapest-window"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+            supports_watch: Some(Signature {
+                accepts: Maybe::Optional(format::ON_OFF.clone()),
+                returns: Maybe::Required(format::ON_OFF.clone()),
+            }),
+            ..Channel::default()
+        }));
+
+        thread::spawn(move || {
+            let mut slots = vec![];
+            let mut last_fetch: Option<Instant> = None;
+            let mut published: Option<(JSON, JSON, bool)> = None;
+            loop {
+                if last_fetch.map_or(true, |last| last.elapsed() >= source.interval()) {
+                    match source.fetch() {
+                        Ok(fetched) => slots = fetched,
+                        Err(err) => warn!("[tariff] Could not fetch prices: {}", err),
+                    }
+                    last_fetch = Some(Instant::now());
+                }
+                let state = Self::state(&slots, &currency, window_hours);
+                if published.as_ref() != Some(&state) {
+                    let (ref price, ref window, in_window) = state;
+                    watchers.update(&Self::getter_current_price_id(),
+                                    Value::new(Json(price.clone())));
+                    watchers.update(&Self::getter_cheapest_window_id(),
+                                    Value::new(Json(window.clone())));
+                    watchers.update(&Self::getter_in_cheapest_window_id(),
+                                    Value::new(if in_window { OnOff::On } else { OnOff::Off }));
+                }
+                published = Some(state);
+                thread::sleep(Duration::from_secs(UPDATE_INTERVAL_S));
+            }
+        });
+        Ok(())
+    }
+
+    /// The current price, the cheapest window and whether we are in it.
+    fn state(slots: &[Slot], currency: &str, window_hours: i64) -> (JSON, JSON, bool) {
+        let now = UTC::now();
+        let mut price = BTreeMap::new();
+        price.insert("currency".to_owned(), JSON::String(currency.to_owned()));
+        if let Some(current) = prices::current_price(slots, now) {
+            price.insert("price".to_owned(), JSON::F64(current));
+        }
+        let mut window = BTreeMap::new();
+        let mut in_window = false;
+        if let Some(cheapest) = prices::cheapest_window(slots,
+                                                        now,
+                                                        chrono::Duration::hours(HORIZON_H),
+                                                        chrono::Duration::hours(window_hours)) {
+            // The window starts now, unless it is worth waiting.
+            in_window = cheapest.start <= now;
+            window.insert("start".to_owned(), JSON::String(cheapest.start.to_rfc3339()));
+            window.insert("end".to_owned(), JSON::String(cheapest.end.to_rfc3339()));
+            window.insert("average_price".to_owned(), JSON::F64(cheapest.average_price));
+            window.insert("currency".to_owned(), JSON::String(currency.to_owned()));
+        }
+        (JSON::Object(price), JSON::Object(window), in_window)
+    }
+}
+
+impl Adapter for TariffAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                if id == Self::getter_current_price_id() ||
+                   id == Self::getter_cheapest_window_id() ||
+                   id == Self::getter_in_cheapest_window_id() {
+                    let value = self.watchers.latest(&id);
+                    return (id, Ok(value));
+                }
+                (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, _)| (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id))))
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
@@ -0,0 +1,205 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Energy prices, as a sequence of time slots.
+//!
+//! Prices come either from a daily time-of-use schedule, e.g. `00:00=0.12,07:00=0.25,23:00=0.12`
+//! (in local time), or from a provider serving JSON such as
+//! `[{"start": "2016-07-01T00:00:00Z", "price": 0.12}, ...]`. In both cases, each slot lasts
+//! until the start of the next one.
+
+use chrono::{DateTime, Duration, TimeZone, UTC};
+use serde_json;
+use serde_json::value::Value as JSON;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Slot {
+    pub start: DateTime<UTC>,
+    pub end: DateTime<UTC>,
+    pub price: f64,
+}
+
+/// A window of time, and its average price.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Window {
+    pub start: DateTime<UTC>,
+    pub end: DateTime<UTC>,
+    pub average_price: f64,
+}
+
+/// Turn a sorted list of `(start, price)` into slots. The last one has no known end, so it
+/// is dropped.
+fn to_slots(mut starts: Vec<(DateTime<UTC>, f64)>) -> Vec<Slot> {
+    starts.sort_by(|a, b| a.0.cmp(&b.0));
+    starts.windows(2)
+        .map(|pair| {
+            Slot {
+                start: pair[0].0,
+                end: pair[1].0,
+                price: pair[0].1,
+            }
+        })
+        .collect()
+}
+
+/// Parse a time-of-use schedule into `(hour, minute, price)`.
+pub fn parse_schedule(source: &str) -> Result<Vec<(u32, u32, f64)>, String> {
+    let mut schedule = Vec::new();
+    for entry in source.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+        let invalid = || format!("Invalid tariff entry {:?}, expected HH:MM=price", entry);
+        let mut split = entry.splitn(2, '=');
+        let time = split.next().unwrap_or("");
+        let price = try!(split.next()
+            .and_then(|price| price.trim().parse().ok())
+            .ok_or_else(&invalid));
+        let mut time = time.trim().splitn(2, ':');
+        let hour = try!(time.next().and_then(|hour| hour.parse().ok()).ok_or_else(&invalid));
+        let minute = try!(time.next()
+            .and_then(|minute| minute.parse().ok())
+            .ok_or_else(&invalid));
+        if hour > 23 || minute > 59 {
+            return Err(invalid());
+        }
+        schedule.push((hour, minute, price));
+    }
+    if schedule.is_empty() {
+        return Err("Empty tariff schedule".to_owned());
+    }
+    Ok(schedule)
+}
+
+/// Expand a daily schedule into slots, from the day before `now` to two days after it.
+pub fn expand_schedule<Tz: TimeZone>(schedule: &[(u32, u32, f64)],
+                                     now: DateTime<Tz>)
+                                     -> Vec<Slot> {
+    let mut starts = Vec::new();
+    let mut day = now.date().pred();
+    for _ in 0..4 {
+        for &(hour, minute, price) in schedule {
+            starts.push((day.and_hms(hour, minute, 0).with_timezone(&UTC), price));
+        }
+        day = day.succ();
+    }
+    to_slots(starts)
+}
+
+/// Parse the prices served by a provider.
+pub fn parse_provider(source: &str) -> Result<Vec<Slot>, String> {
+    let json: JSON = try!(serde_json::from_str(source).map_err(|err| format!("{}", err)));
+    let entries = try!(json.as_array().ok_or_else(|| "Expected an array of prices".to_owned()));
+    let mut starts = Vec::new();
+    for entry in entries {
+        let start = entry.find("start")
+            .and_then(JSON::as_string)
+            .and_then(|start| DateTime::parse_from_rfc3339(start).ok())
+            .map(|start| start.with_timezone(&UTC));
+        let price = entry.find("price").and_then(JSON::as_f64);
+        match (start, price) {
+            (Some(start), Some(price)) => starts.push((start, price)),
+            _ => return Err(format!("Invalid price entry {:?}", entry)),
+        }
+    }
+    // Providers publish hourly prices, so the last one is assumed to last an hour.
+    if let Some(&(last, _)) = starts.iter().max_by_key(|&&(start, _)| start) {
+        starts.push((last + Duration::hours(1), 0.0));
+    }
+    Ok(to_slots(starts))
+}
+
+pub fn current_price(slots: &[Slot], now: DateTime<UTC>) -> Option<f64> {
+    slots.iter().find(|slot| slot.start <= now && now < slot.end).map(|slot| slot.price)
+}
+
+/// The average price between `start` and `end`, if we know the prices for all of it.
+fn average_price(slots: &[Slot], start: DateTime<UTC>, end: DateTime<UTC>) -> Option<f64> {
+    let mut covered = 0;
+    let mut cost = 0.0;
+    for slot in slots {
+        let from = if slot.start > start { slot.start } else { start };
+        let to = if slot.end < end { slot.end } else { end };
+        if from < to {
+            let seconds = (to - from).num_seconds();
+            covered += seconds;
+            cost += slot.price * seconds as f64;
+        }
+    }
+    let total = (end - start).num_seconds();
+    if total <= 0 || covered < total {
+        return None;
+    }
+    Some(cost / total as f64)
+}
+
+/// The cheapest window of `length` starting within `horizon` from `now`. Windows start either
+/// now or at the start of a slot.
+pub fn cheapest_window(slots: &[Slot],
+                       now: DateTime<UTC>,
+                       horizon: Duration,
+                       length: Duration)
+                       -> Option<Window> {
+    let mut best: Option<Window> = None;
+    let starts = Some(now)
+        .into_iter()
+        .chain(slots.iter().map(|slot| slot.start).filter(|start| *start > now));
+    for start in starts {
+        let end = start + length;
+        if end > now + horizon {
+            continue;
+        }
+        if let Some(average_price) = average_price(slots, start, end) {
+            let is_better = best.as_ref().map_or(true, |best| average_price < best.average_price);
+            if is_better {
+                best = Some(Window {
+                    start: start,
+                    end: end,
+                    average_price: average_price,
+                });
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+describe! tariff_prices {
+    before_each {
+        use super::*;
+        use chrono::{Duration, TimeZone, UTC};
+
+        let now = UTC.ymd(2016, 7, 1).and_hms(18, 30, 0);
+        let slots = expand_schedule(&parse_schedule("00:00=0.10, 07:00=0.25,22:00=0.15")
+                                        .unwrap(),
+                                    now);
+    }
+
+    it "should parse and expand schedules" {
+        assert!(parse_schedule("7h=0.2").is_err());
+        assert!(parse_schedule("25:00=0.2").is_err());
+        assert!(parse_schedule("").is_err());
+        assert_eq!(current_price(&slots, now), Some(0.25));
+        assert_eq!(current_price(&slots, now + Duration::hours(6)), Some(0.10));
+    }
+
+    it "should find the cheapest window" {
+        let window = cheapest_window(&slots, now, Duration::hours(12), Duration::hours(2)).unwrap();
+        assert_eq!(window.start, UTC.ymd(2016, 7, 2).and_hms(0, 0, 0));
+        assert_eq!(window.average_price, 0.10);
+
+        // Starting now is the best we can do within 3 hours.
+        let window = cheapest_window(&slots, now, Duration::hours(3), Duration::hours(2)).unwrap();
+        assert_eq!(window.start, now);
+        assert!(cheapest_window(&slots, now, Duration::hours(1), Duration::hours(2)).is_none());
+    }
+
+    it "should parse prices from providers" {
+        let slots = parse_provider(r#"[{"start": "2016-07-01T01:00:00Z", "price": 0.3},
+                                       {"start": "2016-07-01T00:00:00Z", "price": 0.2}]"#)
+            .unwrap();
+        assert_eq!(slots.len(), 2);
+        assert_eq!(slots[0].price, 0.2);
+        assert_eq!(slots[1].end, UTC.ymd(2016, 7, 1).and_hms(2, 0, 0));
+        assert!(parse_provider(r#"[{"start": "yesterday", "price": 1}]"#).is_err());
+        assert_eq!(current_price(&slots, now), None);
+    }
+}
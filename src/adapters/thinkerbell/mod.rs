@@ -1,5 +1,7 @@
 //! An adapter providing access to the Thinkerbell rules engine.
 
+use foxbox_core::config_store::{ConfigService, ConfigType};
+use foxbox_core::timeline::{Entry, EntryKind, Timeline};
 use foxbox_taxonomy::api::{Error, InternalError, User};
 use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::io;
@@ -12,7 +14,7 @@ use foxbox_taxonomy::values::{format, Data, Duration, Json, Value, OnOff};
 use foxbox_thinkerbell::ast::*;
 use foxbox_thinkerbell::compile::ExecutableDevEnv;
 use foxbox_thinkerbell::manager::{ScriptManager, ScriptId, Error as ScriptManagerError};
-use foxbox_thinkerbell::run::ExecutionEvent;
+use foxbox_thinkerbell::run::{simulate, ExecutionEvent, LoopDetection, LoopPolicy, Simulation};
 
 use timer;
 use transformable_channels::mpsc::*;
@@ -22,6 +24,7 @@ use std::fmt;
 use std::path;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration as StdDuration;
 
 use serde_json;
 
@@ -33,11 +36,17 @@ static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
 ///
 /// Each "rule", or "script", is a JSON-serialized structure according to Thinkerbell conventions.
 ///
-/// This adapter exposes a root service, with one `AddThinkerbellRule` setter (to add a new rule).
+/// This adapter exposes a root service, with one `AddThinkerbellRule` setter (to add a new rule),
+/// and one `thinkerbell/simulate-rule` channel: sending a rule to it records what the rule
+/// would send, without starting it or sending anything, and fetching it returns that trace.
 /// Each rule that has been added is exposed as its own service, with the following getters/setters:
 /// - Set Enabled (setter) -- toggles whether or not the script is enabled
 /// - Get Enabled (getter) -- returns whether or not the script is enabled
 /// - Remove (setter) -- removes the script
+/// - Last Execution (getter) -- when the script last sent values, and to which channels,
+///   as JSON, or `null`
+/// - Execution Log (getter) -- the latest events of the script, most recent first, e.g.
+///   compile errors, values sent or failures to send them, see `ExecutionLog`
 ///
 /// This adapter performs most actions by delegating channel messages to its main thread.
 #[derive(Clone)]
@@ -54,11 +63,19 @@ pub struct ThinkerbellAdapter {
     /// The ID of the root service's "Add Rule" setter.
     setter_add_rule_id: Id<Channel>,
 
+    /// The ID of the root service's "Simulate Rule" channel.
+    channel_simulate_id: Id<Channel>,
+
+    /// The environment in which rules are simulated.
+    env: ThinkerbellExecutionEnv,
+
     /// The `FeatureId` for accessing the on/off state of a rule.
     feature_rule_on: Id<FeatureId>,
 
     feature_source: Id<FeatureId>,
     feature_remove: Id<FeatureId>,
+    feature_last_execution: Id<FeatureId>,
+    feature_execution_log: Id<FeatureId>,
 }
 
 /// Thinkerbell requires an execution environment following this API.
@@ -68,6 +85,8 @@ struct ThinkerbellExecutionEnv {
 
     // FIXME: Timer's not clonable, so we should only use one, right? Does this have to be mutexed?
     timer: Arc<Mutex<timer::Timer>>,
+
+    config: Arc<ConfigService>,
 }
 impl fmt::Debug for ThinkerbellExecutionEnv {
     fn fmt(&self, _: &mut fmt::Formatter) -> Result<(), fmt::Error> {
@@ -92,6 +111,51 @@ impl ExecutableDevEnv for ThinkerbellExecutionEnv {
             let _ = sender.send(());
         })
     }
+
+    /// Read from `thinkerbell.loop_policy` ("warn", "suppress" or "stop"),
+    /// `thinkerbell.loop_window_ms` and `thinkerbell.loop_max_echoes`.
+    fn loop_detection(&self) -> LoopDetection {
+        let default = LoopDetection::default();
+        let policy = self.config.get_or_set_default("thinkerbell", "loop_policy", "suppress");
+        let policy = match &*policy {
+            "warn" => LoopPolicy::Warn,
+            "stop" => LoopPolicy::Stop,
+            "suppress" => LoopPolicy::Suppress,
+            other => {
+                warn!("[thinkerbell@link.mozilla.org] Unknown loop_policy {}, using suppress",
+                      other);
+                LoopPolicy::Suppress
+            }
+        };
+        let window = self.config.get_or_set_default("thinkerbell", "loop_window_ms", "2000")
+            .parse()
+            .map(StdDuration::from_millis)
+            .unwrap_or(default.window);
+        let max_echoes = self.config.get_or_set_default("thinkerbell", "loop_max_echoes", "3")
+            .parse()
+            .unwrap_or(default.max_echoes);
+        LoopDetection {
+            window: window,
+            max_echoes: max_echoes,
+            policy: policy,
+        }
+    }
+}
+
+/// Represent the outcome of `simulate` for the `thinkerbell/simulate-rule` channel.
+fn simulation_as_json(name: &str, simulation: Simulation) -> JSON {
+    let trace: Vec<_> = simulation.trace
+        .into_iter()
+        .map(|statement| {
+            json_value!({ rule: statement.rule_index,
+                          statement: statement.statement_index,
+                          otherwise: statement.otherwise,
+                          channels: statement.channels,
+                          value: statement.value.to_json(),
+                          state: statement.state })
+        })
+        .collect();
+    json_value!({ script: name, getters: simulation.getters, trace: trace })
 }
 
 /// Convert a `ScriptManagerError` into an API Error.
@@ -172,6 +236,8 @@ struct ThinkerbellRule {
     getter_source_id: Id<Channel>,
     channel_is_enabled_id: Id<Channel>,
     setter_remove_id: Id<Channel>,
+    getter_last_execution_id: Id<Channel>,
+    getter_execution_log_id: Id<Channel>,
 }
 
 impl ThinkerbellAdapter {
@@ -184,6 +250,9 @@ impl ThinkerbellAdapter {
         // We need to track these to respond to getter/setter requests.
         let mut rules: Vec<ThinkerbellRule> = Vec::new();
 
+        // The trace of the latest rule sent to `thinkerbell/simulate-rule`, if any.
+        let mut latest_simulation = JSON::Null;
+
         'recv: for action in rx {
             match action {
                 // After a script has been started, start a Service for that script.
@@ -224,6 +293,10 @@ impl ThinkerbellAdapter {
                 }
                 // Respond to a pending Getter request.
                 ThinkAction::RespondToGetter(tx, getter_id) => {
+                    if getter_id == self.channel_simulate_id {
+                        let _ = tx.send(Ok(Some(Value::new(Json(latest_simulation.clone())))));
+                        continue 'recv;
+                    }
                     for rule in &rules {
                         if getter_id == rule.channel_is_enabled_id {
                             let is_enabled = script_manager.is_enabled(&rule.script_id);
@@ -251,6 +324,24 @@ impl ThinkerbellAdapter {
                                 }
                             };
                             continue 'recv;
+                        } else if getter_id == rule.getter_last_execution_id {
+                            let log = script_manager.execution_log();
+                            let _ = tx.send(log.last_execution(&rule.script_id)
+                                .map(|event| {
+                                    let json = event.map_or(JSON::Null, |event| event.to_json());
+                                    Some(Value::new(Json(json)))
+                                })
+                                .map_err(sm_error));
+                            continue 'recv;
+                        } else if getter_id == rule.getter_execution_log_id {
+                            let log = script_manager.execution_log();
+                            let _ = tx.send(log.events(&rule.script_id)
+                                .map(|events| {
+                                    let json = events.iter().map(|event| event.to_json()).collect();
+                                    Some(Value::new(Json(JSON::Array(json))))
+                                })
+                                .map_err(sm_error));
+                            continue 'recv;
                         }
                     }
                     let _ = tx.send(Err(Error::Internal(InternalError::NoSuchChannel(getter_id.clone()))));
@@ -279,6 +370,22 @@ impl ThinkerbellAdapter {
                                 let _ = tx.send(Err(err));
                             }
                         }
+                    } else if setter_id == self.channel_simulate_id {
+                        // Simulate a rule, without storing or starting it.
+                        let result = value.cast::<RuleSource>().and_then(|rule_source| {
+                            let script = try!(Script::<UncheckedCtx>::from_str(&rule_source.source)
+                                .map_err(Error::Parsing));
+                            let name = script.name.clone();
+                            simulate(&self.env, script)
+                                .map(|simulation| simulation_as_json(&name, simulation))
+                                .map_err(|err| {
+                                    Error::Internal(InternalError::GenericError(format!("{:?}",
+                                                                                        err)))
+                                })
+                        });
+                        let _ = tx.send(result.map(|json| {
+                            latest_simulation = json;
+                        }));
                     } else {
                         // The rest of the rules are script/rule-specific.
                         // NOTE: This linear search is not ideal, but tracking getters/setters in maps
@@ -326,6 +433,9 @@ impl ThinkerbellAdapter {
             getter_source_id: Id::new(&format!("{}/source", service_id.as_atom())),
             channel_is_enabled_id: Id::new(&format!("{}/is-rule-enabled", service_id.as_atom())),
             setter_remove_id: Id::new(&format!("{}/remove", service_id.as_atom())),
+            getter_last_execution_id: Id::new(&format!("{}/last-execution",
+                                                       service_id.as_atom())),
+            getter_execution_log_id: Id::new(&format!("{}/execution-log", service_id.as_atom())),
         };
 
         try!(self.adapter_manager.add_service(Service::empty(&service_id, &self.adapter_id)));
@@ -360,6 +470,24 @@ impl ThinkerbellAdapter {
             adapter: self.adapter_id.clone(),
             ..Channel::default()
         }));
+
+        // Add getters for debugging the execution of this rule.
+        try!(self.adapter_manager.add_channel(Channel {
+            feature: self.feature_last_execution.clone(),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            id: rule.getter_last_execution_id.clone(),
+            service: service_id.clone(),
+            adapter: self.adapter_id.clone(),
+            ..Channel::default()
+        }));
+        try!(self.adapter_manager.add_channel(Channel {
+            feature: self.feature_execution_log.clone(),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            id: rule.getter_execution_log_id.clone(),
+            service: service_id.clone(),
+            adapter: self.adapter_id.clone(),
+            ..Channel::default()
+        }));
         info!("[thinkerbell@link.mozilla.org] Added Thinkerbell Rule for '{}'", &script_id.to_string());
 
         Ok(rule)
@@ -372,26 +500,40 @@ impl ThinkerbellAdapter {
     }
 
     /// Everything is initialized here, but the real work happens in the main() loop.
-    pub fn init(manager: &Arc<AdapterManager>, scripts_path: &str) -> Result<(), Error> {
+    pub fn init(manager: &Arc<AdapterManager>,
+                scripts_path: &str,
+                timeline: Timeline,
+                config: Arc<ConfigService>)
+                -> Result<(), Error> {
         let adapter_id = Id::new("thinkerbell@link.mozilla.org");
         let setter_add_rule_id = Id::new("thinkerbell-add-rule");
+        let channel_simulate_id = Id::new("thinkerbell-simulate-rule");
         let root_service_id = Id::new("thinkerbell-root-service");
         let feature_rule_on = Id::new("thinkerbell/is-rule-enabled");
         let feature_add_rule = Id::new("thinkerbell/add-rule");
+        let feature_simulate = Id::new("thinkerbell/simulate-rule");
         let feature_remove = Id::new("thinkerbell/remove-rule-id");
         let feature_source = Id::new("thinkerbell/rule-source");
+        let feature_last_execution = Id::new("thinkerbell/last-execution");
+        let feature_execution_log = Id::new("thinkerbell/execution-log");
 
 
+        config.register("thinkerbell", "loop_policy", ConfigType::String);
+        config.register("thinkerbell", "loop_window_ms", ConfigType::Integer);
+        config.register("thinkerbell", "loop_max_echoes", ConfigType::Integer);
+
         // Prepare the script execution environment and load existing scripts.
         let (tx_env, rx_env) = channel();
         let env = ThinkerbellExecutionEnv {
             adapter_manager: manager.clone(),
             timer: Arc::new(Mutex::new(timer::Timer::new())),
+            config: config,
         };
 
         let mut script_manager = try!(
-            ScriptManager::new(env, path::Path::new(scripts_path), Box::new(tx_env)).map_err(sm_error));
+            ScriptManager::new(env.clone(), path::Path::new(scripts_path), Box::new(tx_env)).map_err(sm_error));
 
+        let execution_log = script_manager.execution_log();
         let result_map = try!(script_manager.load().map_err(sm_error));
 
         let (tx, rx) = channel();
@@ -405,9 +547,13 @@ impl ThinkerbellAdapter {
             adapter_manager: manager.clone(),
             adapter_id: adapter_id.clone(),
             setter_add_rule_id: setter_add_rule_id.clone(),
+            channel_simulate_id: channel_simulate_id.clone(),
+            env: env,
             feature_rule_on: feature_rule_on,
             feature_source: feature_source,
             feature_remove: feature_remove,
+            feature_last_execution: feature_last_execution,
+            feature_execution_log: feature_execution_log,
         };
 
         // Add the adapter and the root service (the one that exposes `AddThinkerbellRule` for adding new rules).
@@ -416,25 +562,50 @@ impl ThinkerbellAdapter {
         try!(manager.add_service(Service::empty(&root_service_id, &adapter_id)));
         try!(manager.add_channel(Channel {
             feature: feature_add_rule,
-            supports_send: Some(Signature::accepts(Maybe::Required(rule_source_format))),
+            supports_send: Some(Signature::accepts(Maybe::Required(rule_source_format.clone()))),
             id: setter_add_rule_id,
             service: root_service_id.clone(),
             adapter: adapter_id.clone(),
             ..Channel::default()
         }));
+        try!(manager.add_channel(Channel {
+            feature: feature_simulate,
+            supports_send: Some(Signature::accepts(Maybe::Required(rule_source_format))),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            id: channel_simulate_id,
+            service: root_service_id.clone(),
+            adapter: adapter_id.clone(),
+            ..Channel::default()
+        }));
 
         thread::spawn(move || {
             info!("[thinkerbell@link.mozilla.org] Started Thinkerbell main thread.");
             adapter.main(rx, script_manager)
         });
 
-        // FIXME: We need to consume the events from the execution environment to prevent the
-        // queue from growing unboundedly, but right now we don't use these events.
+        // Consume the events from the execution environment, recording them in the execution
+        // log of each rule and the values sent by rules in the timeline.
         // FIXME: When a script stops due to an error, we should update our state accordingly.
         // (Right now we only update the state when the script is explicitly started/stopped.)
         thread::spawn(move || {
-            loop {
-                let _ = rx_env.recv();
+            for (script_id, event) in rx_env {
+                if let Err(err) = execution_log.record(&script_id, &event) {
+                    warn!("[thinkerbell@link.mozilla.org] Could not log event of rule {}: {:?}",
+                          script_id,
+                          err);
+                }
+                if let ExecutionEvent::Sent { rule_index, statement_index, otherwise, result } =
+                       event {
+                    let channels = result.iter()
+                        .filter(|&&(_, ref result)| result.is_ok())
+                        .map(|&(ref id, _)| id.to_string())
+                        .collect();
+                    let details = json_value!({ script: script_id.to_string(),
+                                                rule: rule_index,
+                                                statement: statement_index,
+                                                otherwise: otherwise });
+                    timeline.push(Entry::new(EntryKind::Rule, channels, None, details));
+                }
             }
         });
 
@@ -0,0 +1,138 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Countdowns, and the names of the timers, kept in `timers.json` in the profile directory.
+//!
+//! Only the names are kept: the countdowns that were running are lost when the box restarts.
+
+use serde_json;
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 &&
+    name.chars().all(|c| match c {
+        'a'...'z' | '0'...'9' | '-' => true,
+        _ => false,
+    })
+}
+
+pub fn load(path: &str) -> BTreeSet<String> {
+    let mut source = String::new();
+    if File::open(path).and_then(|mut file| file.read_to_string(&mut source)).is_err() {
+        return BTreeSet::new();
+    }
+    let names: Vec<String> = serde_json::from_str(&source).unwrap_or_else(|err| {
+        warn!("[timers] Ignoring invalid timers in {}: {}", path, err);
+        vec![]
+    });
+    names.into_iter().filter(|name| is_valid_name(name)).collect()
+}
+
+pub fn save(path: &str, names: &BTreeSet<String>) -> Result<(), String> {
+    let names: Vec<&String> = names.iter().collect();
+    let source = try!(serde_json::to_string(&names).map_err(|err| format!("{}", err)));
+    File::create(path)
+        .and_then(|mut file| file.write_all(source.as_bytes()))
+        .map_err(|err| format!("Could not write {}: {}", path, err))
+}
+
+/// The deadlines of the timers that are running.
+pub struct Countdowns {
+    deadlines: HashMap<String, Instant>,
+}
+
+impl Countdowns {
+    pub fn new() -> Self {
+        Countdowns { deadlines: HashMap::new() }
+    }
+
+    /// Start a timer, or restart it if it is already running.
+    pub fn start(&mut self, name: &str, duration: Duration, now: Instant) {
+        self.deadlines.insert(name.to_owned(), now + duration);
+    }
+
+    /// Stop a timer without expiring it. Returns `false` if it wasn't running.
+    pub fn cancel(&mut self, name: &str) -> bool {
+        self.deadlines.remove(name).is_some()
+    }
+
+    /// The time left before a timer expires, or `None` if it isn't running.
+    pub fn remaining(&self, name: &str, now: Instant) -> Option<Duration> {
+        self.deadlines.get(name).map(|deadline| if *deadline > now {
+            *deadline - now
+        } else {
+            Duration::from_secs(0)
+        })
+    }
+
+    /// Stop the timers whose deadline has passed, and return their names.
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<String> = self.deadlines
+            .iter()
+            .filter(|&(_, deadline)| *deadline <= now)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &expired {
+            self.deadlines.remove(name);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+describe! timers_countdown {
+    before_each {
+        use super::*;
+        use std::collections::BTreeSet;
+        use std::time::{Duration, Instant};
+        use tempdir::TempDir;
+    }
+
+    it "should only accept names fit for ids" {
+        assert!(is_valid_name("bathroom-fan"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("Bathroom fan"));
+        assert!(!is_valid_name("fan.timers@link.mozilla.org"));
+    }
+
+    it "should save and load names" {
+        let dir = TempDir::new("timers").unwrap();
+        let path = dir.path().join("timers.json");
+        let path = path.to_str().unwrap();
+        assert!(load(path).is_empty());
+
+        let mut names = BTreeSet::new();
+        names.insert("bathroom-fan".to_owned());
+        names.insert("porch-light".to_owned());
+        save(path, &names).unwrap();
+        assert_eq!(load(path), names);
+    }
+
+    it "should count down, restart, cancel and expire" {
+        let now = Instant::now();
+        let mut countdowns = Countdowns::new();
+        assert_eq!(countdowns.remaining("fan", now), None);
+
+        countdowns.start("fan", Duration::from_secs(600), now);
+        countdowns.start("light", Duration::from_secs(60), now);
+        assert_eq!(countdowns.remaining("fan", now + Duration::from_secs(100)),
+                   Some(Duration::from_secs(500)));
+        assert!(countdowns.expire(now + Duration::from_secs(30)).is_empty());
+
+        // Restarting pushes the deadline back.
+        countdowns.start("fan", Duration::from_secs(600), now + Duration::from_secs(300));
+        assert_eq!(countdowns.expire(now + Duration::from_secs(600)), vec!["light".to_owned()]);
+        assert_eq!(countdowns.remaining("light", now + Duration::from_secs(600)), None);
+        assert_eq!(countdowns.expire(now + Duration::from_secs(900)), vec!["fan".to_owned()]);
+
+        countdowns.start("fan", Duration::from_secs(600), now);
+        assert!(countdowns.cancel("fan"));
+        assert!(!countdowns.cancel("fan"));
+        assert!(countdowns.expire(now + Duration::from_secs(3600)).is_empty());
+    }
+}
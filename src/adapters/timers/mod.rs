@@ -0,0 +1,424 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Countdown timers, so that rules such as "turn off the bathroom fan 10 minutes after the
+//! light goes off" don't need to keep their own state.
+//!
+//! The `timers` service exposes:
+//! - `timer/create` (send), as a `String`: the name of a new timer, e.g. `bathroom-fan`;
+//! - `timer/delete` (send), as a `String`: the name of a timer to remove.
+//!
+//! Each timer is a service exposing:
+//! - `timer/start` (send), as a `Duration`: (re)start the countdown;
+//! - `timer/cancel` (send, no value): stop the countdown, without expiring the timer;
+//! - `timer/remaining` (fetch), as a `Duration`: the time left, `0` if the timer isn't running;
+//! - `timer/is-running` (fetch, watch), as `OnOff`;
+//! - `timer/expired` (watch, no value): notified each time the countdown reaches `0`.
+//!
+//! The names of the timers are kept in `timers.json`, in the profile directory.
+
+mod countdown;
+
+use self::countdown::Countdowns;
+
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{self, format, OnOff, Value};
+
+use chrono;
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static ADAPTER_NAME: &'static str = "Timers adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const TICK_MS: u64 = 250;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Kind {
+    Create,
+    Delete,
+    Start(String),
+    Cancel(String),
+    Remaining(String),
+    IsRunning(String),
+    Expired(String),
+}
+
+pub struct TimersAdapter {
+    manager: Arc<AdapterManager>,
+    names: Mutex<BTreeSet<String>>,
+    names_path: String,
+    countdowns: Arc<Mutex<Countdowns>>,
+    /// The kind of each channel.
+    channels: Mutex<HashMap<Id<Channel>, Kind>>,
+    watchers: ValueWatchers,
+}
+
+impl TimersAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("timers@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:timers@link.mozilla.org")
+    }
+
+    pub fn timer_service_id(name: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.timers@link.mozilla.org", name))
+    }
+
+    fn channel_id(kind: &Kind) -> Id<Channel> {
+        let (prefix, timer) = match *kind {
+            Kind::Create => return Id::new("setter:create.timers@link.mozilla.org"),
+            Kind::Delete => return Id::new("setter:delete.timers@link.mozilla.org"),
+            Kind::Start(ref name) => ("setter:start", name),
+            Kind::Cancel(ref name) => ("setter:cancel", name),
+            Kind::Remaining(ref name) => ("getter:remaining", name),
+            Kind::IsRunning(ref name) => ("getter:running", name),
+            Kind::Expired(ref name) => ("getter:expired", name),
+        };
+        Id::new(&format!("{}.{}.timers@link.mozilla.org", prefix, timer))
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let names_path = controller.get_profile().path_for("timers.json");
+        let countdowns = Arc::new(Mutex::new(Countdowns::new()));
+        let watchers = ValueWatchers::new();
+        let adapter = Arc::new(TimersAdapter {
+            manager: manager.clone(),
+            names: Mutex::new(countdown::load(&names_path)),
+            names_path: names_path,
+            countdowns: countdowns.clone(),
+            channels: Mutex::new(HashMap::new()),
+            watchers: watchers.clone(),
+        });
+        try!(manager.add_adapter(adapter.clone()));
+
+        let mut service = Service::empty(&Self::service_id(), &Self::id());
+        service.properties.insert("model".to_owned(), "Timers".to_owned());
+        try!(manager.add_service(service));
+        for (kind, feature) in vec![(Kind::Create, "timer/create"),
+                                    (Kind::Delete, "timer/delete")] {
+            let accepts_name = Signature::accepts(Maybe::Required(format::STRING.clone()));
+            try!(adapter.add_channel(&Self::service_id(),
+                                     kind,
+                                     feature,
+                                     Channel {
+                                         supports_send: Some(accepts_name),
+                                         ..Channel::default()
+                                     }));
+        }
+        let known: Vec<String> = adapter.names.lock().unwrap().iter().cloned().collect();
+        for name in known {
+            try!(adapter.add_timer(&name));
+        }
+
+        thread::Builder::new()
+            .name("Timers".to_owned())
+            .spawn(move || {
+                loop {
+                    thread::sleep(Duration::from_millis(TICK_MS));
+                    let expired = countdowns.lock().unwrap().expire(Instant::now());
+                    for name in expired {
+                        debug!("[timers] Timer {} expired", name);
+                        watchers.update(&Self::channel_id(&Kind::IsRunning(name.clone())),
+                                        Value::new(OnOff::Off));
+                        watchers.pulse(&Self::channel_id(&Kind::Expired(name)), Value::new(()));
+                    }
+                }
+            })
+            .unwrap();
+        Ok(())
+    }
+
+    fn add_channel(&self,
+                   service: &Id<ServiceId>,
+                   kind: Kind,
+                   feature: &str,
+                   template: Channel)
+                   -> Result<(), Error> {
+        let id = Self::channel_id(&kind);
+        try!(self.manager.add_channel(Channel {
+            id: id.clone(),
+            service: service.clone(),
+            adapter: Self::id(),
+            feature: Id::new(feature),
+            ..template
+        }));
+        self.channels.lock().unwrap().insert(id, kind);
+        Ok(())
+    }
+
+    /// Expose the service of a timer, along with all its channels.
+    fn add_timer(&self, name: &str) -> Result<(), Error> {
+        let service = Service::empty(&Self::timer_service_id(name), &Self::id());
+        let name = name.to_owned();
+        let duration = Maybe::Required(format::DURATION.clone());
+        let on_off = Maybe::Required(format::ON_OFF.clone());
+        let unit = Maybe::Required(format::UNIT.clone());
+        let channels = vec![(Kind::Start(name.clone()),
+                             "timer/start",
+                             Channel {
+                                 supports_send: Some(Signature::accepts(duration.clone())),
+                                 ..Channel::default()
+                             }),
+                            (Kind::Cancel(name.clone()),
+                             "timer/cancel",
+                             Channel {
+                                 supports_send: Some(Signature::nothing()),
+                                 ..Channel::default()
+                             }),
+                            (Kind::Remaining(name.clone()),
+                             "timer/remaining",
+                             Channel {
+                                 supports_fetch: Some(Signature::returns(duration)),
+                                 ..Channel::default()
+                             }),
+                            (Kind::IsRunning(name.clone()),
+                             "timer/is-running",
+                             Channel {
+                                 supports_fetch: Some(Signature::returns(on_off.clone())),
+                                 supports_watch: Some(Signature {
+                                     accepts: Maybe::Optional(format::ON_OFF.clone()),
+                                     returns: on_off,
+                                 }),
+                                 ..Channel::default()
+                             }),
+                            (Kind::Expired(name.clone()),
+                             "timer/expired",
+                             Channel {
+                                 supports_watch: Some(Signature::returns(unit)),
+                                 ..Channel::default()
+                             })];
+
+        let mut builder = ServiceBuilder::new(service)
+            .with_property("model", "Timer")
+            .with_property("name", &name);
+        for &(ref kind, feature, ref template) in &channels {
+            builder = builder.with_channel(Channel {
+                id: Self::channel_id(kind),
+                feature: Id::new(feature),
+                ..template.clone()
+            });
+        }
+        try!(self.manager.add_service_with_channels(builder));
+
+        {
+            let mut known = self.channels.lock().unwrap();
+            for (kind, _, _) in channels {
+                known.insert(Self::channel_id(&kind), kind);
+            }
+        }
+        self.watchers.update(&Self::channel_id(&Kind::IsRunning(name)), Value::new(OnOff::Off));
+        Ok(())
+    }
+
+    fn save_names(&self, names: &BTreeSet<String>) -> Result<(), Error> {
+        countdown::save(&self.names_path, names)
+            .map_err(|err| Error::Internal(InternalError::GenericError(err)))
+    }
+
+    fn create(&self, name: &str) -> Result<(), Error> {
+        if !countdown::is_valid_name(name) {
+            return Err(Error::InvalidValue);
+        }
+        {
+            let mut names = self.names.lock().unwrap();
+            if !names.insert(name.to_owned()) {
+                // Creating a timer that already exists does nothing.
+                return Ok(());
+            }
+            try!(self.save_names(&names));
+        }
+        info!("[timers] Created timer {}", name);
+        self.add_timer(name)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), Error> {
+        {
+            let mut names = self.names.lock().unwrap();
+            if !names.remove(name) {
+                return Err(Error::InvalidValue);
+            }
+            try!(self.save_names(&names));
+        }
+        info!("[timers] Deleted timer {}", name);
+        self.countdowns.lock().unwrap().cancel(name);
+        {
+            let mut channels = self.channels.lock().unwrap();
+            for kind in vec![Kind::Start(name.to_owned()),
+                             Kind::Cancel(name.to_owned()),
+                             Kind::Remaining(name.to_owned()),
+                             Kind::IsRunning(name.to_owned()),
+                             Kind::Expired(name.to_owned())] {
+                channels.remove(&Self::channel_id(&kind));
+            }
+        }
+        self.manager.remove_service(&Self::timer_service_id(name))
+    }
+
+    fn start(&self, name: &str, value: &Value) -> Result<(), Error> {
+        let duration = try!(value.cast::<values::Duration>()).as_duration();
+        if duration < chrono::Duration::zero() {
+            return Err(Error::InvalidValue);
+        }
+        let duration = Duration::from_millis(duration.num_milliseconds() as u64);
+        self.countdowns.lock().unwrap().start(name, duration, Instant::now());
+        self.watchers.update(&Self::channel_id(&Kind::IsRunning(name.to_owned())),
+                             Value::new(OnOff::On));
+        Ok(())
+    }
+
+    fn cancel(&self, name: &str) {
+        if self.countdowns.lock().unwrap().cancel(name) {
+            self.watchers.update(&Self::channel_id(&Kind::IsRunning(name.to_owned())),
+                                 Value::new(OnOff::Off));
+        }
+    }
+}
+
+impl Adapter for TimersAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                let kind = self.channels.lock().unwrap().get(&id).cloned();
+                let result = match kind {
+                    Some(Kind::Remaining(name)) => {
+                        let remaining = self.countdowns
+                            .lock()
+                            .unwrap()
+                            .remaining(&name, Instant::now())
+                            .unwrap_or(Duration::from_secs(0));
+                        let millis = remaining.as_secs() * 1000 +
+                                     (remaining.subsec_nanos() / 1_000_000) as u64;
+                        let remaining = chrono::Duration::milliseconds(millis as i64);
+                        Ok(Some(Value::new(values::Duration::from(remaining))))
+                    }
+                    Some(Kind::IsRunning(_)) => Ok(self.watchers.latest(&id)),
+                    Some(_) => Err(Error::OperationNotSupported(Operation::Fetch, id.clone())),
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                let kind = self.channels.lock().unwrap().get(&id).cloned();
+                let result = match kind {
+                    Some(Kind::Create) => {
+                        value.cast::<String>().and_then(|name| self.create(name))
+                    }
+                    Some(Kind::Delete) => {
+                        value.cast::<String>().and_then(|name| self.delete(name))
+                    }
+                    Some(Kind::Start(name)) => self.start(&name, &value),
+                    Some(Kind::Cancel(name)) => {
+                        self.cancel(&name);
+                        Ok(())
+                    }
+                    Some(_) => Err(Error::OperationNotSupported(Operation::Send, id.clone())),
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
+
+#[cfg(test)]
+describe! timers_adapter {
+    before_each {
+        use super::*;
+        use foxbox_taxonomy::selector::ServiceSelector;
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        TimersAdapter::init(&harness.manager, harness.controller.clone()).unwrap();
+    }
+
+    it "should create, run, cancel and delete timers" {
+        let create = r#"[{"select": [{"feature": "timer/create"}], "value": "bathroom-fan"}]"#;
+        let (status, _) = harness.request(Method::Put, "/api/v1/channels/set", create, false);
+        assert_eq!(status, Status::Ok);
+        let (_, json) = harness.request_json(Method::Get, "/api/v1/services", "", false);
+        let timers = json.as_array()
+            .unwrap()
+            .iter()
+            .filter(|service| {
+                service.lookup("properties.model").and_then(|model| model.as_string()) ==
+                Some("Timer")
+            })
+            .count();
+        assert_eq!(timers, 1);
+
+        let start = r#"[{"select": [{"feature": "timer/start"}], "value": 600}]"#;
+        let (status, _) = harness.request(Method::Put, "/api/v1/channels/set", start, false);
+        assert_eq!(status, Status::Ok);
+        let running = r#"[{"feature": "timer/is-running"}]"#;
+        let (_, json) = harness.request_json(Method::Put, "/api/v1/channels/get", running, false);
+        assert_eq!(json.find("getter:running.bathroom-fan.timers@link.mozilla.org")
+                       .and_then(|value| value.as_string()),
+                   Some("On"));
+        let remaining = r#"[{"feature": "timer/remaining"}]"#;
+        let (_, json) = harness.request_json(Method::Put, "/api/v1/channels/get", remaining, false);
+        let remaining = json.find("getter:remaining.bathroom-fan.timers@link.mozilla.org")
+            .and_then(|value| value.as_f64())
+            .unwrap();
+        assert!(remaining > 590. && remaining <= 600.);
+
+        let cancel = r#"[{"select": [{"feature": "timer/cancel"}], "value": null}]"#;
+        harness.request(Method::Put, "/api/v1/channels/set", cancel, false);
+        let (_, json) = harness.request_json(Method::Put, "/api/v1/channels/get", running, false);
+        assert_eq!(json.find("getter:running.bathroom-fan.timers@link.mozilla.org")
+                       .and_then(|value| value.as_string()),
+                   Some("Off"));
+
+        let delete = r#"[{"select": [{"feature": "timer/delete"}], "value": "bathroom-fan"}]"#;
+        harness.request(Method::Put, "/api/v1/channels/set", delete, false);
+        assert!(harness.manager
+            .get_services(vec![ServiceSelector::new()
+                                   .with_id(&TimersAdapter::timer_service_id("bathroom-fan"))])
+            .is_empty());
+    }
+}
@@ -2,9 +2,15 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use adapters::tts::speech::Voice;
+
 /// Simple trait to abstract the TTS engine implementation.
 pub trait TtsEngine: Send + Sync {
     fn init(&self) -> bool;
     fn shutdown(&self);
-    fn say(&self, text: &str);
+    /// Speak a text with `voice`, returning once it has been spoken or cancelled. The fields
+    /// of `voice` that are not set use the defaults of the engine.
+    fn say(&self, text: &str, voice: &Voice);
+    /// Interrupt the text being spoken, if any. Called from another thread than `say`.
+    fn cancel(&self);
 }
@@ -5,7 +5,9 @@
 extern crate libc;
 
 use adapters::tts::engine::TtsEngine;
+use adapters::tts::speech::Voice;
 use libc::{c_int, c_char, c_void, size_t, c_uint};
+use std::sync::Mutex;
 
 /// Basic espeak bindings.
 
@@ -37,6 +39,26 @@ pub enum espeak_ERROR {
     EE_NOT_FOUND = 2,
 }
 
+#[repr(C)]
+#[allow(dead_code, non_camel_case_types)]
+pub enum espeak_PARAMETER {
+    espeakSILENCE = 0,
+    espeakRATE,
+    espeakVOLUME,
+    espeakPITCH,
+    espeakRANGE,
+    espeakPUNCTUATION,
+    espeakCAPITALS,
+    espeakWORDGAP,
+}
+
+/// The voice used when no language is requested.
+const DEFAULT_VOICE: &'static str = "default";
+/// The default rate of eSpeak, in words per minute.
+const DEFAULT_RATE: u64 = 175;
+/// The default volume of eSpeak, as a percentage of its normal volume.
+const DEFAULT_VOLUME: u8 = 100;
+
 #[link(name = "espeak")]
 #[allow(dead_code)]
 extern "C" {
@@ -54,10 +76,60 @@ extern "C" {
                         unique_identifier: *mut c_uint,
                         user_data: *mut c_void)
                         -> espeak_ERROR;
+    pub fn espeak_SetVoiceByName(name: *const c_char) -> espeak_ERROR;
+    pub fn espeak_SetParameter(parameter: espeak_PARAMETER,
+                               value: c_int,
+                               relative: c_int)
+                               -> espeak_ERROR;
+    pub fn espeak_Cancel() -> espeak_ERROR;
     pub fn espeak_Terminate() -> espeak_ERROR;
 }
 
-pub struct EspeakEngine;
+pub struct EspeakEngine {
+    /// The name of the voice currently loaded, e.g. "fr+f3", as loading a voice is slow.
+    voice: Mutex<Option<String>>,
+}
+
+impl EspeakEngine {
+    pub fn new() -> Self {
+        EspeakEngine { voice: Mutex::new(None) }
+    }
+
+    /// Switch to the language and variant of `voice`, then set its rate and volume.
+    fn set_voice(&self, voice: &Voice) {
+        use std::ffi::CString;
+
+        let mut name = voice.language.clone().unwrap_or_else(|| DEFAULT_VOICE.to_owned());
+        if let Some(ref variant) = voice.voice {
+            name = format!("{}+{}", name, variant);
+        }
+        let mut current = self.voice.lock().unwrap();
+        if current.as_ref() != Some(&name) {
+            let loaded = match CString::new(name.clone()) {
+                Ok(c_name) => {
+                    match unsafe { espeak_SetVoiceByName(c_name.as_ptr()) } {
+                        espeak_ERROR::EE_OK => true,
+                        _ => false,
+                    }
+                }
+                Err(_) => false,
+            };
+            if loaded {
+                *current = Some(name);
+            } else {
+                warn!("Unknown eSpeak voice {:?}, keeping the current one", name);
+            }
+        }
+        unsafe {
+            espeak_SetParameter(espeak_PARAMETER::espeakRATE,
+                                voice.rate.unwrap_or(DEFAULT_RATE) as c_int,
+                                0);
+            espeak_SetParameter(espeak_PARAMETER::espeakVOLUME,
+                                voice.volume.unwrap_or(DEFAULT_VOLUME) as c_int,
+                                0);
+        }
+    }
+}
 
 impl TtsEngine for EspeakEngine {
     fn init(&self) -> bool {
@@ -65,7 +137,9 @@ impl TtsEngine for EspeakEngine {
 
         let res;
         unsafe {
-            res = espeak_Initialize(espeak_AUDIO_OUTPUT::AUDIO_OUTPUT_PLAYBACK,
+            // Synthesis blocks until the text is played, so that texts are spoken one at a
+            // time.
+            res = espeak_Initialize(espeak_AUDIO_OUTPUT::AUDIO_OUTPUT_SYNCH_PLAYBACK,
                                     0, // Buffer length. 0 == 200ms
                                     ptr::null(), // eSpeak-data dir
                                     0 /* Options. */);
@@ -73,27 +147,36 @@ impl TtsEngine for EspeakEngine {
         res != -1
     }
 
-    fn say(&self, text: &str) {
+    fn say(&self, text: &str, voice: &Voice) {
         use std::ffi::CString;
         use std::ptr;
-        use std::thread;
 
-        let text = String::from(text);
         let len = text.len();
-        let s = CString::new(text.clone()).unwrap();
-
-        thread::spawn(move || {
-            unsafe {
-                espeak_Synth(s.as_ptr() as *const libc::c_void, // Sentence to speak.
-                             len + 1, // Size in bytes of the sentence. Not used in synchronous mode.
-                             0, // Start position.
-                             espeak_POSITION_TYPE::POS_CHARACTER, // Position type.
-                             0, // End position.
-                             ESPEAK_CHARS_UTF8, // Flags.
-                             ptr::null_mut(), // Unique id.
-                             ptr::null_mut() /* Opaque user data. */);
+        let s = match CString::new(text) {
+            Ok(s) => s,
+            Err(_) => {
+                warn!("Not speaking a text containing a nul byte");
+                return;
             }
-        });
+        };
+
+        self.set_voice(voice);
+        unsafe {
+            espeak_Synth(s.as_ptr() as *const libc::c_void, // Sentence to speak.
+                         len + 1, // Size in bytes of the sentence. Not used in synchronous mode.
+                         0, // Start position.
+                         espeak_POSITION_TYPE::POS_CHARACTER, // Position type.
+                         0, // End position.
+                         ESPEAK_CHARS_UTF8, // Flags.
+                         ptr::null_mut(), // Unique id.
+                         ptr::null_mut() /* Opaque user data. */);
+        }
+    }
+
+    fn cancel(&self) {
+        unsafe {
+            espeak_Cancel();
+        }
     }
 
     fn shutdown(&self) {
@@ -6,20 +6,38 @@
 /// Example cUrl request:
 /// curl -X PUT -d '[[[{"id":"setter:talk@link.mozilla.org"}], {"String": "hello world"}]]' http://localhost:3000/api/v1/channels/set
 ///
+/// Sentences are queued and spoken one at a time, see `queue`. `speak/announcement` accepts a
+/// priority, e.g. `{"Json": {"text": "Smoke detected", "priority": "high"}}`.
+///
+/// Both channels accept a language, a voice, a rate and a volume along with the text, e.g.
+/// `{"text": "Bonjour", "language": "fr"}`, see `speech::Speech`. Unset fields use the defaults
+/// of the user, from the config.
+///
 
+use foxbox_core::config_store::ConfigService;
 use foxbox_taxonomy::adapter::*;
 use foxbox_taxonomy::manager::AdapterManager;
-use foxbox_taxonomy::api::{Error, InternalError, User};
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
 use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::io::Format;
+use foxbox_taxonomy::parse::Path;
 use foxbox_taxonomy::services::{AdapterId, Id, Service, ServiceId};
 use foxbox_taxonomy::util::Maybe;
-use foxbox_taxonomy::values::{format, Value};
+use foxbox_taxonomy::values::{format, Json, Value};
+use serde_json::value::Value as JSON;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 pub mod engine;
 pub use self::engine::TtsEngine;
 
+mod queue;
+use self::queue::{AnnouncementQueue, Priority};
+
+pub mod speech;
+use self::speech::{Speech, Voice};
+
 // eSpeak is the only engine supported for now.
 mod espeak;
 use self::espeak::EspeakEngine;
@@ -31,10 +49,38 @@ static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
 
 pub struct TtsAdapter<T> {
     talk_setter_id: Id<Channel>,
-    engine: T,
+    announce_setter_id: Id<Channel>,
+    queue_length_getter_id: Id<Channel>,
+    queue: AnnouncementQueue<T>,
+    /// Where the default voice of each user is set, see `Voice::defaults_for`.
+    config: Arc<ConfigService>,
+}
+
+/// Parse an announcement sent as JSON `{"text": string, "priority": "low"|"normal"|"high"}`,
+/// with the optional fields of a `Voice`. The priority defaults to `normal`.
+fn parse_announcement(value: &Value) -> Result<(Speech, Priority), Error> {
+    let json = &try!(value.cast::<Json>()).0;
+    let text = match json.find("text").and_then(JSON::as_string) {
+        Some(text) => text,
+        None => return Err(Error::InvalidValue),
+    };
+    let priority = match json.find("priority") {
+        None => Priority::Normal,
+        Some(priority) => {
+            match priority.as_string().and_then(Priority::parse) {
+                Some(priority) => priority,
+                None => return Err(Error::InvalidValue),
+            }
+        }
+    };
+    let speech = Speech {
+        text: text.to_owned(),
+        voice: try!(Voice::parse(Path::new(), json)),
+    };
+    Ok((speech, priority))
 }
 
-impl<T: TtsEngine> Adapter for TtsAdapter<T> {
+impl<T: TtsEngine + 'static> Adapter for TtsAdapter<T> {
     fn id(&self) -> Id<AdapterId> {
         adapter_id!(ADAPTER_ID)
     }
@@ -56,35 +102,62 @@ impl<T: TtsEngine> Adapter for TtsAdapter<T> {
                     _: User)
                     -> ResultMap<Id<Channel>, Option<Value>, Error> {
         set.drain(..)
-            .map(|id| (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id)))))
+            .map(|id| {
+                if id == self.queue_length_getter_id {
+                    let length = JSON::U64(self.queue.pending() as u64);
+                    return (id, Ok(Some(Value::new(Json(length)))));
+                }
+                (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+            })
             .collect()
     }
 
     fn send_values(&self,
                    mut values: HashMap<Id<Channel>, Value>,
-                   _: User)
+                   user: User)
                    -> ResultMap<Id<Channel>, (), Error> {
-        use core::ops::Deref;
-
+        let defaults = Voice::defaults_for(&self.config, &user);
+        let with_defaults = |speech: Speech| {
+            Speech {
+                voice: speech.voice.or(&defaults),
+                ..speech
+            }
+        };
         values.drain()
             .map(|(id, value)| {
                 if id == self.talk_setter_id {
-                    match value.cast::<String>() {
-                        Ok(text) => {
-                            self.engine.say(text.deref());
+                    match value.cast::<Speech>() {
+                        Ok(speech) => {
+                            self.queue.push(with_defaults(speech.clone()), Priority::Normal);
                             return (id, Ok(()));
                         }
                         Err(err) => return (id, Err(err)),
                     }
                 }
+                if id == self.announce_setter_id {
+                    return match parse_announcement(&value) {
+                        Ok((speech, priority)) => {
+                            self.queue.push(with_defaults(speech), priority);
+                            (id, Ok(()))
+                        }
+                        Err(err) => (id, Err(err)),
+                    };
+                }
+                if id == self.queue_length_getter_id {
+                    return (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id)));
+                }
                 (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
             })
             .collect()
     }
 }
 
-pub fn init(adapt: &Arc<AdapterManager>) -> Result<(), Error> {
-    let engine = EspeakEngine {};
+/// Start the adapter. Identical announcements sent within `coalesce_window` are spoken once.
+pub fn init(adapt: &Arc<AdapterManager>,
+            coalesce_window: Duration,
+            config: Arc<ConfigService>)
+            -> Result<(), Error> {
+    let engine = EspeakEngine::new();
     if !engine.init() {
         warn!("eSpeak initialization failed!");
         return Err(Error::Internal(InternalError::GenericError("eSpeak initialization failed!"
@@ -92,20 +165,69 @@ pub fn init(adapt: &Arc<AdapterManager>) -> Result<(), Error> {
     }
 
     let talk_setter_id = Id::new("setter:talk@link.mozilla.org");
+    let announce_setter_id = Id::new("setter:announce@link.mozilla.org");
+    let queue_length_getter_id = Id::new("getter:queue-length@link.mozilla.org");
     try!(adapt.add_adapter(Arc::new(TtsAdapter {
         talk_setter_id: talk_setter_id.clone(),
-        engine: engine,
+        announce_setter_id: announce_setter_id.clone(),
+        queue_length_getter_id: queue_length_getter_id.clone(),
+        queue: AnnouncementQueue::new(engine, coalesce_window),
+        config: config,
     })));
     let service_id = service_id!("espeak@link.mozilla.org");
     let adapter_id = adapter_id!(ADAPTER_ID);
     try!(adapt.add_service(Service::empty(&service_id, &adapter_id)));
     try!(adapt.add_channel(Channel {
         feature: Id::new("speak/sentence"),
-        supports_send: Some(Signature::accepts(Maybe::Required(format::STRING.clone()))),
+        supports_send:
+            Some(Signature::accepts(Maybe::Required(Arc::new(Format::new::<Speech>())))),
         id: talk_setter_id,
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        ..Channel::default()
+    }));
+    try!(adapt.add_channel(Channel {
+        feature: Id::new("speak/announcement"),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::JSON.clone()))),
+        id: announce_setter_id,
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        ..Channel::default()
+    }));
+    try!(adapt.add_channel(Channel {
+        feature: Id::new("speak/queue-length"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+        id: queue_length_getter_id,
         service: service_id,
         adapter: adapter_id,
         ..Channel::default()
     }));
     Ok(())
 }
+
+#[cfg(test)]
+describe! tts {
+    before_each {
+        use super::parse_announcement;
+        use super::queue::Priority;
+        use foxbox_taxonomy::values::{Json, Value};
+        use serde_json;
+    }
+
+    it "should parse announcements" {
+        let parse = |source: &str| {
+            parse_announcement(&Value::new(Json(serde_json::from_str(source).unwrap()))).ok()
+        };
+        let text = |source: &str| parse(source).map(|(speech, priority)| (speech.text, priority));
+        assert_eq!(text(r#"{"text": "Dinner is ready"}"#),
+                   Some(("Dinner is ready".to_owned(), Priority::Normal)));
+        assert_eq!(text(r#"{"text": "Smoke detected", "priority": "high"}"#),
+                   Some(("Smoke detected".to_owned(), Priority::High)));
+        assert_eq!(text(r#"{"text": "Hi", "priority": "urgent"}"#), None);
+        assert_eq!(text(r#"{"priority": "low"}"#), None);
+        assert_eq!(parse(r#"{"text": "Achtung", "language": "de"}"#)
+                       .and_then(|(speech, _)| speech.voice.language),
+                   Some("de".to_owned()));
+        assert_eq!(text(r#"{"text": "Hi", "volume": "loud"}"#), None);
+    }
+}
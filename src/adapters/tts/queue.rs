@@ -0,0 +1,244 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Announcements are spoken one at a time, by a single thread, so that concurrent sends don't
+//! produce garbled audio.
+//!
+//! - announcements are spoken by decreasing priority, then in the order they were queued;
+//! - an announcement with a higher priority than the one being spoken interrupts it, the
+//!   interrupted announcement is then spoken again from the start;
+//! - an announcement identical to one queued, being spoken, or spoken less than `window`
+//!   ago is dropped.
+
+use adapters::tts::engine::TtsEngine;
+use adapters::tts::speech::Speech;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn parse(source: &str) -> Option<Self> {
+        match source {
+            "low" => Some(Priority::Low),
+            "normal" => Some(Priority::Normal),
+            "high" => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Announcement {
+    speech: Speech,
+    priority: Priority,
+}
+
+#[derive(Default)]
+struct State {
+    /// Sorted by decreasing priority, then by age.
+    queued: Vec<Announcement>,
+    speaking: Option<Announcement>,
+    /// Whether the announcement being spoken was interrupted.
+    interrupted: bool,
+    /// The texts spoken recently, oldest first.
+    recent: VecDeque<(String, Instant)>,
+}
+
+impl State {
+    fn is_duplicate(&self, text: &str, window: Duration) -> bool {
+        self.speaking.as_ref().map_or(false, |speaking| speaking.speech.text == text) ||
+        self.queued.iter().any(|queued| queued.speech.text == text) ||
+        self.recent.iter().any(|&(ref recent, at)| recent == text && at.elapsed() < window)
+    }
+
+    fn enqueue(&mut self, announcement: Announcement) {
+        let position = self.queued
+            .iter()
+            .position(|queued| queued.priority < announcement.priority)
+            .unwrap_or(self.queued.len());
+        self.queued.insert(position, announcement);
+    }
+}
+
+pub struct AnnouncementQueue<T> {
+    engine: Arc<T>,
+    state: Arc<(Mutex<State>, Condvar)>,
+    window: Duration,
+}
+
+impl<T: TtsEngine + 'static> AnnouncementQueue<T> {
+    /// Start the thread speaking the announcements. Duplicates within `window` are dropped.
+    pub fn new(engine: T, window: Duration) -> Self {
+        let queue = AnnouncementQueue {
+            engine: Arc::new(engine),
+            state: Arc::new((Mutex::new(State::default()), Condvar::new())),
+            window: window,
+        };
+        let engine = queue.engine.clone();
+        let state = queue.state.clone();
+        thread::Builder::new()
+            .name("TtsQueue".to_owned())
+            .spawn(move || Self::speak_all(&*engine, &state))
+            .unwrap();
+        queue
+    }
+
+    fn speak_all(engine: &T, state: &(Mutex<State>, Condvar)) {
+        let (ref lock, ref available) = *state;
+        loop {
+            let announcement = {
+                let mut state = lock.lock().unwrap();
+                while state.queued.is_empty() {
+                    state = available.wait(state).unwrap();
+                }
+                let announcement = state.queued.remove(0);
+                state.speaking = Some(announcement.clone());
+                state.interrupted = false;
+                announcement
+            };
+
+            engine.say(&announcement.speech.text, &announcement.speech.voice);
+
+            let mut state = lock.lock().unwrap();
+            state.speaking = None;
+            if state.interrupted {
+                state.enqueue(announcement);
+                continue;
+            }
+            state.recent.push_back((announcement.speech.text, Instant::now()));
+        }
+    }
+
+    /// Queue an announcement. Returns `false` if it was dropped as a duplicate.
+    pub fn push(&self, speech: Speech, priority: Priority) -> bool {
+        let (ref lock, ref available) = *self.state;
+        let interrupt = {
+            let mut state = lock.lock().unwrap();
+            let window = self.window;
+            while state.recent.front().map_or(false, |&(_, at)| at.elapsed() >= window) {
+                state.recent.pop_front();
+            }
+            if state.is_duplicate(&speech.text, window) {
+                debug!("Dropping duplicate announcement {:?}", speech.text);
+                return false;
+            }
+            state.enqueue(Announcement {
+                speech: speech,
+                priority: priority,
+            });
+            let interrupt = !state.interrupted &&
+                            state.speaking
+                .as_ref()
+                .map_or(false, |speaking| speaking.priority < priority);
+            if interrupt {
+                state.interrupted = true;
+            }
+            interrupt
+        };
+        available.notify_one();
+        if interrupt {
+            self.engine.cancel();
+        }
+        true
+    }
+
+    /// The number of announcements queued or being spoken.
+    pub fn pending(&self) -> usize {
+        let state = self.state.0.lock().unwrap();
+        state.queued.len() + if state.speaking.is_some() { 1 } else { 0 }
+    }
+}
+
+#[cfg(test)]
+describe! tts_queue {
+    before_each {
+        use super::*;
+        use adapters::tts::engine::TtsEngine;
+        use adapters::tts::speech::{Speech, Voice};
+        use std::sync::{Arc, Mutex};
+        use std::sync::mpsc::{channel, Receiver, Sender};
+        use std::thread;
+        use std::time::Duration;
+
+        /// Records what it says, and blocks until allowed to finish or cancelled.
+        struct FakeEngine {
+            said: Arc<Mutex<Vec<String>>>,
+            done_tx: Mutex<Sender<()>>,
+            done_rx: Mutex<Receiver<()>>,
+        }
+        impl TtsEngine for FakeEngine {
+            fn init(&self) -> bool {
+                true
+            }
+            fn shutdown(&self) {}
+            fn say(&self, text: &str, _: &Voice) {
+                self.said.lock().unwrap().push(text.to_owned());
+                self.done_rx.lock().unwrap().recv().unwrap();
+            }
+            fn cancel(&self) {
+                self.done_tx.lock().unwrap().send(()).unwrap();
+            }
+        }
+
+        let said = Arc::new(Mutex::new(vec![]));
+        let (done_tx, done_rx) = channel();
+        let finish = done_tx.clone();
+        let queue = AnnouncementQueue::new(FakeEngine {
+                                               said: said.clone(),
+                                               done_tx: Mutex::new(done_tx),
+                                               done_rx: Mutex::new(done_rx),
+                                           },
+                                           Duration::from_secs(60));
+        let speech = |text: &str| {
+            Speech {
+                text: text.to_owned(),
+                voice: Voice::default(),
+            }
+        };
+        let wait_for = |count: usize| {
+            while said.lock().unwrap().len() < count {
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+    }
+
+    it "should speak by priority and drop duplicates" {
+        assert!(queue.push(speech("first"), Priority::Normal));
+        wait_for(1);
+        assert!(queue.push(speech("later"), Priority::Low));
+        assert!(queue.push(speech("sooner"), Priority::Normal));
+        assert!(!queue.push(speech("sooner"), Priority::Normal));
+        assert!(!queue.push(speech("first"), Priority::Low));
+        assert_eq!(queue.pending(), 3);
+
+        for _ in 0..3 {
+            finish.send(()).unwrap();
+        }
+        wait_for(3);
+        assert_eq!(*said.lock().unwrap(), vec!["first", "sooner", "later"]);
+
+        // Spoken recently.
+        assert!(!queue.push(speech("first"), Priority::Normal));
+    }
+
+    it "should interrupt announcements of a lower priority" {
+        assert!(queue.push(speech("weather"), Priority::Low));
+        wait_for(1);
+        assert!(queue.push(speech("smoke detected"), Priority::High));
+        wait_for(2);
+        finish.send(()).unwrap();
+        wait_for(3);
+        finish.send(()).unwrap();
+        assert_eq!(*said.lock().unwrap(), vec!["weather", "smoke detected", "weather"]);
+    }
+}
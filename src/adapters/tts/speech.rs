@@ -0,0 +1,198 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! What to say, and how to say it.
+
+use foxbox_core::config_store::ConfigService;
+use foxbox_taxonomy::api::{Error, User};
+use foxbox_taxonomy::io::{BinarySource, BinaryTarget};
+use foxbox_taxonomy::parse::*;
+use foxbox_taxonomy::values::Data;
+
+/// How to speak a text. Fields left unset fall back to the defaults of the user, then to
+/// those of the engine.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Voice {
+    /// A language, e.g. "en", "fr" or "pt-br".
+    pub language: Option<String>,
+    /// A variant of the voice, as understood by the engine, e.g. "f3" for eSpeak.
+    pub voice: Option<String>,
+    /// In words per minute.
+    pub rate: Option<u64>,
+    /// From 0 (silent) to 100.
+    pub volume: Option<u8>,
+}
+
+/// The range of rates accepted, in words per minute.
+const MIN_RATE: u64 = 80;
+const MAX_RATE: u64 = 450;
+
+impl Voice {
+    /// Read the optional fields `language`, `voice`, `rate` and `volume` of a JSON object.
+    pub fn parse(path: Path, source: &JSON) -> Result<Self, Error> {
+        macro_rules! take_opt {
+            ($type:ty, $name:expr) => (
+                match path.push($name, |path| <$type>::take_opt(path, source, $name)) {
+                    Some(Err(err)) => return Err(Error::Parsing(err)),
+                    Some(Ok(value)) => Some(value),
+                    None => None,
+                }
+            )
+        }
+        let voice = Voice {
+            language: take_opt!(String, "language"),
+            voice: take_opt!(String, "voice"),
+            rate: take_opt!(u64, "rate"),
+            volume: take_opt!(u8, "volume"),
+        };
+        if voice.rate.map_or(false, |rate| rate < MIN_RATE || rate > MAX_RATE) ||
+           voice.volume.map_or(false, |volume| volume > 100) {
+            return Err(Error::InvalidValue);
+        }
+        Ok(voice)
+    }
+
+    /// The defaults of `user`, as set in the config, namespace `tts`: keys `language`, `voice`,
+    /// `rate` and `volume` for everybody, overridden for a user by keys prefixed with
+    /// `user.<id>.`, e.g. `user.1.language`.
+    pub fn defaults_for(config: &ConfigService, user: &User) -> Self {
+        let get = |key: &str| {
+            let own = match *user {
+                User::Id(ref id) => config.get("tts", &format!("user.{}.{}", id, key)),
+                User::None => None,
+            };
+            own.or_else(|| config.get("tts", key)).and_then(|value| {
+                if value.is_empty() { None } else { Some(value) }
+            })
+        };
+        Voice {
+            language: get("language"),
+            voice: get("voice"),
+            rate: get("rate").and_then(|rate| rate.parse().ok()),
+            volume: get("volume").and_then(|volume| volume.parse().ok()),
+        }
+    }
+
+    /// Complete the fields left unset with those of `defaults`.
+    pub fn or(self, defaults: &Voice) -> Self {
+        Voice {
+            language: self.language.or_else(|| defaults.language.clone()),
+            voice: self.voice.or_else(|| defaults.voice.clone()),
+            rate: self.rate.or(defaults.rate),
+            volume: self.volume.or(defaults.volume),
+        }
+    }
+
+    fn fields(&self) -> Vec<(&'static str, JSON)> {
+        let mut fields = vec![];
+        if let Some(ref language) = self.language {
+            fields.push(("language", language.to_json()));
+        }
+        if let Some(ref voice) = self.voice {
+            fields.push(("voice", voice.to_json()));
+        }
+        if let Some(rate) = self.rate {
+            fields.push(("rate", JSON::U64(rate)));
+        }
+        if let Some(volume) = self.volume {
+            fields.push(("volume", JSON::U64(volume as u64)));
+        }
+        fields
+    }
+}
+
+/// A text to speak, as accepted by channel `speak/sentence`.
+///
+/// # JSON
+///
+/// Either a string, spoken with the defaults of the user, or an object
+/// `{"text": string, "language"?: string, "voice"?: string, "rate"?: number,
+/// "volume"?: number}`, e.g. `{"text": "Bonjour", "language": "fr"}`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Speech {
+    pub text: String,
+    pub voice: Voice,
+}
+
+impl Data for Speech {
+    fn description() -> String {
+        "Speech".to_owned()
+    }
+    fn parse(path: Path, source: &JSON, binary: &BinarySource) -> Result<Self, Error> {
+        if let JSON::String(ref text) = *source {
+            return Ok(Speech {
+                text: text.clone(),
+                voice: Voice::default(),
+            });
+        }
+        let text =
+            try!(path.push("text", |path| String::parse_field(path, source, binary, "text")));
+        Ok(Speech {
+            text: text,
+            voice: try!(Voice::parse(path, source)),
+        })
+    }
+    fn serialize(source: &Self, _: &BinaryTarget) -> Result<JSON, Error> {
+        let mut fields = source.voice.fields();
+        if fields.is_empty() {
+            return Ok(source.text.to_json());
+        }
+        fields.insert(0, ("text", source.text.to_json()));
+        Ok(fields.to_json())
+    }
+}
+
+#[cfg(test)]
+describe! tts_speech {
+    before_each {
+        use super::*;
+        use foxbox_core::config_store::ConfigService;
+        use foxbox_taxonomy::api::User;
+        use foxbox_taxonomy::values::Data;
+        use std::fs;
+        use uuid::Uuid;
+    }
+
+    it "should parse texts with or without a voice" {
+        assert_eq!(Speech::parse_str(r#""Hello""#).unwrap(),
+                   Speech {
+                       text: "Hello".to_owned(),
+                       voice: Voice::default(),
+                   });
+        let speech = Speech::parse_str(r#"{"text": "Bonjour", "language": "fr", "rate": 150}"#)
+            .unwrap();
+        assert_eq!(speech.text, "Bonjour");
+        assert_eq!(speech.voice.language, Some("fr".to_owned()));
+        assert_eq!(speech.voice.rate, Some(150));
+        assert_eq!(speech.voice.volume, None);
+
+        assert!(Speech::parse_str(r#"{"language": "fr"}"#).is_err());
+        assert!(Speech::parse_str(r#"{"text": "Hi", "volume": 250}"#).is_err());
+        assert!(Speech::parse_str(r#"{"text": "Hi", "rate": 10}"#).is_err());
+        assert!(Speech::parse_str(r#"{"text": "Hi", "language": 12}"#).is_err());
+    }
+
+    it "should fall back to the defaults of the user" {
+        let file_name = format!("tts-test-conf-{}.tmp", Uuid::new_v4());
+        let config = ConfigService::new(&file_name);
+        config.set("tts", "language", "en");
+        config.set("tts", "rate", "160");
+        config.set("tts", "user.2.language", "de");
+
+        let defaults = Voice::defaults_for(&config, &User::Id("2".to_owned()));
+        assert_eq!(defaults.language, Some("de".to_owned()));
+        assert_eq!(defaults.rate, Some(160));
+        assert_eq!(Voice::defaults_for(&config, &User::None).language, Some("en".to_owned()));
+
+        let voice = Voice {
+            language: Some("fr".to_owned()),
+            ..Voice::default()
+        };
+        let voice = voice.or(&defaults);
+        assert_eq!(voice.language, Some("fr".to_owned()));
+        assert_eq!(voice.rate, Some(160));
+
+        let _ = fs::remove_file(&file_name);
+    }
+}
@@ -0,0 +1,301 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter monitoring the connection of the box to the Internet.
+//!
+//! The `wan` service exposes:
+//! - `wan/is-online` (fetch, watch): `On` while at least one of the probe hosts
+//!   (`wan.probe_hosts`, comma separated `host:port`) accepts TCP connections. Watching
+//!   for `Off` is a good way to be notified of outages;
+//! - `wan/public-ip` (fetch, watch): the public IP address, as returned by
+//!   `wan.public_ip_url`;
+//! - `wan/speed-test` (fetch, watch): the result of the latest speed test, as JSON
+//!   `{"download_kbps", "latency_ms", "date"}`. Speed tests download
+//!   `wan.speed_test_url` every `wan.speed_test_interval` seconds, and are skipped while
+//!   offline.
+
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, OnOff, Value};
+
+use chrono::UTC;
+use hyper;
+use hyper::header::Connection;
+use serde_json::value::Value as JSON;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static ADAPTER_NAME: &'static str = "WAN status adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+const PROBE_INTERVAL_S: u64 = 30;
+const PROBE_TIMEOUT_S: u64 = 5;
+
+pub struct WanAdapter {
+    watchers: ValueWatchers,
+}
+
+/// Parse a comma separated list of `host:port`.
+fn parse_hosts(source: &str) -> Vec<String> {
+    source.split(',')
+        .map(|host| host.trim())
+        .filter(|host| !host.is_empty() && host.contains(':'))
+        .map(|host| host.to_owned())
+        .collect()
+}
+
+fn is_reachable(host: &str) -> bool {
+    let addresses = match host.to_socket_addrs() {
+        Ok(addresses) => addresses,
+        Err(_) => return false,
+    };
+    for address in addresses {
+        // `TcpStream::connect` has no timeout, so we connect from a separate thread.
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(TcpStream::connect(address).is_ok());
+        });
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(PROBE_TIMEOUT_S) {
+            if let Ok(reachable) = rx.try_recv() {
+                if reachable {
+                    return true;
+                }
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+    false
+}
+
+fn http_get(url: &str) -> Result<Vec<u8>, String> {
+    let client = hyper::Client::new();
+    let mut response = try!(client.get(url)
+        .header(Connection::close())
+        .send()
+        .map_err(|err| format!("{}", err)));
+    if !response.status.is_success() {
+        return Err(format!("{} returned {}", url, response.status));
+    }
+    let mut content = Vec::new();
+    try!(response.read_to_end(&mut content).map_err(|err| format!("{}", err)));
+    Ok(content)
+}
+
+fn public_ip(url: &str) -> Result<String, String> {
+    let content = try!(http_get(url));
+    let ip = String::from_utf8_lossy(&content).trim().to_owned();
+    if ip.is_empty() || ip.len() > 45 {
+        return Err(format!("Unexpected public IP {:?}", ip));
+    }
+    Ok(ip)
+}
+
+fn kbps(bytes: usize, elapsed: Duration) -> u64 {
+    let millis = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+    if millis == 0 {
+        return 0;
+    }
+    bytes as u64 * 8 / millis
+}
+
+fn speed_test(url: &str) -> Result<Value, String> {
+    let start = Instant::now();
+    let client = hyper::Client::new();
+    let mut response = try!(client.get(url)
+        .header(Connection::close())
+        .send()
+        .map_err(|err| format!("{}", err)));
+    // The time to first byte is a reasonable approximation of the latency.
+    let latency = start.elapsed();
+    let mut content = Vec::new();
+    try!(response.read_to_end(&mut content).map_err(|err| format!("{}", err)));
+    let elapsed = start.elapsed();
+
+    let mut result = BTreeMap::new();
+    result.insert("download_kbps".to_owned(), JSON::U64(kbps(content.len(), elapsed)));
+    result.insert("latency_ms".to_owned(),
+                  JSON::U64(latency.as_secs() * 1000 + latency.subsec_nanos() as u64 / 1_000_000));
+    result.insert("date".to_owned(), JSON::String(UTC::now().to_rfc3339()));
+    Ok(Value::new(Json(JSON::Object(result))))
+}
+
+impl WanAdapter {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("wan@link.mozilla.org")
+    }
+
+    pub fn service_id() -> Id<ServiceId> {
+        Id::new("service:wan@link.mozilla.org")
+    }
+
+    pub fn getter_is_online_id() -> Id<Channel> {
+        Id::new("getter:is-online.wan@link.mozilla.org")
+    }
+
+    pub fn getter_public_ip_id() -> Id<Channel> {
+        Id::new("getter:public-ip.wan@link.mozilla.org")
+    }
+
+    pub fn getter_speed_test_id() -> Id<Channel> {
+        Id::new("getter:speed-test.wan@link.mozilla.org")
+    }
+
+    pub fn init<C: Controller>(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        let config = controller.get_config();
+        let hosts = parse_hosts(&config.get_or_set_default("wan",
+                                                           "probe_hosts",
+                                                           "8.8.8.8:53,208.67.222.222:53"));
+        let public_ip_url =
+            config.get_or_set_default("wan", "public_ip_url", "https://api.ipify.org");
+        let speed_test_url = config.get("wan", "speed_test_url");
+        let speed_test_interval = config.get_or_set_default("wan", "speed_test_interval", "21600")
+            .parse()
+            .unwrap_or(21600);
+
+        let watchers = ValueWatchers::new();
+        try!(manager.add_adapter(Arc::new(WanAdapter { watchers: watchers.clone() })));
+
+        let mut service = Service::empty(&Self::service_id(), &Self::id());
+        service.properties.insert("model".to_owned(), "WAN status".to_owned());
+        try!(manager.add_service(service));
+
+        let channels = vec![(Self::getter_is_online_id(), "wan/is-online", format::ON_OFF.clone()),
+                            (Self::getter_public_ip_id(), "wan/public-ip", format::STRING.clone()),
+                            (Self::getter_speed_test_id(), "wan/speed-test", format::JSON.clone())];
+        for (id, feature, format) in channels {
+            try!(manager.add_channel(Channel {
+                id: id,
+                service: Self::service_id(),
+                adapter: Self::id(),
+                feature: Id::new(feature),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format.clone()))),
+                supports_watch: Some(Signature::returns(Maybe::Required(format))),
+                ..Channel::default()
+            }));
+        }
+
+        thread::spawn(move || {
+            let mut last_speed_test: Option<Instant> = None;
+            loop {
+                let online = hosts.iter().any(|host| is_reachable(host));
+                let was_online = watchers.latest(&Self::getter_is_online_id())
+                    .map(|value| value.cast::<OnOff>().ok() == Some(&OnOff::On));
+                if was_online != Some(online) {
+                    info!("[wan] The box is now {}", if online { "online" } else { "offline" });
+                }
+                watchers.update(&Self::getter_is_online_id(),
+                                Value::new(if online { OnOff::On } else { OnOff::Off }));
+
+                if online {
+                    // The public IP typically changes when the connection is restored.
+                    if was_online != Some(true) ||
+                       watchers.latest(&Self::getter_public_ip_id()).is_none() {
+                        match public_ip(&public_ip_url) {
+                            Ok(ip) => {
+                                watchers.update(&Self::getter_public_ip_id(), Value::new(ip))
+                            }
+                            Err(err) => warn!("[wan] Could not get the public IP: {}", err),
+                        }
+                    }
+                    if let Some(ref url) = speed_test_url {
+                        let due = last_speed_test.map_or(true, |last| {
+                            last.elapsed() >= Duration::from_secs(speed_test_interval)
+                        });
+                        if due {
+                            last_speed_test = Some(Instant::now());
+                            match speed_test(url) {
+                                Ok(result) => {
+                                    watchers.update(&Self::getter_speed_test_id(), result)
+                                }
+                                Err(err) => warn!("[wan] Speed test failed: {}", err),
+                            }
+                        }
+                    }
+                }
+                thread::sleep(Duration::from_secs(PROBE_INTERVAL_S));
+            }
+        });
+
+        Ok(())
+    }
+}
+
+impl Adapter for WanAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                let known = id == Self::getter_is_online_id() ||
+                            id == Self::getter_public_ip_id() ||
+                            id == Self::getter_speed_test_id();
+                if !known {
+                    return (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))));
+                }
+                let value = self.watchers.latest(&id);
+                (id, Ok(value))
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, _)| (id.clone(), Err(Error::OperationNotSupported(Operation::Send, id))))
+            .collect()
+    }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, _: User) -> WatchResult {
+        self.watchers.register_watch(watch)
+    }
+}
+
+#[cfg(test)]
+describe! wan {
+    before_each {
+        use super::{kbps, parse_hosts};
+        use std::time::Duration;
+    }
+
+    it "should parse the probe hosts" {
+        assert_eq!(parse_hosts("8.8.8.8:53, example.org:80,,nope"),
+                   vec!["8.8.8.8:53".to_owned(), "example.org:80".to_owned()]);
+    }
+
+    it "should compute the download speed" {
+        assert_eq!(kbps(1_000_000, Duration::from_secs(2)), 4000);
+        assert_eq!(kbps(1_000, Duration::from_millis(0)), 0);
+    }
+}
@@ -15,7 +15,12 @@
 //! The "resources" table stores the resources that the user is watching
 //! in order to receive notifications. Adapters may publish a message
 //! to a given resource and all users watching that resource will be
-//! issued a push notification on each of their subscriptions.
+//! issued a push notification on each of their subscriptions. Each user
+//! also implicitly watches their own resource, see `user_resource`.
+//!
+//! The "expired" table stores the subscriptions that the push service
+//! doesn't know anymore, and that were removed from "subscriptions",
+//! until the user subscribes again.
 //!
 
 use foxbox_taxonomy::api::User;
@@ -42,6 +47,16 @@ fn user_to_str(user: &User) -> String {
     }
 }
 
+/// The key under which the rows of `user` are stored, as returned by `expire`.
+pub fn user_key(user: &User) -> String {
+    escape(&user_to_str(user))
+}
+
+/// The resource that only `user` watches, without having to bind it.
+pub fn user_resource(user: &User) -> String {
+    format!("user:{}", user_to_str(user))
+}
+
 pub struct WebPushDb {
     db: Connection,
 }
@@ -70,11 +85,21 @@ impl WebPushDb {
                      &[])
             .unwrap();
 
+        db.execute("CREATE TABLE IF NOT EXISTS expired (
+                    user_id     TEXT,
+                    push_uri    TEXT NOT NULL
+            )",
+                     &[])
+            .unwrap();
+
         WebPushDb { db: db }
     }
 
-    /// Adds a new push subscription `sub` bound to the user `user_id`.
+    /// Adds a new push subscription `sub` bound to the user `user_id`. This also forgets the
+    /// subscriptions of the user that expired, as the user is subscribing again.
     pub fn subscribe(&self, user_id: &User, sub: &Subscription) -> rusqlite::Result<c_int> {
+        try!(self.db.execute("DELETE FROM expired WHERE user_id=$1",
+                             &[&escape(&user_to_str(user_id))]));
         self.db.execute("INSERT INTO subscriptions VALUES ($1, $2, $3, $4)",
                         &[&escape(&user_to_str(user_id)),
                           &escape(&sub.push_uri),
@@ -88,6 +113,41 @@ impl WebPushDb {
                         &[&escape(push_uri)])
     }
 
+    /// Removes a push subscription that the push service doesn't know anymore, remembering
+    /// that it expired until its user subscribes again. Returns the key of this user, as
+    /// `user_key`, or `None` if there was no such subscription.
+    pub fn expire(&self, push_uri: &str) -> rusqlite::Result<Option<String>> {
+        let user: Option<String> = {
+            let mut stmt =
+                try!(self.db.prepare("SELECT user_id FROM subscriptions WHERE push_uri=$1"));
+            let mut rows = try!(stmt.query(&[&escape(push_uri)]));
+            let user = match rows.next() {
+                Some(result_row) => Some(try!(result_row).get(0)),
+                None => None,
+            };
+            user
+        };
+        try!(self.db.execute("INSERT INTO expired SELECT user_id, push_uri FROM subscriptions \
+                              WHERE push_uri=$1",
+                             &[&escape(push_uri)]));
+        try!(self.db.execute("DELETE FROM subscriptions WHERE push_uri=$1",
+                             &[&escape(push_uri)]));
+        Ok(user)
+    }
+
+    /// Gets the push URIs of the subscriptions of the user `user_id` that expired since the
+    /// user last subscribed.
+    pub fn get_expired(&self, user_id: &User) -> rusqlite::Result<Vec<String>> {
+        let mut uris = Vec::new();
+        let mut stmt = try!(self.db.prepare("SELECT push_uri FROM expired WHERE user_id=$1"));
+        let mut rows = try!(stmt.query(&[&escape(&user_to_str(user_id))]));
+        while let Some(result_row) = rows.next() {
+            let row = try!(result_row);
+            uris.push(row.get(0));
+        }
+        Ok(uris)
+    }
+
     /// Sets the resources to subscribe to notifications for the user `user_id`.
     pub fn set_resources(&self, user_id: &User, resources: &[String]) -> rusqlite::Result<()> {
         try!(self.db.execute("DELETE FROM resources WHERE user_id=$1",
@@ -99,6 +159,20 @@ impl WebPushDb {
         Ok(())
     }
 
+    /// Adds resources to subscribe to notifications for the user `user_id`, keeping those
+    /// already subscribed to.
+    pub fn add_resources(&self, user_id: &User, resources: &[String]) -> rusqlite::Result<()> {
+        let existing = try!(self.get_resources(user_id));
+        for resource in resources.iter() {
+            if existing.contains(&escape(resource)) {
+                continue;
+            }
+            try!(self.db.execute("INSERT INTO resources VALUES ($1, $2)",
+                                 &[&escape(&user_to_str(user_id)), &escape(resource)]));
+        }
+        Ok(())
+    }
+
     /// Gets the resources subscribed to by the user `user_id`.
     pub fn get_resources(&self, user_id: &User) -> rusqlite::Result<Vec<String>> {
         let mut subs = Vec::new();
@@ -128,7 +202,8 @@ impl WebPushDb {
         Ok(subs)
     }
 
-    /// Gets the push subscriptions for users who are subscribed to `resource` notifications.
+    /// Gets the push subscriptions for users who are subscribed to `resource` notifications,
+    /// or whose `user_resource` it is.
     pub fn get_resource_subscriptions(&self,
                                       resource: &str)
                                       -> rusqlite::Result<Vec<Subscription>> {
@@ -136,7 +211,8 @@ impl WebPushDb {
         let mut stmt = try!(self.db
             .prepare("SELECT push_uri, public_key, auth FROM subscriptions WHERE
                                              \
-                      user_id IN (SELECT user_id FROM resources WHERE resource=$1)"));
+                      user_id IN (SELECT user_id FROM resources WHERE resource=$1) \
+                      OR 'user:' || user_id = $1"));
         let mut rows = try!(stmt.query(&[&escape(resource)]));
         while let Some(result_row) = rows.next() {
             let row = try!(result_row);
@@ -202,6 +278,30 @@ describe! tests {
         assert_eq!(subs2.len(), 0);
     }
 
+    it "should prune expired subscriptions" {
+        use super::super::Subscription;
+
+        let user = User::Id(String::from("1"));
+        let sub = |push_uri: &str| Subscription {
+            push_uri: push_uri.to_owned(),
+            public_key: "test_public_key".to_owned(),
+            auth: None
+        };
+        db.subscribe(&user, &sub("expired_push_uri")).unwrap();
+        db.subscribe(&user, &sub("live_push_uri")).unwrap();
+
+        assert_eq!(db.expire("expired_push_uri").unwrap(), Some(user_key(&user)));
+        assert_eq!(db.expire("unknown_push_uri").unwrap(), None);
+        let subs = db.get_subscriptions(&user).unwrap();
+        assert_eq!(subs, vec![sub("live_push_uri")]);
+        assert_eq!(db.get_expired(&user).unwrap(), vec!["expired_push_uri".to_owned()]);
+        assert!(db.get_expired(&User::Id(String::from("2"))).unwrap().is_empty());
+
+        // Subscribing again acknowledges the expired subscriptions.
+        db.subscribe(&user, &sub("new_push_uri")).unwrap();
+        assert!(db.get_expired(&user).unwrap().is_empty());
+    }
+
     it "should manage resources correctly" {
         let res0 = db.get_resources(&User::Id(String::from("1"))).unwrap();
         assert_eq!(res0.len(), 0);
@@ -220,6 +320,30 @@ describe! tests {
         assert_eq!(res2.len(), 0);
     }
 
+    it "should add resources to those of a user" {
+        let user = User::Id(String::from("1"));
+        db.set_resources(&user, &["res1".to_owned()]).unwrap();
+        db.add_resources(&user, &["res2".to_owned(), "res1".to_owned()]).unwrap();
+        assert_eq!(db.get_resources(&user).unwrap(), vec!["res1".to_owned(), "res2".to_owned()]);
+    }
+
+    it "should yield the subscriptions of a user given their resource" {
+        use super::super::Subscription;
+
+        let sub = |push_uri: &str| Subscription {
+            push_uri: push_uri.to_owned(),
+            public_key: "test_public_key".to_owned(),
+            auth: None
+        };
+        db.subscribe(&User::Id(String::from("1")), &sub("u1_sub0_puri")).unwrap();
+        db.subscribe(&User::Id(String::from("2")), &sub("u2_sub0_puri")).unwrap();
+
+        let subs = db.get_resource_subscriptions(&user_resource(&User::Id(String::from("1"))))
+            .unwrap();
+        assert_eq!(subs, vec![sub("u1_sub0_puri")]);
+        assert!(db.get_resource_subscriptions("user:3").unwrap().is_empty());
+    }
+
     it "should yield subscriptions given a resource" {
         use super::super::Subscription;
 
@@ -11,10 +11,26 @@
 //! "webpush" build feature. Older versions of `OpenSSL` (< 1.0.0) are
 //! missing the necessary APIs to support the implementation.
 //!
+//! Subscriptions that the push service doesn't know anymore (404 or 410)
+//! are removed as soon as a notification is sent to them. Channel
+//! `webpush/expired` tells clients about it, so that they subscribe again:
+//! fetching it gives the push URIs of the subscriptions of the user that
+//! expired since the user last subscribed, as `{"push_uris": [string]}`,
+//! and watching it gives the push URIs of each subscription of the user
+//! removed.
+//!
+//! Sending to `webpush/subscribe` registers subscriptions, as
+//! `{"subscriptions": [...]}`, and may also subscribe the user to
+//! resources in the same step, with an optional `"resources": [string]`.
+//! A notification sent to `webpush/notify-msg` without a resource, e.g.
+//! from a Thinkerbell rule, goes to the resource of the user who sent it,
+//! which all the subscriptions of this user receive.
+//!
 
 mod crypto;
 mod db;
 
+use foxbox_taxonomy::adapter_utils::ValueWatchers;
 use foxbox_taxonomy::api::{Error, InternalError, User};
 use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::io;
@@ -27,13 +43,14 @@ use foxbox_taxonomy::values::format;
 use hyper::header::{ContentEncoding, Encoding, Authorization};
 use hyper::Client;
 use hyper::client::Body;
+use hyper::status::StatusCode;
 use rusqlite;
 use self::crypto::CryptoContext;
 use serde_json;
 use std::cmp::max;
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::thread;
+use std::sync::{Arc, Mutex};
+use foxbox_core::config_store::ConfigType;
 use foxbox_core::traits::Controller;
 
 header! { (Encryption, "Encryption") => [String] }
@@ -45,6 +62,16 @@ static ADAPTER_NAME: &'static str = "WebPush adapter (built-in)";
 static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
 static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
 
+// The fields expected by the setters, rejected otherwise when `foxbox.strict_json` is set.
+static SUBSCRIPTION: Fields = Fields::Object(&[("push_uri", Fields::Any),
+                                               ("public_key", Fields::Any),
+                                               ("auth", Fields::Any)]);
+static SUBSCRIPTIONS: Fields =
+    Fields::Object(&[("subscriptions", Fields::Array(&SUBSCRIPTION))]);
+static SUBSCRIBE: Fields = Fields::Object(&[("subscriptions", Fields::Array(&SUBSCRIPTION)),
+                                            ("resources", Fields::Any)]);
+static RESOURCES: Fields = Fields::Object(&[("resources", Fields::Any)]);
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Subscription {
     pub push_uri: String,
@@ -63,6 +90,14 @@ impl SubscriptionGetter {
     }
 }
 
+/// The payload of `webpush/subscribe`: the subscriptions to register, and the resources to
+/// subscribe the user to, in addition to those already subscribed to.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SubscribeSetter {
+    subscriptions: Vec<Subscription>,
+    resources: Option<Vec<String>>,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct ResourceGetter {
     resources: Vec<String>,
@@ -74,9 +109,45 @@ impl ResourceGetter {
     }
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExpiredGetter {
+    push_uris: Vec<String>,
+}
+
+impl ExpiredGetter {
+    fn new(push_uris: Vec<String>) -> Self {
+        ExpiredGetter { push_uris: push_uris }
+    }
+}
+
+/// What became of a notification, see `Subscription::notify`.
+#[derive(Debug, PartialEq)]
+enum Delivery {
+    Sent,
+    /// The push service doesn't know the subscription anymore, the client must subscribe
+    /// again.
+    Expired,
+    Failed,
+}
+
+impl Delivery {
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            // https://tools.ietf.org/html/draft-ietf-webpush-protocol-04#section-7.3
+            //
+            // "A push service MAY return a 404 (Not Found) status code if an
+            //  application server attempts to send a push message to an expired
+            //  push message subscription."
+            StatusCode::NotFound | StatusCode::Gone => Delivery::Expired,
+            status if status.is_success() => Delivery::Sent,
+            _ => Delivery::Failed,
+        }
+    }
+}
+
 impl Subscription {
     #[allow(useless_let_if_seq)] // Clippy's warning make no sense at all in this method.
-    fn notify(&self, crypto: &CryptoContext, gcm_api_key: &str, message: &str) {
+    fn notify(&self, crypto: &CryptoContext, gcm_api_key: &str, message: &str) -> Delivery {
         // Make the record size at least the size of the encrypted message. We must
         // add 16 bytes for the encryption tag, 1 byte for padding and 1 byte to
         // ensure we don't end on a record boundary.
@@ -104,7 +175,7 @@ impl Subscription {
                 warn!("notity subscription {} failed for {}",
                       self.push_uri,
                       message);
-                return;
+                return Delivery::Failed;
             }
         };
 
@@ -132,7 +203,7 @@ impl Subscription {
             if gcm_api_key.is_empty() {
                 warn!("cannot notify subscription {}, GCM API key missing from foxbox.conf",
                       push_uri);
-                return;
+                return Delivery::Failed;
             }
             req = req.header(Authorization(format!("key={}", gcm_api_key)));
         }
@@ -168,13 +239,14 @@ impl Subscription {
             Ok(x) => x,
             Err(e) => {
                 warn!("notify subscription {} failed: {:?}", push_uri, e);
-                return;
+                return Delivery::Failed;
             }
         };
 
         info!("notified subscription {} (status {:?})",
               push_uri,
               rsp.status);
+        Delivery::from_status(rsp.status)
     }
 }
 
@@ -185,6 +257,10 @@ pub struct WebPush<C> {
     channel_subscribe_id: Id<Channel>,
     channel_unsubscribe_id: Id<Channel>,
     channel_notify_id: Id<Channel>,
+    channel_expired_id: Id<Channel>,
+    /// The watchers of `channel_expired_id`, by `db::user_key`, so that users are only told
+    /// about their own subscriptions.
+    watchers: Arc<Mutex<HashMap<String, ValueWatchers>>>,
 }
 
 impl<C: Controller> WebPush<C> {
@@ -211,6 +287,10 @@ impl<C: Controller> WebPush<C> {
     pub fn channel_notify_id() -> Id<Channel> {
         Id::new("channel:notify.webpush@link.mozilla.org")
     }
+
+    pub fn channel_expired_id() -> Id<Channel> {
+        Id::new("channel:expired.webpush@link.mozilla.org")
+    }
 }
 
 impl<C: Controller> Adapter for WebPush<C> {
@@ -255,6 +335,7 @@ impl<C: Controller> Adapter for WebPush<C> {
 
             getter_api!(get_subscriptions, channel_subscribe_id, SubscriptionGetter);
             getter_api!(get_resources, channel_resource_id, ResourceGetter);
+            getter_api!(get_expired, channel_expired_id, ExpiredGetter);
             (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
         }).collect()
     }
@@ -287,9 +368,18 @@ impl<C: Controller> Adapter for WebPush<C> {
             };
             let Json(ref json_value) = *arc_json_value;
 
+            let strict = self.controller
+                .get_config()
+                .get_or_set_default("foxbox", "strict_json", "false") == "true";
+
             macro_rules! setter_api {
-                ($setter:ident, $setter_name: expr, $setter_id:ident, $setter_type:ident) => (
+                ($setter:ident, $setter_name: expr, $setter_id:ident, $setter_type:ident, $fields:expr) => (
                     if id == self.$setter_id {
+                        if strict {
+                            if let Err(err) = $fields.check(Path::new(), json_value) {
+                                return (id, Err(Error::Parsing(err)));
+                            }
+                        }
                         let data: Result<$setter_type, _> = serde_json::from_value(json_value.clone());
                         match data {
                             Ok(x) => {
@@ -302,16 +392,36 @@ impl<C: Controller> Adapter for WebPush<C> {
                 )
             }
 
-            setter_api!(set_resources, "set_resources", channel_resource_id, ResourceGetter);
-            setter_api!(set_subscribe, "set_subscribe", channel_subscribe_id, SubscriptionGetter);
-            setter_api!(set_unsubscribe, "set_unsubscribe", channel_unsubscribe_id, SubscriptionGetter);
+            setter_api!(set_resources, "set_resources", channel_resource_id, ResourceGetter, RESOURCES);
+            setter_api!(set_subscribe, "set_subscribe", channel_subscribe_id, SubscribeSetter, SUBSCRIBE);
+            setter_api!(set_unsubscribe, "set_unsubscribe", channel_unsubscribe_id, SubscriptionGetter, SUBSCRIPTIONS);
             (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
         }).collect()
     }
+
+    fn register_watch(&self, watch: Vec<WatchTarget>, user: User) -> WatchResult {
+        if cfg!(feature = "authentication") && (user == User::None) {
+            return watch.into_iter()
+                .map(|(id, _, _)| {
+                    let err = "Cannot watch this channel without a user.".to_owned();
+                    (id, Err(Error::Internal(InternalError::GenericError(err))))
+                })
+                .collect();
+        }
+        let watchers = self.watchers
+            .lock()
+            .unwrap()
+            .entry(db::user_key(&user))
+            .or_insert_with(ValueWatchers::new)
+            .clone();
+        watchers.register_watch(watch)
+    }
 }
 
 impl<C: Controller> WebPush<C> {
     pub fn init(controller: C, adapt: &Arc<AdapterManager>) -> Result<(), Error> {
+        // The key is read for each notification, so it may be changed at runtime.
+        controller.get_config().register("webpush", "gcm_api_key", ConfigType::Secret);
         let wp = Arc::new(Self::new(controller));
         let id = WebPush::<C>::id();
         let service_id = WebPush::<C>::service_webpush_id();
@@ -319,6 +429,7 @@ impl<C: Controller> WebPush<C> {
         let channel_resource_id = WebPush::<C>::channel_resource_id();
         let channel_subscribe_id = WebPush::<C>::channel_subscribe_id();
         let channel_unsubscribe_id = WebPush::<C>::channel_unsubscribe_id();
+        let channel_expired_id = WebPush::<C>::channel_expired_id();
 
         try!(adapt.add_adapter(wp));
         try!(adapt.add_service(Service::empty(&service_id, &id)));
@@ -359,6 +470,14 @@ impl<C: Controller> WebPush<C> {
             id: channel_unsubscribe_id,
             ..template.clone()
         }));
+
+        try!(adapt.add_channel(Channel {
+            feature: Id::new("webpush/expired"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            supports_watch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            id: channel_expired_id,
+            ..template.clone()
+        }));
         Ok(())
     }
 
@@ -370,18 +489,27 @@ impl<C: Controller> WebPush<C> {
             channel_subscribe_id: Self::channel_subscribe_id(),
             channel_unsubscribe_id: Self::channel_unsubscribe_id(),
             channel_notify_id: Self::channel_notify_id(),
+            channel_expired_id: Self::channel_expired_id(),
+            watchers: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    fn get_db_path(&self) -> String {
+        self.controller.get_profile().path_for("webpush.sqlite")
+    }
+
     fn get_db(&self) -> db::WebPushDb {
-        db::WebPushDb::new(&self.controller.get_profile().path_for("webpush.sqlite"))
+        db::WebPushDb::new(&self.get_db_path())
     }
 
-    fn set_subscribe(&self, user: &User, setter: &SubscriptionGetter) -> rusqlite::Result<()> {
+    fn set_subscribe(&self, user: &User, setter: &SubscribeSetter) -> rusqlite::Result<()> {
         let db = self.get_db();
         for sub in &setter.subscriptions {
             try!(db.subscribe(&user, sub));
         }
+        if let Some(ref resources) = setter.resources {
+            try!(db.add_resources(&user, resources));
+        }
         Ok(())
     }
 
@@ -406,35 +534,77 @@ impl<C: Controller> WebPush<C> {
         self.get_db().get_subscriptions(user)
     }
 
+    fn get_expired(&self, user: &User) -> rusqlite::Result<Vec<String>> {
+        self.get_db().get_expired(user)
+    }
+
     fn get_resource_subscriptions(&self, resource: &str) -> rusqlite::Result<Vec<Subscription>> {
         self.get_db().get_resource_subscriptions(resource)
     }
 
-    fn set_notify(&self, _: &User, setter: &WebPushNotify) -> rusqlite::Result<()> {
-        info!("notify on resource {}: {}", setter.resource, setter.message);
+    fn set_notify(&self, user: &User, setter: &WebPushNotify) -> rusqlite::Result<()> {
+        let resource = match setter.resource {
+            Some(ref resource) => resource.clone(),
+            None => db::user_resource(user),
+        };
+        info!("notify on resource {}: {}", resource, setter.message);
 
-        let subscriptions = try!(self.get_resource_subscriptions(&setter.resource));
+        let subscriptions = try!(self.get_resource_subscriptions(&resource));
         if subscriptions.is_empty() {
             debug!("no users listening on push resource");
         } else {
-            let json = json!({resource: setter.resource, message: setter.message});
+            let json = json!({resource: resource, message: setter.message});
             let crypto = self.crypto.clone();
             let gcm_api_key =
                 self.controller.get_config().get_or_set_default("webpush", "gcm_api_key", "");
 
-            thread::spawn(move || {
-                for sub in subscriptions {
-                    sub.notify(&crypto, &gcm_api_key, &json);
-                }
-            });
+            let pool = self.controller.get_thread_pool();
+            let db_path = self.get_db_path();
+            for sub in subscriptions {
+                let crypto = crypto.clone();
+                let gcm_api_key = gcm_api_key.clone();
+                let json = json.clone();
+                let db_path = db_path.clone();
+                let watchers = self.watchers.clone();
+                let channel_expired_id = self.channel_expired_id.clone();
+                // A full pool drops the notification, and logs it.
+                let _ = pool.execute(move || {
+                    if sub.notify(&crypto, &gcm_api_key, &json) == Delivery::Expired {
+                        prune(&db_path, &watchers, &channel_expired_id, &sub.push_uri);
+                    }
+                });
+            }
         }
         Ok(())
     }
 }
 
+/// Remove a subscription that expired, and tell the watchers of `channel_expired_id` of its
+/// user.
+fn prune(db_path: &str,
+         watchers: &Mutex<HashMap<String, ValueWatchers>>,
+         channel_expired_id: &Id<Channel>,
+         push_uri: &str) {
+    info!("removing expired subscription {}", push_uri);
+    let user = match db::WebPushDb::new(db_path).expire(push_uri) {
+        Ok(Some(user)) => user,
+        Ok(None) => return,
+        Err(err) => {
+            warn!("cannot remove expired subscription {}: {}", push_uri, err);
+            return;
+        }
+    };
+    if let Some(watchers) = watchers.lock().unwrap().get(&user) {
+        let expired = ExpiredGetter::new(vec![push_uri.to_owned()]);
+        watchers.update(channel_expired_id,
+                        Value::new(Json(serde_json::to_value(&expired))));
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct WebPushNotify {
-    pub resource: String,
+    /// If `None`, the resource of the user who sends the notification.
+    pub resource: Option<String>,
     pub message: String,
 }
 
@@ -443,7 +613,13 @@ impl Data for WebPushNotify {
         "WebPushNotify".to_owned()
     }
     fn parse(path: Path, source: &JSON, binary: &io::BinarySource) -> Result<Self, Error> {
-        let resource = try!(path.push("resource", |path| String::parse_field(path, source, binary, "resource")));
+        let resource = path.push("resource",
+                                 |path| String::parse_opt_field(path, source, binary, "resource"));
+        let resource = match resource {
+            Some(Ok(resource)) => Some(resource),
+            Some(Err(err)) => return Err(err),
+            None => None,
+        };
         let message =
             try!(path.push("message", |path| String::parse_field(path, source, binary, "message")));
         Ok(WebPushNotify {
@@ -452,11 +628,10 @@ impl Data for WebPushNotify {
         })
     }
     fn serialize(source: &Self, _binary: &io::BinaryTarget) -> Result<JSON, Error> {
-        let json = vec![
-            ("resource", &source.resource),
-            ("message", &source.message),
-        ]
-            .to_json();
-        Ok(json)
+        let mut fields = vec![("message", &source.message)];
+        if let Some(ref resource) = source.resource {
+            fields.push(("resource", resource));
+        }
+        Ok(fields.to_json())
     }
 }
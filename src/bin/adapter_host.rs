@@ -0,0 +1,97 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Runs one of the built-in adapters in a process of its own, connected to the box through
+//! `foxbox_taxonomy::adapter_proxy`. The box starts it as a sandboxed adapter, e.g. with
+//! `sandbox.adapters = zwave` and
+//! `sandbox.zwave.command = adapter_host openzwave <profile dir> [<devices>] [advanced]`,
+//! and tells it where to connect with environment variable `FOXBOX_ADAPTER_SOCKET`. The
+//! in-process adapter should then be disabled, e.g. with `openzwave.sandboxed = true`.
+
+#![feature(plugin)]
+
+// Make linter fail for every warning
+#![plugin(clippy)]
+#![deny(clippy)]
+
+extern crate env_logger;
+extern crate foxbox_taxonomy;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "zwave")]
+extern crate openzwave_adapter as openzwave;
+
+use foxbox_taxonomy::adapter_proxy::RemoteManager;
+
+use std::env::{args, var};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+/// Set by the sandbox of the box, see `adapters::sandbox`.
+static SOCKET_VAR: &'static str = "FOXBOX_ADAPTER_SOCKET";
+
+#[cfg(feature = "zwave")]
+fn start_openzwave(manager: &Arc<RemoteManager>, arguments: &[String]) -> Result<(), String> {
+    let profile = match arguments.get(0) {
+        Some(profile) => profile,
+        None => return Err("Usage: adapter_host openzwave <profile dir> [<devices>] [advanced]"
+            .to_owned()),
+    };
+    let devices = arguments.get(1).cloned();
+    let advanced = arguments.get(2).map_or(false, |advanced| advanced == "advanced");
+    openzwave::Adapter::init(manager, profile, devices, advanced)
+        .map_err(|err| format!("Could not start the OpenZWave adapter: {:?}", err))
+}
+
+#[cfg(not(feature = "zwave"))]
+fn start_openzwave(_: &Arc<RemoteManager>, _: &[String]) -> Result<(), String> {
+    Err("This build doesn't include the OpenZWave adapter".to_owned())
+}
+
+fn start(manager: &Arc<RemoteManager>, adapter: &str, arguments: &[String]) -> Result<(), String> {
+    match adapter {
+        "openzwave" => start_openzwave(manager, arguments),
+        _ => Err(format!("Unknown adapter {}", adapter)),
+    }
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let arguments: Vec<String> = args().skip(1).collect();
+    let adapter = match arguments.first() {
+        Some(adapter) => adapter.clone(),
+        None => {
+            error!("Usage: adapter_host <adapter> [<arguments>...]");
+            process::exit(2);
+        }
+    };
+    let socket = match var(SOCKET_VAR) {
+        Ok(socket) => socket,
+        Err(_) => {
+            error!("The {} environment variable should be set", SOCKET_VAR);
+            process::exit(2);
+        }
+    };
+
+    let manager = match RemoteManager::connect(Path::new(&socket)) {
+        Ok(manager) => Arc::new(manager),
+        Err(err) => {
+            error!("Could not connect to the box at {}: {}", socket, err);
+            process::exit(1);
+        }
+    };
+    if let Err(err) = start(&manager, &adapter, &arguments[1..]) {
+        error!("{}", err);
+        manager.close();
+        process::exit(1);
+    }
+
+    // Until the box stops the adapter or goes away. The box restarts the process if needed.
+    if let Err(err) = manager.serve() {
+        error!("The connection to the box was lost: {}", err);
+    }
+    manager.close();
+}
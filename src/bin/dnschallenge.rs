@@ -34,33 +34,33 @@ fn main() {
     println!("Challenge value: {:?}", challenge_value);
 
     let certificate_directory_result = var("CERTIFICATE_DIRECTORY");
-    let dns_api_result = var("DNS_API_ENDPOINT");
+    let dns_config_result = DnsConfig::from_env(|name| var(name).ok());
 
     if certificate_directory_result.is_err() {
         panic!("The CERTIFICATE_DIRECTORY environment variable should be set");
     }
 
-    if dns_api_result.is_err() {
-        panic!("The DNS_API_ENDPOINT environment variable should be set");
+    if let Err(ref err) = dns_config_result {
+        panic!("The DNS provider is not configured: {}", err);
     }
 
-    let dns_api = dns_api_result.unwrap();
+    let dns_config = dns_config_result.unwrap();
     let certificate_directory = certificate_directory_result.unwrap();
     println!("Using certificate directory: {:?}", certificate_directory);
-    println!("Using DNS api endpoint: {:?}", dns_api);
+    if let DnsConfig::Knilxof { ref api_endpoint } = dns_config {
+        println!("Using DNS api endpoint: {:?}", api_endpoint);
+    }
 
     let certificate_manager = CertificateManager::new(PathBuf::from(&certificate_directory),
                                                       "knilxof.org", /* This is fine to hardcode here since we only get the local certificate. */
                                                       Box::new(SniSslContextProvider::new()));
 
-    let box_cert = certificate_manager.get_box_certificate().unwrap();
+    let dns_provider = dns_config.provider(&certificate_manager).unwrap();
     println!("Registering DNS record");
-    register_dns_record(box_cert,
-                        &DnsRecord {
-                            record_type: "TXT",
-                            name: &format!("_acme-challenge.{}", hostname.unwrap()),
-                            value: &challenge_value.unwrap(),
-                        },
-                        &dns_api)
+    dns_provider.register(&DnsRecord {
+            record_type: "TXT",
+            name: &format!("_acme-challenge.{}", hostname.unwrap()),
+            value: &challenge_value.unwrap(),
+        })
         .unwrap();
 }
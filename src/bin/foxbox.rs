@@ -29,7 +29,7 @@ extern crate rustc_serialize;
 extern crate time;
 extern crate tls;
 
-use foxboxlib::controller::FoxBox;
+use foxboxlib::controller::{FoxBox, LOG_CAPACITY};
 use env_logger::LogBuilder;
 use foxboxlib::tunnel_controller::{TunnelConfig, Tunnel};
 use libc::{sighandler_t, SIGINT};
@@ -37,15 +37,21 @@ use log::{LogRecord, LogLevelFilter};
 
 use multicast_dns::errors::Error as HostManagerError;
 use multicast_dns::host::HostManager;
-use foxbox_core::profile_service::ProfilePath;
+use foxbox_core::log_buffer::LogBuffer;
+use foxbox_core::profile_service::{ProfilePath, ProfileService};
+use foxboxlib::profile_vault::{self, KeySource, Vault};
 use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 use tls::TlsOption;
 use foxbox_core::traits::Controller;
 use foxbox_core::utils;
 
 docopt!(Args derive Debug, "
-Usage: foxbox [-v] [-h] [-l <hostname>] [-p <port>] [-w <wsport>] [-d <profile_path>] [-r <url>] [-i <iface>] [-t <tunnel>] [-s <secret>] [--disable-tls] [--dns-domain <domain>] [--dns-api <url>] [-c <namespace;key;value>]...
+Usage: foxbox [-v] [-h] [-l <hostname>] [-p <port>] [-w <wsport>] [-d <profile_path>] [-r <url>] [-i <iface>] [-t <tunnel>] [-s <secret>] [--disable-tls] [--dns-domain <domain>] [--dns-api <url>] [--encrypt-profile] [--profile-key <file>] [--unlock-port <port>] [-c <namespace;key;value>]...
 
 Options:
     -v, --verbose            Toggle verbose output.
@@ -60,6 +66,9 @@ Options:
         --disable-tls                  Run as a plain HTTP server, disabling encryption.
         --dns-domain <domain>          Set the top level domain for public DNS [default: box.knilxof.org]
         --dns-api <url>                Set the DNS API endpoint [default: https://knilxof.org:5300]
+        --encrypt-profile              Encrypt the profile, if it isn't encrypted yet.
+        --profile-key <file>           Read the key of the encrypted profile from a file. If omitted, wait for a passphrase.
+        --unlock-port <port>           Set port to listen on for the passphrase of the encrypted profile. [default: 3001]
     -c, --config <namespace;key;value>  Set configuration override
     -h, --help               Print this help menu.
 ",
@@ -74,6 +83,9 @@ Options:
         flag_disable_tls: bool,
         flag_dns_domain: String,
         flag_dns_api: String,
+        flag_encrypt_profile: bool,
+        flag_profile_key: Option<String>,
+        flag_unlock_port: u16,
         flag_config: Option<Vec<String>>);
 
 /// Updates local host name with the provided host name string. If requested host name
@@ -106,6 +118,9 @@ fn update_hostname(hostname: &str) -> Result<String, HostManagerError> {
 // Signal handlers must not do anything substantial. To trigger shutdown, we atomically
 // flip this flag; the event loop checks the flag and exits accordingly.
 static SHUTDOWN_FLAG: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// How often the decrypted profile is saved, in seconds.
+const VAULT_SYNC_INTERVAL: u64 = 600;
 unsafe fn handle_sigint(_: i32) {
     SHUTDOWN_FLAG.store(true, Ordering::Release);
 }
@@ -123,16 +138,67 @@ fn tid_str() -> &'static str {
     ""
 }
 
+/// If the profile is encrypted, or should be, unlock it with the key file or with a
+/// passphrase sent to the unlock endpoint. Returns the vault, whose decrypted directory is to
+/// be used as the profile.
+fn open_vault(profile: &Path,
+              encrypt: bool,
+              key_file: Option<String>,
+              unlock_port: u16)
+              -> Option<Vault> {
+    let exists = Vault::exists(profile);
+    if !exists && !encrypt {
+        return None;
+    }
+    let profile = profile.to_owned();
+    let plain = Vault::default_plain_dir();
+    let open = move |source: KeySource| if exists {
+        Vault::unlock(&profile, &plain, source)
+    } else {
+        Vault::create(&profile, &plain, source)
+    };
+    Some(match key_file {
+        Some(path) => {
+            open(KeySource::KeyFile(PathBuf::from(path)))
+                .unwrap_or_else(|err| panic!("Unable to open the encrypted profile: {:?}", err))
+        }
+        None => {
+            warn!("The profile is encrypted. POST the passphrase to http://localhost:{}/unlock",
+                  unlock_port);
+            profile_vault::wait_for_passphrase(unlock_port, move |passphrase| {
+                open(KeySource::Passphrase(passphrase.to_owned()))
+            })
+        }
+    })
+}
+
+fn plain_line(record: &LogRecord) -> String {
+    let t = time::now();
+    format!("{}.{:03} {}{:5} [{}@{}] {}",
+            time::strftime("%Y-%m-%d %H:%M:%S", &t).unwrap(),
+            t.tm_nsec / 1_000_000,
+            tid_str(),
+            record.level(),
+            record.target(),
+            record.location().line(),
+            record.args())
+}
+
 fn main() {
     unsafe {
         libc::signal(SIGINT, handle_sigint as sighandler_t);
     }
 
+    // Keep the latest lines, e.g. for support sessions.
+    let log = LogBuffer::new(LOG_CAPACITY);
+
     let mut builder = LogBuilder::new();
     let istty = unsafe { libc::isatty(libc::STDERR_FILENO as i32) } != 0;
     if istty {
         // Colorized output formatter
-        let format = |record: &LogRecord| {
+        let log = log.clone();
+        let format = move |record: &LogRecord| {
+            log.push(plain_line(record));
             let t = time::now();
             let level_color = match record.level() {
                 log::LogLevel::Error => "\x1b[1;31m",  // bold red
@@ -154,16 +220,11 @@ fn main() {
         builder.format(format).filter(None, LogLevelFilter::Info);
     } else {
         // Plain output formatter
-        let format = |record: &LogRecord| {
-            let t = time::now();
-            format!("{}.{:03} {}{:5} [{}@{}] {}",
-                    time::strftime("%Y-%m-%d %H:%M:%S", &t).unwrap(),
-                    t.tm_nsec / 1_000_000,
-                    tid_str(),
-                    record.level(),
-                    record.target(),
-                    record.location().line(),
-                    record.args())
+        let log = log.clone();
+        let format = move |record: &LogRecord| {
+            let line = plain_line(record);
+            log.push(line.clone());
+            line
         };
         builder.format(format).filter(None, LogLevelFilter::Info);
     }
@@ -184,6 +245,39 @@ fn main() {
         .and_then(|name| Ok(format!("{}.local", name)))
         .unwrap();
 
+    let profile_path = match args.flag_profile {
+        Some(p) => ProfilePath::Custom(p),
+        None => ProfilePath::Default,
+    };
+    let profile_dir = PathBuf::from(ProfileService::new(profile_path).directory());
+    let vault = open_vault(&profile_dir,
+                           args.flag_encrypt_profile,
+                           args.flag_profile_key,
+                           args.flag_unlock_port)
+        .map(Arc::new);
+    let profile_path = match vault {
+        Some(ref vault) => ProfilePath::Custom(vault.plain_dir().to_string_lossy().into_owned()),
+        None => ProfilePath::Custom(profile_dir.to_string_lossy().into_owned()),
+    };
+    // Regularly save the decrypted profile, since it lives in RAM, until shutdown.
+    let vault_sync = vault.as_ref().map(|vault| {
+        let vault = vault.clone();
+        thread::spawn(move || {
+            let mut elapsed = 0;
+            while !SHUTDOWN_FLAG.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_secs(1));
+                elapsed += 1;
+                if elapsed < VAULT_SYNC_INTERVAL {
+                    continue;
+                }
+                elapsed = 0;
+                if let Err(err) = vault.sync() {
+                    error!("Unable to save the encrypted profile: {:?}", err);
+                }
+            }
+        })
+    });
+
     let mut controller = FoxBox::new(args.flag_verbose,
                                      &local_name,
                                      &args.flag_dns_domain,
@@ -194,10 +288,8 @@ fn main() {
                                      } else {
                                          TlsOption::Enabled
                                      },
-                                     match args.flag_profile {
-                                         Some(p) => ProfilePath::Custom(p),
-                                         None => ProfilePath::Default,
-                                     });
+                                     profile_path);
+    controller.log = log;
 
     // Override config values
     {
@@ -229,6 +321,8 @@ fn main() {
     // domain for the HTTPS tunnel. These public domain names are then verifiable
     // by LetsEncrypt during the validation phase using a dns-01 challenge.
     // See: https://letsencrypt.github.io/acme-spec/#dns
+    // Boxes that are reachable on port 80 may set `foxbox.acme_challenge` to "http-01" to
+    // be validated without the DNS server instead, see `http_server::HttpServer::start`.
     //
     // Once the names have been created in the DNS server, a LetsEncrypt client will
     // issue certificates for each name - the local name will be the common name of
@@ -252,10 +346,21 @@ fn main() {
     registrar.start(args.flag_iface, &tunnel, args.flag_port, &controller);
 
     controller.run(&SHUTDOWN_FLAG);
+    SHUTDOWN_FLAG.store(true, Ordering::Release);
 
     if let Some(mut tunnel) = tunnel {
         tunnel.stop().unwrap();
     }
+
+    // Let a sync in progress finish, then save the profile one last time.
+    if let Some(vault_sync) = vault_sync {
+        let _ = vault_sync.join();
+    }
+    if let Some(vault) = vault {
+        if let Err(err) = vault.lock() {
+            error!("Unable to save the encrypted profile: {:?}", err);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -276,6 +381,9 @@ describe! main {
             assert_eq!(args.flag_iface, None);
             assert_eq!(args.flag_tunnel, None);
             assert_eq!(args.flag_config, None);
+            assert_eq!(args.flag_encrypt_profile, false);
+            assert_eq!(args.flag_profile_key, None);
+            assert_eq!(args.flag_unlock_port, 3001);
             assert_eq!(args.flag_help, false);
         }
 
@@ -0,0 +1,276 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Tell the user when the box restarts after a crash or a power cut, or after an upgrade.
+//!
+//! The daemon keeps `boot_state.json` in the profile, with its version, whether it is running
+//! and when it was last seen alive. On startup, a previous state still marked as running means
+//! that the box didn't shut down cleanly, and the last heartbeat tells roughly for how long it
+//! was down. A notification is then sent to the websocket clients, the console and the
+//! `webpush/notify-msg` channels.
+
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::api::{API, Targetted, User};
+use foxbox_taxonomy::io::Payload;
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_taxonomy::parse::{Parser, Path};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::util::Id;
+use serde_json;
+use serde_json::value::Value as JSON;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the daemon records that it is still alive.
+const HEARTBEAT_INTERVAL_S: u64 = 60;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct BootState {
+    version: String,
+    running: bool,
+    /// Seconds since the epoch.
+    last_seen: u64,
+}
+
+impl BootState {
+    fn load(path: &PathBuf) -> Option<Self> {
+        let mut source = String::new();
+        if File::open(path).and_then(|mut file| file.read_to_string(&mut source)).is_err() {
+            return None;
+        }
+        let json: JSON = match serde_json::from_str(&source) {
+            Ok(json) => json,
+            Err(err) => {
+                warn!("Ignoring invalid {}: {}", path.display(), err);
+                return None;
+            }
+        };
+        Some(BootState {
+            version: json.find("version").and_then(JSON::as_string).unwrap_or("").to_owned(),
+            running: json.find("running").and_then(JSON::as_bool).unwrap_or(false),
+            last_seen: json.find("last_seen").and_then(JSON::as_u64).unwrap_or(0),
+        })
+    }
+
+    fn save(&self, path: &PathBuf) {
+        let mut map = BTreeMap::new();
+        map.insert("version".to_owned(), JSON::String(self.version.clone()));
+        map.insert("running".to_owned(), JSON::Bool(self.running));
+        map.insert("last_seen".to_owned(), JSON::U64(self.last_seen));
+        let serialized = serde_json::to_string(&JSON::Object(map)).unwrap();
+
+        // Write then rename, so that a power cut doesn't leave a truncated file.
+        let tmp = path.with_extension("tmp");
+        let result = File::create(&tmp)
+            .and_then(|mut file| file.write_all(serialized.as_bytes()))
+            .and_then(|_| fs::rename(&tmp, path));
+        if let Err(err) = result {
+            error!("Could not save {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// What happened since the previous run, if anything worth telling.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootEvent {
+    /// The box was not shut down cleanly.
+    pub unclean: bool,
+    /// Roughly how long the box was down, in seconds.
+    pub downtime: u64,
+    /// The previous version, if the box was upgraded.
+    pub upgraded_from: Option<String>,
+    pub version: String,
+}
+
+impl BootEvent {
+    fn compare(previous: &BootState, version: &str, now: u64) -> Option<Self> {
+        let upgraded_from = if previous.version != version {
+            Some(previous.version.clone())
+        } else {
+            None
+        };
+        if !previous.running && upgraded_from.is_none() {
+            return None;
+        }
+        Some(BootEvent {
+            unclean: previous.running,
+            downtime: now.saturating_sub(previous.last_seen),
+            upgraded_from: upgraded_from,
+            version: version.to_owned(),
+        })
+    }
+
+    pub fn message(&self) -> String {
+        let mut parts = vec![];
+        if self.unclean {
+            parts.push(format!("The box restarted after an unexpected shutdown, and was down \
+                                for about {} minutes.",
+                               (self.downtime + 59) / 60));
+        }
+        if let Some(ref previous) = self.upgraded_from {
+            parts.push(format!("The box was upgraded from version {} to version {}.",
+                               previous,
+                               self.version));
+        }
+        parts.join(" ")
+    }
+
+    pub fn to_json(&self) -> JSON {
+        let mut map = BTreeMap::new();
+        map.insert("adapter".to_owned(), JSON::String("core".to_owned()));
+        map.insert("message".to_owned(), JSON::String(self.message()));
+        map.insert("unclean".to_owned(), JSON::Bool(self.unclean));
+        map.insert("downtime".to_owned(), JSON::U64(self.downtime));
+        map.insert("upgraded_from".to_owned(),
+                   self.upgraded_from.clone().map_or(JSON::Null, JSON::String));
+        map.insert("version".to_owned(), JSON::String(self.version.clone()));
+        JSON::Object(map)
+    }
+}
+
+pub struct BootNotifier {
+    path: PathBuf,
+    version: String,
+    stopped: Arc<AtomicBool>,
+}
+
+impl BootNotifier {
+    /// Record that the daemon is running, and return what happened since the previous run.
+    pub fn start(path: PathBuf, version: &str) -> (Self, Option<BootEvent>) {
+        let now = now();
+        let event = BootState::load(&path)
+            .and_then(|previous| BootEvent::compare(&previous, version, now));
+        let notifier = BootNotifier {
+            path: path,
+            version: version.to_owned(),
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        notifier.save(true);
+        (notifier, event)
+    }
+
+    fn save(&self, running: bool) {
+        BootState {
+                version: self.version.clone(),
+                running: running,
+                last_seen: now(),
+            }
+            .save(&self.path);
+    }
+
+    /// Periodically record that the daemon is still alive, until `stop` is called.
+    pub fn keep_alive(&self) {
+        let path = self.path.clone();
+        let version = self.version.clone();
+        let stopped = self.stopped.clone();
+        thread::Builder::new()
+            .name("BootHeartbeat".to_owned())
+            .spawn(move || {
+                loop {
+                    thread::sleep(Duration::from_secs(HEARTBEAT_INTERVAL_S));
+                    if stopped.load(Ordering::Acquire) {
+                        return;
+                    }
+                    BootState {
+                            version: version.clone(),
+                            running: true,
+                            last_seen: now(),
+                        }
+                        .save(&path);
+                }
+            })
+            .unwrap();
+    }
+
+    /// Record a clean shutdown.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        self.save(false);
+    }
+}
+
+/// Tell the user about a restart, through the websockets (and the timeline), the console and
+/// `WebPush`.
+pub fn notify<T: Controller>(controller: &T, manager: &Arc<AdapterManager>, event: &BootEvent) {
+    info!("{}", event.message());
+    controller.adapter_notification(event.to_json());
+
+    let resource = controller.get_config()
+        .get_or_set_default("foxbox", "boot_notification_resource", "system");
+    let mut notification = BTreeMap::new();
+    notification.insert("resource".to_owned(), JSON::String(resource));
+    notification.insert("message".to_owned(), JSON::String(event.message()));
+    let targets = vec![(Id::new("webpush/notify-msg"), JSON::Object(notification)),
+                       (Id::new("log/append-text"), JSON::String(event.message()))];
+    for (feature, json) in targets {
+        let payload = match Payload::parse(Path::new(), &json) {
+            Ok(payload) => payload,
+            Err(err) => {
+                error!("Could not build the boot notification: {:?}", err);
+                continue;
+            }
+        };
+        let results = manager.send_values(vec![Targetted {
+                                              select: vec![ChannelSelector::new()
+                                                  .with_feature(&feature)],
+                                              payload: payload,
+                                          }],
+                                          User::None);
+        for (id, result) in results {
+            if let Err(err) = result {
+                warn!("Could not send the boot notification to {}: {:?}", id, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+describe! boot_notifier {
+    before_each {
+        use super::*;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("boot").unwrap();
+        let path = dir.path().join("boot_state.json");
+    }
+
+    it "should stay silent on a first or clean start" {
+        let (notifier, event) = BootNotifier::start(path.clone(), "1.0.0");
+        assert_eq!(event, None);
+        notifier.stop();
+
+        let (_, event) = BootNotifier::start(path.clone(), "1.0.0");
+        assert_eq!(event, None);
+    }
+
+    it "should report an unclean shutdown" {
+        let (_notifier, _) = BootNotifier::start(path.clone(), "1.0.0");
+        let (_, event) = BootNotifier::start(path.clone(), "1.0.0");
+        let event = event.unwrap();
+        assert!(event.unclean);
+        assert_eq!(event.upgraded_from, None);
+        assert!(event.message().contains("unexpected shutdown"));
+    }
+
+    it "should report an upgrade" {
+        let (notifier, _) = BootNotifier::start(path.clone(), "1.0.0");
+        notifier.stop();
+        let (_, event) = BootNotifier::start(path.clone(), "1.1.0");
+        let event = event.unwrap();
+        assert!(!event.unclean);
+        assert_eq!(event.upgraded_from, Some("1.0.0".to_owned()));
+        assert_eq!(event.message(), "The box was upgraded from version 1.0.0 to version 1.1.0.");
+    }
+}
@@ -7,14 +7,29 @@ extern crate mio;
 
 use adapters::AdapterManager;
 use foxbox_core::config_store::ConfigService;
+use foxbox_core::adapter_status::AdapterStatuses;
+use foxbox_core::app_registry::AppRegistry;
+use foxbox_core::modes::ModeRegistry;
+use foxbox_core::log_buffer::LogBuffer;
+use foxbox_core::oauth2::OAuth2Broker;
 use foxbox_core::profile_service::{ProfilePath, ProfileService};
+use foxbox_core::thread_pool::ThreadPool;
+use foxbox_core::timeline::{Entry, EntryKind, Timeline};
 use foxbox_core::traits::Controller;
 use foxbox_core::upnp::UpnpManager;
-use foxbox_taxonomy::api::{API, Targetted, WatchEvent};
+use foxbox_core::watch_sets::WatchSetRegistry;
+use foxbox_core::ws_trace::{Direction, WsTraces};
+use foxbox_taxonomy::api::{API, Observation, Targetted, User, WatchEvent};
+use foxbox_taxonomy::channel::Channel;
+use foxbox_taxonomy::io::{Format, Payload};
 use foxbox_taxonomy::manager::{AdapterManager as TaxoManager, WatchGuard};
+use foxbox_taxonomy::parse::ToJSON;
 use foxbox_taxonomy::selector::ChannelSelector;
-use foxbox_taxonomy::util::Exactly;
+use foxbox_taxonomy::services::MergePolicy;
+use foxbox_taxonomy::util::{Exactly, Id};
 use foxbox_users::UsersManager;
+use serde_json::value::Value as JSON;
+use boot_notifier::{self, BootNotifier};
 use http_server::HttpServer;
 use mio::{Events, Poll};
 use std::collections::hash_map::HashMap;
@@ -25,12 +40,22 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::Duration;
 use std::vec::IntoIter;
 use tls::{CertificateManager, CertificateRecord, SniSslContextProvider, TlsOption};
 use transformable_channels::mpsc;
 use ws_server::WsServer;
 use ws;
 
+/// How many entries the timeline keeps.
+const TIMELINE_CAPACITY: usize = 5000;
+
+/// How many lines of the daemon's log are kept in memory.
+pub const LOG_CAPACITY: usize = 2000;
+
+/// How many jobs may wait for a thread of the shared pool before new ones are dropped.
+const THREAD_POOL_QUEUE: usize = 1000;
+
 #[derive(Clone)]
 pub struct FoxBox {
     pub verbose: bool,
@@ -40,11 +65,22 @@ pub struct FoxBox {
     domain: String,
     http_port: u16,
     ws_port: u16,
-    websockets: Arc<Mutex<HashMap<ws::util::Token, ws::Sender>>>,
+    /// The websockets, and whether they accept binary frames.
+    websockets: Arc<Mutex<HashMap<ws::util::Token, (ws::Sender, bool)>>>,
+    ws_traces: WsTraces,
     pub config: Arc<ConfigService>,
     upnp: Arc<UpnpManager>,
     users_manager: Arc<UsersManager>,
     profile_service: Arc<ProfileService>,
+    timeline: Timeline,
+    /// The latest lines logged, to be filled by the logger.
+    pub log: LogBuffer,
+    thread_pool: ThreadPool,
+    adapter_statuses: AdapterStatuses,
+    app_registry: AppRegistry,
+    modes: ModeRegistry,
+    oauth2: OAuth2Broker,
+    watch_sets: WatchSetRegistry,
 }
 
 impl FoxBox {
@@ -63,6 +99,14 @@ impl FoxBox {
         let certificate_directory = PathBuf::from(config.get_or_set_default("foxbox",
                                 "certificate_directory",
                                 &profile_service.path_for("certs/")));
+        let apps_path = PathBuf::from(profile_service.path_for("apps.json"));
+        let modes_path = PathBuf::from(profile_service.path_for("modes.json"));
+        let oauth2_path = PathBuf::from(profile_service.path_for("oauth2_tokens.json"));
+        let watch_sets_path = PathBuf::from(profile_service.path_for("watch_sets.json"));
+        let ws_traces_path = PathBuf::from(profile_service.path_for("ws_traces"));
+        let pool_size = config.get_or_set_default("foxbox", "worker_threads", "8")
+            .parse()
+            .unwrap_or(8);
 
         FoxBox {
             certificate_manager: CertificateManager::new(certificate_directory,
@@ -70,6 +114,7 @@ impl FoxBox {
                                                          Box::new(SniSslContextProvider::new())),
             tls_option: tls_option,
             websockets: Arc::new(Mutex::new(HashMap::new())),
+            ws_traces: WsTraces::new(ws_traces_path),
             verbose: verbose,
             hostname: hostname.to_owned(),
             domain: domain.to_owned(),
@@ -80,6 +125,14 @@ impl FoxBox {
             users_manager:
                 Arc::new(UsersManager::new(&profile_service.path_for("users_db.sqlite"))),
             profile_service: Arc::new(profile_service),
+            timeline: Timeline::new(TIMELINE_CAPACITY),
+            log: LogBuffer::new(LOG_CAPACITY),
+            thread_pool: ThreadPool::new("Worker", pool_size, THREAD_POOL_QUEUE),
+            adapter_statuses: AdapterStatuses::new(),
+            app_registry: AppRegistry::new(Some(apps_path)),
+            modes: ModeRegistry::new(Some(modes_path)),
+            oauth2: OAuth2Broker::new(Some(oauth2_path)),
+            watch_sets: WatchSetRegistry::new(Some(watch_sets_path)),
         }
     }
 
@@ -90,10 +143,12 @@ impl FoxBox {
                                            select: vec![ChannelSelector::new()], // All channels.
                                            payload: Exactly::Always, // All events.
                                        }],
-                                  Box::new(tx));
+                                  Box::new(tx),
+                                  User::None);
 
         // This thread will receive the events from the adapters and relay them to websockets.
         let myself = self.clone();
+        let manager = taxo_manager.clone();
         thread::Builder::new()
             .name("ValueWatcher".to_owned())
             .spawn(move || {
@@ -111,13 +166,22 @@ impl FoxBox {
                                 info!("Channel Removed: {}", id);
                                 myself.broadcast_to_websockets(json_value!({ type: "channel/removed", id: id }));
                             }
-                            WatchEvent::EnterRange { channel, value, format} => {
+                            WatchEvent::EnterRange { channel, value, format, observed } => {
                                 info!("Entering Range {} : {:?}", channel, value);
-                                myself.broadcast_to_websockets(json_value!({ type: "range/enter", channel: channel, value: value }));
+                                myself.record_value(&channel, &value, &format, observed);
+                                let links = myself.app_links(&manager, &channel);
+                                myself.broadcast_value_to_websockets("range/enter", channel, value, format, observed, links);
                             }
-                             WatchEvent::ExitRange { channel, value, format} => {
+                             WatchEvent::ExitRange { channel, value, format, observed } => {
                                 info!("Exiting Range {} : {:?}", channel, value);
-                                myself.broadcast_to_websockets(json_value!({ type: "range/exit", channel: channel, value: value }));
+                                let links = myself.app_links(&manager, &channel);
+                                myself.broadcast_value_to_websockets("range/exit", channel, value, format, observed, links);
+                            }
+                            WatchEvent::EnterRangeDelta { channel, patch, observed, .. } => {
+                                info!("Entering Range (delta) {} : {:?}", channel, patch);
+                                myself.broadcast_to_websockets(json_value!({ type: "range/enter-delta", channel: channel, patch: patch,
+                                                                             timestamp: observed.timestamp,
+                                                                             source: observed.source.as_str() }));
                             }
                         }
                     }
@@ -127,6 +191,107 @@ impl FoxBox {
 
         watchguard
     }
+
+    /// Record a value in the timeline, without its binary components (e.g. camera images), as
+    /// of the time the adapter observed it.
+    fn record_value(&self,
+                    channel: &Id<Channel>,
+                    value: &Payload,
+                    format: &Arc<Format>,
+                    observed: Observation) {
+        let details = match value.detach(format) {
+            Ok((header, _)) => header.to_json(),
+            Err(_) => value.to_json(),
+        };
+        let mut entry = Entry::new(EntryKind::Event, vec![channel.to_string()], None, details);
+        entry.timestamp = observed.timestamp;
+        self.timeline.push(entry);
+    }
+
+    /// The "open in app" links of the companion apps for a channel.
+    fn app_links(&self, manager: &Arc<TaxoManager>, channel: &Id<Channel>) -> Vec<JSON> {
+        if self.app_registry.is_empty() {
+            return vec![];
+        }
+        match manager.get_channels(vec![ChannelSelector::new().with_id(channel)]).first() {
+            Some(channel) => {
+                self.app_registry.links(&channel.feature.to_string(),
+                                        &channel.service.to_string(),
+                                        &channel.id.to_string())
+            }
+            None => vec![],
+        }
+    }
+
+    /// Relay a value to all websockets.
+    ///
+    /// Websockets that accept binary frames receive the binary components of the value (e.g.
+    /// camera images) as binary frames following a JSON header frame, in which they are replaced
+    /// by `{"part": index, ...}`. Other websockets receive the value inlined in the JSON.
+    ///
+    /// If companion apps can open the channel, the messages have a field `links`.
+    fn broadcast_value_to_websockets(&self,
+                                     kind: &str,
+                                     channel: Id<Channel>,
+                                     value: Payload,
+                                     format: Arc<Format>,
+                                     observed: Observation,
+                                     links: Vec<JSON>) {
+        let websockets = self.websockets.lock().unwrap();
+
+        let detached = if websockets.values().any(|&(_, binary)| binary) {
+            match value.detach(&format) {
+                Ok((_, ref parts)) if parts.is_empty() => None,
+                Ok((header, parts)) => {
+                    let mut header = json_value!({ type: kind, channel: channel, value: header, parts: parts.len(),
+                                                   timestamp: observed.timestamp,
+                                                   source: observed.source.as_str() });
+                    add_links(&mut header, &links);
+                    Some((serde_json::to_string(&header).unwrap_or("{}".to_owned()), parts))
+                }
+                Err(err) => {
+                    error!("Could not detach binary components of {}: {:?}", channel, err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let mut inline = json_value!({ type: kind, channel: channel, value: value,
+                                       timestamp: observed.timestamp,
+                                       source: observed.source.as_str() });
+        add_links(&mut inline, &links);
+        let inline = serde_json::to_string(&inline).unwrap_or("{}".to_owned());
+
+        for &(ref socket, binary) in websockets.values() {
+            let result = match detached {
+                Some((ref header, ref parts)) if binary => {
+                    self.ws_traces.record(socket.token(), Direction::Out, header);
+                    for part in parts {
+                        self.ws_traces.record_binary(socket.token(), Direction::Out, part.len());
+                    }
+                    parts.iter().fold(socket.send(header.clone()),
+                                      |result, part| result.and_then(|_| socket.send(part.clone())))
+                }
+                _ => {
+                    self.ws_traces.record(socket.token(), Direction::Out, &inline);
+                    socket.send(inline.clone())
+                }
+            };
+            if let Err(err) = result {
+                error!("Error sending to socket: {}", err);
+            }
+        }
+    }
+}
+
+fn add_links(json: &mut JSON, links: &[JSON]) {
+    if links.is_empty() {
+        return;
+    }
+    if let JSON::Object(ref mut map) = *json {
+        map.insert("links".to_owned(), JSON::Array(links.to_vec()));
+    }
 }
 
 impl Controller for FoxBox {
@@ -143,6 +308,24 @@ impl Controller for FoxBox {
         let tags_db_path = PathBuf::from(self.profile_service.path_for("taxonomy_tags.sqlite"));
         let taxo_manager = Arc::new(TaxoManager::new(Some(tags_db_path)));
 
+        // Collapse services describing the same device, e.g. discovered both through UPnP
+        // and added manually. An empty list keeps the duplicates.
+        let merge_properties =
+            self.config.get_or_set_default("foxbox", "merge_services_on", "udn,mac,serial");
+        taxo_manager.set_merge_policy(MergePolicy::from_properties(&merge_properties));
+
+        // Each adapter is called on threads of its own, so that a wedged device only delays
+        // the calls to its adapter. Calls taking longer than this fail, and keep running.
+        let call_timeout_s =
+            self.config.get_or_set_default("foxbox", "adapter_call_timeout_s", "60");
+        taxo_manager.set_call_timeout(Duration::from_secs(call_timeout_s.parse().unwrap_or(60)));
+
+        // Calls to adapters taking longer than this are logged.
+        let slow_call_ms =
+            self.config.get_or_set_default("foxbox", "slow_adapter_call_ms", "1000");
+        taxo_manager.metrics()
+            .set_slow_call_threshold(Duration::from_millis(slow_call_ms.parse().unwrap_or(1000)));
+
         // We can't use let _ = self.watch_values(...) because that would drop the
         // guard immediately and remove the watcher.
         let guard = self.watch_values(&taxo_manager);
@@ -150,8 +333,17 @@ impl Controller for FoxBox {
         let mut adapter_manager = AdapterManager::new(self.clone());
         adapter_manager.start(&taxo_manager);
 
+        // Tell the user if we are restarting after a crash or an upgrade.
+        let (notifier, boot_event) =
+            BootNotifier::start(PathBuf::from(self.profile_service.path_for("boot_state.json")),
+                                env!("CARGO_PKG_VERSION"));
+        notifier.keep_alive();
+        if let Some(event) = boot_event {
+            boot_notifier::notify(&*self, &taxo_manager, &event);
+        }
+
         HttpServer::new(self.clone()).start(&taxo_manager);
-        WsServer::start(self.clone());
+        WsServer::start(self.clone(), &taxo_manager);
 
         let poll = Poll::new().unwrap();
         let mut events = Events::with_capacity(1024);
@@ -165,6 +357,7 @@ impl Controller for FoxBox {
         debug!("Stopping controller");
         adapter_manager.stop();
         taxo_manager.stop();
+        notifier.stop();
     }
 
     fn adapter_started(&self, adapter: String) {
@@ -172,6 +365,7 @@ impl Controller for FoxBox {
     }
 
     fn adapter_notification(&self, notification: serde_json::value::Value) {
+        self.timeline.push(Entry::new(EntryKind::Notification, vec![], None, notification.clone()));
         self.broadcast_to_websockets(json_value!({ type: "core/adapter/notification", message: notification }));
     }
 
@@ -183,8 +377,8 @@ impl Controller for FoxBox {
         ("::", self.ws_port).to_socket_addrs()
     }
 
-    fn add_websocket(&mut self, socket: ws::Sender) {
-        self.websockets.lock().unwrap().insert(socket.token(), socket);
+    fn add_websocket(&mut self, socket: ws::Sender, binary: bool) {
+        self.websockets.lock().unwrap().insert(socket.token(), (socket, binary));
     }
 
     fn remove_websocket(&mut self, socket: ws::Sender) {
@@ -194,7 +388,8 @@ impl Controller for FoxBox {
     fn broadcast_to_websockets(&self, data: serde_json::value::Value) {
         let serialized = serde_json::to_string(&data).unwrap_or("{}".to_owned());
         debug!("broadcast_to_websockets {}", serialized.clone());
-        for socket in self.websockets.lock().unwrap().values() {
+        for &(ref socket, _) in self.websockets.lock().unwrap().values() {
+            self.ws_traces.record(socket.token(), Direction::Out, &serialized);
             match socket.send(serialized.clone()) {
                 Ok(_) => (),
                 Err(err) => error!("Error sending to socket: {}", err),
@@ -202,6 +397,10 @@ impl Controller for FoxBox {
         }
     }
 
+    fn get_ws_traces(&self) -> WsTraces {
+        self.ws_traces.clone()
+    }
+
     fn get_config(&self) -> Arc<ConfigService> {
         self.config.clone()
     }
@@ -210,6 +409,38 @@ impl Controller for FoxBox {
         &self.profile_service
     }
 
+    fn get_timeline(&self) -> Timeline {
+        self.timeline.clone()
+    }
+
+    fn get_log(&self) -> LogBuffer {
+        self.log.clone()
+    }
+
+    fn get_thread_pool(&self) -> ThreadPool {
+        self.thread_pool.clone()
+    }
+
+    fn get_adapter_statuses(&self) -> AdapterStatuses {
+        self.adapter_statuses.clone()
+    }
+
+    fn get_app_registry(&self) -> AppRegistry {
+        self.app_registry.clone()
+    }
+
+    fn get_modes(&self) -> ModeRegistry {
+        self.modes.clone()
+    }
+
+    fn get_oauth2(&self) -> OAuth2Broker {
+        self.oauth2.clone()
+    }
+
+    fn get_watch_sets(&self) -> WatchSetRegistry {
+        self.watch_sets.clone()
+    }
+
     fn get_upnp_manager(&self) -> Arc<UpnpManager> {
         self.upnp.clone()
     }
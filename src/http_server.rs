@@ -10,16 +10,28 @@ use iron::error::IronError;
 use iron::method::Method;
 use iron::status::Status;
 use mount::Mount;
+use oauth2_router;
+use registration;
+use request_watchdog::{RequestWatchdog, WatchdogLimits};
 use router::NoRoute;
 use static_router;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use std::thread;
+use support;
 use taxonomy_router;
+use tls::{ACME_CHALLENGE_PATH, AcmeChallengeHandler};
 
 const THREAD_COUNT: usize = 8;
 
+/// By default, keep a couple of threads to answer that we are busy, see `RequestWatchdog`.
+const DEFAULT_MAX_REQUESTS_IN_FLIGHT: usize = THREAD_COUNT - 2;
+const DEFAULT_STUCK_REQUEST_S: u64 = 30;
+
+/// Where `LetsEncrypt` looks for the tokens of http-01 challenges.
+const DEFAULT_ACME_HTTP_PORT: u16 = 80;
+
 // 404 middleware.
 struct Custom404;
 
@@ -103,35 +115,17 @@ impl<T: Controller> HttpServer<T> {
     }
 
     pub fn start(&mut self, adapter_api: &Arc<AdapterManager>) {
-        let (taxonomy_chain, mut taxonomy_endpoints) =
-            taxonomy_router::create(self.controller.clone(), adapter_api);
-
-        let users_manager = self.controller.get_users_manager();
-        let mut mount = Mount::new();
-        mount.mount("/", static_router::create(users_manager.clone()))
-            .mount("/ping", Ping)
-            .mount("/api/v1", taxonomy_chain)
-            .mount("/users", users_manager.get_router_chain());
-
-        let mut chain = Chain::new(mount);
-        chain.link_after(Custom404);
-
-        // Build the set of CORS endpoints by prefixing the taxonomy ones with api/v1 and
-        // adding the /ping handler.
-        let mut cors_endpoints: Vec<(Vec<Method>, String)> = taxonomy_endpoints.drain(..)
-            .map(|item| (item.0, format!("api/v1/{}", item.1)))
-            .collect();
-        cors_endpoints.push((vec![Method::Get], "ping".to_owned()));
-
-        let cors = CORS::new(cors_endpoints);
-        chain.link_after(cors);
-
+        let mut chain = create_chain(self.controller.clone(), adapter_api);
         let addrs: Vec<_> = self.controller.http_as_addrs().unwrap().collect();
 
         if self.controller.get_tls_enabled() {
             // When running with TLS enabled, add the security headers.
             chain.link_after(SecurityHeaders);
 
+            if registration::uses_http_challenge(&self.controller.get_config()) {
+                self.start_acme_challenge_server(&addrs);
+            }
+
             // This will fail when starting without a certificate, so for now just loop until we generate one.
             loop {
                 // Get the certificate record for the remote hostname, and use its certificate and
@@ -155,6 +149,76 @@ impl<T: Controller> HttpServer<T> {
             start_server(addrs, chain, Protocol::Http);
         }
     }
+
+    /// Serve the tokens of http-01 challenges over plain HTTP on `foxbox.acme_http_port`,
+    /// since the HTTPS server only starts once we have a certificate.
+    fn start_acme_challenge_server(&self, addrs: &[SocketAddr]) {
+        let port = self.controller
+            .get_config()
+            .get_or_set_default("foxbox", "acme_http_port", &DEFAULT_ACME_HTTP_PORT.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_ACME_HTTP_PORT);
+        let mut addr = addrs[0];
+        addr.set_port(port);
+
+        let mut mount = Mount::new();
+        mount.mount(ACME_CHALLENGE_PATH,
+                    AcmeChallengeHandler::new(&self.controller.get_certificate_manager()));
+        let mut chain = Chain::new(mount);
+        chain.link_after(Custom404);
+        start_server(vec![addr], chain, Protocol::Http);
+    }
+}
+
+/// Build the handlers of all the routes, without listening on any socket.
+pub fn create_chain<T: Controller>(controller: T, adapter_api: &Arc<AdapterManager>) -> Chain {
+    let (taxonomy_chain, mut taxonomy_endpoints) =
+        taxonomy_router::create(controller.clone(), adapter_api);
+    let (support_chain, mut support_endpoints) = support::create(controller.clone(), adapter_api);
+    let (oauth2_chain, mut oauth2_endpoints) = oauth2_router::create(controller.clone());
+
+    let users_manager = controller.get_users_manager();
+    let mut mount = Mount::new();
+    mount.mount("/", static_router::create(users_manager.clone()))
+        .mount("/ping", Ping)
+        .mount("/api/v1", taxonomy_chain)
+        .mount("/support", support_chain)
+        .mount("/oauth2", oauth2_chain)
+        .mount("/users", users_manager.get_router_chain());
+
+    let config = controller.get_config();
+    let limits = WatchdogLimits {
+        max_in_flight: config.get_or_set_default("foxbox",
+                                "max_requests_in_flight",
+                                &DEFAULT_MAX_REQUESTS_IN_FLIGHT.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_MAX_REQUESTS_IN_FLIGHT),
+        stuck_after: Duration::from_secs(config.get_or_set_default("foxbox",
+                                "stuck_request_s",
+                                &DEFAULT_STUCK_REQUEST_S.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_STUCK_REQUEST_S)),
+    };
+
+    let mut chain = Chain::new(mount);
+    chain.link_around(RequestWatchdog::new(limits, adapter_api.metrics()));
+    chain.link_after(Custom404);
+
+    // Build the set of CORS endpoints by prefixing the taxonomy ones with api/v1, the
+    // support ones with support and the oauth2 ones with oauth2, and adding the /ping
+    // handler.
+    let mut cors_endpoints: Vec<(Vec<Method>, String)> = taxonomy_endpoints.drain(..)
+        .map(|item| (item.0, format!("api/v1/{}", item.1)))
+        .collect();
+    cors_endpoints.extend(support_endpoints.drain(..)
+        .map(|item| (item.0, format!("support/{}", item.1))));
+    cors_endpoints.extend(oauth2_endpoints.drain(..)
+        .map(|item| (item.0, format!("oauth2/{}", item.1))));
+    cors_endpoints.push((vec![Method::Get], "ping".to_owned()));
+
+    let cors = CORS::new(cors_endpoints);
+    chain.link_after(cors);
+    chain
 }
 
 fn start_server(addrs: Vec<SocketAddr>, chain: Chain, protocol: Protocol) {
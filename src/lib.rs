@@ -32,7 +32,7 @@ extern crate hyper;
 #[macro_use]
 extern crate iron;
 extern crate iron_cors;
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 extern crate iron_test;
 #[macro_use]
 extern crate lazy_static;
@@ -72,19 +72,26 @@ extern crate tempdir;
 #[cfg(test)]
 extern crate uuid;
 
-#[cfg(test)]
-mod stubs {
+/// In-memory stand-ins for the controller and the whole box, for tests.
+#[cfg(any(test, feature = "testing"))]
+pub mod stubs {
     #![allow(dead_code)]
     #![allow(unused_variables)]
     #![allow(boxed_local)]
     pub mod controller;
+    pub mod harness;
 }
 
 mod adapters;
+mod boot_notifier;
 pub mod controller;
 mod http_server;
+mod oauth2_router;
+pub mod profile_vault;
 pub mod registration;
+mod request_watchdog;
 mod static_router;
+mod support;
 mod taxonomy_router;
 pub mod tunnel_controller;
 mod ws_server;
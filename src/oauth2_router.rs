@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Linking the box to the accounts of cloud services, see `foxbox_core::oauth2`.
+//!
+//! - `GET /oauth2/providers` lists the providers and whether the box is linked to each;
+//! - `POST /oauth2/providers/<name>/authorize`, with an optional body
+//!   `{ "redirect_uri": "..." }`, returns `{ "url": "..." }`, the consent page to send the user
+//!   to;
+//! - `GET /oauth2/callback` is where the provider sends the user back;
+//! - `DELETE /oauth2/providers/<name>` forgets the tokens of a provider.
+
+use foxbox_core::oauth2::{OAuth2Broker, OAuth2Error};
+use foxbox_core::traits::Controller;
+use foxbox_users::AuthEndpoint;
+
+use iron::{Handler, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::status::Status;
+use serde_json;
+use serde_json::value::Value as JSON;
+use url::form_urlencoded;
+
+use std::collections::HashMap;
+use std::io::Read;
+
+pub struct OAuth2Router {
+    broker: OAuth2Broker,
+}
+
+impl OAuth2Router {
+    pub fn new(broker: OAuth2Broker) -> Self {
+        OAuth2Router { broker: broker }
+    }
+
+    fn json_response(status: Status, json: &JSON) -> IronResult<Response> {
+        let mut response = Response::with((status, serde_json::to_string(json).unwrap()));
+        response.headers.set(ContentType::json());
+        Ok(response)
+    }
+
+    fn error_response(err: &OAuth2Error) -> IronResult<Response> {
+        let status = match *err {
+            OAuth2Error::UnknownProvider(_) => Status::NotFound,
+            OAuth2Error::NotAuthorized(_) |
+            OAuth2Error::InvalidState => Status::BadRequest,
+            OAuth2Error::Provider(_) |
+            OAuth2Error::Network(_) => Status::BadGateway,
+        };
+        Ok(Response::with((status, format!("{}", err))))
+    }
+
+    fn authorize(&self, req: &mut Request, provider: &str) -> IronResult<Response> {
+        let mut body = String::new();
+        itry!(req.body.read_to_string(&mut body), Status::BadRequest);
+        let redirect_uri = if body.trim().is_empty() {
+            None
+        } else {
+            let json: JSON = itry!(serde_json::from_str(&body), Status::BadRequest);
+            json.find("redirect_uri").and_then(JSON::as_string).map(str::to_owned)
+        };
+        // By default, send the user back to the address they used to reach the box. It must be
+        // one of the redirect uris registered with the provider.
+        let redirect_uri = redirect_uri.unwrap_or_else(|| {
+            format!("{}://{}:{}/oauth2/callback",
+                    req.url.scheme(),
+                    req.url.host(),
+                    req.url.port())
+        });
+        match self.broker.begin(provider, &redirect_uri) {
+            Ok(url) => Self::json_response(Status::Ok, &json_value!({ url: url })),
+            Err(err) => Self::error_response(&err),
+        }
+    }
+
+    fn callback(&self, query: Option<&str>) -> IronResult<Response> {
+        let params: HashMap<String, String> = form_urlencoded::parse(query.unwrap_or("")
+                .as_bytes())
+            .into_owned()
+            .collect();
+        let nonce = match params.get("state") {
+            Some(nonce) => nonce,
+            None => return Self::error_response(&OAuth2Error::InvalidState),
+        };
+        if let Some(error) = params.get("error") {
+            // e.g. the user declined.
+            let provider = self.broker.cancel(nonce);
+            info!("Could not link the box to {:?}: {}", provider, error);
+            return Ok(Response::with((Status::BadRequest,
+                                      format!("The account was not linked: {}", error))));
+        }
+        let code = match params.get("code") {
+            Some(code) => code,
+            None => return Ok(Response::with((Status::BadRequest, "Missing parameter `code`"))),
+        };
+        match self.broker.complete(nonce, code) {
+            Ok(provider) => {
+                Ok(Response::with((Status::Ok,
+                                   format!("The box is now linked to your {} account. You can \
+                                            close this page.",
+                                           provider))))
+            }
+            Err(err) => Self::error_response(&err),
+        }
+    }
+}
+
+impl Handler for OAuth2Router {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let path: Vec<String> = req.url.path().iter().map(|part| part.to_string()).collect();
+        let method = req.method.clone();
+
+        // Keep these urls in sync with the endpoints in the create() method.
+        match (&method, path.len()) {
+            (&Method::Get, 1) if path[0] == "providers" => {
+                return Self::json_response(Status::Ok, &self.broker.to_json());
+            }
+            (&Method::Get, 1) if path[0] == "callback" => {
+                return self.callback(req.url.query());
+            }
+            (&Method::Delete, 2) if path[0] == "providers" => {
+                return if self.broker.revoke(&path[1]) {
+                    info!("Unlinked the box from {}", path[1]);
+                    Ok(Response::with(Status::NoContent))
+                } else {
+                    Ok(Response::with(Status::NotFound))
+                };
+            }
+            (&Method::Post, 3) if path[0] == "providers" && path[2] == "authorize" => {
+                return self.authorize(req, &path[1]);
+            }
+            _ => {}
+        }
+
+        Ok(Response::with((Status::NotFound, format!("Unknown url: {}", req.url))))
+    }
+}
+
+pub fn create<T>(controller: T) -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    let router = OAuth2Router::new(controller.get_oauth2());
+
+    let endpoints = vec![
+        (vec![Method::Get], "providers".to_owned()),
+        (vec![Method::Delete], "providers/:name".to_owned()),
+        (vec![Method::Post], "providers/:name/authorize".to_owned()),
+        (vec![Method::Get], "callback".to_owned()),
+    ];
+
+    // The provider sends the user back without the credentials of the box, the callback is
+    // protected by the `state` parameter instead.
+    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
+        endpoints[0..3]
+            .iter()
+            .map(|&(ref methods, ref path)| AuthEndpoint(methods.clone(), path.clone()))
+            .collect()
+    } else {
+        vec![]
+    };
+
+    let mut chain = Chain::new(router);
+    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    (chain, endpoints)
+}
+
+#[cfg(test)]
+describe! oauth2_router {
+    before_each {
+        use foxbox_core::oauth2::Provider;
+        use foxbox_core::traits::Controller;
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{request, response};
+        use mount::Mount;
+        use serde_json;
+        use serde_json::value::Value as JSON;
+        use stubs::controller::ControllerStub;
+
+        let controller = ControllerStub::new();
+        controller.get_oauth2().register_provider(Provider {
+            name: "cloud".to_owned(),
+            authorize_url: "https://cloud.example.com/authorize".to_owned(),
+            token_url: "https://cloud.example.com/token".to_owned(),
+            client_id: "box".to_owned(),
+            client_secret: "secret".to_owned(),
+            scopes: vec![],
+        });
+        let mut mount = Mount::new();
+        mount.mount("/oauth2", super::create(controller).0);
+    }
+
+    it "should list the providers" {
+        let response = request::get("http://localhost:3000/oauth2/providers",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response::extract_body_to_string(response),
+                   r#"[{"authorized":false,"expires_at":null,"name":"cloud"}]"#);
+    }
+
+    it "should send the user to the consent page, and back to the box" {
+        let response = request::post("http://localhost:3000/oauth2/providers/cloud/authorize",
+                                     Headers::new(),
+                                     "",
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Ok);
+        let json: JSON = serde_json::from_str(&response::extract_body_to_string(response))
+            .unwrap();
+        let url = json.find("url").and_then(JSON::as_string).unwrap();
+        assert!(url.starts_with("https://cloud.example.com/authorize?"));
+        assert!(url.contains("redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Foauth2%2Fcallback"));
+
+        let response = request::post("http://localhost:3000/oauth2/providers/nope/authorize",
+                                     Headers::new(),
+                                     "",
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::NotFound);
+    }
+
+    it "should reject callbacks without a matching authorization" {
+        let response = request::get("http://localhost:3000/oauth2/callback?code=c&state=s",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+    }
+}
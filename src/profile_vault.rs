@@ -0,0 +1,631 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Encryption of the profile at rest, so that lock codes, tokens and the like are not exposed
+//! if the SD card of the box is stolen.
+//!
+//! An encrypted profile only holds files encrypted with AES-256-GCM (`<name>.enc`) and a
+//! `vault.json` describing how to check the key. The key is either read from a key file, e.g.
+//! exported by the system keyring to a tmpfs, or derived from a passphrase with
+//! PBKDF2-SHA256.
+//!
+//! Since SQLite can't work on encrypted files, unlocking the vault decrypts the profile into a
+//! directory in RAM (`$XDG_RUNTIME_DIR/foxbox`, or `/dev/shm/foxbox`), which is then used as
+//! the profile. Changes are encrypted back to the profile by `sync`, which should be called
+//! periodically and on shutdown: anything that happened since the last `sync` is lost on a
+//! power failure. SQLite databases are copied with the backup API, so that `sync` never
+//! captures a half-written transaction.
+//!
+//! The decrypted directory and its files are only readable by the user running the box.
+
+extern crate crypto;
+
+use self::crypto::aead::{AeadDecryptor, AeadEncryptor};
+use self::crypto::aes::KeySize;
+use self::crypto::aes_gcm::AesGcm;
+use self::crypto::hmac::Hmac;
+use self::crypto::pbkdf2::pbkdf2;
+use self::crypto::sha2::Sha256;
+
+use iron::{Iron, IronResult, Request, Response};
+use iron::method::Method;
+use iron::status::Status;
+use rand::Rng;
+use rand::os::OsRng;
+use rusqlite::{Connection, DatabaseName};
+use rustc_serialize::hex::{FromHex, ToHex};
+use serde_json;
+use serde_json::value::Value as JSON;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::cmp::min;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::time::{Duration, Instant};
+
+const VAULT_FILE: &'static str = "vault.json";
+const EXTENSION: &'static str = "enc";
+const MAGIC: &'static [u8] = b"FXV1";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 20_000;
+
+/// The first bytes of SQLite databases.
+const SQLITE_HEADER: &'static [u8] = b"SQLite format 3\0";
+/// The files SQLite keeps next to a database while it is open, which `sync` doesn't need
+/// since it backs up the database itself, and the snapshots taken by `sync`.
+const TRANSIENT_SUFFIXES: &'static [&'static str] = &["-journal", "-wal", "-shm", ".snapshot"];
+
+/// The longest delay imposed after wrong passphrases, in seconds.
+const MAX_UNLOCK_DELAY: u64 = 60;
+
+/// Encrypted along with the key to check that it is the right one.
+const CHECK: &'static [u8] = b"foxbox profile";
+
+#[derive(Debug)]
+pub enum VaultError {
+    Io(io::Error),
+    /// The key file doesn't hold a key.
+    InvalidKey(String),
+    /// The key or passphrase doesn't open this vault.
+    WrongKey,
+    /// A file of the vault has been tampered with, or is damaged.
+    Corrupted(String),
+}
+
+impl From<io::Error> for VaultError {
+    fn from(err: io::Error) -> Self {
+        VaultError::Io(err)
+    }
+}
+
+/// Where the key comes from.
+pub enum KeySource {
+    /// A file holding a 256 bits key, in hexadecimal.
+    KeyFile(PathBuf),
+    /// A passphrase supplied by the user.
+    Passphrase(String),
+}
+
+impl KeySource {
+    fn kind(&self) -> &'static str {
+        match *self {
+            KeySource::KeyFile(_) => "key",
+            KeySource::Passphrase(_) => "passphrase",
+        }
+    }
+
+    fn derive(&self, salt: &[u8], iterations: u32) -> Result<[u8; KEY_LEN], VaultError> {
+        let mut key = [0u8; KEY_LEN];
+        match *self {
+            KeySource::KeyFile(ref path) => {
+                let mut source = String::new();
+                try!(File::open(path).and_then(|mut file| file.read_to_string(&mut source)));
+                let bytes = try!(source.trim()
+                    .from_hex()
+                    .map_err(|err| VaultError::InvalidKey(format!("{}", err))));
+                if bytes.len() != KEY_LEN {
+                    return Err(VaultError::InvalidKey(format!("Expected {} bytes, got {}",
+                                                              KEY_LEN,
+                                                              bytes.len())));
+                }
+                key.copy_from_slice(&bytes);
+            }
+            KeySource::Passphrase(ref passphrase) => {
+                let mut mac = Hmac::new(Sha256::new(), passphrase.as_bytes());
+                pbkdf2(&mut mac, salt, iterations, &mut key);
+            }
+        }
+        Ok(key)
+    }
+}
+
+fn encrypt(key: &[u8], aad: &[u8], plain: &[u8]) -> Result<Vec<u8>, VaultError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    try!(OsRng::new()).fill_bytes(&mut nonce);
+    let mut cipher = vec![0u8; plain.len()];
+    let mut tag = [0u8; TAG_LEN];
+    AesGcm::new(KeySize::KeySize256, key, &nonce, aad).encrypt(plain, &mut cipher, &mut tag);
+
+    let mut result = Vec::with_capacity(MAGIC.len() + NONCE_LEN + plain.len() + TAG_LEN);
+    result.extend_from_slice(MAGIC);
+    result.extend_from_slice(&nonce);
+    result.extend_from_slice(&cipher);
+    result.extend_from_slice(&tag);
+    Ok(result)
+}
+
+/// Decrypt data produced by `encrypt` with the same `key` and `aad`, or return `None`.
+fn decrypt(key: &[u8], aad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    let header = MAGIC.len() + NONCE_LEN;
+    if data.len() < header + TAG_LEN || &data[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let nonce = &data[MAGIC.len()..header];
+    let (cipher, tag) = data[header..].split_at(data.len() - header - TAG_LEN);
+    let mut plain = vec![0u8; cipher.len()];
+    if AesGcm::new(KeySize::KeySize256, key, nonce, aad).decrypt(cipher, &mut plain, tag) {
+        Some(plain)
+    } else {
+        None
+    }
+}
+
+/// The files below `dir`, relative to `dir`.
+fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fn walk(dir: &Path, relative: &Path, result: &mut Vec<PathBuf>) -> io::Result<()> {
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            let relative = relative.join(entry.file_name());
+            if try!(entry.file_type()).is_dir() {
+                try!(walk(&entry.path(), &relative, result));
+            } else {
+                result.push(relative);
+            }
+        }
+        Ok(())
+    }
+    let mut result = vec![];
+    try!(walk(dir, Path::new(""), &mut result));
+    Ok(result)
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut content = vec![];
+    try!(File::open(path).and_then(|mut file| file.read_to_end(&mut content)));
+    Ok(content)
+}
+
+/// Create `dir` and its missing parents, only accessible to the current user. An existing
+/// `dir` is made so as well.
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    try!(DirBuilder::new().recursive(true).mode(0o700).create(dir));
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+}
+
+/// Write a file so that it is never seen half-written, even after a power failure. The file
+/// (and the directories created for it) are only accessible to the current user.
+fn write_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        try!(DirBuilder::new().recursive(true).mode(0o700).create(parent));
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    {
+        let mut file = try!(OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp));
+        try!(file.write_all(content));
+        try!(file.sync_all());
+    }
+    fs::rename(tmp, path)
+}
+
+fn is_transient(relative: &Path) -> bool {
+    let name = relative.to_string_lossy();
+    TRANSIENT_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+/// Read a file of the decrypted profile. SQLite databases are read from a snapshot taken with
+/// the backup API, which is consistent even if the database is being written to.
+fn read_snapshot(path: &Path) -> Result<Vec<u8>, VaultError> {
+    let content = try!(read_file(path));
+    if !content.starts_with(SQLITE_HEADER) {
+        return Ok(content);
+    }
+    let mut snapshot = path.as_os_str().to_owned();
+    snapshot.push(".snapshot");
+    let snapshot = PathBuf::from(snapshot);
+    let result = Connection::open(path)
+        .and_then(|db| db.backup(DatabaseName::Main, &snapshot, None))
+        .map_err(|err| VaultError::Corrupted(format!("{}: {}", path.display(), err)))
+        .and_then(|_| read_file(&snapshot).map_err(VaultError::Io));
+    let _ = fs::remove_file(&snapshot);
+    result
+}
+
+pub struct Vault {
+    /// The profile, holding the encrypted files.
+    profile_dir: PathBuf,
+    /// Where the profile is decrypted.
+    plain_dir: PathBuf,
+    key: [u8; KEY_LEN],
+    /// Held while syncing, so that a periodic sync and the sync on shutdown don't overlap.
+    syncing: Mutex<()>,
+}
+
+impl Vault {
+    /// Whether the profile in `profile_dir` is encrypted.
+    pub fn exists(profile_dir: &Path) -> bool {
+        profile_dir.join(VAULT_FILE).is_file()
+    }
+
+    /// Where to decrypt profiles by default. This should be in RAM.
+    pub fn default_plain_dir() -> PathBuf {
+        match env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => PathBuf::from(dir).join("foxbox"),
+            None => PathBuf::from("/dev/shm/foxbox"),
+        }
+    }
+
+    /// The directory in which the profile is decrypted, to be used as the profile.
+    pub fn plain_dir(&self) -> &Path {
+        &self.plain_dir
+    }
+
+    /// Encrypt the (possibly empty) profile in `profile_dir`, and decrypt it to `plain_dir`.
+    ///
+    /// The plain files are only removed once the encrypted profile is complete, so that an
+    /// interruption never leaves a profile that can't be opened. Note that they are merely
+    /// removed: the flash memory of an SD card can't be reliably overwritten, so a profile with
+    /// secrets should be encrypted from the start.
+    pub fn create(profile_dir: &Path,
+                  plain_dir: &Path,
+                  source: KeySource)
+                  -> Result<Self, VaultError> {
+        let mut salt = [0u8; SALT_LEN];
+        try!(OsRng::new()).fill_bytes(&mut salt);
+        let vault = Vault {
+            profile_dir: profile_dir.to_owned(),
+            plain_dir: plain_dir.to_owned(),
+            key: try!(source.derive(&salt, PBKDF2_ITERATIONS)),
+            syncing: Mutex::new(()),
+        };
+
+        // Copy the existing profile to `plain_dir`, then encrypt it. Encrypted files left by
+        // an interrupted `create` are overwritten.
+        try!(create_private_dir(plain_dir));
+        let files: Vec<_> = try!(list_files(profile_dir))
+            .into_iter()
+            .filter(|relative| relative.extension().map_or(true, |ext| ext != EXTENSION))
+            .collect();
+        for relative in &files {
+            let content = try!(read_file(&profile_dir.join(relative)));
+            try!(write_file(&plain_dir.join(relative), &content));
+        }
+        try!(vault.sync());
+
+        let mut description = BTreeMap::new();
+        description.insert("version".to_owned(), JSON::U64(1));
+        description.insert("source".to_owned(), JSON::String(source.kind().to_owned()));
+        description.insert("salt".to_owned(), JSON::String(salt.to_hex()));
+        description.insert("iterations".to_owned(), JSON::U64(PBKDF2_ITERATIONS as u64));
+        description.insert("check".to_owned(),
+                           JSON::String(try!(encrypt(&vault.key, VAULT_FILE.as_bytes(), CHECK))
+                               .to_hex()));
+        try!(write_file(&profile_dir.join(VAULT_FILE),
+                        serde_json::to_string_pretty(&JSON::Object(description))
+                            .unwrap()
+                            .as_bytes()));
+
+        // The encrypted profile is complete, the plain files can go.
+        for relative in &files {
+            try!(fs::remove_file(profile_dir.join(relative)));
+        }
+        info!("The profile {} is now encrypted", profile_dir.display());
+        Ok(vault)
+    }
+
+    /// Decrypt the profile in `profile_dir` to `plain_dir`.
+    pub fn unlock(profile_dir: &Path,
+                  plain_dir: &Path,
+                  source: KeySource)
+                  -> Result<Self, VaultError> {
+        let corrupted = || VaultError::Corrupted(VAULT_FILE.to_owned());
+        let description = try!(read_file(&profile_dir.join(VAULT_FILE)));
+        let description: JSON = try!(serde_json::from_slice(&description)
+            .map_err(|_| corrupted()));
+        let hex = |name: &str| {
+            description.find(name)
+                .and_then(JSON::as_string)
+                .and_then(|hex| hex.from_hex().ok())
+                .ok_or_else(&corrupted)
+        };
+        let salt = try!(hex("salt"));
+        let check = try!(hex("check"));
+        let iterations = try!(description.find("iterations")
+            .and_then(JSON::as_u64)
+            .ok_or_else(&corrupted));
+
+        let vault = Vault {
+            profile_dir: profile_dir.to_owned(),
+            plain_dir: plain_dir.to_owned(),
+            key: try!(source.derive(&salt, iterations as u32)),
+            syncing: Mutex::new(()),
+        };
+        if decrypt(&vault.key, VAULT_FILE.as_bytes(), &check).as_ref().map(|c| &c[..]) !=
+           Some(CHECK) {
+            return Err(VaultError::WrongKey);
+        }
+
+        try!(create_private_dir(plain_dir));
+        for relative in try!(list_files(profile_dir)) {
+            if relative.extension().map_or(true, |ext| ext != EXTENSION) {
+                // Plain files left by an interrupted `create`, which have been encrypted.
+                if relative != Path::new(VAULT_FILE) {
+                    warn!("Removing plain file {} from the encrypted profile",
+                          relative.display());
+                    try!(fs::remove_file(profile_dir.join(&relative)));
+                }
+                continue;
+            }
+            let plain_relative = relative.with_extension("");
+            let content = try!(read_file(&profile_dir.join(&relative)));
+            let plain = try!(decrypt(&vault.key, Self::aad(&plain_relative), &content)
+                .ok_or_else(|| VaultError::Corrupted(format!("{}", relative.display()))));
+            try!(write_file(&plain_dir.join(&plain_relative), &plain));
+        }
+        info!("Unlocked the profile {} to {}",
+              profile_dir.display(),
+              plain_dir.display());
+        Ok(vault)
+    }
+
+    /// Binding each file to its path prevents swapping encrypted files.
+    fn aad(relative: &Path) -> &[u8] {
+        relative.to_str().unwrap_or("").as_bytes()
+    }
+
+    /// Encrypt the current state of the decrypted profile back to the profile.
+    pub fn sync(&self) -> Result<(), VaultError> {
+        let _syncing = self.syncing.lock().unwrap();
+        let files: Vec<_> = try!(list_files(&self.plain_dir))
+            .into_iter()
+            .filter(|relative| !is_transient(relative))
+            .collect();
+        for relative in &files {
+            let content = try!(read_snapshot(&self.plain_dir.join(relative)));
+            let encrypted = try!(encrypt(&self.key, Self::aad(relative), &content));
+            let mut target = self.profile_dir.join(relative).into_os_string();
+            target.push(".");
+            target.push(EXTENSION);
+            try!(write_file(Path::new(&target), &encrypted));
+        }
+
+        // Remove the files that have been removed from the decrypted profile.
+        for relative in try!(list_files(&self.profile_dir)) {
+            if relative.extension().map_or(false, |ext| ext == EXTENSION) &&
+               !files.contains(&relative.with_extension("")) {
+                try!(fs::remove_file(self.profile_dir.join(relative)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sync, then remove the decrypted profile.
+    pub fn lock(&self) -> Result<(), VaultError> {
+        try!(self.sync());
+        try!(fs::remove_dir_all(&self.plain_dir));
+        Ok(())
+    }
+}
+
+/// Slows down guessing passphrases: after `n` consecutive wrong passphrases, the next attempt
+/// is refused until `2^(n-1)` seconds have passed, up to `MAX_UNLOCK_DELAY`.
+#[derive(Default)]
+pub struct Backoff {
+    failures: u32,
+    until: Option<Instant>,
+}
+
+impl Backoff {
+    /// Whether an attempt is allowed at `now`, or how long to wait.
+    pub fn check(&self, now: Instant) -> Result<(), Duration> {
+        match self.until {
+            Some(until) if until > now => Err(until - now),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record a wrong passphrase, at `now`.
+    pub fn failed(&mut self, now: Instant) {
+        self.failures += 1;
+        let delay = min(1u64 << min(self.failures - 1, 16), MAX_UNLOCK_DELAY);
+        self.until = Some(now + Duration::from_secs(delay));
+    }
+}
+
+/// Serve `POST /unlock` with a body `{ "passphrase": "..." }` on `port`, until `open`
+/// accepts a passphrase. Responds with 204 on success, 403 for a wrong passphrase, and 429 if
+/// the attempt comes too soon after a wrong passphrase, see `Backoff`.
+///
+/// This runs before the box has loaded its certificates, so this is plain HTTP: to keep the
+/// passphrase off the network, it is only served on localhost, e.g. through an SSH tunnel.
+pub fn wait_for_passphrase<F>(port: u16, open: F) -> Vault
+    where F: Fn(&str) -> Result<Vault, VaultError> + Send + Sync + 'static
+{
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let unlocked = AtomicBool::new(false);
+    let backoff = Mutex::new(Backoff::default());
+    let handler = move |req: &mut Request| -> IronResult<Response> {
+        if req.method != Method::Post || req.url.path() != vec!["unlock"] {
+            return Ok(Response::with(Status::NotFound));
+        }
+        if unlocked.load(Ordering::Acquire) {
+            return Ok(Response::with(Status::Conflict));
+        }
+        let mut body = String::new();
+        itry!(req.body.read_to_string(&mut body), Status::BadRequest);
+        let json: JSON = itry!(serde_json::from_str(&body), Status::BadRequest);
+        let passphrase = match json.find("passphrase").and_then(JSON::as_string) {
+            Some(passphrase) => passphrase,
+            None => return Ok(Response::with((Status::BadRequest, "Expected a passphrase"))),
+        };
+        // Attempts are serialized, so that they can't be run in parallel either.
+        let mut backoff = backoff.lock().unwrap();
+        if let Err(wait) = backoff.check(Instant::now()) {
+            return Ok(Response::with((Status::TooManyRequests,
+                                      format!("Retry in {} seconds", wait.as_secs() + 1))));
+        }
+        match open(passphrase) {
+            Ok(vault) => {
+                unlocked.store(true, Ordering::Release);
+                tx.lock().unwrap().send(vault).unwrap();
+                Ok(Response::with(Status::NoContent))
+            }
+            Err(VaultError::WrongKey) => {
+                warn!("Wrong passphrase for the encrypted profile");
+                backoff.failed(Instant::now());
+                Ok(Response::with(Status::Forbidden))
+            }
+            Err(err) => {
+                error!("Unable to unlock the profile: {:?}", err);
+                Ok(Response::with(Status::InternalServerError))
+            }
+        }
+    };
+    let mut listening = Iron::new(handler)
+        .http(("127.0.0.1", port))
+        .unwrap_or_else(|err| panic!("Unable to listen on port {}: {}", port, err));
+    let vault = rx.recv().unwrap();
+    let _ = listening.close();
+    vault
+}
+
+#[cfg(test)]
+describe! profile_vault {
+    before_each {
+        use super::*;
+        use std::fs::{self, File};
+        use std::io::Write;
+        use tempdir::TempDir;
+
+        let profile = TempDir::new("profile").unwrap();
+        let plain = TempDir::new("plain").unwrap();
+        fs::create_dir(profile.path().join("certs")).unwrap();
+        File::create(profile.path().join("certs").join("box.pem"))
+            .unwrap()
+            .write_all(b"secret")
+            .unwrap();
+        let passphrase = || KeySource::Passphrase("correct horse".to_owned());
+    }
+
+    it "should encrypt and decrypt a profile" {
+        let vault = Vault::create(profile.path(), plain.path(), passphrase()).unwrap();
+        assert!(Vault::exists(profile.path()));
+        assert!(!profile.path().join("certs").join("box.pem").exists());
+        assert!(profile.path().join("certs").join("box.pem.enc").exists());
+
+        File::create(plain.path().join("users.sqlite")).unwrap().write_all(b"users").unwrap();
+        vault.lock().unwrap();
+        assert!(!plain.path().exists());
+
+        let wrong = KeySource::Passphrase("wrong horse".to_owned());
+        match Vault::unlock(profile.path(), plain.path(), wrong) {
+            Err(VaultError::WrongKey) => {}
+            _ => panic!("The vault should not open with a wrong passphrase"),
+        }
+
+        use std::io::Read;
+
+        let vault = Vault::unlock(profile.path(), plain.path(), passphrase()).unwrap();
+        let mut content = String::new();
+        File::open(vault.plain_dir().join("users.sqlite"))
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "users");
+    }
+
+    it "should detect tampering" {
+        let vault = Vault::create(profile.path(), plain.path(), passphrase()).unwrap();
+        vault.lock().unwrap();
+        fs::rename(profile.path().join("certs").join("box.pem.enc"),
+                   profile.path().join("certs").join("other.pem.enc"))
+            .unwrap();
+        match Vault::unlock(profile.path(), plain.path(), passphrase()) {
+            Err(VaultError::Corrupted(_)) => {}
+            _ => panic!("Swapped files should be detected"),
+        }
+    }
+
+    it "should only let the current user read the decrypted profile" {
+        use std::os::unix::fs::PermissionsExt;
+        use std::path::Path;
+
+        let plain_dir = plain.path().join("decrypted");
+        let vault = Vault::create(profile.path(), &plain_dir, passphrase()).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&plain_dir), 0o700);
+        assert_eq!(mode(&plain_dir.join("certs")), 0o700);
+        assert_eq!(mode(&plain_dir.join("certs").join("box.pem")), 0o600);
+        vault.lock().unwrap();
+    }
+
+    it "should keep a consistent copy of databases" {
+        use rusqlite::Connection;
+        use std::io::Read;
+
+        let vault = Vault::create(profile.path(), plain.path(), passphrase()).unwrap();
+        let db = Connection::open(plain.path().join("users.sqlite")).unwrap();
+        db.execute("CREATE TABLE users (name TEXT)", &[]).unwrap();
+        db.execute("INSERT INTO users VALUES ('alice')", &[]).unwrap();
+        // Uncommitted changes are not saved.
+        db.execute("BEGIN", &[]).unwrap();
+        db.execute("INSERT INTO users VALUES ('bob')", &[]).unwrap();
+        vault.sync().unwrap();
+        assert!(!profile.path().join("users.sqlite-journal.enc").exists());
+        db.execute("ROLLBACK", &[]).unwrap();
+        drop(db);
+        vault.lock().unwrap();
+
+        let vault = Vault::unlock(profile.path(), plain.path(), passphrase()).unwrap();
+        let db = Connection::open(vault.plain_dir().join("users.sqlite")).unwrap();
+        let count: i64 = db.query_row("SELECT COUNT(*) FROM users", &[], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        let mut content = vec![];
+        File::open(vault.plain_dir().join("certs").join("box.pem"))
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"secret");
+    }
+
+    it "should slow down guessing passphrases" {
+        use std::time::{Duration, Instant};
+
+        let mut backoff = Backoff::default();
+        let now = Instant::now();
+        assert!(backoff.check(now).is_ok());
+        backoff.failed(now);
+        assert_eq!(backoff.check(now), Err(Duration::from_secs(1)));
+        assert!(backoff.check(now + Duration::from_secs(1)).is_ok());
+        backoff.failed(now);
+        backoff.failed(now);
+        assert_eq!(backoff.check(now), Err(Duration::from_secs(4)));
+        for _ in 0..20 {
+            backoff.failed(now);
+        }
+        assert_eq!(backoff.check(now), Err(Duration::from_secs(60)));
+    }
+
+    it "should read keys from a key file" {
+        let key_path = plain.path().join("key");
+        File::create(&key_path).unwrap().write_all(&[b'a'; 64]).unwrap();
+        let vault = Vault::create(profile.path(),
+                                  &plain.path().join("decrypted"),
+                                  KeySource::KeyFile(key_path.clone()));
+        assert!(vault.is_ok());
+
+        File::create(&key_path).unwrap().write_all(b"not a key").unwrap();
+        match Vault::create(profile.path(), plain.path(), KeySource::KeyFile(key_path)) {
+            Err(VaultError::InvalidKey(_)) => {}
+            _ => panic!("An invalid key should be rejected"),
+        }
+    }
+}
@@ -13,16 +13,90 @@ use self::hyper::Client;
 use self::hyper::header::Connection;
 use self::hyper::status::StatusCode;
 use self::get_if_addrs::{IfAddr, Interface};
+use foxbox_core::config_store::{ConfigService, ConfigType};
 use foxbox_core::traits::Controller;
 use serde_json;
 use std::io::Read;
 use std::time::Duration;
 use std::thread;
-use tls::{CertificateManager, DnsRecord, get_san_cert_for, register_dns_record};
+use tls::{AcmeChallenge, CertificateManager, DnsConfig, DnsProvider, DnsRecord, get_san_cert_for};
 use tunnel_controller::Tunnel;
 
 const REGISTRATION_INTERVAL_IN_MINUTES: u32 = 1;
 
+/// Whether `foxbox.acme_challenge` selects the http-01 challenge rather than the default
+/// dns-01 one, for boxes that are reachable on port 80 without the DNS API.
+pub fn uses_http_challenge(config: &ConfigService) -> bool {
+    match config.get_or_set_default("foxbox", "acme_challenge", "dns-01").as_str() {
+        "http-01" => true,
+        "dns-01" => false,
+        other => {
+            warn!("Unknown foxbox.acme_challenge {}, using dns-01", other);
+            false
+        }
+    }
+}
+
+/// Where to publish the names of the box, following `dns.provider`: "knilxof" (the default)
+/// for the DNS API at `dns_api_endpoint`, or "cloudflare" or "route53" for a domain of the
+/// user's own, see `--dns-domain`. The credentials of the provider are properties of the `dns`
+/// namespace, and the keys are kept as secrets. They are read when the box starts.
+pub fn dns_config(config: &ConfigService, dns_api_endpoint: &str) -> DnsConfig {
+    for property in &["provider",
+                      "cloudflare_email",
+                      "cloudflare_zone_id",
+                      "route53_access_key_id",
+                      "route53_hosted_zone_id"] {
+        config.register("dns", property, ConfigType::String);
+    }
+    for property in &["cloudflare_api_key", "route53_secret_access_key"] {
+        config.register("dns", property, ConfigType::Secret);
+    }
+
+    let default = DnsConfig::Knilxof { api_endpoint: dns_api_endpoint.to_owned() };
+    let get = |property| config.get("dns", property);
+    let provider = config.get_or_set_default("dns", "provider", "knilxof");
+    let dns_config = match provider.as_str() {
+        "knilxof" => return default,
+        "cloudflare" => {
+            match (get("cloudflare_email"), get("cloudflare_api_key"), get("cloudflare_zone_id")) {
+                (Some(email), Some(api_key), Some(zone_id)) => {
+                    Some(DnsConfig::Cloudflare {
+                        email: email,
+                        api_key: api_key,
+                        zone_id: zone_id,
+                    })
+                }
+                _ => None,
+            }
+        }
+        "route53" => {
+            match (get("route53_access_key_id"),
+                   get("route53_secret_access_key"),
+                   get("route53_hosted_zone_id")) {
+                (Some(access_key_id), Some(secret_access_key), Some(hosted_zone_id)) => {
+                    Some(DnsConfig::Route53 {
+                        access_key_id: access_key_id,
+                        secret_access_key: secret_access_key,
+                        hosted_zone_id: hosted_zone_id,
+                    })
+                }
+                _ => None,
+            }
+        }
+        other => {
+            warn!("Unknown dns.provider {}, using {}", other, dns_api_endpoint);
+            return default;
+        }
+    };
+    dns_config.unwrap_or_else(|| {
+        error!("dns.provider {} is missing credentials, using {}",
+               provider,
+               dns_api_endpoint);
+        default
+    })
+}
+
 pub struct Registrar {
     certificate_manager: CertificateManager,
     registration_endpoint: String,
@@ -104,19 +178,18 @@ impl Registrar {
     /// names (local.<fingerprint>.box.knilxof.org and
     /// remote.<fingerprint>.box.knilxof.org).  The remote name (tunnel name), is
     /// only configured if the tunnel_frontend option is non-None.
-    fn register_with_dns_server(&self, ip_addr: String, tunnel_frontend: Option<String>) {
-        let client_certificate = self.certificate_manager.get_box_certificate().unwrap();
-
+    fn register_with_dns_server(&self,
+                                dns_provider: &DnsProvider,
+                                ip_addr: String,
+                                tunnel_frontend: Option<String>) {
         let local_name = self.certificate_manager.get_local_dns_name();
         // Create entry for local DNS
         info!("DNS server: Creating DNS entry for {}", local_name);
-        let result = register_dns_record(client_certificate.clone(),
-                                         &DnsRecord {
-                                             record_type: "A",
-                                             name: &local_name,
-                                             value: &ip_addr,
-                                         },
-                                         &self.dns_api_endpoint.clone());
+        let result = dns_provider.register(&DnsRecord {
+            record_type: "A",
+            name: &local_name,
+            value: &ip_addr,
+        });
 
         if result.is_err() {
             warn!("DNS server: Could not create DNS entry for {}", local_name);
@@ -125,13 +198,11 @@ impl Registrar {
         if let Some(tunnel_frontend) = tunnel_frontend {
             let remote_name = self.certificate_manager.get_remote_dns_name();
             info!("DNS server: Creating DNS entry for {}", remote_name);
-            let result = register_dns_record(client_certificate.clone(),
-                                             &DnsRecord {
-                                                 record_type: "CNAME",
-                                                 name: &remote_name,
-                                                 value: &tunnel_frontend,
-                                             },
-                                             &self.dns_api_endpoint.clone());
+            let result = dns_provider.register(&DnsRecord {
+                record_type: "CNAME",
+                name: &remote_name,
+                value: &tunnel_frontend,
+            });
 
             if result.is_err() {
                 warn!("DNS server: Could not create DNS entry for {}", remote_name);
@@ -139,7 +210,7 @@ impl Registrar {
         }
     }
 
-    fn register_certificates(&self) {
+    fn register_certificates(&self, challenge: AcmeChallenge) {
         if self.certificate_manager
             .get_certificate(&self.certificate_manager.get_local_dns_name())
             .is_none() {
@@ -148,7 +219,7 @@ impl Registrar {
             info!("Getting/renewing LetsEncrypt certificate for: {:?}", domains);
             let rx = get_san_cert_for(domains.into_iter(),
                                       self.certificate_manager.clone(),
-                                      self.dns_api_endpoint.clone());
+                                      challenge);
 
             rx.recv().unwrap().unwrap();
             self.certificate_manager.reload().unwrap();
@@ -180,6 +251,12 @@ impl Registrar {
         let enabled_tls = controller.get_tls_enabled();
 
         let http_scheme = if enabled_tls { "https" } else { "http" };
+        let dns_config = dns_config(&controller.get_config(), &self.dns_api_endpoint);
+        let challenge = if uses_http_challenge(&controller.get_config()) {
+            AcmeChallenge::Http01
+        } else {
+            AcmeChallenge::Dns01 { dns: dns_config.clone() }
+        };
 
         // Spawn a thread to register every REGISTRATION_INTERVAL_IN_MINUTES.
         thread::Builder::new()
@@ -188,9 +265,11 @@ impl Registrar {
                 let tunnel_configured = tunnel_frontend.clone().is_some();
 
                 if enabled_tls {
-                    self.register_certificates();
+                    self.register_certificates(challenge);
                 }
 
+                let dns_provider = dns_config.provider(&self.certificate_manager).unwrap();
+
                 loop {
                     // TODO: If the ip address changes, we need to update the dns server and
                     // registration server with the new IP address.
@@ -199,7 +278,8 @@ impl Registrar {
                                                            http_scheme,
                                                            box_port,
                                                            tunnel_configured);
-                    self.register_with_dns_server(ip_addr.clone().unwrap(),
+                    self.register_with_dns_server(&*dns_provider,
+                                                  ip_addr.clone().unwrap(),
                                                   tunnel_frontend.clone());
 
                     // Go to sleep.
@@ -275,6 +355,48 @@ impl Registrar {
     }
 }
 
+#[cfg(test)]
+describe! dns_provider_config {
+    before_each {
+        use foxbox_core::config_store::{ConfigService, ConfigType};
+        use tempdir::TempDir;
+        use tls::DnsConfig;
+
+        let dir = TempDir::new("dns_config").unwrap();
+        let config = ConfigService::new(dir.path().join("foxbox.conf").to_str().unwrap());
+        let knilxof = DnsConfig::Knilxof {
+            api_endpoint: "https://knilxof.org:5300".to_owned()
+        };
+    }
+
+    it "should default to the DNS API" {
+        assert_eq!(dns_config(&config, "https://knilxof.org:5300"), knilxof);
+        assert_eq!(config.get_type("dns", "cloudflare_api_key"), Some(ConfigType::Secret));
+        assert_eq!(config.get_type("dns", "route53_secret_access_key"), Some(ConfigType::Secret));
+    }
+
+    it "should read the credentials of Cloudflare" {
+        config.set("dns", "provider", "cloudflare");
+        config.set("dns", "cloudflare_email", "admin@example.org");
+        config.set("dns", "cloudflare_api_key", "c2547eb745079dac9320b638f5e225cf483cc5cfdda41");
+        config.set("dns", "cloudflare_zone_id", "023e105f4ecef8ad9ca31a8372d0c353");
+        assert_eq!(dns_config(&config, "https://knilxof.org:5300"), DnsConfig::Cloudflare {
+            email: "admin@example.org".to_owned(),
+            api_key: "c2547eb745079dac9320b638f5e225cf483cc5cfdda41".to_owned(),
+            zone_id: "023e105f4ecef8ad9ca31a8372d0c353".to_owned()
+        });
+    }
+
+    it "should fall back to the DNS API without credentials" {
+        config.set("dns", "provider", "route53");
+        config.set("dns", "route53_access_key_id", "AKIDEXAMPLE");
+        assert_eq!(dns_config(&config, "https://knilxof.org:5300"), knilxof);
+
+        config.set("dns", "provider", "unknown");
+        assert_eq!(dns_config(&config, "https://knilxof.org:5300"), knilxof);
+    }
+}
+
 #[cfg(test)]
 describe! registrar {
 
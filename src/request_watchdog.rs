@@ -0,0 +1,267 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Keeps an eye on the HTTP requests in progress.
+//!
+//! The HTTP server handles requests on a fixed number of threads, so an adapter that stops
+//! answering ends up holding all of them, one hung request after the other. The watchdog logs
+//! the requests that have been running for too long, along with the calls to adapters in
+//! progress, and answers new requests with a 503 once too many are in progress, so that
+//! clients back off instead of piling up.
+
+use foxbox_taxonomy::metrics::AdapterMetrics;
+use iron::{AroundMiddleware, Handler, IronResult, Request, Response, Set};
+use iron::modifiers::Header;
+use iron::status::Status;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often we look for stuck requests.
+const CHECK_INTERVAL_S: u64 = 5;
+
+/// How long clients are asked to wait before retrying a request that was shed.
+const RETRY_AFTER_S: u64 = 5;
+
+header! { (RetryAfter, "Retry-After") => [u64] }
+
+#[derive(Clone, Copy, Debug)]
+pub struct WatchdogLimits {
+    /// New requests are rejected while this many requests are in progress.
+    pub max_in_flight: usize,
+    /// Requests running for longer than this are logged.
+    pub stuck_after: Duration,
+}
+
+struct Pending {
+    method: String,
+    path: String,
+    start: Instant,
+    /// Whether the request was already logged as stuck.
+    reported: bool,
+}
+
+#[derive(Default)]
+struct InFlight {
+    counter: usize,
+    requests: HashMap<usize, Pending>,
+}
+
+impl InFlight {
+    /// The requests that have been running for longer than `stuck_after` at `now`, and that
+    /// weren't reported yet, as `(method, path, running for)`.
+    fn take_stuck(&mut self,
+                  now: Instant,
+                  stuck_after: Duration)
+                  -> Vec<(String, String, Duration)> {
+        self.requests
+            .values_mut()
+            .filter(|pending| {
+                !pending.reported && now.duration_since(pending.start) >= stuck_after
+            })
+            .map(|pending| {
+                pending.reported = true;
+                (pending.method.clone(), pending.path.clone(), now.duration_since(pending.start))
+            })
+            .collect()
+    }
+}
+
+/// Middleware tracking the requests in progress, see the module documentation.
+pub struct RequestWatchdog {
+    in_flight: Arc<Mutex<InFlight>>,
+    limits: WatchdogLimits,
+}
+
+impl RequestWatchdog {
+    /// Start looking for stuck requests, until the handler wrapped by the watchdog is dropped.
+    /// The calls in progress are read from `metrics`.
+    pub fn new(limits: WatchdogLimits, metrics: Arc<AdapterMetrics>) -> Self {
+        let in_flight = Arc::new(Mutex::new(InFlight::default()));
+        Self::spawn_monitor(Arc::downgrade(&in_flight), limits.stuck_after, metrics);
+        RequestWatchdog {
+            in_flight: in_flight,
+            limits: limits,
+        }
+    }
+
+    fn spawn_monitor(in_flight: Weak<Mutex<InFlight>>,
+                     stuck_after: Duration,
+                     metrics: Arc<AdapterMetrics>) {
+        thread::Builder::new()
+            .name("RequestWatchdog".to_owned())
+            .spawn(move || {
+                loop {
+                    thread::sleep(Duration::from_secs(CHECK_INTERVAL_S));
+                    let in_flight = match in_flight.upgrade() {
+                        Some(in_flight) => in_flight,
+                        None => return, // The server has been dropped.
+                    };
+                    let stuck = in_flight.lock().unwrap().take_stuck(Instant::now(), stuck_after);
+                    if stuck.is_empty() {
+                        continue;
+                    }
+                    let calls = metrics.pending_calls();
+                    for (method, path, running) in stuck {
+                        // The calls that started during the request may be the culprits.
+                        let culprits: Vec<_> = calls.iter()
+                            .filter(|&&(_, _, elapsed)| elapsed <= running)
+                            .map(|&(ref adapter, ref operation, elapsed)| {
+                                format!("{} {} for {}s", adapter, operation, elapsed.as_secs())
+                            })
+                            .collect();
+                        warn!("Request {} {} stuck for {}s, calls to adapters in progress: {:?}",
+                              method,
+                              path,
+                              running.as_secs(),
+                              culprits);
+                    }
+                }
+            })
+            .unwrap();
+    }
+}
+
+impl AroundMiddleware for RequestWatchdog {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(WatchedHandler {
+            handler: handler,
+            in_flight: self.in_flight,
+            limits: self.limits,
+        })
+    }
+}
+
+struct WatchedHandler {
+    handler: Box<Handler>,
+    in_flight: Arc<Mutex<InFlight>>,
+    limits: WatchdogLimits,
+}
+
+/// Forgets a request once it has been handled, even if the handler panicked.
+struct InFlightGuard<'a> {
+    in_flight: &'a Mutex<InFlight>,
+    key: usize,
+}
+
+impl<'a> Drop for InFlightGuard<'a> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().requests.remove(&self.key);
+    }
+}
+
+impl Handler for WatchedHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let key = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if in_flight.requests.len() >= self.limits.max_in_flight {
+                warn!("Shedding {} {}: {} requests in progress",
+                      req.method,
+                      req.url,
+                      in_flight.requests.len());
+                let mut response = Response::with((Status::ServiceUnavailable,
+                                                   "Too many requests in progress"));
+                response.set_mut(Header(RetryAfter(RETRY_AFTER_S)));
+                return Ok(response);
+            }
+            in_flight.counter += 1;
+            let key = in_flight.counter;
+            in_flight.requests.insert(key,
+                                      Pending {
+                                          method: req.method.to_string(),
+                                          path: req.url.path().join("/"),
+                                          start: Instant::now(),
+                                          reported: false,
+                                      });
+            key
+        };
+        let _guard = InFlightGuard {
+            in_flight: &self.in_flight,
+            key: key,
+        };
+        self.handler.handle(req)
+    }
+}
+
+#[cfg(test)]
+describe! request_watchdog {
+    before_each {
+        use super::*;
+        use std::time::{Duration, Instant};
+    }
+
+    it "should report stuck requests once" {
+        use super::{InFlight, Pending};
+
+        let start = Instant::now();
+        let mut in_flight = InFlight::default();
+        for (key, path) in vec![(1, "api/v1/channels/set"), (2, "ping")] {
+            in_flight.requests.insert(key,
+                                      Pending {
+                                          method: "PUT".to_owned(),
+                                          path: path.to_owned(),
+                                          start: start + Duration::from_secs(key as u64 * 10),
+                                          reported: false,
+                                      });
+        }
+        let stuck_after = Duration::from_secs(30);
+        assert!(in_flight.take_stuck(start + Duration::from_secs(35), stuck_after).is_empty());
+        let stuck = in_flight.take_stuck(start + Duration::from_secs(45), stuck_after);
+        assert_eq!(stuck,
+                   vec![("PUT".to_owned(), "api/v1/channels/set".to_owned(),
+                         Duration::from_secs(35))]);
+        assert!(in_flight.take_stuck(start + Duration::from_secs(45), stuck_after).is_empty());
+        assert_eq!(in_flight.take_stuck(start + Duration::from_secs(60), stuck_after).len(), 1);
+    }
+
+    it "should shed requests while too many are in progress" {
+        use foxbox_taxonomy::metrics::AdapterMetrics;
+        use iron::{AroundMiddleware, Handler, Headers, IronResult, Request, Response};
+        use iron::status::Status;
+        use iron_test::request;
+        use std::sync::{mpsc, Arc, Mutex};
+        use std::thread;
+
+        struct Blocking(Mutex<mpsc::Receiver<()>>);
+        impl Handler for Blocking {
+            fn handle(&self, _: &mut Request) -> IronResult<Response> {
+                let _ = self.0.lock().unwrap().recv();
+                Ok(Response::with(Status::Ok))
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let limits = WatchdogLimits {
+            max_in_flight: 1,
+            stuck_after: Duration::from_secs(30),
+        };
+        let watchdog = RequestWatchdog::new(limits, Arc::new(AdapterMetrics::new()));
+        let in_flight = watchdog.in_flight.clone();
+        let handler: Arc<Box<Handler>> =
+            Arc::new(watchdog.around(Box::new(Blocking(Mutex::new(rx)))));
+
+        let blocked = {
+            let handler = handler.clone();
+            thread::spawn(move || {
+                request::get("http://localhost:3000/slow", Headers::new(), &*handler)
+                    .unwrap()
+                    .status
+            })
+        };
+        while in_flight.lock().unwrap().requests.is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let response = request::get("http://localhost:3000/fast", Headers::new(), &*handler)
+            .unwrap();
+        assert_eq!(response.status, Some(Status::ServiceUnavailable));
+        assert_eq!(response.headers.get_raw("Retry-After"), Some(&[b"5".to_vec()][..]));
+
+        tx.send(()).unwrap();
+        assert_eq!(blocked.join().unwrap(), Some(Status::Ok));
+        assert!(in_flight.lock().unwrap().requests.is_empty());
+    }
+}
@@ -5,9 +5,18 @@
 extern crate rand;
 
 use foxbox_core::config_store::ConfigService;
+use foxbox_core::adapter_status::AdapterStatuses;
+use foxbox_core::app_registry::AppRegistry;
+use foxbox_core::modes::ModeRegistry;
+use foxbox_core::log_buffer::LogBuffer;
+use foxbox_core::oauth2::OAuth2Broker;
 use foxbox_core::profile_service::{ProfilePath, ProfileService};
+use foxbox_core::thread_pool::ThreadPool;
+use foxbox_core::timeline::Timeline;
 use foxbox_core::traits::Controller;
 use foxbox_core::upnp::UpnpManager;
+use foxbox_core::watch_sets::WatchSetRegistry;
+use foxbox_core::ws_trace::WsTraces;
 use foxbox_users::UsersManager;
 use std::vec::IntoIter;
 use serde_json;
@@ -15,7 +24,7 @@ use std::io;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::AtomicBool;
 use tls::{CertificateManager, CertificateRecord, SniSslContextProvider};
 use ws;
@@ -24,17 +33,44 @@ use ws;
 pub struct ControllerStub {
     pub config: Arc<ConfigService>,
     profile_service: Arc<ProfileService>,
+    timeline: Timeline,
+    thread_pool: ThreadPool,
+    log: LogBuffer,
+    adapter_statuses: AdapterStatuses,
+    app_registry: AppRegistry,
+    modes: ModeRegistry,
+    oauth2: OAuth2Broker,
+    watch_sets: WatchSetRegistry,
+    /// What was broadcast to the websockets, oldest first.
+    ws_frames: Arc<Mutex<Vec<serde_json::value::Value>>>,
+    ws_traces: WsTraces,
 }
 
 impl ControllerStub {
     pub fn new() -> Self {
         let path = format!("/tmp/{}", rand::random::<i32>());
         let profile_service = ProfileService::new(ProfilePath::Custom(path));
+        let ws_traces = WsTraces::new(PathBuf::from(profile_service.path_for("ws_traces")));
         ControllerStub {
             config: Arc::new(ConfigService::new(&profile_service.path_for("foxbox.conf"))),
             profile_service: Arc::new(profile_service),
+            timeline: Timeline::new(100),
+            thread_pool: ThreadPool::new("StubWorker", 2, 100),
+            log: LogBuffer::new(100),
+            adapter_statuses: AdapterStatuses::new(),
+            app_registry: AppRegistry::new(None),
+            modes: ModeRegistry::new(None),
+            oauth2: OAuth2Broker::new(None),
+            watch_sets: WatchSetRegistry::new(None),
+            ws_frames: Arc::new(Mutex::new(vec![])),
+            ws_traces: ws_traces,
         }
     }
+
+    /// Remove and return what was broadcast to the websockets since the last call.
+    pub fn take_ws_frames(&self) -> Vec<serde_json::value::Value> {
+        self.ws_frames.lock().unwrap().drain(..).collect()
+    }
 }
 
 impl Default for ControllerStub {
@@ -45,8 +81,13 @@ impl Default for ControllerStub {
 
 impl Controller for ControllerStub {
     fn run(&mut self, _: &AtomicBool) {}
-    fn adapter_started(&self, _: String) {}
-    fn adapter_notification(&self, _: serde_json::value::Value) {}
+    fn adapter_started(&self, adapter: String) {
+        self.broadcast_to_websockets(json_value!({ type: "core/adapter/start", name: adapter }));
+    }
+    fn adapter_notification(&self, notification: serde_json::value::Value) {
+        self.broadcast_to_websockets(json_value!({ type: "core/adapter/notification",
+                                                   message: notification }));
+    }
     fn http_as_addrs(&self) -> Result<IntoIter<SocketAddr>, io::Error> {
         ("localhost", 3000).to_socket_addrs()
     }
@@ -55,9 +96,14 @@ impl Controller for ControllerStub {
         ("localhost", 4000).to_socket_addrs()
     }
 
-    fn add_websocket(&mut self, socket: ws::Sender) {}
+    fn add_websocket(&mut self, socket: ws::Sender, binary: bool) {}
     fn remove_websocket(&mut self, socket: ws::Sender) {}
-    fn broadcast_to_websockets(&self, data: serde_json::value::Value) {}
+    fn broadcast_to_websockets(&self, data: serde_json::value::Value) {
+        self.ws_frames.lock().unwrap().push(data);
+    }
+    fn get_ws_traces(&self) -> WsTraces {
+        self.ws_traces.clone()
+    }
 
     fn get_config(&self) -> Arc<ConfigService> {
         self.config.clone()
@@ -71,6 +117,30 @@ impl Controller for ControllerStub {
     fn get_profile(&self) -> &ProfileService {
         &self.profile_service
     }
+    fn get_timeline(&self) -> Timeline {
+        self.timeline.clone()
+    }
+    fn get_log(&self) -> LogBuffer {
+        self.log.clone()
+    }
+    fn get_thread_pool(&self) -> ThreadPool {
+        self.thread_pool.clone()
+    }
+    fn get_adapter_statuses(&self) -> AdapterStatuses {
+        self.adapter_statuses.clone()
+    }
+    fn get_app_registry(&self) -> AppRegistry {
+        self.app_registry.clone()
+    }
+    fn get_modes(&self) -> ModeRegistry {
+        self.modes.clone()
+    }
+    fn get_oauth2(&self) -> OAuth2Broker {
+        self.oauth2.clone()
+    }
+    fn get_watch_sets(&self) -> WatchSetRegistry {
+        self.watch_sets.clone()
+    }
     fn get_tls_enabled(&self) -> bool {
         false
     }
@@ -0,0 +1,218 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A box running in memory, to test routes and adapters end to end without sockets or
+//! hardware: a `ControllerStub`, an `AdapterManager` that fake adapters can join, and all the
+//! HTTP routes, called through `iron_test`.
+//!
+//! ```ignore
+//! let harness = Harness::new();
+//! let light = harness.add_fake_adapter("light@test");
+//! // ... add services and channels through `harness.manager` ...
+//! let (status, body) = harness.request(Method::Get, "/api/v1/channels", "", false);
+//! ```
+//!
+//! Outside of this crate, the harness is available with the `testing` feature.
+
+use http_server;
+use stubs::controller::ControllerStub;
+
+use foxbox_taxonomy::fake_adapter::{Effect, FakeAdapter, Tweak};
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_taxonomy::services::{AdapterId, Id};
+use iron::{Chain, Headers};
+use iron::headers::{Authorization, Bearer, ContentType};
+use iron::method::Method;
+use iron::status::Status;
+use iron_test::{request, response};
+use serde_json;
+use serde_json::value::Value as JSON;
+use transformable_channels::mpsc::Receiver;
+
+use std::sync::{Arc, Mutex};
+
+static BASE_URL: &'static str = "http://localhost:3000";
+
+/// A fake adapter registered in the harness.
+pub struct FakeDevice {
+    /// Inject values and errors in the channels of the adapter.
+    pub tweak: Arc<Fn(Tweak) + Sync + Send>,
+    /// The values sent to the channels of the adapter.
+    pub effects: Receiver<Effect>,
+}
+
+pub struct Harness {
+    pub controller: ControllerStub,
+    pub manager: Arc<AdapterManager>,
+    chain: Chain,
+    /// The session token of the admin, once signed in.
+    token: Mutex<Option<String>>,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        let controller = ControllerStub::new();
+        let manager = Arc::new(AdapterManager::new(None));
+        Harness {
+            chain: http_server::create_chain(controller.clone(), &manager),
+            controller: controller,
+            manager: manager,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Register a `FakeAdapter`. Its services and channels are added through `manager`.
+    pub fn add_fake_adapter(&self, id: &str) -> FakeDevice {
+        let adapter = FakeAdapter::new(&Id::<AdapterId>::new(id));
+        let device = FakeDevice {
+            tweak: adapter.get_tweak(),
+            effects: adapter.take_rx(),
+        };
+        self.manager.add_adapter(Arc::new(adapter)).unwrap();
+        device
+    }
+
+    /// The session token of the admin of the box, created by the setup flow on first use.
+    pub fn token(&self) -> String {
+        let mut token = self.token.lock().unwrap();
+        if let Some(ref token) = *token {
+            return token.clone();
+        }
+        let body = r#"{"name": "admin", "email": "admin@example.org",
+                       "password": "harness password"}"#;
+        let (status, json) = self.request_json(Method::Post, "/users/v1/setup", body, false);
+        assert_eq!(status, Status::Created, "Setup failed: {:?}", json);
+        let session_token = json.find("session_token")
+            .and_then(JSON::as_string)
+            .expect("Missing session token")
+            .to_owned();
+        *token = Some(session_token.clone());
+        session_token
+    }
+
+    /// Call a route, e.g. `/api/v1/services`, with the token of the admin if `authenticated`.
+    /// Returns the status and the body of the response.
+    pub fn request(&self,
+                   method: Method,
+                   path: &str,
+                   body: &str,
+                   authenticated: bool)
+                   -> (Status, String) {
+        let token = if authenticated { Some(self.token()) } else { None };
+        self.request_with_token(method, path, body, token)
+    }
+
+    /// Call a route with another bearer token than the one of the admin, e.g. the token of a
+    /// support session.
+    pub fn request_with_token(&self,
+                              method: Method,
+                              path: &str,
+                              body: &str,
+                              token: Option<String>)
+                              -> (Status, String) {
+        let mut headers = Headers::new();
+        if let Some(token) = token {
+            headers.set(Authorization(Bearer { token: token }));
+        }
+        if !body.is_empty() {
+            headers.set(ContentType::json());
+        }
+        let url = format!("{}{}", BASE_URL, path);
+        let result = match method {
+            Method::Get => request::get(&url, headers, &self.chain),
+            Method::Post => request::post(&url, headers, body, &self.chain),
+            Method::Put => request::put(&url, headers, body, &self.chain),
+            Method::Delete => request::delete(&url, headers, &self.chain),
+            method => panic!("Unsupported method {}", method),
+        };
+        // Middlewares report some failures, e.g. 401, as errors.
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => err.response,
+        };
+        let status = response.status.unwrap_or(Status::InternalServerError);
+        (status, response::extract_body_to_string(response))
+    }
+
+    /// Call a route answering with JSON.
+    pub fn request_json(&self,
+                        method: Method,
+                        path: &str,
+                        body: &str,
+                        authenticated: bool)
+                        -> (Status, JSON) {
+        let (status, body) = self.request(method, path, body, authenticated);
+        let json = serde_json::from_str(&body)
+            .unwrap_or_else(|err| panic!("Invalid JSON {:?}: {}", body, err));
+        (status, json)
+    }
+
+    /// Remove and return the messages broadcast to the websockets since the last call.
+    pub fn take_ws_frames(&self) -> Vec<JSON> {
+        self.controller.take_ws_frames()
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Harness::new()
+    }
+}
+
+#[cfg(test)]
+describe! harness {
+    before_each {
+        use super::*;
+        use foxbox_core::traits::Controller;
+        use foxbox_taxonomy::channel::*;
+        use foxbox_taxonomy::fake_adapter::Effect;
+        use foxbox_taxonomy::services::*;
+        use foxbox_taxonomy::values::OnOff;
+        use iron::method::Method;
+        use iron::status::Status;
+
+        let harness = Harness::new();
+        let device = harness.add_fake_adapter("light@test");
+        let adapter_id = Id::<AdapterId>::new("light@test");
+        let service_id = Id::<ServiceId>::new("service:light@test");
+        harness.manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+        harness.manager
+            .add_channel(Channel {
+                id: Id::new("setter:light@test"),
+                service: service_id,
+                adapter: adapter_id,
+                ..LIGHT_IS_ON.clone()
+            })
+            .unwrap();
+    }
+
+    it "should route requests to the fake adapters" {
+        let (status, json) = harness.request_json(Method::Get, "/api/v1/channels", "", false);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(json.as_array().map(|channels| channels.len()), Some(1));
+
+        let body = r#"[{"select": [{"id": "setter:light@test", "feature": "light/is-on"}],
+                        "value": "On"}]"#;
+        let (status, _) = harness.request(Method::Put, "/api/v1/channels/set", body, false);
+        assert_eq!(status, Status::Ok);
+        let Effect::ValueSent(id, value) = device.effects.try_recv().unwrap();
+        assert_eq!(id, Id::new("setter:light@test"));
+        assert_eq!(value.cast::<OnOff>().unwrap(), &OnOff::On);
+    }
+
+    it "should sign in and record websocket frames" {
+        let token = harness.token();
+        assert!(!token.is_empty());
+        assert_eq!(harness.token(), token);
+        let (status, _) = harness.request(Method::Get, "/api/v1/services", "", true);
+        assert_eq!(status, Status::Ok);
+
+        harness.controller.adapter_started("light@test".to_owned());
+        let frames = harness.take_ws_frames();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].find("type").and_then(|kind| kind.as_string()),
+                   Some("core/adapter/start"));
+        assert!(harness.take_ws_frames().is_empty());
+    }
+}
@@ -0,0 +1,457 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Remote support sessions.
+//!
+//! To let someone debug the box remotely, the admin of the box explicitly starts a support
+//! session:
+//!
+//! `POST /support/session` with a body `{ "consent": true, "minutes": 60,
+//! "upload_url": "https://..." }`
+//!
+//! This mints a token that only grants access to `GET /support/diagnostics`, a read-only
+//! bundle of the services, the latest timeline entries and the latest lines of the log. The
+//! configuration (which holds tokens and passwords) is never part of the bundle, and the
+//! secrets found in the log, e.g. tokens in urls, are redacted. If an `upload_url` is given, the
+//! bundle is also posted there once.
+//!
+//! The session expires automatically, and can be ended earlier with
+//! `DELETE /support/session`. There is at most one session at a time.
+
+extern crate crypto;
+
+use self::crypto::util::fixed_time_eq;
+
+use foxbox_core::timeline::Filter;
+use foxbox_core::traits::Controller;
+use foxbox_core::ws_trace::redact_line;
+use foxbox_taxonomy::api::API;
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_taxonomy::parse::ToJSON;
+use foxbox_taxonomy::selector::ServiceSelector;
+use foxbox_users::{AuthEndpoint, ReadFilter, SessionToken};
+
+use hyper;
+use hyper::header::Connection;
+use iron::{Handler, headers, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::status::Status;
+use rand::Rng;
+use rand::os::OsRng;
+use rustc_serialize::hex::ToHex;
+use serde_json;
+use serde_json::value::Value as JSON;
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a session lasts when the user doesn't specify it.
+const DEFAULT_DURATION_MINUTES: u64 = 60;
+
+/// Sessions can't last longer than a day.
+const MAX_DURATION_MINUTES: u64 = 24 * 60;
+
+/// How many timeline entries are included in the diagnostics.
+const TIMELINE_ENTRIES: usize = 200;
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0))
+}
+
+#[derive(Clone, Debug)]
+pub struct SupportSession {
+    pub token: String,
+    /// Seconds since the epoch.
+    pub expires_at: u64,
+    /// The user who consented to the session.
+    pub started_by: Option<String>,
+    pub upload_url: Option<String>,
+    /// The outcome of the upload, if any: "pending", "done", or an error message.
+    pub upload: Option<String>,
+}
+
+impl SupportSession {
+    /// The session as shown to the users of the box. The token is only shown once, when the
+    /// session is started.
+    fn to_json(&self, with_token: bool) -> JSON {
+        let mut map = BTreeMap::new();
+        if with_token {
+            map.insert("token".to_owned(), JSON::String(self.token.clone()));
+        }
+        map.insert("expires_at".to_owned(), JSON::U64(self.expires_at));
+        map.insert("started_by".to_owned(),
+                   self.started_by.clone().map_or(JSON::Null, JSON::String));
+        map.insert("upload_url".to_owned(),
+                   self.upload_url.clone().map_or(JSON::Null, JSON::String));
+        map.insert("upload".to_owned(),
+                   self.upload.clone().map_or(JSON::Null, JSON::String));
+        JSON::Object(map)
+    }
+}
+
+/// The current support session, if any.
+#[derive(Clone, Default)]
+pub struct SupportSessions {
+    current: Arc<Mutex<Option<SupportSession>>>,
+}
+
+impl SupportSessions {
+    pub fn new() -> Self {
+        SupportSessions::default()
+    }
+
+    /// Start a session, replacing the current one.
+    pub fn start(&self,
+                 started_by: Option<String>,
+                 duration: Duration,
+                 upload_url: Option<String>)
+                 -> SupportSession {
+        let mut token = [0u8; 32];
+        OsRng::new().expect("No source of randomness").fill_bytes(&mut token);
+        let session = SupportSession {
+            token: token.to_hex(),
+            expires_at: (now() + duration).as_secs(),
+            started_by: started_by,
+            upload: upload_url.as_ref().map(|_| "pending".to_owned()),
+            upload_url: upload_url,
+        };
+        info!("Support session started by {:?}, until {}",
+              session.started_by,
+              session.expires_at);
+        *self.current.lock().unwrap() = Some(session.clone());
+        session
+    }
+
+    /// The current session, unless it has expired.
+    pub fn current(&self) -> Option<SupportSession> {
+        let mut current = self.current.lock().unwrap();
+        if current.as_ref().map_or(false, |session| session.expires_at <= now().as_secs()) {
+            info!("Support session expired");
+            *current = None;
+        }
+        current.clone()
+    }
+
+    /// End the current session. Returns `false` if there was none.
+    pub fn stop(&self) -> bool {
+        self.current.lock().unwrap().take().is_some()
+    }
+
+    /// Whether `token` is the token of the current session.
+    pub fn is_valid(&self, token: &str) -> bool {
+        self.current()
+            .map_or(false, |session| fixed_time_eq(session.token.as_bytes(), token.as_bytes()))
+    }
+
+    fn set_upload(&self, token: &str, outcome: String) {
+        if let Some(ref mut session) = *self.current.lock().unwrap() {
+            if session.token == token {
+                session.upload = Some(outcome);
+            }
+        }
+    }
+}
+
+/// The diagnostics shared with support.
+pub fn diagnostics<T: Controller>(controller: &T, api: &AdapterManager) -> JSON {
+    let timeline = controller.get_timeline().query(&Filter {
+        limit: TIMELINE_ENTRIES,
+        ..Filter::default()
+    });
+    let mut map = BTreeMap::new();
+    map.insert("version".to_owned(),
+               JSON::String(env!("CARGO_PKG_VERSION").to_owned()));
+    map.insert("generated_at".to_owned(), JSON::U64(now().as_secs()));
+    map.insert("hostname".to_owned(), JSON::String(controller.get_hostname()));
+    map.insert("services".to_owned(),
+               api.get_services(vec![ServiceSelector::new()]).to_json());
+    map.insert("timeline".to_owned(),
+               JSON::Array(timeline.iter().map(|entry| entry.to_json()).collect()));
+    let pool = controller.get_thread_pool().stats();
+    map.insert("thread_pool".to_owned(),
+               json_value!({ workers: pool.workers, busy: pool.busy, queued: pool.queued,
+                             completed: pool.completed, rejected: pool.rejected }));
+    let log = controller.get_log()
+        .lines()
+        .iter()
+        .map(|line| JSON::String(redact_line(line)))
+        .collect();
+    map.insert("log".to_owned(), JSON::Array(log));
+    JSON::Object(map)
+}
+
+fn upload(url: &str, bundle: &JSON) -> Result<(), String> {
+    let body = serde_json::to_string(bundle).unwrap();
+    let response = try!(hyper::Client::new()
+        .post(url)
+        .header(ContentType::json())
+        .header(Connection::close())
+        .body(&body[..])
+        .send()
+        .map_err(|err| format!("{}", err)));
+    if response.status.is_success() {
+        Ok(())
+    } else {
+        Err(format!("{} returned {}", url, response.status))
+    }
+}
+
+pub struct SupportRouter<T: Controller> {
+    controller: T,
+    api: Arc<AdapterManager>,
+    sessions: SupportSessions,
+}
+
+impl<T: Controller> SupportRouter<T> {
+    pub fn new(controller: T, api: &Arc<AdapterManager>) -> Self {
+        SupportRouter {
+            controller: controller,
+            api: api.clone(),
+            sessions: SupportSessions::new(),
+        }
+    }
+
+    /// Whether `user` is an administrator of the box. Only they may share its diagnostics.
+    fn is_admin(&self, user: &Option<String>) -> bool {
+        let id = match *user {
+            Some(ref id) => id,
+            None => return false,
+        };
+        match self.controller.get_users_manager().get_db().read(ReadFilter::IsAdmin(true)) {
+            Ok(admins) => admins.iter().any(|admin| admin.id == *id),
+            Err(_) => false,
+        }
+    }
+
+    fn json_response(status: Status, json: &JSON) -> IronResult<Response> {
+        let mut response = Response::with((status, serde_json::to_string(json).unwrap()));
+        response.headers.set(ContentType::json());
+        Ok(response)
+    }
+
+    fn start_session(&self, req: &mut Request, user: Option<String>) -> IronResult<Response> {
+        let mut body = String::new();
+        itry!(req.body.read_to_string(&mut body), Status::BadRequest);
+        let json: JSON = itry!(serde_json::from_str(&body), Status::BadRequest);
+        if json.find("consent").and_then(JSON::as_bool) != Some(true) {
+            return Ok(Response::with((Status::BadRequest,
+                                      "Support sessions require an explicit consent")));
+        }
+        let minutes = match json.find("minutes") {
+            None => DEFAULT_DURATION_MINUTES,
+            Some(minutes) => {
+                match minutes.as_u64() {
+                    Some(minutes) if minutes > 0 && minutes <= MAX_DURATION_MINUTES => minutes,
+                    _ => {
+                        return Ok(Response::with((Status::BadRequest,
+                                                  format!("Expected a number of minutes \
+                                                           between 1 and {}",
+                                                          MAX_DURATION_MINUTES))))
+                    }
+                }
+            }
+        };
+        let upload_url = json.find("upload_url").and_then(JSON::as_string).map(str::to_owned);
+        if let Some(ref url) = upload_url {
+            // The bundle is personal data, so don't send it in the clear.
+            if !url.starts_with("https://") {
+                return Ok(Response::with((Status::BadRequest, "The upload_url must use https")));
+            }
+        }
+
+        let session = self.sessions.start(user, Duration::from_secs(minutes * 60), upload_url);
+        if let Some(ref url) = session.upload_url {
+            let bundle = diagnostics(&self.controller, &self.api);
+            let sessions = self.sessions.clone();
+            let token = session.token.clone();
+            let url = url.clone();
+            thread::spawn(move || {
+                let outcome = match upload(&url, &bundle) {
+                    Ok(()) => "done".to_owned(),
+                    Err(err) => {
+                        warn!("Unable to upload the support bundle: {}", err);
+                        err
+                    }
+                };
+                sessions.set_upload(&token, outcome);
+            });
+        }
+        Self::json_response(Status::Created, &session.to_json(true))
+    }
+}
+
+impl<T: Controller> Handler for SupportRouter<T> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let bearer = match req.headers.get::<headers::Authorization<headers::Bearer>>() {
+            Some(&headers::Authorization(headers::Bearer { ref token })) => Some(token.clone()),
+            _ => None,
+        };
+        let (is_diagnostics, is_session) = {
+            let path = req.url.path();
+            (path == ["diagnostics"], path == ["session"])
+        };
+
+        // Keep these urls in sync with the AuthEndpoint(s) in the create() method.
+
+        // Only the token of the support session gives access to the diagnostics.
+        if is_diagnostics && req.method == Method::Get {
+            return match bearer {
+                Some(ref token) if self.sessions.is_valid(token) => {
+                    Self::json_response(Status::Ok, &diagnostics(&self.controller, &self.api))
+                }
+                _ => Ok(Response::with(Status::Unauthorized)),
+            };
+        }
+
+        if is_session {
+            let user = bearer.and_then(|token| SessionToken::from_string(&token).ok())
+                .map(|token| token.claims.id);
+            if !self.is_admin(&user) {
+                return Ok(Response::with(Status::Forbidden));
+            }
+            match req.method {
+                Method::Post => return self.start_session(req, user),
+                Method::Get => {
+                    return match self.sessions.current() {
+                        Some(session) => Self::json_response(Status::Ok, &session.to_json(false)),
+                        None => Ok(Response::with(Status::NotFound)),
+                    }
+                }
+                Method::Delete => {
+                    return if self.sessions.stop() {
+                        info!("Support session ended by {:?}", user);
+                        Ok(Response::with(Status::NoContent))
+                    } else {
+                        Ok(Response::with(Status::NotFound))
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Response::with((Status::NotFound, format!("Unknown url: {}", req.url))))
+    }
+}
+
+pub fn create<T>(controller: T,
+                 adapter_api: &Arc<AdapterManager>)
+                 -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    let router = SupportRouter::new(controller.clone(), adapter_api);
+
+    let endpoints = vec![
+        (vec![Method::Get, Method::Post, Method::Delete], "session".to_owned()),
+        (vec![Method::Get], "diagnostics".to_owned()),
+    ];
+
+    // Managing the session requires the admin of the box. The diagnostics are protected by the
+    // token of the session instead.
+    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
+        vec![AuthEndpoint(endpoints[0].0.clone(), endpoints[0].1.clone())]
+    } else {
+        vec![]
+    };
+
+    let mut chain = Chain::new(router);
+    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    (chain, endpoints)
+}
+
+#[cfg(test)]
+describe! support {
+    before_each {
+        use super::*;
+        use std::time::Duration;
+    }
+
+    it "should only accept the token of the current session" {
+        let sessions = SupportSessions::new();
+        assert!(!sessions.is_valid(""));
+        let session = sessions.start(Some("alice".to_owned()), Duration::from_secs(3600), None);
+        assert_eq!(session.upload, None);
+        assert!(sessions.is_valid(&session.token));
+        assert!(!sessions.is_valid("not the token"));
+
+        let other = sessions.start(None, Duration::from_secs(3600), None);
+        assert!(!sessions.is_valid(&session.token));
+        assert!(sessions.is_valid(&other.token));
+
+        assert!(sessions.stop());
+        assert!(!sessions.is_valid(&other.token));
+        assert!(!sessions.stop());
+    }
+
+    it "should expire sessions" {
+        let sessions = SupportSessions::new();
+        let session = sessions.start(None, Duration::from_secs(0), None);
+        assert!(!sessions.is_valid(&session.token));
+        assert!(sessions.current().is_none());
+    }
+}
+
+#[cfg(test)]
+describe! support_router {
+    before_each {
+        use foxbox_core::traits::Controller;
+        use iron::method::Method;
+        use iron::status::Status;
+        use serde_json;
+        use serde_json::value::Value as JSON;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+    }
+
+    it "should require consent, and scope the token to the diagnostics" {
+        let (status, _) =
+            harness.request(Method::Post, "/support/session", r#"{"minutes": 30}"#, true);
+        assert_eq!(status, Status::BadRequest);
+
+        let body = r#"{"consent": true, "upload_url": "http://example.org"}"#;
+        let (status, _) = harness.request(Method::Post, "/support/session", body, true);
+        assert_eq!(status, Status::BadRequest);
+
+        let (status, _) = harness.request(Method::Get, "/support/diagnostics", "", false);
+        assert_eq!(status, Status::Unauthorized);
+
+        let body = r#"{"consent": true, "minutes": 30}"#;
+        let (status, json) = harness.request_json(Method::Post, "/support/session", body, true);
+        assert_eq!(status, Status::Created);
+        let token = json.find("token").and_then(JSON::as_string).unwrap().to_owned();
+
+        // The token of the admin doesn't give access to the diagnostics.
+        let (status, _) = harness.request(Method::Get, "/support/diagnostics", "", true);
+        assert_eq!(status, Status::Unauthorized);
+
+        harness.controller.get_log().push("GET /?auth=abc&trace=true".to_owned());
+        let (status, body) =
+            harness.request_with_token(Method::Get, "/support/diagnostics", "", Some(token));
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains("/?auth=[redacted]&trace=true"));
+        assert!(!body.contains("abc"));
+        let json: JSON = serde_json::from_str(&body).unwrap();
+        assert!(json.find("services").is_some());
+        assert!(json.find("log").is_some());
+
+        let (status, _) = harness.request(Method::Delete, "/support/session", "", true);
+        assert_eq!(status, Status::NoContent);
+    }
+
+    it "should refuse the sessions to users who are not admins" {
+        let body = r#"{"consent": true, "minutes": 30}"#;
+        let (status, _) = harness.request(Method::Post, "/support/session", body, false);
+        assert_eq!(status, Status::Forbidden);
+        let (status, _) = harness.request(Method::Get, "/support/session", "", false);
+        assert_eq!(status, Status::Forbidden);
+        let (status, _) = harness.request(Method::Delete, "/support/session", "", false);
+        assert_eq!(status, Status::Forbidden);
+    }
+}
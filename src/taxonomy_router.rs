@@ -4,40 +4,160 @@
 
 extern crate serde_json;
 
+use foxbox_core::adapter_status::AdapterStatuses;
+use foxbox_core::app_registry::{App, AppRegistry};
+use foxbox_core::config_store::{ConfigService, ConfigType};
+use foxbox_core::modes::{Mode, ModeRegistry};
+use foxbox_core::timeline::{Entry, EntryKind, Filter, Timeline};
 use foxbox_core::traits::Controller;
+use foxbox_core::watch_sets::{WatchSet, WatchSetRegistry};
+use foxbox_core::ws_trace::WsTraces;
+use foxbox_taxonomy::history::HistoryRange;
 use foxbox_taxonomy::manager::*;
 use foxbox_taxonomy::api::{API, Error, TargetMap, Targetted, User};
 use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::io::*;
+use foxbox_taxonomy::parse::Fields;
 use foxbox_taxonomy::values::{format, Binary, Json, Value};
 use foxbox_taxonomy::selector::*;
 use foxbox_taxonomy::services::*;
 use foxbox_taxonomy::util::MimeTypeId;
 
-use foxbox_users::AuthEndpoint;
+use foxbox_users::{AuthEndpoint, ReadFilter, UsersManager};
 use foxbox_users::SessionToken;
 
-use iron::{Handler, headers, IronResult, Request, Response};
+use iron::{Handler, headers, IronError, IronResult, Request, Response};
 use iron::headers::ContentType;
 use iron::method::Method;
 use iron::prelude::Chain;
 use iron::request::Body;
 use iron::status::Status;
 
-use std::io::{Error as IOError, Read};
+use serde_json::value::Value as JSON;
+
+use std::io::Read;
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::Duration;
+
+use url::form_urlencoded;
+
+/// How many timeline entries are returned when the client doesn't specify a `limit`.
+const DEFAULT_TIMELINE_LIMIT: usize = 100;
+
+/// How long values sent with `?deliver=when-reachable` wait for their device, unless the
+/// client specifies a `ttl`, in seconds.
+const DEFAULT_DELIVERY_TTL_S: u64 = 24 * 3600;
+
+/// The largest JSON body accepted, in bytes, unless `foxbox.max_body_size` says otherwise.
+const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+/// The largest binary body, e.g. an image sent to a channel, unless
+/// `foxbox.max_binary_body_size` says otherwise.
+const DEFAULT_MAX_BINARY_BODY_SIZE: u64 = 16 * 1024 * 1024;
+
+// The revision of the taxonomy a services or channels query was evaluated against.
+header! { (XTaxonomyRevision, "X-Taxonomy-Revision") => [u64] }
 
 /// This is a specialized Router for the taxonomy API.
 /// It handles all the calls under the api/v1/ url space.
 pub struct TaxonomyRouter {
     api: Arc<AdapterManager>,
+    timeline: Timeline,
+    adapter_statuses: AdapterStatuses,
+    apps: AppRegistry,
+    modes: ModeRegistry,
+    watch_sets: WatchSetRegistry,
+    limits: BodyLimits,
+    ws_traces: WsTraces,
+    config: Arc<ConfigService>,
+    users: Arc<UsersManager>,
+}
+
+/// The limits applied to request bodies before they are parsed.
+#[derive(Clone, Debug)]
+pub struct BodyLimits {
+    /// The largest JSON body, in bytes.
+    pub max_size: u64,
+    /// The largest binary body, in bytes.
+    pub max_binary_size: u64,
+    /// Reject the fields of JSON bodies that we don't know about, rather than ignoring them.
+    pub strict: bool,
 }
 
+// The fields expected in JSON bodies, for `BodyLimits::strict`. Selectors ignore the fields
+// they don't know, so a typo such as `"feture"` would otherwise select every channel.
+static CHANNEL_SELECTOR: Fields = Fields::Object(&[("id", Fields::Any),
+                                                   ("service", Fields::Any),
+                                                   ("tags", Fields::Any),
+                                                   ("service_tags", Fields::Any),
+                                                   ("feature", Fields::Any),
+                                                   ("supports_send", Fields::Any),
+                                                   ("supports_fetch", Fields::Any),
+                                                   ("supports_watch", Fields::Any)]);
+static SERVICE_SELECTOR: Fields =
+    Fields::Object(&[("id", Fields::Any),
+                     ("tags", Fields::Any),
+                     ("channels", Fields::Array(&CHANNEL_SELECTOR)),
+                     ("properties", Fields::Array(&PROPERTY_PREDICATE))]);
+static PROPERTY_PREDICATE: Fields = Fields::Object(&[("property", Fields::Any),
+                                                     ("equals", Fields::Any),
+                                                     ("contains", Fields::Any)]);
+static CHANNEL_SELECTORS: Fields = Fields::Array(&CHANNEL_SELECTOR);
+static SERVICE_SELECTORS: Fields = Fields::Array(&SERVICE_SELECTOR);
+static TARGET: Fields = Fields::Object(&[("select", Fields::Array(&CHANNEL_SELECTOR)),
+                                         ("value", Fields::Any)]);
+static TARGETS: Fields = Fields::Array(&TARGET);
+static SERVICE_TAGS: Fields = Fields::Object(&[("services", Fields::Array(&SERVICE_SELECTOR)),
+                                               ("tags", Fields::Any)]);
+static CHANNEL_TAGS: Fields = Fields::Object(&[("channels", Fields::Array(&CHANNEL_SELECTOR)),
+                                               ("tags", Fields::Any)]);
+static SERVICE_METADATA: Fields =
+    Fields::Object(&[("services", Fields::Array(&SERVICE_SELECTOR)),
+                     ("metadata", Fields::Any)]);
+static CHANNEL_HISTORY: Fields =
+    Fields::Object(&[("channels", Fields::Array(&CHANNEL_SELECTOR)),
+                     ("range", Fields::Object(&[("from", Fields::Any), ("to", Fields::Any)]))]);
+
 type GetterResultMap = ResultMap<Id<Channel>, Option<(Payload, Arc<Format>)>, Error>;
 
 impl TaxonomyRouter {
-    pub fn new(adapter_api: &Arc<AdapterManager>) -> Self {
-        TaxonomyRouter { api: adapter_api.clone() }
+    pub fn new(adapter_api: &Arc<AdapterManager>,
+               timeline: Timeline,
+               adapter_statuses: AdapterStatuses,
+               apps: AppRegistry,
+               modes: ModeRegistry,
+               watch_sets: WatchSetRegistry,
+               limits: BodyLimits,
+               ws_traces: WsTraces,
+               config: Arc<ConfigService>,
+               users: Arc<UsersManager>)
+               -> Self {
+        TaxonomyRouter {
+            api: adapter_api.clone(),
+            timeline: timeline,
+            adapter_statuses: adapter_statuses,
+            apps: apps,
+            modes: modes,
+            watch_sets: watch_sets,
+            limits: limits,
+            ws_traces: ws_traces,
+            config: config,
+            users: users,
+        }
+    }
+
+    /// Whether `user` is an administrator of the box. The routes exposing the internals of the
+    /// box, e.g. its configuration, are reserved to them.
+    fn is_admin(&self, user: &User) -> bool {
+        let id = match *user {
+            User::Id(ref id) => id,
+            User::None => return false,
+        };
+        match self.users.get_db().read(ReadFilter::IsAdmin(true)) {
+            Ok(admins) => admins.iter().any(|admin| admin.id == *id),
+            Err(_) => false,
+        }
     }
 
     fn build_binary_response(&self, payload: &Binary) -> IronResult<Response> {
@@ -62,17 +182,458 @@ impl TaxonomyRouter {
         Ok(response)
     }
 
+    fn build_revision_response<S: ToJSON>(&self,
+                                          (obj, revision): (S, u64))
+                                          -> IronResult<Response> {
+        let mut json = obj.to_json();
+        if !self.apps.is_empty() {
+            self.add_app_links(&mut json);
+        }
+        let mut response = try!(self.build_response(&json));
+        response.headers.set(XTaxonomyRevision(revision));
+        Ok(response)
+    }
+
+    /// Add the "open in app" links to the channels of a list of services or channels, as a
+    /// field `app_links`.
+    fn add_app_links(&self, json: &mut JSON) {
+        let items = match *json {
+            JSON::Array(ref mut items) => items,
+            _ => return,
+        };
+        for item in items.iter_mut() {
+            let map = match *item {
+                JSON::Object(ref mut map) => map,
+                _ => continue,
+            };
+            if let Some(&mut JSON::Object(ref mut channels)) = map.get_mut("channels") {
+                // A service.
+                for channel in channels.values_mut() {
+                    if let JSON::Object(ref mut channel) = *channel {
+                        self.add_channel_app_links(channel);
+                    }
+                }
+                continue;
+            }
+            self.add_channel_app_links(map);
+        }
+    }
+
+    fn add_channel_app_links(&self, channel: &mut BTreeMap<String, JSON>) {
+        let links = {
+            let feature = channel.get("feature").and_then(JSON::as_string).unwrap_or("");
+            let service = channel.get("service").and_then(JSON::as_string).unwrap_or("");
+            let id = channel.get("id").and_then(JSON::as_string).unwrap_or("");
+            self.apps.links(feature, service, id)
+        };
+        if !links.is_empty() {
+            channel.insert("app_links".to_owned(), JSON::Array(links));
+        }
+    }
+
+    /// GET apps, POST apps (register or replace an app), DELETE apps/:id.
+    fn apps_response<'a, 'b: 'a>(&self,
+                                 method: &Method,
+                                 body: &mut Body<'a, 'b>,
+                                 id: Option<&str>)
+                                 -> IronResult<Response> {
+        match (method, id) {
+            (&Method::Get, None) => {
+                let apps = self.apps.apps().iter().map(App::to_json).collect();
+                self.build_response(&JSON::Array(apps))
+            }
+            (&Method::Post, None) => {
+                let source = match self.read_body_to_string(body) {
+                    Ok(source) => source,
+                    Err(response) => return response,
+                };
+                let app = match serde_json::from_str::<JSON>(&source)
+                    .map_err(|err| err.to_string())
+                    .and_then(|json| App::from_json(&json)) {
+                    Ok(app) => app,
+                    Err(err) => return Ok(Response::with((Status::BadRequest, err))),
+                };
+                info!("Registering app {} ({})", app.id, app.name);
+                let json = app.to_json();
+                self.apps.register(app);
+                let mut response = try!(self.build_response(&json));
+                response.status = Some(Status::Created);
+                Ok(response)
+            }
+            (&Method::Delete, Some(id)) => {
+                if self.apps.unregister(id) {
+                    Ok(Response::with(Status::NoContent))
+                } else {
+                    Ok(Response::with((Status::NotFound, format!("No app {}", id))))
+                }
+            }
+            (method, _) => {
+                Ok(Response::with((Status::MethodNotAllowed, format!("Bad method: {}", method))))
+            }
+        }
+    }
+
+    /// GET modes, POST modes with a mode as body, GET modes/:name, DELETE modes/:name. The
+    /// current mode can't be removed.
+    fn modes_response<'a, 'b: 'a>(&self,
+                                  method: &Method,
+                                  body: &mut Body<'a, 'b>,
+                                  name: Option<&str>)
+                                  -> IronResult<Response> {
+        let not_found = |name| Ok(Response::with((Status::NotFound, format!("No mode {}", name))));
+        match (method, name) {
+            (&Method::Get, None) => {
+                let modes = self.modes.modes().iter().map(Mode::to_json).collect();
+                let current = self.modes.current().map_or(JSON::Null, JSON::String);
+                self.build_response(&json_value!({ current: current, modes: JSON::Array(modes) }))
+            }
+            (&Method::Post, None) => {
+                let source = match self.read_body_to_string(body) {
+                    Ok(source) => source,
+                    Err(response) => return response,
+                };
+                let mode = match serde_json::from_str::<JSON>(&source)
+                    .map_err(|err| err.to_string())
+                    .and_then(|json| Mode::from_json(&json)) {
+                    Ok(mode) => mode,
+                    Err(err) => return Ok(Response::with((Status::BadRequest, err))),
+                };
+                info!("Defining mode {}", mode.name);
+                let json = mode.to_json();
+                self.modes.put(mode);
+                let mut response = try!(self.build_response(&json));
+                response.status = Some(Status::Created);
+                Ok(response)
+            }
+            (&Method::Get, Some(name)) => {
+                match self.modes.get(name) {
+                    Some(mode) => self.build_response(&mode.to_json()),
+                    None => not_found(name),
+                }
+            }
+            (&Method::Delete, Some(name)) => {
+                if self.modes.current().as_ref().map_or(false, |current| current == name) {
+                    return Ok(Response::with((Status::Conflict,
+                                              format!("Mode {} is the current mode", name))));
+                }
+                if self.modes.remove(name) {
+                    Ok(Response::with(Status::NoContent))
+                } else {
+                    not_found(name)
+                }
+            }
+            (method, _) => {
+                Ok(Response::with((Status::MethodNotAllowed, format!("Bad method: {}", method))))
+            }
+        }
+    }
+
+    /// GET watch-sets, POST watch-sets with `{"name": string, "watches": [{"selector":
+    /// selectors, "range"?: value}]}` as body, GET watch-sets/:id, DELETE watch-sets/:id.
+    /// Removing a set also cancels it for the websocket clients that resumed it.
+    fn watch_sets_response<'a, 'b: 'a>(&self,
+                                       method: &Method,
+                                       body: &mut Body<'a, 'b>,
+                                       id: Option<&str>)
+                                       -> IronResult<Response> {
+        let not_found = |id| {
+            Ok(Response::with((Status::NotFound, format!("No watch set {}", id))))
+        };
+        match (method, id) {
+            (&Method::Get, None) => {
+                let sets = self.watch_sets.sets().iter().map(WatchSet::to_json).collect();
+                self.build_response(&JSON::Array(sets))
+            }
+            (&Method::Post, None) => {
+                let source = match self.read_body_to_string(body) {
+                    Ok(source) => source,
+                    Err(response) => return response,
+                };
+                let (name, watches) = match serde_json::from_str::<JSON>(&source)
+                    .map_err(|err| err.to_string())
+                    .and_then(|json| {
+                        let name = json.find("name")
+                            .and_then(JSON::as_string)
+                            .map(str::to_owned)
+                            .unwrap_or_else(String::new);
+                        WatchSet::watches_from_json(&json).map(|watches| (name, watches))
+                    }) {
+                    Ok(set) => set,
+                    Err(err) => return Ok(Response::with((Status::BadRequest, err))),
+                };
+                // Check the watches now, rather than when a client resumes the set.
+                for watch in &watches {
+                    if let Err(err) = Vec::<ChannelSelector>::parse(Path::new(),
+                                                                    &watch.selector) {
+                        return self.build_parse_error(&err);
+                    }
+                    if let Some(ref range) = watch.range {
+                        if let Err(err) = Payload::parse(Path::new(), range) {
+                            return self.build_parse_error(&err);
+                        }
+                    }
+                }
+                let set = self.watch_sets.put(&name, watches);
+                info!("Registering watch set {} ({})", set.id, set.name);
+                let mut response = try!(self.build_response(&set.to_json()));
+                response.status = Some(Status::Created);
+                Ok(response)
+            }
+            (&Method::Get, Some(id)) => {
+                match self.watch_sets.get(id) {
+                    Some(set) => self.build_response(&set.to_json()),
+                    None => not_found(id),
+                }
+            }
+            (&Method::Delete, Some(id)) => {
+                if self.watch_sets.remove(id) {
+                    Ok(Response::with(Status::NoContent))
+                } else {
+                    not_found(id)
+                }
+            }
+            (method, _) => {
+                Ok(Response::with((Status::MethodNotAllowed, format!("Bad method: {}", method))))
+            }
+        }
+    }
+
+    /// A registered property of the configuration. Secrets are never shown.
+    fn config_json(&self, namespace: &str, property: &str, kind: ConfigType) -> JSON {
+        let value = match self.config.get(namespace, property) {
+            Some(ref value) if value.is_empty() => JSON::Null,
+            Some(_) if kind == ConfigType::Secret => JSON::String("[redacted]".to_owned()),
+            Some(value) => JSON::String(value),
+            None => JSON::Null,
+        };
+        json_value!({ namespace: namespace, key: property, type: kind.as_str(), value: value })
+    }
+
+    /// GET config, GET config/:namespace/:key, PUT config/:namespace/:key with a body
+    /// `{"value": ...}`. Only the properties registered in the schema are available.
+    fn config_response<'a, 'b: 'a>(&self,
+                                   method: &Method,
+                                   body: &mut Body<'a, 'b>,
+                                   property: Option<(&str, &str)>)
+                                   -> IronResult<Response> {
+        let (namespace, key) = match (method, property) {
+            (&Method::Get, None) => {
+                let schema = self.config
+                    .schema()
+                    .iter()
+                    .map(|&(ref namespace, ref key, kind)| self.config_json(namespace, key, kind))
+                    .collect();
+                return self.build_response(&JSON::Array(schema));
+            }
+            (_, Some(property)) => property,
+            (method, None) => {
+                return Ok(Response::with((Status::MethodNotAllowed,
+                                          format!("Bad method: {}", method))))
+            }
+        };
+        let kind = match self.config.get_type(namespace, key) {
+            Some(kind) => kind,
+            None => {
+                return Ok(Response::with((Status::NotFound,
+                                          format!("No property {}.{}", namespace, key))))
+            }
+        };
+        match *method {
+            Method::Get => self.build_response(&self.config_json(namespace, key, kind)),
+            Method::Put => {
+                let source = match self.read_body_to_string(body) {
+                    Ok(source) => source,
+                    Err(response) => return response,
+                };
+                let value = match serde_json::from_str::<JSON>(&source)
+                    .ok()
+                    .as_ref()
+                    .and_then(|json| json.find("value")) {
+                    Some(&JSON::String(ref value)) => value.clone(),
+                    Some(&JSON::Bool(value)) => value.to_string(),
+                    Some(&JSON::U64(value)) => value.to_string(),
+                    Some(&JSON::I64(value)) => value.to_string(),
+                    _ => {
+                        return Ok(Response::with((Status::BadRequest,
+                                                  "Expected a body {\"value\": ...}")))
+                    }
+                };
+                if let Err(err) = self.config.set_checked(namespace, key, &value) {
+                    return Ok(Response::with((Status::BadRequest, err)));
+                }
+                info!("Configuration {}.{} changed", namespace, key);
+                self.build_response(&self.config_json(namespace, key, kind))
+            }
+            ref method => {
+                Ok(Response::with((Status::MethodNotAllowed, format!("Bad method: {}", method))))
+            }
+        }
+    }
+
     fn build_parse_error(&self, obj: &ParseError) -> IronResult<Response> {
         let mut response = Response::with(itry!(serde_json::to_string(obj)));
         response.status = Some(Status::BadRequest);
-        response.headers.set(ContentType::plaintext()); // FIXME: Should be JSON
+        response.headers.set(ContentType::json());
         Ok(response)
     }
 
-    fn read_body_to_string<'a, 'b: 'a>(body: &mut Body<'a, 'b>) -> Result<String, IOError> {
-        let mut s = String::new();
-        try!(body.read_to_string(&mut s));
-        Ok(s)
+    fn build_too_large_error(&self, limit: u64) -> IronResult<Response> {
+        let json = vec![("BodyTooLarge", vec![("limit", limit as usize)])].to_json();
+        let mut response = Response::with(itry!(serde_json::to_string(&json)));
+        response.status = Some(Status::PayloadTooLarge);
+        response.headers.set(ContentType::json());
+        Ok(response)
+    }
+
+    /// Record the channels to which a user has successfully sent values in the timeline.
+    fn record_sends<T>(&self, results: &ResultMap<Id<Channel>, T, Error>, user: &User) {
+        let channels: Vec<String> = results.iter()
+            .filter(|&(_, result)| result.is_ok())
+            .map(|(id, _)| id.to_string())
+            .collect();
+        if channels.is_empty() {
+            return;
+        }
+        let user = match *user {
+            User::Id(ref id) => Some(id.clone()),
+            User::None => None,
+        };
+        self.timeline.push(Entry::new(EntryKind::Action, channels, user, JSON::Null));
+    }
+
+    /// GET timeline?service=...&tag=...&user=...&kind=...&since=...&limit=...
+    ///
+    /// All parameters are optional, and `service`, `tag` and `kind` may be repeated. Entries are
+    /// returned most recent first.
+    fn timeline_response(&self, req: &Request) -> IronResult<Response> {
+        let mut filter = Filter { limit: DEFAULT_TIMELINE_LIMIT, ..Filter::default() };
+        let mut selectors = vec![];
+        let query = req.url.query().unwrap_or("").to_owned();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "service" => selectors.push(ChannelSelector::new().with_parent(&Id::new(&value))),
+                "tag" => {
+                    let tag = Id::<TagId>::new(&value);
+                    selectors.push(ChannelSelector::new().with_tags(vec![tag.clone()]));
+                    selectors.push(ChannelSelector::new().with_service_tags(vec![tag]));
+                }
+                "user" => filter.user = Some(value.into_owned()),
+                "kind" => {
+                    match EntryKind::parse(&value) {
+                        Some(kind) => filter.kinds.push(kind),
+                        None => {
+                            return Ok(Response::with((Status::BadRequest,
+                                                      format!("Unknown kind: {}", value))))
+                        }
+                    }
+                }
+                "since" => filter.since = Some(itry!(value.parse::<u64>(), Status::BadRequest)),
+                "limit" => filter.limit = itry!(value.parse::<usize>(), Status::BadRequest),
+                _ => {}
+            }
+        }
+        if !selectors.is_empty() {
+            filter.channels = Some(self.api
+                .get_channels(selectors)
+                .iter()
+                .map(|channel| channel.id.to_string())
+                .collect());
+        }
+        let entries = self.timeline.query(&filter).iter().map(Entry::to_json).collect();
+        self.build_response(&JSON::Array(entries))
+    }
+
+    /// PUT channels/set?dry_run=true
+    ///
+    /// With `dry_run=true`, the selectors are resolved and the values checked, but nothing is
+    /// sent: the response tells which value each channel would receive.
+    fn is_dry_run(req: &Request) -> bool {
+        let query = req.url.query().unwrap_or("").to_owned();
+        form_urlencoded::parse(query.as_bytes())
+            .any(|(key, value)| key == "dry_run" && value == "true")
+    }
+
+    /// PUT channels/set?atomic=true
+    ///
+    /// With `atomic=true`, the values are sent all or nothing, e.g. to activate a scene: if one
+    /// of them cannot be sent, the others are reverted.
+    fn is_atomic(req: &Request) -> bool {
+        let query = req.url.query().unwrap_or("").to_owned();
+        form_urlencoded::parse(query.as_bytes())
+            .any(|(key, value)| key == "atomic" && value == "true")
+    }
+
+    /// PUT channels/set?deliver=when-reachable&ttl=...
+    ///
+    /// With `deliver=when-reachable`, the values sent to devices that can't be reached right
+    /// now are queued for at most `ttl` seconds. Returns `None` for a regular send.
+    fn delivery_ttl(req: &Request) -> Result<Option<Duration>, Response> {
+        let query = req.url.query().unwrap_or("").to_owned();
+        let mut when_reachable = false;
+        let mut ttl = DEFAULT_DELIVERY_TTL_S;
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "deliver" if value == "when-reachable" => when_reachable = true,
+                "deliver" if value == "now" => when_reachable = false,
+                "deliver" => {
+                    return Err(Response::with((Status::BadRequest,
+                                               format!("Unknown delivery: {}", value))))
+                }
+                "ttl" => {
+                    ttl = match value.parse() {
+                        Ok(ttl) => ttl,
+                        Err(_) => {
+                            return Err(Response::with((Status::BadRequest,
+                                                       format!("Invalid ttl: {}", value))))
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(if when_reachable {
+            Some(Duration::from_secs(ttl))
+        } else {
+            None
+        })
+    }
+
+    /// Read a body of at most `limit` bytes. Larger bodies are answered with a 413, without
+    /// reading them in full.
+    fn read_body<'a, 'b: 'a>(&self,
+                             body: &mut Body<'a, 'b>,
+                             limit: u64)
+                             -> Result<Vec<u8>, IronResult<Response>> {
+        let mut buffer = Vec::new();
+        if let Err(err) = body.by_ref().take(limit + 1).read_to_end(&mut buffer) {
+            return Err(Err(IronError::new(err, Status::InternalServerError)));
+        }
+        if buffer.len() as u64 > limit {
+            return Err(self.build_too_large_error(limit));
+        }
+        Ok(buffer)
+    }
+
+    /// Read a JSON body, see `read_body`.
+    fn read_body_to_string<'a, 'b: 'a>(&self,
+                                       body: &mut Body<'a, 'b>)
+                                       -> Result<String, IronResult<Response>> {
+        let buffer = try!(self.read_body(body, self.limits.max_size));
+        String::from_utf8(buffer)
+            .map_err(|err| Err(IronError::new(err, Status::BadRequest)))
+    }
+
+    /// Parse a JSON body, rejecting the fields we don't expect if `BodyLimits::strict`.
+    fn parse_body<T, F>(&self, source: &str, fields: &Fields, parse: F) -> Result<T, ParseError>
+        where F: FnOnce(Path, &JSON) -> Result<T, ParseError>
+    {
+        let json: JSON = try!(serde_json::from_str(source).map_err(ParseError::json));
+        Path::new().push_str("body", |path| {
+            if self.limits.strict {
+                try!(fields.check(path.clone(), &json));
+            }
+            parse(path, &json)
+        })
     }
 
     // Checks if a getter result map is a binary payload.
@@ -127,8 +688,38 @@ impl Handler for TaxonomyRouter {
         // the req.url.path will only contain ["services"]
         let path = req.url.path();
 
-        macro_rules! simple_response {
-            ($api:ident, $arg:ident, $call:ident) => (self.build_response(&$api.$call($arg, user)))
+        // Reads a JSON body, or returns the response rejecting it.
+        macro_rules! read_body_to_string {
+            () => (match self.read_body_to_string(&mut req.body) {
+                Ok(source) => source,
+                Err(response) => return response,
+            })
+        }
+
+        macro_rules! send_response {
+            ($api:ident, $arg:ident, $call:ident) => ({
+                        if Self::is_dry_run(req) {
+                            return self.build_response(&$api.preview_send_values($arg));
+                        }
+                        if Self::is_atomic(req) {
+                            let res = $api.send_values_atomic($arg, user.clone());
+                            self.record_sends(&res, &user);
+                            return self.build_response(&res);
+                        }
+                        match Self::delivery_ttl(req) {
+                            Err(response) => Ok(response),
+                            Ok(Some(ttl)) => {
+                                let res = $api.send_values_when_reachable($arg, user.clone(), ttl);
+                                self.record_sends(&res, &user);
+                                self.build_response(&res)
+                            }
+                            Ok(None) => {
+                                let res = $api.$call($arg, user.clone());
+                                self.record_sends(&res, &user);
+                                self.build_response(&res)
+                            }
+                        }
+                    })
         }
 
         macro_rules! binary_response {
@@ -142,6 +733,10 @@ impl Handler for TaxonomyRouter {
                     })
         }
 
+        macro_rules! json_response {
+            ($api:ident, $arg:ident, $call:ident) => (self.build_response(&$api.$call($arg)))
+        }
+
         // Special case for GET channel/:id
         // This will fetch the values for a ChannelSelector using the id.
         if req.method == Method::Get && path.len() == 2 && path[0] == "channel" {
@@ -165,7 +760,7 @@ impl Handler for TaxonomyRouter {
 
             let payload = if content_type.starts_with("application/json") {
                 // JSON payload.
-                let source = itry!(Self::read_body_to_string(&mut req.body));
+                let source = read_body_to_string!();
                 let json = match serde_json::de::from_str(&source as &str) {
                     Err(err) => return self.build_parse_error(&ParseError::json(err)),
                     Ok(args) => args,
@@ -174,8 +769,10 @@ impl Handler for TaxonomyRouter {
                 itry!(Payload::from_value(&Value::new(Json(json)), &format::JSON))
             } else {
                 // Read a binary payload.
-                let mut buffer = Vec::new();
-                itry!(req.body.read_to_end(&mut buffer));
+                let buffer = match self.read_body(&mut req.body, self.limits.max_binary_size) {
+                    Ok(buffer) => buffer,
+                    Err(response) => return response,
+                };
                 itry!(Payload::from_value(&Value::new(Binary {
                                               data: buffer,
                                               mimetype: Id::<MimeTypeId>::new(&content_type),
@@ -186,30 +783,31 @@ impl Handler for TaxonomyRouter {
                                payload: payload,
                                select: selector,
                            }];
-            return simple_response!(api, arg, send_values);
+            return send_response!(api, arg, send_values);
         }
 
         /// Generates the code for a generic HTTP call, where we use an empty
         /// taxonomy selector for GET requests, and a decoded json body for POST ones.
-        /// $call is the method we'll call on the api, like get_services.
+        /// $call is the method we'll call on the api, like get_services_at_revision.
         /// $sel  is the selector type, like ServiceSelector
         /// $path is a vector describing the url path, like ["service", "tags"]
+        /// $fields are the fields expected in the body, like SERVICE_SELECTORS
         macro_rules! get_post_api {
-            ($call:ident, $sel:ident, $path:expr) => (
+            ($call:ident, $sel:ident, $path:expr, $fields:expr) => (
             if path == $path {
                 return {
                     match req.method {
                         Method::Get => {
                             // On a GET, just send the full taxonomy content for
                             // this kind of selector.
-                            self.build_response(&self.api.$call(vec![$sel::new()]))
+                            self.build_revision_response(self.api.$call(vec![$sel::new()]))
                         },
                         Method::Post => {
-                            let source = itry!(Self::read_body_to_string(&mut req.body));
-                            match Path::new().push_str("body",
-                                |path| Vec::<$sel>::from_str_at(path, &source as &str))
+                            let source = read_body_to_string!();
+                            match self.parse_body(&source, &$fields,
+                                |path, json| Vec::<$sel>::parse(path, json))
                             {
-                                Ok(arg) => self.build_response(&self.api.$call(arg)),
+                                Ok(arg) => self.build_revision_response(self.api.$call(arg)),
                                 Err(err) => self.build_parse_error(&err)
                             }
                         },
@@ -222,14 +820,14 @@ impl Handler for TaxonomyRouter {
 
         // Generates the code to process a given HTTP call with a json body.
         macro_rules! payload_api {
-            ($call:ident, $param:ty, $path:expr, $method:expr, $action:ident) => (
+            ($call:ident, $param:ty, $path:expr, $method:expr, $action:ident, $fields:expr) => (
                 if path == $path && req.method == $method {
                     type Arg = $param;
                     return {
                         let api = &self.api;
-                        let source = itry!(Self::read_body_to_string(&mut req.body));
-                        match Path::new().push_str("body",
-                            |path| Arg::from_str_at(path, &source as &str))
+                        let source = read_body_to_string!();
+                        match self.parse_body(&source, &$fields,
+                            |path, json| Arg::parse(path, json))
                         {
                             Ok(arg) => {
                                 $action!(api, arg, $call)
@@ -244,16 +842,22 @@ impl Handler for TaxonomyRouter {
         // Generates the code to process a given HTTP call with a json body.
         // This version takes 2 parameters for the internal call.
         macro_rules! payload_api2 {
-            ($call:ident, $name1:ident => $param1:ty, $name2:ident => $param2:ty, $path:expr, $method:expr) => (
+            ($call:ident, $name1:ident => $param1:ty, $name2:ident => $param2:ty, $path:expr, $method:expr, $fields:expr) => (
                 if path == $path && req.method == $method {
                     type Param1 = $param1;
                     type Param2 = $param2;
                     return {
-                        let source = itry!(Self::read_body_to_string(&mut req.body));
+                        let source = read_body_to_string!();
                         let json = match serde_json::de::from_str(&source as &str) {
                             Err(err) => return self.build_parse_error(&ParseError::json(err)),
                             Ok(args) => args
                         };
+                        if self.limits.strict {
+                            if let Err(err) = Path::new().push_str("body",
+                                |path| $fields.check(path, &json)) {
+                                return self.build_parse_error(&err);
+                            }
+                        }
                         let arg_1 = match Path::new().push_str(&format!("body.{}", stringify!($name1)),
                             |path| Param1::take(path, &json, stringify!($name1))) {
                             Err(err) => return self.build_parse_error(&err),
@@ -272,35 +876,183 @@ impl Handler for TaxonomyRouter {
 
         // Keep these urls in sync with the AuthEndpoint(s) in the create() method.
 
+        // The catalog of standardized channels.
+        if path == ["channels", "standard"] && req.method == Method::Get {
+            return self.build_response(&*STANDARD_CHANNELS);
+        }
+
+        // The history of what happened.
+        if path == ["timeline"] && req.method == Method::Get {
+            return self.timeline_response(req);
+        }
+
+        // Statistics on the calls to adapters, which tell who uses which device.
+        if path[0] == "metrics" && !self.is_admin(&user) {
+            return Ok(Response::with(Status::Forbidden));
+        }
+        if path == ["metrics"] && req.method == Method::Get {
+            return self.build_response(&*self.api.metrics());
+        }
+
+        // The calls to adapters in progress, which may be aborted when a device wedges.
+        if path[0] == "operations" && !self.is_admin(&user) {
+            return Ok(Response::with(Status::Forbidden));
+        }
+        if path == ["operations"] && req.method == Method::Get {
+            return self.build_response(&self.api.pending_operations());
+        }
+        if path.len() == 2 && path[0] == "operations" && req.method == Method::Delete {
+            let id = itry!(path[1].parse::<usize>(), Status::BadRequest);
+            return if self.api.abort_operation(id) {
+                Ok(Response::with(Status::NoContent))
+            } else {
+                Ok(Response::with((Status::NotFound, format!("No operation {}", id))))
+            };
+        }
+
+        // How often each channel is used, most used first.
+        if path == ["stats", "channels"] && req.method == Method::Get {
+            let stats = self.api
+                .channel_usage()
+                .iter()
+                .map(|&(ref channel, ref stats)| {
+                    vec![("id", channel.id.to_json()),
+                         ("service", channel.service.to_json()),
+                         ("feature", channel.feature.to_json()),
+                         ("usage", stats.to_json())]
+                        .to_json()
+                })
+                .collect();
+            return self.build_response(&JSON::Array(stats));
+        }
+
+        // The alerts raised by adapters.
+        if path == ["alerts"] && req.method == Method::Get {
+            return self.build_response(&self.api.alerts());
+        }
+        if path.len() == 2 && path[0] == "alerts" && req.method == Method::Delete {
+            let id = itry!(path[1].parse::<u64>(), Status::BadRequest);
+            return if self.api.dismiss_alert(id) {
+                Ok(Response::with(Status::NoContent))
+            } else {
+                Ok(Response::with((Status::NotFound, format!("No alert {}", id))))
+            };
+        }
+
+        // The frames recorded for the websockets opened with `trace=true`.
+        if path[0] == "ws-traces" && !self.is_admin(&user) {
+            return Ok(Response::with(Status::Forbidden));
+        }
+        if path == ["ws-traces"] && req.method == Method::Get {
+            let names = self.ws_traces.list().into_iter().map(JSON::String).collect();
+            return self.build_response(&JSON::Array(names));
+        }
+        if path.len() == 2 && path[0] == "ws-traces" {
+            let not_found = (Status::NotFound, format!("No trace {}", path[1]));
+            match req.method {
+                Method::Get => {
+                    return match self.ws_traces.read(path[1]) {
+                        Some(entries) => self.build_response(&JSON::Array(entries)),
+                        None => Ok(Response::with(not_found)),
+                    };
+                }
+                Method::Delete => {
+                    return if self.ws_traces.remove(path[1]) {
+                        Ok(Response::with(Status::NoContent))
+                    } else {
+                        Ok(Response::with(not_found))
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        // The configuration properties that may be changed at runtime.
+        if path[0] == "config" && (path.len() == 1 || path.len() == 3) {
+            if !self.is_admin(&user) {
+                return Ok(Response::with(Status::Forbidden));
+            }
+            let property = if path.len() == 3 {
+                Some((path[1], path[2]))
+            } else {
+                None
+            };
+            return self.config_response(&req.method, &mut req.body, property);
+        }
+
+        // The companion apps.
+        if path[0] == "apps" && path.len() <= 2 {
+            return self.apps_response(&req.method, &mut req.body, path.get(1).cloned());
+        }
+
+        // The global modes, e.g. "home" or "away". They are entered through channel
+        // `mode/current`. Modes override the configuration, so only admins define them.
+        if path[0] == "modes" && path.len() <= 2 {
+            if req.method != Method::Get && !self.is_admin(&user) {
+                return Ok(Response::with(Status::Forbidden));
+            }
+            return self.modes_response(&req.method, &mut req.body, path.get(1).cloned());
+        }
+
+        // The sets of watches that websocket clients follow as a unit, with `resume`.
+        if path[0] == "watch-sets" && path.len() <= 2 {
+            return self.watch_sets_response(&req.method, &mut req.body, path.get(1).cloned());
+        }
+
+        // Whether each adapter started.
+        if path == ["adapters", "status"] && req.method == Method::Get {
+            return self.build_response(&self.adapter_statuses.to_json());
+        }
+
+        // How each adapter is doing, as reported by the adapters themselves.
+        if path == ["adapters", "reported-status"] && req.method == Method::Get {
+            return self.build_response(&self.api.adapter_statuses());
+        }
+
         // Selectors queries.
-        get_post_api!(get_services, ServiceSelector, ["services"]);
-        get_post_api!(get_channels, ChannelSelector, ["channels"]);
+        get_post_api!(get_services_at_revision, ServiceSelector, ["services"], SERVICE_SELECTORS);
+        get_post_api!(get_channels_at_revision, ChannelSelector, ["channels"], CHANNEL_SELECTORS);
 
         // Fetching and getting values.
         // We can't use a GET http method here because the Fetch() DOM api
         // doesn't allow bodies with GET and HEAD requests.
-        payload_api!(fetch_values, Vec<ChannelSelectorWithFeature>, ["channels", "get"], Method::Put, binary_response);
-        payload_api!(send_values, TargetMap<ChannelSelectorWithFeature, Payload>, ["channels", "set"], Method::Put, simple_response);
+        payload_api!(fetch_values, Vec<ChannelSelectorWithFeature>, ["channels", "get"], Method::Put, binary_response, CHANNEL_SELECTORS);
+        payload_api!(send_values, TargetMap<ChannelSelectorWithFeature, Payload>, ["channels", "set"], Method::Put, send_response, TARGETS);
+
+        // Reading the latest values, without calling the adapters.
+        payload_api!(fetch_latest, Vec<ChannelSelector>, ["channels", "latest"], Method::Put, json_response, CHANNEL_SELECTORS);
+
+        // Reading the values recorded over time.
+        payload_api2!(get_history,
+                      channels => Vec<ChannelSelector>,
+                      range => HistoryRange,
+                      ["channels", "history"], Method::Put, CHANNEL_HISTORY);
 
         // Adding tags.
         payload_api2!(add_service_tags,
                       services => Vec<ServiceSelector>,
                       tags => Vec<Id<TagId>>,
-                      ["services", "tags"], Method::Post);
+                      ["services", "tags"], Method::Post, SERVICE_TAGS);
         payload_api2!(add_channel_tags,
                     channels => Vec<ChannelSelector>,
                     tags => Vec<Id<TagId>>,
-                    ["channels", "tags"], Method::Post);
+                    ["channels", "tags"], Method::Post, CHANNEL_TAGS);
+
+        // Editing the metadata of services.
+        payload_api2!(set_service_metadata,
+                      services => Vec<ServiceSelector>,
+                      metadata => ServiceMetadata,
+                      ["services", "metadata"], Method::Put, SERVICE_METADATA);
 
         // Removing tags.
         payload_api2!(remove_service_tags,
                       services => Vec<ServiceSelector>,
                       tags => Vec<Id<TagId>>,
-                      ["services", "tags"], Method::Delete);
+                      ["services", "tags"], Method::Delete, SERVICE_TAGS);
         payload_api2!(remove_channel_tags,
                        channels => Vec<ChannelSelector>,
                        tags => Vec<Id<TagId>>,
-                       ["channels", "tags"], Method::Delete);
+                       ["channels", "tags"], Method::Delete, CHANNEL_TAGS);
 
         // Fallthrough, returning a 404.
         Ok(Response::with((Status::NotFound, format!("Unknown url: {}", req.url))))
@@ -312,7 +1064,30 @@ pub fn create<T>(controller: T,
                  -> (Chain, Vec<(Vec<Method>, String)>)
     where T: Controller
 {
-    let router = TaxonomyRouter::new(adapter_api);
+    let config = controller.get_config();
+    let limits = BodyLimits {
+        max_size: config.get_or_set_default("foxbox",
+                                "max_body_size",
+                                &DEFAULT_MAX_BODY_SIZE.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_MAX_BODY_SIZE),
+        max_binary_size: config.get_or_set_default("foxbox",
+                                "max_binary_body_size",
+                                &DEFAULT_MAX_BINARY_BODY_SIZE.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_MAX_BINARY_BODY_SIZE),
+        strict: config.get_or_set_default("foxbox", "strict_json", "false") == "true",
+    };
+    let router = TaxonomyRouter::new(adapter_api,
+                                     controller.get_timeline(),
+                                     controller.get_adapter_statuses(),
+                                     controller.get_app_registry(),
+                                     controller.get_modes(),
+                                     controller.get_watch_sets(),
+                                     limits,
+                                     controller.get_ws_traces(),
+                                     config,
+                                     controller.get_users_manager());
 
     // The list of endpoints supported by this router.
     // Keep it in sync with all the (url path, http method) from
@@ -320,11 +1095,34 @@ pub fn create<T>(controller: T,
     let endpoints = vec![
         (vec![Method::Get, Method::Post], "services".to_owned()),
         (vec![Method::Post, Method::Delete], "services/tags".to_owned()),
+        (vec![Method::Put], "services/metadata".to_owned()),
         (vec![Method::Get, Method::Post], "channels".to_owned()),
+        (vec![Method::Get], "channels/standard".to_owned()),
         (vec![Method::Put], "channels/get".to_owned()),
         (vec![Method::Put], "channels/set".to_owned()),
+        (vec![Method::Put], "channels/latest".to_owned()),
+        (vec![Method::Put], "channels/history".to_owned()),
         (vec![Method::Post, Method::Delete], "channels/tags".to_owned()),
         (vec![Method::Get, Method::Put], "channel/:id".to_owned()),
+        (vec![Method::Get], "timeline".to_owned()),
+        (vec![Method::Get], "metrics".to_owned()),
+        (vec![Method::Get], "operations".to_owned()),
+        (vec![Method::Delete], "operations/:id".to_owned()),
+        (vec![Method::Get], "stats/channels".to_owned()),
+        (vec![Method::Get], "adapters/status".to_owned()),
+        (vec![Method::Get], "adapters/reported-status".to_owned()),
+        (vec![Method::Get], "alerts".to_owned()),
+        (vec![Method::Delete], "alerts/:id".to_owned()),
+        (vec![Method::Get], "ws-traces".to_owned()),
+        (vec![Method::Get, Method::Delete], "ws-traces/:name".to_owned()),
+        (vec![Method::Get], "config".to_owned()),
+        (vec![Method::Get, Method::Put], "config/:namespace/:key".to_owned()),
+        (vec![Method::Get, Method::Post], "apps".to_owned()),
+        (vec![Method::Delete], "apps/:id".to_owned()),
+        (vec![Method::Get, Method::Post], "modes".to_owned()),
+        (vec![Method::Get, Method::Delete], "modes/:name".to_owned()),
+        (vec![Method::Get, Method::Post], "watch-sets".to_owned()),
+        (vec![Method::Get, Method::Delete], "watch-sets/:id".to_owned()),
     ];
 
     let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
@@ -364,7 +1162,7 @@ describe! taxonomy_router {
                                     Headers::new(),
                                     &mount).unwrap();
         let body = response::extract_body_to_string(response);
-        let s = r#"[{"adapter":"clock@link.mozilla.org","channels":{"getter:interval.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-interval-seconds","id":"getter:interval.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":null,"supports_send":null,"tags":[]},"getter:timeofday.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-of-day-seconds","id":"getter:timeofday.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"Duration (s)"}},"supports_send":null,"tags":[]},"getter:timestamp.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-timestamp-rfc-3339","id":"getter:timestamp.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)"}},"supports_send":null,"tags":[]}},"id":"service:clock@link.mozilla.org","properties":{"model":"Mozilla clock v1"},"tags":[]}]"#;
+        let s = r#"[{"adapter":"clock@link.mozilla.org","channels":{"getter:interval.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-interval-seconds","id":"getter:interval.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":null,"supports_send":null,"tags":[]},"getter:timeofday.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-of-day-seconds","id":"getter:timeofday.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"Duration (s)"}},"supports_send":null,"tags":[]},"getter:timestamp.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-timestamp-rfc-3339","id":"getter:timestamp.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)"}},"supports_send":null,"tags":[]}},"friendly_name":null,"icon":null,"id":"service:clock@link.mozilla.org","properties":{"model":"Mozilla clock v1"},"room":null,"tags":[]}]"#;
 
         assert_eq!(body, s);
     }
@@ -375,11 +1173,137 @@ describe! taxonomy_router {
                                     r#"[{"id":"service:clock@link.mozilla.org"}]"#,
                                     &mount).unwrap();
         let body = response::extract_body_to_string(response);
-        let s = r#"[{"adapter":"clock@link.mozilla.org","channels":{"getter:interval.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-interval-seconds","id":"getter:interval.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":null,"supports_send":null,"tags":[]},"getter:timeofday.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-of-day-seconds","id":"getter:timeofday.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"Duration (s)"}},"supports_send":null,"tags":[]},"getter:timestamp.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-timestamp-rfc-3339","id":"getter:timestamp.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)"}},"supports_send":null,"tags":[]}},"id":"service:clock@link.mozilla.org","properties":{"model":"Mozilla clock v1"},"tags":[]}]"#;
+        let s = r#"[{"adapter":"clock@link.mozilla.org","channels":{"getter:interval.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-interval-seconds","id":"getter:interval.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":null,"supports_send":null,"tags":[]},"getter:timeofday.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-of-day-seconds","id":"getter:timeofday.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"Duration (s)"}},"supports_send":null,"tags":[]},"getter:timestamp.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-timestamp-rfc-3339","id":"getter:timestamp.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)"}},"supports_send":null,"tags":[]}},"friendly_name":null,"icon":null,"id":"service:clock@link.mozilla.org","properties":{"model":"Mozilla clock v1"},"room":null,"tags":[]}]"#;
 
         assert_eq!(body, s);
     }
 
+    it "should tell the revision of the taxonomy" {
+        use foxbox_taxonomy::api::API;
+        use foxbox_taxonomy::selector::ServiceSelector;
+        use foxbox_taxonomy::util::{Id, TagId};
+
+        let response = request::get("http://localhost:3000/api/v1/services",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let revision = response.headers.get::<XTaxonomyRevision>().unwrap().0;
+        assert_eq!(revision, taxo_manager.revision());
+
+        taxo_manager.add_service_tags(vec![ServiceSelector::new()], vec![Id::<TagId>::new("hall")]);
+        let response = request::get("http://localhost:3000/api/v1/channels",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert!(response.headers.get::<XTaxonomyRevision>().unwrap().0 > revision);
+    }
+
+    it "should link channels to the registered apps" {
+        let response = request::post("http://localhost:3000/api/v1/apps",
+                                     Headers::new(),
+                                     r#"{"id":"clockapp","name":"Clock","links":{"clock/time-of-day-seconds":"clock://show?channel={channel}"}}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Created);
+
+        let response = request::get("http://localhost:3000/api/v1/channels",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert!(body.contains(r#""app_links":[{"app":"clockapp","icon":null,"name":"Clock","url":"clock://show?channel=getter%3Atimeofday.clock%40link.mozilla.org"}]"#));
+
+        let response = request::delete("http://localhost:3000/api/v1/apps/clockapp",
+                                       Headers::new(),
+                                       &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::NoContent);
+        let response = request::get("http://localhost:3000/api/v1/channels",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert!(!response::extract_body_to_string(response).contains("app_links"));
+    }
+
+    it "should define and remove modes" {
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        let mode = r#"{"name":"away","disable_rules":["morning-lights"],"starts_at":["9:00"]}"#;
+        let (status, _) = harness.request(Method::Post, "/api/v1/modes", mode, true);
+        assert_eq!(status, Status::Created);
+        let (status, _) = harness.request(Method::Post,
+                                          "/api/v1/modes",
+                                          r#"{"disable_rules":["morning-lights"]}"#,
+                                          true);
+        assert_eq!(status, Status::BadRequest);
+        let mode = r#"{"name":"away",
+                       "config":[{"namespace":"supervisor","key":"x.command","value":"sh"}]}"#;
+        let (status, _) = harness.request(Method::Post, "/api/v1/modes", mode, true);
+        assert_eq!(status, Status::BadRequest);
+
+        let (status, body) = harness.request(Method::Get, "/api/v1/modes/away", "", false);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body, r#"{"config":[],"disable_rules":["morning-lights"],"enable_rules":[],"name":"away","starts_at":["09:00"]}"#);
+        let (_, body) = harness.request(Method::Get, "/api/v1/modes", "", false);
+        assert!(body.starts_with(r#"{"current":null,"modes":[{"config":[]"#));
+
+        let (status, _) = harness.request(Method::Delete, "/api/v1/modes/away", "", true);
+        assert_eq!(status, Status::NoContent);
+        let (status, _) = harness.request(Method::Get, "/api/v1/modes/away", "", false);
+        assert_eq!(status, Status::NotFound);
+    }
+
+    it "should refuse to define modes to users who are not admins" {
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        let mode = r#"{"name":"away",
+                       "config":[{"namespace":"presence","key":"enabled","value":"true"}]}"#;
+        let (status, _) = harness.request(Method::Post, "/api/v1/modes", mode, false);
+        assert_eq!(status, Status::Forbidden);
+        let (status, _) = harness.request(Method::Post, "/api/v1/modes", mode, true);
+        assert_eq!(status, Status::Created);
+        let (status, _) = harness.request(Method::Delete, "/api/v1/modes/away", "", false);
+        assert_eq!(status, Status::Forbidden);
+        let (status, _) = harness.request(Method::Get, "/api/v1/modes/away", "", false);
+        assert_eq!(status, Status::Ok);
+    }
+
+    it "should register and remove watch sets" {
+        let response = request::post("http://localhost:3000/api/v1/watch-sets",
+                                     Headers::new(),
+                                     r#"{"name":"dashboard","watches":[{"selector":{"feature":"clock/time-of-day-seconds"}},{"selector":[{"tags":["hall"]}],"range":"On"}]}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Created);
+        let body = response::extract_body_to_string(response);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let id = json.find("id").and_then(|id| id.as_string()).unwrap().to_owned();
+        assert_eq!(json.find("watches").and_then(|watches| watches.as_array()).map(|watches| watches.len()), Some(2));
+
+        // Watches that can't be resumed are rejected upfront.
+        let response = request::post("http://localhost:3000/api/v1/watch-sets",
+                                     Headers::new(),
+                                     r#"{"name":"broken","watches":[{"selector":{"feature":42}}]}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+        let response = request::post("http://localhost:3000/api/v1/watch-sets",
+                                     Headers::new(),
+                                     r#"{"name":"empty","watches":[]}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+
+        let response = request::get("http://localhost:3000/api/v1/watch-sets",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert!(body.contains(r#""name":"dashboard""#));
+
+        let url = format!("http://localhost:3000/api/v1/watch-sets/{}", id);
+        let response = request::delete(&url, Headers::new(), &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::NoContent);
+        let response = request::get(&url, Headers::new(), &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::NotFound);
+    }
+
     it "should return the list of channels from a POST request" {
         let response = request::post("http://localhost:3000/api/v1/channels",
                                      Headers::new(),
@@ -390,6 +1314,624 @@ describe! taxonomy_router {
 
         assert_eq!(body, s);
     }
+
+    it "should return the catalog of standardized channels" {
+        let response = request::get("http://localhost:3000/api/v1/channels/standard",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let s = r#"{"description":"Log text to a console, a file, etc.","feature":"log/append-text","supports_fetch":null,"supports_send":{"accepts":{"requires":"String"}},"supports_watch":null}"#;
+
+        assert!(body.starts_with(r#"[{"description":"Determine whether a door is locked"#));
+        assert!(body.contains(s));
+        assert!(body.contains(r#""feature":"device/identify","supports_fetch":null,"supports_send":{}"#));
+    }
+
+    it "should set the metadata of services" {
+        use iron::status::Status;
+
+        let response = request::put("http://localhost:3000/api/v1/services/metadata",
+                                    Headers::new(),
+                                    r#"{"services": [{"id":"service:clock@link.mozilla.org"}],
+                                        "metadata": {"friendly_name": "Clock", "room": "Hall"}}"#,
+                                    &mount).unwrap();
+        assert_eq!(response::extract_body_to_string(response), "1");
+
+        let response = request::get("http://localhost:3000/api/v1/services",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert!(body.contains(r#""friendly_name":"Clock","icon":null"#));
+        assert!(body.contains(r#""room":"Hall""#));
+
+        let response = request::put("http://localhost:3000/api/v1/services/metadata",
+                                    Headers::new(),
+                                    r#"{"services": [{"id":"service:clock@link.mozilla.org"}],
+                                        "metadata": {"colour": "blue"}}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+    }
+
+    it "should only return the history of channels that record it" {
+        use iron::status::Status;
+
+        let response = request::put("http://localhost:3000/api/v1/channels/history",
+                                    Headers::new(),
+                                    r#"{"channels": [{"id":"getter:interval.clock@link.mozilla.org"}],
+                                        "range": {"from": 0}}"#,
+                                    &mount).unwrap();
+        assert_eq!(response::extract_body_to_string(response), "{}");
+
+        let response = request::put("http://localhost:3000/api/v1/channels/history",
+                                    Headers::new(),
+                                    r#"{"channels": [], "range": {"from": "yesterday"}}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+    }
+
+    it "should filter the timeline" {
+        use foxbox_core::timeline::{Entry, EntryKind};
+        use foxbox_core::traits::Controller;
+        use iron::status::Status;
+        use serde_json::value::Value as JSON;
+
+        let controller = ControllerStub::new();
+        let timeline = controller.get_timeline();
+        timeline.push(Entry::new(EntryKind::Event,
+                                 vec!["getter:interval.clock@link.mozilla.org".to_owned()],
+                                 None,
+                                 JSON::Null));
+        timeline.push(Entry::new(EntryKind::Notification, vec![], None, JSON::Null));
+        let mut mount = Mount::new();
+        mount.mount("/api/v1", create(controller, &taxo_manager).0);
+
+        let response = request::get("http://localhost:3000/api/v1/timeline?\
+                                     service=service:clock@link.mozilla.org",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body: JSON = serde_json::from_str(&response::extract_body_to_string(response))
+            .unwrap();
+        let entries = body.as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].find("kind").and_then(JSON::as_string), Some("event"));
+
+        let response = request::get("http://localhost:3000/api/v1/timeline?kind=gossip",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+    }
+
+    it "should report the calls to adapters" {
+        use iron::method::Method;
+        use iron::status::Status;
+        use serde_json::value::Value as JSON;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        clock::Clock::init(&harness.manager).unwrap();
+        harness.request(Method::Put,
+                        "/api/v1/channels/get",
+                        r#"[{"id":"getter:timeofday.clock@link.mozilla.org",
+                             "feature":"clock/time-of-day-seconds"}]"#,
+                        false);
+        let (status, body) = harness.request_json(Method::Get, "/api/v1/metrics", "", true);
+        assert_eq!(status, Status::Ok);
+        let fetch = body.find("clock@link.mozilla.org").and_then(|clock| clock.find("Fetch"));
+        assert_eq!(fetch.and_then(|fetch| fetch.find("calls")).and_then(JSON::as_u64), Some(1));
+
+        let (status, _) = harness.request(Method::Get, "/api/v1/metrics", "", false);
+        assert_eq!(status, Status::Forbidden);
+    }
+
+    it "should report the status of adapters" {
+        use foxbox_core::adapter_status::AdapterStatus;
+        use foxbox_core::traits::Controller;
+
+        let controller = ControllerStub::new();
+        controller.get_adapter_statuses().set("clock", AdapterStatus::Running);
+        controller.get_adapter_statuses().set("philips_hue",
+                                              AdapterStatus::Failed {
+                                                  error: "unreachable".to_owned(),
+                                                  attempts: 1,
+                                                  retry_in: 10,
+                                              });
+        let mut mount = Mount::new();
+        mount.mount("/api/v1", create(controller, &taxo_manager).0);
+
+        let response = request::get("http://localhost:3000/api/v1/adapters/status",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response::extract_body_to_string(response),
+                   r#"{"clock":{"status":"running"},"philips_hue":{"attempts":1,"error":"unreachable","retry_in":10,"status":"failed"}}"#);
+    }
+
+    it "should report the statuses reported by adapters" {
+        use foxbox_taxonomy::adapter::{AdapterManagerHandle, AdapterStatus};
+        use foxbox_taxonomy::util::Id;
+        use serde_json::value::Value as JSON;
+
+        taxo_manager.report_adapter_status(&Id::new("OpenZwave Adapter"),
+                                           AdapterStatus::Degraded("No controller".to_owned()));
+        taxo_manager.report_adapter_status(&clock::Clock::id(), AdapterStatus::Running);
+
+        let response = request::get("http://localhost:3000/api/v1/adapters/reported-status",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body: JSON = serde_json::from_str(&response::extract_body_to_string(response))
+            .unwrap();
+        assert_eq!(body.lookup("OpenZwave Adapter.status").and_then(JSON::as_string),
+                   Some("degraded"));
+        assert_eq!(body.lookup("OpenZwave Adapter.reason").and_then(JSON::as_string),
+                   Some("No controller"));
+        let clock = body.find(&clock::Clock::id().to_string()).unwrap();
+        assert_eq!(clock.find("status").and_then(JSON::as_string), Some("running"));
+        assert_eq!(clock.find("reason"), None);
+    }
+
+    it "should reject bodies that are too large" {
+        use foxbox_core::traits::Controller;
+        use iron::status::Status;
+
+        let controller = ControllerStub::new();
+        controller.get_config().set("foxbox", "max_body_size", "64");
+        let mut mount = Mount::new();
+        mount.mount("/api/v1", create(controller, &taxo_manager).0);
+
+        let id: String = ::std::iter::repeat('a').take(64).collect();
+        let body = format!(r#"[{{"id":"{}"}}]"#, id);
+        let response = request::post("http://localhost:3000/api/v1/services",
+                                     Headers::new(),
+                                     &body,
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::PayloadTooLarge);
+        assert_eq!(response::extract_body_to_string(response),
+                   r#"{"BodyTooLarge":{"limit":64}}"#);
+
+        let response = request::post("http://localhost:3000/api/v1/services",
+                                     Headers::new(),
+                                     r#"[{"id":"service:clock@link.mozilla.org"}]"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Ok);
+    }
+
+    it "should reject unknown fields in strict mode" {
+        use foxbox_core::traits::Controller;
+        use iron::status::Status;
+
+        let body = r#"[{"select": [{"id": "getter:timeofday.clock@link.mozilla.org",
+                                    "feture": "clock/time-of-day-seconds"}],
+                        "value": 0}]"#;
+
+        // By default, unknown fields are ignored.
+        let response = request::put("http://localhost:3000/api/v1/channels/set",
+                                    Headers::new(),
+                                    body,
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Ok);
+
+        let controller = ControllerStub::new();
+        controller.get_config().set("foxbox", "strict_json", "true");
+        let mut mount = Mount::new();
+        mount.mount("/api/v1", create(controller, &taxo_manager).0);
+
+        let response = request::put("http://localhost:3000/api/v1/channels/set",
+                                    Headers::new(),
+                                    body,
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+        assert_eq!(response::extract_body_to_string(response),
+                   r#"{"UnknownFields":{"names":["feture"],"at":"body[0].select[0]"}}"#);
+
+        let response = request::post("http://localhost:3000/api/v1/services/tags",
+                                     Headers::new(),
+                                     r#"{"services": [{"id": "service:clock@link.mozilla.org"}],
+                                         "tags": ["hall"], "colour": "blue"}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+    }
+
+    it "should queue the values sent to unreachable devices on request" {
+        use foxbox_taxonomy::adapter::AdapterManagerHandle;
+        use foxbox_taxonomy::api::Error;
+        use foxbox_taxonomy::channel::*;
+        use foxbox_taxonomy::fake_adapter::{Effect, Tweak};
+        use foxbox_taxonomy::services::*;
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        let device = harness.add_fake_adapter("lock@test");
+        let adapter_id = Id::<AdapterId>::new("lock@test");
+        let service_id = Id::<ServiceId>::new("service:lock@test");
+        let channel_id = Id::<Channel>::new("setter:lock@test");
+        harness.manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+        harness.manager
+            .add_channel(Channel {
+                id: channel_id.clone(),
+                service: service_id.clone(),
+                adapter: adapter_id,
+                ..DOOR_IS_LOCKED.clone()
+            })
+            .unwrap();
+        (device.tweak)(Tweak::InjectSetterError(channel_id.clone(),
+                                                Some(Error::Unreachable(channel_id.clone()))));
+
+        let body = r#"[{"select": [{"id": "setter:lock@test", "feature": "door/is-locked"}],
+                        "value": "Locked"}]"#;
+        let (status, _) =
+            harness.request(Method::Put, "/api/v1/channels/set?deliver=later", body, false);
+        assert_eq!(status, Status::BadRequest);
+        let (status, response) = harness.request(Method::Put, "/api/v1/channels/set", body, false);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(response,
+                   r#"{"setter:lock@test":{"Error":{"Unreachable":"setter:lock@test"}}}"#);
+        let url = "/api/v1/channels/set?deliver=when-reachable&ttl=60";
+        let (status, response) = harness.request(Method::Put, url, body, false);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(response, r#"{"setter:lock@test":"queued"}"#);
+
+        (device.tweak)(Tweak::InjectSetterError(channel_id.clone(), None));
+        harness.manager.service_reachable(&service_id);
+        let Effect::ValueSent(id, _) = device.effects.try_recv().unwrap();
+        assert_eq!(id, channel_id);
+    }
+
+    it "should tell which values would be sent without sending them on request" {
+        use foxbox_taxonomy::channel::*;
+        use foxbox_taxonomy::services::*;
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        let device = harness.add_fake_adapter("light@test");
+        let adapter_id = Id::<AdapterId>::new("light@test");
+        let service_id = Id::<ServiceId>::new("service:light@test");
+        harness.manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+        for id in &["setter:kitchen@test", "setter:attic@test"] {
+            harness.manager
+                .add_channel(Channel {
+                    id: Id::new(id),
+                    service: service_id.clone(),
+                    adapter: adapter_id.clone(),
+                    ..LIGHT_IS_ON.clone()
+                })
+                .unwrap();
+        }
+
+        let url = "/api/v1/channels/set?dry_run=true";
+        let body = r#"[{"select": [{"feature": "light/is-on"}], "value": "On"}]"#;
+        let (status, json) = harness.request_json(Method::Put, url, body, false);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(json.find("setter:kitchen@test").and_then(|value| value.as_string()),
+                   Some("On"));
+        assert_eq!(json.find("setter:attic@test").and_then(|value| value.as_string()),
+                   Some("On"));
+
+        // Invalid values are reported, as they would be by a regular send.
+        let body = r#"[{"select": [{"id": "setter:kitchen@test", "feature": "light/is-on"}],
+                        "value": "Dimmed"}]"#;
+        let (status, json) = harness.request_json(Method::Put, url, body, false);
+        assert_eq!(status, Status::Ok);
+        assert!(json.lookup("setter:kitchen@test.Error").is_some());
+        assert!(device.effects.try_recv().is_err());
+    }
+
+    it "should send values all or nothing" {
+        use foxbox_taxonomy::api::Error;
+        use foxbox_taxonomy::channel::*;
+        use foxbox_taxonomy::fake_adapter::Tweak;
+        use foxbox_taxonomy::services::*;
+        use foxbox_taxonomy::values::{OnOff, Value};
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        let device = harness.add_fake_adapter("light@test");
+        let adapter_id = Id::<AdapterId>::new("light@test");
+        let service_id = Id::<ServiceId>::new("service:light@test");
+        harness.manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+        for id in &["setter:kitchen@test", "setter:attic@test"] {
+            harness.manager
+                .add_channel(Channel {
+                    id: Id::new(id),
+                    service: service_id.clone(),
+                    adapter: adapter_id.clone(),
+                    ..LIGHT_IS_ON.clone()
+                })
+                .unwrap();
+        }
+        let kitchen = Id::new("setter:kitchen@test");
+        let attic = Id::new("setter:attic@test");
+        (device.tweak)(Tweak::InjectGetterValue(kitchen, Ok(Some(Value::new(OnOff::Off)))));
+        (device.tweak)(Tweak::InjectSetterError(attic.clone(), Some(Error::Unreachable(attic))));
+
+        let url = "/api/v1/channels/set?atomic=true";
+        let body = r#"[{"select": [{"feature": "light/is-on"}], "value": "On"}]"#;
+        let (status, json) = harness.request_json(Method::Put, url, body, false);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(json.lookup("setter:kitchen@test.Error").and_then(|err| err.as_string()),
+                   Some("TransactionAborted"));
+        assert!(json.lookup("setter:attic@test.Error.Unreachable").is_some());
+    }
+
+    it "should count the uses of each channel" {
+        use foxbox_taxonomy::channel::*;
+        use foxbox_taxonomy::services::*;
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        harness.add_fake_adapter("light@test");
+        let adapter_id = Id::<AdapterId>::new("light@test");
+        let service_id = Id::<ServiceId>::new("service:light@test");
+        harness.manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+        for id in &["setter:kitchen@test", "setter:attic@test"] {
+            harness.manager
+                .add_channel(Channel {
+                    id: Id::new(id),
+                    service: service_id.clone(),
+                    adapter: adapter_id.clone(),
+                    ..LIGHT_IS_ON.clone()
+                })
+                .unwrap();
+        }
+
+        let body = r#"[{"select": [{"id": "setter:kitchen@test", "feature": "light/is-on"}],
+                        "value": "On"}]"#;
+        for _ in 0..2 {
+            let (status, _) = harness.request(Method::Put, "/api/v1/channels/set", body, false);
+            assert_eq!(status, Status::Ok);
+        }
+
+        let (status, json) = harness.request_json(Method::Get, "/api/v1/stats/channels", "", false);
+        assert_eq!(status, Status::Ok);
+        let channels = json.as_array().unwrap();
+        assert_eq!(channels.len(), 2);
+        // The most used channels come first, the channels never used last.
+        assert_eq!(channels[0].find("id").and_then(|id| id.as_string()),
+                   Some("setter:kitchen@test"));
+        assert_eq!(channels[0].lookup("usage.Send.last_7_days").and_then(|n| n.as_u64()),
+                   Some(2));
+        assert_eq!(channels[1].find("id").and_then(|id| id.as_string()),
+                   Some("setter:attic@test"));
+        assert_eq!(channels[1].lookup("usage.last_used"), Some(&serde_json::Value::Null));
+    }
+
+    it "should report stale latest values as expired" {
+        use foxbox_taxonomy::channel::*;
+        use foxbox_taxonomy::fake_adapter::Tweak;
+        use foxbox_taxonomy::services::*;
+        use foxbox_taxonomy::values::{format, OnOff, Value};
+        use iron::method::Method;
+        use iron::status::Status;
+        use std::thread;
+        use std::time::Duration;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        let device = harness.add_fake_adapter("motion@test");
+        let adapter_id = Id::<AdapterId>::new("motion@test");
+        let service_id = Id::<ServiceId>::new("service:motion@test");
+        harness.manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+        for &(id, valid_for) in &[("getter:motion@test", Some(Duration::from_millis(1))),
+                                  ("getter:power@test", None)] {
+            harness.manager
+                .add_channel(Channel {
+                    id: Id::new(id),
+                    service: service_id.clone(),
+                    adapter: adapter_id.clone(),
+                    feature: Id::new("x-test/is-on"),
+                    supports_fetch:
+                        Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+                    valid_for: valid_for,
+                    ..Channel::default()
+                })
+                .unwrap();
+            (device.tweak)(Tweak::InjectGetterValue(Id::new(id),
+                                                    Ok(Some(Value::new(OnOff::On)))));
+        }
+
+        let url = "/api/v1/channels/latest";
+        let body = r#"[{"feature": "x-test/is-on"}]"#;
+        let (status, json) = harness.request_json(Method::Put, url, body, false);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(json.find("getter:motion@test"), Some(&serde_json::Value::Null));
+
+        let body = r#"[{"feature": "x-test/is-on"}]"#;
+        let (status, _) = harness.request(Method::Put, "/api/v1/channels/get", body, false);
+        assert_eq!(status, Status::Ok);
+        thread::sleep(Duration::from_millis(10));
+
+        let (status, json) = harness.request_json(Method::Put, url, body, false);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(json.lookup("getter:motion@test.expired"),
+                   Some(&serde_json::Value::Bool(true)));
+        assert!(json.lookup("getter:motion@test.value").is_none());
+        assert_eq!(json.lookup("getter:power@test.value").and_then(|value| value.as_string()),
+                   Some("On"));
+        assert_eq!(json.lookup("getter:power@test.observed.source")
+                       .and_then(|source| source.as_string()),
+                   Some("cache"));
+    }
+
+    it "should expose the labels of services and channels" {
+        use foxbox_taxonomy::channel::*;
+        use foxbox_taxonomy::services::*;
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        harness.add_fake_adapter("light@test");
+        let adapter_id = Id::<AdapterId>::new("light@test");
+        let service_id = Id::<ServiceId>::new("service:light@test");
+        harness.manager
+            .add_service(Service {
+                label: Some("Lamp 2000".to_owned()),
+                icon: Some("lamp".to_owned()),
+                ..Service::empty(&service_id, &adapter_id)
+            })
+            .unwrap();
+        harness.manager
+            .add_channel(Channel {
+                id: Id::new("setter:kitchen@test"),
+                service: service_id.clone(),
+                adapter: adapter_id.clone(),
+                label: Some("Power".to_owned()),
+                description: Some("Turns the lamp on or off".to_owned()),
+                ..LIGHT_IS_ON.clone()
+            })
+            .unwrap();
+
+        let (status, json) = harness.request_json(Method::Get, "/api/v1/services", "", false);
+        assert_eq!(status, Status::Ok);
+        let service = &json.as_array().unwrap()[0];
+        assert_eq!(service.find("label").and_then(|label| label.as_string()),
+                   Some("Lamp 2000"));
+        assert_eq!(service.find("icon").and_then(|icon| icon.as_string()), Some("lamp"));
+        assert!(service.find("description").is_none());
+
+        let (status, json) = harness.request_json(Method::Get, "/api/v1/channels", "", false);
+        assert_eq!(status, Status::Ok);
+        let channel = &json.as_array().unwrap()[0];
+        assert_eq!(channel.find("label").and_then(|label| label.as_string()), Some("Power"));
+        assert_eq!(channel.find("description").and_then(|label| label.as_string()),
+                   Some("Turns the lamp on or off"));
+        assert!(channel.find("icon").is_none());
+
+        // The icon picked by the user takes precedence over the one of the adapter.
+        let body = r#"{"services": [{"id": "service:light@test"}],
+                       "metadata": {"icon": "reading-lamp"}}"#;
+        let (status, _) = harness.request(Method::Put, "/api/v1/services/metadata", body, false);
+        assert_eq!(status, Status::Ok);
+        let (_, json) = harness.request_json(Method::Get, "/api/v1/services", "", false);
+        assert_eq!(json.lookup("0.icon").and_then(|icon| icon.as_string()),
+                   Some("reading-lamp"));
+    }
+
+    it "should serve and delete the websocket traces" {
+        use foxbox_core::traits::Controller;
+        use foxbox_core::ws_trace::Direction;
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+        use ws::util::Token;
+
+        let harness = Harness::new();
+        let traces = harness.controller.get_ws_traces();
+        let name = traces.start(Token(1), "/?auth=secret&trace=true").unwrap();
+        traces.record(Token(1), Direction::In, r#"{"type": "watch", "password": "secret"}"#);
+        traces.stop(Token(1));
+
+        let (status, json) = harness.request_json(Method::Get, "/api/v1/ws-traces", "", true);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(json.as_array().map(|names| names.len()), Some(1));
+
+        let path = format!("/api/v1/ws-traces/{}", name);
+        let (status, body) = harness.request(Method::Get, &path, "", true);
+        assert_eq!(status, Status::Ok);
+        assert!(body.contains(r#""type":"watch""#));
+        assert!(!body.contains("secret"));
+
+        let (status, _) = harness.request(Method::Delete, &path, "", true);
+        assert_eq!(status, Status::NoContent);
+        let (status, _) = harness.request(Method::Get, &path, "", true);
+        assert_eq!(status, Status::NotFound);
+
+        let (status, _) = harness.request(Method::Get, "/api/v1/ws-traces", "", false);
+        assert_eq!(status, Status::Forbidden);
+    }
+
+    it "should list and abort the calls to adapters in progress" {
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        let (status, json) = harness.request_json(Method::Get, "/api/v1/operations", "", true);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(json.as_array().map(|operations| operations.len()), Some(0));
+
+        let (status, _) = harness.request(Method::Delete, "/api/v1/operations/1", "", true);
+        assert_eq!(status, Status::NotFound);
+        let (status, _) = harness.request(Method::Delete, "/api/v1/operations/x", "", true);
+        assert_eq!(status, Status::BadRequest);
+    }
+
+    it "should refuse the calls in progress to users who are not admins" {
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        let (status, _) = harness.request(Method::Get, "/api/v1/operations", "", false);
+        assert_eq!(status, Status::Forbidden);
+        let (status, _) = harness.request(Method::Delete, "/api/v1/operations/1", "", false);
+        assert_eq!(status, Status::Forbidden);
+    }
+
+    it "should change the registered configuration properties" {
+        use foxbox_core::config_store::ConfigType;
+        use foxbox_core::traits::Controller;
+        use iron::method::Method;
+        use iron::status::Status;
+        use std::sync::mpsc;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        let config = harness.controller.get_config();
+        config.register("netatmo", "poll_interval", ConfigType::Integer);
+        config.register("webpush", "gcm_api_key", ConfigType::Secret);
+        config.set("webpush", "gcm_api_key", "AIzaSecret");
+        let (tx, rx) = mpsc::channel();
+        config.watch("netatmo", tx);
+
+        let (status, json) = harness.request_json(Method::Get, "/api/v1/config", "", true);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(json.as_array().map(|properties| properties.len()), Some(2));
+        let (status, json) =
+            harness.request_json(Method::Get, "/api/v1/config/webpush/gcm_api_key", "", true);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(json.find("value").and_then(|value| value.as_string()), Some("[redacted]"));
+
+        let path = "/api/v1/config/netatmo/poll_interval";
+        let (status, _) = harness.request(Method::Put, path, r#"{"value": "often"}"#, true);
+        assert_eq!(status, Status::BadRequest);
+        let (status, json) = harness.request_json(Method::Put, path, r#"{"value": 600}"#, true);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(json.find("value").and_then(|value| value.as_string()), Some("600"));
+        assert_eq!(config.get("netatmo", "poll_interval"), Some("600".to_owned()));
+        assert_eq!(rx.try_recv().ok().and_then(|change| change.value),
+                   Some("600".to_owned()));
+
+        let path = "/api/v1/config/foxbox/secret";
+        let (status, _) = harness.request(Method::Put, path, r#"{"value": "x"}"#, true);
+        assert_eq!(status, Status::NotFound);
+        assert_eq!(config.get("foxbox", "secret"), None);
+    }
+
+    it "should refuse the configuration to users who are not admins" {
+        use foxbox_core::config_store::ConfigType;
+        use foxbox_core::traits::Controller;
+        use iron::method::Method;
+        use iron::status::Status;
+        use stubs::harness::Harness;
+
+        let harness = Harness::new();
+        let config = harness.controller.get_config();
+        config.register("netatmo", "poll_interval", ConfigType::Integer);
+
+        let (status, _) = harness.request(Method::Get, "/api/v1/config", "", false);
+        assert_eq!(status, Status::Forbidden);
+        let path = "/api/v1/config/netatmo/poll_interval";
+        let (status, _) = harness.request(Method::Put, path, r#"{"value": 600}"#, false);
+        assert_eq!(status, Status::Forbidden);
+        assert_eq!(config.get("netatmo", "poll_interval"), None);
+    }
 }
 
 #[cfg(test)]
@@ -490,7 +2032,7 @@ describe! binary_getter {
                 }).collect()
             }
 
-            fn register_watch(&self, mut watch: Vec<WatchTarget>) -> WatchResult
+            fn register_watch(&self, mut watch: Vec<WatchTarget>, _: User) -> WatchResult
             {
                 watch.drain(..).map(|(id, _, _)| {
                     (id.clone(), Err(Error::OperationNotSupported(Operation::Watch, id)))
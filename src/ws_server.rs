@@ -5,24 +5,140 @@ extern crate url;
 
 use self::url::Url;
 use foxbox_core::traits::Controller;
+use foxbox_core::watch_sets::WatchSetRegistry;
+use foxbox_core::ws_trace::{Direction, WsTraces};
+use foxbox_taxonomy::api::{Targetted, User, WatchEvent};
+use foxbox_taxonomy::io::{Delivery, Payload};
+use foxbox_taxonomy::manager::{AdapterManager, LeaseId};
+use foxbox_taxonomy::parse::{Parser, Path};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::util::Exactly;
+use foxbox_users::SessionToken;
 use openssl::ssl::{Ssl, SslContext, SslMethod};
 use openssl::x509::X509FileType;
+use serde_json;
+use serde_json::value::Value as JSON;
+use std::collections::HashMap;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::Duration;
 use std::thread;
+use transformable_channels::mpsc;
 use ws;
 use ws::{Handler, Sender, Result, Message, Handshake, CloseCode, Error};
 
+/// How long the watches of a client last without a keepalive, unless the client specifies it.
+const DEFAULT_LEASE_TTL_S: u64 = 60;
+
+/// Clients can't keep watches without a keepalive for longer than this.
+const MAX_LEASE_TTL_S: u64 = 3600;
+
 pub struct WsServer;
 
+/// The subscriptions of a client, by the id that tags their events. Each subscription is a
+/// leased watch, renewed by the keepalives of the client like the other watches.
+#[derive(Default)]
+struct Subscriptions {
+    leases: HashMap<String, LeaseId>,
+}
+
+impl Subscriptions {
+    fn contains(&self, id: &str) -> bool {
+        self.leases.contains_key(id)
+    }
+
+    fn insert(&mut self, id: String, lease: LeaseId) {
+        self.leases.insert(id, lease);
+    }
+
+    fn remove(&mut self, id: &str) -> Option<LeaseId> {
+        self.leases.remove(id)
+    }
+
+    /// Renew the leases of all the subscriptions, forgetting those that have already expired.
+    /// Returns the ids of the subscriptions that are still active.
+    fn renew(&mut self, api: &AdapterManager) -> Vec<String> {
+        let expired: Vec<_> = self.leases
+            .iter()
+            .filter(|&(_, lease)| !api.renew_lease(*lease))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            self.leases.remove(&id);
+        }
+        let mut ids: Vec<_> = self.leases.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    fn drain(&mut self) -> Vec<LeaseId> {
+        self.leases.drain().map(|(_, lease)| lease).collect()
+    }
+}
+
+/// Represent a watch event as a `watch/*` message, before it is tagged with the lease or the
+/// subscription that caused it.
+fn event_as_json(event: WatchEvent) -> JSON {
+    match event {
+        WatchEvent::EnterRange { channel, value, observed, .. } => {
+            json_value!({ type: "watch/enter", channel: channel, value: value,
+                          timestamp: observed.timestamp, source: observed.source.as_str() })
+        }
+        WatchEvent::ExitRange { channel, value, observed, .. } => {
+            json_value!({ type: "watch/exit", channel: channel, value: value,
+                          timestamp: observed.timestamp, source: observed.source.as_str() })
+        }
+        WatchEvent::EnterRangeDelta { channel, patch, observed, .. } => {
+            json_value!({ type: "watch/enter-delta", channel: channel, patch: patch,
+                          timestamp: observed.timestamp, source: observed.source.as_str() })
+        }
+        WatchEvent::ChannelAdded(channel) => {
+            json_value!({ type: "watch/channel-added", channel: channel })
+        }
+        WatchEvent::ChannelRemoved(channel) => {
+            json_value!({ type: "watch/channel-removed", channel: channel })
+        }
+        WatchEvent::Error { channel, error } => {
+            json_value!({ type: "watch/error", channel: channel, error: format!("{:?}", error) })
+        }
+    }
+}
+
+/// Clamp the `ttl` requested by a client, in seconds.
+fn lease_ttl(request: &JSON) -> u64 {
+    let ttl = request.find("ttl")
+        .and_then(JSON::as_u64)
+        .unwrap_or(DEFAULT_LEASE_TTL_S);
+    if ttl == 0 || ttl > MAX_LEASE_TTL_S {
+        MAX_LEASE_TTL_S
+    } else {
+        ttl
+    }
+}
+
 pub struct WsHandler<T> {
     pub out: Sender,
     pub controller: T,
     ssl: Option<Rc<SslContext>>,
+    api: Arc<AdapterManager>,
+    /// The user authenticated when the client connected, on behalf of whom watches are
+    /// registered.
+    user: User,
+    /// The watches registered by this client.
+    leases: Vec<LeaseId>,
+    /// The subscriptions of this client, see `subscribe`.
+    subscriptions: Subscriptions,
+    /// The watch sets resumed by this client, see `resume`.
+    watch_sets: Subscriptions,
+    /// The tokens to stop following the removal of the resumed watch sets.
+    followed: Vec<usize>,
+    /// Where the frames are recorded, if the client opted in with `trace=true`.
+    traces: WsTraces,
 }
 
 impl WsServer {
-    pub fn start<T: Controller>(controller: T) {
+    pub fn start<T: Controller>(controller: T, adapter_api: &Arc<AdapterManager>) {
+        let api = adapter_api.clone();
         let addrs: Vec<_> = controller.ws_as_addrs().unwrap().collect();
         thread::Builder::new()
             .name("WsServer".to_owned())
@@ -63,6 +179,13 @@ impl WsServer {
                             out: out,
                             controller: controller.clone(),
                             ssl: ssl.clone(),
+                            api: api.clone(),
+                            user: User::None,
+                            leases: vec![],
+                            subscriptions: Subscriptions::default(),
+                            watch_sets: Subscriptions::default(),
+                            followed: vec![],
+                            traces: controller.get_ws_traces(),
                         }
                 }).unwrap().listen(addrs[0]).unwrap();
             })
@@ -74,6 +197,244 @@ impl<T: Controller> WsHandler<T> {
     fn close_with_error(&mut self, reason: &'static str) -> Result<()> {
         self.out.close_with_reason(ws::CloseCode::Error, reason)
     }
+
+    fn send_json(&self, json: JSON) -> Result<()> {
+        let frame = serde_json::to_string(&json).unwrap_or("{}".to_owned());
+        self.traces.record(self.out.token(), Direction::Out, &frame);
+        self.out.send(frame)
+    }
+
+    /// Register a watch on behalf of this client, leased for `ttl` seconds. Events are relayed
+    /// to the client as `watch/*` messages.
+    fn watch(&mut self, request: &JSON) -> Result<()> {
+        let selectors = match request.find("channels")
+            .map(|channels| Vec::<ChannelSelector>::parse(Path::new(), channels)) {
+            Some(Ok(selectors)) => selectors,
+            _ => {
+                return self.send_json(json_value!({ type: "error",
+                                                    message: "Expected channel selectors" }))
+            }
+        };
+        let ttl = lease_ttl(request);
+
+        let (tx, rx) = mpsc::channel::<WatchEvent>();
+        let lease = self.api.watch_values_leased(vec![Targetted {
+                                                          select: selectors,
+                                                          payload: (Exactly::Always,
+                                                                    None,
+                                                                    Delivery::Full,
+                                                                    None),
+                                                      }],
+                                                 Box::new(tx),
+                                                 Duration::from_secs(ttl),
+                                                 self.user.clone());
+        self.leases.push(lease);
+
+        let id = lease.as_usize();
+        self.relay_events(rx, format!("WsWatch-{}", id), "lease", JSON::U64(id as u64));
+
+        self.send_json(json_value!({ type: "watch", lease: id, ttl: ttl }))
+    }
+
+    /// Relay the events received on `rx` to the client, tagged with `key: tag`. The thread
+    /// stops once the watch is released, which drops the sender.
+    fn relay_events(&self,
+                    rx: mpsc::Receiver<WatchEvent>,
+                    name: String,
+                    key: &'static str,
+                    tag: JSON) {
+        let out = self.out.clone();
+        let traces = self.traces.clone();
+        thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                while let Ok(event) = rx.recv() {
+                    let mut json = event_as_json(event);
+                    if let JSON::Object(ref mut object) = json {
+                        object.insert(key.to_owned(), tag.clone());
+                    }
+                    let frame = serde_json::to_string(&json).unwrap_or("{}".to_owned());
+                    traces.record(out.token(), Direction::Out, &frame);
+                    if out.send(frame).is_err() {
+                        break;
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    /// Subscribe to the values of some channels, on behalf of this client. Events are relayed
+    /// as `watch/*` messages tagged with `"subscription": id`, so that a client may multiplex
+    /// many subscriptions over a single connection.
+    fn subscribe(&mut self, request: &JSON) -> Result<()> {
+        let selectors = match request.find("selector")
+            .map(|selector| Vec::<ChannelSelector>::parse(Path::new(), selector)) {
+            Some(Ok(selectors)) => selectors,
+            _ => {
+                return self.send_json(json_value!({ type: "error",
+                                                    message: "Expected channel selectors" }))
+            }
+        };
+        let range = match request.find("range") {
+            None | Some(&JSON::Null) => Exactly::Always,
+            Some(range) => {
+                match Payload::parse(Path::new(), range) {
+                    Ok(range) => Exactly::Exactly(range),
+                    Err(_) => {
+                        return self.send_json(json_value!({ type: "error",
+                                                            message: "Invalid range" }))
+                    }
+                }
+            }
+        };
+        let ttl = lease_ttl(request);
+
+        // Clients may pick the ids of their subscriptions, to route the events themselves.
+        let requested_id = request.find("id").and_then(JSON::as_string).map(str::to_owned);
+        if let Some(ref id) = requested_id {
+            if self.subscriptions.contains(id) {
+                return self.send_json(json_value!({ type: "error", subscription: id,
+                                                    message: "Subscription already exists" }));
+            }
+        }
+
+        let (tx, rx) = mpsc::channel::<WatchEvent>();
+        let lease = self.api.watch_values_leased(vec![Targetted {
+                                                          select: selectors,
+                                                          payload: (range,
+                                                                    None,
+                                                                    Delivery::Full,
+                                                                    None),
+                                                      }],
+                                                 Box::new(tx),
+                                                 Duration::from_secs(ttl),
+                                                 self.user.clone());
+        let id = requested_id.unwrap_or_else(|| format!("{}", lease.as_usize()));
+        self.subscriptions.insert(id.clone(), lease);
+        self.relay_events(rx,
+                          format!("WsSubscription-{}", lease.as_usize()),
+                          "subscription",
+                          JSON::String(id.clone()));
+
+        self.send_json(json_value!({ type: "subscribed", subscription: id, ttl: ttl }))
+    }
+
+    fn unsubscribe(&mut self, request: &JSON) -> Result<()> {
+        // Accept both `{"unsubscribe": id}` and `{"unsubscribe": {"id": id}}`.
+        let id = request.as_string()
+            .or_else(|| request.find("id").and_then(JSON::as_string))
+            .map(str::to_owned);
+        match id.as_ref().and_then(|id| self.subscriptions.remove(id)) {
+            Some(lease) => {
+                self.api.release_lease(lease);
+                self.send_json(json_value!({ type: "unsubscribed", subscription: id }))
+            }
+            None => {
+                self.send_json(json_value!({ type: "error", message: "Unknown subscription" }))
+            }
+        }
+    }
+
+    /// Resume a watch set registered over REST, on behalf of this client. All the watches of
+    /// the set share a single lease, and their events are tagged with `"watch_set": id`. If
+    /// the set is removed, its lease is released and the client is told.
+    fn resume(&mut self, request: &JSON) -> Result<()> {
+        // Accept both `{"resume": id}` and `{"resume": {"id": id, "ttl"?: seconds}}`.
+        let id = request.as_string()
+            .or_else(|| request.find("id").and_then(JSON::as_string))
+            .map(str::to_owned);
+        let id = match id {
+            Some(id) => id,
+            None => {
+                return self.send_json(json_value!({ type: "error",
+                                                    message: "Expected a watch set id" }))
+            }
+        };
+        if self.watch_sets.contains(&id) {
+            return self.send_json(json_value!({ type: "error", watch_set: id,
+                                                message: "Watch set already resumed" }));
+        }
+        let registry = self.controller.get_watch_sets();
+        let set = match registry.get(&id) {
+            Some(set) => set,
+            None => {
+                return self.send_json(json_value!({ type: "error", watch_set: id,
+                                                    message: "Unknown watch set" }))
+            }
+        };
+        // The watches were checked when the set was registered.
+        let mut targets = vec![];
+        for watch in &set.watches {
+            let selectors = Vec::<ChannelSelector>::parse(Path::new(), &watch.selector);
+            let range = match watch.range {
+                None => Ok(Exactly::Always),
+                Some(ref range) => Payload::parse(Path::new(), range).map(Exactly::Exactly),
+            };
+            if let (Ok(selectors), Ok(range)) = (selectors, range) {
+                targets.push(Targetted {
+                    select: selectors,
+                    payload: (range, None, Delivery::Full, None),
+                });
+            }
+        }
+        let ttl = lease_ttl(request);
+
+        let (tx, rx) = mpsc::channel::<WatchEvent>();
+        let lease = self.api.watch_values_leased(targets,
+                                                 Box::new(tx),
+                                                 Duration::from_secs(ttl),
+                                                 self.user.clone());
+        let api = self.api.clone();
+        let out = self.out.clone();
+        let traces = self.traces.clone();
+        let removed = id.clone();
+        let on_removed = Box::new(move || {
+            api.release_lease(lease);
+            let json = json_value!({ type: "watch-set/deleted", watch_set: removed });
+            let frame = serde_json::to_string(&json).unwrap_or("{}".to_owned());
+            traces.record(out.token(), Direction::Out, &frame);
+            let _ = out.send(frame);
+        });
+        match registry.follow(&id, on_removed) {
+            Some(token) => self.followed.push(token),
+            None => {
+                // The set was removed meanwhile.
+                self.api.release_lease(lease);
+                return self.send_json(json_value!({ type: "error", watch_set: id,
+                                                    message: "Unknown watch set" }));
+            }
+        }
+        self.watch_sets.insert(id.clone(), lease);
+        self.relay_events(rx,
+                          format!("WsWatchSet-{}", lease.as_usize()),
+                          "watch_set",
+                          JSON::String(id.clone()));
+
+        self.send_json(json_value!({ type: "resumed", watch_set: id, ttl: ttl }))
+    }
+
+    /// Renew the leases of all the watches, subscriptions and watch sets of this client.
+    fn keepalive(&mut self) -> Result<()> {
+        let api = self.api.clone();
+        self.leases.retain(|lease| api.renew_lease(*lease));
+        let leases: Vec<_> = self.leases.iter().map(LeaseId::as_usize).collect();
+        let subscriptions = self.subscriptions.renew(&api);
+        let watch_sets = self.watch_sets.renew(&api);
+        self.send_json(json_value!({ type: "keepalive", leases: leases,
+                                     subscriptions: subscriptions, watch_sets: watch_sets }))
+    }
+
+    fn unwatch(&mut self, request: &JSON) -> Result<()> {
+        let id = request.find("lease").and_then(JSON::as_u64);
+        match self.leases.iter().position(|lease| Some(lease.as_usize() as u64) == id) {
+            Some(index) => {
+                let lease = self.leases.remove(index);
+                self.api.release_lease(lease);
+                Ok(())
+            }
+            None => self.send_json(json_value!({ type: "error", message: "Unknown lease" })),
+        }
+    }
 }
 
 impl<T: Controller> Handler for WsHandler<T> {
@@ -100,16 +461,85 @@ impl<T: Controller> Handler for WsHandler<T> {
         if self.controller.get_users_manager().verify_token(&token).is_err() {
             return self.close_with_error("Authorization failed");
         }
+        if let Ok(token) = SessionToken::from_string(&token) {
+            self.user = User::Id(token.claims.id);
+        }
 
-        self.controller.add_websocket(self.out.clone());
+        // Clients opting in with `binary=true` receive binary values (e.g. camera images) as
+        // a JSON header frame followed by binary frames, instead of inlined in the JSON.
+        let binary = url.query_pairs()
+            .any(|set| set.0.to_lowercase() == "binary" && set.1 == "true");
+
+        // Clients opting in with `trace=true` have their frames recorded, to diagnose them.
+        let trace = url.query_pairs()
+            .any(|set| set.0.to_lowercase() == "trace" && set.1 == "true");
+        if trace {
+            match self.traces.start(self.out.token(), resource) {
+                Ok(name) => info!("Recording websocket {:?} as trace {}", self.out.token(), name),
+                Err(err) => error!("Could not record websocket {:?}: {}", self.out.token(), err),
+            }
+        }
+
+        self.controller.add_websocket(self.out.clone(), binary);
 
         Ok(())
     }
 
+    /// Clients may register their own watches:
+    ///
+    /// - `{"type": "watch", "channels": [selectors], "ttl": seconds}` registers a watch, and
+    /// replies `{"type": "watch", "lease": id, "ttl": seconds}`;
+    /// - `{"type": "keepalive"}` must be sent at least every `ttl` seconds, otherwise the
+    /// watches are released, e.g. if the client crashed without closing the connection;
+    /// - `{"type": "unwatch", "lease": id}` releases a watch.
+    ///
+    /// They may also multiplex subscriptions over the connection:
+    ///
+    /// - `{"subscribe": {"selector": selectors, "range"?: value, "id"?: string, "ttl"?: seconds}}`
+    /// subscribes to the values of some channels, in `range` if specified, and replies
+    /// `{"type": "subscribed", "subscription": id, "ttl": seconds}`. The events are the same
+    /// as for watches, tagged with `"subscription": id` rather than with a lease;
+    /// - `{"unsubscribe": id}` cancels a subscription, and replies
+    /// `{"type": "unsubscribed", "subscription": id}`.
+    ///
+    /// Or follow the watch sets registered with `api/v1/watch-sets`:
+    ///
+    /// - `{"resume": {"id": string, "ttl"?: seconds}}` registers all the watches of the set,
+    /// and replies `{"type": "resumed", "watch_set": id, "ttl": seconds}`. The events are
+    /// tagged with `"watch_set": id`. Once the set is removed, the client receives
+    /// `{"type": "watch-set/deleted", "watch_set": id}`.
+    ///
+    /// Keepalives also renew the subscriptions and watch sets.
     fn on_message(&mut self, msg: Message) -> Result<()> {
         info!("Message from websocket ({:?}): {}", self.out.token(), msg);
+        match msg {
+            Message::Text(ref text) => self.traces.record(self.out.token(), Direction::In, text),
+            Message::Binary(ref data) => {
+                self.traces.record_binary(self.out.token(), Direction::In, data.len())
+            }
+        }
 
-        Ok(())
+        let request: JSON = match msg.as_text()
+            .ok()
+            .and_then(|text| serde_json::from_str(text).ok()) {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        if let Some(subscribe) = request.find("subscribe") {
+            return self.subscribe(subscribe);
+        }
+        if let Some(unsubscribe) = request.find("unsubscribe") {
+            return self.unsubscribe(unsubscribe);
+        }
+        if let Some(resume) = request.find("resume") {
+            return self.resume(resume);
+        }
+        match request.find("type").and_then(JSON::as_string) {
+            Some("watch") => self.watch(&request),
+            Some("keepalive") => self.keepalive(),
+            Some("unwatch") => self.unwatch(&request),
+            _ => Ok(()),
+        }
     }
 
     fn on_close(&mut self, code: CloseCode, reason: &str) {
@@ -120,6 +550,16 @@ impl<T: Controller> Handler for WsHandler<T> {
         }
 
         self.controller.remove_websocket(self.out.clone());
+        self.traces.stop(self.out.token());
+        let watch_sets = self.controller.get_watch_sets();
+        for token in self.followed.drain(..) {
+            watch_sets.unfollow(token);
+        }
+        for lease in self.leases.drain(..)
+            .chain(self.subscriptions.drain())
+            .chain(self.watch_sets.drain()) {
+            self.api.release_lease(lease);
+        }
     }
 
     fn on_error(&mut self, err: Error) {
@@ -134,3 +574,48 @@ impl<T: Controller> Handler for WsHandler<T> {
         Ssl::new(&self.ssl.clone().unwrap()).map_err(ws::Error::from)
     }
 }
+
+#[cfg(test)]
+describe! ws_subscriptions {
+    before_each {
+        use foxbox_taxonomy::api::WatchEvent;
+        use foxbox_taxonomy::manager::AdapterManager;
+        use serde_json;
+        use serde_json::value::Value as JSON;
+        use std::time::Duration;
+        use super::{Subscriptions, lease_ttl};
+        use transformable_channels::mpsc;
+
+        let api = AdapterManager::new(None);
+        let lease = || {
+            let (tx, _) = mpsc::channel::<WatchEvent>();
+            api.watch_values_leased(vec![], Box::new(tx), Duration::from_secs(60), User::None)
+        };
+    }
+
+    it "should forget the subscriptions that are cancelled or expired" {
+        let mut subscriptions = Subscriptions::default();
+        let kitchen = lease();
+        subscriptions.insert("kitchen".to_owned(), kitchen);
+        subscriptions.insert("hall".to_owned(), lease());
+        assert!(subscriptions.contains("kitchen"));
+        assert_eq!(subscriptions.renew(&api), vec!["hall".to_owned(), "kitchen".to_owned()]);
+
+        // The lease of the kitchen expires, e.g. after the box missed a keepalive.
+        api.release_lease(kitchen);
+        assert_eq!(subscriptions.renew(&api), vec!["hall".to_owned()]);
+        assert!(!subscriptions.contains("kitchen"));
+
+        assert!(subscriptions.remove("hall").is_some());
+        assert!(subscriptions.remove("hall").is_none());
+        assert!(subscriptions.drain().is_empty());
+    }
+
+    it "should clamp the ttl of subscriptions" {
+        let ttl = |text: &str| lease_ttl(&serde_json::from_str::<JSON>(text).unwrap());
+        assert_eq!(ttl(r#"{"selector": {}}"#), 60);
+        assert_eq!(ttl(r#"{"selector": {}, "ttl": 120}"#), 120);
+        assert_eq!(ttl(r#"{"selector": {}, "ttl": 0}"#), 3600);
+        assert_eq!(ttl(r#"{"selector": {}, "ttl": 100000}"#), 3600);
+    }
+}